@@ -1,2 +1,6 @@
 pub mod config;
+pub mod error_log;
+pub mod ip_trie;
+pub mod log_file;
+pub mod log_target;
 pub mod processor;
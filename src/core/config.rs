@@ -2,3 +2,5 @@ pub mod command;
 pub mod config_context;
 pub mod config_loader;
 pub mod config_manager;
+pub mod context_store;
+pub mod units;
@@ -1,12 +1,197 @@
-use crate::core::config::config_context::ConfigContext;
+use crate::core::config::{
+    config_context::ConfigContext,
+    config_loader::ConfigError,
+    config_manager::bool_str_to_bool,
+    units::{parse_duration, parse_size},
+};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgType {
+    String,
+    Bool,
+    Number,
+    Address,
+    Size,
+    Duration,
+    Path,
+    Regex,
+    Enum(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValue {
+    String(String),
+    Bool(bool),
+    Number(i64),
+    Address(String),
+    Size(u64),
+    Duration(Duration),
+    Path(PathBuf),
+    Enum(String),
+}
+
+impl ArgType {
+    fn from_type_name(type_name: &str) -> Self {
+        match type_name.to_lowercase().as_str() {
+            "bool" => ArgType::Bool,
+            "u16" | "u32" | "u64" | "i32" | "i64" => ArgType::Number,
+            _ => ArgType::String,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            ArgType::String => "String",
+            ArgType::Bool => "bool",
+            ArgType::Number => "i64",
+            ArgType::Address => "address",
+            ArgType::Size => "size",
+            ArgType::Duration => "duration",
+            ArgType::Path => "path",
+            ArgType::Regex => "regex",
+            ArgType::Enum(_) => "enum",
+        }
+    }
+
+    pub fn parse(&self, value: &str) -> Result<ArgValue, String> {
+        match self {
+            ArgType::String => Ok(ArgValue::String(value.to_string())),
+            ArgType::Bool => bool_str_to_bool(value).map(ArgValue::Bool),
+            ArgType::Number => value
+                .parse::<i64>()
+                .map(ArgValue::Number)
+                .map_err(|_| "expected a number".to_string()),
+            ArgType::Address => parse_address(value).map(ArgValue::Address),
+            ArgType::Size => parse_size(value).map(ArgValue::Size),
+            ArgType::Duration => parse_duration(value).map(ArgValue::Duration),
+            ArgType::Path => {
+                if value.is_empty() {
+                    Err("expected a path".to_string())
+                } else {
+                    Ok(ArgValue::Path(PathBuf::from(value)))
+                }
+            }
+            ArgType::Regex => regex::Regex::new(value)
+                .map(|_| ArgValue::String(value.to_string()))
+                .map_err(|e| format!("invalid regular expression: {}", e)),
+            ArgType::Enum(allowed) => {
+                if allowed.iter().any(|a| a.eq_ignore_ascii_case(value)) {
+                    Ok(ArgValue::Enum(value.to_lowercase()))
+                } else {
+                    Err(format!("expected one of: {}", allowed.join(", ")))
+                }
+            }
+        }
+    }
+}
+
+fn parse_address(value: &str) -> Result<String, String> {
+    if value.parse::<u16>().is_ok() {
+        return Ok(format!("0.0.0.0:{}", value));
+    }
+    if value.parse::<SocketAddr>().is_ok() {
+        return Ok(value.to_string());
+    }
+    match value.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty()
+                && port.parse::<u16>().is_ok()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-') =>
+        {
+            Ok(value.to_string())
+        }
+        _ => Err("expected \"address:port\" or a port number".to_string()),
+    }
+}
+
+impl ArgValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ArgValue::String(s) | ArgValue::Address(s) | ArgValue::Enum(s) => Some(s),
+            ArgValue::Path(p) => p.to_str(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ArgValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            ArgValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_size(&self) -> Option<u64> {
+        match self {
+            ArgValue::Size(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_duration(&self) -> Option<Duration> {
+        match self {
+            ArgValue::Duration(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    pub fn as_path(&self) -> Option<&PathBuf> {
+        match self {
+            ArgValue::Path(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub fn socket_addrs(&self) -> Option<Vec<SocketAddr>> {
+        match self {
+            ArgValue::Address(addr) => addr.to_socket_addrs().ok().map(|a| a.collect()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    Range(usize, usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => count == n,
+            Arity::Range(min, max) => count >= min && count <= max,
+            Arity::AtLeast(min) => count >= min,
+        }
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        matches!(self, Arity::AtLeast(_))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Parameter {
     pub index: usize,
     pub display_name: HashMap<String, String>,
     pub type_name: String,
+    pub arg_type: ArgType,
     pub is_required: bool,
     pub default: String,
     pub desc: HashMap<String, String>,
@@ -25,6 +210,7 @@ impl Parameter {
             index,
             display_name,
             type_name: type_name.to_string(),
+            arg_type: ArgType::from_type_name(type_name),
             is_required,
             default: default.to_string(),
             desc,
@@ -36,6 +222,7 @@ pub struct ParameterBuilder {
     index: usize,
     display_name: HashMap<String, String>,
     type_name: String,
+    arg_type: Option<ArgType>,
     is_required: bool,
     default: String,
     desc: HashMap<String, String>,
@@ -47,6 +234,7 @@ impl ParameterBuilder {
             index,
             display_name: HashMap::new(),
             type_name: "".to_string(),
+            arg_type: None,
             is_required: false,
             default: "".to_string(),
             desc: HashMap::new(),
@@ -63,6 +251,14 @@ impl ParameterBuilder {
         self
     }
 
+    pub fn arg_type(mut self, arg_type: ArgType) -> Self {
+        if self.type_name.is_empty() {
+            self.type_name = arg_type.type_name().to_string();
+        }
+        self.arg_type = Some(arg_type);
+        self
+    }
+
     pub fn is_required(mut self, is_required: bool) -> Self {
         self.is_required = is_required;
         self
@@ -79,10 +275,14 @@ impl ParameterBuilder {
     }
 
     pub fn build(self) -> Parameter {
+        let arg_type = self
+            .arg_type
+            .unwrap_or_else(|| ArgType::from_type_name(&self.type_name));
         Parameter {
             index: self.index,
             display_name: self.display_name,
             type_name: self.type_name,
+            arg_type,
             is_required: self.is_required,
             default: self.default,
             desc: self.desc,
@@ -90,33 +290,74 @@ impl ParameterBuilder {
     }
 }
 
-type CommandHandler = Box<dyn Fn(&mut ConfigContext, &Value) + Send + Sync>;
+type CommandHandler =
+    Box<dyn Fn(&mut ConfigContext, &Value) -> Result<(), ConfigError> + Send + Sync>;
+
+type BlockEndHandler = Box<dyn Fn(&ConfigContext) -> Result<(), ConfigError> + Send + Sync>;
 
 pub struct Command {
     pub name: String,
     pub is_block: bool,
+    pub raw_block: bool,
     pub unique: bool,
+    pub repeatable: bool,
     pub allowed_parents: Vec<String>,
     pub display_name: HashMap<String, String>,
     pub desc: HashMap<String, String>,
     pub params: Vec<Parameter>,
+    pub arity: Arity,
     pub handler: CommandHandler,
+    pub block_end: Option<BlockEndHandler>,
 }
 
 impl Command {
-    pub fn handle(&self, ctx: &mut ConfigContext, config: &Value) {
-        (self.handler)(ctx, config);
+    pub fn handle(&self, ctx: &mut ConfigContext, config: &Value) -> Result<(), ConfigError> {
+        (self.handler)(ctx, config)
+    }
+
+    pub fn end_block(&self, ctx: &ConfigContext) -> Result<(), ConfigError> {
+        match &self.block_end {
+            Some(handler) => handler(ctx),
+            None => Ok(()),
+        }
+    }
+
+    pub fn param_for(&self, index: usize) -> Option<&Parameter> {
+        self.params.get(index).or_else(|| {
+            if self.arity.is_variadic() {
+                self.params.last()
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn parse_args(&self, args: &[String]) -> Result<Vec<ArgValue>, (String, String)> {
+        args.iter()
+            .enumerate()
+            .map(|(i, arg)| match self.param_for(i) {
+                Some(param) => param
+                    .arg_type
+                    .parse(arg)
+                    .map_err(|reason| (arg.clone(), reason)),
+                None => Ok(ArgValue::String(arg.clone())),
+            })
+            .collect()
     }
 }
 
 pub struct CommandBuilder {
     name: String,
     is_block: bool,
+    raw_block: bool,
     unique: bool,
+    repeatable: bool,
     allowed_parents: Vec<String>,
     display_name: HashMap<String, String>,
     desc: HashMap<String, String>,
     params: Vec<Parameter>,
+    arity: Option<Arity>,
+    block_end: Option<BlockEndHandler>,
 }
 
 impl CommandBuilder {
@@ -124,11 +365,15 @@ impl CommandBuilder {
         Self {
             name: name.to_string(),
             is_block: false,
+            raw_block: false,
             unique: false,
+            repeatable: false,
             allowed_parents: vec![],
             display_name: HashMap::new(),
             desc: HashMap::new(),
             params: vec![],
+            arity: None,
+            block_end: None,
         }
     }
 
@@ -137,11 +382,26 @@ impl CommandBuilder {
         self
     }
 
+    /// A raw block keeps its children as ordered `RawEntry` lines instead of
+    /// validating them as directives, e.g. the `key value;` pairs of `map`.
+    pub fn is_raw_block(mut self) -> Self {
+        self.is_block = true;
+        self.raw_block = true;
+        self
+    }
+
     pub fn is_unique(mut self) -> Self {
         self.unique = true;
         self
     }
 
+    /// Allows a non-block directive to appear several times in one block; the
+    /// handler is invoked once per occurrence, in order.
+    pub fn is_repeatable(mut self) -> Self {
+        self.repeatable = true;
+        self
+    }
+
     pub fn allowed_parents(mut self, parents: Vec<String>) -> Self {
         self.allowed_parents = parents;
         self
@@ -162,19 +422,46 @@ impl CommandBuilder {
         self
     }
 
+    pub fn arity(mut self, arity: Arity) -> Self {
+        self.arity = Some(arity);
+        self
+    }
+
+    /// Runs `handler` on each block that allows the directive once the block
+    /// and everything nested in it are processed, whether or not the
+    /// directive is set; for checks that need the whole block, such as names
+    /// used before or below their definition.
+    pub fn on_block_end<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&ConfigContext) -> Result<(), ConfigError> + Send + Sync + 'static,
+    {
+        self.block_end = Some(Box::new(handler));
+        self
+    }
+
     pub fn build<F>(self, handler: F) -> Command
     where
-        F: Fn(&mut ConfigContext, &Value) + Send + Sync + 'static,
+        F: Fn(&mut ConfigContext, &Value) -> Result<(), ConfigError> + Send + Sync + 'static,
     {
+        let required = self.params.iter().filter(|p| p.is_required).count();
+        let arity = self.arity.unwrap_or(if required == self.params.len() {
+            Arity::Exact(required)
+        } else {
+            Arity::Range(required, self.params.len())
+        });
         Command {
             name: self.name,
             is_block: self.is_block,
+            raw_block: self.raw_block,
             unique: self.unique,
+            repeatable: self.repeatable,
             allowed_parents: self.allowed_parents,
             display_name: self.display_name,
             desc: self.desc,
             params: self.params,
+            arity,
             handler: Box::new(handler),
+            block_end: self.block_end,
         }
     }
 }
@@ -205,3 +492,41 @@ macro_rules! register_commands {
         )*
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg_type_parse() {
+        assert_eq!(
+            ArgType::Address.parse("8080"),
+            Ok(ArgValue::Address("0.0.0.0:8080".to_string()))
+        );
+        assert!(ArgType::Address.parse("[::1]:443").is_ok());
+        assert!(ArgType::Address.parse("not an address").is_err());
+        assert_eq!(ArgType::Bool.parse("on"), Ok(ArgValue::Bool(true)));
+        assert!(ArgType::Number.parse("ten").is_err());
+
+        let methods = ArgType::Enum(vec!["get".to_string(), "post".to_string()]);
+        assert_eq!(
+            methods.parse("POST"),
+            Ok(ArgValue::Enum("post".to_string()))
+        );
+        assert!(methods.parse("put").is_err());
+    }
+
+    #[test]
+    fn test_arity_defaults_from_params() {
+        let cmd = CommandBuilder::new("test_arity")
+            .params(vec![
+                ParameterBuilder::new(0).is_required(true).build(),
+                ParameterBuilder::new(1).build(),
+            ])
+            .build(|_, _| Ok(()));
+        assert_eq!(cmd.arity, Arity::Range(1, 2));
+        assert!(!cmd.arity.accepts(0));
+        assert!(cmd.arity.accepts(2));
+        assert!(!cmd.arity.accepts(3));
+    }
+}
@@ -1,4 +1,88 @@
-use std::{any::TypeId, sync::atomic::AtomicPtr};
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use super::{command::ArgValue, config_loader::ConfigError, context_store::ContextStore};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigPosition {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl ConfigPosition {
+    pub fn new(file: &str, line: usize, column: usize) -> Self {
+        Self {
+            file: file.to_string(),
+            line,
+            column,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            file: value.get("file")?.as_str()?.to_string(),
+            line: value.get("line")?.as_u64()? as usize,
+            column: value.get("column")?.as_u64()? as usize,
+        })
+    }
+}
+
+impl fmt::Display for ConfigPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawEntry {
+    pub args: Vec<String>,
+    pub pos: Option<ConfigPosition>,
+}
+
+impl RawEntry {
+    pub fn to_json(&self) -> Value {
+        let mut entry = json!({ "args": self.args });
+        if let Some(pos) = &self.pos {
+            entry["position"] = pos.to_json();
+        }
+        entry
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let args = value
+            .get("args")?
+            .as_array()?
+            .iter()
+            .map(|arg| arg.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self {
+            args,
+            pos: value.get("position").and_then(ConfigPosition::from_json),
+        })
+    }
+}
+
+/// Settings of a block, completed from its parent's with `merge_from`.
+/// Settings that make a location act on its own, such as `proxy_pass` or
+/// `stub_status`, are left out of `merge_from` and so never inherited.
+pub trait MergeConfig: Default + Clone + Send + 'static {
+    fn merge_from(&mut self, parent: &Self);
+}
 
 #[derive(Debug)]
 pub struct ConfigContext {
@@ -6,14 +90,11 @@ pub struct ConfigContext {
     pub block_args: Vec<String>,
     pub current_cmd_name: String,
     pub current_cmd_args: Vec<String>,
-    pub current_block_type_id: Option<TypeId>,
-    pub current_ctx: Option<AtomicPtr<u8>>,
-    pub spare1: Option<AtomicPtr<u8>>,
-    pub spare2: Option<AtomicPtr<u8>>,
-    pub spare3: Option<AtomicPtr<u8>>,
-    pub spare4: Option<AtomicPtr<u8>>,
-    pub spare5: Option<AtomicPtr<u8>>,
-    pub parse_pos: u64,
+    pub current_cmd_values: Vec<ArgValue>,
+    pub current_cmd_pos: Option<ConfigPosition>,
+    pub raw_entries: Vec<RawEntry>,
+    pub store: ContextStore,
+    pub position: Option<ConfigPosition>,
     pub children: Vec<ConfigContext>,
 }
 
@@ -24,15 +105,116 @@ impl ConfigContext {
             block_args: args,
             current_cmd_name: String::new(),
             current_cmd_args: Vec::new(),
-            current_block_type_id: None,
-            current_ctx: None,
-            spare1: None,
-            spare2: None,
-            spare3: None,
-            spare4: None,
-            spare5: None,
-            parse_pos: 0,
+            current_cmd_values: Vec::new(),
+            current_cmd_pos: None,
+            raw_entries: Vec::new(),
+            store: ContextStore::new(),
+            position: None,
             children: Vec::new(),
         }
     }
+
+    pub fn block_config<T: MergeConfig>(&mut self) -> Arc<Mutex<T>> {
+        self.store.get_or_insert_with(|| Mutex::new(T::default()))
+    }
+
+    pub fn arg(&self, index: usize) -> Result<&ArgValue, ConfigError> {
+        self.current_cmd_values
+            .get(index)
+            .ok_or_else(|| ConfigError::InvalidArgCount {
+                name: self.current_cmd_name.clone(),
+                pos: self.current_cmd_pos.clone(),
+            })
+    }
+
+    pub fn opt_arg(&self, index: usize) -> Option<&ArgValue> {
+        self.current_cmd_values.get(index)
+    }
+
+    fn typed_arg<T>(
+        &self,
+        index: usize,
+        convert: impl Fn(&ArgValue) -> Option<T>,
+    ) -> Result<T, ConfigError> {
+        let value = self.arg(index)?;
+        convert(value).ok_or_else(|| {
+            let raw = self
+                .current_cmd_args
+                .get(index)
+                .cloned()
+                .unwrap_or_default();
+            self.invalid_value(&raw, "unexpected argument type")
+        })
+    }
+
+    pub fn str_arg(&self, index: usize) -> Result<String, ConfigError> {
+        self.typed_arg(index, |v| v.as_str().map(str::to_string))
+    }
+
+    /// The arguments of the current command as written, for directives
+    /// taking any number of them; empty when the command is not set.
+    pub fn args(&self) -> Vec<String> {
+        self.current_cmd_args
+            .iter()
+            .filter(|arg| !arg.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    pub fn bool_arg(&self, index: usize) -> Result<bool, ConfigError> {
+        self.typed_arg(index, ArgValue::as_bool)
+    }
+
+    pub fn number_arg(&self, index: usize) -> Result<i64, ConfigError> {
+        self.typed_arg(index, ArgValue::as_number)
+    }
+
+    pub fn size_arg(&self, index: usize) -> Result<u64, ConfigError> {
+        self.typed_arg(index, ArgValue::as_size)
+    }
+
+    pub fn duration_arg(&self, index: usize) -> Result<Duration, ConfigError> {
+        self.typed_arg(index, ArgValue::as_duration)
+    }
+
+    pub fn path_arg(&self, index: usize) -> Result<PathBuf, ConfigError> {
+        self.typed_arg(index, |v| v.as_path().cloned())
+    }
+
+    pub fn invalid_value(&self, value: &str, reason: impl Into<String>) -> ConfigError {
+        ConfigError::InvalidValue {
+            name: self.current_cmd_name.clone(),
+            value: value.to_string(),
+            reason: reason.into(),
+            pos: self.current_cmd_pos.clone(),
+        }
+    }
+
+    pub fn invalid_entry(
+        &self,
+        entry: &RawEntry,
+        value: &str,
+        reason: impl Into<String>,
+    ) -> ConfigError {
+        ConfigError::InvalidValue {
+            name: self.current_cmd_name.clone(),
+            value: value.to_string(),
+            reason: reason.into(),
+            pos: entry.pos.clone().or_else(|| self.current_cmd_pos.clone()),
+        }
+    }
+}
+
+pub fn merged_config<T: MergeConfig>(chain: &[&ConfigContext]) -> T {
+    let mut merged = T::default();
+    for ctx in chain {
+        if let Some(config) = ctx.store.get::<Mutex<T>>() {
+            if let Ok(config) = config.lock() {
+                let mut current = config.clone();
+                current.merge_from(&merged);
+                merged = current;
+            }
+        }
+    }
+    merged
 }
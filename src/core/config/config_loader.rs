@@ -1,9 +1,10 @@
-use crate::core::config::config_context::ConfigContext;
+use crate::core::config::config_context::{ConfigContext, ConfigPosition, RawEntry};
 use crate::core::config::config_manager::ConfigManager;
 use serde_json::{json, Map, Value};
+use std::env;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use thiserror::Error;
 
@@ -18,6 +19,44 @@ pub enum ConfigError {
     JsonError(#[from] serde_json::Error),
     #[error("Validation error: {0}")]
     ValidationError(String),
+    #[error("{message} in {pos}")]
+    Syntax {
+        message: String,
+        pos: ConfigPosition,
+    },
+    #[error("unknown directive \"{name}\"{}", at(.pos))]
+    UnknownDirective {
+        name: String,
+        pos: Option<ConfigPosition>,
+    },
+    #[error("\"{name}\" directive is not allowed here{}", at(.pos))]
+    NotAllowedHere {
+        name: String,
+        pos: Option<ConfigPosition>,
+    },
+    #[error("invalid number of arguments in \"{name}\" directive{}", at(.pos))]
+    InvalidArgCount {
+        name: String,
+        pos: Option<ConfigPosition>,
+    },
+    #[error("environment variable \"{name}\" is not set{}", at(.pos))]
+    UndefinedEnvVar {
+        name: String,
+        pos: Option<ConfigPosition>,
+    },
+    #[error("invalid value \"{value}\" in \"{name}\" directive: {reason}{}", at(.pos))]
+    InvalidValue {
+        name: String,
+        value: String,
+        reason: String,
+        pos: Option<ConfigPosition>,
+    },
+}
+
+fn at(pos: &Option<ConfigPosition>) -> String {
+    pos.as_ref()
+        .map(|pos| format!(" in {}", pos))
+        .unwrap_or_default()
 }
 
 fn merge_config(template: &Value, user: &Value) -> Value {
@@ -49,28 +88,136 @@ fn merge_config(template: &Value, user: &Value) -> Value {
     }
 }
 
-fn parse_nginx_config(file_path: &str) -> Result<Value, ConfigError> {
+const INCLUDE_DIRECTIVE: &str = "include";
+
+fn parse_nginx_config(file_path: &str) -> Result<Vec<ConfigNode>, ConfigError> {
+    let mut include_stack = vec![fs::canonicalize(file_path)?];
+    let nodes = parse_config_file(file_path, &mut include_stack)?;
+    validate_nodes(&nodes, "root")?;
+    Ok(nodes)
+}
+
+fn parse_config_file(
+    file_path: &str,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Vec<ConfigNode>, ConfigError> {
     let content = fs::read_to_string(file_path)?;
     let tokens = tokenize(&content);
-    let (nodes, _) = parse_tokens(&tokens, 0)?;
-    let json_value = nodes_to_json(&nodes);
-    Ok(json_value)
+    let (nodes, _) = parse_tokens(&tokens, 0, 0, file_path)?;
+    expand_includes(nodes, file_path, include_stack)
+}
+
+fn expand_includes(
+    nodes: Vec<ConfigNode>,
+    file_path: &str,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Vec<ConfigNode>, ConfigError> {
+    let mut expanded = Vec::with_capacity(nodes.len());
+    for mut node in nodes {
+        if node.command != INCLUDE_DIRECTIVE {
+            node.children = expand_includes(node.children, file_path, include_stack)?;
+            expanded.push(node);
+            continue;
+        }
+        if node.is_block {
+            return Err(ConfigError::Syntax {
+                message: format!("directive \"{}\" is not terminated by \";\"", node.command),
+                pos: node.pos,
+            });
+        }
+        if node.args.len() != 1 {
+            return Err(ConfigError::InvalidArgCount {
+                name: node.command,
+                pos: Some(node.pos),
+            });
+        }
+        for path in resolve_include(&node, file_path)? {
+            let include_error = |message: String| ConfigError::Syntax {
+                message,
+                pos: node.pos.clone(),
+            };
+            let canonical = fs::canonicalize(&path).map_err(|e| {
+                include_error(format!("open() \"{}\" failed ({})", path.display(), e))
+            })?;
+            if include_stack.contains(&canonical) {
+                return Err(include_error(format!(
+                    "recursive include of \"{}\"",
+                    path.display()
+                )));
+            }
+            include_stack.push(canonical);
+            let included =
+                parse_config_file(&path.to_string_lossy(), include_stack).map_err(|e| match e {
+                    ConfigError::IoError(e) => {
+                        include_error(format!("open() \"{}\" failed ({})", path.display(), e))
+                    }
+                    other => other,
+                })?;
+            include_stack.pop();
+            expanded.extend(included);
+        }
+    }
+    Ok(expanded)
+}
+
+fn resolve_include(node: &ConfigNode, file_path: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let pattern = Path::new(&node.args[0]);
+    let pattern = if pattern.is_absolute() {
+        pattern.to_path_buf()
+    } else {
+        Path::new(file_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(pattern)
+    };
+    let pattern_str = pattern.to_string_lossy();
+
+    if !pattern_str.contains(['*', '?', '[']) {
+        return Ok(vec![pattern]);
+    }
+
+    let invalid = |reason: String| ConfigError::InvalidValue {
+        name: node.command.clone(),
+        value: node.args[0].clone(),
+        reason,
+        pos: Some(node.pos.clone()),
+    };
+    let mut paths = glob::glob(&pattern_str)
+        .map_err(|e| invalid(e.to_string()))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(e.to_string()))?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+    Ok(paths)
 }
 
 #[derive(Debug, Clone)]
-enum Token {
+enum TokenKind {
     Word(String),
     LBrace,
     RBrace,
     Semicolon,
 }
 
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
 fn tokenize(input: &str) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut pos = 0;
+    let mut line = 1;
+    let mut line_start = 0;
     let bytes = input.as_bytes();
     while pos < bytes.len() {
         while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+            if bytes[pos] == b'\n' {
+                line += 1;
+                line_start = pos + 1;
+            }
             pos += 1;
         }
         if pos >= bytes.len() {
@@ -82,18 +229,44 @@ fn tokenize(input: &str) -> Vec<Token> {
             }
             continue;
         }
-        match bytes[pos] {
+        let column = pos - line_start + 1;
+        let kind = match bytes[pos] {
             b'{' => {
-                tokens.push(Token::LBrace);
                 pos += 1;
+                TokenKind::LBrace
             }
             b'}' => {
-                tokens.push(Token::RBrace);
                 pos += 1;
+                TokenKind::RBrace
             }
             b';' => {
-                tokens.push(Token::Semicolon);
                 pos += 1;
+                TokenKind::Semicolon
+            }
+            quote @ (b'"' | b'\'') => {
+                pos += 1;
+                let mut word = Vec::new();
+                while pos < bytes.len() && bytes[pos] != quote {
+                    if bytes[pos] == b'\\' && pos + 1 < bytes.len() {
+                        pos += 1;
+                        match bytes[pos] {
+                            b'n' => word.push(b'\n'),
+                            b't' => word.push(b'\t'),
+                            b'r' => word.push(b'\r'),
+                            b'"' | b'\'' | b'\\' => word.push(bytes[pos]),
+                            other => word.extend_from_slice(&[b'\\', other]),
+                        }
+                    } else {
+                        if bytes[pos] == b'\n' {
+                            line += 1;
+                            line_start = pos + 1;
+                        }
+                        word.push(bytes[pos]);
+                    }
+                    pos += 1;
+                }
+                pos += 1;
+                TokenKind::Word(String::from_utf8_lossy(&word).to_string())
             }
             _ => {
                 let start = pos;
@@ -105,10 +278,10 @@ fn tokenize(input: &str) -> Vec<Token> {
                 {
                     pos += 1;
                 }
-                let word = String::from_utf8_lossy(&bytes[start..pos]).to_string();
-                tokens.push(Token::Word(word));
+                TokenKind::Word(String::from_utf8_lossy(&bytes[start..pos]).to_string())
             }
-        }
+        };
+        tokens.push(Token { kind, line, column });
     }
     tokens
 }
@@ -118,38 +291,69 @@ struct ConfigNode {
     command: String,
     args: Vec<String>,
     children: Vec<ConfigNode>,
+    is_block: bool,
+    pos: ConfigPosition,
+}
+
+fn syntax_error(file: &str, token: &Token, message: String) -> ConfigError {
+    ConfigError::Syntax {
+        message,
+        pos: ConfigPosition::new(file, token.line, token.column),
+    }
 }
 
-fn parse_tokens(tokens: &[Token], mut pos: usize) -> Result<(Vec<ConfigNode>, usize), ConfigError> {
+fn parse_tokens(
+    tokens: &[Token],
+    mut pos: usize,
+    depth: usize,
+    file: &str,
+) -> Result<(Vec<ConfigNode>, usize), ConfigError> {
     let mut nodes = Vec::new();
     while pos < tokens.len() {
-        match &tokens[pos] {
-            Token::RBrace => {
-                pos += 1;
-                break;
+        let token = &tokens[pos];
+        match &token.kind {
+            TokenKind::RBrace => {
+                if depth == 0 {
+                    return Err(syntax_error(file, token, "unexpected \"}\"".into()));
+                }
+                return Ok((nodes, pos + 1));
             }
-            Token::Word(cmd) => {
+            TokenKind::Word(cmd) => {
                 pos += 1;
                 let mut args = Vec::new();
                 let mut children = Vec::new();
-                while pos < tokens.len() {
-                    match &tokens[pos] {
-                        Token::Semicolon => {
+                let mut is_block = false;
+                loop {
+                    let Some(next) = tokens.get(pos) else {
+                        return Err(syntax_error(
+                            file,
+                            token,
+                            format!("directive \"{}\" is not terminated by \";\"", cmd),
+                        ));
+                    };
+                    match &next.kind {
+                        TokenKind::Semicolon => {
                             pos += 1;
                             break;
                         }
-                        Token::LBrace => {
-                            pos += 1;
-                            let (child_nodes, new_pos) = parse_tokens(tokens, pos)?;
+                        TokenKind::LBrace => {
+                            let (child_nodes, new_pos) =
+                                parse_tokens(tokens, pos + 1, depth + 1, file)?;
                             children = child_nodes;
+                            is_block = true;
                             pos = new_pos;
                             break;
                         }
-                        Token::RBrace => {
-                            break;
+                        TokenKind::RBrace => {
+                            return Err(syntax_error(
+                                file,
+                                token,
+                                format!("directive \"{}\" is not terminated by \";\"", cmd),
+                            ));
                         }
-                        Token::Word(arg) => {
-                            args.push(arg.clone());
+                        TokenKind::Word(arg) => {
+                            let arg_pos = ConfigPosition::new(file, next.line, next.column);
+                            args.push(substitute_env_vars(arg, &arg_pos)?);
                             pos += 1;
                         }
                     }
@@ -158,41 +362,171 @@ fn parse_tokens(tokens: &[Token], mut pos: usize) -> Result<(Vec<ConfigNode>, us
                     command: cmd.clone(),
                     args,
                     children,
+                    is_block,
+                    pos: ConfigPosition::new(file, token.line, token.column),
                 });
             }
-            Token::LBrace => {
-                pos += 1;
-                let (child_nodes, new_pos) = parse_tokens(tokens, pos)?;
-                nodes.extend(child_nodes);
-                pos = new_pos;
+            TokenKind::LBrace => {
+                return Err(syntax_error(file, token, "unexpected \"{\"".into()));
             }
-            Token::Semicolon => {
-                pos += 1;
+            TokenKind::Semicolon => {
+                return Err(syntax_error(file, token, "unexpected \";\"".into()));
             }
         }
     }
+    if depth > 0 {
+        let pos = tokens
+            .last()
+            .map(|t| ConfigPosition::new(file, t.line, t.column))
+            .unwrap_or_else(|| ConfigPosition::new(file, 1, 1));
+        return Err(ConfigError::Syntax {
+            message: "unexpected end of file, expecting \"}\"".into(),
+            pos,
+        });
+    }
     Ok((nodes, pos))
 }
 
+fn substitute_env_vars(word: &str, pos: &ConfigPosition) -> Result<String, ConfigError> {
+    let mut result = String::with_capacity(word.len());
+    let mut rest = word;
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| ConfigError::Syntax {
+            message: format!("unterminated \"${{\" in \"{}\"", word),
+            pos: pos.clone(),
+        })?;
+        let expr = &after[..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(ConfigError::Syntax {
+                message: format!("invalid environment variable name \"{}\"", name),
+                pos: pos.clone(),
+            });
+        }
+        match (env::var(name), default) {
+            (Ok(value), _) if !value.is_empty() => result.push_str(&value),
+            (_, Some(default)) => result.push_str(default),
+            (Ok(value), None) => result.push_str(&value),
+            (Err(_), None) => {
+                return Err(ConfigError::UndefinedEnvVar {
+                    name: name.to_string(),
+                    pos: Some(pos.clone()),
+                })
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn validate_nodes(nodes: &[ConfigNode], parent: &str) -> Result<(), ConfigError> {
+    for node in nodes {
+        let pos = Some(node.pos.clone());
+        let Some(cmd) = get_command(&node.command) else {
+            return Err(ConfigError::UnknownDirective {
+                name: node.command.clone(),
+                pos,
+            });
+        };
+        if !cmd.allowed_parents.iter().any(|p| p == parent) {
+            return Err(ConfigError::NotAllowedHere {
+                name: node.command.clone(),
+                pos,
+            });
+        }
+        if cmd.is_block && !node.is_block {
+            return Err(ConfigError::Syntax {
+                message: format!("directive \"{}\" has no opening \"{{\"", node.command),
+                pos: node.pos.clone(),
+            });
+        }
+        if !cmd.is_block && node.is_block {
+            return Err(ConfigError::Syntax {
+                message: format!("directive \"{}\" is not terminated by \";\"", node.command),
+                pos: node.pos.clone(),
+            });
+        }
+        if !cmd.arity.accepts(node.args.len()) {
+            return Err(ConfigError::InvalidArgCount {
+                name: node.command.clone(),
+                pos,
+            });
+        }
+        cmd.parse_args(&node.args)
+            .map_err(|(value, reason)| ConfigError::InvalidValue {
+                name: node.command.clone(),
+                value,
+                reason,
+                pos: pos.clone(),
+            })?;
+        if cmd.raw_block {
+            if let Some(child) = node.children.iter().find(|child| child.is_block) {
+                return Err(ConfigError::Syntax {
+                    message: "unexpected \"{\"".into(),
+                    pos: child.pos.clone(),
+                });
+            }
+            continue;
+        }
+        validate_nodes(&node.children, &node.command)?;
+    }
+    Ok(())
+}
+
+fn raw_entries(nodes: &[ConfigNode]) -> Vec<RawEntry> {
+    nodes
+        .iter()
+        .map(|node| RawEntry {
+            args: std::iter::once(node.command.clone())
+                .chain(node.args.iter().cloned())
+                .collect(),
+            pos: Some(node.pos.clone()),
+        })
+        .collect()
+}
+
 fn nodes_to_json(nodes: &[ConfigNode]) -> Value {
     let mut map = Map::new();
     for node in nodes {
         if let Some(cmd) = get_command(&node.command) {
-            let mut node_json = if cmd.is_block {
-                ConfigManager::get_block_template(&node.command, false).unwrap_or_else(|| json!({}))
-            } else {
-                ConfigManager::get_block_template(&node.command, false).unwrap_or_else(|| json!({}))
-            };
+            let mut node_json = ConfigManager::get_block_template(&node.command, false)
+                .unwrap_or_else(|| json!({}));
 
             if let Some(Value::Array(arr)) = node_json.get_mut("params") {
                 for (i, arg) in node.args.iter().enumerate() {
+                    if i >= arr.len() && cmd.arity.is_variadic() {
+                        if let Some(mut extra) = arr.last().cloned() {
+                            extra["index"] = json!(i);
+                            arr.push(extra);
+                        }
+                    }
                     if let Some(param_obj) = arr.get_mut(i).and_then(|v| v.as_object_mut()) {
                         param_obj.insert("value".to_string(), Value::String(arg.clone()));
                     }
                 }
             }
 
-            if !node.children.is_empty() {
+            node_json
+                .as_object_mut()
+                .unwrap()
+                .insert("position".to_string(), node.pos.to_json());
+
+            if cmd.raw_block {
+                let entries = raw_entries(&node.children)
+                    .iter()
+                    .map(RawEntry::to_json)
+                    .collect();
+                node_json
+                    .as_object_mut()
+                    .unwrap()
+                    .insert("entries".to_string(), Value::Array(entries));
+            } else if !node.children.is_empty() {
                 let children_json = nodes_to_json(&node.children);
                 node_json
                     .as_object_mut()
@@ -233,6 +567,81 @@ fn extract_args(obj: &Map<String, Value>) -> Vec<String> {
     }
 }
 
+fn extract_position(obj: &Map<String, Value>) -> Option<ConfigPosition> {
+    obj.get("position").and_then(ConfigPosition::from_json)
+}
+
+fn extract_entries(obj: &Map<String, Value>) -> Vec<RawEntry> {
+    match obj.get("entries") {
+        Some(Value::Array(entries)) => entries.iter().filter_map(RawEntry::from_json).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn prepare_command(
+    cmd: &Command,
+    key: &str,
+    obj: &Map<String, Value>,
+    ctx: &mut ConfigContext,
+) -> Result<(), ConfigError> {
+    let mut args = extract_args(obj);
+    while args.len() > 1 && args.last().is_some_and(|arg| arg.is_empty()) {
+        args.pop();
+    }
+    let pos = extract_position(obj);
+    let values = if args.iter().all(|arg| arg.is_empty()) {
+        Vec::new()
+    } else {
+        cmd.parse_args(&args)
+            .map_err(|(value, reason)| ConfigError::InvalidValue {
+                name: key.to_string(),
+                value,
+                reason,
+                pos: pos.clone(),
+            })?
+    };
+    ctx.current_cmd_name = key.to_string();
+    ctx.current_cmd_args = args;
+    ctx.current_cmd_values = values;
+    ctx.current_cmd_pos = pos;
+    ctx.raw_entries = extract_entries(obj);
+    Ok(())
+}
+
+fn process_block_instance(
+    cmd: &Command,
+    key: &str,
+    item: &Value,
+    parent_ctx: &mut ConfigContext,
+) -> Result<(), ConfigError> {
+    let Value::Object(obj) = item else {
+        return Ok(());
+    };
+    if is_unset_block(cmd, obj) {
+        return Ok(());
+    }
+    let mut child_ctx = ConfigContext::new_empty(key, extract_args(obj));
+    child_ctx.position = extract_position(obj);
+    prepare_command(cmd, key, obj, &mut child_ctx)?;
+    cmd.handle(&mut child_ctx, item)?;
+    if let Some(children) = obj.get("children") {
+        process_final_config(children, &mut child_ctx)?;
+    }
+    parent_ctx.children.push(child_ctx);
+    Ok(())
+}
+
+/// Template instances of blocks that take required arguments, such as
+/// `location` or `map`, or of raw blocks like `types` carry only empty
+/// defaults and must not be created.
+fn is_unset_block(cmd: &Command, obj: &Map<String, Value>) -> bool {
+    if !extract_args(obj).iter().all(|arg| arg.is_empty()) {
+        return false;
+    }
+    cmd.params.iter().any(|param| param.is_required)
+        || (cmd.raw_block && !obj.contains_key("position") && extract_entries(obj).is_empty())
+}
+
 fn process_block_command(
     cmd: Arc<Command>,
     key: &String,
@@ -241,14 +650,8 @@ fn process_block_command(
 ) -> Result<(), ConfigError> {
     if cmd.unique {
         match value {
-            Value::Object(obj) => {
-                let args = extract_args(obj);
-                let mut child_ctx = ConfigContext::new_empty(key, args);
-                cmd.handle(&mut child_ctx, value);
-                if let Some(children) = obj.get("children") {
-                    process_final_config(children, &mut child_ctx)?;
-                }
-                parent_ctx.children.push(child_ctx);
+            Value::Object(_) => {
+                process_block_instance(&cmd, key, value, parent_ctx)?;
             }
             Value::Array(arr) => {
                 if arr.len() != 1 {
@@ -257,15 +660,7 @@ fn process_block_command(
                         key
                     )));
                 }
-                if let Some(Value::Object(obj)) = arr.first() {
-                    let args = extract_args(obj);
-                    let mut child_ctx = ConfigContext::new_empty(key, args);
-                    cmd.handle(&mut child_ctx, arr.first().unwrap());
-                    if let Some(children) = obj.get("children") {
-                        process_final_config(children, &mut child_ctx)?;
-                    }
-                    parent_ctx.children.push(child_ctx);
-                }
+                process_block_instance(&cmd, key, &arr[0], parent_ctx)?;
             }
             _ => {
                 return Err(ConfigError::ValidationError(format!(
@@ -276,15 +671,7 @@ fn process_block_command(
         }
     } else if let Value::Array(arr) = value {
         for item in arr {
-            if let Value::Object(obj) = item {
-                let args = extract_args(obj);
-                let mut child_ctx = ConfigContext::new_empty(key, args);
-                cmd.handle(&mut child_ctx, item);
-                if let Some(children) = obj.get("children") {
-                    process_final_config(children, &mut child_ctx)?;
-                }
-                parent_ctx.children.push(child_ctx);
-            }
+            process_block_instance(&cmd, key, item, parent_ctx)?;
         }
     } else {
         return Err(ConfigError::ValidationError(format!(
@@ -295,6 +682,26 @@ fn process_block_command(
     Ok(())
 }
 
+fn process_directive(
+    cmd: &Command,
+    key: &str,
+    item: &Value,
+    parent_ctx: &mut ConfigContext,
+) -> Result<(), ConfigError> {
+    let Value::Object(obj) = item else {
+        return Ok(());
+    };
+    if extract_args(obj).iter().all(|arg| arg.is_empty()) {
+        return Ok(()); // Skip commands with no arguments
+    }
+    prepare_command(cmd, key, obj, parent_ctx)?;
+    cmd.handle(parent_ctx, item)?;
+    if let Some(children) = obj.get("children") {
+        process_final_config(children, parent_ctx)?;
+    }
+    Ok(())
+}
+
 fn process_non_block_command(
     cmd: Arc<Command>,
     key: &String,
@@ -302,37 +709,26 @@ fn process_non_block_command(
     parent_ctx: &mut ConfigContext,
 ) -> Result<(), ConfigError> {
     match value {
-        Value::Object(obj) => {
-            let args = extract_args(obj);
-            if args.iter().all(|arg| arg.is_empty()) {
-                return Ok(()); // Skip commands with no arguments
-            }
-            parent_ctx.current_cmd_name = key.clone();
-            parent_ctx.current_cmd_args = args;
-            cmd.handle(parent_ctx, value);
-            if let Some(children) = obj.get("children") {
-                process_final_config(children, parent_ctx)?;
+        Value::Object(_) => {
+            process_directive(&cmd, key, value, parent_ctx)?;
+        }
+        Value::Array(arr) if cmd.repeatable => {
+            for item in arr {
+                process_directive(&cmd, key, item, parent_ctx)?;
             }
         }
         Value::Array(arr) => {
             if arr.len() != 1 {
-                return Err(ConfigError::ValidationError(format!(
-                    "Non-unique command {} must be in an array with one element",
-                    key
-                )));
-            }
-            if let Some(Value::Object(obj)) = arr.first() {
-                let args = extract_args(obj);
-                if args.iter().all(|arg| arg.is_empty()) {
-                    return Ok(()); // Skip commands with no arguments
-                }
-                parent_ctx.current_cmd_name = key.clone();
-                parent_ctx.current_cmd_args = args;
-                cmd.handle(parent_ctx, arr.first().unwrap());
-                if let Some(children) = obj.get("children") {
-                    process_final_config(children, parent_ctx)?;
-                }
+                return Err(ConfigError::Syntax {
+                    message: format!("\"{}\" directive is duplicate", key),
+                    pos: arr
+                        .get(1)
+                        .and_then(|v| v.as_object())
+                        .and_then(extract_position)
+                        .unwrap_or_default(),
+                });
             }
+            process_directive(&cmd, key, &arr[0], parent_ctx)?;
         }
         _ => {
             return Err(ConfigError::ValidationError(format!(
@@ -346,34 +742,39 @@ fn process_non_block_command(
 
 fn process_final_config(config: &Value, parent_ctx: &mut ConfigContext) -> Result<(), ConfigError> {
     if let Value::Object(map) = config {
+        let mut commands = Vec::new();
         for (key, value) in map {
             if let Some(cmd) = get_command(key) {
                 if cmd.is_block {
-                    process_block_command(cmd, key, value, parent_ctx)?;
+                    process_block_command(Arc::clone(&cmd), key, value, parent_ctx)?;
                 } else {
-                    process_non_block_command(cmd, key, value, parent_ctx)?;
+                    process_non_block_command(Arc::clone(&cmd), key, value, parent_ctx)?;
                 }
+                commands.push(cmd);
             } else {
-                return Err(ConfigError::ValidationError(format!(
-                    "Unknown command: {}",
-                    key
-                )));
+                return Err(ConfigError::UnknownDirective {
+                    name: key.clone(),
+                    pos: None,
+                });
             }
         }
+        for cmd in commands {
+            cmd.end_block(parent_ctx)?;
+        }
     }
     Ok(())
 }
 
-pub fn load_config(
+pub fn resolve_config(
     storage_path: &str,
     config_file: Option<&str>,
     top_blocks: Vec<String>,
-) -> Result<ConfigContext, ConfigError> {
+) -> Result<Value, ConfigError> {
     let complete_template =
         ConfigManager::get_complete_template(top_blocks).map_err(ConfigError::ValidationError)?;
 
     let file_config = if let Some(path) = config_file {
-        Some(parse_nginx_config(path)?)
+        Some(nodes_to_json(&parse_nginx_config(path)?))
     } else {
         None
     };
@@ -393,12 +794,213 @@ pub fn load_config(
         json!({})
     };
 
-    let final_config = merge_config(&complete_template, &user_config);
+    Ok(merge_config(&complete_template, &user_config))
+}
+
+pub fn load_config(
+    storage_path: &str,
+    config_file: Option<&str>,
+    top_blocks: Vec<String>,
+) -> Result<ConfigContext, ConfigError> {
+    let final_config = resolve_config(storage_path, config_file, top_blocks)?;
 
-    fs::write(storage_path, serde_json::to_string_pretty(&final_config)?)?;
+    fs::write(
+        storage_path,
+        serde_json::to_string_pretty(&strip_positions(&final_config))?,
+    )?;
 
     let mut root_ctx = ConfigContext::new_empty("root", vec![]);
     process_final_config(&final_config, &mut root_ctx)?;
 
     Ok(root_ctx)
 }
+
+fn strip_positions(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(k, _)| k.as_str() != "position")
+                .map(|(k, v)| (k.clone(), strip_positions(v)))
+                .collect(),
+        ),
+        Value::Array(arr) => Value::Array(arr.iter().map(strip_positions).collect()),
+        other => other.clone(),
+    }
+}
+
+pub fn render_config(config: &Value) -> String {
+    let mut out = String::new();
+    if let Value::Object(map) = config {
+        render_directives(map, 0, &mut out);
+    }
+    out
+}
+
+fn render_directives(map: &Map<String, Value>, depth: usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+    for (name, value) in map {
+        let cmd = get_command(name);
+        let is_block = cmd.as_ref().is_some_and(|cmd| cmd.is_block);
+        let items = match value {
+            Value::Array(arr) => arr.iter().collect::<Vec<_>>(),
+            other => vec![other],
+        };
+        for item in items {
+            let Value::Object(obj) = item else {
+                continue;
+            };
+            let args = extract_args(obj);
+            if !is_block && args.iter().all(|arg| arg.is_empty()) {
+                continue;
+            }
+            if is_block && cmd.as_ref().is_some_and(|cmd| is_unset_block(cmd, obj)) {
+                continue;
+            }
+            let mut line = format!("{}{}", indent, name);
+            for arg in args.iter().filter(|arg| !arg.is_empty()) {
+                line.push(' ');
+                line.push_str(&quote_arg(arg));
+            }
+            if is_block {
+                out.push_str(&line);
+                out.push_str(" {\n");
+                if let Some(Value::Object(children)) = obj.get("children") {
+                    render_directives(children, depth + 1, out);
+                }
+                for entry in extract_entries(obj) {
+                    let args: Vec<_> = entry.args.iter().map(|arg| quote_arg(arg)).collect();
+                    out.push_str(&format!("{}    {};\n", indent, args.join(" ")));
+                }
+                out.push_str(&indent);
+                out.push_str("}\n");
+            } else {
+                out.push_str(&line);
+                out.push_str(";\n");
+            }
+        }
+    }
+}
+
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '{' | '}' | ';' | '"' | '\'' | '#'))
+    {
+        return arg.to_string();
+    }
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Vec<ConfigNode>, ConfigError> {
+        let tokens = tokenize(input);
+        parse_tokens(&tokens, 0, 0, "test.conf").map(|(nodes, _)| nodes)
+    }
+
+    #[test]
+    fn test_parse_tracks_positions() {
+        let nodes = parse("http {\n  server {\n    listen 8080;\n  }\n}\n").unwrap();
+        let server = &nodes[0].children[0];
+        assert_eq!(server.pos, ConfigPosition::new("test.conf", 2, 3));
+        assert_eq!(
+            server.children[0].pos,
+            ConfigPosition::new("test.conf", 3, 5)
+        );
+        assert_eq!(server.children[0].args, vec!["8080".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_reports_unbalanced_braces() {
+        match parse("http {\n  server {\n  }\n") {
+            Err(ConfigError::Syntax { pos, .. }) => assert_eq!(pos.line, 3),
+            _ => panic!("expected parse error"),
+        }
+        match parse("listen 8080\n}") {
+            Err(ConfigError::Syntax { pos, .. }) => assert_eq!(pos.line, 1),
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn test_env_vars_are_substituted() {
+        env::set_var("BLUR_TEST_LISTEN_HOST", "10.0.0.1");
+        env::remove_var("BLUR_TEST_UNSET_PORT");
+        let pos = ConfigPosition::new("test.conf", 1, 8);
+
+        assert_eq!(
+            substitute_env_vars(
+                "${BLUR_TEST_LISTEN_HOST}:${BLUR_TEST_UNSET_PORT:-8080}",
+                &pos
+            )
+            .unwrap(),
+            "10.0.0.1:8080"
+        );
+        assert_eq!(substitute_env_vars("plain", &pos).unwrap(), "plain");
+        match substitute_env_vars("${BLUR_TEST_UNSET_PORT}", &pos) {
+            Err(ConfigError::UndefinedEnvVar { name, .. }) => {
+                assert_eq!(name, "BLUR_TEST_UNSET_PORT")
+            }
+            _ => panic!("expected undefined variable error"),
+        }
+    }
+
+    fn write_temp_config(dir: &Path, name: &str, content: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_include_expands_glob_in_place() {
+        let dir = std::env::temp_dir().join("blur_test_include_glob");
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        write_temp_config(&dir.join("conf.d"), "b.conf", "server_name b;\n");
+        write_temp_config(&dir.join("conf.d"), "a.conf", "server_name a;\n");
+        let main = write_temp_config(
+            &dir,
+            "main.conf",
+            "http {\n  server {\n    include conf.d/*.conf;\n  }\n}\n",
+        );
+
+        let nodes = parse_nginx_config(&main).unwrap();
+        let server = &nodes[0].children[0];
+        let names: Vec<_> = server.children.iter().map(|n| n.args[0].clone()).collect();
+        assert_eq!(names, vec!["a".to_string(), "b".to_string()]);
+        assert!(server.children[0].pos.file.ends_with("a.conf"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_rejects_recursion() {
+        let dir = std::env::temp_dir().join("blur_test_include_recursive");
+        fs::create_dir_all(&dir).unwrap();
+        write_temp_config(&dir, "a.conf", "include b.conf;\n");
+        let main = write_temp_config(&dir, "b.conf", "include a.conf;\n");
+
+        match parse_nginx_config(&main) {
+            Err(ConfigError::Syntax { message, pos }) => {
+                assert!(message.contains("recursive include"));
+                assert!(pos.file.ends_with("a.conf"));
+            }
+            _ => panic!("expected recursive include error"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_unknown_directive() {
+        let nodes = parse("http {\n  server {\n    bogus on;\n  }\n}\n").unwrap();
+        match validate_nodes(&nodes, "root") {
+            Err(ConfigError::UnknownDirective { name, pos }) => {
+                assert_eq!(name, "bogus");
+                assert_eq!(pos, Some(ConfigPosition::new("test.conf", 3, 5)));
+            }
+            _ => panic!("expected validation error"),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+};
+
+#[derive(Default, Clone)]
+pub struct ContextStore {
+    entries: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl ContextStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: Arc<T>) -> Option<Arc<T>> {
+        self.entries
+            .insert(TypeId::of::<T>(), value)
+            .and_then(|old| old.downcast::<T>().ok())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.entries
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.clone().downcast::<T>().ok())
+    }
+
+    pub fn get_or_insert_with<T, F>(&mut self, init: F) -> Arc<T>
+    where
+        T: Any + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get::<T>() {
+            return value;
+        }
+        let value = Arc::new(init());
+        self.entries.insert(TypeId::of::<T>(), value.clone());
+        value
+    }
+
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.entries.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<Arc<T>> {
+        self.entries
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast::<T>().ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Debug for ContextStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextStore")
+            .field("entries", &self.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_store_is_keyed_by_type() {
+        let mut store = ContextStore::new();
+        store.insert(Arc::new(Mutex::new(String::from("server"))));
+        store.insert(Arc::new(42u16));
+
+        assert_eq!(*store.get::<u16>().unwrap(), 42);
+        store
+            .get::<Mutex<String>>()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .push_str("_ctx");
+        assert_eq!(
+            *store.get::<Mutex<String>>().unwrap().lock().unwrap(),
+            "server_ctx"
+        );
+        assert!(store.get::<u32>().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((idx, unit)) if unit.is_ascii_alphabetic() => {
+            let multiplier = match unit.to_ascii_lowercase() {
+                'k' => 1u64 << 10,
+                'm' => 1u64 << 20,
+                'g' => 1u64 << 30,
+                _ => return Err(format!("unknown size unit \"{}\"", unit)),
+            };
+            (&value[..idx], multiplier)
+        }
+        _ => (value, 1),
+    };
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return Err("expected a size such as \"512\", \"64k\", \"10m\" or \"1g\"".to_string());
+    }
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("size \"{}\" is too large", value))
+}
+
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("expected a duration".to_string());
+    }
+    let mut total_ms: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(
+                "expected a duration such as \"500ms\", \"30s\", \"5m\", \"1h\" or \"1d\""
+                    .to_string(),
+            );
+        }
+        let number = rest[..digits]
+            .parse::<u64>()
+            .map_err(|_| format!("duration \"{}\" is too large", value))?;
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit_ms: u64 = match &rest[..unit_len] {
+            "ms" => 1,
+            "" | "s" => 1_000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            "w" => 604_800_000,
+            other => return Err(format!("unknown duration unit \"{}\"", other)),
+        };
+        rest = &rest[unit_len..];
+        total_ms = number
+            .checked_mul(unit_ms)
+            .and_then(|ms| total_ms.checked_add(ms))
+            .ok_or_else(|| format!("duration \"{}\" is too large", value))?;
+    }
+    Ok(Duration::from_millis(total_ms))
+}
+
+/// Writes a duration the way `parse_duration` reads it, in whole seconds
+/// when there is no fraction.
+pub fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_millis();
+    if ms.is_multiple_of(1000) {
+        format!("{}s", ms / 1000)
+    } else {
+        format!("{}ms", ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512k"), Ok(512 * 1024));
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("10x").is_err());
+        assert!(parse_size("k").is_err());
+        assert!(parse_size("99999999999999999g").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("75"), Ok(Duration::from_secs(75)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+}
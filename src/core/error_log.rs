@@ -0,0 +1,389 @@
+use std::{fmt, io, sync::RwLock};
+
+use chrono::Local;
+use serde_json::{json, Value};
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        log_file::LogFileOptions,
+        log_target::{LogDestination, LogTarget},
+    },
+    register_commands,
+};
+
+register_commands!(CommandBuilder::new("error_log")
+    .allowed_parents(vec!["root".to_string(), "http".to_string()])
+    .display_name("en", "Error Log")
+    .display_name("zh-tw", "錯誤日誌")
+    .desc(
+        "en",
+        "Sets where server messages are written and the least severe level written"
+    )
+    .desc("zh-tw", "設定伺服器訊息的寫入位置與最低寫入等級")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "File the messages are appended to, stderr, syslog:server=address[,facility=name,tag=name,nohostname], or journald"
+            )
+            .desc(
+                "zh-tw",
+                "附加訊息的檔案、stderr、syslog:server=位址[,facility=名稱,tag=名稱,nohostname]，或 journald"
+            )
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Level")
+            .display_name("zh-tw", "等級")
+            .type_name("String")
+            .default("")
+            .desc(
+                "en",
+                "debug, info, notice, warn, error (the default) or crit"
+            )
+            .desc("zh-tw", "debug、info、notice、warn、error（預設）或 crit")
+            .build(),
+        ParameterBuilder::new(2)
+            .display_name("en", "Options")
+            .display_name("zh-tw", "選項")
+            .type_name("String")
+            .default("")
+            .desc(
+                "en",
+                "modules=a,b to only write debug messages from those modules, format=json to write JSON lines, rotate_size=size, rotate_time=time and rotate_keep=n to rotate the file"
+            )
+            .desc(
+                "zh-tw",
+                "modules=a,b 只寫入這些模組的 debug 訊息，format=json 以 JSON 格式逐行寫入，rotate_size=大小、rotate_time=時間 與 rotate_keep=數量 輪替檔案"
+            )
+            .build(),
+    ])
+    .arity(Arity::AtLeast(1))
+    .build(handle_error_log));
+
+/// The level messages go to stderr at until `error_log` is configured, and
+/// when it is not.
+const DEFAULT_LEVEL: Level = Level::Notice;
+
+static ERROR_LOG: RwLock<Option<ErrorLog>> = RwLock::new(None);
+
+/// Severity of a message, from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+    Crit,
+}
+
+impl Level {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "notice" => Ok(Self::Notice),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            "crit" => Ok(Self::Crit),
+            _ => Err("level must be debug, info, notice, warn, error or crit".to_string()),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Notice => "notice",
+            Self::Warn => "warn",
+            Self::Error => "error",
+            Self::Crit => "crit",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLogSpec {
+    pub destination: LogDestination,
+    pub level: Level,
+    /// Modules whose debug messages are written; empty means all of them.
+    pub modules: Vec<String>,
+    pub json: bool,
+    pub file: LogFileOptions,
+}
+
+impl ErrorLogSpec {
+    /// Parses the arguments of `error_log`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (path, rest) = args.split_first().ok_or("missing log file path")?;
+        let mut spec = Self {
+            destination: LogDestination::parse(path)?,
+            level: Level::Error,
+            modules: Vec::new(),
+            json: false,
+            file: LogFileOptions::default(),
+        };
+        for (i, arg) in rest.iter().enumerate() {
+            let Some((name, value)) = arg.split_once('=') else {
+                if i > 0 {
+                    return Err(format!("unknown option \"{}\"", arg));
+                }
+                spec.level = Level::parse(arg)?;
+                continue;
+            };
+            match name {
+                "modules" => {
+                    spec.modules = value
+                        .split(',')
+                        .filter(|module| !module.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                "format" => {
+                    spec.json = match value {
+                        "json" => true,
+                        "text" => false,
+                        _ => return Err("format must be text or json".to_string()),
+                    }
+                }
+                // Messages are written as they come, so only rotation applies.
+                _ if name.starts_with("rotate_") && spec.file.parse_option(name, value)? => {}
+                _ => return Err(format!("unknown option \"{}\"", name)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ErrorLogConfig {
+    pub log: Option<ErrorLogSpec>,
+}
+
+impl MergeConfig for ErrorLogConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.log = self.log.take().or_else(|| parent.log.clone());
+    }
+}
+
+/// The destination of server messages, shared by the whole process.
+pub struct ErrorLog {
+    spec: ErrorLogSpec,
+    target: LogTarget,
+}
+
+impl ErrorLog {
+    pub fn open(spec: ErrorLogSpec) -> io::Result<Self> {
+        let target = spec.destination.open(&spec.file)?;
+        Ok(Self { spec, target })
+    }
+
+    pub fn enabled(&self, level: Level, module: &str) -> bool {
+        if level < self.spec.level {
+            return false;
+        }
+        level > Level::Debug
+            || self.spec.modules.is_empty()
+            || self.spec.modules.iter().any(|name| name == module)
+    }
+
+    /// Writes one message as a line; `module` is the module it came from.
+    pub fn write(&self, level: Level, module: &str, args: fmt::Arguments) {
+        if !self.enabled(level, module) {
+            return;
+        }
+        let now = Local::now();
+        let line = if self.spec.json {
+            let entry: Value = json!({
+                "time": now.format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+                "level": level.as_str(),
+                "pid": std::process::id(),
+                "module": module,
+                "message": args.to_string(),
+            });
+            entry.to_string()
+        } else {
+            format!(
+                "{} [{}] {} {}: {}",
+                now.format("%Y/%m/%d %H:%M:%S"),
+                level.as_str(),
+                std::process::id(),
+                module,
+                args
+            )
+        };
+        self.target.write(level, &line);
+    }
+}
+
+/// Opens the error log a block chain sets, or the default one, and sends
+/// every message written from then on to it.
+pub fn configure(chain: &[&ConfigContext]) -> io::Result<()> {
+    let spec = merged_config::<ErrorLogConfig>(chain)
+        .log
+        .unwrap_or_else(default_spec);
+    let log = ErrorLog::open(spec)?;
+    if let Ok(mut current) = ERROR_LOG.write() {
+        *current = Some(log);
+    }
+    Ok(())
+}
+
+fn default_spec() -> ErrorLogSpec {
+    ErrorLogSpec {
+        destination: LogDestination::Stderr,
+        level: DEFAULT_LEVEL,
+        modules: Vec::new(),
+        json: false,
+        file: LogFileOptions::default(),
+    }
+}
+
+/// Writes a message to the error log; used through the `log_*!` macros,
+/// which pass the module they are called from.
+pub fn log(level: Level, module_path: &str, args: fmt::Arguments) {
+    let module = module_path.rsplit("::").next().unwrap_or(module_path);
+    let Ok(current) = ERROR_LOG.read() else {
+        return;
+    };
+    match &*current {
+        Some(log) => log.write(level, module, args),
+        None if level >= DEFAULT_LEVEL => eprintln!("[{}] {}: {}", level.as_str(), module, args),
+        None => {}
+    }
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Debug,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Info,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_notice {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Notice,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Warn,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Error,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+#[macro_export]
+macro_rules! log_crit {
+    ($($arg:tt)+) => {
+        $crate::core::error_log::log(
+            $crate::core::error_log::Level::Crit,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+pub fn handle_error_log(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let spec =
+        ErrorLogSpec::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    if let Ok(mut config) = ctx.block_config::<ErrorLogConfig>().lock() {
+        config.log = Some(spec);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_levels_and_debug_modules() {
+        let path = std::env::temp_dir().join(format!("blur-error-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let args = [
+            path.to_string_lossy().to_string(),
+            "debug".to_string(),
+            "modules=http_proxy".to_string(),
+        ];
+        let log = ErrorLog::open(ErrorLogSpec::parse(&args).unwrap()).unwrap();
+        log.write(Level::Debug, "http_proxy", format_args!("picked {}", 1));
+        log.write(Level::Debug, "http_server", format_args!("accepted"));
+        log.write(Level::Warn, "http_server", format_args!("slow"));
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("[debug]"));
+        assert!(lines[0].ends_with("http_proxy: picked 1"), "{}", lines[0]);
+        assert!(lines[1].contains("[warn]") && lines[1].ends_with("http_server: slow"));
+        drop(log);
+        let _ = fs::remove_file(&path);
+
+        let args = [
+            path.to_string_lossy().to_string(),
+            "format=json".to_string(),
+        ];
+        let log = ErrorLog::open(ErrorLogSpec::parse(&args).unwrap()).unwrap();
+        log.write(Level::Warn, "http_proxy", format_args!("ignored"));
+        log.write(Level::Crit, "http_proxy", format_args!("disk \"full\""));
+        let written = fs::read_to_string(&path).unwrap();
+        let entry: Value = serde_json::from_str(written.trim_end()).unwrap();
+        assert_eq!(entry["level"], "crit");
+        assert_eq!(entry["module"], "http_proxy");
+        assert_eq!(entry["message"], "disk \"full\"");
+        let _ = fs::remove_file(&path);
+
+        assert!(ErrorLogSpec::parse(&["stderr".to_string(), "loud".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Longest-prefix-match radix trie keyed by IPv4 and IPv6 networks.
+#[derive(Debug, Clone)]
+pub struct IpTrie<T> {
+    v4: TrieNode<T>,
+    v6: TrieNode<T>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct TrieNode<T> {
+    children: [Option<Box<TrieNode<T>>>; 2],
+    value: Option<T>,
+}
+
+impl<T> Default for TrieNode<T> {
+    fn default() -> Self {
+        Self {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+impl<T> Default for IpTrie<T> {
+    fn default() -> Self {
+        Self {
+            v4: TrieNode::default(),
+            v6: TrieNode::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> IpTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Inserts a network, replacing and returning any value stored for the
+    /// exact same prefix.
+    pub fn insert(&mut self, addr: IpAddr, prefix_len: u8, value: T) -> Option<T> {
+        let (mut node, bits, max, prefix_len) = self.root_mut(addr, prefix_len);
+        for i in 0..prefix_len.min(max) {
+            let bit = bit_at(bits, i, max);
+            node = node.children[bit].get_or_insert_with(Box::default);
+        }
+        let old = node.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, addr: IpAddr, prefix_len: u8) -> Option<T> {
+        let (mut node, bits, max, prefix_len) = self.root_mut(addr, prefix_len);
+        for i in 0..prefix_len.min(max) {
+            node = node.children[bit_at(bits, i, max)].as_deref_mut()?;
+        }
+        let old = node.value.take();
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+
+    /// Returns the value of the most specific network containing `addr`.
+    pub fn lookup(&self, addr: IpAddr) -> Option<&T> {
+        let addr = canonical(addr);
+        let (mut node, bits, max) = match addr {
+            IpAddr::V4(v4) => (&self.v4, u32::from(v4) as u128, 32),
+            IpAddr::V6(v6) => (&self.v6, u128::from(v6), 128),
+        };
+        let mut best = node.value.as_ref();
+        for i in 0..max {
+            match node.children[bit_at(bits, i, max)].as_deref() {
+                Some(child) => node = child,
+                None => break,
+            }
+            if node.value.is_some() {
+                best = node.value.as_ref();
+            }
+        }
+        best
+    }
+
+    fn root_mut(&mut self, addr: IpAddr, prefix_len: u8) -> (&mut TrieNode<T>, u128, u8, u8) {
+        match (addr, canonical(addr)) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) => (
+                &mut self.v4,
+                u32::from(v4) as u128,
+                32,
+                prefix_len.saturating_sub(96),
+            ),
+            (_, IpAddr::V4(v4)) => (&mut self.v4, u32::from(v4) as u128, 32, prefix_len),
+            (_, IpAddr::V6(v6)) => (&mut self.v6, u128::from(v6), 128, prefix_len),
+        }
+    }
+}
+
+fn bit_at(bits: u128, index: u8, width: u8) -> usize {
+    ((bits >> (width - 1 - index)) & 1) as usize
+}
+
+/// Treats IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) as plain IPv4.
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+/// Parses `addr` or `addr/prefix` into a network address and prefix length.
+pub fn parse_cidr(value: &str) -> Result<(IpAddr, u8), String> {
+    let (addr, prefix) = match value.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (value, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("invalid IP address \"{}\"", addr))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix {
+        Some(prefix) => prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= max)
+            .ok_or_else(|| format!("invalid prefix length \"{}\"", prefix))?,
+        None => max,
+    };
+    Ok((mask(addr, prefix_len), prefix_len))
+}
+
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let mut trie = IpTrie::new();
+        for (cidr, value) in [
+            ("0.0.0.0/0", "any"),
+            ("10.0.0.0/8", "internal"),
+            ("10.1.0.0/16", "office"),
+            ("2001:db8::/32", "v6"),
+        ] {
+            let (addr, len) = parse_cidr(cidr).unwrap();
+            trie.insert(addr, len, value);
+        }
+
+        let lookup = |ip: &str| trie.lookup(ip.parse().unwrap()).copied();
+        assert_eq!(lookup("10.1.2.3"), Some("office"));
+        assert_eq!(lookup("10.2.0.1"), Some("internal"));
+        assert_eq!(lookup("8.8.8.8"), Some("any"));
+        assert_eq!(lookup("::ffff:10.1.0.1"), Some("office"));
+        assert_eq!(lookup("2001:db8::1"), Some("v6"));
+        assert_eq!(lookup("2001:db9::1"), None);
+    }
+
+    #[test]
+    fn test_parse_cidr_masks_host_bits() {
+        assert_eq!(
+            parse_cidr("192.168.1.77/24").unwrap(),
+            ("192.168.1.0".parse().unwrap(), 24)
+        );
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("nope").is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::config::units::{parse_duration, parse_size};
+
+/// Buffer size used when `flush=` is given without `buffer=`.
+const DEFAULT_BUFFER: usize = 64 * 1024;
+
+/// How often the signal watcher checks for a reopen request.
+const REOPEN_POLL: Duration = Duration::from_millis(200);
+
+/// Rotated files kept when `rotate_keep=` is not given.
+const DEFAULT_KEEP: usize = 7;
+
+/// Log files open across the whole process by path, so that every log
+/// naming the same file shares one handle and one buffer.
+static OPEN_LOGS: LazyLock<Mutex<HashMap<PathBuf, Weak<LogFile>>>> = LazyLock::new(Mutex::default);
+
+/// How a log file is buffered and rotated.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogFileOptions {
+    /// Bytes of lines held in memory before they are written; 0 writes
+    /// each line as it comes.
+    pub buffer: usize,
+    pub flush: Option<Duration>,
+    /// Size past which the file is rotated.
+    pub rotate_size: Option<u64>,
+    /// Age past which the file is rotated, counted from when it was opened.
+    pub rotate_time: Option<Duration>,
+    /// Rotated files kept as `name.1` (the newest) to `name.N`.
+    pub rotate_keep: Option<usize>,
+}
+
+impl LogFileOptions {
+    /// Applies a `name=value` option, returning false for names that are
+    /// not log file options.
+    pub fn parse_option(&mut self, name: &str, value: &str) -> Result<bool, String> {
+        match name {
+            "buffer" => self.buffer = parse_size(value)? as usize,
+            "flush" => {
+                let flush = parse_duration(value)?;
+                if flush.is_zero() {
+                    return Err("flush must be greater than zero".to_string());
+                }
+                self.flush = Some(flush);
+                if self.buffer == 0 {
+                    self.buffer = DEFAULT_BUFFER;
+                }
+            }
+            "rotate_size" => self.rotate_size = Some(parse_size(value)?).filter(|size| *size > 0),
+            "rotate_time" => {
+                self.rotate_time = Some(parse_duration(value)?).filter(|time| !time.is_zero())
+            }
+            "rotate_keep" => {
+                let keep = value
+                    .parse()
+                    .map_err(|_| "rotate_keep must be a number".to_string())?;
+                self.rotate_keep = Some(keep);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug)]
+struct LogState {
+    writer: BufWriter<File>,
+    /// Bytes in the file, including those still buffered.
+    size: u64,
+    opened: Instant,
+}
+
+/// An open log file. Lines are appended whole under a lock, and buffered
+/// ones are written when the buffer fills, by the flusher thread every
+/// `flush=` interval, and when the last log using the file goes.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    options: LogFileOptions,
+    state: Mutex<LogState>,
+}
+
+impl LogFile {
+    /// Opens the file at `path`, or returns the one already open there; a
+    /// file opened earlier keeps its own options.
+    pub fn open(path: &Path, options: &LogFileOptions) -> io::Result<Arc<Self>> {
+        let mut open = OPEN_LOGS
+            .lock()
+            .map_err(|_| io::Error::other("log file registry poisoned"))?;
+        open.retain(|_, log| log.strong_count() > 0);
+        if let Some(log) = open.get(path).and_then(Weak::upgrade) {
+            return Ok(log);
+        }
+
+        let log = Arc::new(Self {
+            path: path.to_path_buf(),
+            options: options.clone(),
+            state: Mutex::new(open_state(path, options.buffer)?),
+        });
+        open.insert(path.to_path_buf(), Arc::downgrade(&log));
+
+        if let Some(interval) = options.flush {
+            let weak = Arc::downgrade(&log);
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                match weak.upgrade() {
+                    Some(log) => log.flush(),
+                    None => break,
+                }
+            });
+        }
+        Ok(log)
+    }
+
+    pub fn write_line(&self, line: &[u8]) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let mut result = Ok(());
+        if self.should_rotate(&state, line.len() as u64) {
+            result = self.rotate(&mut state);
+        }
+        if result.is_ok() {
+            result = state.writer.write_all(line);
+            state.size += line.len() as u64;
+        }
+        if result.is_ok() && self.options.buffer == 0 {
+            result = state.writer.flush();
+        }
+        drop(state);
+        if let Err(e) = result {
+            eprintln!("cannot write log file \"{}\": {}", self.path.display(), e);
+        }
+    }
+
+    pub fn flush(&self) {
+        let result = match self.state.lock() {
+            Ok(mut state) => state.writer.flush(),
+            Err(_) => return,
+        };
+        if let Err(e) = result {
+            eprintln!("cannot write log file \"{}\": {}", self.path.display(), e);
+        }
+    }
+
+    /// Writes out buffered lines and opens the path again, so that lines go
+    /// to a new file once the old one has been moved away.
+    pub fn reopen(&self) -> io::Result<()> {
+        let Ok(mut state) = self.state.lock() else {
+            return Ok(());
+        };
+        state.writer.flush()?;
+        *state = open_state(&self.path, self.options.buffer)?;
+        Ok(())
+    }
+
+    fn should_rotate(&self, state: &LogState, incoming: u64) -> bool {
+        let too_large = self
+            .options
+            .rotate_size
+            .is_some_and(|max| state.size > 0 && state.size + incoming > max);
+        let too_old = self
+            .options
+            .rotate_time
+            .is_some_and(|max| state.opened.elapsed() >= max);
+        too_large || too_old
+    }
+
+    /// Renames the file to `name.1`, shifting older ones up and dropping
+    /// those past `rotate_keep`, and starts a new one.
+    fn rotate(&self, state: &mut LogState) -> io::Result<()> {
+        state.writer.flush()?;
+        let keep = self.options.rotate_keep.unwrap_or(DEFAULT_KEEP);
+        let moved = if keep == 0 {
+            fs::remove_file(&self.path)
+        } else {
+            for n in (1..keep).rev() {
+                let from = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))
+        };
+        // Already moved away by someone else; just start a new file.
+        match moved {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        *state = open_state(&self.path, self.options.buffer)?;
+        Ok(())
+    }
+}
+
+fn open_state(path: &Path, buffer: usize) -> io::Result<LogState> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(LogState {
+        size: file.metadata()?.len(),
+        writer: BufWriter::with_capacity(buffer, file),
+        opened: Instant::now(),
+    })
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Reopens every open log file, for when an outside tool such as logrotate
+/// has moved them away.
+pub fn reopen_all() {
+    let logs: Vec<Arc<LogFile>> = match OPEN_LOGS.lock() {
+        Ok(open) => open.values().filter_map(Weak::upgrade).collect(),
+        Err(_) => return,
+    };
+    for log in logs {
+        if let Err(e) = log.reopen() {
+            eprintln!("cannot reopen log file \"{}\": {}", log.path.display(), e);
+        }
+    }
+}
+
+static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reopen(_: libc::c_int) {
+    REOPEN_REQUESTED.store(true, Ordering::Release);
+}
+
+/// Reopens every open log file whenever the process gets `SIGUSR1`. The
+/// signal handler only sets a flag, which a watcher thread picks up.
+pub fn reopen_on_signal() {
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGUSR1,
+            request_reopen as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    thread::spawn(|| loop {
+        thread::sleep(REOPEN_POLL);
+        if REOPEN_REQUESTED.swap(false, Ordering::AcqRel) {
+            reopen_all();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("blur-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let mut options = LogFileOptions::default();
+        assert!(options.parse_option("rotate_size", "10").unwrap());
+        assert!(options.parse_option("rotate_keep", "2").unwrap());
+        assert!(!options.parse_option("gzip", "1").unwrap());
+
+        let log = LogFile::open(&path, &options).unwrap();
+        for line in [
+            "aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeee\n", "ffff\n", "gggg\n",
+        ] {
+            log.write_line(line.as_bytes());
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "gggg\n");
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 1)).unwrap(),
+            "eeee\nffff\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 2)).unwrap(),
+            "cccc\ndddd\n"
+        );
+        assert!(!rotated(&path, 3).exists());
+
+        fs::rename(&path, dir.join("moved.log")).unwrap();
+        log.write_line(b"h\n");
+        reopen_all();
+        log.write_line(b"i\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("moved.log")).unwrap(),
+            "gggg\nh\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "i\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    ffi::CStr,
+    fmt,
+    io::{self, Write},
+    net::{ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::Local;
+
+use super::{
+    error_log::Level,
+    log_file::{LogFile, LogFileOptions},
+};
+
+const SYSLOG_PORT: u16 = 514;
+const DEFAULT_FACILITY: u8 = 23;
+const DEFAULT_TAG: &str = "blur";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "intern", "lpr", "news", "uucp", "clock", "authpriv",
+    "ftp", "ntp", "audit", "alert", "cron", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+/// Where a log writes its lines, as named in `access_log` or `error_log`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogDestination {
+    File(PathBuf),
+    Stderr,
+    Syslog(SyslogServer),
+    Journald { tag: String },
+}
+
+/// The parameters of a `syslog:` destination.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyslogServer {
+    /// `host:port`, or a path for a local `unix:` socket.
+    pub server: SyslogAddress,
+    pub facility: u8,
+    /// Fixed severity for every line; by default access logs use info and
+    /// the error log the level of each message.
+    pub severity: Option<Level>,
+    pub tag: String,
+    pub hostname: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyslogAddress {
+    Udp(String),
+    Unix(PathBuf),
+}
+
+impl LogDestination {
+    /// Parses `stderr`, `syslog:server=address[,option=value...]`,
+    /// `journald[:tag=name]`, or a file path.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "stderr" {
+            return Ok(Self::Stderr);
+        }
+        if let Some(params) = value.strip_prefix("syslog:") {
+            return SyslogServer::parse(params).map(Self::Syslog);
+        }
+        if value == "journald" || value.starts_with("journald:") {
+            let mut tag = DEFAULT_TAG.to_string();
+            for param in value["journald".len()..].trim_start_matches(':').split(',') {
+                match param.split_once('=') {
+                    Some(("tag", value)) if !value.is_empty() => tag = value.to_string(),
+                    _ if param.is_empty() => {}
+                    _ => return Err(format!("unknown journald parameter \"{}\"", param)),
+                }
+            }
+            return Ok(Self::Journald { tag });
+        }
+        Ok(Self::File(PathBuf::from(value)))
+    }
+
+    pub fn open(&self, options: &LogFileOptions) -> io::Result<LogTarget> {
+        Ok(match self {
+            Self::File(path) => LogTarget::File(LogFile::open(path, options)?),
+            Self::Stderr => LogTarget::Stderr,
+            Self::Syslog(server) => LogTarget::Syslog(Arc::new(SyslogSender::connect(server)?)),
+            Self::Journald { tag } => LogTarget::Journald(Arc::new(JournaldSender::connect(tag)?)),
+        })
+    }
+}
+
+impl fmt::Display for LogDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Stderr => f.write_str("stderr"),
+            Self::Syslog(server) => match &server.server {
+                SyslogAddress::Udp(address) => write!(f, "syslog:server={}", address),
+                SyslogAddress::Unix(path) => write!(f, "syslog:server=unix:{}", path.display()),
+            },
+            Self::Journald { .. } => f.write_str("journald"),
+        }
+    }
+}
+
+impl SyslogServer {
+    fn parse(params: &str) -> Result<Self, String> {
+        let mut server = None;
+        let mut facility = DEFAULT_FACILITY;
+        let mut severity = None;
+        let mut tag = DEFAULT_TAG.to_string();
+        let mut hostname = true;
+        for param in params.split(',').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name {
+                "server" => {
+                    server = Some(match value.strip_prefix("unix:") {
+                        Some(path) => SyslogAddress::Unix(PathBuf::from(path)),
+                        None if value.is_empty() => return Err("server is empty".to_string()),
+                        None => SyslogAddress::Udp(with_default_port(value)),
+                    })
+                }
+                "facility" => {
+                    facility = FACILITIES
+                        .iter()
+                        .position(|name| *name == value)
+                        .ok_or_else(|| format!("unknown syslog facility \"{}\"", value))?
+                        as u8
+                }
+                "severity" => severity = Some(Level::parse(value)?),
+                "tag" => {
+                    if value.is_empty()
+                        || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    {
+                        return Err("tag must be letters, digits or underscores".to_string());
+                    }
+                    tag = value.to_string();
+                }
+                "nohostname" => hostname = false,
+                _ => return Err(format!("unknown syslog parameter \"{}\"", name)),
+            }
+        }
+        Ok(Self {
+            server: server.ok_or("syslog requires server=address")?,
+            facility,
+            severity,
+            tag,
+            hostname,
+        })
+    }
+}
+
+fn with_default_port(host: &str) -> String {
+    let has_port = match host.strip_prefix('[') {
+        Some(v6) => v6.contains("]:"),
+        None => host.contains(':'),
+    };
+    if has_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, SYSLOG_PORT)
+    }
+}
+
+/// An open log destination.
+#[derive(Debug, Clone)]
+pub enum LogTarget {
+    File(Arc<LogFile>),
+    Stderr,
+    Syslog(Arc<SyslogSender>),
+    Journald(Arc<JournaldSender>),
+}
+
+impl LogTarget {
+    /// Writes one line, given without its line break, at `level`.
+    pub fn write(&self, level: Level, line: &str) {
+        match self {
+            Self::File(file) => file.write_line(format!("{}\n", line).as_bytes()),
+            Self::Stderr => {
+                let _ = io::stderr().write_all(format!("{}\n", line).as_bytes());
+            }
+            Self::Syslog(sender) => sender.send(level, line),
+            Self::Journald(sender) => sender.send(level, line),
+        }
+    }
+}
+
+/// The syslog severity of a level.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Debug => 7,
+        Level::Info => 6,
+        Level::Notice => 5,
+        Level::Warn => 4,
+        Level::Error => 3,
+        Level::Crit => 2,
+    }
+}
+
+#[derive(Debug)]
+enum SyslogSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Sends lines to a syslog server as RFC 3164 datagrams.
+#[derive(Debug)]
+pub struct SyslogSender {
+    server: SyslogServer,
+    socket: SyslogSocket,
+    hostname: String,
+}
+
+impl SyslogSender {
+    fn connect(server: &SyslogServer) -> io::Result<Self> {
+        let socket = match &server.server {
+            SyslogAddress::Udp(address) => {
+                let target = address.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "syslog server has no address")
+                })?;
+                let local = if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(target)?;
+                SyslogSocket::Udp(socket)
+            }
+            SyslogAddress::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                SyslogSocket::Unix(socket)
+            }
+        };
+        Ok(Self {
+            server: server.clone(),
+            socket,
+            hostname: hostname(),
+        })
+    }
+
+    fn message(&self, level: Level, line: &str) -> String {
+        let severity = severity(self.server.severity.unwrap_or(level));
+        let priority = u16::from(self.server.facility) * 8 + u16::from(severity);
+        let mut message = format!("<{}>{} ", priority, Local::now().format("%b %e %H:%M:%S"));
+        if self.server.hostname {
+            message.push_str(&self.hostname);
+            message.push(' ');
+        }
+        message.push_str(&self.server.tag);
+        message.push_str(": ");
+        message.push_str(line);
+        message
+    }
+
+    fn send(&self, level: Level, line: &str) {
+        let message = self.message(level, line);
+        // Lost lines are not retried, as with any syslog over UDP.
+        let _ = match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes()),
+            SyslogSocket::Unix(socket) => socket.send(message.as_bytes()),
+        };
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and its last byte
+    // stays zero so the result is always terminated.
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Sends lines to the systemd journal over its native protocol.
+#[derive(Debug)]
+pub struct JournaldSender {
+    socket: UnixDatagram,
+    tag: String,
+}
+
+impl JournaldSender {
+    fn connect(tag: &str) -> io::Result<Self> {
+        Self::connect_to(Path::new(JOURNALD_SOCKET), tag)
+    }
+
+    fn connect_to(path: &Path, tag: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            tag: tag.to_string(),
+        })
+    }
+
+    fn entry(&self, level: Level, line: &str) -> Vec<u8> {
+        let mut entry = Vec::new();
+        journal_field(&mut entry, "MESSAGE", line);
+        journal_field(&mut entry, "PRIORITY", &severity(level).to_string());
+        journal_field(&mut entry, "SYSLOG_IDENTIFIER", &self.tag);
+        entry
+    }
+
+    fn send(&self, level: Level, line: &str) {
+        let _ = self.socket.send(&self.entry(level, line));
+    }
+}
+
+/// Appends a `NAME=value` field, or the length-prefixed binary form when
+/// the value spans lines.
+fn journal_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_syslog_and_journald_datagrams() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let address = collector.local_addr().unwrap();
+        let destination = LogDestination::parse(&format!(
+            "syslog:server={},facility=local7,tag=web,nohostname",
+            address
+        ))
+        .unwrap();
+        let target = destination.open(&LogFileOptions::default()).unwrap();
+        target.write(Level::Warn, "upstream failed");
+        let mut buf = [0; 512];
+        let n = collector.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        // local7 (23) * 8 + warning (4)
+        assert!(message.starts_with("<188>"), "{}", message);
+        assert!(message.ends_with(" web: upstream failed"), "{}", message);
+
+        let dir = std::env::temp_dir().join(format!("blur-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = UnixDatagram::bind(dir.join("socket")).unwrap();
+        let sender = JournaldSender::connect_to(&dir.join("socket"), "blur").unwrap();
+        sender.send(Level::Error, "two\nlines");
+        let n = socket.recv(&mut buf).unwrap();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nPRIORITY=3\nSYSLOG_IDENTIFIER=blur\n");
+        assert_eq!(&buf[..n], &expected[..]);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            LogDestination::parse("journald:tag=api").unwrap(),
+            LogDestination::Journald {
+                tag: "api".to_string()
+            }
+        );
+        assert!(LogDestination::parse("syslog:facility=local7").is_err());
+        assert!(LogDestination::parse("syslog:server=10.0.0.1,facility=local9").is_err());
+    }
+}
@@ -1,9 +1,21 @@
+use crate::events::executor::EXECUTOR;
 use crate::http::http_response::get_content_type;
-use crate::http::{http_request::HttpRequest, http_response::HttpResponse};
+use crate::http::{
+    http_error_page::{ErrorPage, ErrorPageStatus, ErrorPages},
+    http_request::{normalize_target, HttpRequest},
+    http_response::{parse_http_date, HttpResponse},
+    http_rewrite::redirect_response,
+    http_static::is_not_modified,
+    http_variables::RequestVariables,
+};
+use crate::{log_debug, log_error};
 use http::{Method, StatusCode, Version};
+use regex::{Regex, RegexBuilder};
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock, Weak};
 use thiserror::Error;
 
 type ProcessorResult<T> = Result<T, ProcessorError>;
@@ -25,6 +37,205 @@ pub trait Processor {
 }
 
 pub type HttpHandler = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static>;
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HttpResponse> + Send + 'static>>;
+
+/// A content handler that can wait on databases or upstreams without
+/// holding an executor thread while it waits; it gets its own copy of the
+/// request so the future can outlive the call.
+pub trait AsyncHandler: Send + Sync {
+    fn call(&self, req: HttpRequest) -> HandlerFuture;
+}
+
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(HttpRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    fn call(&self, req: HttpRequest) -> HandlerFuture {
+        Box::pin(self(req))
+    }
+}
+
+/// Wraps `handler` into an `HttpHandler` whose futures run on the shared
+/// executor while the connection's thread waits for the response, so it can
+/// be used anywhere a synchronous handler can. A future that panics is
+/// answered with 500.
+pub fn async_handler(handler: impl AsyncHandler + 'static) -> HttpHandler {
+    Box::new(move |req| {
+        EXECUTOR
+            .block_on(handler.call(req.clone()))
+            .unwrap_or_else(|| {
+                log_error!("async handler panicked while processing \"{}\"", req.path());
+                HttpProcessor::create_status_response(
+                    req.version(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            })
+    })
+}
+
+/// Upper bound on location re-matches caused by internal redirects such as
+/// `rewrite ... last`.
+pub const MAX_INTERNAL_REDIRECTS: usize = 10;
+
+/// Upper bound on subrequests made from within subrequests, such as SSI
+/// includes that include further documents.
+pub const MAX_SUBREQUEST_DEPTH: usize = 50;
+
+/// The processor of a server, filled in once it is built so the phases and
+/// filters of its locations can send subrequests through it.
+pub type ProcessorSlot = Arc<OnceLock<Weak<HttpProcessor>>>;
+
+pub enum PhaseResult {
+    Continue,
+    Respond(Box<HttpResponse>),
+    /// The request URI changed and the location must be selected again.
+    Restart,
+}
+
+/// A step run on every request before the content handler, at server level
+/// before location matching or inside the selected location.
+pub trait RequestPhase: Send + Sync {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult;
+}
+
+impl<F> RequestPhase for F
+where
+    F: Fn(&mut HttpRequest) -> PhaseResult + Send + Sync,
+{
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        self(req)
+    }
+}
+
+/// A step run on every finished response, including error pages, in the
+/// location that produced it; used to transform the body, e.g. compress it.
+pub trait ResponseFilter: Send + Sync {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse);
+}
+
+impl<F> ResponseFilter for F
+where
+    F: Fn(&HttpRequest, &mut HttpResponse) + Send + Sync,
+{
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        self(req, resp)
+    }
+}
+
+/// Code wrapped around request handling, such as authentication, logging
+/// or tracing: it receives the request and the rest of the chain, and may
+/// answer by itself or pass the request on with `Next::run` and adjust the
+/// response. Global middleware wraps every server, a server's middleware
+/// wraps everything it does including error pages and filters, and a
+/// location's middleware wraps its content handler after its phases.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, req: &mut HttpRequest, next: Next) -> HttpResponse;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut HttpRequest, Next) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, req: &mut HttpRequest, next: Next) -> HttpResponse {
+        self(req, next)
+    }
+}
+
+/// The middleware after the current one, ending with the handling it wraps.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut HttpRequest) -> HttpResponse,
+}
+
+impl<'a> Next<'a> {
+    fn new(
+        middleware: &'a [Arc<dyn Middleware>],
+        endpoint: &'a dyn Fn(&mut HttpRequest) -> HttpResponse,
+    ) -> Self {
+        Self {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// Passes the request on and returns the response produced further in.
+    pub fn run(self, req: &mut HttpRequest) -> HttpResponse {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware.handle(req, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+static GLOBAL_MIDDLEWARE: RwLock<Vec<Arc<dyn Middleware>>> = RwLock::new(Vec::new());
+
+/// Wraps the request handling of every server, including ones configured
+/// afterwards, in `middleware`; global middleware runs outermost, in the
+/// order it was added.
+pub fn add_global_middleware(middleware: impl Middleware + 'static) {
+    if let Ok(mut global) = GLOBAL_MIDDLEWARE.write() {
+        global.push(Arc::new(middleware));
+    }
+}
+
+/// A request the server makes to one of its own locations on behalf of
+/// another, run by `HttpProcessor::subrequest` without a network round
+/// trip. It starts as a copy of the parent addressed to the new URI, with
+/// the parent's method, headers, body and query string.
+pub struct Subrequest {
+    uri: String,
+    req: HttpRequest,
+}
+
+impl Subrequest {
+    pub fn new(parent: &HttpRequest, uri: &str) -> Self {
+        Self {
+            uri: uri.to_string(),
+            req: parent.subrequest(uri),
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.req.set_method(method);
+        self
+    }
+
+    /// Leaves out the body of the parent and the headers describing it.
+    pub fn without_body(mut self) -> Self {
+        self.req.set_body(Vec::new());
+        self.req.remove_header("Content-Length");
+        self.req.remove_header("Transfer-Encoding");
+        self
+    }
+
+    /// Sends the URI as given rather than with the parent's query string.
+    pub fn without_args(mut self) -> Self {
+        self.req.set_path(&self.uri);
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.req.set_header(name, value);
+        self
+    }
+
+    pub fn remove_header(mut self, name: &str) -> Self {
+        self.req.remove_header(name);
+        self
+    }
+
+    pub fn var(mut self, name: &str, value: &str) -> Self {
+        self.req.set_var(name, value);
+        self
+    }
+
+    pub fn request(&self) -> &HttpRequest {
+        &self.req
+    }
+}
+
 pub type PathMapper = dyn Fn(&str) -> Option<String> + Send + Sync + 'static;
 
 #[derive(Default)]
@@ -68,20 +279,237 @@ impl StaticFileConfig {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LocationModifier {
+    #[default]
+    Prefix,
+    Exact,
+    PreferPrefix,
+    Regex,
+    RegexCaseless,
+    Named,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocationPattern {
+    pub modifier: LocationModifier,
+    pub path: String,
+    regex: Option<Regex>,
+}
+
+impl LocationPattern {
+    /// Parses the arguments of a `location` block: `[=|^~|~|~*] uri` or `@name`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (modifier, path) = match args {
+            [path] if path.starts_with('@') => (LocationModifier::Named, path),
+            [path] => match path.strip_prefix('=') {
+                Some(rest) if !rest.is_empty() => {
+                    return Self::parse(&["=".to_string(), rest.to_string()])
+                }
+                _ => (LocationModifier::Prefix, path),
+            },
+            [modifier, path] => {
+                let modifier = match modifier.as_str() {
+                    "=" => LocationModifier::Exact,
+                    "^~" => LocationModifier::PreferPrefix,
+                    "~" => LocationModifier::Regex,
+                    "~*" => LocationModifier::RegexCaseless,
+                    other => return Err(format!("invalid location modifier \"{}\"", other)),
+                };
+                (modifier, path)
+            }
+            _ => return Err("invalid number of arguments".to_string()),
+        };
+        let regex = match modifier {
+            LocationModifier::Regex | LocationModifier::RegexCaseless => Some(
+                RegexBuilder::new(path)
+                    .case_insensitive(modifier == LocationModifier::RegexCaseless)
+                    .build()
+                    .map_err(|e| e.to_string())?,
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            modifier,
+            path: path.to_string(),
+            regex,
+        })
+    }
+
+    pub fn prefix(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex.as_ref()
+    }
+}
+
+struct Location {
+    pattern: LocationPattern,
+    handler: Option<Arc<HttpHandler>>,
+    phases: Vec<Arc<dyn RequestPhase>>,
+    error_pages: Arc<ErrorPages>,
+    filters: Vec<Arc<dyn ResponseFilter>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// Whether request bodies are handed to the handler as a stream.
+    stream_body: bool,
+}
+
 #[derive(Default)]
 pub struct HttpProcessor {
     handlers: HashMap<(String, StatusCode, &'static Method), Arc<HttpHandler>>,
+    locations: Vec<Location>,
+    server_phases: Vec<Arc<dyn RequestPhase>>,
+    error_pages: Arc<ErrorPages>,
+    filters: Vec<Arc<dyn ResponseFilter>>,
+    default_handler: Option<Arc<HttpHandler>>,
     excluded_files: Vec<PathBuf>,
+    middleware: Vec<Arc<dyn Middleware>>,
 }
 
 impl HttpProcessor {
     pub fn new() -> Self {
-        Self {
-            handlers: HashMap::new(),
-            excluded_files: Vec::new(),
+        Self::default()
+    }
+
+    /// Adds a location handler; regex locations are tried in the order they
+    /// were added.
+    pub fn add_location(&mut self, pattern: LocationPattern, handler: HttpHandler) {
+        self.add_location_with_phases(
+            pattern,
+            Some(handler),
+            Vec::new(),
+            Arc::default(),
+            Vec::new(),
+        );
+    }
+
+    pub fn add_location_with_phases(
+        &mut self,
+        pattern: LocationPattern,
+        handler: Option<HttpHandler>,
+        phases: Vec<Arc<dyn RequestPhase>>,
+        error_pages: Arc<ErrorPages>,
+        filters: Vec<Arc<dyn ResponseFilter>>,
+    ) {
+        self.locations.push(Location {
+            pattern,
+            handler: handler.map(Arc::new),
+            phases,
+            error_pages,
+            filters,
+            middleware: Vec::new(),
+            stream_body: false,
+        });
+    }
+
+    /// Has the server hand request bodies for the location added last to
+    /// its handler as a `BodyStream`, read from the client as the handler
+    /// consumes it, instead of buffering them first.
+    pub fn set_stream_body(&mut self) {
+        if let Some(location) = self.locations.last_mut() {
+            location.stream_body = true;
+        }
+    }
+
+    /// Whether the location selected for `req` streams request bodies.
+    pub fn streams_body(&self, req: &HttpRequest) -> bool {
+        let path = req.path().split('?').next().unwrap_or_default();
+        self.find_handler(path, req.method()).is_none()
+            && self
+                .match_location(path)
+                .is_some_and(|location| location.stream_body)
+    }
+
+    /// Wraps the content handler of the location added last in
+    /// `middleware`, inside any added before.
+    pub fn add_location_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        if let Some(location) = self.locations.last_mut() {
+            location.middleware.push(middleware);
         }
     }
 
+    /// Wraps all request handling of this server in `middleware`, inside
+    /// the global middleware and any added before.
+    pub fn add_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    pub fn add_server_phase(&mut self, phase: Arc<dyn RequestPhase>) {
+        self.server_phases.push(phase);
+    }
+
+    /// Sets the handler for requests that match no location.
+    pub fn set_default_handler(&mut self, handler: HttpHandler) {
+        self.default_handler = Some(Arc::new(handler));
+    }
+
+    /// Sets the `error_page` rules used outside of any location.
+    pub fn set_error_pages(&mut self, error_pages: Arc<ErrorPages>) {
+        self.error_pages = error_pages;
+    }
+
+    /// Sets the response filters used outside of any location.
+    pub fn set_filters(&mut self, filters: Vec<Arc<dyn ResponseFilter>>) {
+        self.filters = filters;
+    }
+
+    /// Selects a location the way nginx does: an exact `=` match wins, then the
+    /// longest prefix if it is marked `^~`, then the first matching regex, and
+    /// finally the longest prefix.
+    pub fn find_location(&self, path: &str) -> Option<&LocationPattern> {
+        self.match_location(path).map(|location| &location.pattern)
+    }
+
+    pub fn find_named_location(&self, name: &str) -> Option<&Arc<HttpHandler>> {
+        self.named_location(name)
+            .and_then(|loc| loc.handler.as_ref())
+    }
+
+    fn named_location(&self, name: &str) -> Option<&Location> {
+        self.locations
+            .iter()
+            .find(|loc| loc.pattern.modifier == LocationModifier::Named && loc.pattern.path == name)
+    }
+
+    fn match_location(&self, path: &str) -> Option<&Location> {
+        let mut longest: Option<&Location> = None;
+        for location in &self.locations {
+            let pattern = &location.pattern;
+            match pattern.modifier {
+                LocationModifier::Exact if pattern.path == path => return Some(location),
+                LocationModifier::Prefix | LocationModifier::PreferPrefix
+                    if path.starts_with(&pattern.path)
+                        && longest.is_none_or(|l| l.pattern.path.len() < pattern.path.len()) =>
+                {
+                    longest = Some(location);
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(location) = longest {
+            if location.pattern.modifier == LocationModifier::PreferPrefix {
+                return Some(location);
+            }
+        }
+
+        self.locations
+            .iter()
+            .find(|location| {
+                location
+                    .pattern
+                    .regex
+                    .as_ref()
+                    .is_some_and(|regex| regex.is_match(path))
+            })
+            .or(longest)
+    }
+
     pub fn add_handler(
         &mut self,
         path: String,
@@ -349,7 +777,325 @@ impl HttpProcessor {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.handlers.is_empty()
+        self.handlers.is_empty() && self.locations.is_empty() && self.default_handler.is_none()
+    }
+
+    pub fn create_status_response(http_version: &Version, status: StatusCode) -> HttpResponse {
+        let mut response = HttpResponse::new();
+        response.set_status_line(*http_version, status);
+        response.set_header("Content-Type", "text/plain");
+        response.set_body(status.canonical_reason().unwrap_or_default());
+        response
+    }
+
+    pub fn handle(&self, req: &mut HttpRequest) -> HttpResponse {
+        let global = GLOBAL_MIDDLEWARE
+            .read()
+            .map(|global| global.clone())
+            .unwrap_or_default();
+        if global.is_empty() && self.middleware.is_empty() {
+            return self.handle_wrapped(req);
+        }
+        let middleware: Vec<_> = global
+            .into_iter()
+            .chain(self.middleware.iter().cloned())
+            .collect();
+        Next::new(&middleware, &|req| self.handle_wrapped(req)).run(req)
+    }
+
+    /// The handling the server's middleware wraps.
+    fn handle_wrapped(&self, req: &mut HttpRequest) -> HttpResponse {
+        let (mut response, mut location) = self.dispatch(req);
+        let mut redirects = 0;
+        while let Some(uri) = response.accel_redirect.take() {
+            redirects += 1;
+            if redirects > MAX_INTERNAL_REDIRECTS {
+                log_error!("X-Accel-Redirect cycle while processing \"{}\"", req.path());
+                response =
+                    Self::create_status_response(req.version(), StatusCode::INTERNAL_SERVER_ERROR);
+                break;
+            }
+            let (mut target, target_location) = self.accel_redirect(req, &uri);
+            if target.status().is_some_and(|status| status < 300) {
+                carry_accel_headers(&response, &mut target);
+            }
+            (response, location) = (target, target_location);
+        }
+        let (error_pages, filters) = match location {
+            Some(location) => (&location.error_pages, &location.filters),
+            None => (&self.error_pages, &self.filters),
+        };
+        Self::apply_not_modified(req, &mut response);
+        let response = self.apply_error_page(req, response, error_pages);
+        Self::apply_filters(req, response, filters)
+    }
+
+    /// Turns a full `GET` or `HEAD` response into 304 Not Modified when its
+    /// `ETag` or `Last-Modified` satisfies the request's `If-None-Match` or
+    /// `If-Modified-Since`, so any handler setting validators supports
+    /// conditional requests.
+    fn apply_not_modified(req: &HttpRequest, response: &mut HttpResponse) {
+        if req.is_subrequest()
+            || !matches!(*req.method(), Method::GET | Method::HEAD)
+            || response.status() != Some(200)
+            || !response.has_validators()
+        {
+            return;
+        }
+        let etag = response.header_value("ETag").map(str::to_string);
+        let modified = response
+            .header_value("Last-Modified")
+            .and_then(parse_http_date);
+        if !is_not_modified(req, etag.as_deref(), modified) {
+            return;
+        }
+        response.set_status_line(*req.version(), StatusCode::NOT_MODIFIED);
+        response.discard_body();
+        for name in [
+            "Content-Length",
+            "Content-Type",
+            "Content-Range",
+            "Accept-Ranges",
+            "Transfer-Encoding",
+        ] {
+            response.remove_header(name);
+        }
+    }
+
+    /// Runs `sub` through the phases, handler and filters of the location it
+    /// matches, like a request from a client. Subrequests nested deeper
+    /// than `MAX_SUBREQUEST_DEPTH` are answered with 500.
+    pub fn subrequest(&self, sub: Subrequest) -> HttpResponse {
+        let mut req = sub.req;
+        if req.subrequest_depth() > MAX_SUBREQUEST_DEPTH {
+            log_error!(
+                "subrequests nested deeper than {} while processing \"{}\"",
+                MAX_SUBREQUEST_DEPTH,
+                req.path()
+            );
+            return Self::create_status_response(req.version(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        self.handle(&mut req)
+    }
+
+    /// Builds the response for an error detected outside the handlers, such
+    /// as an oversized body, honouring the server's `error_page` rules.
+    pub fn error_response(&self, req: &mut HttpRequest, status: StatusCode) -> HttpResponse {
+        let response = Self::create_status_response(req.version(), status);
+        let response = self.apply_error_page(req, response, &self.error_pages);
+        Self::apply_filters(req, response, &self.filters)
+    }
+
+    fn apply_filters(
+        req: &HttpRequest,
+        mut response: HttpResponse,
+        filters: &[Arc<dyn ResponseFilter>],
+    ) -> HttpResponse {
+        for filter in filters {
+            filter.filter(req, &mut response);
+        }
+        response
+    }
+
+    /// Runs the request through the server phases and the selected location,
+    /// returning the location that produced the response, if any.
+    fn dispatch(&self, req: &mut HttpRequest) -> (HttpResponse, Option<&Location>) {
+        for phase in &self.server_phases {
+            if let PhaseResult::Respond(response) = phase.run(req) {
+                return (*response, None);
+            }
+        }
+
+        let mut redirects = 0;
+        loop {
+            let clean_path = req.path().split('?').next().unwrap().to_owned();
+            if let Some(handler) = self.find_handler(&clean_path, req.method()) {
+                return (handler(req), None);
+            }
+
+            let Some(location) = self.match_location(&clean_path) else {
+                let response = match &self.default_handler {
+                    Some(handler) => handler(req),
+                    None => Self::fallback_response(req),
+                };
+                return (response, None);
+            };
+            if let Some(response) = Self::run_location(req, location) {
+                return (response, Some(location));
+            }
+
+            redirects += 1;
+            if redirects > MAX_INTERNAL_REDIRECTS {
+                log_error!(
+                    "rewrite or internal redirection cycle while processing \"{}\"",
+                    req.path()
+                );
+                let response =
+                    Self::create_status_response(req.version(), StatusCode::INTERNAL_SERVER_ERROR);
+                return (response, Some(location));
+            }
+        }
+    }
+
+    /// Runs the phases and the content handler of a location; `None` means the
+    /// URI was rewritten and the location has to be selected again.
+    fn run_location(req: &mut HttpRequest, location: &Location) -> Option<HttpResponse> {
+        for phase in &location.phases {
+            match phase.run(req) {
+                PhaseResult::Continue => {}
+                PhaseResult::Respond(response) => return Some(*response),
+                PhaseResult::Restart => return None,
+            }
+        }
+        let handler = |req: &mut HttpRequest| match &location.handler {
+            Some(handler) => handler(req),
+            None => Self::fallback_response(req),
+        };
+        Some(Next::new(&location.middleware, &handler).run(req))
+    }
+
+    fn fallback_response(req: &HttpRequest) -> HttpResponse {
+        if *req.method() == Method::OPTIONS {
+            let mut response = HttpResponse::new();
+            response.set_status_line(*req.version(), StatusCode::OK);
+            response.set_header("Content-Type", "text/plain");
+            response.set_body("");
+            return response;
+        }
+
+        log_debug!("Handler: {} 404 Not Found", req.method());
+        Self::create_404_response(req.version())
+    }
+
+    /// Serves the URI an upstream named in `X-Accel-Redirect` in place of its
+    /// response: a named location, or a URI selected like a new request.
+    fn accel_redirect(
+        &self,
+        req: &mut HttpRequest,
+        uri: &str,
+    ) -> (HttpResponse, Option<&Location>) {
+        req.set_internal();
+        if *req.method() != Method::HEAD {
+            req.set_method(Method::GET);
+        }
+        if uri.starts_with('@') {
+            return match self.named_location(uri) {
+                Some(location) => match Self::run_location(req, location) {
+                    Some(response) => (response, Some(location)),
+                    None => self.dispatch(req),
+                },
+                None => {
+                    log_error!("named location \"{}\" not found", uri);
+                    let response = Self::create_status_response(
+                        req.version(),
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    );
+                    (response, None)
+                }
+            };
+        }
+        match normalize_target(uri) {
+            Ok(path) => {
+                req.set_path(path);
+                self.dispatch(req)
+            }
+            Err(reason) => {
+                log_error!("invalid X-Accel-Redirect URI \"{}\": {}", uri, reason);
+                let response =
+                    Self::create_status_response(req.version(), StatusCode::INTERNAL_SERVER_ERROR);
+                (response, None)
+            }
+        }
+    }
+
+    /// Replaces an error response with the configured error page. Error pages
+    /// are applied once; errors raised while serving them are returned as is.
+    fn apply_error_page(
+        &self,
+        req: &mut HttpRequest,
+        response: HttpResponse,
+        error_pages: &ErrorPages,
+    ) -> HttpResponse {
+        let Some(page) = response
+            .status()
+            .and_then(|status| error_pages.get(status))
+            .cloned()
+        else {
+            return response;
+        };
+        let original = response
+            .status()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let uri = page.uri.render(&RequestVariables::new(req));
+
+        if ErrorPage::is_external(&uri) {
+            let status = match page.status {
+                ErrorPageStatus::Fixed(status) if status.is_redirection() => status,
+                _ => StatusCode::FOUND,
+            };
+            return redirect_response(req, status, &uri);
+        }
+
+        req.set_internal();
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            req.set_method(Method::GET);
+        }
+        let mut target = match uri.strip_prefix('@') {
+            Some(_) => match self.named_location(&uri) {
+                Some(location) => {
+                    Self::run_location(req, location).unwrap_or_else(|| self.dispatch(req).0)
+                }
+                None => {
+                    log_error!("named location \"{}\" not found", uri);
+                    Self::create_status_response(req.version(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            },
+            None => {
+                req.set_path(uri);
+                self.dispatch(req).0
+            }
+        };
+
+        let succeeded = target.status().is_some_and(|status| status < 300);
+        match page.status {
+            ErrorPageStatus::Original if succeeded => {
+                target.set_status_line(*req.version(), original);
+            }
+            ErrorPageStatus::Fixed(status) => {
+                target.set_status_line(*req.version(), status);
+            }
+            _ => {}
+        }
+        target
+    }
+}
+
+/// Headers of an upstream response that sent `X-Accel-Redirect` which are
+/// kept on the response served in its place.
+const ACCEL_HEADERS: &[&str] = &[
+    "Content-Type",
+    "Content-Disposition",
+    "Accept-Ranges",
+    "Set-Cookie",
+    "Cache-Control",
+    "Expires",
+];
+
+fn carry_accel_headers(upstream: &HttpResponse, target: &mut HttpResponse) {
+    for name in ACCEL_HEADERS {
+        let values: Vec<&str> = upstream
+            .headers()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        target.remove_header(name);
+        for value in values {
+            target.set_header(name, value);
+        }
     }
 }
 
@@ -359,23 +1105,313 @@ impl Processor for HttpProcessor {
         req.parse(&request)
             .map_err(|_| ProcessorError::ParseError)?;
 
-        let clean_path = req.path().split('?').next().unwrap().to_owned();
-        let method = req.method();
+        Ok(self.handle(&mut req).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(args: &[&str]) -> LocationPattern {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        LocationPattern::parse(&args).unwrap()
+    }
 
-        if let Some(handler) = self.find_handler(&clean_path, method) {
-            let response = handler(&req);
-            return Ok(response.as_bytes());
+    #[test]
+    fn test_location_precedence() {
+        let mut processor = HttpProcessor::new();
+        for args in [
+            vec!["/"],
+            vec!["=", "/"],
+            vec!["/images/"],
+            vec!["^~", "/static/"],
+            vec!["~*", "\\.(gif|jpg)$"],
+            vec!["~", "^/images/.*\\.png$"],
+            vec!["@fallback"],
+        ] {
+            processor.add_location(location(&args), Box::new(|_req| HttpResponse::new()));
         }
 
-        if *method == Method::OPTIONS {
-            let mut response = HttpResponse::new();
-            response.set_status_line(*req.version(), StatusCode::OK);
-            response.set_header("Content-Type", "text/plain");
-            response.set_body("");
-            return Ok(response.as_bytes());
+        let matched = |path: &str| {
+            processor
+                .find_location(path)
+                .map(|loc| (loc.modifier, loc.path.clone()))
+        };
+        assert_eq!(matched("/"), Some((LocationModifier::Exact, "/".into())));
+        assert_eq!(
+            matched("/index.html"),
+            Some((LocationModifier::Prefix, "/".into()))
+        );
+        assert_eq!(
+            matched("/static/a.jpg"),
+            Some((LocationModifier::PreferPrefix, "/static/".into()))
+        );
+        assert_eq!(
+            matched("/images/a.JPG"),
+            Some((LocationModifier::RegexCaseless, "\\.(gif|jpg)$".into()))
+        );
+        assert_eq!(
+            matched("/images/a.png"),
+            Some((LocationModifier::Regex, "^/images/.*\\.png$".into()))
+        );
+        assert_eq!(
+            matched("/images/a.txt"),
+            Some((LocationModifier::Prefix, "/images/".into()))
+        );
+        assert!(processor.find_named_location("@fallback").is_some());
+        assert!(LocationPattern::parse(&["~".into(), "(".into()]).is_err());
+        assert!(LocationPattern::parse(&["!".into(), "/".into()]).is_err());
+    }
+
+    #[test]
+    fn test_error_page_replaces_error_responses() {
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["=", "/404.html"]),
+            Box::new(|req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body("custom");
+                resp
+            }),
+        );
+        let mut pages = ErrorPages::default();
+        pages.add(&["404".into(), "/404.html".into()]).unwrap();
+        pages
+            .add(&["405".into(), "=200".into(), "/404.html".into()])
+            .unwrap();
+        processor.set_error_pages(Arc::new(pages));
+
+        let mut req = HttpRequest::new();
+        req.parse(b"POST /missing HTTP/1.1\r\n\r\n").unwrap();
+        let resp = processor.handle(&mut req);
+        assert_eq!(resp.status(), Some(404));
+        assert!(String::from_utf8_lossy(&resp.as_bytes()).ends_with("custom"));
+        assert_eq!(*req.method(), Method::GET);
+
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /missing HTTP/1.1\r\n\r\n").unwrap();
+        let resp = processor.error_response(&mut req, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.status(), Some(200));
+    }
+
+    #[test]
+    fn test_subrequest() {
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["/"]),
+            Box::new(|req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!(
+                    "{} {} {} {} {}",
+                    req.method(),
+                    req.path(),
+                    String::from_utf8_lossy(req.body()),
+                    req.header("X-Tag").unwrap_or("-"),
+                    req.var("tag").unwrap_or("-"),
+                ));
+                resp
+            }),
+        );
+        let mut parent = HttpRequest::new();
+        parent
+            .parse(b"POST /page?a=1 HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody")
+            .unwrap();
+
+        let body = |sub: Subrequest| {
+            assert!(sub.request().is_subrequest());
+            let resp = processor.subrequest(sub);
+            String::from_utf8(resp.body).unwrap()
+        };
+        assert_eq!(
+            body(Subrequest::new(&parent, "/copy")),
+            "POST /copy?a=1 body - -"
+        );
+        assert_eq!(
+            body(
+                Subrequest::new(&parent, "/fragment")
+                    .method(Method::GET)
+                    .without_args()
+                    .without_body()
+                    .header("X-Tag", "t")
+                    .var("tag", "v")
+            ),
+            "GET /fragment  t v"
+        );
+
+        let mut nested = parent.clone();
+        for _ in 0..MAX_SUBREQUEST_DEPTH {
+            nested = nested.subrequest("/deeper");
         }
+        assert_eq!(
+            processor.subrequest(Subrequest::new(&parent, "/")).status(),
+            Some(200)
+        );
+        assert_eq!(
+            processor.subrequest(Subrequest::new(&nested, "/")).status(),
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn test_conditional_get() {
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["/"]),
+            Box::new(move |req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_header("Content-Type", "text/plain");
+                if req.path() != "/plain" {
+                    resp.set_etag("v1").set_last_modified(modified);
+                }
+                resp.set_body("content");
+                resp
+            }),
+        );
+        let send = |head: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("{}\r\nHost: a\r\n\r\n", head).as_bytes())
+                .unwrap();
+            processor.handle(&mut req)
+        };
+
+        let resp = send("GET / HTTP/1.1\r\nIf-None-Match: \"v0\", \"v1\"");
+        assert_eq!(resp.status(), Some(304));
+        assert!(resp.body.is_empty());
+        assert_eq!(resp.header_value("ETag"), Some("\"v1\""));
+        assert!(!resp.has_header("Content-Type"));
+
+        let since = crate::http::http_response::http_date(modified);
+        let resp = send(&format!("HEAD / HTTP/1.1\r\nIf-Modified-Since: {}", since));
+        assert_eq!(resp.status(), Some(304));
+        // If-None-Match takes precedence over If-Modified-Since.
+        let resp = send(&format!(
+            "GET / HTTP/1.1\r\nIf-None-Match: \"v2\"\r\nIf-Modified-Since: {}",
+            since
+        ));
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.body, b"content");
+        let resp = send("POST / HTTP/1.1\r\nIf-None-Match: *");
+        assert_eq!(resp.status(), Some(200));
+        let resp = send("GET /plain HTTP/1.1\r\nIf-None-Match: *");
+        assert_eq!(resp.status(), Some(200));
+    }
+
+    #[test]
+    fn test_middleware() {
+        #[derive(Clone)]
+        struct User(&'static str);
+
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["/"]),
+            Box::new(|req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                let user = req.extensions().get::<User>().map_or("-", |user| user.0);
+                resp.set_header("X-User", user);
+                resp.set_body(req.var("trace").unwrap_or_default());
+                resp
+            }),
+        );
+        processor.add_location_middleware(Arc::new(|req: &mut HttpRequest, next: Next| {
+            let trace = format!("{} location", req.var("trace").unwrap_or_default());
+            req.set_var("trace", &trace);
+            next.run(req)
+        }));
+        processor.add_middleware(Arc::new(|req: &mut HttpRequest, next: Next| {
+            if req.header("Authorization").is_none() {
+                return HttpProcessor::create_status_response(
+                    req.version(),
+                    StatusCode::UNAUTHORIZED,
+                );
+            }
+            req.set_var("trace", "auth");
+            req.extensions_mut().insert(User("ann"));
+            next.run(req)
+        }));
+        processor.add_middleware(Arc::new(|req: &mut HttpRequest, next: Next| {
+            let mut resp = next.run(req);
+            resp.set_header("X-Traced", "yes");
+            resp
+        }));
+        let send = |head: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("{}\r\nHost: a\r\n\r\n", head).as_bytes())
+                .unwrap();
+            processor.handle(&mut req)
+        };
+
+        let resp = send("GET / HTTP/1.1\r\nAuthorization: Basic eDp5");
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.body, b"auth location");
+        assert_eq!(resp.header_value("X-User"), Some("ann"));
+        assert_eq!(resp.header_value("X-Traced"), Some("yes"));
+        // An outer middleware answering stops the chain.
+        let resp = send("GET / HTTP/1.1");
+        assert_eq!(resp.status(), Some(401));
+        assert!(!resp.has_header("X-Traced"));
+    }
+
+    #[test]
+    fn test_middleware_passes_extensions_to_handler() {
+        #[derive(Clone)]
+        struct Tenant(String);
+
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["/"]),
+            Box::new(|req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                let tenant = req.extensions().get::<Tenant>();
+                resp.set_body(tenant.map_or("none", |tenant| tenant.0.as_str()));
+                resp
+            }),
+        );
+        processor.add_middleware(Arc::new(|req: &mut HttpRequest, next: Next| {
+            if let Some(tenant) = req.header("X-Tenant").map(str::to_string) {
+                req.extensions_mut().insert(Tenant(tenant));
+            }
+            next.run(req)
+        }));
+        let send = |head: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", head).as_bytes())
+                .unwrap();
+            processor.handle(&mut req)
+        };
+
+        assert_eq!(send("X-Tenant: acme\r\n").body, b"acme");
+        assert_eq!(send("").body, b"none");
+    }
+
+    #[test]
+    fn test_panicking_async_handler() {
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            location(&["/"]),
+            async_handler(|req: HttpRequest| async move {
+                assert_ne!(req.path(), "/boom", "handler failed");
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp
+            }),
+        );
+        let send = |path: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path).as_bytes())
+                .unwrap();
+            processor.handle(&mut req)
+        };
 
-        println!("Handler: {} 404 Not Found", method);
-        Ok(Self::create_404_response(req.version()).as_bytes())
+        assert_eq!(send("/boom").status(), Some(500));
+        assert_eq!(send("/").status(), Some(200));
+        assert_eq!(send("/boom").status(), Some(500));
+        assert_eq!(send("/").status(), Some(200));
     }
 }
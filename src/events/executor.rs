@@ -0,0 +1,199 @@
+use std::{
+    cell::Cell,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    thread,
+};
+
+use once_cell::sync::Lazy;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A future spawned on the executor, queued again whenever it is woken.
+struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    queue: Sender<Arc<Task>>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        let queue = self.queue.clone();
+        let _ = queue.send(self);
+    }
+}
+
+/// A small executor for the futures of async handlers. A few threads take
+/// turns polling every spawned future, so a future waiting on a database or
+/// an upstream holds no thread until it is woken.
+pub struct Executor {
+    queue: Sender<Arc<Task>>,
+}
+
+pub static EXECUTOR: Lazy<Executor> = Lazy::new(|| {
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    Executor::new(threads)
+});
+
+thread_local! {
+    static IN_EXECUTOR: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Executor {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Arc<Task>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || {
+                IN_EXECUTOR.with(|flag| flag.set(true));
+                while let Some(task) = next_task(&receiver) {
+                    poll_task(&task);
+                }
+            });
+        }
+        Self { queue: sender }
+    }
+
+    /// Runs `future` in the background.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(future))),
+            queue: self.queue.clone(),
+        });
+        let _ = self.queue.send(task);
+    }
+
+    /// Runs `future` on the executor and waits for its output, or `None` if
+    /// it panicked. Called from inside an executor thread, the future is
+    /// polled on the calling thread instead so that nested calls cannot use
+    /// up the executor.
+    pub fn block_on<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + Send + 'static,
+    ) -> Option<T> {
+        if IN_EXECUTOR.with(Cell::get) {
+            return block_on_current_thread(future);
+        }
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.spawn(async move {
+            let _ = sender.send(future.await);
+        });
+        // A panicking future is dropped along with the sender.
+        receiver.recv().ok()
+    }
+}
+
+fn next_task(receiver: &Mutex<Receiver<Arc<Task>>>) -> Option<Arc<Task>> {
+    receiver.lock().ok()?.recv().ok()
+}
+
+fn poll_task(task: &Arc<Task>) {
+    let Ok(mut slot) = task.future.lock() else {
+        return;
+    };
+    // A task woken again after it finished has nothing left to poll.
+    let Some(mut future) = slot.take() else {
+        return;
+    };
+    let waker = Waker::from(Arc::clone(task));
+    let poll = panic::catch_unwind(AssertUnwindSafe(|| {
+        future.as_mut().poll(&mut Context::from_waker(&waker))
+    }));
+    // A panicked task is dropped so the thread can go on with the others.
+    if let Ok(Poll::Pending) = poll {
+        *slot = Some(future);
+    }
+}
+
+/// Wakes a thread parked in `block_on_current_thread`.
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on_current_thread<T>(future: impl Future<Output = T>) -> Option<T> {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        let poll = panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut context)));
+        match poll {
+            Ok(Poll::Ready(output)) => return Some(output),
+            Ok(Poll::Pending) => thread::park(),
+            Err(_) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Completes once a thread started on its first poll has slept.
+    struct Sleep {
+        started: bool,
+        done: Arc<Mutex<bool>>,
+    }
+
+    impl Future for Sleep {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+            if *self.done.lock().unwrap() {
+                return Poll::Ready(7);
+            }
+            if !self.started {
+                self.started = true;
+                let (done, waker) = (Arc::clone(&self.done), cx.waker().clone());
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    *done.lock().unwrap() = true;
+                    waker.wake();
+                });
+            }
+            Poll::Pending
+        }
+    }
+
+    fn sleep() -> Sleep {
+        Sleep {
+            started: false,
+            done: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_block_on() {
+        let executor = Executor::new(1);
+        assert_eq!(executor.block_on(async { sleep().await + 1 }), Some(8));
+        // Nested calls on the single executor thread run inline.
+        let nested = executor.block_on(async { EXECUTOR.block_on(sleep()) });
+        assert_eq!(nested, Some(Some(7)));
+    }
+
+    #[test]
+    fn test_panicking_future_leaves_executor_running() {
+        let executor = Executor::new(1);
+        let failed = executor.block_on(async {
+            sleep().await;
+            panic!("handler failed");
+        });
+        assert_eq!(failed, None::<()>);
+        assert_eq!(executor.block_on(async { sleep().await + 1 }), Some(8));
+        let nested = executor.block_on(async { EXECUTOR.block_on(async { panic!("inline") }) });
+        assert_eq!(nested, Some(None::<()>));
+    }
+}
@@ -1,8 +1,12 @@
 use std::{
     collections::VecDeque,
-    sync::{Arc, Condvar, Mutex},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
     thread,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use once_cell::sync::Lazy;
@@ -40,14 +44,22 @@ impl ThreadPoolConfig {
 
 type Task = Box<dyn FnOnce() + Send + 'static>;
 
+/// The queued tasks and how many workers are waiting for one.
+#[derive(Default)]
+struct Queue {
+    tasks: VecDeque<Task>,
+    idle: usize,
+}
+
 struct Worker {
-    last_active: Instant,
+    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 pub struct ThreadPool {
-    tasks: Arc<(Mutex<VecDeque<Task>>, Condvar)>,
+    tasks: Arc<(Mutex<Queue>, Condvar)>,
     workers: Arc<Mutex<Vec<Worker>>>,
+    next_id: AtomicUsize,
     keep_alive: Duration,
     max_threads: usize,
     max_queue_size: usize,
@@ -56,41 +68,43 @@ pub struct ThreadPool {
 pub static THREAD_POOL: Lazy<Mutex<ThreadPool>> =
     Lazy::new(|| Mutex::new(ThreadPool::new(ThreadPoolConfig::new())));
 
+/// Locks a mutex even if a thread panicked while holding it; the pool's
+/// state stays consistent because tasks never run under its locks.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 impl ThreadPool {
     pub fn new(config: ThreadPoolConfig) -> Self {
         Self {
-            tasks: Arc::new((Mutex::new(VecDeque::new()), Condvar::new())),
+            tasks: Arc::new((Mutex::new(Queue::default()), Condvar::new())),
             workers: Arc::new(Mutex::new(Vec::new())),
+            next_id: AtomicUsize::new(0),
             keep_alive: config.keep_alive,
             max_threads: config.max_threads,
             max_queue_size: config.max_queue_size,
         }
     }
 
+    /// Queues a task, starting a worker when none is idle. A task may run as
+    /// long as it likes, such as a keep-alive connection, without holding up
+    /// the others beyond `max_threads`.
     pub fn spawn<F>(&self, f: F) -> Result<(), ThreadPoolError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let task = Box::new(f);
-        let (lock, cvar) = &*self.tasks;
-        let mut queue = lock.lock().unwrap();
-        if queue.len() >= self.max_queue_size {
+        let (queue, cvar) = &*self.tasks;
+        let mut queue = lock(queue);
+        if queue.tasks.len() >= self.max_queue_size {
             return Err(ThreadPoolError::QueueFull);
         }
-        queue.push_back(task);
-        let task_count = queue.len();
+        queue.tasks.push_back(Box::new(f));
+        let needs_worker = queue.tasks.len() > queue.idle;
         drop(queue);
 
-        let mut workers_guard = self.workers.lock().unwrap();
-        workers_guard.retain(|w| w.thread.is_some());
-        let active_count = workers_guard.len();
-        if task_count > active_count && active_count < self.max_threads {
-            drop(workers_guard);
+        if needs_worker && lock(&self.workers).len() < self.max_threads {
             self.spawn_worker();
-        } else {
-            drop(workers_guard);
         }
-
         cvar.notify_one();
 
         Ok(())
@@ -100,51 +114,38 @@ impl ThreadPool {
         let workers = Arc::clone(&self.workers);
         let tasks = Arc::clone(&self.tasks);
         let keep_alive = self.keep_alive;
-
-        let worker_id = {
-            let mut workers = workers.lock().unwrap();
-            let id = workers.len();
-            workers.push(Worker {
-                last_active: Instant::now(),
-                thread: None,
-            });
-            id
-        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        lock(&self.workers).push(Worker { id, thread: None });
 
         let thread = thread::spawn(move || {
-            let (lock, cvar) = &*tasks;
-
+            let (queue, cvar) = &*tasks;
             loop {
-                let queue = lock.lock().unwrap();
-
-                let result = cvar
-                    .wait_timeout_while(queue, keep_alive, |queue| queue.is_empty())
-                    .unwrap();
-
-                let (mut queue, timeout) = result;
-
-                if timeout.timed_out() && queue.is_empty() {
-                    let mut workers = workers.lock().unwrap();
-                    if let Some(worker) = workers.get_mut(worker_id) {
-                        worker.thread = None;
+                let mut queue = lock(queue);
+                queue.idle += 1;
+                let (mut queue, timeout) = cvar
+                    .wait_timeout_while(queue, keep_alive, |queue| queue.tasks.is_empty())
+                    .unwrap_or_else(PoisonError::into_inner);
+                queue.idle -= 1;
+
+                match queue.tasks.pop_front() {
+                    Some(task) => {
+                        drop(queue);
+                        // A panicking task only ends itself; the worker goes
+                        // on with the next one.
+                        let _ = panic::catch_unwind(AssertUnwindSafe(task));
                     }
-                    break;
-                }
-
-                if let Some(task) = queue.pop_front() {
-                    let mut workers = workers.lock().unwrap();
-                    if let Some(worker) = workers.get_mut(worker_id) {
-                        worker.last_active = Instant::now();
+                    None if timeout.timed_out() => {
+                        // Leaves while still holding the queue, so a task
+                        // queued next sees this worker gone and starts another.
+                        lock(&workers).retain(|worker| worker.id != id);
+                        break;
                     }
-                    drop(queue);
-
-                    task();
+                    None => {}
                 }
             }
         });
 
-        let mut workers = self.workers.lock().unwrap();
-        if let Some(worker) = workers.get_mut(worker_id) {
+        if let Some(worker) = lock(&self.workers).iter_mut().find(|w| w.id == id) {
             worker.thread = Some(thread);
         }
     }
@@ -152,12 +153,76 @@ impl ThreadPool {
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        if let Ok(mut workers) = self.workers.lock() {
-            for worker in workers.iter_mut() {
-                if let Some(thread) = worker.thread.take() {
-                    thread.join().unwrap();
-                }
-            }
+        let threads: Vec<_> = lock(&self.workers)
+            .iter_mut()
+            .filter_map(|worker| worker.thread.take())
+            .collect();
+        for thread in threads {
+            let _ = thread.join();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_long_task_does_not_hold_up_others() {
+        let pool = ThreadPool::new(ThreadPoolConfig {
+            keep_alive: Duration::from_millis(100),
+            max_threads: 4,
+            max_queue_size: 16,
+        });
+        let (release, wait) = mpsc::channel::<()>();
+        pool.spawn(move || {
+            let _ = wait.recv_timeout(Duration::from_secs(10));
+        })
+        .unwrap();
+
+        // Another task runs while the first still occupies its worker, and
+        // queueing it does not wait on that worker either.
+        let (done, finished) = mpsc::channel();
+        let started = Instant::now();
+        pool.spawn(move || done.send(()).unwrap()).unwrap();
+        assert!(finished.recv_timeout(Duration::from_secs(2)).is_ok());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_panicking_task_leaves_worker_running() {
+        let pool = ThreadPool::new(ThreadPoolConfig {
+            keep_alive: Duration::from_secs(1),
+            max_threads: 1,
+            max_queue_size: 16,
+        });
+        pool.spawn(|| panic!("task failed")).unwrap();
+        let (done, finished) = mpsc::channel();
+        pool.spawn(move || done.send(()).unwrap()).unwrap();
+        assert!(finished.recv_timeout(Duration::from_secs(2)).is_ok());
+        assert_eq!(lock(&pool.workers).len(), 1);
+    }
+
+    #[test]
+    fn test_idle_workers_are_reused_and_expire() {
+        let pool = ThreadPool::new(ThreadPoolConfig {
+            keep_alive: Duration::from_millis(100),
+            max_threads: 4,
+            max_queue_size: 16,
+        });
+        let (done, finished) = mpsc::channel();
+        for _ in 0..3 {
+            let done = done.clone();
+            pool.spawn(move || done.send(()).unwrap()).unwrap();
+            finished.recv_timeout(Duration::from_secs(2)).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(lock(&pool.workers).len(), 1);
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(lock(&pool.workers).is_empty());
+    }
+}
@@ -1,7 +1,74 @@
+pub mod http_access;
+pub mod http_addition;
+pub mod http_api;
+pub mod http_auth_basic;
+pub mod http_auth_jwt;
+pub mod http_auth_request;
+pub mod http_autoindex;
+pub mod http_ban;
+pub mod http_body;
+pub mod http_bot_filter;
+#[cfg(feature = "brotli")]
+pub mod http_brotli;
+pub mod http_builder;
+pub mod http_cgi;
+pub mod http_compression;
+pub mod http_core;
+pub mod http_cors;
+pub mod http_dav;
+pub mod http_error_page;
+pub mod http_fastcgi;
+pub mod http_file_cache;
+pub mod http_gateway;
+pub mod http_geo;
+#[cfg(feature = "geoip")]
+pub mod http_geoip;
+pub mod http_gunzip;
+pub mod http_gzip;
+pub mod http_headers;
+pub mod http_health;
+pub mod http_internal;
+pub mod http_keyval;
+pub mod http_limit_conn;
+pub mod http_limit_rate;
+pub mod http_limit_req;
 pub mod http_location;
+pub mod http_log;
 pub mod http_manager;
+pub mod http_mime;
+pub mod http_mirror;
+pub mod http_negotiate;
+pub mod http_oidc;
+pub mod http_otel;
+pub mod http_proxy;
+pub mod http_proxy_cache;
+pub mod http_proxy_ssl;
+pub mod http_real_ip;
+pub mod http_redirect;
+pub mod http_referer;
 pub mod http_request;
+pub mod http_resolver;
 pub mod http_response;
+pub mod http_rewrite;
+pub mod http_scgi;
+pub mod http_secure_link;
+pub mod http_security_headers;
 pub mod http_server;
+pub mod http_slice;
+pub mod http_slow_log;
+pub mod http_split_clients;
+pub mod http_ssi;
 pub mod http_ssl;
+pub mod http_static;
+pub mod http_statsd;
+pub mod http_status;
+pub mod http_sub_filter;
+pub mod http_upstream;
+pub mod http_upstream_api;
+pub mod http_userid;
+pub mod http_uwsgi;
+pub mod http_variables;
+pub mod http_waf;
+#[cfg(feature = "zstd")]
+pub mod http_zstd;
 pub mod web_config;
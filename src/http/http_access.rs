@@ -0,0 +1,261 @@
+use std::net::IpAddr;
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{CommandBuilder, Parameter, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, ConfigPosition, MergeConfig},
+            config_loader::ConfigError,
+        },
+        ip_trie::{canonical, parse_cidr},
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_info, register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("allow")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Allow")
+        .display_name("zh-tw", "允許存取")
+        .desc(
+            "en",
+            "Allows clients from an address or network; allow and deny rules are checked in order and the first match decides"
+        )
+        .desc(
+            "zh-tw",
+            "允許來自指定位址或網段的客戶端；allow 與 deny 規則依序比對，以第一個符合者為準"
+        )
+        .params(vec![address_param()])
+        .build(handle_allow),
+    CommandBuilder::new("deny")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Deny")
+        .display_name("zh-tw", "拒絕存取")
+        .desc(
+            "en",
+            "Answers 403 to clients from an address or network; allow and deny rules are checked in order and the first match decides"
+        )
+        .desc(
+            "zh-tw",
+            "對來自指定位址或網段的客戶端回應 403；allow 與 deny 規則依序比對，以第一個符合者為準"
+        )
+        .params(vec![address_param()])
+        .build(handle_deny)
+);
+
+fn address_param() -> Parameter {
+    ParameterBuilder::new(0)
+        .display_name("en", "Address")
+        .display_name("zh-tw", "位址")
+        .type_name("String")
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "IPv4 or IPv6 address, network in CIDR form such as 10.0.0.0/8, or all",
+        )
+        .desc(
+            "zh-tw",
+            "IPv4 或 IPv6 位址、CIDR 格式的網段（例如 10.0.0.0/8），或 all",
+        )
+        .build()
+}
+
+/// Clients an `allow` or `deny` rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessTarget {
+    All,
+    Network(IpAddr, u8),
+}
+
+impl AccessTarget {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "all" {
+            return Ok(Self::All);
+        }
+        let (addr, prefix_len) = parse_cidr(value)?;
+        // Compare IPv4-mapped addresses as IPv4, as the client address is.
+        Ok(match (addr, canonical(addr)) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) => {
+                Self::Network(IpAddr::V4(v4), prefix_len.saturating_sub(96))
+            }
+            _ => Self::Network(addr, prefix_len),
+        })
+    }
+
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        let (network, prefix_len) = match self {
+            Self::All => return true,
+            Self::Network(network, prefix_len) => (network, *prefix_len as u32),
+        };
+        let (bits, network, width) = match (canonical(addr), network) {
+            (IpAddr::V4(addr), IpAddr::V4(network)) => {
+                (u32::from(addr) as u128, u32::from(*network) as u128, 32)
+            }
+            (IpAddr::V6(addr), IpAddr::V6(network)) => {
+                (u128::from(addr), u128::from(*network), 128)
+            }
+            _ => return false,
+        };
+        let shift = width - prefix_len;
+        shift >= width || (bits >> shift) == (network >> shift)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessRule {
+    pub allow: bool,
+    pub target: AccessTarget,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AccessConfig {
+    /// The rules of the block and where each was written, as allow and deny
+    /// reach their handlers grouped by name.
+    pub rules: Option<Vec<(Option<ConfigPosition>, AccessRule)>>,
+}
+
+impl MergeConfig for AccessConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.rules.is_none() {
+            self.rules = parent.rules.clone();
+        }
+    }
+}
+
+/// Answers 403 to clients whose address is denied by the first matching
+/// rule. Clients no rule matches are allowed.
+pub struct AccessPhase {
+    rules: Vec<AccessRule>,
+}
+
+impl AccessPhase {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Whether a request from `addr` may proceed; requests without a client
+    /// address, such as those over a Unix socket, are only denied by `all`.
+    pub fn allows(&self, addr: Option<IpAddr>) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| match addr {
+                Some(addr) => rule.target.matches(addr),
+                None => rule.target == AccessTarget::All,
+            })
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+impl RequestPhase for AccessPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let addr = req.remote_addr().map(|addr| addr.ip());
+        if self.allows(addr) {
+            return PhaseResult::Continue;
+        }
+        log_info!(
+            "access forbidden by rule, client: {}, request: \"{}\"",
+            addr.map(|addr| addr.to_string()).unwrap_or_default(),
+            req.path()
+        );
+        PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+            req.version(),
+            StatusCode::FORBIDDEN,
+        )))
+    }
+}
+
+/// Builds the access phase in effect for a block chain, if it has rules.
+pub fn access_phase(chain: &[&ConfigContext]) -> Option<AccessPhase> {
+    let mut rules = merged_config::<AccessConfig>(chain).rules?;
+    let same_file = rules.windows(2).all(|pair| match (&pair[0].0, &pair[1].0) {
+        (Some(a), Some(b)) => a.file == b.file,
+        _ => false,
+    });
+    if same_file {
+        rules.sort_by_key(|(pos, _)| {
+            pos.as_ref()
+                .map(|pos| (pos.line, pos.column))
+                .unwrap_or_default()
+        });
+    }
+    Some(AccessPhase::new(
+        rules.into_iter().map(|(_, rule)| rule).collect(),
+    ))
+}
+
+fn add_rule(ctx: &mut ConfigContext, allow: bool) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let value = ctx.str_arg(0)?;
+    let target = AccessTarget::parse(&value).map_err(|reason| ctx.invalid_value(&value, reason))?;
+    let pos = ctx.current_cmd_pos.clone();
+    if let Ok(mut config) = ctx.block_config::<AccessConfig>().lock() {
+        config
+            .rules
+            .get_or_insert_with(Vec::new)
+            .push((pos, AccessRule { allow, target }));
+    }
+    Ok(())
+}
+
+pub fn handle_allow(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    add_rule(ctx, true)
+}
+
+pub fn handle_deny(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    add_rule(ctx, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let rule = |allow, value: &str| AccessRule {
+            allow,
+            target: AccessTarget::parse(value).unwrap(),
+        };
+        let phase = AccessPhase::new(vec![
+            rule(false, "10.1.2.3"),
+            rule(true, "10.0.0.0/8"),
+            rule(true, "2001:db8::/32"),
+            rule(true, "::ffff:192.168.1.0/120"),
+            rule(false, "all"),
+        ]);
+        let allows = |addr: &str| phase.allows(Some(addr.parse().unwrap()));
+        assert!(!allows("10.1.2.3"));
+        assert!(allows("10.200.0.1"));
+        assert!(allows("2001:db8:1::5"));
+        assert!(allows("192.168.1.77"));
+        assert!(allows("::ffff:10.0.0.1"));
+        assert!(!allows("11.0.0.1"));
+        assert!(!allows("2001:db9::1"));
+        assert!(!phase.allows(None));
+
+        assert!(AccessPhase::new(vec![rule(false, "10.0.0.0/8")]).allows(None));
+        assert!(AccessTarget::parse("0.0.0.0/0")
+            .unwrap()
+            .matches("8.8.8.8".parse().unwrap()));
+        assert!(AccessTarget::parse("10.0.0.0/33").is_err());
+        assert!(AccessTarget::parse("none").is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    io::{Cursor, Read},
+    sync::Weak,
+};
+
+use http::Method;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{ProcessorSlot, ResponseFilter, Subrequest},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::{HttpResponse, StreamBody},
+};
+
+register_commands!(
+    CommandBuilder::new("add_before_body")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Add Before Body")
+        .display_name("zh-tw", "內容前加入")
+        .desc(
+            "en",
+            "Puts the response of a subrequest before the body of responses of the addition_types"
+        )
+        .desc(
+            "zh-tw",
+            "在 addition_types 類型回應的內容之前加入子請求的回應"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "URI")
+            .display_name("zh-tw", "URI")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "A local URI such as /header.html, or off")
+            .desc("zh-tw", "本機 URI，例如 /header.html，或 off")
+            .build()])
+        .build(handle_add_before_body),
+    CommandBuilder::new("add_after_body")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Add After Body")
+        .display_name("zh-tw", "內容後加入")
+        .desc(
+            "en",
+            "Puts the response of a subrequest after the body of responses of the addition_types"
+        )
+        .desc(
+            "zh-tw",
+            "在 addition_types 類型回應的內容之後加入子請求的回應"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "URI")
+            .display_name("zh-tw", "URI")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "A local URI such as /footer.html, or off")
+            .desc("zh-tw", "本機 URI，例如 /footer.html，或 off")
+            .build()])
+        .build(handle_add_after_body),
+    CommandBuilder::new("addition_types")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Addition Types")
+        .display_name("zh-tw", "加入內容類型")
+        .desc(
+            "en",
+            "Sets the MIME types add_before_body and add_after_body apply to in addition to text/html"
+        )
+        .desc(
+            "zh-tw",
+            "設定除了 text/html 之外 add_before_body 與 add_after_body 適用的 MIME 類型"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "MIME Types")
+            .display_name("zh-tw", "MIME 類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more MIME types, e.g. text/plain, or * for any type"
+            )
+            .desc(
+                "zh-tw",
+                "一個或多個 MIME 類型，例如 text/plain，或以 * 表示所有類型"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_addition_types)
+);
+
+#[derive(Debug, Default, Clone)]
+pub struct AdditionConfig {
+    /// `Some(None)` records an explicit `off`.
+    pub before: Option<Option<String>>,
+    pub after: Option<Option<String>>,
+    pub types: Option<Vec<String>>,
+}
+
+impl MergeConfig for AdditionConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.before.is_none() {
+            self.before = parent.before.clone();
+        }
+        if self.after.is_none() {
+            self.after = parent.after.clone();
+        }
+        if self.types.is_none() {
+            self.types = parent.types.clone();
+        }
+    }
+}
+
+/// Surrounds response bodies with the responses of subrequests, such as a
+/// shared header and footer.
+pub struct AdditionFilter {
+    before: Option<String>,
+    after: Option<String>,
+    types: Vec<String>,
+    processor: ProcessorSlot,
+}
+
+impl AdditionFilter {
+    fn applies_to(&self, req: &HttpRequest, resp: &HttpResponse) -> bool {
+        if req.is_subrequest()
+            || *req.method() == Method::HEAD
+            || resp.status() != Some(200)
+            || resp.has_header("Content-Encoding")
+        {
+            return false;
+        }
+        let content_type = resp
+            .header_value("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        content_type == "text/html"
+            || self
+                .types
+                .iter()
+                .any(|t| t == "*" || t.eq_ignore_ascii_case(&content_type))
+    }
+
+    /// The body of a `GET` subrequest to `uri`, or nothing if it fails.
+    fn fetch(&self, req: &HttpRequest, uri: &str) -> Vec<u8> {
+        let Some(processor) = self.processor.get().and_then(Weak::upgrade) else {
+            return Vec::new();
+        };
+        let sub = Subrequest::new(req, uri)
+            .without_args()
+            .method(Method::GET)
+            .without_body()
+            .remove_header("Range");
+        let mut resp = processor.subrequest(sub);
+        let status = resp.status().unwrap_or(0);
+        if !(200..300).contains(&status) {
+            log_error!(
+                "addition subrequest to \"{}\" answered status {} for \"{}\"",
+                uri,
+                status,
+                req.path()
+            );
+            resp.discard_body();
+            return Vec::new();
+        }
+        if let Err(e) = resp.load_body() {
+            log_error!("Failed to read addition subrequest to \"{}\": {}", uri, e);
+            return Vec::new();
+        }
+        resp.body
+    }
+}
+
+impl ResponseFilter for AdditionFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if !self.applies_to(req, resp) {
+            return;
+        }
+        let before = self
+            .before
+            .as_deref()
+            .map(|uri| self.fetch(req, uri))
+            .unwrap_or_default();
+        let after = self
+            .after
+            .as_deref()
+            .map(|uri| self.fetch(req, uri))
+            .unwrap_or_default();
+        if resp.file.is_none() && resp.stream.is_none() {
+            let body = std::mem::take(&mut resp.body);
+            resp.body = [before, body, after].concat();
+        } else {
+            let added = (before.len() + after.len()) as u64;
+            let (body, len) = resp.take_body_reader();
+            let reader = Cursor::new(before).chain(body).chain(Cursor::new(after));
+            resp.set_body_stream(StreamBody::new(reader, len.map(|len| len + added)));
+        }
+        resp.remove_header("Content-Length");
+        resp.remove_header("Accept-Ranges");
+        if let Some(etag) = resp.header_value("ETag").map(str::to_string) {
+            if !etag.starts_with("W/") {
+                resp.remove_header("ETag");
+                resp.set_header("ETag", &format!("W/{}", etag));
+            }
+        }
+    }
+}
+
+/// Builds the addition filter for a block chain with a body to add.
+pub fn addition_filter(
+    chain: &[&ConfigContext],
+    processor: &ProcessorSlot,
+) -> Option<AdditionFilter> {
+    let config = merged_config::<AdditionConfig>(chain);
+    let before = config.before.flatten();
+    let after = config.after.flatten();
+    if before.is_none() && after.is_none() {
+        return None;
+    }
+    Some(AdditionFilter {
+        before,
+        after,
+        types: config.types.unwrap_or_default(),
+        processor: ProcessorSlot::clone(processor),
+    })
+}
+
+fn uri_arg(ctx: &ConfigContext) -> Result<Option<Option<String>>, ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(None);
+    }
+    let uri = ctx.str_arg(0)?;
+    if uri == "off" {
+        return Ok(Some(None));
+    }
+    if !uri.starts_with('/') {
+        return Err(ctx.invalid_value(&uri, "expected a local URI starting with / or off"));
+    }
+    Ok(Some(Some(uri)))
+}
+
+pub fn handle_add_before_body(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(uri) = uri_arg(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<AdditionConfig>().lock() {
+        config.before = Some(uri);
+    }
+    Ok(())
+}
+
+pub fn handle_add_after_body(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(uri) = uri_arg(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<AdditionConfig>().lock() {
+        config.after = Some(uri);
+    }
+    Ok(())
+}
+
+pub fn handle_addition_types(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let types = ctx.args();
+    if types.is_empty() {
+        return Ok(());
+    }
+    if let Ok(mut config) = ctx.block_config::<AdditionConfig>().lock() {
+        config.types = Some(types);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use http::StatusCode;
+
+    use crate::core::processor::{HttpProcessor, LocationPattern};
+
+    use super::*;
+
+    #[test]
+    fn test_bodies_are_surrounded() {
+        let slot = ProcessorSlot::default();
+        let filter = AdditionFilter {
+            before: Some("/header".to_string()),
+            after: Some("/footer".to_string()),
+            types: Vec::new(),
+            processor: Arc::clone(&slot),
+        };
+        let mut processor = HttpProcessor::new();
+        processor.add_location_with_phases(
+            LocationPattern::parse(&["/".to_string()]).unwrap(),
+            Some(Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_header("Content-Type", "text/html");
+                match req.path().split('?').next().unwrap_or_default() {
+                    "/header" => resp.set_body("<h1>"),
+                    "/footer" => resp.set_body("</h1>"),
+                    "/stream" => resp.set_body_stream(StreamBody::new(Cursor::new("s"), None)),
+                    _ => resp.set_body("page"),
+                };
+                resp
+            })),
+            Vec::new(),
+            Arc::default(),
+            vec![Arc::new(filter)],
+        );
+        let processor = Arc::new(processor);
+        slot.set(Arc::downgrade(&processor)).unwrap();
+
+        let send = |path: &str, method: &str| {
+            let mut req = HttpRequest::new();
+            let raw = format!("{} {}?q=1 HTTP/1.1\r\nHost: a\r\n\r\n", method, path);
+            req.parse(raw.as_bytes()).unwrap();
+            processor.handle(&mut req)
+        };
+        assert_eq!(send("/page", "GET").body, b"<h1>page</h1>");
+        assert_eq!(send("/page", "POST").body, b"<h1>page</h1>");
+        assert_eq!(send("/page", "HEAD").body, b"page");
+
+        let mut resp = send("/stream", "GET");
+        assert!(resp.is_chunked());
+        resp.load_body().unwrap();
+        assert_eq!(resp.body, b"<h1>s</h1>");
+    }
+}
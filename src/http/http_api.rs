@@ -0,0 +1,173 @@
+use http::StatusCode;
+use serde_json::{json, Map, Value};
+
+use crate::core::processor::{HttpHandler, LocationModifier, LocationPattern};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+/// What an API route answers: a status and, unless it has none, a JSON body.
+pub type ApiResult = Result<(StatusCode, Option<Value>), ApiError>;
+
+/// A failed API call, answered as `{"error": "..."}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+    /// The methods the resource allows, sent as `Allow` with a 405.
+    pub allow: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            allow: None,
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// A method the resource does not answer; `allow` lists those it does.
+    pub fn method(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+    }
+}
+
+/// Reads the request body as a JSON object.
+pub fn body_object(req: &HttpRequest) -> Result<Map<String, Value>, ApiError> {
+    match serde_json::from_slice(req.body()) {
+        Ok(Value::Object(body)) => Ok(body),
+        Ok(_) => Err(ApiError::invalid("expected a JSON object")),
+        Err(e) => Err(ApiError::invalid(format!("invalid JSON: {}", e))),
+    }
+}
+
+/// The segments of the request path below `prefix`.
+pub fn route_segments<'a>(req: &'a HttpRequest, prefix: &str) -> Vec<&'a str> {
+    let path = req.path().split('?').next().unwrap_or_default();
+    let route = path.strip_prefix(prefix).unwrap_or(path);
+    route.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+/// Builds the handler of a JSON API served by a location. `route` gets the
+/// path segments below a prefix location, or the whole path otherwise.
+pub fn api_handler<F>(pattern: Option<&LocationPattern>, route: F) -> HttpHandler
+where
+    F: Fn(&HttpRequest, &[&str]) -> ApiResult + Send + Sync + 'static,
+{
+    let prefix = match pattern {
+        Some(pattern) if pattern.modifier == LocationModifier::Prefix => pattern.path.clone(),
+        _ => String::new(),
+    };
+    Box::new(move |req: &HttpRequest| api_response(req, route(req, &route_segments(req, &prefix))))
+}
+
+fn api_response(req: &HttpRequest, result: ApiResult) -> HttpResponse {
+    let mut resp = HttpResponse::new();
+    let (status, body) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            if let Some(allow) = e.allow {
+                resp.set_header("Allow", allow);
+            }
+            (e.status, Some(json!({ "error": e.message })))
+        }
+    };
+    resp.set_status_line(*req.version(), status);
+    resp.set_header("Cache-Control", "no-store");
+    match body {
+        Some(body) => {
+            resp.set_header("Content-Type", "application/json");
+            resp.set_body(&format!("{}\n", body));
+        }
+        None => {
+            resp.set_header("Content-Length", "0");
+        }
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(handler: &HttpHandler, method: &str, path: &str) -> HttpResponse {
+        let mut req = HttpRequest::new();
+        req.parse(format!("{} {} HTTP/1.1\r\n\r\n", method, path).as_bytes())
+            .unwrap();
+        handler(&req)
+    }
+
+    #[test]
+    fn test_routes_below_prefix() {
+        let echo = |req: &HttpRequest, segments: &[&str]| -> ApiResult {
+            match (req.method().as_str(), segments) {
+                ("GET", _) => Ok((StatusCode::OK, Some(json!(segments)))),
+                ("DELETE", [_]) => Ok((StatusCode::NO_CONTENT, None)),
+                ("DELETE", _) => Err(ApiError::not_found("no such resource")),
+                _ => Err(ApiError::method("GET, DELETE")),
+            }
+        };
+        let handler = api_handler(Some(&LocationPattern::prefix("/api/")), echo);
+
+        let resp = call(&handler, "GET", "/api/zone/key?x=1");
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.header_value("Content-Type"), Some("application/json"));
+        assert_eq!(resp.header_value("Cache-Control"), Some("no-store"));
+        assert_eq!(resp.body, b"[\"zone\",\"key\"]\n");
+        assert_eq!(call(&handler, "GET", "/api/").body, b"[]\n");
+
+        let resp = call(&handler, "DELETE", "/api/zone");
+        assert_eq!(resp.status(), Some(204));
+        assert_eq!(resp.header_value("Content-Length"), Some("0"));
+        assert!(resp.body.is_empty());
+
+        let resp = call(&handler, "DELETE", "/api/");
+        assert_eq!(resp.status(), Some(404));
+        assert_eq!(resp.body, b"{\"error\":\"no such resource\"}\n");
+        assert!(!resp.has_header("Allow"));
+
+        let resp = call(&handler, "PUT", "/api/zone");
+        assert_eq!(resp.status(), Some(405));
+        assert_eq!(resp.header_value("Allow"), Some("GET, DELETE"));
+
+        let exact = api_handler(
+            Some(&LocationPattern::parse(&["=".to_string(), "/api".to_string()]).unwrap()),
+            echo,
+        );
+        assert_eq!(call(&exact, "GET", "/api").body, b"[\"api\"]\n");
+    }
+
+    #[test]
+    fn test_body_object() {
+        let body = |body: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(
+                format!(
+                    "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            body_object(&req)
+        };
+        assert_eq!(body(r#"{"a":1}"#).unwrap()["a"], 1);
+        assert_eq!(
+            body("[1]").unwrap_err(),
+            ApiError::invalid("expected a JSON object")
+        );
+        assert_eq!(body("{").unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use http::StatusCode;
+use openssl::{
+    base64,
+    hash::{hash, MessageDigest},
+    memcmp, sha,
+};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_error, log_info, register_commands,
+};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(
+    CommandBuilder::new("auth_basic")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Basic Authentication")
+        .display_name("zh-tw", "基本認證")
+        .desc(
+            "en",
+            "Requires a user name and password from auth_basic_user_file, shown to the client with the given realm; off turns off an inherited one"
+        )
+        .desc(
+            "zh-tw",
+            "要求提供 auth_basic_user_file 中的帳號與密碼，並以指定的領域名稱提示客戶端；off 關閉繼承來的設定"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Realm")
+            .display_name("zh-tw", "領域")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Realm shown to the client, or off")
+            .desc("zh-tw", "提示客戶端的領域名稱，或 off")
+            .build()])
+        .build(handle_auth_basic),
+    CommandBuilder::new("auth_basic_user_file")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Basic Authentication User File")
+        .display_name("zh-tw", "基本認證帳號檔")
+        .desc(
+            "en",
+            "htpasswd file with user:password lines, the password hashed with bcrypt, apr1 or {SHA}; reread when it changes"
+        )
+        .desc(
+            "zh-tw",
+            "htpasswd 格式的帳號檔，每行為 帳號:密碼，密碼以 bcrypt、apr1 或 {SHA} 雜湊；檔案變更時會重新讀取"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Path of the user file")
+            .desc("zh-tw", "帳號檔路徑")
+            .build()])
+        .build(handle_auth_basic_user_file)
+);
+
+/// Most verified credentials remembered so bcrypt does not run on every
+/// request; the cache starts over when full.
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Default, Clone)]
+pub struct AuthBasicConfig {
+    /// The realm, or `Some(None)` after `auth_basic off`.
+    pub realm: Option<Option<String>>,
+    pub user_file: Option<PathBuf>,
+}
+
+impl MergeConfig for AuthBasicConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.realm.is_none() {
+            self.realm = parent.realm.clone();
+        }
+        if self.user_file.is_none() {
+            self.user_file = parent.user_file.clone();
+        }
+    }
+}
+
+/// The user name and password sent with Basic authentication.
+pub fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let (scheme, credentials) = req.header("Authorization")?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = base64::decode_block(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Checks a password against an htpasswd hash in bcrypt (`$2y$`, `$2b$`,
+/// `$2a$`), apr1 (`$apr1$`) or `{SHA}` form.
+pub fn verify_password(password: &str, stored: &str) -> Result<bool, String> {
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+    {
+        return bcrypt::verify(password, stored).map_err(|e| e.to_string());
+    }
+    if let Some(rest) = stored.strip_prefix("$apr1$") {
+        let (salt, _) = rest.split_once('$').ok_or("malformed apr1 hash")?;
+        return Ok(constant_time_eq(
+            apr1_crypt(password.as_bytes(), salt.as_bytes()).as_bytes(),
+            stored.as_bytes(),
+        ));
+    }
+    if let Some(digest) = stored.strip_prefix("{SHA}") {
+        let computed = base64::encode_block(&sha::sha1(password.as_bytes()));
+        return Ok(constant_time_eq(computed.as_bytes(), digest.as_bytes()));
+    }
+    Err("unsupported password hash".to_string())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    let mut out = [0; 16];
+    out.copy_from_slice(&hash(MessageDigest::md5(), data).expect("md5 is available"));
+    out
+}
+
+/// The Apache variant of MD5-crypt, as written by `htpasswd -m`.
+fn apr1_crypt(password: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = md5(&[password, salt, password].concat());
+    let mut data = [password, MAGIC, salt].concat();
+    for chunk in alternate.iter().cycle().take(password.len()) {
+        data.push(*chunk);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        data.push(if len & 1 == 1 { 0 } else { password[0] });
+        len >>= 1;
+    }
+    let mut digest = md5(&data);
+
+    for round in 0..1000 {
+        let mut data = Vec::with_capacity(password.len() * 2 + salt.len() + 16);
+        if round & 1 == 1 {
+            data.extend_from_slice(password);
+        } else {
+            data.extend_from_slice(&digest);
+        }
+        if round % 3 != 0 {
+            data.extend_from_slice(salt);
+        }
+        if round % 7 != 0 {
+            data.extend_from_slice(password);
+        }
+        if round & 1 == 1 {
+            data.extend_from_slice(&digest);
+        } else {
+            data.extend_from_slice(password);
+        }
+        digest = md5(&data);
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut encoded = String::with_capacity(22);
+    let mut push = |value: u32, count: usize| {
+        for i in 0..count {
+            encoded.push(ALPHABET[((value >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+
+    format!("$apr1${}${}", String::from_utf8_lossy(salt), encoded)
+}
+
+#[derive(Default)]
+struct UserFileState {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+    /// SHA-256 of user and password pairs that were verified.
+    verified: HashSet<[u8; 32]>,
+}
+
+/// An htpasswd file, reread when its modification time changes.
+pub struct UserFile {
+    path: PathBuf,
+    state: Mutex<UserFileState>,
+}
+
+impl UserFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            state: Mutex::new(UserFileState::default()),
+        }
+    }
+
+    fn parse(content: &str) -> HashMap<String, String> {
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (user, rest) = line.split_once(':')?;
+                let password = rest.split(':').next().unwrap_or_default();
+                Some((user.to_string(), password.to_string()))
+            })
+            .collect()
+    }
+
+    /// Whether the user exists and the password matches.
+    pub fn verify(&self, user: &str, password: &str) -> Result<bool, String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| "user file lock poisoned".to_string())?;
+        if state.modified != Some(modified) {
+            let content = fs::read_to_string(&self.path)
+                .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+            *state = UserFileState {
+                modified: Some(modified),
+                users: Self::parse(&content),
+                verified: HashSet::new(),
+            };
+        }
+
+        let key = sha::sha256(format!("{}\0{}", user, password).as_bytes());
+        if state.verified.contains(&key) {
+            return Ok(true);
+        }
+        let Some(stored) = state.users.get(user).cloned() else {
+            return Ok(false);
+        };
+        // bcrypt is slow on purpose, so other requests must not wait on it.
+        drop(state);
+        if !verify_password(password, &stored)
+            .map_err(|e| format!("{}: user \"{}\": {}", self.path.display(), user, e))?
+        {
+            return Ok(false);
+        }
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| "user file lock poisoned".to_string())?;
+        if state.verified.len() >= VERIFIED_CACHE_SIZE {
+            state.verified.clear();
+        }
+        state.verified.insert(key);
+        Ok(true)
+    }
+}
+
+/// Answers 401 to requests without a user name and password from the file.
+pub struct AuthBasicPhase {
+    realm: String,
+    users: Option<UserFile>,
+}
+
+impl AuthBasicPhase {
+    fn challenge(&self, req: &HttpRequest) -> HttpResponse {
+        let mut resp =
+            HttpProcessor::create_status_response(req.version(), StatusCode::UNAUTHORIZED);
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        resp.set_header("WWW-Authenticate", &format!("Basic realm=\"{}\"", realm));
+        resp
+    }
+}
+
+impl RequestPhase for AuthBasicPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let Some(users) = &self.users else {
+            log_error!("auth_basic: no auth_basic_user_file for \"{}\"", req.path());
+            return PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+                req.version(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )));
+        };
+        let Some((user, password)) = basic_credentials(req) else {
+            return PhaseResult::Respond(Box::new(self.challenge(req)));
+        };
+        match users.verify(&user, &password) {
+            Ok(true) => PhaseResult::Continue,
+            Ok(false) => {
+                log_info!(
+                    "user \"{}\" was not authenticated, request: \"{}\"",
+                    user,
+                    req.path()
+                );
+                PhaseResult::Respond(Box::new(self.challenge(req)))
+            }
+            Err(e) => {
+                log_error!("auth_basic: {}", e);
+                PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+                    req.version(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )))
+            }
+        }
+    }
+}
+
+/// Builds the authentication phase in effect for a block chain, if any.
+pub fn auth_basic_phase(chain: &[&ConfigContext]) -> Option<AuthBasicPhase> {
+    let config = merged_config::<AuthBasicConfig>(chain);
+    let realm = config.realm.flatten()?;
+    Some(AuthBasicPhase {
+        realm,
+        users: config.user_file.map(UserFile::new),
+    })
+}
+
+pub fn handle_auth_basic(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let realm = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<AuthBasicConfig>().lock() {
+        config.realm = Some((realm != "off").then_some(realm));
+    }
+    Ok(())
+}
+
+pub fn handle_auth_basic_user_file(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let path = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<AuthBasicConfig>().lock() {
+        config.user_file = Some(PathBuf::from(path));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verifies_htpasswd_formats() {
+        let path = std::env::temp_dir().join(format!("blur-htpasswd-{}", std::process::id()));
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        fs::write(
+            &path,
+            format!(
+                "# users\nann:{}\nbob:$apr1$xxxxxxxx$dxHfLAsjHkDRmG83UXe8K0\ncat:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=:comment\ndan:plain\n",
+                bcrypt_hash
+            ),
+        )
+        .unwrap();
+        let users = UserFile::new(path.clone());
+
+        assert_eq!(users.verify("ann", "secret"), Ok(true));
+        assert_eq!(users.verify("ann", "secret"), Ok(true));
+        assert_eq!(users.verify("ann", "wrong"), Ok(false));
+        assert_eq!(users.verify("bob", "password"), Ok(true));
+        assert_eq!(users.verify("bob", "passwore"), Ok(false));
+        assert_eq!(users.verify("cat", "password"), Ok(true));
+        assert_eq!(users.verify("cat", ""), Ok(false));
+        assert_eq!(users.verify("eve", "password"), Ok(false));
+        assert!(users.verify("dan", "plain").is_err());
+
+        fs::remove_file(&path).unwrap();
+        assert!(users.verify("ann", "secret").is_err());
+    }
+}
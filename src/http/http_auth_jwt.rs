@@ -0,0 +1,864 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::StatusCode;
+use openssl::{
+    base64,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    memcmp,
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rsa::{Padding, Rsa},
+    sign::{RsaPssSaltlen, Signer, Verifier},
+};
+use reqwest::blocking::Client;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_error, log_info, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("auth_jwt")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "JWT Authentication")
+        .display_name("zh-tw", "JWT 認證")
+        .desc(
+            "en",
+            "Requires a JSON Web Token signed by a key from auth_jwt_keys; off turns off an inherited one"
+        )
+        .desc(
+            "zh-tw",
+            "要求由 auth_jwt_keys 中的金鑰簽署的 JSON Web Token；off 關閉繼承來的設定"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Realm")
+                .display_name("zh-tw", "領域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Realm shown to the client, or off")
+                .desc("zh-tw", "提示客戶端的領域名稱，或 off")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Token")
+                .display_name("zh-tw", "權杖")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "token=value takes the token from a value with variables, such as token=$cookie_auth, instead of the Authorization: Bearer header"
+                )
+                .desc(
+                    "zh-tw",
+                    "token=值 改從可含變數的值取得權杖，例如 token=$cookie_auth，而非 Authorization: Bearer 標頭"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_auth_jwt),
+    CommandBuilder::new("auth_jwt_keys")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "JWT Keys")
+        .display_name("zh-tw", "JWT 金鑰")
+        .desc(
+            "en",
+            "JSON Web Key Set that tokens are verified with, read from a file or fetched from an http or https URL"
+        )
+        .desc(
+            "zh-tw",
+            "驗證權杖所用的 JSON Web Key Set，可從檔案讀取或從 http、https 網址取得"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Source")
+                .display_name("zh-tw", "來源")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "File path or URL of the key set")
+                .desc("zh-tw", "金鑰集的檔案路徑或網址")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Cache")
+                .display_name("zh-tw", "快取")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "cache=time keeps a fetched key set for that long, 1h by default; files are reread when they change"
+                )
+                .desc(
+                    "zh-tw",
+                    "cache=時間 為取得的金鑰集保留時間，預設 1h；檔案則在變更時重新讀取"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_auth_jwt_keys),
+    CommandBuilder::new("auth_jwt_claims")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "JWT Claims")
+        .display_name("zh-tw", "JWT 聲明")
+        .desc(
+            "en",
+            "Claims a token must carry besides an unexpired exp"
+        )
+        .desc("zh-tw", "權杖除了未過期的 exp 之外必須符合的聲明")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Claims")
+            .display_name("zh-tw", "聲明")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "iss=issuer, aud=audience (repeatable, any one must match) and leeway=time allowed for clock skew, 0s by default"
+            )
+            .desc(
+                "zh-tw",
+                "iss=簽發者、aud=受眾（可重複，符合其一即可），以及 leeway=容許的時鐘誤差，預設 0s"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_auth_jwt_claims)
+);
+
+pub const DEFAULT_KEY_CACHE: Duration = Duration::from_secs(3600);
+/// Least time between fetches when a token names a key not in the set, so
+/// rotated keys are picked up without letting clients force a fetch each
+/// request.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthJwtSpec {
+    pub realm: String,
+    /// Where the token comes from instead of the Authorization header.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySource {
+    File(PathBuf),
+    Url(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClaimRules {
+    pub issuer: Option<String>,
+    pub audiences: Vec<String>,
+    pub leeway: Duration,
+}
+
+impl ClaimRules {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut rules = Self::default();
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("iss", issuer)) if !issuer.is_empty() => {
+                    rules.issuer = Some(issuer.to_string())
+                }
+                Some(("aud", audience)) if !audience.is_empty() => {
+                    rules.audiences.push(audience.to_string())
+                }
+                Some(("leeway", value)) => rules.leeway = parse_duration(value)?,
+                _ => return Err(format!("unknown claim rule \"{}\"", arg)),
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Checks the registered claims of a token whose signature is valid.
+    pub fn check(&self, claims: &Value, now: u64) -> Result<(), String> {
+        let leeway = self.leeway.as_secs();
+        let time = |name: &str| match claims.get(name) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .map(|time| Some(time as u64))
+                .ok_or(format!("{} is not a number", name)),
+        };
+        let exp = time("exp")?.ok_or("token has no exp")?;
+        if now >= exp.saturating_add(leeway) {
+            return Err("token expired".to_string());
+        }
+        if let Some(nbf) = time("nbf")? {
+            if now.saturating_add(leeway) < nbf {
+                return Err("token not yet valid".to_string());
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err("issuer mismatch".to_string());
+            }
+        }
+        if !self.audiences.is_empty() {
+            let audiences: Vec<&str> = match claims.get("aud") {
+                Some(Value::String(aud)) => vec![aud],
+                Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !audiences
+                .iter()
+                .any(|aud| self.audiences.iter().any(|a| a == aud))
+            {
+                return Err("audience mismatch".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AuthJwtConfig {
+    /// The settings, or `Some(None)` after `auth_jwt off`.
+    pub jwt: Option<Option<AuthJwtSpec>>,
+    pub keys: Option<(KeySource, Duration)>,
+    pub claims: Option<ClaimRules>,
+}
+
+impl MergeConfig for AuthJwtConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.jwt.is_none() {
+            self.jwt = parent.jwt.clone();
+        }
+        if self.keys.is_none() {
+            self.keys = parent.keys.clone();
+        }
+        if self.claims.is_none() {
+            self.claims = parent.claims.clone();
+        }
+    }
+}
+
+/// The header and claims of a token that passed verification, kept on the
+/// request for `$jwt_header_*` and `$jwt_claim_*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedJwt {
+    pub header: Value,
+    pub claims: Value,
+}
+
+impl VerifiedJwt {
+    /// A header or claim value as a variable: strings as they are, arrays
+    /// joined with commas and anything else as JSON.
+    pub fn value(fields: &Value, name: &str) -> Option<String> {
+        let text = |value: &Value| match value {
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        match fields.get(name)? {
+            Value::Null => None,
+            Value::Array(items) => Some(items.iter().map(text).collect::<Vec<_>>().join(",")),
+            value => Some(text(value)),
+        }
+    }
+}
+
+pub fn base64url_encode(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+pub fn base64url_decode(data: &str) -> Result<Vec<u8>, String> {
+    let mut standard: String = data
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    while !standard.len().is_multiple_of(4) {
+        standard.push('=');
+    }
+    base64::decode_block(&standard).map_err(|_| "invalid base64url".to_string())
+}
+
+fn json_part(part: &str) -> Result<Value, String> {
+    serde_json::from_slice(&base64url_decode(part)?).map_err(|e| e.to_string())
+}
+
+enum KeyMaterial {
+    Secret(Vec<u8>),
+    Rsa(PKey<Public>),
+    Ec(PKey<Public>, Nid),
+    Ed25519(PKey<Public>),
+}
+
+/// A verification key from a JSON Web Key Set.
+pub struct Jwk {
+    kid: Option<String>,
+    alg: Option<String>,
+    material: KeyMaterial,
+}
+
+impl Jwk {
+    pub fn parse(jwk: &Value) -> Result<Self, String> {
+        let field = |name: &str| {
+            jwk.get(name)
+                .and_then(Value::as_str)
+                .ok_or(format!("missing \"{}\"", name))
+        };
+        let number = |name: &str| {
+            base64url_decode(field(name)?)
+                .and_then(|bytes| BigNum::from_slice(&bytes).map_err(|e| e.to_string()))
+        };
+        let material = match field("kty")? {
+            "oct" => KeyMaterial::Secret(base64url_decode(field("k")?)?),
+            "RSA" => {
+                let rsa = Rsa::from_public_components(number("n")?, number("e")?)
+                    .map_err(|e| e.to_string())?;
+                KeyMaterial::Rsa(PKey::from_rsa(rsa).map_err(|e| e.to_string())?)
+            }
+            "EC" => {
+                let nid = match field("crv")? {
+                    "P-256" => Nid::X9_62_PRIME256V1,
+                    "P-384" => Nid::SECP384R1,
+                    "P-521" => Nid::SECP521R1,
+                    crv => return Err(format!("unsupported curve \"{}\"", crv)),
+                };
+                let group = EcGroup::from_curve_name(nid).map_err(|e| e.to_string())?;
+                let (x, y) = (number("x")?, number("y")?);
+                let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+                    .map_err(|e| e.to_string())?;
+                KeyMaterial::Ec(PKey::from_ec_key(ec).map_err(|e| e.to_string())?, nid)
+            }
+            "OKP" if field("crv")? == "Ed25519" => KeyMaterial::Ed25519(
+                PKey::public_key_from_raw_bytes(&base64url_decode(field("x")?)?, Id::ED25519)
+                    .map_err(|e| e.to_string())?,
+            ),
+            kty => return Err(format!("unsupported key type \"{}\"", kty)),
+        };
+        Ok(Self {
+            kid: jwk.get("kid").and_then(Value::as_str).map(str::to_string),
+            alg: jwk.get("alg").and_then(Value::as_str).map(str::to_string),
+            material,
+        })
+    }
+
+    /// Parses a key set, or a single key; keys for other uses than signing
+    /// or of unsupported types are skipped.
+    pub fn parse_set(data: &[u8]) -> Result<Vec<Jwk>, String> {
+        let set: Value = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+        let keys = match set.get("keys") {
+            Some(Value::Array(keys)) => keys.clone(),
+            Some(_) => return Err("\"keys\" is not an array".to_string()),
+            None => vec![set],
+        };
+        Ok(keys
+            .iter()
+            .filter(|key| key.get("use").and_then(Value::as_str).unwrap_or("sig") == "sig")
+            .filter_map(|key| match Jwk::parse(key) {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    log_error!("auth_jwt: skipping key: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Whether the signature over `input` was made with this key and `alg`.
+    fn verify(&self, alg: &str, input: &[u8], signature: &[u8]) -> bool {
+        if self.alg.as_deref().is_some_and(|key_alg| key_alg != alg) {
+            return false;
+        }
+        let (Some(family), Some(bits)) = (alg.get(..2), alg.get(2..)) else {
+            return false;
+        };
+        let digest = match bits {
+            "256" => MessageDigest::sha256(),
+            "384" => MessageDigest::sha384(),
+            "512" => MessageDigest::sha512(),
+            _ if alg == "EdDSA" => MessageDigest::null(),
+            _ => return false,
+        };
+        let verified = match (family, &self.material) {
+            ("HS", KeyMaterial::Secret(secret)) => PKey::hmac(secret)
+                .and_then(|key| Signer::new(digest, &key)?.sign_oneshot_to_vec(input))
+                .map(|mac| mac.len() == signature.len() && memcmp::eq(&mac, signature)),
+            ("RS", KeyMaterial::Rsa(key)) => Verifier::new(digest, key)
+                .and_then(|mut verifier| verifier.verify_oneshot(signature, input)),
+            ("PS", KeyMaterial::Rsa(key)) => Verifier::new(digest, key).and_then(|mut verifier| {
+                verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+                verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                verifier.set_rsa_mgf1_md(digest)?;
+                verifier.verify_oneshot(signature, input)
+            }),
+            ("ES", KeyMaterial::Ec(key, nid)) => {
+                let size = match (bits, *nid) {
+                    ("256", Nid::X9_62_PRIME256V1) => 32,
+                    ("384", Nid::SECP384R1) => 48,
+                    ("512", Nid::SECP521R1) => 66,
+                    _ => return false,
+                };
+                if signature.len() != size * 2 {
+                    return false;
+                }
+                let (r, s) = signature.split_at(size);
+                BigNum::from_slice(r)
+                    .and_then(|r| EcdsaSig::from_private_components(r, BigNum::from_slice(s)?))
+                    .and_then(|sig| sig.to_der())
+                    .and_then(|der| Verifier::new(digest, key)?.verify_oneshot(&der, input))
+            }
+            ("Ed", KeyMaterial::Ed25519(key)) if alg == "EdDSA" => {
+                Verifier::new_without_digest(key)
+                    .and_then(|mut verifier| verifier.verify_oneshot(signature, input))
+            }
+            _ => return false,
+        };
+        verified.unwrap_or(false)
+    }
+}
+
+/// Verifies a compact JWS token's signature against the keys, returning its
+/// header and claims. Registered claims are not checked here.
+pub fn verify_token(token: &str, keys: &[Jwk]) -> Result<VerifiedJwt, String> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("malformed token".to_string());
+    };
+    let header_json = json_part(header)?;
+    let alg = header_json
+        .get("alg")
+        .and_then(Value::as_str)
+        .ok_or("missing alg")?;
+    let kid = header_json.get("kid").and_then(Value::as_str);
+    let signature = base64url_decode(signature)?;
+    let input = format!("{}.{}", header, payload);
+    let verified = keys
+        .iter()
+        .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+        .any(|key| key.verify(alg, input.as_bytes(), &signature));
+    if !verified {
+        return Err(match kid {
+            Some(kid) if !keys.iter().any(|key| key.kid.as_deref() == Some(kid)) => {
+                format!("unknown key \"{}\"", kid)
+            }
+            _ => "invalid signature".to_string(),
+        });
+    }
+    Ok(VerifiedJwt {
+        claims: json_part(payload)?,
+        header: header_json,
+    })
+}
+
+struct LoadedKeys {
+    keys: Arc<Vec<Jwk>>,
+    loaded: Instant,
+    modified: Option<SystemTime>,
+}
+
+/// A key set, reloaded when its file changes or its cache time passes.
+pub struct KeySet {
+    source: KeySource,
+    cache: Duration,
+    loaded: Mutex<Option<LoadedKeys>>,
+}
+
+impl KeySet {
+    pub fn new(source: KeySource, cache: Duration) -> Self {
+        Self {
+            source,
+            cache,
+            loaded: Mutex::new(None),
+        }
+    }
+
+    fn fetch(&self) -> Result<(Vec<Jwk>, Option<SystemTime>), String> {
+        match &self.source {
+            KeySource::File(path) => {
+                let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+                let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Ok((Jwk::parse_set(&data)?, modified))
+            }
+            KeySource::Url(url) => {
+                let response = Client::builder()
+                    .timeout(FETCH_TIMEOUT)
+                    .build()
+                    .and_then(|client| client.get(url).send())
+                    .and_then(|response| response.error_for_status())
+                    .and_then(|response| response.bytes())
+                    .map_err(|e| format!("{}: {}", url, e))?;
+                Ok((Jwk::parse_set(&response)?, None))
+            }
+        }
+    }
+
+    /// The current keys; `missing_kid` asks for a reload when the set was
+    /// not loaded too recently, for keys rotated in before the cache ends.
+    fn keys(&self, missing_kid: bool) -> Result<Arc<Vec<Jwk>>, String> {
+        let mut loaded = self
+            .loaded
+            .lock()
+            .map_err(|_| "key set lock poisoned".to_string())?;
+        let stale = match (&*loaded, &self.source) {
+            (None, _) => true,
+            (Some(current), _) if missing_kid => current.loaded.elapsed() >= MIN_REFETCH_INTERVAL,
+            (Some(current), KeySource::File(path)) => {
+                fs::metadata(path).and_then(|meta| meta.modified()).ok() != current.modified
+            }
+            (Some(current), KeySource::Url(_)) => current.loaded.elapsed() >= self.cache,
+        };
+        if stale {
+            match self.fetch() {
+                Ok((keys, modified)) => {
+                    *loaded = Some(LoadedKeys {
+                        keys: Arc::new(keys),
+                        loaded: Instant::now(),
+                        modified,
+                    })
+                }
+                // Keep the previous keys working while the source is down.
+                Err(e) if loaded.is_some() => {
+                    log_error!("auth_jwt: reloading keys: {}", e);
+                    if let Some(current) = loaded.as_mut() {
+                        current.loaded = Instant::now();
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        loaded
+            .as_ref()
+            .map(|current| Arc::clone(&current.keys))
+            .ok_or("no keys".to_string())
+    }
+
+    /// Verifies a token's signature, reloading the set once for a key it
+    /// does not have. The outer error is for keys that cannot be loaded.
+    pub fn verify(&self, token: &str) -> Result<Result<VerifiedJwt, String>, String> {
+        Ok(match verify_token(token, &self.keys(false)?) {
+            Err(e) if e.starts_with("unknown key") => verify_token(token, &self.keys(true)?),
+            result => result,
+        })
+    }
+}
+
+/// Answers 401 to requests without a valid token and keeps the verified
+/// token on the request for variables.
+pub struct AuthJwtPhase {
+    realm: String,
+    token: Option<VarTemplate>,
+    keys: Option<KeySet>,
+    claims: ClaimRules,
+}
+
+impl AuthJwtPhase {
+    fn challenge(&self, req: &HttpRequest, error: Option<&str>) -> HttpResponse {
+        let mut resp =
+            HttpProcessor::create_status_response(req.version(), StatusCode::UNAUTHORIZED);
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut challenge = format!("Bearer realm=\"{}\"", realm);
+        if let Some(error) = error {
+            challenge.push_str(&format!(", error=\"{}\"", error));
+        }
+        resp.set_header("WWW-Authenticate", &challenge);
+        resp
+    }
+
+    fn token(&self, req: &HttpRequest) -> Option<String> {
+        let token = match &self.token {
+            Some(template) => template.render(&RequestVariables::new(req)),
+            None => {
+                let (scheme, token) = req.header("Authorization")?.trim().split_once(' ')?;
+                if !scheme.eq_ignore_ascii_case("Bearer") {
+                    return None;
+                }
+                token.trim().to_string()
+            }
+        };
+        (!token.is_empty()).then_some(token)
+    }
+
+    fn verify(&self, keys: &KeySet, token: &str) -> Result<Result<VerifiedJwt, String>, String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(keys
+            .verify(token)?
+            .and_then(|jwt| self.claims.check(&jwt.claims, now).map(|_| jwt)))
+    }
+}
+
+impl RequestPhase for AuthJwtPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let server_error = |req: &HttpRequest| {
+            PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+                req.version(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )))
+        };
+        let Some(keys) = &self.keys else {
+            log_error!("auth_jwt: no auth_jwt_keys for \"{}\"", req.path());
+            return server_error(req);
+        };
+        let Some(token) = self.token(req) else {
+            return PhaseResult::Respond(Box::new(self.challenge(req, None)));
+        };
+        match self.verify(keys, &token) {
+            Ok(Ok(jwt)) => {
+                req.extensions_mut().insert(Arc::new(jwt));
+                PhaseResult::Continue
+            }
+            Ok(Err(reason)) => {
+                log_info!("JWT rejected: {}, request: \"{}\"", reason, req.path());
+                PhaseResult::Respond(Box::new(self.challenge(req, Some("invalid_token"))))
+            }
+            Err(e) => {
+                log_error!("auth_jwt: {}", e);
+                server_error(req)
+            }
+        }
+    }
+}
+
+/// Builds the JWT phase in effect for a block chain, if any.
+pub fn auth_jwt_phase(chain: &[&ConfigContext]) -> Option<AuthJwtPhase> {
+    let config = merged_config::<AuthJwtConfig>(chain);
+    let spec = config.jwt.flatten()?;
+    Some(AuthJwtPhase {
+        realm: spec.realm,
+        token: spec.token.as_deref().map(VarTemplate::parse),
+        keys: config
+            .keys
+            .map(|(source, cache)| KeySet::new(source, cache)),
+        claims: config.claims.unwrap_or_default(),
+    })
+}
+
+pub fn handle_auth_jwt(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((realm, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let jwt = if realm == "off" {
+        None
+    } else {
+        let mut spec = AuthJwtSpec {
+            realm: realm.clone(),
+            token: None,
+        };
+        for option in options {
+            match option.strip_prefix("token=") {
+                Some(token) if !token.is_empty() => spec.token = Some(token.to_string()),
+                _ => return Err(ctx.invalid_value(option, "unknown option")),
+            }
+        }
+        Some(spec)
+    };
+    if let Ok(mut config) = ctx.block_config::<AuthJwtConfig>().lock() {
+        config.jwt = Some(jwt);
+    }
+    Ok(())
+}
+
+pub fn handle_auth_jwt_keys(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((source, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let source = if source.starts_with("http://") || source.starts_with("https://") {
+        KeySource::Url(source.clone())
+    } else {
+        KeySource::File(PathBuf::from(source))
+    };
+    let mut cache = DEFAULT_KEY_CACHE;
+    for option in options {
+        match option.strip_prefix("cache=") {
+            Some(value) => {
+                cache = parse_duration(value).map_err(|reason| ctx.invalid_value(option, reason))?
+            }
+            None => return Err(ctx.invalid_value(option, "unknown option")),
+        }
+    }
+    if let Ok(mut config) = ctx.block_config::<AuthJwtConfig>().lock() {
+        config.keys = Some((source, cache));
+    }
+    Ok(())
+}
+
+pub fn handle_auth_jwt_claims(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let rules =
+        ClaimRules::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    if let Ok(mut config) = ctx.block_config::<AuthJwtConfig>().lock() {
+        config.claims = Some(rules);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::{ec::EcKey, pkey::Private};
+    use serde_json::json;
+
+    use super::*;
+
+    fn sign(header: Value, claims: Value, key: &PKey<Private>) -> String {
+        let input = format!(
+            "{}.{}",
+            base64url_encode(header.to_string().as_bytes()),
+            base64url_encode(claims.to_string().as_bytes())
+        );
+        let der = Signer::new(MessageDigest::sha256(), key)
+            .unwrap()
+            .sign_oneshot_to_vec(input.as_bytes())
+            .unwrap();
+        let sig = EcdsaSig::from_der(&der).unwrap();
+        let mut raw = sig.r().to_vec_padded(32).unwrap();
+        raw.extend(sig.s().to_vec_padded(32).unwrap());
+        format!("{}.{}", input, base64url_encode(&raw))
+    }
+
+    #[test]
+    fn test_verifies_signature_and_claims() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = EcKey::generate(&group).unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        let mut bn_ctx = openssl::bn::BigNumContext::new().unwrap();
+        ec.public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut bn_ctx)
+            .unwrap();
+        let key = PKey::from_ec_key(ec).unwrap();
+        let set = json!({"keys": [
+            {"kty": "oct", "kid": "shared", "k": base64url_encode(b"secret")},
+            {"kty": "EC", "kid": "ec1", "crv": "P-256", "use": "sig",
+             "x": base64url_encode(&x.to_vec_padded(32).unwrap()),
+             "y": base64url_encode(&y.to_vec_padded(32).unwrap())},
+        ]});
+        let keys = Jwk::parse_set(set.to_string().as_bytes()).unwrap();
+        assert_eq!(keys.len(), 2);
+
+        let claims = json!({"sub": "ann", "iss": "https://id", "aud": ["api", "web"], "exp": 2000, "roles": ["a", "b"]});
+        let token = sign(json!({"alg": "ES256", "kid": "ec1"}), claims.clone(), &key);
+        let jwt = verify_token(&token, &keys).unwrap();
+        assert_eq!(
+            VerifiedJwt::value(&jwt.claims, "sub").as_deref(),
+            Some("ann")
+        );
+        assert_eq!(
+            VerifiedJwt::value(&jwt.claims, "roles").as_deref(),
+            Some("a,b")
+        );
+        assert_eq!(
+            VerifiedJwt::value(&jwt.claims, "exp").as_deref(),
+            Some("2000")
+        );
+
+        let tampered = token.replacen(".", ".e30", 1);
+        assert!(verify_token(&tampered, &keys).is_err());
+        let other_kid = sign(json!({"alg": "ES256", "kid": "ec2"}), claims.clone(), &key);
+        assert_eq!(
+            verify_token(&other_kid, &keys).unwrap_err(),
+            "unknown key \"ec2\""
+        );
+        let confused = sign(json!({"alg": "HS256", "kid": "ec1"}), claims.clone(), &key);
+        assert!(verify_token(&confused, &keys).is_err());
+        let unsigned = format!(
+            "{}.{}.",
+            base64url_encode(json!({"alg": "none"}).to_string().as_bytes()),
+            base64url_encode(claims.to_string().as_bytes())
+        );
+        assert!(verify_token(&unsigned, &keys).is_err());
+
+        let rules = ClaimRules::parse(&[
+            "iss=https://id".to_string(),
+            "aud=api".to_string(),
+            "leeway=30s".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(rules.check(&claims, 1990), Ok(()));
+        assert_eq!(rules.check(&claims, 2029), Ok(()));
+        assert!(rules.check(&claims, 2030).is_err());
+        assert!(rules
+            .check(&json!({"iss": "https://id", "aud": "web"}), 0)
+            .is_err());
+        assert!(rules
+            .check(&json!({"iss": "https://other", "aud": "api"}), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_rejects_token_without_exp() {
+        let rules = ClaimRules::parse(&[]).unwrap();
+        assert_eq!(
+            rules.check(&json!({"sub": "ann"}), 0),
+            Err("token has no exp".to_string())
+        );
+        assert_eq!(
+            rules.check(&json!({"sub": "ann", "exp": "soon"}), 0),
+            Err("exp is not a number".to_string())
+        );
+        assert_eq!(rules.check(&json!({"sub": "ann", "exp": 10}), 0), Ok(()));
+    }
+
+    #[test]
+    fn test_rejects_non_ascii_alg() {
+        let keys = Jwk::parse_set(
+            json!({"keys": [{"kty": "oct", "k": base64url_encode(b"secret")}]})
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        for alg in ["aé", "é", "HSé", "\u{1F600}256"] {
+            let token = format!(
+                "{}.{}.{}",
+                base64url_encode(json!({"alg": alg}).to_string().as_bytes()),
+                base64url_encode(json!({"exp": 10}).to_string().as_bytes()),
+                base64url_encode(b"signature")
+            );
+            assert!(verify_token(&token, &keys).is_err(), "{}", alg);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::Weak;
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, ProcessorSlot, RequestPhase, Subrequest},
+    },
+    log_error, log_info, register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("auth_request")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Auth Request")
+        .display_name("zh-tw", "外部認證請求")
+        .desc(
+            "en",
+            "Asks another location whether each request may proceed: a 2xx answer allows it, 401 and 403 are passed to the client"
+        )
+        .desc(
+            "zh-tw",
+            "向另一個 location 詢問每個請求是否可繼續：回應 2xx 時放行，401 與 403 則回應給客戶端"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "URI")
+            .display_name("zh-tw", "URI")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "URI of the location that decides, e.g. /auth, or off"
+            )
+            .desc("zh-tw", "負責判斷的 location URI，例如 /auth，或 off")
+            .build()])
+        .build(handle_auth_request),
+    CommandBuilder::new("auth_request_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Auth Request Header")
+        .display_name("zh-tw", "認證回應標頭")
+        .desc(
+            "en",
+            "Copies a header of the allowing auth response into the request, replacing any value the client sent"
+        )
+        .desc(
+            "zh-tw",
+            "將放行的認證回應中的標頭複製到請求中，並取代客戶端送來的同名標頭"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Header")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Header name, such as X-User")
+            .desc("zh-tw", "標頭名稱，例如 X-User")
+            .build()])
+        .build(handle_auth_request_header)
+);
+
+#[derive(Debug, Default, Clone)]
+pub struct AuthRequestConfig {
+    /// The URI, or `Some(None)` after `auth_request off`.
+    pub uri: Option<Option<String>>,
+    pub headers: Option<Vec<String>>,
+}
+
+impl MergeConfig for AuthRequestConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.uri.is_none() {
+            self.uri = parent.uri.clone();
+        }
+        if self.headers.is_none() {
+            self.headers = parent.headers.clone();
+        }
+    }
+}
+
+/// Sends a bodiless copy of each request to the auth URI through the
+/// server's processor and lets the request proceed on a 2xx answer.
+pub struct AuthRequestPhase {
+    uri: String,
+    headers: Vec<String>,
+    processor: ProcessorSlot,
+}
+
+impl AuthRequestPhase {
+    pub fn new(uri: String, headers: Vec<String>, processor: ProcessorSlot) -> Self {
+        Self {
+            uri,
+            headers,
+            processor,
+        }
+    }
+
+    fn respond(req: &HttpRequest, status: StatusCode) -> PhaseResult {
+        PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+            req.version(),
+            status,
+        )))
+    }
+}
+
+impl RequestPhase for AuthRequestPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        // Subrequests, including the auth request itself, are not checked.
+        if req.is_subrequest() {
+            return PhaseResult::Continue;
+        }
+        let Some(processor) = self.processor.get().and_then(Weak::upgrade) else {
+            return Self::respond(req, StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        let mut resp = processor.subrequest(Subrequest::new(req, &self.uri).without_body());
+        resp.discard_body();
+
+        match resp.status().unwrap_or(0) {
+            200..=299 => {
+                for name in &self.headers {
+                    match resp.header_value(name) {
+                        Some(value) => req.set_header(name, value),
+                        None => req.remove_header(name),
+                    }
+                }
+                PhaseResult::Continue
+            }
+            401 => {
+                log_info!("auth request to \"{}\" denied \"{}\"", self.uri, req.path());
+                let mut denied =
+                    HttpProcessor::create_status_response(req.version(), StatusCode::UNAUTHORIZED);
+                if let Some(challenge) = resp.header_value("WWW-Authenticate") {
+                    denied.set_header("WWW-Authenticate", challenge);
+                }
+                PhaseResult::Respond(Box::new(denied))
+            }
+            403 => {
+                log_info!("auth request to \"{}\" denied \"{}\"", self.uri, req.path());
+                Self::respond(req, StatusCode::FORBIDDEN)
+            }
+            status => {
+                log_error!(
+                    "auth request to \"{}\" answered unexpected status {}",
+                    self.uri,
+                    status
+                );
+                Self::respond(req, StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// Builds the auth request phase in effect for a block chain, if any.
+pub fn auth_request_phase(
+    chain: &[&ConfigContext],
+    processor: &ProcessorSlot,
+) -> Option<AuthRequestPhase> {
+    let config = merged_config::<AuthRequestConfig>(chain);
+    Some(AuthRequestPhase::new(
+        config.uri.flatten()?,
+        config.headers.unwrap_or_default(),
+        ProcessorSlot::clone(processor),
+    ))
+}
+
+pub fn handle_auth_request(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let uri = ctx.str_arg(0)?;
+    if uri != "off" && !uri.starts_with('/') {
+        return Err(ctx.invalid_value(&uri, "must start with /"));
+    }
+    if let Ok(mut config) = ctx.block_config::<AuthRequestConfig>().lock() {
+        config.uri = Some((uri != "off").then_some(uri));
+    }
+    Ok(())
+}
+
+pub fn handle_auth_request_header(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<AuthRequestConfig>().lock() {
+        config.headers.get_or_insert_with(Vec::new).push(name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{core::processor::LocationPattern, http::http_response::HttpResponse};
+
+    use super::*;
+
+    #[test]
+    fn test_auth_response_decides() {
+        let slot = ProcessorSlot::default();
+        let mut processor = HttpProcessor::new();
+        processor.add_location_with_phases(
+            LocationPattern::parse(&["/".to_string()]).unwrap(),
+            Some(Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(req.header("X-User").unwrap_or("-"));
+                resp
+            })),
+            vec![Arc::new(AuthRequestPhase::new(
+                "/auth".to_string(),
+                vec!["X-User".to_string()],
+                Arc::clone(&slot),
+            ))],
+            Arc::default(),
+            Vec::new(),
+        );
+        processor.add_location(
+            LocationPattern::parse(&["/auth".to_string()]).unwrap(),
+            Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                match req.header("Authorization") {
+                    Some("Bearer ann") => {
+                        resp.set_status_line(*req.version(), StatusCode::NO_CONTENT);
+                        resp.set_header("X-User", "ann");
+                    }
+                    Some(_) => {
+                        resp.set_status_line(*req.version(), StatusCode::FORBIDDEN);
+                    }
+                    None => {
+                        resp.set_status_line(*req.version(), StatusCode::UNAUTHORIZED);
+                        resp.set_header("WWW-Authenticate", "Bearer");
+                    }
+                }
+                resp
+            }),
+        );
+        let processor = Arc::new(processor);
+        slot.set(Arc::downgrade(&processor)).unwrap();
+
+        let send = |headers: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET /app HTTP/1.1\r\nHost: a\r\n{}\r\n", headers).as_bytes())
+                .unwrap();
+            processor.handle(&mut req)
+        };
+        let resp = send("Authorization: Bearer ann\r\nX-User: eve\r\n");
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.body, b"ann");
+        assert_eq!(send("Authorization: Bearer eve\r\n").status(), Some(403));
+        let resp = send("");
+        assert_eq!(resp.status(), Some(401));
+        assert_eq!(resp.header_value("WWW-Authenticate"), Some("Bearer"));
+    }
+}
@@ -0,0 +1,380 @@
+use std::{fs, io, path::Path, time::SystemTime};
+
+use chrono::{DateTime, Local, Utc};
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{
+    core::config::{
+        command::{ArgType, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::{http_date, HttpResponse},
+};
+
+register_commands!(
+    CommandBuilder::new("autoindex")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Directory Listing")
+        .display_name("zh-tw", "目錄列表")
+        .desc(
+            "en",
+            "Lists the contents of directories that have no index file"
+        )
+        .desc("zh-tw", "列出沒有索引檔案的目錄內容")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_autoindex),
+    CommandBuilder::new("autoindex_format")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Directory Listing Format")
+        .display_name("zh-tw", "目錄列表格式")
+        .desc("en", "Sets the output format of directory listings")
+        .desc("zh-tw", "設定目錄列表的輸出格式")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Format")
+            .display_name("zh-tw", "格式")
+            .arg_type(ArgType::Enum(vec!["html".to_string(), "json".to_string()]))
+            .is_required(true)
+            .default("")
+            .desc("en", "html or json, defaults to html")
+            .desc("zh-tw", "html 或 json，預設為 html")
+            .build()])
+        .build(handle_autoindex_format),
+    CommandBuilder::new("autoindex_exact_size")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Directory Listing Exact Size")
+        .display_name("zh-tw", "目錄列表精確大小")
+        .desc(
+            "en",
+            "Shows exact file sizes in HTML listings instead of rounded ones"
+        )
+        .desc("zh-tw", "在 HTML 列表中顯示精確的檔案大小而非約略值")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_autoindex_exact_size),
+    CommandBuilder::new("autoindex_localtime")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Directory Listing Local Time")
+        .display_name("zh-tw", "目錄列表本地時間")
+        .desc(
+            "en",
+            "Shows modification times in local time instead of UTC in HTML listings"
+        )
+        .desc("zh-tw", "在 HTML 列表中以本地時間而非 UTC 顯示修改時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_autoindex_localtime),
+);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AutoindexFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AutoindexConfig {
+    pub enabled: Option<bool>,
+    pub format: Option<AutoindexFormat>,
+    pub exact_size: Option<bool>,
+    pub localtime: Option<bool>,
+}
+
+impl MergeConfig for AutoindexConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        self.format = self.format.or(parent.format);
+        self.exact_size = self.exact_size.or(parent.exact_size);
+        self.localtime = self.localtime.or(parent.localtime);
+    }
+}
+
+impl AutoindexConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+}
+
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+fn read_entries(dir: &Path) -> io::Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follow symlinks so links to directories are listed as directories.
+        let Ok(metadata) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(DirEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Renders the listing of `dir`, requested as `uri`.
+pub fn listing_response(
+    req: &HttpRequest,
+    dir: &Path,
+    uri: &str,
+    config: &AutoindexConfig,
+) -> io::Result<HttpResponse> {
+    let entries = read_entries(dir)?;
+    let (content_type, body) = match config.format.unwrap_or_default() {
+        AutoindexFormat::Html => ("text/html", render_html(uri, &entries, config)),
+        AutoindexFormat::Json => ("application/json", render_json(&entries)),
+    };
+
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*req.version(), StatusCode::OK);
+    resp.set_header("Content-Type", content_type);
+    if *req.method() == Method::HEAD {
+        resp.set_header("Content-Length", &body.len().to_string());
+    } else {
+        resp.set_body(&body);
+    }
+    Ok(resp)
+}
+
+fn render_html(uri: &str, entries: &[DirEntry], config: &AutoindexConfig) -> String {
+    let title = escape_html(uri);
+    let mut out = format!(
+        "<html>\r\n<head><title>Index of {title}</title></head>\r\n<body>\r\n<h1>Index of {title}</h1><hr><pre><a href=\"../\">../</a>\r\n"
+    );
+    for entry in entries {
+        let mut name = entry.name.clone();
+        if entry.is_dir {
+            name.push('/');
+        }
+        let shown = truncate_name(&name, 50);
+        let padding = " ".repeat(51 - shown.chars().count());
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else if config.exact_size.unwrap_or(true) {
+            entry.size.to_string()
+        } else {
+            human_size(entry.size)
+        };
+        out.push_str(&format!(
+            "<a href=\"{}\">{}</a>{}{} {:>19}\r\n",
+            encode_href(&name),
+            escape_html(&shown),
+            padding,
+            format_time(entry.modified, config.localtime.unwrap_or(false)),
+            size,
+        ));
+    }
+    out.push_str("</pre><hr></body>\r\n</html>\r\n");
+    out
+}
+
+fn render_json(entries: &[DirEntry]) -> String {
+    let items: Vec<Value> = entries
+        .iter()
+        .map(|entry| {
+            let mut item = json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "mtime": http_date(entry.modified),
+            });
+            if !entry.is_dir {
+                item["size"] = json!(entry.size);
+            }
+            item
+        })
+        .collect();
+    Value::Array(items).to_string()
+}
+
+fn format_time(time: SystemTime, localtime: bool) -> String {
+    const FORMAT: &str = "%d-%b-%Y %H:%M";
+    if localtime {
+        DateTime::<Local>::from(time).format(FORMAT).to_string()
+    } else {
+        DateTime::<Utc>::from(time).format(FORMAT).to_string()
+    }
+}
+
+fn truncate_name(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name.to_string();
+    }
+    let mut shown: String = name.chars().take(width - 3).collect();
+    shown.push_str("..>");
+    shown
+}
+
+/// Rounds a size the way nginx does when `autoindex_exact_size` is off.
+fn human_size(size: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    for (unit, suffix) in UNITS {
+        if size >= unit {
+            return format!("{}{}", size.div_ceil(unit), suffix);
+        }
+    }
+    size.to_string()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn encode_href(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut AutoindexConfig)) {
+    if let Ok(mut config) = ctx.block_config::<AutoindexConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_autoindex(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.enabled = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_autoindex_format(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let format = match ctx.str_arg(0)?.as_str() {
+        "json" => AutoindexFormat::Json,
+        _ => AutoindexFormat::Html,
+    };
+    update(ctx, |config| config.format = Some(format));
+    Ok(())
+}
+
+pub fn handle_autoindex_exact_size(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let exact = ctx.bool_arg(0)?;
+    update(ctx, |config| config.exact_size = Some(exact));
+    Ok(())
+}
+
+pub fn handle_autoindex_localtime(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let localtime = ctx.bool_arg(0)?;
+    update(ctx, |config| config.localtime = Some(localtime));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing_formats() {
+        let dir = std::env::temp_dir().join(format!("blur-autoindex-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a b.txt"), vec![0u8; 2048]).unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+
+        let entries = read_entries(&dir).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["sub", "a b.txt"]);
+
+        let config = AutoindexConfig {
+            exact_size: Some(false),
+            ..Default::default()
+        };
+        let html = render_html("/files/", &entries, &config);
+        assert!(html.contains("<a href=\"sub/\">sub/</a>"));
+        assert!(html.contains("<a href=\"a%20b.txt\">a b.txt</a>"));
+        assert!(html.contains("  2K\r\n"));
+
+        let json: Value = serde_json::from_str(&render_json(&entries)).unwrap();
+        assert_eq!(json[0]["type"], "directory");
+        assert_eq!(json[1]["size"], 2048);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
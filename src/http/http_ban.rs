@@ -0,0 +1,691 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::{Method, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::{parse_duration, parse_size},
+        },
+        processor::{
+            HttpHandler, HttpProcessor, LocationPattern, PhaseResult, RequestPhase, ResponseFilter,
+        },
+    },
+    log_error, log_notice, log_warn, register_commands,
+};
+
+use super::{
+    http_api::{api_handler, ApiError, ApiResult},
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_waf::WAF_RULES,
+};
+
+register_commands!(
+    CommandBuilder::new("ban_zone")
+        .is_repeatable()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Ban Zone")
+        .display_name("zh-tw", "封鎖區域")
+        .desc(
+            "en",
+            "Defines a zone that counts the failures of each client address and bans the addresses with too many, for ban"
+        )
+        .desc(
+            "zh-tw",
+            "定義記錄每個客戶端位址失敗次數的區域，失敗過多的位址會被封鎖，供 ban 使用"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "zone=name:size; each address takes 64 bytes"
+                )
+                .desc("zh-tw", "zone=名稱:大小；每個位址占用 64 位元組")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Options")
+                .display_name("zh-tw", "選項")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "maxretry=N failures (5) within findtime= (10m) ban an address for bantime= (1h)"
+                )
+                .desc(
+                    "zh-tw",
+                    "在 findtime=（10m）內失敗 maxretry=N 次（5）的位址會被封鎖 bantime=（1h）"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_ban_zone),
+    CommandBuilder::new("ban")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Ban Failing Clients")
+        .display_name("zh-tw", "封鎖失敗過多的客戶端")
+        .desc(
+            "en",
+            "Counts failed requests in a ban_zone and answers banned addresses with 403; off turns off an inherited one"
+        )
+        .desc(
+            "zh-tw",
+            "在 ban_zone 中記錄失敗的請求，並以 403 回應被封鎖的位址；off 關閉繼承來的設定"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "zone=name, or off")
+                .desc("zh-tw", "zone=名稱，或 off")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Events")
+                .display_name("zh-tw", "事件")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "events= followed by what counts as a failure: auth for 401 and 407, 4xx for any client error, waf for requests the waf blocked; auth,waf by default"
+                )
+                .desc(
+                    "zh-tw",
+                    "events= 接上視為失敗的事件：auth 為 401 與 407，4xx 為任何客戶端錯誤，waf 為被 waf 阻擋的請求；預設 auth,waf"
+                )
+                .build(),
+        ])
+        .arity(Arity::Range(1, 2))
+        .build(handle_ban),
+    CommandBuilder::new("ban_api")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "Ban API")
+        .display_name("zh-tw", "封鎖 API")
+        .desc(
+            "en",
+            "Sets whether the location serves a JSON API to list, add and lift the bans of the ban_zone zones"
+        )
+        .desc(
+            "zh-tw",
+            "設定 location 是否提供列出、新增與解除 ban_zone 封鎖的 JSON API"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_ban_api)
+);
+
+const BYTES_PER_ADDRESS: u64 = 64;
+const DEFAULT_MAX_RETRY: usize = 5;
+const DEFAULT_FIND_TIME: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_BAN_TIME: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Default)]
+struct Offender {
+    /// When the recent failures happened, oldest first.
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+    bans: u32,
+}
+
+impl Offender {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.failures.back().copied().max(self.banned_until)
+    }
+}
+
+/// The failures and bans of client addresses.
+pub struct BanZone {
+    pub name: String,
+    max_retry: usize,
+    find_time: Duration,
+    ban_time: Duration,
+    capacity: usize,
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+}
+
+impl std::fmt::Debug for BanZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BanZone")
+            .field("name", &self.name)
+            .field("max_retry", &self.max_retry)
+            .finish()
+    }
+}
+
+impl BanZone {
+    pub fn new(
+        name: &str,
+        max_retry: usize,
+        find_time: Duration,
+        ban_time: Duration,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            max_retry,
+            find_time,
+            ban_time,
+            capacity: capacity.max(1),
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long the address stays banned, if it is.
+    pub fn banned_for(&self, addr: IpAddr, now: Instant) -> Option<Duration> {
+        let offenders = self.offenders.lock().ok()?;
+        let until = offenders.get(&addr)?.banned_until?;
+        until
+            .checked_duration_since(now)
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Counts a failure, banning the address once it has `max_retry` within
+    /// `find_time`. Returns whether this failure banned it.
+    pub fn record_failure(&self, addr: IpAddr, now: Instant) -> bool {
+        let Ok(mut offenders) = self.offenders.lock() else {
+            return false;
+        };
+        if !offenders.contains_key(&addr) && offenders.len() >= self.capacity {
+            self.evict(&mut offenders, now);
+        }
+        let offender = offenders.entry(addr).or_default();
+        if offender.is_banned(now) {
+            return false;
+        }
+        while offender
+            .failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) >= self.find_time)
+        {
+            offender.failures.pop_front();
+        }
+        offender.failures.push_back(now);
+        if offender.failures.len() < self.max_retry {
+            return false;
+        }
+        offender.failures.clear();
+        offender.banned_until = Some(now + self.ban_time);
+        offender.bans += 1;
+        true
+    }
+
+    /// Bans an address for the zone's ban time.
+    pub fn ban(&self, addr: IpAddr, now: Instant) {
+        if let Ok(mut offenders) = self.offenders.lock() {
+            if !offenders.contains_key(&addr) && offenders.len() >= self.capacity {
+                self.evict(&mut offenders, now);
+            }
+            let offender = offenders.entry(addr).or_default();
+            offender.failures.clear();
+            offender.banned_until = Some(now + self.ban_time);
+            offender.bans += 1;
+        }
+    }
+
+    /// Lifts the ban of an address and forgets its failures. Returns
+    /// whether the zone knew it.
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        self.offenders
+            .lock()
+            .map(|mut offenders| offenders.remove(&addr).is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut offenders) = self.offenders.lock() {
+            offenders.clear();
+        }
+    }
+
+    /// The addresses with recent failures or bans, as JSON.
+    pub fn entries(&self, now: Instant) -> Map<String, Value> {
+        let Ok(offenders) = self.offenders.lock() else {
+            return Map::new();
+        };
+        offenders
+            .iter()
+            .filter_map(|(addr, offender)| {
+                let failures = offender
+                    .failures
+                    .iter()
+                    .filter(|failure| now.duration_since(**failure) < self.find_time)
+                    .count();
+                let banned_for = offender
+                    .banned_until
+                    .and_then(|until| until.checked_duration_since(now))
+                    .filter(|left| !left.is_zero());
+                (failures > 0 || banned_for.is_some()).then(|| {
+                    (
+                        addr.to_string(),
+                        json!({
+                            "banned": banned_for.is_some(),
+                            "expires_in": banned_for.map(|left| left.as_secs()),
+                            "failures": failures,
+                            "bans": offender.bans,
+                        }),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Makes room by dropping an address that is no longer banned, the one
+    /// seen longest ago, or else the ban that ends first.
+    fn evict(&self, offenders: &mut HashMap<IpAddr, Offender>, now: Instant) {
+        let victim = offenders
+            .iter()
+            .filter(|(_, offender)| !offender.is_banned(now))
+            .min_by_key(|(_, offender)| offender.last_seen())
+            .or_else(|| {
+                offenders
+                    .iter()
+                    .min_by_key(|(_, offender)| offender.banned_until)
+            })
+            .map(|(addr, _)| *addr);
+        if let Some(addr) = victim {
+            offenders.remove(&addr);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BanZones {
+    pub zones: Vec<Arc<BanZone>>,
+}
+
+impl MergeConfig for BanZones {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// What counts as a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanEvents {
+    pub auth: bool,
+    pub client_errors: bool,
+    pub waf: bool,
+}
+
+impl Default for BanEvents {
+    fn default() -> Self {
+        Self {
+            auth: true,
+            client_errors: false,
+            waf: true,
+        }
+    }
+}
+
+impl BanEvents {
+    fn is_failure(&self, req: &HttpRequest, resp: &HttpResponse) -> bool {
+        let status = resp.status().unwrap_or(200);
+        let client_error = (400..500).contains(&status);
+        (self.auth && matches!(status, 401 | 407))
+            || (self.client_errors && client_error)
+            || (self.waf && client_error && req.var(WAF_RULES).is_some_and(|ids| !ids.is_empty()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanSpec {
+    pub zone: String,
+    pub events: BanEvents,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BanConfig {
+    /// Unset, `Some(None)` after `ban off`, or the zone to use.
+    pub ban: Option<Option<BanSpec>>,
+}
+
+impl MergeConfig for BanConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.ban.is_none() {
+            self.ban = parent.ban.clone();
+        }
+    }
+}
+
+/// Answers banned addresses with 403.
+pub struct BanPhase {
+    zone: Arc<BanZone>,
+}
+
+impl RequestPhase for BanPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let Some(addr) = req.remote_addr().map(|addr| addr.ip()) else {
+            return PhaseResult::Continue;
+        };
+        match self.zone.banned_for(addr, Instant::now()) {
+            Some(left) => {
+                let mut resp =
+                    HttpProcessor::create_status_response(req.version(), StatusCode::FORBIDDEN);
+                resp.set_header("Retry-After", &left.as_secs().max(1).to_string());
+                PhaseResult::Respond(Box::new(resp))
+            }
+            None => PhaseResult::Continue,
+        }
+    }
+}
+
+/// Counts the failed requests of each address.
+pub struct BanFilter {
+    zone: Arc<BanZone>,
+    events: BanEvents,
+}
+
+impl ResponseFilter for BanFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if req.is_subrequest() || !self.events.is_failure(req, resp) {
+            return;
+        }
+        let Some(addr) = req.remote_addr().map(|addr| addr.ip()) else {
+            return;
+        };
+        if self.zone.record_failure(addr, Instant::now()) {
+            log_warn!(
+                "banning {} for {}s after {} failures by zone \"{}\", last request: \"{} {}\"",
+                addr,
+                self.zone.ban_time.as_secs(),
+                self.zone.max_retry,
+                self.zone.name,
+                req.method(),
+                req.request_uri()
+            );
+        }
+    }
+}
+
+fn ban_zone(chain: &[&ConfigContext]) -> Option<(Arc<BanZone>, BanEvents)> {
+    let spec = merged_config::<BanConfig>(chain).ban.flatten()?;
+    let zones = merged_config::<BanZones>(&chain[..1]).zones;
+    let Some(zone) = zones.into_iter().find(|zone| zone.name == spec.zone) else {
+        log_error!("ban: zone \"{}\" is not defined", spec.zone);
+        return None;
+    };
+    Some((zone, spec.events))
+}
+
+/// Builds the ban check for a block chain that sets `ban`.
+pub fn ban_phase(chain: &[&ConfigContext]) -> Option<BanPhase> {
+    ban_zone(chain).map(|(zone, _)| BanPhase { zone })
+}
+
+/// Builds the failure counting filter for a block chain that sets `ban`.
+pub fn ban_filter(chain: &[&ConfigContext]) -> Option<BanFilter> {
+    ban_zone(chain).map(|(zone, events)| BanFilter { zone, events })
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BanApiConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for BanApiConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Answers `/`, `/{zone}` and `/{zone}/{address}` below the location.
+struct BanApi {
+    zones: Vec<Arc<BanZone>>,
+}
+
+impl BanApi {
+    fn handle(&self, req: &HttpRequest, segments: &[&str]) -> ApiResult {
+        let method = req.method();
+        let now = Instant::now();
+        match *segments {
+            [] if method == Method::GET => {
+                let all: Map<String, Value> = self
+                    .zones
+                    .iter()
+                    .map(|zone| (zone.name.clone(), Value::Object(zone.entries(now))))
+                    .collect();
+                Ok((StatusCode::OK, Some(Value::Object(all))))
+            }
+            [name] => {
+                let zone = self.zone(name)?;
+                match *method {
+                    Method::GET => Ok((StatusCode::OK, Some(Value::Object(zone.entries(now))))),
+                    Method::DELETE => {
+                        zone.clear();
+                        log_notice!("lifted every ban of zone \"{}\"", zone.name);
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    _ => Err(ApiError::method("GET, DELETE")),
+                }
+            }
+            [name, addr] => {
+                let zone = self.zone(name)?;
+                let addr: IpAddr = addr
+                    .parse()
+                    .map_err(|_| ApiError::invalid(format!("\"{}\" is not an address", addr)))?;
+                match *method {
+                    Method::GET => {
+                        let entry =
+                            zone.entries(now).remove(&addr.to_string()).ok_or_else(|| {
+                                ApiError::not_found(format!("no failures of {}", addr))
+                            })?;
+                        Ok((StatusCode::OK, Some(entry)))
+                    }
+                    Method::POST | Method::PUT => {
+                        zone.ban(addr, now);
+                        log_notice!("banned {} by zone \"{}\"", addr, zone.name);
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    Method::DELETE => {
+                        if !zone.unban(addr) {
+                            return Err(ApiError::not_found(format!("no failures of {}", addr)));
+                        }
+                        log_notice!("lifted the ban of {} by zone \"{}\"", addr, zone.name);
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    _ => Err(ApiError::method("GET, POST, PUT, DELETE")),
+                }
+            }
+            [] => Err(ApiError::method("GET")),
+            _ => Err(ApiError::not_found("no such resource")),
+        }
+    }
+
+    fn zone(&self, name: &str) -> Result<&Arc<BanZone>, ApiError> {
+        self.zones
+            .iter()
+            .find(|zone| zone.name == name)
+            .ok_or_else(|| ApiError::not_found(format!("no ban_zone named \"{}\"", name)))
+    }
+}
+
+/// Builds the handler for a location block that sets `ban_api on`.
+pub fn ban_api_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let block = chain.last()?;
+    if !merged_config::<BanApiConfig>(&[block])
+        .enabled
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let api = BanApi {
+        zones: merged_config::<BanZones>(&chain[..1]).zones,
+    };
+    Some(api_handler(pattern, move |req, segments| {
+        api.handle(req, segments)
+    }))
+}
+
+pub fn handle_ban_zone(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((zone, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let (name, size) = zone
+        .strip_prefix("zone=")
+        .and_then(|zone| zone.split_once(':'))
+        .ok_or_else(|| ctx.invalid_value(zone, "expected zone=name:size"))?;
+    let size = parse_size(size).map_err(|reason| ctx.invalid_value(zone, reason))?;
+    let mut max_retry = DEFAULT_MAX_RETRY;
+    let mut find_time = DEFAULT_FIND_TIME;
+    let mut ban_time = DEFAULT_BAN_TIME;
+    for option in options {
+        let duration =
+            |value: &str| parse_duration(value).map_err(|reason| ctx.invalid_value(option, reason));
+        match option.split_once('=') {
+            Some(("maxretry", value)) => {
+                max_retry = value
+                    .parse()
+                    .ok()
+                    .filter(|max_retry| *max_retry > 0)
+                    .ok_or_else(|| ctx.invalid_value(option, "expected a positive number"))?;
+            }
+            Some(("findtime", value)) => find_time = duration(value)?,
+            Some(("bantime", value)) => ban_time = duration(value)?,
+            _ => return Err(ctx.invalid_value(option, "unknown option")),
+        }
+    }
+    let zone = BanZone::new(
+        name,
+        max_retry,
+        find_time,
+        ban_time,
+        (size / BYTES_PER_ADDRESS) as usize,
+    );
+    if let Ok(mut config) = ctx.block_config::<BanZones>().lock() {
+        if config.zones.iter().any(|zone| zone.name == name) {
+            return Err(ctx.invalid_value(name, "zone is already defined"));
+        }
+        config.zones.push(Arc::new(zone));
+    }
+    Ok(())
+}
+
+pub fn handle_ban(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((zone, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let spec = if zone == "off" {
+        None
+    } else {
+        let zone = zone
+            .strip_prefix("zone=")
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| ctx.invalid_value(zone, "expected zone=name or off"))?;
+        let mut events = BanEvents::default();
+        for option in options {
+            let Some(list) = option.strip_prefix("events=") else {
+                return Err(ctx.invalid_value(option, "unknown option"));
+            };
+            events = BanEvents {
+                auth: false,
+                client_errors: false,
+                waf: false,
+            };
+            for event in list.split(',') {
+                match event {
+                    "auth" => events.auth = true,
+                    "4xx" => events.client_errors = true,
+                    "waf" => events.waf = true,
+                    _ => return Err(ctx.invalid_value(event, "expected auth, 4xx or waf")),
+                }
+            }
+        }
+        Some(BanSpec {
+            zone: zone.to_string(),
+            events,
+        })
+    };
+    if let Ok(mut config) = ctx.block_config::<BanConfig>().lock() {
+        config.ban = Some(spec);
+    }
+    Ok(())
+}
+
+pub fn handle_ban_api(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<BanApiConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_ban_then_expire() {
+        let zone = BanZone::new(
+            "bans",
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+            2,
+        );
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!zone.record_failure(client, at(0)));
+        assert!(!zone.record_failure(client, at(10)));
+        // The first failure left the window before the third.
+        assert!(!zone.record_failure(client, at(65)));
+        assert!(zone.banned_for(client, at(65)).is_none());
+        assert!(zone.record_failure(client, at(68)));
+        assert_eq!(
+            zone.banned_for(client, at(68)),
+            Some(Duration::from_secs(600))
+        );
+        assert!(!zone.record_failure(client, at(80)));
+        assert_eq!(zone.entries(at(80))["192.0.2.1"]["bans"], 1);
+        assert!(zone.banned_for(client, at(670)).is_none());
+
+        // A full zone makes room by dropping the address seen longest ago
+        // that is not banned.
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let third: IpAddr = "192.0.2.3".parse().unwrap();
+        zone.record_failure(other, at(90));
+        zone.record_failure(third, at(95));
+        assert!(zone.banned_for(client, at(95)).is_some());
+        assert!(!zone.entries(at(95)).contains_key("192.0.2.2"));
+
+        assert!(zone.unban(client));
+        assert!(zone.banned_for(client, at(100)).is_none());
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+/// Largest piece of body read from the client at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest chunk-size line, and trailer section, of a chunked body.
+const MAX_CHUNK_LINE: usize = 8 * 1024;
+
+/// A request body read from the client only as the handler consumes it,
+/// for locations that stream bodies instead of buffering them; an upload
+/// can go straight to disk without being held in memory. A read fails with
+/// `TimedOut` once the client sends nothing for `client_body_timeout`, with
+/// `UnexpectedEof` if it closes the connection early, and with
+/// `InvalidData` on a malformed chunked body.
+pub struct BodyStream {
+    buffered: Vec<u8>,
+    position: usize,
+    /// Bytes of the body not yet received from the connection, unknown for
+    /// a chunked body until its last chunk arrives.
+    remaining: Option<u64>,
+    demand: Sender<()>,
+    chunks: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl BodyStream {
+    /// The bytes of the body the handler has not read yet, once known.
+    pub fn len(&self) -> Option<u64> {
+        self.remaining
+            .map(|remaining| remaining + (self.buffered.len() - self.position) as u64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffered.len() {
+            if self.remaining == Some(0) {
+                return Ok(0);
+            }
+            let chunk = self
+                .demand
+                .send(())
+                .ok()
+                .and_then(|_| self.chunks.recv().ok())
+                .unwrap_or_else(|| Err(io::ErrorKind::BrokenPipe.into()))?;
+            self.remaining = match self.remaining {
+                Some(remaining) => Some(remaining.saturating_sub(chunk.len() as u64)),
+                // An empty piece ends a chunked body.
+                None => chunk.is_empty().then_some(0),
+            };
+            self.buffered = chunk;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.buffered.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffered[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// The connection's end of a `BodyStream`, reading from the client each
+/// time the handler asks for more.
+pub struct BodyFeed {
+    framing: Framing,
+    demand: Receiver<()>,
+    chunks: Sender<io::Result<Vec<u8>>>,
+}
+
+enum Framing {
+    /// Bytes of a `Content-Length` body not yet read.
+    Length(u64),
+    Chunked(ChunkedDecoder),
+}
+
+impl BodyFeed {
+    /// Serves the handler's reads from `source` until the body is read or
+    /// the stream is dropped. Returns whether the whole body was taken off
+    /// the connection.
+    pub fn feed(mut self, source: &mut impl Read) -> bool {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        while !self.is_done() {
+            if self.demand.recv().is_err() {
+                return false;
+            }
+            let chunk = match self.read(source, &mut buffer) {
+                Ok(n) => Ok(buffer[..n].to_vec()),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock) => {
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if self.chunks.send(chunk).is_err() || failed {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_done(&self) -> bool {
+        match &self.framing {
+            Framing::Length(remaining) => *remaining == 0,
+            Framing::Chunked(decoder) => decoder.done,
+        }
+    }
+
+    fn read(&mut self, source: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+        match &mut self.framing {
+            Framing::Length(remaining) => {
+                let want = buffer.len().min(*remaining as usize);
+                match source.read(&mut buffer[..want])? {
+                    0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => {
+                        *remaining -= n as u64;
+                        Ok(n)
+                    }
+                }
+            }
+            Framing::Chunked(decoder) => decoder.read(source, buffer),
+        }
+    }
+}
+
+/// Decodes a chunked body as it is read, taking no byte past its end off
+/// the connection so that the next request stays in place; the framing
+/// lines are read a byte at a time for that.
+struct ChunkedDecoder {
+    /// Bytes left of the data of the current chunk.
+    left: u64,
+    /// Set once a chunk's data is read and before the CRLF after it is.
+    data_end: bool,
+    done: bool,
+    decoded: u64,
+    /// The most bytes the body may decode to, or 0 for no limit.
+    limit: u64,
+}
+
+impl ChunkedDecoder {
+    /// Reads data of the body into `buf`, returning 0 once the last chunk
+    /// and the trailer section are read.
+    fn read(&mut self, source: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+        while self.left == 0 {
+            if self.done {
+                return Ok(0);
+            }
+            if self.data_end {
+                if !read_line(source, MAX_CHUNK_LINE)?.is_empty() {
+                    return Err(invalid("chunk data longer than its size"));
+                }
+                self.data_end = false;
+            }
+            let size = parse_chunk_size(&read_line(source, MAX_CHUNK_LINE)?)
+                .ok_or_else(|| invalid("invalid chunk size"))?;
+            if size == 0 {
+                // Trailer fields are not passed on.
+                let mut budget = MAX_CHUNK_LINE;
+                loop {
+                    let line = read_line(source, budget)?;
+                    if line.is_empty() {
+                        break;
+                    }
+                    budget -= line.len();
+                }
+                self.done = true;
+                return Ok(0);
+            }
+            if self.limit > 0 && self.decoded.saturating_add(size) > self.limit {
+                return Err(invalid("request body too large"));
+            }
+            self.left = size;
+        }
+        let want = buf
+            .len()
+            .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        let n = match source.read(&mut buf[..want])? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => n,
+        };
+        self.left -= n as u64;
+        self.decoded += n as u64;
+        self.data_end = self.left == 0;
+        Ok(n)
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads a CRLF-terminated line of at most `max` bytes, without its CRLF.
+fn read_line(source: &mut impl Read, max: usize) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        if source.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match byte[0] {
+            b'\n' if line.last() == Some(&b'\r') => {
+                line.pop();
+                return Ok(line);
+            }
+            b'\n' => return Err(invalid("bare LF in chunked body")),
+            _ if line.last() == Some(&b'\r') => return Err(invalid("bare CR in chunked body")),
+            b => line.push(b),
+        }
+        if line.len() > max + 1 {
+            return Err(invalid("chunked body line too long"));
+        }
+    }
+}
+
+/// Parses `chunk-size [ chunk-ext ]`; extensions are ignored.
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let end = line.iter().position(|b| *b == b';').unwrap_or(line.len());
+    let size = &line[..end];
+    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u64::from_str_radix(std::str::from_utf8(size).ok()?, 16).ok()
+}
+
+/// Pairs a body whose first bytes, `received`, arrived with the header
+/// and whose other `remaining` bytes are still to be read from the client.
+pub fn body_channel(received: Vec<u8>, remaining: u64) -> (BodyStream, BodyFeed) {
+    channel(received, Some(remaining), Framing::Length(remaining))
+}
+
+/// Pairs a chunked body, decoded from the client as it is read; a chunk
+/// that would take it past `limit` bytes, unless that is 0, fails the read.
+pub fn chunked_body_channel(limit: u64) -> (BodyStream, BodyFeed) {
+    let decoder = ChunkedDecoder {
+        left: 0,
+        data_end: false,
+        done: false,
+        decoded: 0,
+        limit,
+    };
+    channel(Vec::new(), None, Framing::Chunked(decoder))
+}
+
+fn channel(received: Vec<u8>, remaining: Option<u64>, framing: Framing) -> (BodyStream, BodyFeed) {
+    let (demand_sender, demand) = mpsc::channel();
+    let (chunks, chunk_receiver) = mpsc::channel();
+    let stream = BodyStream {
+        buffered: received,
+        position: 0,
+        remaining,
+        demand: demand_sender,
+        chunks: chunk_receiver,
+    };
+    let feed = BodyFeed {
+        framing,
+        demand,
+        chunks,
+    };
+    (stream, feed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Hands out at most `step` bytes per read, like a client sending the
+    /// body in small packets, then fails with `end` or reports EOF.
+    struct Trickle {
+        data: Vec<u8>,
+        read: usize,
+        step: usize,
+        end: Option<io::ErrorKind>,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.read == self.data.len() {
+                return self.end.map_or(Ok(0), |kind| Err(kind.into()));
+            }
+            let n = buf.len().min(self.step).min(self.data.len() - self.read);
+            buf[..n].copy_from_slice(&self.data[self.read..self.read + n]);
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    fn trickle(data: &[u8], step: usize) -> Trickle {
+        Trickle {
+            data: data.to_vec(),
+            read: 0,
+            step,
+            end: None,
+        }
+    }
+
+    /// Runs `handler` on the stream while the feed reads `source` on another
+    /// thread, as the server does; the stream is dropped with the handler.
+    fn serve<T: Send>(
+        (stream, feed): (BodyStream, BodyFeed),
+        source: &mut Trickle,
+        handler: impl FnOnce(BodyStream) -> T + Send,
+    ) -> (T, bool) {
+        thread::scope(|scope| {
+            let reader = scope.spawn(move || feed.feed(source));
+            let output = handler(stream);
+            (output, reader.join().unwrap())
+        })
+    }
+
+    #[test]
+    fn test_reads_content_length_across_partial_reads() {
+        let mut source = trickle(b"llo worldGET / HTTP/1.1\r\n", 2);
+        let channel = body_channel(b"he".to_vec(), 9);
+        assert_eq!(channel.0.len(), Some(11));
+        let (body, complete) = serve(channel, &mut source, |mut stream| {
+            let mut body = Vec::new();
+            stream.read_to_end(&mut body).unwrap();
+            assert!(stream.is_empty());
+            body
+        });
+        assert_eq!(body, b"hello world");
+        assert!(complete);
+        // The next request on the connection is left in place.
+        assert_eq!(source.read, 9);
+    }
+
+    #[test]
+    fn test_dropped_stream_stops_reading() {
+        let mut source = trickle(&[b'x'; 100], 10);
+        let (len, complete) = serve(body_channel(Vec::new(), 100), &mut source, |mut stream| {
+            let mut start = [0; 5];
+            stream.read_exact(&mut start).unwrap();
+            stream.len()
+        });
+        assert_eq!(len, Some(95));
+        assert!(!complete);
+        assert_eq!(source.read, 10);
+    }
+
+    #[test]
+    fn test_client_disconnect_mid_body() {
+        let mut source = trickle(b"abcd", 4);
+        let (error, complete) = serve(body_channel(Vec::new(), 10), &mut source, |mut stream| {
+            stream.read_to_end(&mut Vec::new()).unwrap_err().kind()
+        });
+        assert_eq!(error, io::ErrorKind::UnexpectedEof);
+        assert!(!complete);
+
+        // A read timeout on the socket reaches the handler as `TimedOut`.
+        let mut source = trickle(b"abcd", 4);
+        source.end = Some(io::ErrorKind::WouldBlock);
+        let (error, complete) = serve(body_channel(Vec::new(), 10), &mut source, |mut stream| {
+            stream.read_to_end(&mut Vec::new()).unwrap_err().kind()
+        });
+        assert_eq!(error, io::ErrorKind::TimedOut);
+        assert!(!complete);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        // A handler with a limit, like `json_limited`, reads one byte past it
+        // and the rest of the body is never taken off the connection.
+        let limit = 8;
+        let mut source = trickle(&[b'x'; 20], 4);
+        let (read, complete) = serve(body_channel(Vec::new(), 20), &mut source, |stream| {
+            let mut body = Vec::new();
+            stream.take(limit + 1).read_to_end(&mut body).unwrap();
+            body.len()
+        });
+        assert_eq!(read, 9);
+        assert!(!complete);
+        assert_eq!(source.read, 12);
+
+        // Bytes past the declared length are never handed out.
+        let mut source = trickle(&[b'x'; 20], 64);
+        let (read, complete) = serve(
+            body_channel(b"ab".to_vec(), 6),
+            &mut source,
+            |mut stream| {
+                let mut body = Vec::new();
+                stream.read_to_end(&mut body).unwrap();
+                body.len()
+            },
+        );
+        assert_eq!(read, 8);
+        assert!(complete);
+        assert_eq!(source.read, 6);
+    }
+
+    fn read_chunked(encoded: &[u8], step: usize, limit: u64) -> (io::Result<Vec<u8>>, Trickle) {
+        let mut source = trickle(encoded, step);
+        let (body, _) = serve(chunked_body_channel(limit), &mut source, |mut stream| {
+            assert_eq!(stream.len(), None);
+            let mut body = Vec::new();
+            stream.read_to_end(&mut body).map(|_| {
+                assert!(stream.is_empty());
+                body
+            })
+        });
+        (body, source)
+    }
+
+    #[test]
+    fn test_reads_chunked_body() {
+        let encoded =
+            b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: 1\r\n\r\nGET / HTTP/1.1\r\n";
+        for step in [1, 3, 64] {
+            let (body, source) = read_chunked(encoded, step, 0);
+            assert_eq!(body.unwrap(), b"hello world");
+            // The next request on the connection is left in place.
+            assert_eq!(&source.data[source.read..], b"GET / HTTP/1.1\r\n");
+        }
+
+        let error = |encoded: &[u8]| read_chunked(encoded, 4, 0).0.unwrap_err().kind();
+        assert_eq!(error(b"5\nhello\r\n0\r\n\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(error(b"x\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(error(b" 5\r\nhello\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(error(b"10000000000000000\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error(b"3\r\nhello\r\n0\r\n\r\n"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(error(b"5\r\nhel"), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error(b"5\r\nhello\r\n"), io::ErrorKind::UnexpectedEof);
+
+        // A chunk past the limit fails before its data is read.
+        let (body, source) = read_chunked(b"4\r\nabcd\r\n5\r\nefghi\r\n0\r\n\r\n", 64, 8);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(source.read, 12);
+    }
+}
@@ -0,0 +1,659 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::StatusCode;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, rand::rand_bytes, sign::Signer};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::HttpRequest, http_response::HttpResponse, http_variables::parse_regex_arg,
+};
+
+register_commands!(
+    CommandBuilder::new("bot_filter")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Bot Filter")
+        .display_name("zh-tw", "機器人過濾")
+        .desc(
+            "en",
+            "What to do with requests matching the bot fingerprints: tag only sets $is_bot, block answers 403 and challenge serves a page that sets a cookie with JavaScript"
+        )
+        .desc(
+            "zh-tw",
+            "符合機器人特徵的請求的處理方式：tag 只設定 $is_bot，block 以 403 回應，challenge 則回傳以 JavaScript 設定 Cookie 的頁面"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Mode")
+            .display_name("zh-tw", "模式")
+            .type_name("String")
+            .is_required(true)
+            .default("off")
+            .desc("en", "tag, block, challenge or off")
+            .desc("zh-tw", "tag、block、challenge 或 off")
+            .build()])
+        .build(handle_bot_filter),
+    CommandBuilder::new("bot_user_agent")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Bot User Agents")
+        .display_name("zh-tw", "機器人 User-Agent")
+        .desc(
+            "en",
+            "User-Agent patterns marking a request as a bot; without any bot_user_agent or bot_header the default list is used"
+        )
+        .desc(
+            "zh-tw",
+            "將請求標記為機器人的 User-Agent 樣式；未設定 bot_user_agent 與 bot_header 時使用預設清單"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Patterns")
+            .display_name("zh-tw", "樣式")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Case-insensitive substrings or ~regex / ~*regex; default adds the built-in list of tools and crawlers and empty matches a missing or empty User-Agent"
+            )
+            .desc(
+                "zh-tw",
+                "不分大小寫的子字串或 ~regex / ~*regex；default 加入內建的工具與爬蟲清單，empty 符合缺少或空白的 User-Agent"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_bot_user_agent),
+    CommandBuilder::new("bot_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Bot Header")
+        .display_name("zh-tw", "機器人標頭")
+        .desc(
+            "en",
+            "A request header fingerprint marking a request as a bot"
+        )
+        .desc("zh-tw", "將請求標記為機器人的請求標頭特徵")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Header")
+                .display_name("zh-tw", "標頭")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "The header name, matching when it is present, or !name, matching when it is missing"
+                )
+                .desc("zh-tw", "標頭名稱，存在時符合；或 !名稱，缺少時符合")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Value")
+                .display_name("zh-tw", "值")
+                .type_name("String")
+                .default("")
+                .desc("en", "A ~regex or ~*regex the value must match")
+                .desc("zh-tw", "值必須符合的 ~regex 或 ~*regex")
+                .build(),
+        ])
+        .arity(Arity::Range(1, 2))
+        .build(handle_bot_header),
+    CommandBuilder::new("bot_allow")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Allowed Bots")
+        .display_name("zh-tw", "允許的機器人")
+        .desc(
+            "en",
+            "User-Agent patterns of bots that are tagged but never blocked or challenged"
+        )
+        .desc(
+            "zh-tw",
+            "只標記、不會被阻擋或驗證的機器人 User-Agent 樣式"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Patterns")
+            .display_name("zh-tw", "樣式")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Case-insensitive substrings or ~regex / ~*regex")
+            .desc("zh-tw", "不分大小寫的子字串或 ~regex / ~*regex")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_bot_allow),
+    CommandBuilder::new("bot_challenge_secret")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Bot Challenge Secret")
+        .display_name("zh-tw", "機器人驗證密鑰")
+        .desc(
+            "en",
+            "The key signing challenge cookies; a random key chosen at startup is used by default, so servers sharing clients should set the same one"
+        )
+        .desc(
+            "zh-tw",
+            "簽署驗證 Cookie 的密鑰；預設使用啟動時產生的隨機密鑰，服務相同客戶端的多台伺服器應設定相同的密鑰"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Secret")
+            .display_name("zh-tw", "密鑰")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Any text")
+            .desc("zh-tw", "任意文字")
+            .build()])
+        .build(handle_bot_challenge_secret),
+    CommandBuilder::new("bot_challenge_ttl")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Bot Challenge Lifetime")
+        .display_name("zh-tw", "機器人驗證有效時間")
+        .desc(
+            "en",
+            "How long a solved challenge lets a client through"
+        )
+        .desc("zh-tw", "通過驗證後客戶端可免驗證的時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "e.g. 30m or 1d; 1h by default")
+            .desc("zh-tw", "例如 30m 或 1d；預設 1h")
+            .build()])
+        .build(handle_bot_challenge_ttl)
+);
+
+/// Set to `1` for requests matching the bot fingerprints and empty otherwise.
+pub const IS_BOT: &str = "is_bot";
+/// The cookie holding a solved challenge.
+pub const BOT_COOKIE: &str = "blur_bot";
+
+const DEFAULT_CHALLENGE_TTL: Duration = Duration::from_secs(3600);
+
+/// Tools, libraries and crawlers that announce themselves in the User-Agent.
+const DEFAULT_AGENTS: &[&str] = &[
+    "curl",
+    "wget",
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "go-http-client",
+    "libwww-perl",
+    "java/",
+    "okhttp",
+    "apache-httpclient",
+    "scrapy",
+    "headlesschrome",
+    "phantomjs",
+    "nikto",
+    "sqlmap",
+    "nmap",
+    "masscan",
+    "zgrab",
+    "bot",
+    "crawler",
+    "spider",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotMode {
+    Off,
+    Tag,
+    Block,
+    Challenge,
+}
+
+/// A User-Agent fingerprint.
+#[derive(Debug, Clone)]
+pub enum AgentPattern {
+    /// A missing or empty User-Agent.
+    Empty,
+    /// A lowercase substring.
+    Contains(String),
+    Regex(Regex),
+}
+
+impl AgentPattern {
+    fn parse(arg: &str) -> Result<Self, String> {
+        if arg == "empty" {
+            return Ok(Self::Empty);
+        }
+        match parse_regex_arg(arg) {
+            Some(regex) => regex.map(Self::Regex),
+            None => Ok(Self::Contains(arg.to_ascii_lowercase())),
+        }
+    }
+
+    fn matches(&self, agent: &str) -> bool {
+        match self {
+            Self::Empty => agent.trim().is_empty(),
+            Self::Contains(needle) => {
+                !agent.is_empty() && agent.to_ascii_lowercase().contains(needle)
+            }
+            Self::Regex(regex) => regex.is_match(agent),
+        }
+    }
+}
+
+/// A request header fingerprint.
+#[derive(Debug, Clone)]
+pub enum HeaderPattern {
+    Present(String),
+    Missing(String),
+    Matches(String, Regex),
+}
+
+impl HeaderPattern {
+    fn matches(&self, req: &HttpRequest) -> bool {
+        match self {
+            Self::Present(name) => req.header(name).is_some(),
+            Self::Missing(name) => req.header(name).is_none(),
+            Self::Matches(name, regex) => {
+                req.header(name).is_some_and(|value| regex.is_match(value))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct BotFilterConfig {
+    pub mode: Option<BotMode>,
+    pub agents: Option<Vec<AgentPattern>>,
+    pub headers: Option<Vec<HeaderPattern>>,
+    pub allow: Option<Vec<AgentPattern>>,
+    pub secret: Option<Vec<u8>>,
+    pub ttl: Option<Duration>,
+}
+
+impl MergeConfig for BotFilterConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.mode.is_none() {
+            self.mode = parent.mode;
+        }
+        // The fingerprints are one list, inherited only by blocks setting
+        // neither part of it.
+        if self.agents.is_none() && self.headers.is_none() {
+            self.agents = parent.agents.clone();
+            self.headers = parent.headers.clone();
+        }
+        if self.allow.is_none() {
+            self.allow = parent.allow.clone();
+        }
+        if self.secret.is_none() {
+            self.secret = parent.secret.clone();
+        }
+        if self.ttl.is_none() {
+            self.ttl = parent.ttl;
+        }
+    }
+}
+
+/// Tags bots in `$is_bot` and blocks or challenges them, ahead of the rate
+/// limits that may key on the variable.
+pub struct BotFilterPhase {
+    mode: BotMode,
+    agents: Vec<AgentPattern>,
+    headers: Vec<HeaderPattern>,
+    allow: Vec<AgentPattern>,
+    secret: Vec<u8>,
+    ttl: Duration,
+}
+
+impl BotFilterPhase {
+    fn is_bot(&self, req: &HttpRequest) -> bool {
+        let agent = req.header("User-Agent").unwrap_or_default();
+        self.agents.iter().any(|pattern| pattern.matches(agent))
+            || self.headers.iter().any(|pattern| pattern.matches(req))
+    }
+
+    fn is_allowed(&self, req: &HttpRequest) -> bool {
+        let agent = req.header("User-Agent").unwrap_or_default();
+        self.allow.iter().any(|pattern| pattern.matches(agent))
+    }
+
+    /// Signs the client address and User-Agent until `expires`.
+    fn token(&self, req: &HttpRequest, expires: u64) -> Option<String> {
+        let addr = req.remote_addr().map(|addr| addr.ip().to_string());
+        let message = format!(
+            "{}|{}|{}",
+            addr.unwrap_or_default(),
+            expires,
+            req.header("User-Agent").unwrap_or_default()
+        );
+        let mac = PKey::hmac(&self.secret)
+            .and_then(|key| {
+                Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(message.as_bytes())
+            })
+            .ok()?;
+        let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+        Some(format!("{}.{}", expires, hex))
+    }
+
+    fn solved(&self, req: &HttpRequest, now: u64) -> bool {
+        let cookie = req.header("Cookie").and_then(|cookies| {
+            cookies.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                (key == BOT_COOKIE).then_some(value)
+            })
+        });
+        let Some((expires, _)) = cookie.and_then(|cookie| cookie.split_once('.')) else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<u64>() else {
+            return false;
+        };
+        let (Some(cookie), Some(expected)) = (cookie, self.token(req, expires)) else {
+            return false;
+        };
+        expires >= now
+            && cookie.len() == expected.len()
+            && memcmp::eq(cookie.as_bytes(), expected.as_bytes())
+    }
+
+    fn challenge(&self, req: &HttpRequest, now: u64) -> HttpResponse {
+        let ttl = self.ttl.as_secs();
+        let token = self.token(req, now + ttl).unwrap_or_default();
+        let reversed: String = token.chars().rev().collect();
+        let page = format!(
+            concat!(
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">",
+                "<meta name=\"robots\" content=\"noindex\"><title>Checking your browser</title></head>\n",
+                "<body><p id=\"m\">Checking your browser&hellip;</p>\n",
+                "<noscript><p>Please turn on JavaScript and cookies to continue.</p></noscript>\n",
+                "<script>document.cookie=\"{}=\"+\"{}\".split(\"\").reverse().join(\"\")",
+                "+\"; path=/; max-age={}; SameSite=Lax\";",
+                "if(document.cookie.indexOf(\"{}=\")<0){{document.getElementById(\"m\").textContent=",
+                "\"Please turn on cookies to continue.\"}}else{{location.reload()}}</script>\n",
+                "</body></html>\n"
+            ),
+            BOT_COOKIE, reversed, ttl, BOT_COOKIE
+        );
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), StatusCode::SERVICE_UNAVAILABLE);
+        resp.set_header("Content-Type", "text/html; charset=utf-8");
+        resp.set_header("Cache-Control", "no-store");
+        resp.set_body(&page);
+        resp
+    }
+
+    fn decide(&self, req: &mut HttpRequest, now: u64) -> PhaseResult {
+        if !self.is_bot(req) {
+            req.set_var(IS_BOT, "");
+            return PhaseResult::Continue;
+        }
+        // A solved challenge counts the client as a person from then on.
+        if self.mode == BotMode::Challenge && self.solved(req, now) {
+            req.set_var(IS_BOT, "");
+            return PhaseResult::Continue;
+        }
+        req.set_var(IS_BOT, "1");
+        if self.is_allowed(req) {
+            return PhaseResult::Continue;
+        }
+        match self.mode {
+            BotMode::Block => PhaseResult::Respond(Box::new(
+                HttpProcessor::create_status_response(req.version(), StatusCode::FORBIDDEN),
+            )),
+            BotMode::Challenge => PhaseResult::Respond(Box::new(self.challenge(req, now))),
+            BotMode::Off | BotMode::Tag => PhaseResult::Continue,
+        }
+    }
+}
+
+impl RequestPhase for BotFilterPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.decide(req, now)
+    }
+}
+
+/// The key challenge cookies are signed with when none is configured.
+fn process_secret() -> Vec<u8> {
+    static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    SECRET
+        .get_or_init(|| {
+            let mut bytes = [0u8; 32];
+            rand_bytes(&mut bytes).expect("the system random source is available");
+            bytes
+        })
+        .to_vec()
+}
+
+/// Builds the bot filter for a block chain with `bot_filter` turned on.
+pub fn bot_filter_phase(chain: &[&ConfigContext]) -> Option<BotFilterPhase> {
+    let config = merged_config::<BotFilterConfig>(chain);
+    let mode = config.mode.filter(|mode| *mode != BotMode::Off)?;
+    let (agents, headers) = match (config.agents, config.headers) {
+        (None, None) => (
+            DEFAULT_AGENTS
+                .iter()
+                .map(|agent| AgentPattern::Contains(agent.to_string()))
+                .chain([AgentPattern::Empty])
+                .collect(),
+            Vec::new(),
+        ),
+        (agents, headers) => (agents.unwrap_or_default(), headers.unwrap_or_default()),
+    };
+    Some(BotFilterPhase {
+        mode,
+        agents,
+        headers,
+        allow: config.allow.unwrap_or_default(),
+        secret: config.secret.unwrap_or_else(process_secret),
+        ttl: config.ttl.unwrap_or(DEFAULT_CHALLENGE_TTL),
+    })
+}
+
+fn agent_patterns(ctx: &ConfigContext) -> Result<Vec<AgentPattern>, ConfigError> {
+    let mut patterns = Vec::new();
+    for arg in ctx.args() {
+        if arg == "default" {
+            patterns.extend(
+                DEFAULT_AGENTS
+                    .iter()
+                    .map(|agent| AgentPattern::Contains(agent.to_string())),
+            );
+            patterns.push(AgentPattern::Empty);
+            continue;
+        }
+        patterns.push(AgentPattern::parse(&arg).map_err(|reason| ctx.invalid_value(&arg, reason))?);
+    }
+    Ok(patterns)
+}
+
+pub fn handle_bot_filter(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(mode) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    let mode = match mode.as_str() {
+        "off" => BotMode::Off,
+        "tag" => BotMode::Tag,
+        "block" => BotMode::Block,
+        "challenge" => BotMode::Challenge,
+        _ => return Err(ctx.invalid_value(&mode, "expected tag, block, challenge or off")),
+    };
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.mode = Some(mode);
+    }
+    Ok(())
+}
+
+pub fn handle_bot_user_agent(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let patterns = agent_patterns(ctx)?;
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.agents.get_or_insert_with(Vec::new).extend(patterns);
+    }
+    Ok(())
+}
+
+pub fn handle_bot_header(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((name, value)) = args.split_first() else {
+        return Ok(());
+    };
+    let pattern = match (name.strip_prefix('!'), value.first()) {
+        (Some(name), None) => HeaderPattern::Missing(name.to_string()),
+        (Some(_), Some(value)) => {
+            return Err(ctx.invalid_value(value, "a missing header has no value to match"))
+        }
+        (None, None) => HeaderPattern::Present(name.clone()),
+        (None, Some(value)) => match parse_regex_arg(value) {
+            Some(Ok(regex)) => HeaderPattern::Matches(name.clone(), regex),
+            Some(Err(reason)) => return Err(ctx.invalid_value(value, reason)),
+            None => return Err(ctx.invalid_value(value, "expected ~regex or ~*regex")),
+        },
+    };
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.headers.get_or_insert_with(Vec::new).push(pattern);
+    }
+    Ok(())
+}
+
+pub fn handle_bot_allow(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let patterns = agent_patterns(ctx)?;
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.allow.get_or_insert_with(Vec::new).extend(patterns);
+    }
+    Ok(())
+}
+
+pub fn handle_bot_challenge_secret(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let Some(secret) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.secret = Some(secret.into_bytes());
+    }
+    Ok(())
+}
+
+pub fn handle_bot_challenge_ttl(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let ttl = ctx.duration_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<BotFilterConfig>().lock() {
+        config.ttl = Some(ttl);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        let raw = format!("GET / HTTP/1.1\r\nHost: a\r\n{}\r\n", headers);
+        req.parse(raw.as_bytes()).unwrap();
+        req
+    }
+
+    fn status(result: PhaseResult) -> Option<u16> {
+        match result {
+            PhaseResult::Respond(resp) => resp.status(),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_bots_are_tagged_blocked_and_challenged() {
+        let mut phase = BotFilterPhase {
+            mode: BotMode::Block,
+            agents: vec![AgentPattern::parse("curl").unwrap(), AgentPattern::Empty],
+            headers: vec![HeaderPattern::Missing("Accept-Language".to_string())],
+            allow: vec![AgentPattern::parse("~*googlebot").unwrap()],
+            secret: b"key".to_vec(),
+            ttl: DEFAULT_CHALLENGE_TTL,
+        };
+        let browser = "User-Agent: Mozilla/5.0\r\nAccept-Language: en\r\n";
+        let mut req = request(browser);
+        assert!(matches!(phase.decide(&mut req, 0), PhaseResult::Continue));
+        assert_eq!(req.var(IS_BOT), Some(""));
+
+        assert_eq!(
+            status(phase.decide(&mut request("User-Agent: CURL/8.0\r\n"), 0)),
+            Some(403)
+        );
+        assert_eq!(
+            status(phase.decide(&mut request("Accept-Language: en\r\n"), 0)),
+            Some(403)
+        );
+        assert_eq!(
+            status(phase.decide(&mut request("User-Agent: Mozilla/5.0\r\n"), 0)),
+            Some(403)
+        );
+
+        let mut req = request("User-Agent: Googlebot/2.1\r\n");
+        assert!(matches!(phase.decide(&mut req, 0), PhaseResult::Continue));
+        assert_eq!(req.var(IS_BOT), Some("1"));
+
+        phase.mode = BotMode::Challenge;
+        let agent = "User-Agent: curl/8.0\r\n";
+        assert_eq!(status(phase.decide(&mut request(agent), 0)), Some(503));
+        let token = phase.token(&request(agent), 100).unwrap();
+        let solved = format!("{}Cookie: a=b; {}={}\r\n", agent, BOT_COOKIE, token);
+        let mut req = request(&solved);
+        assert!(matches!(phase.decide(&mut req, 50), PhaseResult::Continue));
+        assert_eq!(req.var(IS_BOT), Some(""));
+        assert_eq!(status(phase.decide(&mut request(&solved), 200)), Some(503));
+        let other = format!("User-Agent: wget\r\nCookie: {}={}\r\n", BOT_COOKIE, token);
+        assert_eq!(status(phase.decide(&mut request(&other), 50)), Some(503));
+
+        phase.mode = BotMode::Tag;
+        let mut req = request(agent);
+        assert!(matches!(phase.decide(&mut req, 0), PhaseResult::Continue));
+        assert_eq!(req.var(IS_BOT), Some("1"));
+    }
+}
@@ -0,0 +1,152 @@
+use std::io::{self, Write};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, CommandBuilder, ParameterBuilder},
+        config_context::{merged_config, ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_compression::Encoder;
+
+register_commands!(
+    CommandBuilder::new("brotli")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Brotli")
+        .display_name("zh-tw", "Brotli 壓縮")
+        .desc(
+            "en",
+            "Compresses responses with Brotli for clients that accept br"
+        )
+        .desc("zh-tw", "對接受 br 的客戶端以 Brotli 壓縮回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_brotli),
+    CommandBuilder::new("brotli_comp_level")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Brotli Compression Level")
+        .display_name("zh-tw", "Brotli 壓縮等級")
+        .desc("en", "Sets the Brotli compression level")
+        .desc("zh-tw", "設定 Brotli 壓縮等級")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Level")
+            .display_name("zh-tw", "等級")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "0 (fastest) to 11 (smallest), defaults to 6")
+            .desc("zh-tw", "0（最快）到 11（最小），預設為 6")
+            .build()])
+        .build(handle_brotli_comp_level),
+);
+
+pub const DEFAULT_COMP_LEVEL: u32 = 6;
+
+#[derive(Debug, Default, Clone)]
+pub struct BrotliConfig {
+    pub enabled: Option<bool>,
+    pub comp_level: Option<u32>,
+}
+
+impl MergeConfig for BrotliConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        self.comp_level = self.comp_level.or(parent.comp_level);
+    }
+}
+
+pub struct BrotliEncoder {
+    level: u32,
+}
+
+impl BrotliEncoder {
+    pub fn from_chain(chain: &[&ConfigContext]) -> Option<Self> {
+        let config = merged_config::<BrotliConfig>(chain);
+        config.enabled.unwrap_or(false).then(|| Self {
+            level: config.comp_level.unwrap_or(DEFAULT_COMP_LEVEL),
+        })
+    }
+}
+
+impl Encoder for BrotliEncoder {
+    fn coding(&self) -> &'static str {
+        "br"
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, self.level, 22);
+        encoder.write_all(body)?;
+        encoder.flush()?;
+        Ok(encoder.into_inner())
+    }
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut BrotliConfig)) {
+    if let Ok(mut config) = ctx.block_config::<BrotliConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_brotli(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.enabled = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_brotli_comp_level(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let level = ctx.number_arg(0)?;
+    if !(0..=11).contains(&level) {
+        return Err(ctx.invalid_value(&level.to_string(), "must be between 0 and 11"));
+    }
+    update(ctx, |config| config.comp_level = Some(level as u32));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let body = "brotli brotli brotli brotli brotli".repeat(64);
+        for level in [0, DEFAULT_COMP_LEVEL, 11] {
+            let encoded = BrotliEncoder { level }.encode(body.as_bytes()).unwrap();
+            assert!(encoded.len() < body.len());
+            let mut decoded = String::new();
+            brotli::Decompressor::new(encoded.as_slice(), 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, body);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use thiserror::Error;
+
+use crate::core::processor::{
+    async_handler, AsyncHandler, HttpHandler, HttpProcessor, LocationPattern, Middleware,
+    RequestPhase, ResponseFilter,
+};
+
+use super::{
+    http_core::HttpCoreConfig,
+    http_mime::MimeConfig,
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_server::HttpServer,
+    http_static::{StaticConfig, StaticFiles},
+};
+
+#[derive(Debug, Error)]
+pub enum ServerBuildError {
+    #[error("Invalid route \"{0}\": {1}")]
+    InvalidRoute(String, String),
+    #[error("Invalid TLS settings: {0}")]
+    Tls(String),
+    #[error("Failed to listen on {0}: {1}")]
+    Listen(String, std::io::Error),
+}
+
+/// A location added with `ServerBuilder::route`.
+struct Route {
+    pattern: LocationPattern,
+    handler: HttpHandler,
+    phases: Vec<Arc<dyn RequestPhase>>,
+    filters: Vec<Arc<dyn ResponseFilter>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    stream_body: bool,
+}
+
+/// Builds a server in code, for applications embedding blur without a
+/// configuration file.
+///
+/// Routes take location patterns as written after `location` in a
+/// configuration file, such as `/api/`, `= /health` or `~ \.php$`, and are
+/// selected the same way. Phases run before the handler and filters after
+/// it, for every route; middleware wraps the whole handling of a request,
+/// or of one route, for concerns such as authentication or tracing that
+/// need both sides.
+///
+/// ```no_run
+/// use blur::http::{http_builder::ServerBuilder, http_response::HttpResponse};
+/// use http::StatusCode;
+///
+/// let server = ServerBuilder::new()
+///     .listen("127.0.0.1:8080")
+///     .route("/hello", |req| {
+///         let mut resp = HttpResponse::new();
+///         resp.set_status_line(*req.version(), StatusCode::OK);
+///         resp.set_header("Content-Type", "text/plain");
+///         resp.set_body("hello");
+///         resp
+///     })
+///     .static_dir("/assets/", "./public")
+///     .build()
+///     .unwrap();
+/// server.start().join().unwrap();
+/// ```
+pub struct ServerBuilder {
+    listen: String,
+    tls: Option<Arc<ServerConfig>>,
+    routes: Vec<Route>,
+    default_handler: Option<HttpHandler>,
+    phases: Vec<Arc<dyn RequestPhase>>,
+    filters: Vec<Arc<dyn ResponseFilter>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    core: HttpCoreConfig,
+    /// The first mistake made while building, reported by `build`.
+    error: Option<ServerBuildError>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_string(),
+            tls: None,
+            routes: Vec::new(),
+            default_handler: None,
+            phases: Vec::new(),
+            filters: Vec::new(),
+            middleware: Vec::new(),
+            core: HttpCoreConfig::default(),
+            error: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the address to listen on, `127.0.0.1:8080` by default.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        self.listen = addr.into();
+        self
+    }
+
+    /// Serves HTTPS with a ready rustls configuration.
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Serves HTTPS with a PEM certificate chain and private key.
+    pub fn tls_pem_files(mut self, cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        let config = (|| {
+            let certs = CertificateDer::pem_file_iter(cert.as_ref())
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("{}: {}", cert.as_ref().display(), e))?;
+            let key = PrivateKeyDer::from_pem_file(key.as_ref())
+                .map_err(|e| format!("{}: {}", key.as_ref().display(), e))?;
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .map_err(|e| e.to_string())
+        })();
+        match config {
+            Ok(config) => self.tls = Some(Arc::new(config)),
+            Err(e) => self.fail(ServerBuildError::Tls(e)),
+        }
+        self
+    }
+
+    /// Answers requests matching a location pattern with `handler`.
+    pub fn route(
+        self,
+        pattern: &str,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) -> Self {
+        self.route_with(pattern, handler, Vec::new(), Vec::new())
+    }
+
+    /// Like `route`, for a handler returning a future, such as an async
+    /// closure; the future runs on a small shared executor.
+    pub fn route_async(self, pattern: &str, handler: impl AsyncHandler + 'static) -> Self {
+        self.route_handler(pattern, async_handler(handler), Vec::new(), Vec::new())
+    }
+
+    /// Like `route`, with phases and filters for this route only; its
+    /// phases run after the server-wide ones and its filters before them.
+    pub fn route_with(
+        self,
+        pattern: &str,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+        phases: Vec<Arc<dyn RequestPhase>>,
+        filters: Vec<Arc<dyn ResponseFilter>>,
+    ) -> Self {
+        self.route_handler(pattern, Box::new(handler), phases, filters)
+    }
+
+    fn route_handler(
+        mut self,
+        pattern: &str,
+        handler: HttpHandler,
+        phases: Vec<Arc<dyn RequestPhase>>,
+        filters: Vec<Arc<dyn ResponseFilter>>,
+    ) -> Self {
+        let args: Vec<String> = pattern.split_whitespace().map(str::to_string).collect();
+        match LocationPattern::parse(&args) {
+            Ok(pattern) => self.routes.push(Route {
+                pattern,
+                handler,
+                phases,
+                filters,
+                middleware: Vec::new(),
+                stream_body: false,
+            }),
+            Err(e) => self.fail(ServerBuildError::InvalidRoute(pattern.to_string(), e)),
+        }
+        self
+    }
+
+    /// Serves the files below `dir` for URIs starting with `prefix`, as a
+    /// location with `alias` would.
+    pub fn static_dir(self, prefix: &str, dir: impl AsRef<Path>) -> Self {
+        let config = StaticConfig {
+            alias: Some(dir.as_ref().to_path_buf()),
+            ..Default::default()
+        };
+        let files = StaticFiles::new(
+            &config,
+            MimeConfig::default(),
+            Some(&LocationPattern::prefix(prefix)),
+        )
+        .map(Arc::new);
+        match files {
+            Some(files) => self.route(prefix, move |req| files.serve(req)),
+            None => self,
+        }
+    }
+
+    /// Answers requests that match no route, instead of 404.
+    pub fn fallback(
+        mut self,
+        handler: impl Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    ) -> Self {
+        self.default_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Runs `phase` on every request before a route is selected.
+    pub fn phase(mut self, phase: impl RequestPhase + 'static) -> Self {
+        self.phases.push(Arc::new(phase));
+        self
+    }
+
+    /// Runs `filter` on every response, including those of routes with
+    /// filters of their own, after them.
+    pub fn filter(mut self, filter: impl ResponseFilter + 'static) -> Self {
+        self.filters.push(Arc::new(filter));
+        self
+    }
+
+    /// Wraps the handling of every request in `middleware`, inside any
+    /// added before.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Wraps the handler of the route added last in `middleware`, after
+    /// the route's phases and inside any middleware added before.
+    pub fn route_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.middleware.push(Arc::new(middleware));
+        }
+        self
+    }
+
+    /// Hands request bodies for the route added last to its handler as a
+    /// stream, taken with `HttpRequest::take_body_stream`, instead of
+    /// buffering them; for uploads piped straight to disk. Only such routes
+    /// accept chunked request bodies, which the stream decodes.
+    pub fn stream_body(mut self) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.stream_body = true;
+        }
+        self
+    }
+
+    /// Limits request bodies, 1m by default; 0 disables the check.
+    pub fn client_max_body_size(mut self, size: u64) -> Self {
+        self.core.client_max_body_size = Some(size);
+        self
+    }
+
+    /// How long idle keep-alive connections stay open, 75s by default.
+    pub fn keepalive_timeout(mut self, timeout: Duration) -> Self {
+        self.core.keepalive_timeout = Some(timeout);
+        self
+    }
+
+    /// Binds the listening socket; the server accepts connections once
+    /// started.
+    pub fn build(self) -> Result<HttpServer, ServerBuildError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let mut processor = HttpProcessor::new();
+        for phase in self.phases {
+            processor.add_server_phase(phase);
+        }
+        for route in self.routes {
+            let mut filters = route.filters;
+            filters.extend(self.filters.iter().cloned());
+            processor.add_location_with_phases(
+                route.pattern,
+                Some(route.handler),
+                route.phases,
+                Arc::default(),
+                filters,
+            );
+            for middleware in route.middleware {
+                processor.add_location_middleware(middleware);
+            }
+            if route.stream_body {
+                processor.set_stream_body();
+            }
+        }
+        if let Some(handler) = self.default_handler {
+            processor.set_default_handler(handler);
+        }
+        processor.set_filters(self.filters);
+        for middleware in self.middleware {
+            processor.add_middleware(middleware);
+        }
+        HttpServer::from_processor(&self.listen, processor, self.tls, self.core)
+            .map_err(|e| ServerBuildError::Listen(self.listen, e))
+    }
+
+    fn fail(&mut self, error: ServerBuildError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use http::StatusCode;
+
+    use crate::core::processor::{Next, PhaseResult};
+
+    use super::*;
+
+    #[test]
+    fn test_builder_serves_routes() {
+        let server = ServerBuilder::new()
+            .listen("127.0.0.1:0")
+            .route("/hello", |req| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!("hello {}", req.var("user").unwrap_or("-")));
+                resp
+            })
+            .phase(|req: &mut HttpRequest| {
+                if let Some(user) = req.header("X-User").map(str::to_string) {
+                    req.set_var("user", &user);
+                }
+                PhaseResult::Continue
+            })
+            .filter(|_: &HttpRequest, resp: &mut HttpResponse| {
+                resp.set_header("X-Served-By", "blur");
+            })
+            .middleware(|req: &mut HttpRequest, next: Next| {
+                let mut resp = next.run(req);
+                resp.set_header("X-Middleware", "server");
+                resp
+            })
+            .route_async("/async", |req: HttpRequest| async move {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!("async {}", req.path()));
+                resp
+            })
+            .route("/private", |_| HttpResponse::new())
+            .route_middleware(|req: &mut HttpRequest, _: Next| {
+                HttpProcessor::create_status_response(req.version(), StatusCode::FORBIDDEN)
+            })
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        server.start();
+
+        let send = |head: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "{}\r\nHost: a\r\nConnection: close\r\n\r\n", head).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = send("GET /hello HTTP/1.1\r\nX-User: ann");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("X-Served-By: blur\r\n"));
+        assert!(response.contains("X-Middleware: server\r\n"));
+        assert!(response.ends_with("hello ann"));
+        let response = send("GET /missing HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.contains("X-Served-By: blur\r\n"));
+        let response = send("GET /async?a=1 HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("async /async?a=1"));
+        let response = send("GET /private HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 403"));
+        assert!(response.contains("X-Middleware: server\r\n"));
+
+        assert!(matches!(
+            ServerBuilder::new()
+                .route("~ (", |_| HttpResponse::new())
+                .build(),
+            Err(ServerBuildError::InvalidRoute(..))
+        ));
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::PathBuf,
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, HttpProcessor, LocationModifier, LocationPattern},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_gateway::{read_response, GatewayParams, CGI_PARAMS},
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::RequestVariables,
+};
+
+register_commands!(
+    CommandBuilder::new("cgi_pass")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "CGI Pass")
+        .display_name("zh-tw", "CGI 執行")
+        .desc(
+            "en",
+            "Runs the CGI scripts in a directory for the requests of a location"
+        )
+        .desc("zh-tw", "以目錄中的 CGI 腳本處理此 location 的請求")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Directory")
+            .display_name("zh-tw", "目錄")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Directory holding the scripts, which the rest of the URI after the location is looked up in"
+            )
+            .desc("zh-tw", "存放腳本的目錄，URI 在 location 之後的部分會對應到其中的檔案")
+            .build()])
+        .build(handle_cgi_pass),
+    CommandBuilder::new("cgi_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "CGI Timeout")
+        .display_name("zh-tw", "CGI 執行逾時")
+        .desc(
+            "en",
+            "Sets how long a CGI script may run before it is killed"
+        )
+        .desc("zh-tw", "設定 CGI 腳本最多可執行多久，逾時即終止")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Timeout, defaults to 60s")
+            .desc("zh-tw", "逾時時間，預設為 60s")
+            .build()])
+        .build(handle_cgi_timeout),
+    CommandBuilder::new("cgi_max_processes")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "CGI Max Processes")
+        .display_name("zh-tw", "CGI 最大行程數")
+        .desc(
+            "en",
+            "Limits how many scripts of a location may run at once; further requests get 503"
+        )
+        .desc("zh-tw", "限制同一 location 同時執行的腳本數量，超過時回應 503")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Processes")
+            .display_name("zh-tw", "行程數")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "Number of processes, defaults to 16")
+            .desc("zh-tw", "行程數量，預設為 16")
+            .build()])
+        .build(handle_cgi_max_processes),
+);
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_PROCESSES: usize = 16;
+/// The only variable scripts inherit besides the CGI ones, as the server's
+/// own environment is not passed on.
+const SCRIPT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Parameters set for scripts in addition to the common CGI ones.
+const SCRIPT_PARAMS: &[(&str, &str, bool)] = &[
+    ("SCRIPT_NAME", "$cgi_script_name", false),
+    ("SCRIPT_FILENAME", "$cgi_script_filename", false),
+    ("PATH_INFO", "$cgi_path_info", true),
+];
+
+#[derive(Debug, Default, Clone)]
+pub struct CgiConfig {
+    pub pass: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+    pub max_processes: Option<usize>,
+}
+
+impl MergeConfig for CgiConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.timeout = self.timeout.or(parent.timeout);
+        self.max_processes = self.max_processes.or(parent.max_processes);
+    }
+}
+
+/// Runs the scripts of one location as CGI/1.1 processes.
+pub struct Cgi {
+    dir: PathBuf,
+    location_prefix: Option<String>,
+    params: GatewayParams,
+    timeout: Duration,
+    max_processes: usize,
+    running: Arc<AtomicUsize>,
+}
+
+/// The script a request names, with the rest of its path.
+struct Script {
+    filename: PathBuf,
+    name: String,
+    path_info: String,
+}
+
+impl Cgi {
+    pub fn new(dir: PathBuf, pattern: Option<&LocationPattern>) -> Self {
+        let location_prefix = pattern
+            .filter(|pattern| {
+                matches!(
+                    pattern.modifier,
+                    LocationModifier::Prefix
+                        | LocationModifier::PreferPrefix
+                        | LocationModifier::Exact
+                )
+            })
+            .map(|pattern| pattern.path.clone());
+        Self {
+            dir,
+            location_prefix,
+            params: GatewayParams::new(CGI_PARAMS.iter().chain(SCRIPT_PARAMS), Vec::new()),
+            timeout: DEFAULT_TIMEOUT,
+            max_processes: DEFAULT_MAX_PROCESSES,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_processes(mut self, max_processes: usize) -> Self {
+        self.max_processes = max_processes;
+        self
+    }
+
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        let status = |status| HttpProcessor::create_status_response(req.version(), status);
+        let path = req.path().split('?').next().unwrap_or_default();
+        let Some(script) = self.find_script(path) else {
+            return status(StatusCode::NOT_FOUND);
+        };
+        let executable = script
+            .filename
+            .metadata()
+            .is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0);
+        if !executable {
+            log_error!(
+                "CGI script \"{}\" is not executable",
+                script.filename.display()
+            );
+            return status(StatusCode::FORBIDDEN);
+        }
+        let Some(slot) = ProcessSlot::acquire(&self.running, self.max_processes) else {
+            log_error!(
+                "CGI script \"{}\" not run: {} scripts already running",
+                script.filename.display(),
+                self.max_processes
+            );
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        match self.run(req, &script, slot) {
+            Ok(resp) => resp,
+            Err(e) => {
+                log_error!(
+                    "CGI script \"{}\" failed for \"{}\": {}",
+                    script.filename.display(),
+                    req.path(),
+                    e
+                );
+                match e.kind() {
+                    io::ErrorKind::TimedOut => status(StatusCode::GATEWAY_TIMEOUT),
+                    _ => status(StatusCode::BAD_GATEWAY),
+                }
+            }
+        }
+    }
+
+    /// Walks the path below the directory up to the first regular file,
+    /// leaving what follows it as `PATH_INFO`.
+    fn find_script(&self, path: &str) -> Option<Script> {
+        let offset = match &self.location_prefix {
+            Some(prefix) => path.strip_prefix(prefix.as_str()).map(|_| prefix.len())?,
+            None => 0,
+        };
+        let mut filename = self.dir.clone();
+        let mut end = offset;
+        for segment in path[offset..].split('/') {
+            let start = end;
+            end += segment.len() + 1;
+            if segment.is_empty() {
+                continue;
+            }
+            if segment == ".." || segment.contains('\0') {
+                return None;
+            }
+            filename.push(segment);
+            let meta = filename.metadata().ok()?;
+            if meta.is_file() {
+                let end = start + segment.len();
+                return Some(Script {
+                    filename,
+                    name: path[..end].to_string(),
+                    path_info: path[end..].to_string(),
+                });
+            }
+            if !meta.is_dir() {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn run(
+        &self,
+        req: &HttpRequest,
+        script: &Script,
+        slot: ProcessSlot,
+    ) -> io::Result<HttpResponse> {
+        let vars = RequestVariables::new(req)
+            .with_value(
+                "document_root",
+                self.dir.to_string_lossy().trim_end_matches('/').to_string(),
+            )
+            .with_value("cgi_script_name", script.name.clone())
+            .with_value(
+                "cgi_script_filename",
+                script.filename.to_string_lossy().into_owned(),
+            )
+            .with_value("cgi_path_info", script.path_info.clone());
+
+        let mut command = Command::new(&script.filename);
+        command
+            .env_clear()
+            .env("PATH", SCRIPT_PATH)
+            .envs(self.params.render(&vars))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        if let Some(dir) = script.filename.parent() {
+            command.current_dir(dir);
+        }
+        let mut child = command.spawn()?;
+        let (stdin, stdout, stderr) =
+            match (child.stdin.take(), child.stdout.take(), child.stderr.take()) {
+                (Some(stdin), Some(stdout), Some(stderr)) => (stdin, stdout, stderr),
+                _ => unreachable!("all of the script's streams are piped"),
+            };
+
+        // Written from their own threads, so a script that answers before
+        // reading all of its input, or fills its stderr pipe, cannot
+        // deadlock against the server.
+        let body = req.body().to_vec();
+        thread::spawn(move || {
+            let mut stdin = stdin;
+            let _ = stdin.write_all(&body);
+        });
+        let name = script.filename.display().to_string();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                log_error!("CGI script \"{}\" sent in stderr: {}", name, line);
+            }
+        });
+
+        let process = Arc::new(Process {
+            child: Mutex::new(child),
+            timed_out: AtomicBool::new(false),
+        });
+        let (done, finished) = mpsc::channel::<()>();
+        let watched = Arc::clone(&process);
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            if finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                watched.kill_running(true);
+            }
+        });
+
+        read_response(
+            BufReader::new(ScriptOutput {
+                stdout,
+                process,
+                _done: done,
+                _slot: slot,
+            }),
+            req,
+        )
+    }
+}
+
+/// Counts a running script against `cgi_max_processes` until dropped.
+struct ProcessSlot(Arc<AtomicUsize>);
+
+impl ProcessSlot {
+    fn acquire(running: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        running
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| Self(Arc::clone(running)))
+    }
+}
+
+impl Drop for ProcessSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct Process {
+    child: Mutex<Child>,
+    timed_out: AtomicBool,
+}
+
+impl Process {
+    /// Kills the script unless it has already exited, then reaps it. On
+    /// timeouts the whole process group goes, since anything the script
+    /// started may still hold its stdout open.
+    fn kill_running(&self, timed_out: bool) {
+        let Ok(mut child) = self.child.lock() else {
+            return;
+        };
+        let running = matches!(child.try_wait(), Ok(None));
+        if running || timed_out {
+            self.timed_out.store(timed_out, Ordering::Release);
+            // The script leads its own group, so its pid is the group id.
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+        }
+        let _ = child.wait();
+    }
+}
+
+/// The script's stdout, which the response body streams from. Dropping it
+/// ends the script and stops its timeout.
+struct ScriptOutput {
+    stdout: ChildStdout,
+    process: Arc<Process>,
+    _done: Sender<()>,
+    _slot: ProcessSlot,
+}
+
+impl Read for ScriptOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stdout.read(buf)? {
+            0 if self.process.timed_out.load(Ordering::Acquire) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "script ran past cgi_timeout",
+            )),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Drop for ScriptOutput {
+    fn drop(&mut self) {
+        self.process.kill_running(false);
+    }
+}
+
+/// Builds the CGI handler for a location chain that sets `cgi_pass`.
+pub fn cgi_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let config = merged_config::<CgiConfig>(chain);
+    let cgi = Arc::new(
+        Cgi::new(config.pass?, pattern)
+            .with_timeout(config.timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .with_max_processes(config.max_processes.unwrap_or(DEFAULT_MAX_PROCESSES)),
+    );
+    Some(Box::new(move |req: &HttpRequest| cgi.handle(req)))
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut CgiConfig)) {
+    if let Ok(mut config) = ctx.block_config::<CgiConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_cgi_pass(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let dir = ctx.path_arg(0)?;
+    if !dir.is_dir() {
+        return Err(ctx.invalid_value(&dir.to_string_lossy(), "is not a directory"));
+    }
+    update(ctx, |config| config.pass = Some(dir));
+    Ok(())
+}
+
+pub fn handle_cgi_timeout(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_cgi_max_processes(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let max = ctx.number_arg(0)?;
+    let max = usize::try_from(max)
+        .ok()
+        .filter(|max| *max > 0)
+        .ok_or_else(|| ctx.invalid_value(&max.to_string(), "must be at least 1"))?;
+    update(ctx, |config| config.max_processes = Some(max));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Instant};
+
+    use super::*;
+
+    fn write_script(dir: &std::path::Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_cgi_runs_scripts() {
+        let dir = std::env::temp_dir().join(format!("blur-cgi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_script(
+            &dir,
+            "echo.sh",
+            "printf 'Content-Type: text/plain\\r\\nStatus: 201 Created\\r\\n\\r\\n'\n\
+             printf '%s|%s|%s|' \"$SCRIPT_NAME\" \"$PATH_INFO\" \"$QUERY_STRING\"\n\
+             cat\n",
+        );
+        write_script(&dir, "slow.sh", "sleep 5\n");
+        write_script(&dir, "hold.sh", "printf '\\r\\n'\nsleep 5\n");
+        fs::write(dir.join("plain.txt"), "text").unwrap();
+
+        let pattern = LocationPattern::parse(&["/cgi-bin/".to_string()]).unwrap();
+        let cgi = Cgi::new(dir.clone(), Some(&pattern)).with_timeout(Duration::from_millis(300));
+        let request = |raw: &[u8]| {
+            let mut req = HttpRequest::new();
+            req.parse(raw).unwrap();
+            req
+        };
+
+        let mut resp = cgi.handle(&request(
+            b"POST /cgi-bin/echo.sh/extra?a=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nbody",
+        ));
+        resp.load_body().unwrap();
+        assert_eq!(resp.status(), Some(201));
+        assert_eq!(resp.body, b"/cgi-bin/echo.sh|/extra|a=1|body");
+
+        let resp = cgi.handle(&request(
+            b"GET /cgi-bin/plain.txt HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(resp.status(), Some(403));
+        let resp = cgi.handle(&request(
+            b"GET /cgi-bin/missing HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(resp.status(), Some(404));
+
+        let started = Instant::now();
+        let resp = cgi.handle(&request(
+            b"GET /cgi-bin/slow.sh HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(resp.status(), Some(504));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert_eq!(cgi.running.load(Ordering::Acquire), 0);
+
+        // A script counts until its response is dropped.
+        let cgi = Cgi::new(dir.clone(), Some(&pattern)).with_max_processes(1);
+        let held = cgi.handle(&request(
+            b"GET /cgi-bin/hold.sh HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(held.status(), Some(200));
+        let resp = cgi.handle(&request(
+            b"GET /cgi-bin/echo.sh HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(resp.status(), Some(503));
+        drop(held);
+        let resp = cgi.handle(&request(
+            b"GET /cgi-bin/echo.sh HTTP/1.1\r\nHost: a\r\n\r\n",
+        ));
+        assert_eq!(resp.status(), Some(201));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
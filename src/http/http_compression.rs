@@ -0,0 +1,307 @@
+use std::io;
+
+use http::Method;
+
+use crate::{
+    core::{
+        config::config_context::{merged_config, ConfigContext},
+        processor::ResponseFilter,
+    },
+    log_error,
+};
+
+use super::{
+    http_gzip::{GzipConfig, GzipEncoder},
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+};
+
+pub const DEFAULT_MIN_LENGTH: u64 = 20;
+
+/// A content coding the compression filter can apply to response bodies.
+pub trait Encoder: Send + Sync {
+    /// The token used in `Accept-Encoding` and `Content-Encoding`.
+    fn coding(&self) -> &'static str;
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Decides which responses are worth compressing, shared by the encoders.
+#[derive(Debug, Clone)]
+pub struct CompressionRules {
+    types: Vec<String>,
+    min_length: u64,
+}
+
+impl CompressionRules {
+    pub fn new(types: Option<&[String]>, min_length: Option<u64>) -> Self {
+        Self {
+            types: types.map(<[String]>::to_vec).unwrap_or_default(),
+            min_length: min_length.unwrap_or(DEFAULT_MIN_LENGTH),
+        }
+    }
+
+    /// Whether the response has a body of a listed type that is large enough
+    /// and not already encoded, regardless of what the client accepts.
+    pub fn applies_to(&self, req: &HttpRequest, resp: &HttpResponse) -> bool {
+        if *req.method() == Method::HEAD || resp.has_header("Content-Encoding") {
+            return false;
+        }
+        let status = resp.status().unwrap_or_default();
+        if !(((200..300).contains(&status) && status != 204 && status != 206) || status >= 400) {
+            return false;
+        }
+        if resp.body_len() < self.min_length {
+            return false;
+        }
+        let content_type = resp
+            .header_value("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        content_type == "text/html"
+            || self
+                .types
+                .iter()
+                .any(|t| t == "*" || t.eq_ignore_ascii_case(&content_type))
+    }
+}
+
+/// Replaces the body with its encoded form and updates the headers that
+/// depend on it.
+pub fn encode_body(
+    resp: &mut HttpResponse,
+    coding: &str,
+    encode: impl FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+) -> io::Result<()> {
+    resp.load_body()?;
+    let encoded = encode(&resp.body)?;
+    resp.body = encoded;
+    resp.remove_header("Content-Length");
+    resp.set_header("Content-Encoding", coding);
+    if let Some(etag) = resp.header_value("ETag").map(str::to_string) {
+        if !etag.starts_with("W/") {
+            resp.remove_header("ETag");
+            resp.set_header("ETag", &format!("W/{}", etag));
+        }
+    }
+    Ok(())
+}
+
+/// Marks the response as depending on `Accept-Encoding`.
+pub fn add_vary(resp: &mut HttpResponse) {
+    let vary = resp.header_value("Vary").map(str::to_string);
+    match vary {
+        None => {
+            resp.set_header("Vary", "Accept-Encoding");
+        }
+        Some(vary)
+            if !vary
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case("Accept-Encoding")) =>
+        {
+            resp.remove_header("Vary");
+            resp.set_header("Vary", &format!("{}, Accept-Encoding", vary));
+        }
+        Some(_) => {}
+    }
+}
+
+/// Compresses responses with the enabled encoder the client prefers. Equal
+/// qualities are resolved in the order the encoders were added.
+pub struct CompressionFilter {
+    rules: CompressionRules,
+    encoders: Vec<Box<dyn Encoder>>,
+}
+
+impl CompressionFilter {
+    pub fn new(rules: CompressionRules) -> Self {
+        Self {
+            rules,
+            encoders: Vec::new(),
+        }
+    }
+
+    pub fn add_encoder(&mut self, encoder: Box<dyn Encoder>) {
+        self.encoders.push(encoder);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.encoders.is_empty()
+    }
+
+    fn select(&self, req: &HttpRequest) -> Option<&dyn Encoder> {
+        let mut best: Option<(&dyn Encoder, f32)> = None;
+        for encoder in &self.encoders {
+            let Some(quality) = req.encoding_quality(encoder.coding()) else {
+                continue;
+            };
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((encoder.as_ref(), quality));
+            }
+        }
+        best.map(|(encoder, _)| encoder)
+    }
+}
+
+impl ResponseFilter for CompressionFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if !self.rules.applies_to(req, resp) {
+            return;
+        }
+        add_vary(resp);
+        let Some(encoder) = self.select(req) else {
+            return;
+        };
+        if let Err(e) = encode_body(resp, encoder.coding(), |body| encoder.encode(body)) {
+            log_error!("Failed to {} response: {}", encoder.coding(), e);
+        }
+    }
+}
+
+/// Builds the compression filter for a block chain from the encoders enabled
+/// in it, preferring brotli, then zstd, then gzip.
+pub fn compression_filter(chain: &[&ConfigContext]) -> Option<CompressionFilter> {
+    let gzip = merged_config::<GzipConfig>(chain);
+    let mut filter = CompressionFilter::new(CompressionRules::new(
+        gzip.types.as_deref(),
+        gzip.min_length,
+    ));
+    #[cfg(feature = "brotli")]
+    if let Some(encoder) = super::http_brotli::BrotliEncoder::from_chain(chain) {
+        filter.add_encoder(Box::new(encoder));
+    }
+    #[cfg(feature = "zstd")]
+    if let Some(encoder) = super::http_zstd::ZstdEncoder::from_chain(chain) {
+        filter.add_encoder(Box::new(encoder));
+    }
+    if let Some(encoder) = GzipEncoder::from_config(&gzip) {
+        filter.add_encoder(Box::new(encoder));
+    }
+    (!filter.is_empty()).then_some(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use http::{StatusCode, Version};
+
+    use super::*;
+
+    fn request(accept: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept).as_bytes())
+            .unwrap();
+        req
+    }
+
+    fn response(content_type: &str, body: &str) -> HttpResponse {
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+        resp.set_header("Content-Type", content_type);
+        resp.set_header("ETag", "\"abc\"");
+        resp.set_body(body);
+        resp
+    }
+
+    struct Reverse;
+
+    impl Encoder for Reverse {
+        fn coding(&self) -> &'static str {
+            "x-reverse"
+        }
+
+        fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(body.iter().rev().copied().collect())
+        }
+    }
+
+    #[test]
+    fn test_compression_filter() {
+        let mut filter = CompressionFilter::new(CompressionRules::new(
+            Some(&["application/json".to_string()]),
+            None,
+        ));
+        filter.add_encoder(Box::new(Reverse));
+        filter.add_encoder(Box::new(
+            GzipEncoder::from_config(&GzipConfig {
+                enabled: Some(true),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let body = "{\"message\": \"hello hello hello hello\"}";
+
+        let mut resp = response("application/json; charset=utf-8", body);
+        filter.filter(&request("gzip, deflate"), &mut resp);
+        assert_eq!(resp.header_value("Content-Encoding"), Some("gzip"));
+        assert_eq!(resp.header_value("Vary"), Some("Accept-Encoding"));
+        assert_eq!(resp.header_value("ETag"), Some("W/\"abc\""));
+        let mut decoded = String::new();
+        GzDecoder::new(resp.body.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let mut resp = response("application/json", body);
+        filter.filter(&request("gzip, x-reverse"), &mut resp);
+        assert_eq!(resp.header_value("Content-Encoding"), Some("x-reverse"));
+        let mut resp = response("application/json", body);
+        filter.filter(&request("gzip;q=1.0, x-reverse;q=0.5"), &mut resp);
+        assert_eq!(resp.header_value("Content-Encoding"), Some("gzip"));
+
+        let mut resp = response("application/json", body);
+        filter.filter(&request("identity"), &mut resp);
+        assert!(!resp.has_header("Content-Encoding"));
+        assert_eq!(resp.header_value("Vary"), Some("Accept-Encoding"));
+
+        let mut resp = response("image/png", body);
+        filter.filter(&request("gzip"), &mut resp);
+        assert!(!resp.has_header("Content-Encoding"));
+
+        let mut resp = response("text/html", "short");
+        filter.filter(&request("gzip"), &mut resp);
+        assert_eq!(resp.body, b"short");
+    }
+
+    #[cfg(all(feature = "brotli", feature = "zstd"))]
+    #[test]
+    fn test_accept_encoding_quality() {
+        use crate::http::{http_brotli::BrotliConfig, http_zstd::ZstdConfig};
+
+        let mut ctx = ConfigContext::new_empty("http", vec![]);
+        if let Ok(mut config) = ctx.block_config::<GzipConfig>().lock() {
+            config.enabled = Some(true);
+        }
+        if let Ok(mut config) = ctx.block_config::<BrotliConfig>().lock() {
+            config.enabled = Some(true);
+        }
+        if let Ok(mut config) = ctx.block_config::<ZstdConfig>().lock() {
+            config.enabled = Some(true);
+        }
+        let filter = compression_filter(&[&ctx]).unwrap();
+        let chosen = |accept: &str| {
+            let mut resp = response("text/html", &"hello ".repeat(20));
+            filter.filter(&request(accept), &mut resp);
+            resp.header_value("Content-Encoding").map(str::to_string)
+        };
+
+        assert_eq!(chosen("gzip, zstd, br").as_deref(), Some("br"));
+        assert_eq!(chosen("gzip, zstd").as_deref(), Some("zstd"));
+        assert_eq!(
+            chosen("gzip;q=1.0, zstd;q=0.9, br;q=0.8").as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(
+            chosen("gzip;q=0.5, zstd, br;q=0.9").as_deref(),
+            Some("zstd")
+        );
+        assert_eq!(
+            chosen("br;q=0, zstd;q=0, gzip;q=0.1").as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(chosen("*;q=0.5, br;q=0").as_deref(), Some("zstd"));
+        assert_eq!(chosen("br;q=0, zstd;q=0, gzip;q=0"), None);
+    }
+}
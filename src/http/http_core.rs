@@ -0,0 +1,410 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_request::{HeaderLimits, Strictness};
+
+register_commands!(
+    CommandBuilder::new("client_max_body_size")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Client Max Body Size")
+        .display_name("zh-tw", "請求主體大小上限")
+        .desc(
+            "en",
+            "Sets the maximum allowed size of the client request body"
+        )
+        .desc("zh-tw", "設定客戶端請求主體允許的最大大小")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Maximum body size, e.g. 512k or 10m; 0 disables the check"
+            )
+            .desc("zh-tw", "最大主體大小，例如 512k 或 10m；設為 0 則不檢查")
+            .build()])
+        .build(handle_client_max_body_size),
+    CommandBuilder::new("keepalive_timeout")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Keep-Alive Timeout")
+        .display_name("zh-tw", "長連線逾時")
+        .desc(
+            "en",
+            "Sets how long an idle keep-alive connection stays open"
+        )
+        .desc("zh-tw", "設定閒置長連線保持開啟的時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Timeout")
+            .display_name("zh-tw", "逾時")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Idle timeout, e.g. 75s or 2m; 0 disables keep-alive")
+            .desc("zh-tw", "閒置逾時，例如 75s 或 2m；設為 0 則停用長連線")
+            .build()])
+        .build(handle_keepalive_timeout),
+    CommandBuilder::new("client_body_timeout")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Client Body Timeout")
+        .display_name("zh-tw", "客戶端主體逾時")
+        .desc(
+            "en",
+            "Sets how long a streamed request body may go without data from the client"
+        )
+        .desc("zh-tw", "設定串流讀取請求主體時，客戶端可以多久未送出資料")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Timeout")
+            .display_name("zh-tw", "逾時")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Timeout between two reads, e.g. 60s; defaults to 60s")
+            .desc("zh-tw", "兩次讀取之間的逾時，例如 60s；預設為 60s")
+            .build()])
+        .build(handle_client_body_timeout),
+    CommandBuilder::new("request_id")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Request ID Header")
+        .display_name("zh-tw", "請求編號標頭")
+        .desc(
+            "en",
+            "Sets whether $request_id is sent as X-Request-Id to upstreams and clients"
+        )
+        .desc(
+            "zh-tw",
+            "設定是否將 $request_id 以 X-Request-Id 傳給上游與客戶端"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_request_id),
+    CommandBuilder::new("client_header_buffer_size")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Client Header Buffer Size")
+        .display_name("zh-tw", "請求標頭緩衝區大小")
+        .desc(
+            "en",
+            "Sets the buffer a request header is read into; a header that fits in it is always accepted, larger ones need large_client_header_buffers"
+        )
+        .desc(
+            "zh-tw",
+            "設定讀取請求標頭的緩衝區大小；放得下的標頭一律接受，更大的標頭則受 large_client_header_buffers 限制"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc("en", "Buffer size, 1k by default")
+            .desc("zh-tw", "緩衝區大小，預設 1k")
+            .build()])
+        .build(handle_client_header_buffer_size),
+    CommandBuilder::new("large_client_header_buffers")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Large Client Header Buffers")
+        .display_name("zh-tw", "大型請求標頭緩衝區")
+        .desc(
+            "en",
+            "Limits long request headers: a longer request line gets 414 and a longer header line or header gets 431"
+        )
+        .desc(
+            "zh-tw",
+            "限制較長的請求標頭：請求列超過上限回應 414，標頭欄位或整個標頭超過上限回應 431"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Number")
+                .display_name("zh-tw", "數量")
+                .arg_type(ArgType::Number)
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "How many buffers the whole header may use, 4 by default"
+                )
+                .desc("zh-tw", "整個標頭可使用的緩衝區數量，預設 4")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Size")
+                .display_name("zh-tw", "大小")
+                .arg_type(ArgType::Size)
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "The longest request line or header line, 8k by default"
+                )
+                .desc("zh-tw", "請求列或單一標頭欄位的長度上限，預設 8k")
+                .build(),
+        ])
+        .arity(Arity::Exact(2))
+        .build(handle_large_client_header_buffers),
+    CommandBuilder::new("request_strictness")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Request Strictness")
+        .display_name("zh-tw", "請求解析嚴格程度")
+        .desc(
+            "en",
+            "Sets how strictly request headers are parsed; requests that servers could frame differently, such as with both Transfer-Encoding and Content-Length, are always rejected"
+        )
+        .desc(
+            "zh-tw",
+            "設定解析請求標頭的嚴格程度；同時帶有 Transfer-Encoding 與 Content-Length 等可能被不同伺服器解讀為不同請求的內容一律拒絕"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Level")
+            .display_name("zh-tw", "等級")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "strict, standard (the default) which rejects folded headers and bare CR or LF, or lenient which repairs them"
+            )
+            .desc(
+                "zh-tw",
+                "strict、standard（預設，拒絕折行標頭與單獨的 CR 或 LF），或 lenient（修正這些內容後接受）"
+            )
+            .build()])
+        .build(handle_request_strictness),
+    CommandBuilder::new("merge_slashes")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Merge Slashes")
+        .display_name("zh-tw", "合併斜線")
+        .desc(
+            "en",
+            "Sets whether repeated slashes in request paths are merged into one before locations are matched"
+        )
+        .desc(
+            "zh-tw",
+            "設定比對 location 前是否將請求路徑中連續的斜線合併為一個"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_merge_slashes),
+);
+
+pub const DEFAULT_CLIENT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(75);
+pub const DEFAULT_CLIENT_BODY_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_CLIENT_HEADER_BUFFER_SIZE: u64 = 1024;
+pub const DEFAULT_LARGE_CLIENT_HEADER_BUFFERS: (u64, u64) = (4, 8 * 1024);
+
+#[derive(Debug, Default, Clone)]
+pub struct HttpCoreConfig {
+    pub client_max_body_size: Option<u64>,
+    pub keepalive_timeout: Option<Duration>,
+    pub client_body_timeout: Option<Duration>,
+    pub request_id: Option<bool>,
+    pub client_header_buffer_size: Option<u64>,
+    /// The number and size of the large header buffers.
+    pub large_client_header_buffers: Option<(u64, u64)>,
+    pub request_strictness: Option<Strictness>,
+    pub merge_slashes: Option<bool>,
+}
+
+impl MergeConfig for HttpCoreConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.client_max_body_size = self.client_max_body_size.or(parent.client_max_body_size);
+        self.keepalive_timeout = self.keepalive_timeout.or(parent.keepalive_timeout);
+        self.client_body_timeout = self.client_body_timeout.or(parent.client_body_timeout);
+        self.request_id = self.request_id.or(parent.request_id);
+        self.client_header_buffer_size = self
+            .client_header_buffer_size
+            .or(parent.client_header_buffer_size);
+        self.large_client_header_buffers = self
+            .large_client_header_buffers
+            .or(parent.large_client_header_buffers);
+        self.request_strictness = self.request_strictness.or(parent.request_strictness);
+        self.merge_slashes = self.merge_slashes.or(parent.merge_slashes);
+    }
+}
+
+impl HttpCoreConfig {
+    pub fn client_max_body_size(&self) -> u64 {
+        self.client_max_body_size
+            .unwrap_or(DEFAULT_CLIENT_MAX_BODY_SIZE)
+    }
+
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout.unwrap_or(DEFAULT_KEEPALIVE_TIMEOUT)
+    }
+
+    pub fn client_body_timeout(&self) -> Duration {
+        self.client_body_timeout
+            .unwrap_or(DEFAULT_CLIENT_BODY_TIMEOUT)
+    }
+
+    /// Whether requests and responses carry an `X-Request-Id` header.
+    pub fn request_id(&self) -> bool {
+        self.request_id.unwrap_or(false)
+    }
+
+    /// The limits of a request header. Anything that fits in the client
+    /// header buffer is accepted, however small the large buffers are.
+    pub fn header_limits(&self) -> HeaderLimits {
+        let buffer = self
+            .client_header_buffer_size
+            .unwrap_or(DEFAULT_CLIENT_HEADER_BUFFER_SIZE);
+        let (number, size) = self
+            .large_client_header_buffers
+            .unwrap_or(DEFAULT_LARGE_CLIENT_HEADER_BUFFERS);
+        HeaderLimits {
+            line: buffer.max(size) as usize,
+            total: buffer.max(number * size) as usize,
+        }
+    }
+
+    pub fn request_strictness(&self) -> Strictness {
+        self.request_strictness.unwrap_or_default()
+    }
+
+    pub fn merge_slashes(&self) -> bool {
+        self.merge_slashes.unwrap_or(true)
+    }
+}
+
+pub fn handle_client_max_body_size(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let size = ctx.size_arg(0)?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.client_max_body_size = Some(size);
+    }
+    Ok(())
+}
+
+pub fn handle_keepalive_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.keepalive_timeout = Some(timeout);
+    }
+    Ok(())
+}
+
+pub fn handle_client_body_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.client_body_timeout = Some(timeout);
+    }
+    Ok(())
+}
+
+pub fn handle_request_id(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.request_id = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_client_header_buffer_size(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let size = ctx.size_arg(0)?;
+    if size == 0 {
+        return Err(ctx.invalid_value("0", "the buffer size must be positive"));
+    }
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.client_header_buffer_size = Some(size);
+    }
+    Ok(())
+}
+
+pub fn handle_large_client_header_buffers(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let number = ctx.number_arg(0)?;
+    let size = ctx.size_arg(1)?;
+    if number <= 0 {
+        return Err(ctx.invalid_value(&number.to_string(), "expected at least one buffer"));
+    }
+    if size == 0 {
+        return Err(ctx.invalid_value("0", "the buffer size must be positive"));
+    }
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.large_client_header_buffers = Some((number as u64, size));
+    }
+    Ok(())
+}
+
+pub fn handle_request_strictness(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let level = ctx.str_arg(0)?;
+    let strictness = level
+        .parse::<Strictness>()
+        .map_err(|reason| ctx.invalid_value(&level, reason))?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.request_strictness = Some(strictness);
+    }
+    Ok(())
+}
+
+pub fn handle_merge_slashes(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let merge = ctx.bool_arg(0)?;
+    if let Ok(mut core) = ctx.block_config::<HttpCoreConfig>().lock() {
+        core.merge_slashes = Some(merge);
+    }
+    Ok(())
+}
@@ -0,0 +1,386 @@
+use std::sync::Arc;
+
+use http::{Method, StatusCode};
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::CommandBuilder, config_context::ConfigContext, config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase, ResponseFilter},
+    },
+    log_info, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest, http_response::HttpResponse, http_variables::parse_regex_arg,
+};
+
+register_commands!(CommandBuilder::new("cors")
+    .is_raw_block()
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "CORS")
+    .display_name("zh-tw", "跨來源資源共用")
+    .desc(
+        "en",
+        "Lets pages from other origins call the block: answers preflight OPTIONS requests and adds the CORS headers to responses"
+    )
+    .desc(
+        "zh-tw",
+        "允許其他來源的網頁呼叫此區塊：自動回應預檢 OPTIONS 請求，並在回應加上 CORS 標頭"
+    )
+    .build(handle_cors));
+
+const DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+#[derive(Debug, Clone)]
+pub enum OriginPattern {
+    Any,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl OriginPattern {
+    /// Parses `*`, `~regex`, an origin, or an origin with `*` wildcards
+    /// that each match within one host label, such as `https://*.example.com`.
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern == "*" {
+            return Ok(Self::Any);
+        }
+        if let Some(regex) = parse_regex_arg(pattern) {
+            return regex.map(Self::Regex);
+        }
+        if !pattern.contains('*') {
+            return Ok(Self::Exact(
+                pattern.trim_end_matches('/').to_ascii_lowercase(),
+            ));
+        }
+        let source = pattern
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join("[^./]+");
+        RegexBuilder::new(&format!("^{}$", source))
+            .case_insensitive(true)
+            .build()
+            .map(Self::Regex)
+            .map_err(|e| e.to_string())
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            Self::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    pub origins: Vec<OriginPattern>,
+    pub methods: Vec<String>,
+    /// Request headers allowed in preflights; `None` allows those asked for.
+    pub headers: Option<Vec<String>>,
+    pub expose_headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: DEFAULT_METHODS.iter().map(|m| m.to_string()).collect(),
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// The `Access-Control-Allow-Origin` value for a request's origin, if
+    /// the origin is allowed.
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        let pattern = self.origins.iter().find(|p| p.matches(origin))?;
+        // Credentialed responses must name the origin instead of `*`.
+        match pattern {
+            OriginPattern::Any if !self.credentials => Some("*".to_string()),
+            _ => Some(origin.to_string()),
+        }
+    }
+
+    fn add_common(&self, resp: &mut HttpResponse, allow_origin: &str) {
+        resp.remove_header("Access-Control-Allow-Origin");
+        resp.set_header("Access-Control-Allow-Origin", allow_origin);
+        if self.credentials {
+            resp.remove_header("Access-Control-Allow-Credentials");
+            resp.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if allow_origin != "*" {
+            resp.set_header("Vary", "Origin");
+        }
+    }
+
+    /// Answers a preflight request, or `None` when the request is not one.
+    pub fn preflight(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        if req.method() != Method::OPTIONS {
+            return None;
+        }
+        let origin = req.header("Origin")?;
+        let method = req.header("Access-Control-Request-Method")?;
+        let allow_origin = self.allow_origin(origin);
+        let method_allowed = self.methods.iter().any(|m| m == method);
+        let requested: Vec<&str> = req
+            .header("Access-Control-Request-Headers")
+            .map(|headers| {
+                headers
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|h| !h.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let headers_allowed = match &self.headers {
+            Some(allowed) => requested
+                .iter()
+                .all(|h| allowed.iter().any(|a| a.eq_ignore_ascii_case(h))),
+            None => true,
+        };
+        let (Some(allow_origin), true, true) = (allow_origin, method_allowed, headers_allowed)
+        else {
+            log_info!(
+                "CORS preflight from \"{}\" for {} \"{}\" refused",
+                origin,
+                method,
+                req.path()
+            );
+            return Some(HttpProcessor::create_status_response(
+                req.version(),
+                StatusCode::FORBIDDEN,
+            ));
+        };
+
+        let mut resp = HttpProcessor::create_status_response(req.version(), StatusCode::NO_CONTENT);
+        self.add_common(&mut resp, &allow_origin);
+        resp.set_header("Access-Control-Allow-Methods", &self.methods.join(", "));
+        if !requested.is_empty() {
+            let allowed = match &self.headers {
+                Some(allowed) => allowed.join(", "),
+                None => requested.join(", "),
+            };
+            resp.set_header("Access-Control-Allow-Headers", &allowed);
+        }
+        if let Some(max_age) = self.max_age {
+            resp.set_header("Access-Control-Max-Age", &max_age.to_string());
+        }
+        resp.set_header(
+            "Vary",
+            "Access-Control-Request-Method, Access-Control-Request-Headers",
+        );
+        Some(resp)
+    }
+}
+
+/// Answers the preflight requests of a block before any access check, as
+/// browsers send them without credentials.
+pub struct CorsPhase {
+    policy: Arc<CorsPolicy>,
+}
+
+impl RequestPhase for CorsPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        match self.policy.preflight(req) {
+            Some(resp) => PhaseResult::Respond(Box::new(resp)),
+            None => PhaseResult::Continue,
+        }
+    }
+}
+
+/// Adds the CORS headers to the responses of allowed cross-origin requests.
+pub struct CorsFilter {
+    policy: Arc<CorsPolicy>,
+}
+
+impl ResponseFilter for CorsFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if resp.has_header("Access-Control-Allow-Methods") {
+            // A preflight answer, complete already.
+            return;
+        }
+        let Some(allow_origin) = req
+            .header("Origin")
+            .and_then(|origin| self.policy.allow_origin(origin))
+        else {
+            return;
+        };
+        self.policy.add_common(resp, &allow_origin);
+        if !self.policy.expose_headers.is_empty() {
+            resp.set_header(
+                "Access-Control-Expose-Headers",
+                &self.policy.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+/// The `cors` block nearest to the end of a chain; blocks are stored as
+/// children of the block they apply to.
+fn find_policy(chain: &[&ConfigContext]) -> Option<Arc<CorsPolicy>> {
+    chain.iter().rev().find_map(|ctx| {
+        ctx.children
+            .iter()
+            .filter(|child| child.block_name.trim() == "cors")
+            .find_map(|child| child.store.get::<CorsPolicy>())
+    })
+}
+
+/// Builds the preflight phase for a block chain with a `cors` block.
+pub fn cors_phase(chain: &[&ConfigContext]) -> Option<CorsPhase> {
+    find_policy(chain).map(|policy| CorsPhase { policy })
+}
+
+/// Builds the CORS header filter for a block chain with a `cors` block.
+pub fn cors_filter(chain: &[&ConfigContext]) -> Option<CorsFilter> {
+    find_policy(chain).map(|policy| CorsFilter { policy })
+}
+
+pub fn handle_cors(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let mut policy = CorsPolicy::default();
+    for entry in &ctx.raw_entries {
+        let invalid = |reason: String| ctx.invalid_entry(entry, &entry.args.join(" "), reason);
+        let Some((directive, values)) = entry.args.split_first() else {
+            continue;
+        };
+        match (directive.as_str(), values) {
+            ("origin", origins) if !origins.is_empty() => {
+                for origin in origins {
+                    policy
+                        .origins
+                        .push(OriginPattern::parse(origin).map_err(invalid)?);
+                }
+            }
+            ("methods", methods) if !methods.is_empty() => {
+                policy.methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+            }
+            ("headers", headers) if !headers.is_empty() => {
+                policy.headers = Some(headers.to_vec());
+            }
+            ("expose_headers", headers) if !headers.is_empty() => {
+                policy.expose_headers = headers.to_vec();
+            }
+            ("credentials", [value]) => {
+                policy.credentials = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid("expected on or off".to_string())),
+                };
+            }
+            ("max_age", [age]) => {
+                policy.max_age = Some(parse_duration(age).map_err(invalid)?.as_secs());
+            }
+            _ => {
+                return Err(invalid(
+                    "expected origin, methods, headers, expose_headers, credentials or max_age"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+    ctx.store.insert(Arc::new(policy));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preflight_and_actual_responses() {
+        let policy = Arc::new(CorsPolicy {
+            origins: vec![
+                OriginPattern::parse("https://*.example.com").unwrap(),
+                OriginPattern::parse("~^https://app[0-9]\\.test$").unwrap(),
+            ],
+            methods: vec!["GET".to_string(), "PUT".to_string()],
+            headers: Some(vec!["Content-Type".to_string()]),
+            expose_headers: vec!["X-Total".to_string()],
+            credentials: true,
+            max_age: Some(600),
+        });
+        let request = |head: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("{}\r\nHost: api\r\n\r\n", head).as_bytes())
+                .unwrap();
+            req
+        };
+
+        let req = request(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: https://www.example.com\r\n\
+             Access-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: content-type",
+        );
+        let resp = policy.preflight(&req).unwrap();
+        assert_eq!(resp.status(), Some(204));
+        assert_eq!(
+            resp.header_value("Access-Control-Allow-Origin"),
+            Some("https://www.example.com")
+        );
+        assert_eq!(
+            resp.header_value("Access-Control-Allow-Methods"),
+            Some("GET, PUT")
+        );
+        assert_eq!(
+            resp.header_value("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(resp.header_value("Access-Control-Max-Age"), Some("600"));
+
+        let req = request(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: https://a.b.example.com\r\n\
+             Access-Control-Request-Method: PUT",
+        );
+        assert_eq!(policy.preflight(&req).unwrap().status(), Some(403));
+        let req = request(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: https://app1.test\r\n\
+             Access-Control-Request-Method: DELETE",
+        );
+        assert_eq!(policy.preflight(&req).unwrap().status(), Some(403));
+        assert!(policy
+            .preflight(&request("OPTIONS /items HTTP/1.1"))
+            .is_none());
+
+        let filter = CorsFilter {
+            policy: Arc::clone(&policy),
+        };
+        let mut resp = HttpResponse::new();
+        filter.filter(
+            &request("GET /items HTTP/1.1\r\nOrigin: https://app1.test"),
+            &mut resp,
+        );
+        assert_eq!(
+            resp.header_value("Access-Control-Allow-Origin"),
+            Some("https://app1.test")
+        );
+        assert_eq!(
+            resp.header_value("Access-Control-Expose-Headers"),
+            Some("X-Total")
+        );
+        assert_eq!(resp.header_value("Vary"), Some("Origin"));
+
+        let mut resp = HttpResponse::new();
+        filter.filter(
+            &request("GET /items HTTP/1.1\r\nOrigin: https://evil.test"),
+            &mut resp,
+        );
+        assert!(!resp.has_header("Access-Control-Allow-Origin"));
+    }
+}
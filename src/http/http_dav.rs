@@ -0,0 +1,1203 @@
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{symlink, DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::{Method, StatusCode};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, HttpProcessor, LocationPattern},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::{generate_request_id, normalize_target, HttpRequest},
+    http_response::HttpResponse,
+    http_static::{static_files, StaticFiles},
+};
+
+register_commands!(
+    CommandBuilder::new("dav_methods")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WebDAV Methods")
+        .display_name("zh-tw", "WebDAV 方法")
+        .desc(
+            "en",
+            "Lets clients upload and manage files below root or alias with the listed WebDAV methods"
+        )
+        .desc(
+            "zh-tw",
+            "允許客戶端以列出的 WebDAV 方法在 root 或 alias 目錄下上傳與管理檔案"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Methods")
+            .display_name("zh-tw", "方法")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Any of PUT, DELETE, MKCOL, COPY and MOVE, or off (the default)"
+            )
+            .desc(
+                "zh-tw",
+                "PUT、DELETE、MKCOL、COPY、MOVE 的任意組合，或 off（預設）"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_dav_methods),
+    CommandBuilder::new("dav_access")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WebDAV Access")
+        .display_name("zh-tw", "WebDAV 權限")
+        .desc(
+            "en",
+            "Sets the permissions of files and directories created with WebDAV methods"
+        )
+        .desc("zh-tw", "設定以 WebDAV 方法建立的檔案與目錄權限")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Permissions")
+            .display_name("zh-tw", "權限")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Entries such as user:rw group:r all:r; user:rw by default, and directories also get x where r is given"
+            )
+            .desc(
+                "zh-tw",
+                "例如 user:rw group:r all:r；預設為 user:rw，目錄在有 r 的地方另加 x"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_dav_access),
+    CommandBuilder::new("create_full_put_path")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Create Full PUT Path")
+        .display_name("zh-tw", "PUT 自動建立目錄")
+        .desc(
+            "en",
+            "Sets whether PUT creates missing intermediate directories instead of answering 409"
+        )
+        .desc("zh-tw", "設定 PUT 是否自動建立不存在的上層目錄，而非回應 409")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_create_full_put_path),
+    CommandBuilder::new("dav_lock")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WebDAV Locking")
+        .display_name("zh-tw", "WebDAV 鎖定")
+        .desc(
+            "en",
+            "Enables LOCK and UNLOCK; changes to locked resources then need the lock token in the If header"
+        )
+        .desc(
+            "zh-tw",
+            "啟用 LOCK 與 UNLOCK；變更已鎖定的資源時需在 If 標頭中附上鎖定權杖"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_dav_lock),
+    CommandBuilder::new("dav_lock_timeout")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WebDAV Lock Timeout")
+        .display_name("zh-tw", "WebDAV 鎖定時間")
+        .desc(
+            "en",
+            "The longest a lock lasts before the client has to refresh it"
+        )
+        .desc("zh-tw", "鎖定在客戶端更新前最長的有效時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "e.g. 10m; 1m by default")
+            .desc("zh-tw", "例如 10m；預設 1m")
+            .build()])
+        .build(handle_dav_lock_timeout)
+);
+
+const DEFAULT_ACCESS: u32 = 0o600;
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DavMethod {
+    Put,
+    Delete,
+    Mkcol,
+    Copy,
+    Move,
+}
+
+impl DavMethod {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "PUT" => Some(Self::Put),
+            "DELETE" => Some(Self::Delete),
+            "MKCOL" => Some(Self::Mkcol),
+            "COPY" => Some(Self::Copy),
+            "MOVE" => Some(Self::Move),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Mkcol => "MKCOL",
+            Self::Copy => "COPY",
+            Self::Move => "MOVE",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DavConfig {
+    /// Empty after `dav_methods off`.
+    pub methods: Option<Vec<DavMethod>>,
+    /// Permission bits of created files.
+    pub access: Option<u32>,
+    pub create_full_put_path: Option<bool>,
+    pub lock: Option<bool>,
+    pub lock_timeout: Option<Duration>,
+}
+
+impl MergeConfig for DavConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.methods.is_none() {
+            self.methods = parent.methods.clone();
+        }
+        if self.access.is_none() {
+            self.access = parent.access;
+        }
+        if self.create_full_put_path.is_none() {
+            self.create_full_put_path = parent.create_full_put_path;
+        }
+        if self.lock.is_none() {
+            self.lock = parent.lock;
+        }
+        if self.lock_timeout.is_none() {
+            self.lock_timeout = parent.lock_timeout;
+        }
+    }
+}
+
+/// An exclusive write lock taken with `LOCK`.
+#[derive(Debug, Clone)]
+struct DavLock {
+    path: PathBuf,
+    /// The URI the lock was taken on, reported as its root.
+    uri: String,
+    token: String,
+    /// Depth infinity: the lock covers everything below a directory.
+    infinite: bool,
+    owner: Option<String>,
+    timeout: Duration,
+    expires: Instant,
+}
+
+impl DavLock {
+    /// Whether the lock is in the way of changing `path` or anything below it.
+    fn covers(&self, path: &Path) -> bool {
+        self.path == path
+            || (self.infinite && path.starts_with(&self.path))
+            || self.path.starts_with(path)
+    }
+}
+
+/// Locks are kept by file path, so locations sharing a directory share
+/// them.
+static LOCKS: Mutex<Vec<DavLock>> = Mutex::new(Vec::new());
+
+/// Handles the WebDAV methods of a location and leaves other requests to
+/// the static files below the same directory.
+pub struct Dav {
+    files: StaticFiles,
+    methods: Vec<DavMethod>,
+    file_mode: u32,
+    create_full_put_path: bool,
+    /// The longest lock lifetime when `dav_lock` is on.
+    lock_timeout: Option<Duration>,
+}
+
+impl Dav {
+    pub fn serve(&self, req: &HttpRequest) -> HttpResponse {
+        let method = req.method().as_str();
+        if *req.method() == Method::OPTIONS {
+            return self.options(req);
+        }
+        let uri = req.path().split('?').next().unwrap_or_default();
+        let locking = matches!(method, "LOCK" | "UNLOCK") && self.lock_timeout.is_some();
+        let dav = DavMethod::parse(method).filter(|method| self.methods.contains(method));
+        if !locking && dav.is_none() {
+            return self.files.serve(req);
+        }
+        let Some(path) = self.files.map_uri(uri) else {
+            return status_response(req, StatusCode::BAD_REQUEST);
+        };
+        if !self.files.allows_change(&path) {
+            return status_response(req, StatusCode::FORBIDDEN);
+        }
+        let result = match (method, dav) {
+            ("LOCK", _) => {
+                return self
+                    .lock(req, uri, &path)
+                    .unwrap_or_else(|status| status_response(req, status))
+            }
+            ("UNLOCK", _) => self.unlock(req, &path),
+            (_, Some(DavMethod::Put)) => self.put(req, uri, &path),
+            (_, Some(DavMethod::Delete)) => self.delete(req, uri, &path),
+            (_, Some(DavMethod::Mkcol)) => self.mkcol(req, uri, &path),
+            (_, Some(DavMethod::Copy)) => self.copy_or_move(req, uri, &path, false),
+            (_, Some(DavMethod::Move)) => self.copy_or_move(req, uri, &path, true),
+            _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+        };
+        match result {
+            Ok(status) => {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), status);
+                if status != StatusCode::NO_CONTENT {
+                    resp.set_header("Content-Length", "0");
+                }
+                resp
+            }
+            Err(status) => status_response(req, status),
+        }
+    }
+
+    fn options(&self, req: &HttpRequest) -> HttpResponse {
+        let mut allow = vec!["GET", "HEAD", "OPTIONS"];
+        allow.extend(self.methods.iter().map(|method| method.as_str()));
+        if self.lock_timeout.is_some() {
+            allow.extend(["LOCK", "UNLOCK"]);
+        }
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), StatusCode::OK);
+        resp.set_header("Allow", &allow.join(", "));
+        resp.set_header(
+            "DAV",
+            if self.lock_timeout.is_some() {
+                "1, 2"
+            } else {
+                "1"
+            },
+        );
+        resp.set_header("Content-Length", "0");
+        resp
+    }
+
+    fn dir_mode(&self) -> u32 {
+        // Directories can be entered wherever they can be read.
+        self.file_mode | ((self.file_mode & 0o444) >> 2)
+    }
+
+    /// Refuses to change `path` while someone else holds a lock on it; the
+    /// holder names its token in the `If` header.
+    fn check_locks(&self, req: &HttpRequest, path: &Path) -> Result<(), StatusCode> {
+        if self.lock_timeout.is_none() {
+            return Ok(());
+        }
+        let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        let presented = req.header("If").unwrap_or_default();
+        let blocked = locks
+            .iter()
+            .filter(|lock| lock.covers(path))
+            .any(|lock| !presented.contains(&format!("<{}>", lock.token)));
+        if blocked {
+            Err(StatusCode::LOCKED)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drops the locks on `path` and below once it is gone.
+    fn release_locks(&self, path: &Path) {
+        if let Ok(mut locks) = LOCKS.lock() {
+            locks.retain(|lock| !lock.path.starts_with(path));
+        }
+    }
+
+    fn put(&self, req: &HttpRequest, uri: &str, path: &Path) -> Result<StatusCode, StatusCode> {
+        if uri.ends_with('/') {
+            return Err(StatusCode::CONFLICT);
+        }
+        self.check_locks(req, path)?;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(StatusCode::CONFLICT);
+        };
+        if !parent.is_dir() {
+            if !self.create_full_put_path {
+                return Err(StatusCode::CONFLICT);
+            }
+            DirBuilder::new()
+                .recursive(true)
+                .mode(self.dir_mode())
+                .create(parent)
+                .map_err(|e| io_status(req, &e))?;
+        }
+        let existed = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => return Err(StatusCode::CONFLICT),
+            Ok(_) => true,
+            Err(_) => false,
+        };
+
+        // Written beside the target and renamed over it, so readers never
+        // see a partial file.
+        let temp = parent.join(format!(
+            ".{}.{}.dav",
+            name.to_string_lossy(),
+            generate_request_id()
+        ));
+        let written = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(self.file_mode)
+            .open(&temp)
+            .and_then(|mut file| file.write_all(req.body()))
+            .and_then(|_| fs::set_permissions(&temp, fs::Permissions::from_mode(self.file_mode)))
+            .and_then(|_| fs::rename(&temp, path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(io_status(req, &e));
+        }
+        Ok(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        })
+    }
+
+    fn delete(&self, req: &HttpRequest, uri: &str, path: &Path) -> Result<StatusCode, StatusCode> {
+        if path == self.files.base() {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let metadata = fs::symlink_metadata(path).map_err(|_| StatusCode::NOT_FOUND)?;
+        self.check_locks(req, path)?;
+        let removed = if metadata.is_dir() {
+            if !uri.ends_with('/') {
+                return Err(StatusCode::CONFLICT);
+            }
+            if !matches!(req.header("Depth"), None | Some("infinity")) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        removed.map_err(|e| io_status(req, &e))?;
+        self.release_locks(path);
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    fn mkcol(&self, req: &HttpRequest, uri: &str, path: &Path) -> Result<StatusCode, StatusCode> {
+        if !uri.ends_with('/') {
+            return Err(StatusCode::CONFLICT);
+        }
+        if !req.body().is_empty() {
+            return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        self.check_locks(req, path)?;
+        DirBuilder::new()
+            .mode(self.dir_mode())
+            .create(path)
+            .map_err(|e| io_status(req, &e))?;
+        Ok(StatusCode::CREATED)
+    }
+
+    fn copy_or_move(
+        &self,
+        req: &HttpRequest,
+        uri: &str,
+        path: &Path,
+        moving: bool,
+    ) -> Result<StatusCode, StatusCode> {
+        let destination = req
+            .header("Destination")
+            .map(str::trim)
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        // Another location, or another server, would have to take it.
+        if !names_request_host(req, destination) {
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        let destination = normalize_target(destination).map_err(|_| StatusCode::BAD_REQUEST)?;
+        let dest_uri = destination.split('?').next().unwrap_or_default();
+        let dest = self
+            .files
+            .map_uri(dest_uri)
+            .ok_or(StatusCode::BAD_GATEWAY)?;
+        if !self.files.allows_change(&dest) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let metadata = fs::symlink_metadata(path).map_err(|_| StatusCode::NOT_FOUND)?;
+        if metadata.is_dir() != dest_uri.ends_with('/') || metadata.is_dir() != uri.ends_with('/') {
+            return Err(StatusCode::CONFLICT);
+        }
+        if dest == path || dest.starts_with(path) || path == self.files.base() {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let recursive = match req.header("Depth") {
+            None | Some("infinity") => true,
+            Some("0") if !moving => false,
+            Some(_) => return Err(StatusCode::BAD_REQUEST),
+        };
+        let overwrite = !req
+            .header("Overwrite")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("F"));
+        if !dest.parent().is_some_and(Path::is_dir) {
+            return Err(StatusCode::CONFLICT);
+        }
+        if moving {
+            self.check_locks(req, path)?;
+        }
+        self.check_locks(req, &dest)?;
+
+        let existed = match fs::symlink_metadata(&dest) {
+            Ok(_) if !overwrite => return Err(StatusCode::PRECONDITION_FAILED),
+            Ok(existing) => {
+                let removed = if existing.is_dir() {
+                    fs::remove_dir_all(&dest)
+                } else {
+                    fs::remove_file(&dest)
+                };
+                removed.map_err(|e| io_status(req, &e))?;
+                true
+            }
+            Err(_) => false,
+        };
+        let done = if moving {
+            fs::rename(path, &dest).or_else(|_| {
+                copy_tree(path, &dest, true)?;
+                if metadata.is_dir() {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                }
+            })
+        } else {
+            copy_tree(path, &dest, recursive)
+        };
+        done.map_err(|e| io_status(req, &e))?;
+        if moving {
+            self.release_locks(path);
+        }
+        Ok(if existed {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::CREATED
+        })
+    }
+
+    fn lock(&self, req: &HttpRequest, uri: &str, path: &Path) -> Result<HttpResponse, StatusCode> {
+        let max = self.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT);
+        let timeout = requested_timeout(req).map_or(max, |timeout| timeout.min(max));
+        let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+
+        // A LOCK without a body naming a held lock refreshes it.
+        if req.body().is_empty() {
+            let presented = req.header("If").unwrap_or_default();
+            let lock = locks
+                .iter_mut()
+                .find(|lock| lock.covers(path) && presented.contains(&format!("<{}>", lock.token)))
+                .ok_or(StatusCode::PRECONDITION_FAILED)?;
+            lock.timeout = timeout;
+            lock.expires = now + timeout;
+            return Ok(lock_response(req, lock, StatusCode::OK));
+        }
+
+        let infinite = match req.header("Depth") {
+            None | Some("infinity") => true,
+            Some("0") => false,
+            Some(_) => return Err(StatusCode::BAD_REQUEST),
+        };
+        if locks
+            .iter()
+            .any(|lock| lock.covers(path) || (infinite && lock.path.starts_with(path)))
+        {
+            return Err(StatusCode::LOCKED);
+        }
+        // Locking a missing resource creates it empty.
+        let status = if fs::symlink_metadata(path).is_ok() {
+            StatusCode::OK
+        } else {
+            if uri.ends_with('/') || !path.parent().is_some_and(Path::is_dir) {
+                return Err(StatusCode::CONFLICT);
+            }
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(self.file_mode)
+                .open(path)
+                .map_err(|e| io_status(req, &e))?;
+            StatusCode::CREATED
+        };
+        let id = generate_request_id();
+        let lock = DavLock {
+            path: path.to_path_buf(),
+            uri: uri.to_string(),
+            token: format!(
+                "opaquelocktoken:{}-{}-{}-{}-{}",
+                &id[..8],
+                &id[8..12],
+                &id[12..16],
+                &id[16..20],
+                &id[20..]
+            ),
+            infinite,
+            owner: lock_owner(req.body()),
+            timeout,
+            expires: now + timeout,
+        };
+        let resp = lock_response(req, &lock, status);
+        locks.push(lock);
+        Ok(resp)
+    }
+
+    fn unlock(&self, req: &HttpRequest, path: &Path) -> Result<StatusCode, StatusCode> {
+        let token = req
+            .header("Lock-Token")
+            .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        let before = locks.len();
+        locks.retain(|lock| {
+            !(lock.token == token
+                && (lock.path == path || (lock.infinite && path.starts_with(&lock.path))))
+        });
+        if locks.len() == before {
+            return Err(StatusCode::CONFLICT);
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// The lifetime asked for in a `Timeout` header such as `Second-600`.
+fn requested_timeout(req: &HttpRequest) -> Option<Duration> {
+    req.header("Timeout")?
+        .split(',')
+        .map(str::trim)
+        .find_map(|value| match value {
+            "Infinite" => Some(Duration::MAX),
+            _ => value
+                .strip_prefix("Second-")?
+                .parse()
+                .ok()
+                .map(Duration::from_secs),
+        })
+}
+
+/// The `owner` element of a `lockinfo` body, kept as the client wrote it.
+fn lock_owner(body: &[u8]) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let owner = Regex::new(r"(?s)<(?:[\w.-]+:)?owner(?:\s[^>]*)?>(.*?)</(?:[\w.-]+:)?owner\s*>")
+        .ok()?
+        .captures(&body)?
+        .get(1)?
+        .as_str()
+        .trim()
+        .to_string();
+    (!owner.is_empty()).then_some(owner)
+}
+
+/// Answers `LOCK` with the lock discovery document and the token.
+fn lock_response(req: &HttpRequest, lock: &DavLock, status: StatusCode) -> HttpResponse {
+    let timeout = if lock.timeout == Duration::MAX {
+        "Infinite".to_string()
+    } else {
+        format!("Second-{}", lock.timeout.as_secs())
+    };
+    let owner = lock
+        .owner
+        .as_ref()
+        .map(|owner| format!("<D:owner>{}</D:owner>\n", owner))
+        .unwrap_or_default();
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:prop xmlns:D=\"DAV:\">\n\
+         <D:lockdiscovery>\n\
+         <D:activelock>\n\
+         <D:locktype><D:write/></D:locktype>\n\
+         <D:lockscope><D:exclusive/></D:lockscope>\n\
+         <D:depth>{}</D:depth>\n\
+         {}\
+         <D:timeout>{}</D:timeout>\n\
+         <D:locktoken><D:href>{}</D:href></D:locktoken>\n\
+         <D:lockroot><D:href>{}</D:href></D:lockroot>\n\
+         </D:activelock>\n\
+         </D:lockdiscovery>\n\
+         </D:prop>\n",
+        if lock.infinite { "infinity" } else { "0" },
+        owner,
+        timeout,
+        lock.token,
+        escape_xml(&lock.uri)
+    );
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*req.version(), status);
+    resp.set_header("Content-Type", "text/xml; charset=utf-8");
+    resp.set_header("Lock-Token", &format!("<{}>", lock.token));
+    resp.set_body(&body);
+    resp
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Whether a `Destination` header names the host the request was sent to;
+/// one without a scheme and host always does.
+fn names_request_host(req: &HttpRequest, destination: &str) -> bool {
+    let authority = match destination.split_once("://") {
+        Some((scheme, rest)) if !scheme.contains('/') => {
+            rest.split(['/', '?']).next().unwrap_or_default()
+        }
+        _ => return true,
+    };
+    req.header("Host")
+        .is_some_and(|host| host.trim().eq_ignore_ascii_case(authority))
+}
+
+/// Copies `from` to `to` without following symbolic links, which are
+/// copied as links so that nothing outside the tree is read.
+fn copy_tree(from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.file_type().is_symlink() {
+        return symlink(fs::read_link(from)?, to);
+    }
+    if !metadata.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir(to)?;
+    fs::set_permissions(to, metadata.permissions())?;
+    if recursive {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), true)?;
+        }
+    }
+    Ok(())
+}
+
+fn io_status(req: &HttpRequest, e: &io::Error) -> StatusCode {
+    match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => StatusCode::CONFLICT,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::AlreadyExists => StatusCode::METHOD_NOT_ALLOWED,
+        _ => {
+            log_error!("{} \"{}\" failed: {}", req.method(), req.path(), e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+fn status_response(req: &HttpRequest, status: StatusCode) -> HttpResponse {
+    HttpProcessor::create_status_response(req.version(), status)
+}
+
+/// Builds the WebDAV handler for a block chain ending in a `location` or
+/// `server`, if `dav_methods` or `dav_lock` enable it and a `root` or `alias`
+/// applies.
+pub fn dav_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let config = merged_config::<DavConfig>(chain);
+    let methods = config.methods.unwrap_or_default();
+    let lock = config.lock.unwrap_or(false);
+    if methods.is_empty() && !lock {
+        return None;
+    }
+    let dav = Arc::new(Dav {
+        files: static_files(chain, pattern)?,
+        methods,
+        file_mode: config.access.unwrap_or(DEFAULT_ACCESS),
+        create_full_put_path: config.create_full_put_path.unwrap_or(false),
+        lock_timeout: lock.then(|| config.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT)),
+    });
+    Some(Box::new(move |req: &HttpRequest| dav.serve(req)))
+}
+
+/// Parses `dav_access` entries such as `user:rw` into permission bits.
+fn parse_access(entries: &[String]) -> Result<u32, String> {
+    let mut mode = 0;
+    for entry in entries {
+        let (who, perms) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("expected user, group or all followed by :, got {}", entry))?;
+        let shift = match who {
+            "user" => 6,
+            "group" => 3,
+            "all" => 0,
+            _ => return Err(format!("unknown class {}", who)),
+        };
+        for perm in perms.chars() {
+            let bit = match perm {
+                'r' => 4,
+                'w' => 2,
+                _ => return Err(format!("unknown permission {} in {}", perm, entry)),
+            };
+            mode |= bit << shift;
+        }
+    }
+    Ok(mode)
+}
+
+pub fn handle_dav_methods(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let methods = if args == ["off"] {
+        Vec::new()
+    } else {
+        args.iter()
+            .map(|arg| {
+                DavMethod::parse(arg).ok_or_else(|| {
+                    ctx.invalid_value(arg, "expected PUT, DELETE, MKCOL, COPY, MOVE or off")
+                })
+            })
+            .collect::<Result<_, _>>()?
+    };
+    if let Ok(mut config) = ctx.block_config::<DavConfig>().lock() {
+        config.methods = Some(methods);
+    }
+    Ok(())
+}
+
+pub fn handle_dav_access(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let mode = parse_access(&args).map_err(|e| ctx.invalid_value(&args.join(" "), e))?;
+    if let Ok(mut config) = ctx.block_config::<DavConfig>().lock() {
+        config.access = Some(mode);
+    }
+    Ok(())
+}
+
+pub fn handle_create_full_put_path(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<DavConfig>().lock() {
+        config.create_full_put_path = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_dav_lock(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<DavConfig>().lock() {
+        config.lock = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_dav_lock_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<DavConfig>().lock() {
+        config.lock_timeout = Some(timeout);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{
+        http_mime::MimeConfig,
+        http_static::{StaticConfig, SymlinkPolicy},
+    };
+
+    use super::*;
+
+    fn request(head: &str, body: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        let raw = format!(
+            "{}\r\nHost: a\r\nContent-Length: {}\r\n\r\n{}",
+            head,
+            body.len(),
+            body
+        );
+        req.parse(raw.as_bytes()).unwrap();
+        req
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blur-dav-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root")).unwrap();
+        dir
+    }
+
+    /// Serves `dir/root` with every method and locking enabled.
+    fn dav(dir: &Path, symlinks: SymlinkPolicy) -> Dav {
+        Dav {
+            files: StaticFiles::new(
+                &StaticConfig {
+                    root: Some(dir.join("root")),
+                    disable_symlinks: Some(symlinks),
+                    ..Default::default()
+                },
+                MimeConfig::default(),
+                None,
+            )
+            .unwrap(),
+            methods: vec![
+                DavMethod::Put,
+                DavMethod::Delete,
+                DavMethod::Mkcol,
+                DavMethod::Copy,
+                DavMethod::Move,
+            ],
+            file_mode: parse_access(&[
+                "user:rw".to_string(),
+                "group:r".to_string(),
+                "all:r".to_string(),
+            ])
+            .unwrap(),
+            create_full_put_path: false,
+            lock_timeout: Some(Duration::from_secs(60)),
+        }
+    }
+
+    fn send(dav: &Dav, head: &str, body: &str) -> Option<u16> {
+        dav.serve(&request(head, body)).status()
+    }
+
+    #[test]
+    fn test_put_mkcol_delete() {
+        let dir = temp_dir("basic");
+        let root = dir.join("root");
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        assert_eq!(send("PUT /a/b.txt HTTP/1.1", "x"), Some(409));
+        assert_eq!(send("MKCOL /a HTTP/1.1", ""), Some(409));
+        assert_eq!(send("MKCOL /a/ HTTP/1.1", ""), Some(201));
+        assert_eq!(send("MKCOL /a/ HTTP/1.1", ""), Some(405));
+        assert_eq!(send("PUT /a/b.txt HTTP/1.1", "one"), Some(201));
+        assert_eq!(send("PUT /a/b.txt HTTP/1.1", "two"), Some(204));
+        assert_eq!(fs::read(root.join("a/b.txt")).unwrap(), b"two");
+        let mode = fs::metadata(root.join("a/b.txt"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o644);
+        assert_eq!(send("GET /a/b.txt HTTP/1.1", ""), Some(200));
+
+        assert_eq!(send("DELETE /a HTTP/1.1", ""), Some(409));
+        assert_eq!(send("DELETE /a/ HTTP/1.1", ""), Some(204));
+        assert_eq!(send("DELETE /a/ HTTP/1.1", ""), Some(404));
+        assert_eq!(send("DELETE / HTTP/1.1", ""), Some(403));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_depth() {
+        let dir = temp_dir("depth");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("a/sub")).unwrap();
+        fs::write(root.join("a/sub/f.txt"), "f").unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        // Depth 0 copies the collection without its members.
+        assert_eq!(
+            send(
+                "COPY /a/ HTTP/1.1\r\nDestination: /shallow/\r\nDepth: 0",
+                ""
+            ),
+            Some(201)
+        );
+        assert!(root.join("shallow").is_dir());
+        assert_eq!(fs::read_dir(root.join("shallow")).unwrap().count(), 0);
+        assert_eq!(
+            send(
+                "COPY /a/ HTTP/1.1\r\nDestination: /deep/\r\nDepth: infinity",
+                ""
+            ),
+            Some(201)
+        );
+        assert_eq!(fs::read(root.join("deep/sub/f.txt")).unwrap(), b"f");
+        assert_eq!(
+            send("COPY /a/ HTTP/1.1\r\nDestination: /one/\r\nDepth: 1", ""),
+            Some(400)
+        );
+        // MOVE and DELETE always act on the whole tree.
+        assert_eq!(
+            send("MOVE /a/ HTTP/1.1\r\nDestination: /b/\r\nDepth: 0", ""),
+            Some(400)
+        );
+        assert_eq!(send("DELETE /a/ HTTP/1.1\r\nDepth: 0", ""), Some(400));
+        assert!(root.join("a/sub/f.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let dir = temp_dir("overwrite");
+        let root = dir.join("root");
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        assert_eq!(
+            send(
+                "COPY /a.txt HTTP/1.1\r\nDestination: /b.txt\r\nOverwrite: F",
+                ""
+            ),
+            Some(412)
+        );
+        assert_eq!(
+            send(
+                "MOVE /a.txt HTTP/1.1\r\nDestination: /b.txt\r\nOverwrite: F",
+                ""
+            ),
+            Some(412)
+        );
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"b");
+        assert_eq!(
+            send(
+                "COPY /a.txt HTTP/1.1\r\nDestination: /b.txt\r\nOverwrite: T",
+                ""
+            ),
+            Some(204)
+        );
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"a");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_move_across_directories() {
+        let dir = temp_dir("move");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("from")).unwrap();
+        fs::create_dir_all(root.join("to")).unwrap();
+        fs::write(root.join("from/a.txt"), "a").unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        assert_eq!(
+            send(
+                "MOVE /from/a.txt HTTP/1.1\r\nDestination: http://a/to/b.txt",
+                ""
+            ),
+            Some(201)
+        );
+        assert!(!root.join("from/a.txt").exists());
+        assert_eq!(fs::read(root.join("to/b.txt")).unwrap(), b"a");
+        assert_eq!(
+            send("MOVE /to/b.txt HTTP/1.1\r\nDestination: /missing/b.txt", ""),
+            Some(409)
+        );
+        assert_eq!(
+            send("MOVE /from/ HTTP/1.1\r\nDestination: /to/from/", ""),
+            Some(201)
+        );
+        assert!(root.join("to/from").is_dir());
+        assert_eq!(
+            send("MOVE /to/ HTTP/1.1\r\nDestination: /to/from/inner/", ""),
+            Some(403)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_destination_outside_root() {
+        let dir = temp_dir("destination");
+        let root = dir.join("root");
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        symlink(dir.join("outside"), root.join("out")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        assert_eq!(
+            send("COPY /a.txt HTTP/1.1\r\nDestination: /../a.txt", ""),
+            Some(400)
+        );
+        assert_eq!(
+            send("COPY /a.txt HTTP/1.1\r\nDestination: http://b/a2.txt", ""),
+            Some(502)
+        );
+        assert_eq!(
+            send("MOVE /a.txt HTTP/1.1\r\nDestination: /out/a.txt", ""),
+            Some(403)
+        );
+        assert!(!dir.join("outside/a.txt").exists());
+        assert!(root.join("a.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_symlink_escaping_root() {
+        let dir = temp_dir("escape");
+        let root = dir.join("root");
+        fs::create_dir_all(dir.join("outside/sub")).unwrap();
+        fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+        symlink(dir.join("outside"), root.join("out")).unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        assert_eq!(send("PUT /out/new.txt HTTP/1.1", "x"), Some(403));
+        assert_eq!(send("PUT /out/secret.txt HTTP/1.1", "x"), Some(403));
+        assert_eq!(send("MKCOL /out/dir/ HTTP/1.1", ""), Some(403));
+        assert_eq!(send("DELETE /out/sub/ HTTP/1.1", ""), Some(403));
+        assert_eq!(
+            send("COPY /out/secret.txt HTTP/1.1\r\nDestination: /s.txt", ""),
+            Some(403)
+        );
+        assert!(!dir.join("outside/new.txt").exists());
+        assert_eq!(fs::read(dir.join("outside/secret.txt")).unwrap(), b"secret");
+        assert!(dir.join("outside/sub").is_dir());
+
+        // Links inside a copied tree stay links instead of being read.
+        fs::create_dir_all(root.join("tree")).unwrap();
+        symlink(dir.join("outside/secret.txt"), root.join("tree/link")).unwrap();
+        assert_eq!(
+            send("COPY /tree/ HTTP/1.1\r\nDestination: /copy/", ""),
+            Some(201)
+        );
+        let copied = fs::symlink_metadata(root.join("copy/link")).unwrap();
+        assert!(copied.file_type().is_symlink());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disable_symlinks_on() {
+        let dir = temp_dir("nolinks");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("real")).unwrap();
+        symlink(root.join("real"), root.join("linked")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+
+        let strict = dav(&dir, SymlinkPolicy::On);
+        assert_eq!(send(&strict, "PUT /linked/x.txt HTTP/1.1", "x"), Some(403));
+        assert_eq!(send(&strict, "DELETE /linked HTTP/1.1", ""), Some(403));
+        assert_eq!(
+            send(
+                &strict,
+                "COPY /a.txt HTTP/1.1\r\nDestination: /linked/a.txt",
+                ""
+            ),
+            Some(403)
+        );
+        assert_eq!(send(&strict, "PUT /real/x.txt HTTP/1.1", "x"), Some(201));
+        assert!(!root.join("real/a.txt").exists());
+
+        let lenient = dav(&dir, SymlinkPolicy::Off);
+        assert_eq!(send(&lenient, "PUT /linked/y.txt HTTP/1.1", "y"), Some(201));
+        assert_eq!(fs::read(root.join("real/y.txt")).unwrap(), b"y");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_locks() {
+        let dir = temp_dir("locks");
+        fs::write(dir.join("root/c.txt"), "c").unwrap();
+        let dav = dav(&dir, SymlinkPolicy::Off);
+        let send = |head: &str, body: &str| send(&dav, head, body);
+
+        let locked = dav.serve(&request(
+            "LOCK /c.txt HTTP/1.1",
+            "<D:lockinfo xmlns:D=\"DAV:\"><D:owner>me</D:owner></D:lockinfo>",
+        ));
+        assert_eq!(locked.status(), Some(200));
+        let token = locked.header_value("Lock-Token").unwrap().to_string();
+        assert!(String::from_utf8_lossy(&locked.body).contains("<D:owner>me</D:owner>"));
+        assert_eq!(send("PUT /c.txt HTTP/1.1", "x"), Some(423));
+        assert_eq!(
+            send(&format!("PUT /c.txt HTTP/1.1\r\nIf: ({})", token), "x"),
+            Some(204)
+        );
+        assert_eq!(
+            send(
+                &format!("UNLOCK /c.txt HTTP/1.1\r\nLock-Token: {}", token),
+                ""
+            ),
+            Some(204)
+        );
+        assert_eq!(send("DELETE /c.txt HTTP/1.1", ""), Some(204));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::HashMap, sync::Arc};
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{Arity, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_variables::VarTemplate;
+
+register_commands!(CommandBuilder::new("error_page")
+    .is_repeatable()
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "Error Page")
+    .display_name("zh-tw", "錯誤頁面")
+    .desc(
+        "en",
+        "Replaces error responses with the content of another URI"
+    )
+    .desc("zh-tw", "以另一個 URI 的內容取代錯誤回應")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Codes")
+            .display_name("zh-tw", "狀態碼")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more status codes, optionally followed by =code or = to change the response status"
+            )
+            .desc(
+                "zh-tw",
+                "一個或多個狀態碼，之後可加上 =狀態碼 或 = 以變更回應狀態"
+            )
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "URI")
+            .display_name("zh-tw", "URI")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "URI served instead, a named location such as @fallback, or an external URL to redirect to"
+            )
+            .desc(
+                "zh-tw",
+                "改為提供的 URI、如 @fallback 的命名 location，或轉址的外部網址"
+            )
+            .build(),
+    ])
+    .arity(Arity::AtLeast(2))
+    .build(handle_error_page));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPageStatus {
+    /// Keep the status of the original error.
+    Original,
+    /// `=`: use the status of the response produced by the error page URI.
+    FromTarget,
+    /// `=code`: respond with a fixed status.
+    Fixed(StatusCode),
+}
+
+pub struct ErrorPage {
+    pub status: ErrorPageStatus,
+    pub uri: VarTemplate,
+}
+
+impl ErrorPage {
+    pub fn is_external(uri: &str) -> bool {
+        uri.starts_with("http://") || uri.starts_with("https://")
+    }
+}
+
+/// The `error_page` rules in effect for a block. A block that declares any
+/// `error_page` replaces the inherited rules instead of extending them.
+#[derive(Default, Clone)]
+pub struct ErrorPages {
+    pages: HashMap<u16, Arc<ErrorPage>>,
+}
+
+impl MergeConfig for ErrorPages {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.pages.is_empty() {
+            self.pages = parent.pages.clone();
+        }
+    }
+}
+
+impl ErrorPages {
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn get(&self, status: u16) -> Option<&Arc<ErrorPage>> {
+        self.pages.get(&status)
+    }
+
+    /// Parses the arguments of one `error_page` directive.
+    pub fn add(&mut self, args: &[String]) -> Result<(), String> {
+        let Some((uri, codes)) = args.split_last() else {
+            return Err("invalid number of arguments".to_string());
+        };
+        let (status, codes) = match codes.split_last() {
+            Some((last, codes)) if last.starts_with('=') => {
+                let status = match &last[1..] {
+                    "" => ErrorPageStatus::FromTarget,
+                    code => ErrorPageStatus::Fixed(parse_status(code)?),
+                };
+                (status, codes)
+            }
+            _ => (ErrorPageStatus::Original, codes),
+        };
+        if codes.is_empty() {
+            return Err("at least one status code is required".to_string());
+        }
+
+        let page = Arc::new(ErrorPage {
+            status,
+            uri: VarTemplate::parse(uri),
+        });
+        for code in codes {
+            let code = parse_status(code)?.as_u16();
+            if !(300..=599).contains(&code) {
+                return Err(format!("value \"{}\" must be between 300 and 599", code));
+            }
+            self.pages.insert(code, page.clone());
+        }
+        Ok(())
+    }
+}
+
+fn parse_status(code: &str) -> Result<StatusCode, String> {
+    code.parse::<u16>()
+        .ok()
+        .filter(|code| *code < 1000)
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| format!("invalid status code \"{}\"", code))
+}
+
+pub fn handle_error_page(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let pages = ctx.block_config::<ErrorPages>();
+    let result = match pages.lock() {
+        Ok(mut pages) => pages.add(&args),
+        Err(_) => Ok(()),
+    };
+    result.map_err(|reason| ctx.invalid_value(&args.join(" "), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_error_page_parsing() {
+        let mut pages = ErrorPages::default();
+        pages.add(&args("404 /404.html")).unwrap();
+        pages
+            .add(&args("500 502 503 =200 /maintenance.html"))
+            .unwrap();
+        pages.add(&args("403 = @denied")).unwrap();
+
+        assert_eq!(pages.get(404).unwrap().status, ErrorPageStatus::Original);
+        assert_eq!(
+            pages.get(502).unwrap().status,
+            ErrorPageStatus::Fixed(StatusCode::OK)
+        );
+        assert_eq!(pages.get(403).unwrap().status, ErrorPageStatus::FromTarget);
+        assert!(pages.get(200).is_none());
+
+        assert!(pages.add(&args("=200 /x")).is_err());
+        assert!(pages.add(&args("200 /x")).is_err());
+
+        let mut child = ErrorPages::default();
+        child.merge_from(&pages);
+        assert!(child.get(404).is_some());
+    }
+}
@@ -0,0 +1,499 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    sync::Arc,
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, LocationPattern},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_gateway::{
+        document_root, gateway_error_handler, read_response, Gateway, GatewayConnection,
+        GatewayParam, GatewayParams, GatewayPass, CGI_PARAMS,
+    },
+    http_request::HttpRequest,
+    http_resolver::resolver,
+    http_response::HttpResponse,
+    http_variables::RequestVariables,
+};
+
+register_commands!(
+    CommandBuilder::new("fastcgi_pass")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "FastCGI Pass")
+        .display_name("zh-tw", "FastCGI 轉送")
+        .desc("en", "Passes requests to a FastCGI server such as PHP-FPM")
+        .desc("zh-tw", "將請求交給 FastCGI 伺服器（例如 PHP-FPM）處理")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Address")
+            .display_name("zh-tw", "位址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "host:port, an upstream name, or unix: followed by a socket path, e.g. unix:/run/php-fpm.sock"
+            )
+            .desc(
+                "zh-tw",
+                "host:port、upstream 名稱，或 unix: 加上 socket 路徑，例如 unix:/run/php-fpm.sock"
+            )
+            .build()])
+        .build(handle_fastcgi_pass),
+    CommandBuilder::new("fastcgi_param")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "FastCGI Param")
+        .display_name("zh-tw", "FastCGI 參數")
+        .desc(
+            "en",
+            "Sets a parameter passed to the FastCGI server, replacing any default of the same name"
+        )
+        .desc(
+            "zh-tw",
+            "設定傳給 FastCGI 伺服器的參數，會取代同名的預設參數"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Name")
+                .display_name("zh-tw", "名稱")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Parameter name, e.g. SCRIPT_FILENAME")
+                .desc("zh-tw", "參數名稱，例如 SCRIPT_FILENAME")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Value")
+                .display_name("zh-tw", "值")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Value, may contain variables")
+                .desc("zh-tw", "值，可包含變數")
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "If Not Empty")
+                .display_name("zh-tw", "非空才傳送")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "if_not_empty to leave the parameter out when its value is empty"
+                )
+                .desc("zh-tw", "if_not_empty 表示值為空時不傳送此參數")
+                .build(),
+        ])
+        .arity(Arity::AtLeast(2))
+        .is_repeatable()
+        .build(handle_fastcgi_param),
+    CommandBuilder::new("fastcgi_index")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "FastCGI Index")
+        .display_name("zh-tw", "FastCGI 索引")
+        .desc(
+            "en",
+            "Sets the script name appended to URIs ending with a slash in $fastcgi_script_name"
+        )
+        .desc(
+            "zh-tw",
+            "設定以斜線結尾的 URI 在 $fastcgi_script_name 中補上的腳本名稱"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Script name, e.g. index.php")
+            .desc("zh-tw", "腳本名稱，例如 index.php")
+            .build()])
+        .build(handle_fastcgi_index),
+);
+
+const FCGI_VERSION: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+/// Only one request is sent per connection.
+const REQUEST_ID: u16 = 1;
+/// The largest record content that keeps records 8-byte aligned.
+const MAX_CONTENT: usize = 0xfff8;
+
+/// Parameters sent by FastCGI in addition to the common CGI ones.
+const FASTCGI_PARAMS: &[(&str, &str, bool)] = &[
+    ("SCRIPT_NAME", "$fastcgi_script_name", false),
+    (
+        "SCRIPT_FILENAME",
+        "$document_root$fastcgi_script_name",
+        false,
+    ),
+];
+
+#[derive(Debug, Default, Clone)]
+pub struct FastCgiConfig {
+    pub pass: Option<GatewayPass>,
+    pub params: Option<Vec<GatewayParam>>,
+    pub index: Option<String>,
+}
+
+impl MergeConfig for FastCgiConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.params = self.params.take().or_else(|| parent.params.clone());
+        self.index = self.index.take().or_else(|| parent.index.clone());
+    }
+}
+
+/// Serves the requests of one location through a FastCGI server.
+pub struct FastCgi {
+    gateway: Gateway,
+    params: GatewayParams,
+    index: Option<String>,
+    document_root: String,
+}
+
+impl FastCgi {
+    pub fn new(
+        gateway: Gateway,
+        params: Vec<GatewayParam>,
+        index: Option<String>,
+        document_root: String,
+    ) -> Self {
+        Self {
+            gateway,
+            params: GatewayParams::new(CGI_PARAMS.iter().chain(FASTCGI_PARAMS), params),
+            index,
+            document_root,
+        }
+    }
+
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        self.gateway.handle(req, |conn| self.exchange(conn, req))
+    }
+
+    fn exchange(&self, mut conn: GatewayConnection, req: &HttpRequest) -> io::Result<HttpResponse> {
+        let mut begin = FCGI_RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[0; 6]);
+        let mut request = Vec::new();
+        write_record(&mut request, FCGI_BEGIN_REQUEST, &begin);
+        write_stream(&mut request, FCGI_PARAMS, &encode_params(&self.params(req)));
+        conn.write_all(&request)?;
+        for chunk in req.body().chunks(MAX_CONTENT) {
+            let mut record = Vec::with_capacity(chunk.len() + 16);
+            write_record(&mut record, FCGI_STDIN, chunk);
+            conn.write_all(&record)?;
+        }
+        let mut end = Vec::new();
+        write_record(&mut end, FCGI_STDIN, &[]);
+        conn.write_all(&end)?;
+        conn.flush()?;
+
+        read_response(BufReader::new(Stdout::new(conn, req.path())), req)
+    }
+
+    fn params(&self, req: &HttpRequest) -> Vec<(String, String)> {
+        let (path, _) = req.path().split_once('?').unwrap_or((req.path(), ""));
+        let mut script_name = path.to_string();
+        if let Some(index) = self.index.as_ref().filter(|_| path.ends_with('/')) {
+            script_name.push_str(index);
+        }
+        let vars = RequestVariables::new(req)
+            .with_value("document_root", self.document_root.clone())
+            .with_value("fastcgi_script_name", script_name);
+        self.params.render(&vars)
+    }
+}
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[FCGI_VERSION, kind]);
+    out.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.resize(out.len() + padding, 0);
+}
+
+/// Writes `data` as a stream of records closed by an empty one.
+fn write_stream(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    for chunk in data.chunks(MAX_CONTENT) {
+        write_record(out, kind, chunk);
+    }
+    write_record(out, kind, &[]);
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    fn encode_len(out: &mut Vec<u8>, len: usize) {
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+
+    let mut out = Vec::new();
+    for (name, value) in params {
+        encode_len(&mut out, name.len());
+        encode_len(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+/// Reads one record, returning its type and content.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[0] != FCGI_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported FastCGI record version",
+        ));
+    }
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; len + header[6] as usize];
+    reader.read_exact(&mut content)?;
+    content.truncate(len);
+    Ok((header[1], content))
+}
+
+/// The STDOUT stream of a FastCGI response. STDERR output met along the way
+/// goes to the error log.
+struct Stdout {
+    stream: GatewayConnection,
+    /// Request path, for the error log.
+    path: String,
+    pending: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl Stdout {
+    fn new(stream: GatewayConnection, path: &str) -> Self {
+        Self {
+            stream,
+            path: path.to_string(),
+            pending: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for Stdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let (kind, content) = read_record(&mut self.stream)?;
+            match kind {
+                FCGI_STDOUT => {
+                    self.pending = content;
+                    self.pos = 0;
+                }
+                FCGI_STDERR => {
+                    let message = String::from_utf8_lossy(&content);
+                    log_error!(
+                        "FastCGI sent in stderr: \"{}\" while serving \"{}\"",
+                        message.trim_end(),
+                        self.path
+                    );
+                }
+                FCGI_END_REQUEST => self.done = true,
+                _ => {}
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Builds the FastCGI handler for a location chain that sets `fastcgi_pass`.
+pub fn fastcgi_handler(
+    chain: &[&ConfigContext],
+    _pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let config = merged_config::<FastCgiConfig>(chain);
+    let pass = config.pass?;
+    let gateway = match Gateway::new("FastCGI", pass.clone(), chain.first().copied()) {
+        Ok(gateway) => gateway.with_resolver(resolver(chain)),
+        Err(e) => return Some(gateway_error_handler(&pass, e)),
+    };
+    let fastcgi = Arc::new(FastCgi::new(
+        gateway,
+        config.params.unwrap_or_default(),
+        config.index,
+        document_root(chain),
+    ));
+    Some(Box::new(move |req: &HttpRequest| fastcgi.handle(req)))
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut FastCgiConfig)) {
+    if let Ok(mut config) = ctx.block_config::<FastCgiConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_fastcgi_pass(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let address = ctx.str_arg(0)?;
+    let pass =
+        GatewayPass::parse(&address).map_err(|reason| ctx.invalid_value(&address, reason))?;
+    update(ctx, |config| config.pass = Some(pass));
+    Ok(())
+}
+
+pub fn handle_fastcgi_param(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(param) = GatewayParam::from_args(ctx)? else {
+        return Ok(());
+    };
+    update(ctx, |config| {
+        config.params.get_or_insert_with(Vec::new).push(param)
+    });
+    Ok(())
+}
+
+pub fn handle_fastcgi_index(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let index = ctx.str_arg(0)?;
+    update(ctx, |config| config.index = Some(index));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::TcpListener, thread};
+
+    use super::*;
+    use crate::http::http_variables::VarTemplate;
+
+    fn decode_params(mut data: &[u8]) -> HashMap<String, String> {
+        fn decode_len(data: &mut &[u8]) -> usize {
+            if data[0] < 0x80 {
+                let len = data[0] as usize;
+                *data = &data[1..];
+                len
+            } else {
+                let len = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]);
+                *data = &data[4..];
+                len as usize
+            }
+        }
+
+        let mut params = HashMap::new();
+        while !data.is_empty() {
+            let name_len = decode_len(&mut data);
+            let value_len = decode_len(&mut data);
+            let name = String::from_utf8(data[..name_len].to_vec()).unwrap();
+            let value = String::from_utf8(data[name_len..name_len + value_len].to_vec()).unwrap();
+            data = &data[name_len + value_len..];
+            params.insert(name, value);
+        }
+        params
+    }
+
+    #[test]
+    fn test_fastcgi_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (mut params, mut stdin) = (Vec::new(), Vec::new());
+            loop {
+                let (kind, content) = read_record(&mut stream).unwrap();
+                match kind {
+                    FCGI_PARAMS => params.extend(content),
+                    FCGI_STDIN if content.is_empty() => break,
+                    FCGI_STDIN => stdin.extend(content),
+                    _ => {}
+                }
+            }
+            let mut response = Vec::new();
+            write_record(&mut response, FCGI_STDERR, b"PHP Notice: x\n");
+            write_record(
+                &mut response,
+                FCGI_STDOUT,
+                b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\nhel",
+            );
+            write_record(&mut response, FCGI_STDOUT, b"lo");
+            write_stream(&mut response, FCGI_STDOUT, &[]);
+            write_record(&mut response, FCGI_END_REQUEST, &[0; 8]);
+            stream.write_all(&response).unwrap();
+            (decode_params(&params), stdin)
+        });
+
+        let fastcgi = FastCgi::new(
+            Gateway::new(
+                "FastCGI",
+                GatewayPass::parse(&format!("127.0.0.1:{}", port)).unwrap(),
+                None,
+            )
+            .unwrap(),
+            vec![GatewayParam {
+                name: "APP_ENV".to_string(),
+                value: VarTemplate::parse("$arg_env"),
+                if_not_empty: true,
+            }],
+            Some("index.php".to_string()),
+            "/srv/www".to_string(),
+        );
+        let mut req = HttpRequest::new();
+        req.parse(
+            b"POST /app/?a=1 HTTP/1.1\r\nHost: example.com\r\nX-Token: t\r\nContent-Length: 4\r\n\r\nbody",
+        )
+        .unwrap();
+
+        let mut resp = fastcgi.handle(&req);
+        resp.load_body().unwrap();
+        assert_eq!(resp.status(), Some(201));
+        assert_eq!(resp.header_value("Content-Type"), Some("text/plain"));
+        assert_eq!(resp.body, b"hello");
+
+        let (params, stdin) = server.join().unwrap();
+        assert_eq!(stdin, b"body");
+        assert_eq!(params["SCRIPT_FILENAME"], "/srv/www/app/index.php");
+        assert_eq!(params["SCRIPT_NAME"], "/app/index.php");
+        assert_eq!(params["QUERY_STRING"], "a=1");
+        assert_eq!(params["REQUEST_METHOD"], "POST");
+        assert_eq!(params["CONTENT_LENGTH"], "4");
+        assert_eq!(params["HTTP_X_TOKEN"], "t");
+        assert!(!params.contains_key("APP_ENV"));
+        assert!(!params.contains_key("HTTPS"));
+
+        assert_eq!(
+            GatewayPass::parse("unix:/run/php-fpm.sock"),
+            Ok(GatewayPass::Unix("/run/php-fpm.sock".into()))
+        );
+        assert!(Gateway::new("FastCGI", GatewayPass::parse("localhost").unwrap(), None).is_err());
+    }
+}
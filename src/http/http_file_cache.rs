@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+        units::parse_duration,
+    },
+    register_commands,
+};
+
+register_commands!(
+    CommandBuilder::new("open_file_cache")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Open File Cache")
+        .display_name("zh-tw", "開啟檔案快取")
+        .desc(
+            "en",
+            "Caches open descriptors, sizes and modification times of served files"
+        )
+        .desc("zh-tw", "快取已開啟檔案的描述符、大小與修改時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Options")
+            .display_name("zh-tw", "選項")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "max=N [inactive=time], or off; inactive defaults to 60s"
+            )
+            .desc(
+                "zh-tw",
+                "max=數量 [inactive=時間]，或 off；inactive 預設為 60s"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_open_file_cache),
+    CommandBuilder::new("open_file_cache_valid")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Open File Cache Valid")
+        .display_name("zh-tw", "開啟檔案快取有效時間")
+        .desc(
+            "en",
+            "Sets how long a cached entry is trusted before the file is checked again"
+        )
+        .desc("zh-tw", "設定快取項目在重新檢查檔案前可信任的時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Validity period, defaults to 60s")
+            .desc("zh-tw", "有效時間，預設為 60s")
+            .build()])
+        .build(handle_open_file_cache_valid),
+);
+
+pub const DEFAULT_INACTIVE: Duration = Duration::from_secs(60);
+pub const DEFAULT_VALID: Duration = Duration::from_secs(60);
+
+/// An open file together with the metadata it was opened with.
+#[derive(Debug, Clone)]
+pub struct OpenFile {
+    pub file: Arc<File>,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    file: OpenFile,
+    checked: Instant,
+    accessed: Instant,
+}
+
+/// Keeps files open between requests so that repeated hits skip `open` and,
+/// until an entry needs revalidating, `stat` as well.
+#[derive(Debug)]
+pub struct OpenFileCache {
+    max: usize,
+    inactive: Duration,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl OpenFileCache {
+    pub fn new(max: usize, inactive: Duration) -> Self {
+        Self {
+            max,
+            inactive,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached file for `path`, reopening it when an entry older
+    /// than `valid` no longer matches the file on disk. Errors are not cached.
+    pub fn open(&self, path: &Path, valid: Duration) -> io::Result<OpenFile> {
+        let now = Instant::now();
+        let Ok(mut entries) = self.entries.lock() else {
+            return open_file(path);
+        };
+
+        if let Some(entry) = entries.get_mut(path) {
+            if now.duration_since(entry.checked) < valid {
+                entry.accessed = now;
+                return Ok(entry.file.clone());
+            }
+            if let Ok(metadata) = fs::metadata(path) {
+                if is_same_file(&entry.file.metadata, &metadata) {
+                    entry.checked = now;
+                    entry.accessed = now;
+                    return Ok(entry.file.clone());
+                }
+            }
+            entries.remove(path);
+        }
+
+        let file = open_file(path)?;
+        if entries.len() >= self.max {
+            self.evict(&mut entries, now);
+        }
+        entries.insert(
+            path.to_path_buf(),
+            CacheEntry {
+                file: file.clone(),
+                checked: now,
+                accessed: now,
+            },
+        );
+        Ok(file)
+    }
+
+    /// Drops entries unused for `inactive`, then the least recently used ones
+    /// until there is room for a new entry.
+    fn evict(&self, entries: &mut HashMap<PathBuf, CacheEntry>, now: Instant) {
+        entries.retain(|_, entry| now.duration_since(entry.accessed) < self.inactive);
+        while entries.len() >= self.max.max(1) {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.accessed)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .map(|entries| entries.len())
+            .unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<OpenFile> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    Ok(OpenFile {
+        file: Arc::new(file),
+        metadata,
+    })
+}
+
+fn is_same_file(cached: &Metadata, current: &Metadata) -> bool {
+    cached.dev() == current.dev()
+        && cached.ino() == current.ino()
+        && cached.len() == current.len()
+        && cached.modified().ok() == current.modified().ok()
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct OpenFileCacheConfig {
+    /// `Some(None)` when the block turned the cache `off`.
+    pub cache: Option<Option<Arc<OpenFileCache>>>,
+    pub valid: Option<Duration>,
+}
+
+impl MergeConfig for OpenFileCacheConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.cache = self.cache.take().or_else(|| parent.cache.clone());
+        self.valid = self.valid.or(parent.valid);
+    }
+}
+
+impl OpenFileCacheConfig {
+    pub fn cache(&self) -> Option<Arc<OpenFileCache>> {
+        self.cache.clone().flatten()
+    }
+
+    pub fn valid(&self) -> Duration {
+        self.valid.unwrap_or(DEFAULT_VALID)
+    }
+}
+
+pub fn handle_open_file_cache(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+
+    let cache = if args == ["off"] {
+        None
+    } else {
+        let mut max = None;
+        let mut inactive = DEFAULT_INACTIVE;
+        for arg in &args {
+            match arg.split_once('=') {
+                Some(("max", value)) => {
+                    max = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|max| *max > 0)
+                            .ok_or_else(|| ctx.invalid_value(arg, "invalid max"))?,
+                    );
+                }
+                Some(("inactive", value)) => {
+                    inactive = parse_duration(value).map_err(|e| ctx.invalid_value(arg, e))?;
+                }
+                _ => return Err(ctx.invalid_value(arg, "expected max=N, inactive=time or off")),
+            }
+        }
+        let max = max.ok_or_else(|| ctx.invalid_value(&args.join(" "), "\"max\" is required"))?;
+        Some(Arc::new(OpenFileCache::new(max, inactive)))
+    };
+
+    if let Ok(mut config) = ctx.block_config::<OpenFileCacheConfig>().lock() {
+        config.cache = Some(cache);
+    }
+    Ok(())
+}
+
+pub fn handle_open_file_cache_valid(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let valid = ctx.duration_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<OpenFileCacheConfig>().lock() {
+        config.valid = Some(valid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_file_cache_revalidates_and_evicts() {
+        let dir = std::env::temp_dir().join(format!("blur-open-file-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "one").unwrap();
+        fs::write(&b, "two").unwrap();
+
+        let cache = OpenFileCache::new(1, DEFAULT_INACTIVE);
+        let first = cache.open(&a, DEFAULT_VALID).unwrap();
+        assert!(Arc::ptr_eq(
+            &first.file,
+            &cache.open(&a, DEFAULT_VALID).unwrap().file
+        ));
+
+        fs::write(&a, "changed").unwrap();
+        assert_eq!(cache.open(&a, DEFAULT_VALID).unwrap().metadata.len(), 3);
+        assert_eq!(cache.open(&a, Duration::ZERO).unwrap().metadata.len(), 7);
+
+        cache.open(&b, DEFAULT_VALID).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.open(&dir.join("missing"), DEFAULT_VALID).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,703 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    marker::PhantomData,
+    net::TcpStream,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, HttpProcessor},
+    },
+    log_error,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_resolver::{lookup, resolver, Resolver},
+    http_response::{HttpResponse, StreamBody},
+    http_static::StaticConfig,
+    http_upstream::{find_upstream, split_host_port, ActivePeer, Upstream},
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Parameters sent by every gateway protocol unless a `*_param` directive
+/// sets one of the same name; the flag leaves a parameter out when its value
+/// is empty.
+pub const CGI_PARAMS: &[(&str, &str, bool)] = &[
+    ("GATEWAY_INTERFACE", "CGI/1.1", false),
+    (
+        "SERVER_SOFTWARE",
+        concat!("blur/", env!("CARGO_PKG_VERSION")),
+        false,
+    ),
+    ("QUERY_STRING", "$query_string", false),
+    ("REQUEST_METHOD", "$request_method", false),
+    ("CONTENT_TYPE", "$content_type", false),
+    ("CONTENT_LENGTH", "$content_length", false),
+    ("REQUEST_URI", "$request_uri", false),
+    ("DOCUMENT_URI", "$document_uri", false),
+    ("DOCUMENT_ROOT", "$document_root", false),
+    ("SERVER_PROTOCOL", "$server_protocol", false),
+    ("REQUEST_SCHEME", "$scheme", false),
+    ("HTTPS", "$https", true),
+    ("REMOTE_ADDR", "$remote_addr", false),
+    ("REMOTE_PORT", "$remote_port", false),
+    ("SERVER_ADDR", "$server_addr", false),
+    ("SERVER_PORT", "$server_port", false),
+    ("SERVER_NAME", "$host", false),
+];
+
+/// Where `fastcgi_pass`, `uwsgi_pass` or `scgi_pass` sends requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatewayPass {
+    Unix(PathBuf),
+    /// An `upstream` group name, or a single `host:port` server.
+    Upstream(String),
+}
+
+impl GatewayPass {
+    pub fn parse(address: &str) -> Result<Self, String> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing socket path".to_string());
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if address.is_empty() {
+            return Err("missing address".to_string());
+        }
+        Ok(Self::Upstream(address.to_string()))
+    }
+}
+
+impl std::fmt::Display for GatewayPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Upstream(name) => f.write_str(name),
+        }
+    }
+}
+
+enum GatewayTarget {
+    Unix(PathBuf),
+    Upstream(Arc<Upstream>),
+}
+
+/// Connects requests of one location to an application server speaking a
+/// gateway protocol, balancing across an `upstream` group when there is one.
+pub struct Gateway {
+    /// Protocol name for the error log.
+    protocol: &'static str,
+    pass: GatewayPass,
+    target: GatewayTarget,
+    /// Resolves server host names instead of the system resolver.
+    resolver: Option<Arc<Resolver>>,
+}
+
+impl Gateway {
+    /// Resolves `pass` against the `upstream` blocks of `http_config`; other
+    /// addresses must carry a port.
+    pub fn new(
+        protocol: &'static str,
+        pass: GatewayPass,
+        http_config: Option<&ConfigContext>,
+    ) -> Result<Self, String> {
+        let target = match &pass {
+            GatewayPass::Unix(path) => GatewayTarget::Unix(path.clone()),
+            GatewayPass::Upstream(name) => {
+                match http_config.and_then(|http_config| find_upstream(http_config, name)) {
+                    Some(upstream) => GatewayTarget::Upstream(upstream),
+                    None => {
+                        let (host, port) = split_host_port(name, 0)?;
+                        if port == 0 {
+                            return Err(format!("no port in upstream \"{}\"", name));
+                        }
+                        GatewayTarget::Upstream(Arc::new(Upstream::single(host, port)))
+                    }
+                }
+            }
+        };
+        Ok(Self {
+            protocol,
+            pass,
+            target,
+            resolver: None,
+        })
+    }
+
+    pub fn with_resolver(mut self, resolver: Option<Arc<Resolver>>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Connects to the server and runs `exchange` over the connection,
+    /// answering 502, or 504 on timeouts, when either fails. Requests are
+    /// turned away while the circuit breaker of the upstream is open.
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        exchange: impl FnOnce(GatewayConnection) -> io::Result<HttpResponse>,
+    ) -> HttpResponse {
+        if let GatewayTarget::Upstream(upstream) = &self.target {
+            if let Err(status) = upstream.admit() {
+                return HttpProcessor::create_status_response(req.version(), status);
+            }
+        }
+        match self.connect(req).and_then(exchange) {
+            Ok(resp) => resp,
+            Err(e) => {
+                log_error!(
+                    "{} server {} failed for \"{}\": {}",
+                    self.protocol,
+                    self.pass,
+                    req.path(),
+                    e
+                );
+                let status = match e.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                        StatusCode::GATEWAY_TIMEOUT
+                    }
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                HttpProcessor::create_status_response(req.version(), status)
+            }
+        }
+    }
+
+    /// Opens a connection, trying the other servers of the group when one
+    /// cannot be reached.
+    fn connect(&self, req: &HttpRequest) -> io::Result<GatewayConnection> {
+        let upstream = match &self.target {
+            GatewayTarget::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                return Ok(GatewayConnection {
+                    stream: GatewayStream::Unix(stream),
+                    _peer: None,
+                });
+            }
+            GatewayTarget::Upstream(upstream) => upstream,
+        };
+        let mut tried = Vec::new();
+        let mut last_error = None;
+        while let Some(peer) = upstream.select(req, &tried) {
+            tried.push(peer.index());
+            match connect_tcp(&peer, self.resolver.as_ref()) {
+                Ok(stream) => {
+                    upstream.report(peer.index(), true);
+                    return Ok(GatewayConnection {
+                        stream: GatewayStream::Tcp(stream),
+                        _peer: Some(peer),
+                    });
+                }
+                Err(e) => {
+                    upstream.report(peer.index(), false);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no live upstreams")))
+    }
+}
+
+fn connect_tcp(peer: &ActivePeer, resolver: Option<&Arc<Resolver>>) -> io::Result<TcpStream> {
+    let server = peer.server();
+    let mut last_error = None;
+    for addr in lookup(resolver, &server.host, server.port)? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "upstream host has no addresses")
+    }))
+}
+
+enum GatewayStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A connection to a gateway server, keeping the server counted as active
+/// for as long as it is open.
+pub struct GatewayConnection {
+    stream: GatewayStream,
+    _peer: Option<ActivePeer>,
+}
+
+impl Read for GatewayConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            GatewayStream::Tcp(stream) => stream.read(buf),
+            GatewayStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for GatewayConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stream {
+            GatewayStream::Tcp(stream) => stream.write(buf),
+            GatewayStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            GatewayStream::Tcp(stream) => stream.flush(),
+            GatewayStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatewayParam {
+    pub name: String,
+    pub value: VarTemplate,
+    pub if_not_empty: bool,
+}
+
+impl GatewayParam {
+    /// Parses the arguments of `fastcgi_param`, `uwsgi_param` or
+    /// `scgi_param`: a name, a value and an optional `if_not_empty`.
+    pub fn from_args(ctx: &ConfigContext) -> Result<Option<Self>, ConfigError> {
+        let Some(name) = ctx
+            .current_cmd_args
+            .first()
+            .filter(|name| !name.is_empty())
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        let value = ctx.current_cmd_args.get(1).map_or("", String::as_str);
+        let if_not_empty = match ctx.current_cmd_args.get(2).map(String::as_str) {
+            None | Some("") => false,
+            Some("if_not_empty") => true,
+            Some(other) => return Err(ctx.invalid_value(other, "expected if_not_empty")),
+        };
+        Ok(Some(Self {
+            name,
+            value: VarTemplate::parse(value),
+            if_not_empty,
+        }))
+    }
+}
+
+/// The parameters a gateway sends with each request.
+pub struct GatewayParams {
+    params: Vec<GatewayParam>,
+}
+
+impl GatewayParams {
+    /// `params` are sent after the `defaults`, replacing those of the same
+    /// name.
+    pub fn new<'a>(
+        defaults: impl IntoIterator<Item = &'a (&'a str, &'a str, bool)>,
+        params: Vec<GatewayParam>,
+    ) -> Self {
+        let mut all: Vec<GatewayParam> = defaults
+            .into_iter()
+            .filter(|(name, _, _)| !params.iter().any(|param| param.name == *name))
+            .map(|(name, value, if_not_empty)| GatewayParam {
+                name: name.to_string(),
+                value: VarTemplate::parse(value),
+                if_not_empty: *if_not_empty,
+            })
+            .collect();
+        all.extend(params);
+        Self { params: all }
+    }
+
+    /// Renders the parameters, followed by the request headers as `HTTP_*`
+    /// parameters.
+    pub fn render(&self, vars: &RequestVariables) -> Vec<(String, String)> {
+        let mut params: Vec<(String, String)> = self
+            .params
+            .iter()
+            .map(|param| (param, param.value.render(vars)))
+            .filter(|(param, value)| !(param.if_not_empty && value.is_empty()))
+            .map(|(param, value)| (param.name.clone(), value))
+            .collect();
+        for (name, value) in vars.request().headers() {
+            let name = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+            // Content headers already have their own parameters, and
+            // `Proxy` must never reach the application as HTTP_PROXY.
+            if matches!(
+                name.as_str(),
+                "HTTP_CONTENT_TYPE" | "HTTP_CONTENT_LENGTH" | "HTTP_PROXY"
+            ) || params.iter().any(|(n, _)| *n == name)
+            {
+                continue;
+            }
+            params.push((name, value.clone()));
+        }
+        params
+    }
+}
+
+/// A handler for a location whose gateway cannot be set up: logs why once
+/// and answers 502 rather than serving the location some other way.
+pub fn gateway_error_handler(pass: &GatewayPass, reason: String) -> HttpHandler {
+    log_error!("cannot pass requests to {}: {}", pass, reason);
+    Box::new(|req: &HttpRequest| {
+        HttpProcessor::create_status_response(req.version(), StatusCode::BAD_GATEWAY)
+    })
+}
+
+/// The `root` or `alias` directory of a block chain, as `$document_root`.
+pub fn document_root(chain: &[&ConfigContext]) -> String {
+    let config = merged_config::<StaticConfig>(chain);
+    config
+        .root
+        .or(config.alias)
+        .map(|root| root.to_string_lossy().trim_end_matches('/').to_string())
+        .unwrap_or_default()
+}
+
+/// A protocol that sends the parameters and body of a request in one go and
+/// reads back a CGI response, as uwsgi and SCGI do.
+pub trait GatewayProtocol: Default + Clone + Send + Sync + 'static {
+    /// The protocol name used in logs.
+    const NAME: &'static str;
+    /// Parameters sent in addition to [`CGI_PARAMS`].
+    const PARAMS: &'static [(&'static str, &'static str, bool)];
+
+    /// Encodes the request sent to the server.
+    fn encode(params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// The `*_pass` and `*_param` settings of a [`GatewayProtocol`].
+#[derive(Debug, Default, Clone)]
+pub struct GatewayConfig<P> {
+    pub pass: Option<GatewayPass>,
+    pub params: Option<Vec<GatewayParam>>,
+    protocol: PhantomData<P>,
+}
+
+impl<P: GatewayProtocol> MergeConfig for GatewayConfig<P> {
+    fn merge_from(&mut self, parent: &Self) {
+        self.params = self.params.take().or_else(|| parent.params.clone());
+    }
+}
+
+/// Serves the requests of one location through a [`GatewayProtocol`] server.
+pub struct ProtocolGateway<P> {
+    gateway: Gateway,
+    params: GatewayParams,
+    document_root: String,
+    protocol: PhantomData<P>,
+}
+
+impl<P: GatewayProtocol> ProtocolGateway<P> {
+    pub fn new(gateway: Gateway, params: Vec<GatewayParam>, document_root: String) -> Self {
+        Self {
+            gateway,
+            params: GatewayParams::new(CGI_PARAMS.iter().chain(P::PARAMS), params),
+            document_root,
+            protocol: PhantomData,
+        }
+    }
+
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        self.gateway.handle(req, |conn| self.exchange(conn, req))
+    }
+
+    fn exchange(&self, mut conn: GatewayConnection, req: &HttpRequest) -> io::Result<HttpResponse> {
+        let vars =
+            RequestVariables::new(req).with_value("document_root", self.document_root.clone());
+        let request = P::encode(&self.params.render(&vars), req.body())?;
+        conn.write_all(&request)?;
+        conn.flush()?;
+        read_response(BufReader::new(conn), req)
+    }
+}
+
+/// Builds the handler for a location chain that sets the protocol's `*_pass`.
+pub fn protocol_handler<P: GatewayProtocol>(chain: &[&ConfigContext]) -> Option<HttpHandler> {
+    let config = merged_config::<GatewayConfig<P>>(chain);
+    let pass = config.pass?;
+    let gateway = match Gateway::new(P::NAME, pass.clone(), chain.first().copied()) {
+        Ok(gateway) => gateway.with_resolver(resolver(chain)),
+        Err(e) => return Some(gateway_error_handler(&pass, e)),
+    };
+    let handler = Arc::new(ProtocolGateway::<P>::new(
+        gateway,
+        config.params.unwrap_or_default(),
+        document_root(chain),
+    ));
+    Some(Box::new(move |req: &HttpRequest| handler.handle(req)))
+}
+
+fn update<P: GatewayProtocol>(ctx: &mut ConfigContext, apply: impl FnOnce(&mut GatewayConfig<P>)) {
+    if let Ok(mut config) = ctx.block_config::<GatewayConfig<P>>().lock() {
+        apply(&mut config);
+    }
+}
+
+/// Handles the protocol's `*_pass` directive.
+pub fn handle_protocol_pass<P: GatewayProtocol>(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let address = ctx.str_arg(0)?;
+    let pass =
+        GatewayPass::parse(&address).map_err(|reason| ctx.invalid_value(&address, reason))?;
+    update::<P>(ctx, |config| config.pass = Some(pass));
+    Ok(())
+}
+
+/// Handles the protocol's `*_param` directive.
+pub fn handle_protocol_param<P: GatewayProtocol>(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let Some(param) = GatewayParam::from_args(ctx)? else {
+        return Ok(());
+    };
+    update::<P>(ctx, |config| {
+        config.params.get_or_insert_with(Vec::new).push(param)
+    });
+    Ok(())
+}
+
+/// Reads the head of a CGI response, or of a full HTTP response as uwsgi
+/// servers send, and streams the rest of `reader` as its body.
+pub fn read_response<R: BufRead + Send + 'static>(
+    mut reader: R,
+    req: &HttpRequest,
+) -> io::Result<HttpResponse> {
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+    let mut status = None;
+    let mut location = false;
+    let mut content_length = None;
+    let mut accel_redirect = None;
+    let mut headers = Vec::new();
+    let mut first = true;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("response ended before its headers"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if std::mem::take(&mut first) && line.starts_with("HTTP/") {
+            status = line.split_whitespace().nth(1).and_then(parse_status);
+            if status.is_none() {
+                return Err(invalid("invalid response status line"));
+            }
+            continue;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid("invalid response header"));
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            status = value.split_whitespace().next().and_then(parse_status);
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            content_length = value.parse::<u64>().ok();
+        } else if name.eq_ignore_ascii_case("X-Accel-Redirect") {
+            accel_redirect = Some(value.to_string());
+        } else if name
+            .get(..8)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("X-Accel-"))
+        {
+            // Other X-Accel-* headers control blur and are not relayed.
+        } else if !["Connection", "Keep-Alive", "Transfer-Encoding"]
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            location |= name.eq_ignore_ascii_case("Location");
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let status = status.unwrap_or(if location {
+        StatusCode::FOUND
+    } else {
+        StatusCode::OK
+    });
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*req.version(), status);
+    for (name, value) in &headers {
+        resp.set_header(name, value);
+    }
+    if accel_redirect.is_some() {
+        resp.accel_redirect = accel_redirect;
+    } else if *req.method() == http::Method::HEAD {
+        if let Some(len) = content_length {
+            resp.set_header("Content-Length", &len.to_string());
+        }
+    } else {
+        resp.set_body_stream(StreamBody::new(reader, content_length));
+    }
+    Ok(resp)
+}
+
+fn parse_status(code: &str) -> Option<StatusCode> {
+    StatusCode::from_u16(code.parse().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, TcpListener},
+        thread,
+    };
+
+    use super::*;
+
+    /// Sends `name=value` lines, a blank line and the body.
+    #[derive(Debug, Default, Clone)]
+    struct Lines;
+
+    impl GatewayProtocol for Lines {
+        const NAME: &'static str = "lines";
+        const PARAMS: &'static [(&'static str, &'static str, bool)] =
+            &[("PATH_INFO", "$document_uri", false)];
+
+        fn encode(params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+            let mut request = String::new();
+            for (name, value) in params {
+                request.push_str(&format!("{}={}\n", name, value));
+            }
+            request.push('\n');
+            let mut request = request.into_bytes();
+            request.extend_from_slice(body);
+            Ok(request)
+        }
+    }
+
+    #[test]
+    fn test_protocol_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut params = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\n" {
+                    break;
+                }
+                params.push(line.trim_end().to_string());
+            }
+            let mut body = [0; 4];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(b"Location: /done\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            (params, body)
+        });
+
+        let gateway = Gateway::new(
+            Lines::NAME,
+            GatewayPass::parse(&format!("127.0.0.1:{}", port)).unwrap(),
+            None,
+        )
+        .unwrap();
+        let param = GatewayParam {
+            name: "PATH_INFO".to_string(),
+            value: VarTemplate::parse("/app$uri"),
+            if_not_empty: false,
+        };
+        let handler = ProtocolGateway::<Lines>::new(gateway, vec![param], "/srv".to_string());
+        let mut req = HttpRequest::new();
+        req.parse(b"POST /form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 4\r\n\r\nbody")
+            .unwrap();
+
+        let resp = handler.handle(&req);
+        assert_eq!(resp.status(), Some(302));
+        assert_eq!(resp.header_value("Location"), Some("/done"));
+
+        let (params, body) = server.join().unwrap();
+        assert_eq!(&body, b"body");
+        for expected in [
+            "REQUEST_METHOD=POST",
+            "CONTENT_LENGTH=4",
+            "DOCUMENT_ROOT=/srv",
+            "HTTP_HOST=example.com",
+        ] {
+            assert!(params.iter().any(|p| p == expected), "{:?}", params);
+        }
+        // A param replaces the protocol's default of the same name.
+        let path_info: Vec<&String> = params
+            .iter()
+            .filter(|p| p.starts_with("PATH_INFO="))
+            .collect();
+        assert_eq!(path_info, ["PATH_INFO=/app/form"]);
+    }
+
+    #[test]
+    fn test_read_http_response() {
+        let mut req = HttpRequest::new();
+        req.parse(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let upstream = b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\r\nmissing";
+        let mut resp = read_response(io::Cursor::new(upstream.to_vec()), &req).unwrap();
+        resp.load_body().unwrap();
+        assert_eq!(resp.status(), Some(404));
+        assert_eq!(resp.header_value("Content-Type"), Some("text/plain"));
+        assert_eq!(resp.body, b"missing");
+    }
+
+    #[test]
+    fn test_protocol_config_merge() {
+        let param = |name: &str| GatewayParam {
+            name: name.to_string(),
+            value: VarTemplate::parse(""),
+            if_not_empty: false,
+        };
+        let parent = GatewayConfig::<Lines> {
+            pass: GatewayPass::parse("127.0.0.1:9000").ok(),
+            params: Some(vec![param("A")]),
+            ..Default::default()
+        };
+        let mut inherits = GatewayConfig::<Lines>::default();
+        inherits.merge_from(&parent);
+        assert_eq!(inherits.params.unwrap()[0].name, "A");
+        assert!(inherits.pass.is_none());
+
+        let mut overrides = GatewayConfig::<Lines> {
+            params: Some(vec![param("B")]),
+            ..Default::default()
+        };
+        overrides.merge_from(&parent);
+        let names: Vec<String> = overrides
+            .params
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(names, ["B"]);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{CommandBuilder, ParameterBuilder},
+            config_context::ConfigContext,
+            config_loader::ConfigError,
+        },
+        ip_trie::{canonical, parse_cidr, IpTrie},
+    },
+    register_commands,
+};
+
+use super::http_variables::{
+    parse_variable_name, RequestVariables, VarTemplate, VariableDefinition, VariableSource,
+};
+
+register_commands!(CommandBuilder::new("geo")
+    .is_raw_block()
+    .allowed_parents(vec!["http".to_string()])
+    .display_name("en", "Geo")
+    .display_name("zh-tw", "IP 位址變數")
+    .desc(
+        "en",
+        "Creates a variable whose value depends on the client IP address"
+    )
+    .desc("zh-tw", "建立一個依據客戶端 IP 位址決定其值的變數")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Address or Variable")
+            .display_name("zh-tw", "位址或變數")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Variable holding the address to match, or the resulting variable when only one argument is given"
+            )
+            .desc("zh-tw", "存放要匹配位址的變數；只有一個參數時則為結果變數")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Variable")
+            .display_name("zh-tw", "變數")
+            .type_name("String")
+            .is_required(false)
+            .default("")
+            .desc("en", "Name of the resulting variable, e.g. $geo")
+            .desc("zh-tw", "結果變數名稱，例如 $geo")
+            .build(),
+    ])
+    .build(handle_geo));
+
+#[derive(Default)]
+pub struct GeoMap {
+    source: Option<VarTemplate>,
+    networks: IpTrie<String>,
+    ranges: Vec<(IpAddr, IpAddr, String)>,
+    default: String,
+}
+
+impl GeoMap {
+    pub fn new(source: Option<VarTemplate>) -> Self {
+        Self {
+            source,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_default(&mut self, value: &str) {
+        self.default = value.to_string();
+    }
+
+    pub fn insert(&mut self, network: &str, value: &str) -> Result<(), String> {
+        let (addr, prefix_len) = parse_cidr(network)?;
+        self.networks.insert(addr, prefix_len, value.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, network: &str) -> Result<(), String> {
+        let (addr, prefix_len) = parse_cidr(network)?;
+        self.networks.remove(addr, prefix_len);
+        Ok(())
+    }
+
+    pub fn insert_range(&mut self, range: &str, value: &str) -> Result<(), String> {
+        let parse = |addr: &str| {
+            addr.parse::<IpAddr>()
+                .map(canonical)
+                .map_err(|_| format!("invalid IP address \"{}\"", addr))
+        };
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("invalid range \"{}\"", range))?;
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start.is_ipv4() != end.is_ipv4() || start > end {
+            return Err(format!("invalid range \"{}\"", range));
+        }
+        self.ranges.push((start, end, value.to_string()));
+        Ok(())
+    }
+
+    pub fn lookup(&self, addr: IpAddr) -> &str {
+        let addr = canonical(addr);
+        // Later ranges take precedence, as they do for networks declared twice.
+        if let Some((_, _, value)) = self
+            .ranges
+            .iter()
+            .rev()
+            .find(|(start, end, _)| *start <= addr && addr <= *end)
+        {
+            return value;
+        }
+        self.networks.lookup(addr).unwrap_or(&self.default)
+    }
+}
+
+impl VariableSource for GeoMap {
+    fn evaluate(&self, vars: &RequestVariables) -> Option<String> {
+        let addr = match &self.source {
+            Some(source) => parse_client_addr(&source.render(vars)),
+            None => vars.request().remote_addr().map(|addr| addr.ip()),
+        };
+        Some(match addr {
+            Some(addr) => self.lookup(addr).to_string(),
+            None => self.default.clone(),
+        })
+    }
+}
+
+fn parse_client_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+pub fn handle_geo(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let first = ctx.str_arg(0)?;
+    let (source, target) = match ctx.opt_arg(1).and_then(|v| v.as_str()) {
+        Some(target) if !target.is_empty() => {
+            (Some(VarTemplate::parse(&first)), target.to_string())
+        }
+        _ => (None, first),
+    };
+    let name = parse_variable_name(&target)
+        .ok_or_else(|| ctx.invalid_value(&target, "variable name must start with \"$\""))?
+        .to_string();
+
+    let mut geo = GeoMap::new(source);
+    let ranges = ctx
+        .raw_entries
+        .iter()
+        .any(|entry| entry.args.len() == 1 && entry.args[0] == "ranges");
+    for entry in &ctx.raw_entries {
+        let result = match entry.args.as_slice() {
+            [flag] if flag == "ranges" => Ok(()),
+            [key, value] if key == "default" => {
+                geo.set_default(value);
+                Ok(())
+            }
+            [key, network] if key == "delete" => geo.remove(network),
+            [key, value] if ranges => geo.insert_range(key, value),
+            [key, value] => geo.insert(key, value),
+            _ => {
+                return Err(ConfigError::InvalidArgCount {
+                    name: ctx.current_cmd_name.clone(),
+                    pos: entry.pos.clone(),
+                })
+            }
+        };
+        result.map_err(|reason| ctx.invalid_entry(entry, &entry.args[0], reason))?;
+    }
+
+    ctx.store.insert(Arc::new(VariableDefinition {
+        name,
+        source: Arc::new(geo),
+    }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_networks_and_ranges() {
+        let mut geo = GeoMap::new(None);
+        geo.set_default("world");
+        geo.insert("10.0.0.0/8", "internal").unwrap();
+        geo.insert("10.9.0.0/16", "lab").unwrap();
+        geo.remove("10.9.0.0/16").unwrap();
+        geo.insert_range("192.168.1.10-192.168.1.20", "office")
+            .unwrap();
+
+        let lookup = |ip: &str| geo.lookup(ip.parse().unwrap()).to_string();
+        assert_eq!(lookup("10.9.1.1"), "internal");
+        assert_eq!(lookup("192.168.1.15"), "office");
+        assert_eq!(lookup("192.168.1.21"), "world");
+        assert!(geo.insert_range("10.0.0.5-10.0.0.1", "bad").is_err());
+        assert_eq!(parse_client_addr("[::1]:443"), Some("::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,388 @@
+use std::{net::IpAddr, sync::Arc};
+
+use maxminddb::{geoip2, Reader};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("geoip_country")
+        .is_unique()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "GeoIP Country Database")
+        .display_name("zh-tw", "GeoIP 國家資料庫")
+        .desc(
+            "en",
+            "A MaxMind GeoLite2 or GeoIP2 Country database setting $geoip_country_code and $geoip_country_name"
+        )
+        .desc(
+            "zh-tw",
+            "設定 $geoip_country_code 與 $geoip_country_name 的 MaxMind GeoLite2 或 GeoIP2 國家資料庫"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "The .mmdb file, e.g. GeoLite2-Country.mmdb")
+            .desc("zh-tw", ".mmdb 檔案，例如 GeoLite2-Country.mmdb")
+            .build()])
+        .build(handle_geoip_country),
+    CommandBuilder::new("geoip_city")
+        .is_unique()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "GeoIP City Database")
+        .display_name("zh-tw", "GeoIP 城市資料庫")
+        .desc(
+            "en",
+            "A MaxMind GeoLite2 or GeoIP2 City database setting $geoip_city and $geoip_region, and the country without geoip_country"
+        )
+        .desc(
+            "zh-tw",
+            "設定 $geoip_city 與 $geoip_region 的 MaxMind GeoLite2 或 GeoIP2 城市資料庫，未設定 geoip_country 時也提供國家"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "The .mmdb file, e.g. GeoLite2-City.mmdb")
+            .desc("zh-tw", ".mmdb 檔案，例如 GeoLite2-City.mmdb")
+            .build()])
+        .build(handle_geoip_city),
+    CommandBuilder::new("geoip_asn")
+        .is_unique()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "GeoIP ASN Database")
+        .display_name("zh-tw", "GeoIP ASN 資料庫")
+        .desc(
+            "en",
+            "A MaxMind GeoLite2 or GeoIP2 ASN database setting $geoip_asn and $geoip_org"
+        )
+        .desc(
+            "zh-tw",
+            "設定 $geoip_asn 與 $geoip_org 的 MaxMind GeoLite2 或 GeoIP2 ASN 資料庫"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "The .mmdb file, e.g. GeoLite2-ASN.mmdb")
+            .desc("zh-tw", ".mmdb 檔案，例如 GeoLite2-ASN.mmdb")
+            .build()])
+        .build(handle_geoip_asn)
+);
+
+/// The ISO 3166 code of the client's country, such as `TW`.
+pub const GEOIP_COUNTRY_CODE: &str = "geoip_country_code";
+/// The English name of the client's country.
+pub const GEOIP_COUNTRY_NAME: &str = "geoip_country_name";
+/// The English name of the client's city.
+pub const GEOIP_CITY: &str = "geoip_city";
+/// The English name of the largest subdivision the client is in.
+pub const GEOIP_REGION: &str = "geoip_region";
+/// The number of the autonomous system the client address belongs to.
+pub const GEOIP_ASN: &str = "geoip_asn";
+/// The organization running that autonomous system.
+pub const GEOIP_ORG: &str = "geoip_org";
+
+const LANGUAGE: &str = "en";
+
+type Database = Arc<Reader<Vec<u8>>>;
+
+#[derive(Default, Clone)]
+pub struct GeoIpConfig {
+    pub country: Option<Database>,
+    pub city: Option<Database>,
+    pub asn: Option<Database>,
+}
+
+impl MergeConfig for GeoIpConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Looks up the client address in the configured databases.
+pub struct GeoIpPhase {
+    config: GeoIpConfig,
+}
+
+impl GeoIpPhase {
+    fn lookup(&self, addr: IpAddr) -> [(&'static str, String); 6] {
+        let name = |names: Option<std::collections::BTreeMap<&str, &str>>| {
+            names
+                .and_then(|names| names.get(LANGUAGE).map(|name| name.to_string()))
+                .unwrap_or_default()
+        };
+        let mut country_code = String::new();
+        let mut country_name = String::new();
+        let mut city_name = String::new();
+        let mut region = String::new();
+        let mut asn = String::new();
+        let mut org = String::new();
+        if let Some(country) = self
+            .config
+            .country
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Country>(addr).ok())
+            .and_then(|record| record.country)
+        {
+            country_code = country.iso_code.unwrap_or_default().to_string();
+            country_name = name(country.names);
+        }
+        if let Some(record) = self
+            .config
+            .city
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::City>(addr).ok())
+        {
+            city_name = name(record.city.and_then(|city| city.names));
+            region = name(
+                record
+                    .subdivisions
+                    .and_then(|subdivisions| subdivisions.into_iter().next())
+                    .and_then(|subdivision| subdivision.names),
+            );
+            if self.config.country.is_none() {
+                if let Some(country) = record.country {
+                    country_code = country.iso_code.unwrap_or_default().to_string();
+                    country_name = name(country.names);
+                }
+            }
+        }
+        if let Some(record) = self
+            .config
+            .asn
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Asn>(addr).ok())
+        {
+            asn = record
+                .autonomous_system_number
+                .map(|number| number.to_string())
+                .unwrap_or_default();
+            org = record
+                .autonomous_system_organization
+                .unwrap_or_default()
+                .to_string();
+        }
+        [
+            (GEOIP_COUNTRY_CODE, country_code),
+            (GEOIP_COUNTRY_NAME, country_name),
+            (GEOIP_CITY, city_name),
+            (GEOIP_REGION, region),
+            (GEOIP_ASN, asn),
+            (GEOIP_ORG, org),
+        ]
+    }
+}
+
+impl RequestPhase for GeoIpPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        // The server phases have already looked the client up.
+        if req.var(GEOIP_COUNTRY_CODE).is_some() {
+            return PhaseResult::Continue;
+        }
+        let Some(addr) = req.remote_addr().map(|addr| addr.ip().to_canonical()) else {
+            return PhaseResult::Continue;
+        };
+        for (name, value) in self.lookup(addr) {
+            req.set_var(name, &value);
+        }
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the lookup for the databases of the `http` block at the start of
+/// the chain.
+pub fn geoip_phase(chain: &[&ConfigContext]) -> Option<GeoIpPhase> {
+    let config = merged_config::<GeoIpConfig>(&chain[..1]);
+    if config.country.is_none() && config.city.is_none() && config.asn.is_none() {
+        return None;
+    }
+    Some(GeoIpPhase { config })
+}
+
+fn open_database(ctx: &ConfigContext) -> Result<Option<Database>, ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(None);
+    }
+    let path = ctx.path_arg(0)?;
+    Reader::open_readfile(&path)
+        .map(|reader| Some(Arc::new(reader)))
+        .map_err(|e| ctx.invalid_value(&path.display().to_string(), e.to_string()))
+}
+
+pub fn handle_geoip_country(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(database) = open_database(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<GeoIpConfig>().lock() {
+        config.country = Some(database);
+    }
+    Ok(())
+}
+
+pub fn handle_geoip_city(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(database) = open_database(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<GeoIpConfig>().lock() {
+        config.city = Some(database);
+    }
+    Ok(())
+}
+
+pub fn handle_geoip_asn(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(database) = open_database(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<GeoIpConfig>().lock() {
+        config.asn = Some(database);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        match value.len() {
+            len @ 0..29 => out.push(2 << 5 | len as u8),
+            len => out.extend([2 << 5 | 29, (len - 29) as u8]),
+        }
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn map(out: &mut Vec<u8>, entries: usize) {
+        out.push(7 << 5 | entries as u8);
+    }
+
+    fn uint(out: &mut Vec<u8>, kind: u8, value: u64) {
+        let bytes = value.to_be_bytes();
+        let bytes = match kind {
+            5 => &bytes[6..],
+            6 => &bytes[4..],
+            _ => &bytes[..],
+        };
+        if kind > 7 {
+            out.extend([bytes.len() as u8, kind - 7]);
+        } else {
+            out.push(kind << 5 | bytes.len() as u8);
+        }
+        out.extend_from_slice(bytes);
+    }
+
+    fn names(out: &mut Vec<u8>, key: &str, name: &str) {
+        string(out, key);
+        map(out, 1);
+        string(out, "names");
+        map(out, 1);
+        string(out, LANGUAGE);
+        string(out, name);
+    }
+
+    /// Writes an IPv4 MaxMind database with one record for `prefix`.
+    fn database(prefix: [u8; 4], len: u32) -> Reader<Vec<u8>> {
+        let mut data = Vec::new();
+        map(&mut data, 5);
+        string(&mut data, "country");
+        map(&mut data, 2);
+        string(&mut data, "iso_code");
+        string(&mut data, "TW");
+        string(&mut data, "names");
+        map(&mut data, 1);
+        string(&mut data, LANGUAGE);
+        string(&mut data, "Taiwan");
+        names(&mut data, "city", "Taipei");
+        string(&mut data, "subdivisions");
+        data.extend([1, 11 - 7]);
+        map(&mut data, 1);
+        string(&mut data, "names");
+        map(&mut data, 1);
+        string(&mut data, LANGUAGE);
+        string(&mut data, "Taipei City");
+        string(&mut data, "autonomous_system_number");
+        uint(&mut data, 6, 3462);
+        string(&mut data, "autonomous_system_organization");
+        string(&mut data, "Chunghwa Telecom");
+
+        let prefix = u32::from_be_bytes(prefix);
+        let mut db = Vec::new();
+        for node in 0..len {
+            let next = if node + 1 < len { node + 1 } else { len + 16 };
+            let (left, right) = if (prefix >> (31 - node)) & 1 == 0 {
+                (next, len)
+            } else {
+                (len, next)
+            };
+            db.extend_from_slice(&left.to_be_bytes()[1..]);
+            db.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        db.extend([0; 16]);
+        db.extend(data);
+        db.extend(b"\xAB\xCD\xEFMaxMind.com");
+        map(&mut db, 9);
+        string(&mut db, "binary_format_major_version");
+        uint(&mut db, 5, 2);
+        string(&mut db, "binary_format_minor_version");
+        uint(&mut db, 5, 0);
+        string(&mut db, "build_epoch");
+        uint(&mut db, 9, 0);
+        string(&mut db, "database_type");
+        string(&mut db, "Test");
+        string(&mut db, "description");
+        map(&mut db, 0);
+        string(&mut db, "ip_version");
+        uint(&mut db, 5, 4);
+        string(&mut db, "languages");
+        db.extend([0, 11 - 7]);
+        string(&mut db, "node_count");
+        uint(&mut db, 6, len as u64);
+        string(&mut db, "record_size");
+        uint(&mut db, 5, 24);
+        Reader::from_source(db).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_fills_variables() {
+        let db = Arc::new(database([203, 0, 113, 0], 24));
+        let phase = GeoIpPhase {
+            config: GeoIpConfig {
+                country: None,
+                city: Some(db.clone()),
+                asn: Some(db),
+            },
+        };
+        let vars = phase.lookup("203.0.113.7".parse().unwrap());
+        let value = |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(value(GEOIP_COUNTRY_CODE), Some("TW"));
+        assert_eq!(value(GEOIP_COUNTRY_NAME), Some("Taiwan"));
+        assert_eq!(value(GEOIP_CITY), Some("Taipei"));
+        assert_eq!(value(GEOIP_REGION), Some("Taipei City"));
+        assert_eq!(value(GEOIP_ASN), Some("3462"));
+        assert_eq!(value(GEOIP_ORG), Some("Chunghwa Telecom"));
+
+        let missing = phase.lookup("198.51.100.1".parse().unwrap());
+        assert!(missing.iter().all(|(_, value)| value.is_empty()));
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_rewrite::status_response};
+
+register_commands!(
+    CommandBuilder::new("gunzip_request")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Gunzip Request")
+        .display_name("zh-tw", "解壓縮請求")
+        .desc(
+            "en",
+            "Decompresses request bodies sent with Content-Encoding: gzip before handlers see them"
+        )
+        .desc(
+            "zh-tw",
+            "在處理器讀取前解壓縮帶有 Content-Encoding: gzip 的請求內容"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_gunzip_request),
+    CommandBuilder::new("gunzip_request_max_size")
+        .allowed_parents(vec!["http".to_string(), "server".to_string()])
+        .display_name("en", "Gunzip Request Max Size")
+        .display_name("zh-tw", "解壓縮請求大小上限")
+        .desc(
+            "en",
+            "Limits the decompressed size of a request body; larger bodies are rejected with 413"
+        )
+        .desc("zh-tw", "限制請求內容解壓縮後的大小，超過時回傳 413")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc("en", "Decompressed size, e.g. 10m, defaults to 10m")
+            .desc("zh-tw", "解壓縮後的大小，例如 10m，預設為 10m")
+            .build()])
+        .build(handle_gunzip_request_max_size),
+);
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Default, Clone)]
+pub struct GunzipConfig {
+    pub enabled: Option<bool>,
+    pub max_size: Option<u64>,
+}
+
+impl MergeConfig for GunzipConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        self.max_size = self.max_size.or(parent.max_size);
+    }
+}
+
+/// Decompresses gzip request bodies in place, so handlers always see the
+/// plain body.
+pub struct GunzipPhase {
+    max_size: u64,
+}
+
+impl GunzipPhase {
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size }
+    }
+
+    fn decompress(&self, body: &[u8]) -> Result<Vec<u8>, StatusCode> {
+        let mut decoded = Vec::new();
+        GzDecoder::new(body)
+            .take(self.max_size + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        if decoded.len() as u64 > self.max_size {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Ok(decoded)
+    }
+}
+
+impl RequestPhase for GunzipPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let is_gzip = req.header("Content-Encoding").is_some_and(|coding| {
+            coding.trim().eq_ignore_ascii_case("gzip")
+                || coding.trim().eq_ignore_ascii_case("x-gzip")
+        });
+        if !is_gzip {
+            return PhaseResult::Continue;
+        }
+        match self.decompress(req.body()) {
+            Ok(body) => {
+                req.remove_header("Content-Encoding");
+                req.set_header("Content-Length", &body.len().to_string());
+                req.set_body(body);
+                PhaseResult::Continue
+            }
+            Err(status) => PhaseResult::Respond(Box::new(status_response(req, status))),
+        }
+    }
+}
+
+/// Builds the decompression phase for a block chain, if it is enabled.
+pub fn gunzip_phase(chain: &[&ConfigContext]) -> Option<GunzipPhase> {
+    let config = merged_config::<GunzipConfig>(chain);
+    config
+        .enabled
+        .unwrap_or(false)
+        .then(|| GunzipPhase::new(config.max_size.unwrap_or(DEFAULT_MAX_SIZE)))
+}
+
+pub fn handle_gunzip_request(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<GunzipConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_gunzip_request_max_size(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let max_size = ctx.size_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<GunzipConfig>().lock() {
+        config.max_size = Some(max_size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn gzip_request(body: &[u8]) -> HttpRequest {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut req = HttpRequest::new();
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            compressed.len()
+        );
+        req.parse(&[head.as_bytes(), &compressed].concat()).unwrap();
+        req
+    }
+
+    #[test]
+    fn test_gunzip_request_body() {
+        let mut req = gzip_request(b"hello hello hello");
+        assert!(matches!(
+            GunzipPhase::new(1024).run(&mut req),
+            PhaseResult::Continue
+        ));
+        assert_eq!(req.body(), b"hello hello hello");
+        assert_eq!(req.header("Content-Length"), Some("17"));
+        assert!(req.header("Content-Encoding").is_none());
+
+        let mut bomb = gzip_request(&[0; 4096]);
+        match GunzipPhase::new(1024).run(&mut bomb) {
+            PhaseResult::Respond(resp) => assert_eq!(resp.status(), Some(413)),
+            _ => panic!("expected 413"),
+        }
+    }
+}
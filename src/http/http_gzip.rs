@@ -0,0 +1,208 @@
+use std::io::{self, Write};
+
+use flate2::{write::GzEncoder, Compression};
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_compression::Encoder;
+
+register_commands!(
+    CommandBuilder::new("gzip")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Gzip")
+        .display_name("zh-tw", "Gzip 壓縮")
+        .desc(
+            "en",
+            "Compresses responses with gzip for clients that accept it"
+        )
+        .desc("zh-tw", "對接受 gzip 的客戶端以 gzip 壓縮回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_gzip),
+    CommandBuilder::new("gzip_types")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Gzip Types")
+        .display_name("zh-tw", "Gzip 類型")
+        .desc(
+            "en",
+            "Sets the MIME types compressed, by any encoder, in addition to text/html"
+        )
+        .desc(
+            "zh-tw",
+            "設定除了 text/html 之外要壓縮的 MIME 類型，適用於所有壓縮方式"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "MIME Types")
+            .display_name("zh-tw", "MIME 類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more MIME types, e.g. application/json, or * for any type"
+            )
+            .desc(
+                "zh-tw",
+                "一個或多個 MIME 類型，例如 application/json，或以 * 表示所有類型"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_gzip_types),
+    CommandBuilder::new("gzip_min_length")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Gzip Min Length")
+        .display_name("zh-tw", "Gzip 最小長度")
+        .desc(
+            "en",
+            "Sets the smallest response body that is compressed, by any encoder"
+        )
+        .desc(
+            "zh-tw",
+            "設定會被壓縮的最小回應內容長度，適用於所有壓縮方式"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc("en", "Body size, e.g. 1k, defaults to 20")
+            .desc("zh-tw", "內容大小，例如 1k，預設為 20")
+            .build()])
+        .build(handle_gzip_min_length),
+    CommandBuilder::new("gzip_comp_level")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Gzip Compression Level")
+        .display_name("zh-tw", "Gzip 壓縮等級")
+        .desc("en", "Sets the gzip compression level")
+        .desc("zh-tw", "設定 gzip 壓縮等級")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Level")
+            .display_name("zh-tw", "等級")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "1 (fastest) to 9 (smallest), defaults to 1")
+            .desc("zh-tw", "1（最快）到 9（最小），預設為 1")
+            .build()])
+        .build(handle_gzip_comp_level),
+);
+
+pub const DEFAULT_COMP_LEVEL: u32 = 1;
+
+#[derive(Debug, Default, Clone)]
+pub struct GzipConfig {
+    pub enabled: Option<bool>,
+    pub types: Option<Vec<String>>,
+    pub min_length: Option<u64>,
+    pub comp_level: Option<u32>,
+}
+
+impl MergeConfig for GzipConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        self.types = self.types.take().or_else(|| parent.types.clone());
+        self.min_length = self.min_length.or(parent.min_length);
+        self.comp_level = self.comp_level.or(parent.comp_level);
+    }
+}
+
+pub struct GzipEncoder {
+    level: u32,
+}
+
+impl GzipEncoder {
+    pub fn from_config(config: &GzipConfig) -> Option<Self> {
+        config.enabled.unwrap_or(false).then(|| Self {
+            level: config.comp_level.unwrap_or(DEFAULT_COMP_LEVEL),
+        })
+    }
+}
+
+impl Encoder for GzipEncoder {
+    fn coding(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.level));
+        encoder.write_all(body)?;
+        encoder.finish()
+    }
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut GzipConfig)) {
+    if let Ok(mut config) = ctx.block_config::<GzipConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_gzip(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.enabled = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_gzip_types(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let types = ctx.args();
+    if types.is_empty() {
+        return Ok(());
+    }
+    update(ctx, |config| config.types = Some(types));
+    Ok(())
+}
+
+pub fn handle_gzip_min_length(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let min_length = ctx.size_arg(0)?;
+    update(ctx, |config| config.min_length = Some(min_length));
+    Ok(())
+}
+
+pub fn handle_gzip_comp_level(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let level = ctx.number_arg(0)?;
+    if !(1..=9).contains(&level) {
+        return Err(ctx.invalid_value(&level.to_string(), "must be between 1 and 9"));
+    }
+    update(ctx, |config| config.comp_level = Some(level as u32));
+    Ok(())
+}
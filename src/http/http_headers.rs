@@ -0,0 +1,483 @@
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, Parameter, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::ResponseFilter,
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("add_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Add Header")
+        .display_name("zh-tw", "新增回應標頭")
+        .desc(
+            "en",
+            "Adds a header to responses with status 200, 201, 204, 206, 301, 302, 303, 304, 307 or 308"
+        )
+        .desc(
+            "zh-tw",
+            "在狀態碼為 200、201、204、206、301、302、303、304、307 或 308 的回應加上標頭"
+        )
+        .params(header_params())
+        .arity(Arity::Range(2, 3))
+        .build(handle_add_header),
+    CommandBuilder::new("add_trailer")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Add Trailer")
+        .display_name("zh-tw", "新增回應尾端標頭")
+        .desc(
+            "en",
+            "Adds a trailer field after chunked response bodies, for the same statuses as add_header"
+        )
+        .desc(
+            "zh-tw",
+            "在分塊傳輸的回應主體之後加上尾端標頭，適用狀態碼與 add_header 相同"
+        )
+        .params(header_params())
+        .arity(Arity::Range(2, 3))
+        .build(handle_add_trailer),
+    CommandBuilder::new("more_set_headers")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Set Headers")
+        .display_name("zh-tw", "設定回應標頭")
+        .desc(
+            "en",
+            "Replaces response headers, or removes them when the value is empty, optionally only for some statuses"
+        )
+        .desc(
+            "zh-tw",
+            "取代回應標頭，值為空時移除該標頭，可限定只套用於特定狀態碼"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Headers")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Optional -s \"404 500\" for the statuses, then \"Name: value\" pairs; values may contain variables"
+            )
+            .desc(
+                "zh-tw",
+                "可先以 -s \"404 500\" 指定狀態碼，之後為 \"名稱: 值\"；值可包含變數"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_more_set_headers),
+    CommandBuilder::new("more_clear_headers")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Clear Headers")
+        .display_name("zh-tw", "移除回應標頭")
+        .desc(
+            "en",
+            "Removes response headers, optionally only for some statuses"
+        )
+        .desc("zh-tw", "移除回應標頭，可限定只套用於特定狀態碼")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Headers")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Optional -s \"404 500\" for the statuses, then header names; a trailing * matches a prefix, such as X-Debug-*"
+            )
+            .desc(
+                "zh-tw",
+                "可先以 -s \"404 500\" 指定狀態碼，之後為標頭名稱；結尾的 * 比對前綴，例如 X-Debug-*"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_more_clear_headers)
+);
+
+fn header_params() -> Vec<Parameter> {
+    vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Name")
+            .display_name("zh-tw", "名稱")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Field name")
+            .desc("zh-tw", "欄位名稱")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Value")
+            .display_name("zh-tw", "值")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Value with variables; nothing is added when it is empty",
+            )
+            .desc("zh-tw", "值，可包含變數；結果為空時不加入")
+            .build(),
+        ParameterBuilder::new(2)
+            .display_name("en", "Always")
+            .display_name("zh-tw", "一律加入")
+            .type_name("String")
+            .default("")
+            .desc("en", "always to add it whatever the status")
+            .desc("zh-tw", "always 表示不論狀態碼皆加入")
+            .build(),
+    ]
+}
+
+/// Statuses `add_header` and `add_trailer` apply to without `always`.
+const ADD_STATUSES: [u16; 10] = [200, 201, 204, 206, 301, 302, 303, 304, 307, 308];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddedHeader {
+    pub name: String,
+    pub value: VarTemplate,
+    pub always: bool,
+}
+
+impl AddedHeader {
+    fn applies(&self, status: u16) -> bool {
+        self.always || ADD_STATUSES.contains(&status)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderEdit {
+    /// Replaces the header, or only removes it when the value is empty.
+    Set(String, VarTemplate),
+    /// Removes the header; a name ending in `*` matches a prefix.
+    Clear(String),
+}
+
+impl HeaderEdit {
+    fn clears(pattern: &str, name: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name
+                .get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => name.eq_ignore_ascii_case(pattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderEdits {
+    /// Empty for every status.
+    pub statuses: Vec<u16>,
+    pub edits: Vec<HeaderEdit>,
+}
+
+/// Each list is only inherited by blocks that set none of its directive.
+#[derive(Debug, Default, Clone)]
+pub struct HeadersConfig {
+    pub headers: Option<Vec<AddedHeader>>,
+    pub trailers: Option<Vec<AddedHeader>>,
+    pub edits: Option<Vec<HeaderEdits>>,
+}
+
+impl MergeConfig for HeadersConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.headers.is_none() {
+            self.headers = parent.headers.clone();
+        }
+        if self.trailers.is_none() {
+            self.trailers = parent.trailers.clone();
+        }
+        if self.edits.is_none() {
+            self.edits = parent.edits.clone();
+        }
+    }
+}
+
+/// Applies the header directives of a block to its responses: the
+/// `more_*` edits first, then the added headers and trailers.
+#[derive(Debug, Default)]
+pub struct HeadersFilter {
+    headers: Vec<AddedHeader>,
+    trailers: Vec<AddedHeader>,
+    edits: Vec<HeaderEdits>,
+}
+
+impl ResponseFilter for HeadersFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        let status = resp.status().unwrap_or(0);
+        for group in &self.edits {
+            if !group.statuses.is_empty() && !group.statuses.contains(&status) {
+                continue;
+            }
+            for edit in &group.edits {
+                match edit {
+                    HeaderEdit::Set(name, value) => {
+                        let value = value.render(&RequestVariables::new(req).with_response(resp));
+                        resp.remove_header(name);
+                        if !value.is_empty() {
+                            resp.set_header(name, &value);
+                        }
+                    }
+                    HeaderEdit::Clear(pattern) => {
+                        let names: Vec<String> = resp
+                            .headers()
+                            .filter(|(name, _)| HeaderEdit::clears(pattern, name))
+                            .map(|(name, _)| name.to_string())
+                            .collect();
+                        for name in names {
+                            resp.remove_header(&name);
+                        }
+                    }
+                }
+            }
+        }
+
+        let render = |added: &[AddedHeader], resp: &HttpResponse| -> Vec<(String, String)> {
+            let vars = RequestVariables::new(req).with_response(resp);
+            added
+                .iter()
+                .filter(|header| header.applies(status))
+                .map(|header| (header.name.clone(), header.value.render(&vars)))
+                .filter(|(_, value)| !value.is_empty())
+                .collect()
+        };
+        for (name, value) in render(&self.headers, resp) {
+            resp.set_header(&name, &value);
+        }
+        if resp.is_chunked() {
+            let trailers = render(&self.trailers, resp);
+            if !trailers.is_empty() {
+                let names: Vec<&str> = trailers.iter().map(|(name, _)| name.as_str()).collect();
+                resp.set_header("Trailer", &names.join(", "));
+            }
+            for (name, value) in trailers {
+                resp.set_trailer(&name, &value);
+            }
+        }
+    }
+}
+
+/// Builds the header filter for a block chain, if it changes any header.
+pub fn headers_filter(chain: &[&ConfigContext]) -> Option<HeadersFilter> {
+    let config = merged_config::<HeadersConfig>(chain);
+    let filter = HeadersFilter {
+        headers: config.headers.unwrap_or_default(),
+        trailers: config.trailers.unwrap_or_default(),
+        edits: config.edits.unwrap_or_default(),
+    };
+    let changes_any =
+        !filter.headers.is_empty() || !filter.trailers.is_empty() || !filter.edits.is_empty();
+    changes_any.then_some(filter)
+}
+
+fn added_header(ctx: &ConfigContext) -> Result<Option<AddedHeader>, ConfigError> {
+    let args = ctx.args();
+    let (name, value, always) = match args.as_slice() {
+        [] => return Ok(None),
+        [name, value] => (name, value, false),
+        [name, value, always] if always == "always" => (name, value, true),
+        [_, _, other] => return Err(ctx.invalid_value(other, "expected always")),
+        [name] | [name, ..] => return Err(ctx.invalid_value(name, "expected a name and a value")),
+    };
+    if name.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(ctx.invalid_value(name, "invalid field name"));
+    }
+    Ok(Some(AddedHeader {
+        name: name.clone(),
+        value: VarTemplate::parse(value),
+        always,
+    }))
+}
+
+pub fn handle_add_header(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(header) = added_header(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<HeadersConfig>().lock() {
+        config.headers.get_or_insert_with(Vec::new).push(header);
+    }
+    Ok(())
+}
+
+pub fn handle_add_trailer(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(trailer) = added_header(ctx)? else {
+        return Ok(());
+    };
+    if let Ok(mut config) = ctx.block_config::<HeadersConfig>().lock() {
+        config.trailers.get_or_insert_with(Vec::new).push(trailer);
+    }
+    Ok(())
+}
+
+/// Splits the `-s "..."` status list off the arguments of a `more_*`
+/// directive.
+fn statuses(ctx: &ConfigContext, args: &mut Vec<String>) -> Result<Vec<u16>, ConfigError> {
+    let mut statuses = Vec::new();
+    while args.first().is_some_and(|arg| arg == "-s") {
+        let list = args
+            .get(1)
+            .cloned()
+            .ok_or_else(|| ctx.invalid_value("-s", "expected a list of statuses"))?;
+        for status in list.split_whitespace() {
+            let status = status
+                .parse::<u16>()
+                .ok()
+                .filter(|status| (100..=599).contains(status))
+                .ok_or_else(|| ctx.invalid_value(status, "invalid status"))?;
+            statuses.push(status);
+        }
+        args.drain(..2);
+    }
+    Ok(statuses)
+}
+
+fn push_edits(ctx: &mut ConfigContext, statuses: Vec<u16>, edits: Vec<HeaderEdit>) {
+    if edits.is_empty() {
+        return;
+    }
+    if let Ok(mut config) = ctx.block_config::<HeadersConfig>().lock() {
+        config
+            .edits
+            .get_or_insert_with(Vec::new)
+            .push(HeaderEdits { statuses, edits });
+    }
+}
+
+pub fn handle_more_set_headers(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let mut args = ctx.args();
+    let statuses = statuses(ctx, &mut args)?;
+    let mut edits = Vec::new();
+    for arg in &args {
+        let (name, value) = arg
+            .split_once(':')
+            .ok_or_else(|| ctx.invalid_value(arg, "expected \"Name: value\""))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(ctx.invalid_value(arg, "invalid field name"));
+        }
+        edits.push(HeaderEdit::Set(
+            name.to_string(),
+            VarTemplate::parse(value.trim()),
+        ));
+    }
+    push_edits(ctx, statuses, edits);
+    Ok(())
+}
+
+pub fn handle_more_clear_headers(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let mut args = ctx.args();
+    let statuses = statuses(ctx, &mut args)?;
+    let edits = args.into_iter().map(HeaderEdit::Clear).collect();
+    push_edits(ctx, statuses, edits);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use http::{StatusCode, Version};
+
+    use crate::http::http_response::StreamBody;
+
+    use super::*;
+
+    #[test]
+    fn test_filter_edits_and_adds_headers() {
+        let added = |name: &str, value: &str, always| AddedHeader {
+            name: name.to_string(),
+            value: VarTemplate::parse(value),
+            always,
+        };
+        let filter = HeadersFilter {
+            headers: vec![
+                added("X-Served-For", "$request_uri", false),
+                added("X-Status", "$status", true),
+                added("X-Empty", "$http_x_missing", true),
+            ],
+            trailers: vec![added("X-Checksum", "abc", false)],
+            edits: vec![
+                HeaderEdits {
+                    statuses: Vec::new(),
+                    edits: vec![
+                        HeaderEdit::Set("Server".to_string(), VarTemplate::parse("edge")),
+                        HeaderEdit::Clear("X-Debug-*".to_string()),
+                    ],
+                },
+                HeaderEdits {
+                    statuses: vec![404],
+                    edits: vec![HeaderEdit::Set(
+                        "Cache-Control".to_string(),
+                        VarTemplate::parse("no-store"),
+                    )],
+                },
+            ],
+        };
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /a?b HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let respond = |status| {
+            let mut resp = HttpResponse::new();
+            resp.set_status_line(Version::HTTP_11, status);
+            resp.set_header("Server", "blur");
+            resp.set_header("X-Debug-Time", "3ms");
+            resp.stream = Some(StreamBody::new(Cursor::new(b"body".to_vec()), None));
+            filter.filter(&req, &mut resp);
+            resp
+        };
+
+        let resp = respond(StatusCode::OK);
+        assert_eq!(resp.header_value("Server"), Some("edge"));
+        assert_eq!(resp.header_value("X-Debug-Time"), None);
+        assert_eq!(resp.header_value("X-Served-For"), Some("/a?b"));
+        assert_eq!(resp.header_value("X-Status"), Some("200"));
+        assert!(!resp.has_header("X-Empty"));
+        assert!(!resp.has_header("Cache-Control"));
+        assert_eq!(resp.header_value("Trailer"), Some("X-Checksum"));
+        assert!(resp
+            .as_bytes()
+            .ends_with(b"4\r\nbody\r\n0\r\nX-Checksum: abc\r\n\r\n"));
+
+        let resp = respond(StatusCode::NOT_FOUND);
+        assert_eq!(resp.header_value("Cache-Control"), Some("no-store"));
+        assert_eq!(resp.header_value("X-Status"), Some("404"));
+        assert!(!resp.has_header("X-Served-For"));
+        assert!(!resp.has_header("Trailer"));
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    fs,
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::{Method, StatusCode};
+use serde_json::{json, Value};
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::HttpHandler,
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_upstream::{find_upstream, Upstream},
+};
+
+register_commands!(CommandBuilder::new("health_check")
+    .allowed_parents(vec!["location".to_string()])
+    .display_name("en", "Health Check")
+    .display_name("zh-tw", "健康檢查")
+    .desc(
+        "en",
+        "Makes the location answer liveness or readiness probes with a JSON report, 200 when healthy and 503 otherwise"
+    )
+    .desc(
+        "zh-tw",
+        "讓 location 以 JSON 回應存活或就緒檢查，健康時回傳 200，否則回傳 503"
+    )
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Type")
+            .display_name("zh-tw", "類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "liveness, which only reports that the server answers, or readiness, which also runs the probes"
+            )
+            .desc("zh-tw", "liveness 僅表示伺服器可回應；readiness 另會執行探測")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Probes")
+            .display_name("zh-tw", "探測")
+            .type_name("String")
+            .default("")
+            .desc(
+                "en",
+                "upstream=name needs a server of the upstream to accept connections, path=directory needs the directory to be writable, timeout=time bounds each probe (1s by default); upstream and path may be repeated"
+            )
+            .desc(
+                "zh-tw",
+                "upstream=名稱 要求該 upstream 至少一台伺服器可連線，path=目錄 要求該目錄可寫入，timeout=時間 為每項探測的上限（預設 1s）；upstream 與 path 可重複指定"
+            )
+            .build(),
+    ])
+    .arity(Arity::AtLeast(1))
+    .build(handle_health_check));
+
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    Upstream(String),
+    Path(PathBuf),
+}
+
+impl Probe {
+    fn name(&self) -> String {
+        match self {
+            Probe::Upstream(name) => format!("upstream {}", name),
+            Probe::Path(path) => format!("path {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheckSpec {
+    /// Whether the probes run; a liveness check has none.
+    pub readiness: bool,
+    pub probes: Vec<Probe>,
+    pub timeout: Duration,
+}
+
+impl HealthCheckSpec {
+    /// Parses the arguments of `health_check`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (kind, options) = args.split_first().ok_or("missing check type")?;
+        let readiness = match kind.as_str() {
+            "liveness" => false,
+            "readiness" => true,
+            _ => return Err(format!("unknown check type \"{}\"", kind)),
+        };
+        let mut spec = Self {
+            readiness,
+            probes: Vec::new(),
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        };
+        for option in options {
+            match option.split_once('=') {
+                Some(("upstream", name)) if !name.is_empty() => {
+                    spec.probes.push(Probe::Upstream(name.to_string()))
+                }
+                Some(("path", path)) if !path.is_empty() => {
+                    spec.probes.push(Probe::Path(PathBuf::from(path)))
+                }
+                Some(("timeout", value)) => spec.timeout = parse_duration(value)?,
+                _ => return Err(format!("unknown option \"{}\"", option)),
+            }
+        }
+        if !readiness && !spec.probes.is_empty() {
+            return Err("liveness checks cannot have probes".to_string());
+        }
+        Ok(spec)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HealthCheckConfig {
+    pub check: Option<HealthCheckSpec>,
+}
+
+impl MergeConfig for HealthCheckConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// A probe with what it checks resolved.
+enum Target {
+    Upstream(Option<Arc<Upstream>>),
+    Path(PathBuf),
+}
+
+struct HealthCheck {
+    probes: Vec<(String, Target)>,
+    timeout: Duration,
+}
+
+impl HealthCheck {
+    /// Runs every probe, returning the report and whether all passed.
+    fn run(&self) -> (Value, bool) {
+        let mut healthy = true;
+        let checks: Vec<Value> = self
+            .probes
+            .iter()
+            .map(|(name, target)| {
+                let started = Instant::now();
+                let result = match target {
+                    Target::Upstream(upstream) => probe_upstream(upstream.as_deref(), self.timeout),
+                    Target::Path(path) => probe_path(path),
+                };
+                let mut check = json!({
+                    "name": name,
+                    "status": if result.is_ok() { "ok" } else { "fail" },
+                    "time": (started.elapsed().as_secs_f64() * 1000.0).round() / 1000.0,
+                });
+                if let Err(e) = result {
+                    healthy = false;
+                    check["error"] = json!(e);
+                }
+                check
+            })
+            .collect();
+        let mut report = json!({ "status": if healthy { "ok" } else { "fail" } });
+        if !checks.is_empty() {
+            report["checks"] = json!(checks);
+        }
+        (report, healthy)
+    }
+}
+
+/// Passes when any server of the upstream accepts a connection in time.
+fn probe_upstream(upstream: Option<&Upstream>, timeout: Duration) -> Result<(), String> {
+    let upstream = upstream.ok_or("no such upstream")?;
+    let mut last_error = "no servers".to_string();
+    for server in upstream.servers() {
+        let address = server.address();
+        let connected = address
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())
+            .and_then(|mut addrs| addrs.next().ok_or("no addresses".to_string()))
+            .and_then(|addr| TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string()));
+        match connected {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = format!("{}: {}", address, e),
+        }
+    }
+    Err(last_error)
+}
+
+/// Passes when a file can be created in the directory.
+fn probe_path(path: &Path) -> Result<(), String> {
+    if !path.is_dir() {
+        return Err("not a directory".to_string());
+    }
+    let file = path.join(format!(".blur-health-{}", std::process::id()));
+    fs::write(&file, b"").map_err(|e| e.to_string())?;
+    let _ = fs::remove_file(&file);
+    Ok(())
+}
+
+/// Builds the handler for a location block that sets `health_check`.
+pub fn health_handler(chain: &[&ConfigContext]) -> Option<HttpHandler> {
+    let block = chain.last()?;
+    let spec = merged_config::<HealthCheckConfig>(&[block]).check?;
+    let probes = spec
+        .probes
+        .iter()
+        .map(|probe| {
+            let target = match probe {
+                Probe::Upstream(name) => {
+                    let upstream = chain
+                        .first()
+                        .and_then(|http_config| find_upstream(http_config, name));
+                    if upstream.is_none() {
+                        log_error!("health_check: upstream \"{}\" is not defined", name);
+                    }
+                    Target::Upstream(upstream)
+                }
+                Probe::Path(path) => Target::Path(path.clone()),
+            };
+            (probe.name(), target)
+        })
+        .collect();
+    let check = HealthCheck {
+        probes,
+        timeout: spec.timeout,
+    };
+    Some(Box::new(move |req: &HttpRequest| {
+        let mut resp = HttpResponse::new();
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            resp.set_status_line(*req.version(), StatusCode::METHOD_NOT_ALLOWED);
+            resp.set_header("Allow", "GET, HEAD");
+            resp.set_header("Content-Length", "0");
+            return resp;
+        }
+        let (report, healthy) = check.run();
+        let status = if healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = format!("{}\n", report);
+        resp.set_status_line(*req.version(), status);
+        resp.set_header("Content-Type", "application/json");
+        resp.set_header("Cache-Control", "no-store");
+        if *req.method() == Method::HEAD {
+            resp.set_header("Content-Length", &body.len().to_string());
+        } else {
+            resp.set_body(&body);
+        }
+        resp
+    }))
+}
+
+pub fn handle_health_check(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let spec = HealthCheckSpec::parse(&args)
+        .map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    if let Ok(mut config) = ctx.block_config::<HealthCheckConfig>().lock() {
+        config.check = Some(spec);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_readiness_fails_when_a_probe_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("blur-health-missing-{}", std::process::id()));
+        let check = HealthCheck {
+            probes: vec![
+                (
+                    "upstream backend".to_string(),
+                    Target::Upstream(Some(Arc::new(Upstream::single("127.0.0.1", port)))),
+                ),
+                ("path tmp".to_string(), Target::Path(dir)),
+            ],
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        };
+        let (report, healthy) = check.run();
+        assert!(healthy, "{}", report);
+        assert_eq!(report["checks"][0]["status"], "ok");
+
+        let check = HealthCheck {
+            probes: vec![
+                ("upstream gone".to_string(), Target::Upstream(None)),
+                ("path cache".to_string(), Target::Path(missing)),
+            ],
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        };
+        let (report, healthy) = check.run();
+        assert!(!healthy);
+        assert_eq!(report["status"], "fail");
+        assert_eq!(report["checks"][0]["error"], "no such upstream");
+        assert_eq!(report["checks"][1]["error"], "not a directory");
+
+        let args = ["liveness".to_string(), "path=/tmp".to_string()];
+        assert!(HealthCheckSpec::parse(&args).is_err());
+    }
+}
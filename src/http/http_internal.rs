@@ -0,0 +1,134 @@
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(CommandBuilder::new("internal")
+    .allowed_parents(vec!["location".to_string()])
+    .display_name("en", "Internal")
+    .display_name("zh-tw", "僅限內部")
+    .desc(
+        "en",
+        "Sets whether the location only serves internal redirects, such as error pages and X-Accel-Redirect"
+    )
+    .desc(
+        "zh-tw",
+        "設定 location 是否只處理內部重新導向，例如錯誤頁面與 X-Accel-Redirect"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Enabled")
+        .display_name("zh-tw", "啟用")
+        .arg_type(ArgType::Bool)
+        .is_required(true)
+        .default("")
+        .desc("en", "on, or off (the default)")
+        .desc("zh-tw", "on，或 off（預設）")
+        .build()])
+    .build(handle_internal));
+
+#[derive(Debug, Default, Clone)]
+pub struct InternalConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for InternalConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Answers 404 to requests that reach an `internal` location directly from a
+/// client.
+pub struct InternalPhase;
+
+impl RequestPhase for InternalPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        if req.is_internal() {
+            return PhaseResult::Continue;
+        }
+        PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+            req.version(),
+            StatusCode::NOT_FOUND,
+        )))
+    }
+}
+
+/// Builds the phase for a location block that sets `internal on`.
+pub fn internal_phase(block: &ConfigContext) -> Option<InternalPhase> {
+    merged_config::<InternalConfig>(&[block])
+        .enabled
+        .unwrap_or(false)
+        .then_some(InternalPhase)
+}
+
+pub fn handle_internal(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<InternalConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{core::processor::LocationPattern, http::http_response::HttpResponse};
+
+    use super::*;
+
+    #[test]
+    fn test_internal_locations_follow_accel_redirects() {
+        let mut processor = HttpProcessor::new();
+        processor.add_location(
+            LocationPattern::parse(&["/app".to_string()]).unwrap(),
+            Box::new(|_: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(http::Version::HTTP_11, StatusCode::OK);
+                resp.set_header("Content-Type", "application/pdf");
+                resp.set_header("Content-Disposition", "attachment");
+                resp.accel_redirect = Some("/protected/report.pdf?v=2".to_string());
+                resp
+            }),
+        );
+        processor.add_location_with_phases(
+            LocationPattern::parse(&["/protected".to_string()]).unwrap(),
+            Some(Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!("{} {}", req.method(), req.path()));
+                resp
+            })),
+            vec![Arc::new(InternalPhase)],
+            Arc::default(),
+            Vec::new(),
+        );
+
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /protected/report.pdf HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        assert_eq!(processor.handle(&mut req).status(), Some(404));
+
+        let mut req = HttpRequest::new();
+        req.parse(b"POST /app/download HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let resp = processor.handle(&mut req);
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.body, b"GET /protected/report.pdf?v=2");
+        assert_eq!(resp.header_value("Content-Type"), Some("application/pdf"));
+        assert_eq!(resp.header_value("Content-Disposition"), Some("attachment"));
+    }
+}
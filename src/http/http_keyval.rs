@@ -0,0 +1,491 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use http::{Method, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::{HttpHandler, LocationPattern},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_api::{api_handler, body_object, ApiError, ApiResult},
+    http_request::HttpRequest,
+    http_variables::{
+        parse_variable_name, RequestVariables, VarTemplate, VariableDefinition, VariableSource,
+    },
+};
+
+register_commands!(
+    CommandBuilder::new("keyval_zone")
+        .is_raw_block()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Key-Value Zone")
+        .display_name("zh-tw", "鍵值區域")
+        .desc(
+            "en",
+            "Defines a named key-value map shared by all connections, read through variables and changed at runtime with keyval_api"
+        )
+        .desc(
+            "zh-tw",
+            "定義所有連線共用的具名鍵值對應，透過變數讀取，並可在執行期間以 keyval_api 修改"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Name")
+            .display_name("zh-tw", "名稱")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Zone name; entries are keyval key $variable, which sets the variable to the value stored under the key (a template that may contain variables), state file, which keeps the entries across restarts, and timeout time, after which entries not set again expire"
+            )
+            .desc(
+                "zh-tw",
+                "區域名稱；項目為 keyval 鍵 $變數，將變數設為該鍵（可包含變數的樣板）儲存的值，state 檔案，在重新啟動後保留內容，以及 timeout 時間，超過此時間未再設定的項目會過期"
+            )
+            .build()])
+        .build(handle_keyval_zone),
+    CommandBuilder::new("keyval_api")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "Key-Value API")
+        .display_name("zh-tw", "鍵值 API")
+        .desc(
+            "en",
+            "Sets whether the location serves a JSON API to read and change the keyval_zone entries"
+        )
+        .desc("zh-tw", "設定 location 是否提供讀取與修改 keyval_zone 內容的 JSON API")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_keyval_api)
+);
+
+/// Keys to set, or to remove when the value is `None`.
+pub type Changes = Vec<(String, Option<String>)>;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: String,
+    /// When the entry was last set, for `timeout`.
+    updated: Instant,
+}
+
+/// A named key-value map. It lives in the server process, so every
+/// connection sees a change as soon as it is made.
+#[derive(Debug)]
+pub struct KeyvalZone {
+    pub name: String,
+    entries: RwLock<HashMap<String, Entry>>,
+    timeout: Option<Duration>,
+    /// Where the entries are saved after every change, set with `state`.
+    state_file: Option<PathBuf>,
+}
+
+impl KeyvalZone {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            entries: RwLock::new(HashMap::new()),
+            timeout: None,
+            state_file: None,
+        }
+    }
+
+    /// Expires entries not set again within `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Saves the entries to `path` whenever they change.
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    fn is_live(&self, entry: &Entry) -> bool {
+        self.timeout
+            .is_none_or(|timeout| entry.updated.elapsed() < timeout)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.read().ok()?;
+        entries
+            .get(key)
+            .filter(|entry| self.is_live(entry))
+            .map(|entry| entry.value.clone())
+    }
+
+    /// The entries that have not expired, sorted by key.
+    pub fn entries(&self) -> BTreeMap<String, String> {
+        let Ok(entries) = self.entries.read() else {
+            return BTreeMap::new();
+        };
+        entries
+            .iter()
+            .filter(|(_, entry)| self.is_live(entry))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    /// Sets every key in `changes`; a `None` value removes the key.
+    pub fn update(&self, changes: Changes) -> io::Result<()> {
+        {
+            let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+            entries.retain(|_, entry| self.is_live(entry));
+            let now = Instant::now();
+            for (key, value) in changes {
+                match value {
+                    Some(value) => {
+                        entries.insert(
+                            key,
+                            Entry {
+                                value,
+                                updated: now,
+                            },
+                        );
+                    }
+                    None => {
+                        entries.remove(&key);
+                    }
+                }
+            }
+        }
+        self.save()
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> io::Result<()> {
+        self.update(vec![(key.to_string(), Some(value.to_string()))])
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        self.update(vec![(key.to_string(), None)])
+    }
+
+    pub fn clear(&self) -> io::Result<()> {
+        self.entries
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.save()
+    }
+
+    /// Writes the entries to the state file as a JSON object.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, json!(self.entries()).to_string())?;
+        fs::rename(&temp, path)
+    }
+
+    /// Reads the entries saved to the state file, if it exists.
+    fn load(&self, path: &Path) -> Result<(), String> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let saved: BTreeMap<String, String> =
+            serde_json::from_str(&content).map_err(|e| e.to_string())?;
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        for (key, value) in saved {
+            entries.insert(
+                key,
+                Entry {
+                    value,
+                    updated: now,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The value stored in a zone under the rendered key, or empty.
+struct KeyvalLookup {
+    zone: Arc<KeyvalZone>,
+    key: VarTemplate,
+}
+
+impl VariableSource for KeyvalLookup {
+    fn evaluate(&self, vars: &RequestVariables) -> Option<String> {
+        Some(self.zone.get(&self.key.render(vars)).unwrap_or_default())
+    }
+}
+
+/// Every `keyval_zone` declared in the http block.
+pub fn keyval_zones(http_config: &ConfigContext) -> Vec<Arc<KeyvalZone>> {
+    http_config
+        .children
+        .iter()
+        .filter_map(|child| child.store.get::<KeyvalZone>())
+        .collect()
+}
+
+pub fn handle_keyval_zone(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let name = ctx.str_arg(0)?;
+    let mut zone = KeyvalZone::new(&name);
+    let mut lookups = Vec::new();
+    for entry in &ctx.raw_entries {
+        let invalid = |reason: String| ctx.invalid_entry(entry, &entry.args.join(" "), reason);
+        match entry.args.as_slice() {
+            [directive, path] if directive == "state" => {
+                zone = zone.with_state_file(PathBuf::from(path));
+            }
+            [directive, timeout] if directive == "timeout" => {
+                zone = zone.with_timeout(parse_duration(timeout).map_err(invalid)?);
+            }
+            [directive, key, variable] if directive == "keyval" => {
+                let variable = parse_variable_name(variable)
+                    .ok_or_else(|| invalid("variable name must start with \"$\"".to_string()))?;
+                lookups.push((VarTemplate::parse(key), variable.to_string()));
+            }
+            _ => {
+                return Err(invalid(
+                    "expected keyval key $variable, state file or timeout time".to_string(),
+                ))
+            }
+        }
+    }
+    if let Some(path) = zone.state_file.clone() {
+        zone.load(&path)
+            .map_err(|reason| ctx.invalid_value(&path.display().to_string(), reason))?;
+    }
+
+    let zone = Arc::new(zone);
+    let definitions: Vec<VariableDefinition> = lookups
+        .into_iter()
+        .map(|(key, name)| VariableDefinition {
+            name,
+            source: Arc::new(KeyvalLookup {
+                zone: zone.clone(),
+                key,
+            }),
+        })
+        .collect();
+    ctx.store.insert(Arc::new(definitions));
+    ctx.store.insert(zone);
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct KeyvalApiConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for KeyvalApiConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Answers `/`, `/{zone}` and `/{zone}/{key}` below the location.
+struct KeyvalApi {
+    zones: Vec<Arc<KeyvalZone>>,
+}
+
+impl KeyvalApi {
+    fn handle(&self, req: &HttpRequest, segments: &[&str]) -> ApiResult {
+        let method = req.method();
+        let saved = |result: io::Result<()>| {
+            result.map_err(|e| {
+                log_error!("cannot save keyval state: {}", e);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            })
+        };
+        match *segments {
+            [] if method == Method::GET => {
+                let all: Map<String, Value> = self
+                    .zones
+                    .iter()
+                    .map(|zone| (zone.name.clone(), json!(zone.entries())))
+                    .collect();
+                Ok((StatusCode::OK, Some(Value::Object(all))))
+            }
+            [name] => {
+                let zone = self.zone(name)?;
+                match *method {
+                    Method::GET => Ok((StatusCode::OK, Some(json!(zone.entries())))),
+                    Method::POST | Method::PATCH => {
+                        let changes = body_changes(req, *method == Method::PATCH)?;
+                        if *method == Method::POST {
+                            let existing = zone.entries();
+                            if let Some((key, _)) =
+                                changes.iter().find(|(key, _)| existing.contains_key(key))
+                            {
+                                return Err(ApiError::new(
+                                    StatusCode::CONFLICT,
+                                    format!("key \"{}\" already exists", key),
+                                ));
+                            }
+                        }
+                        saved(zone.update(changes))?;
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    Method::DELETE => {
+                        saved(zone.clear())?;
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    _ => Err(ApiError::method("GET, POST, PATCH, DELETE")),
+                }
+            }
+            [name, key] => {
+                let zone = self.zone(name)?;
+                let missing = || ApiError::not_found(format!("no key \"{}\"", key));
+                match *method {
+                    Method::GET => {
+                        let value = zone.get(key).ok_or_else(missing)?;
+                        Ok((StatusCode::OK, Some(json!({ key: value }))))
+                    }
+                    Method::DELETE => {
+                        zone.get(key).ok_or_else(missing)?;
+                        saved(zone.remove(key))?;
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    _ => Err(ApiError::method("GET, DELETE")),
+                }
+            }
+            [] => Err(ApiError::method("GET")),
+            _ => Err(ApiError::not_found("no such resource")),
+        }
+    }
+
+    fn zone(&self, name: &str) -> Result<&Arc<KeyvalZone>, ApiError> {
+        self.zones
+            .iter()
+            .find(|zone| zone.name == name)
+            .ok_or_else(|| ApiError::not_found(format!("no keyval_zone named \"{}\"", name)))
+    }
+}
+
+/// Reads a JSON object of string values; with `nulls`, a null value
+/// removes the key.
+fn body_changes(req: &HttpRequest, nulls: bool) -> Result<Changes, ApiError> {
+    body_object(req)?
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, Some(value))),
+            Value::Null if nulls => Ok((key, None)),
+            _ => Err(ApiError::invalid(format!(
+                "value of \"{}\" must be a string",
+                key
+            ))),
+        })
+        .collect()
+}
+
+/// Builds the handler for a location block that sets `keyval_api on`.
+pub fn keyval_api_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let block = chain.last()?;
+    if !merged_config::<KeyvalApiConfig>(&[block])
+        .enabled
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let api = KeyvalApi {
+        zones: chain
+            .first()
+            .map(|http| keyval_zones(http))
+            .unwrap_or_default(),
+    };
+    Some(api_handler(pattern, move |req, segments| {
+        api.handle(req, segments)
+    }))
+}
+
+pub fn handle_keyval_api(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<KeyvalApiConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::http_api::route_segments;
+
+    fn request(method: &str, uri: &str, body: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: a.example\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                uri,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        req
+    }
+
+    #[test]
+    fn test_zone_changes_are_seen_by_variables() {
+        let path = std::env::temp_dir().join(format!("blur-keyval-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let zone = Arc::new(KeyvalZone::new("tenants").with_state_file(path.clone()));
+        let lookup = KeyvalLookup {
+            zone: zone.clone(),
+            key: VarTemplate::parse("$host"),
+        };
+        let api = KeyvalApi {
+            zones: vec![zone.clone()],
+        };
+        let handle = |req: &HttpRequest| api.handle(req, &route_segments(req, "/kv"));
+        let get = request("GET", "/", "");
+        assert_eq!(lookup.evaluate(&RequestVariables::new(&get)).unwrap(), "");
+
+        let post = request("POST", "/kv/tenants", r#"{"a.example":"blue"}"#);
+        assert_eq!(handle(&post).unwrap().0, StatusCode::NO_CONTENT);
+        assert_eq!(handle(&post).unwrap_err().status, StatusCode::CONFLICT);
+        assert_eq!(
+            lookup.evaluate(&RequestVariables::new(&get)).unwrap(),
+            "blue"
+        );
+
+        let patch = request("PATCH", "/kv/tenants", r#"{"a.example":null,"b":"green"}"#);
+        assert_eq!(handle(&patch).unwrap().0, StatusCode::NO_CONTENT);
+        assert_eq!(zone.get("a.example"), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), r#"{"b":"green"}"#);
+
+        let reloaded = KeyvalZone::new("tenants");
+        reloaded.load(&path).unwrap();
+        assert_eq!(reloaded.get("b").as_deref(), Some("green"));
+        let _ = fs::remove_file(&path);
+
+        let expiring = KeyvalZone::new("short").with_timeout(Duration::ZERO);
+        expiring.set("k", "v").unwrap();
+        assert_eq!(expiring.get("k"), None);
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, ConfigPosition, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_size,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_error, log_warn, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("limit_conn_zone")
+        .is_repeatable()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Connection Limit Zone")
+        .display_name("zh-tw", "連線數限制區域")
+        .desc(
+            "en",
+            "Defines a zone that counts the open connections of each key, for limit_conn"
+        )
+        .desc("zh-tw", "定義記錄每個鍵開啟中連線數的區域，供 limit_conn 使用")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Key")
+                .display_name("zh-tw", "鍵")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "Value with variables connections are counted by, such as $binary_remote_addr; requests with an empty key are not limited"
+                )
+                .desc(
+                    "zh-tw",
+                    "用來區分連線的值，可包含變數，例如 $binary_remote_addr；鍵為空的請求不受限制"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "zone=name:size, where each key takes about 64 bytes of the size"
+                )
+                .desc("zh-tw", "zone=名稱:大小，每個鍵約佔用 64 位元組")
+                .build(),
+        ])
+        .arity(Arity::Exact(2))
+        .on_block_end(resolve_limit_conn_zones)
+        .build(handle_limit_conn_zone),
+    CommandBuilder::new("limit_conn")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Connection Limit")
+        .display_name("zh-tw", "連線數限制")
+        .desc(
+            "en",
+            "Limits how many connections with the same key may use a block at once"
+        )
+        .desc("zh-tw", "限制同一個鍵同時使用此區塊的連線數")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Name of a limit_conn_zone")
+                .desc("zh-tw", "limit_conn_zone 的名稱")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Connections")
+                .display_name("zh-tw", "連線數")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Connections allowed per key")
+                .desc("zh-tw", "每個鍵允許的連線數")
+                .build(),
+        ])
+        .arity(Arity::Exact(2))
+        .build(handle_limit_conn),
+    CommandBuilder::new("limit_conn_status")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Connection Limit Status")
+        .display_name("zh-tw", "連線數限制狀態碼")
+        .desc("en", "Status code of requests over the connection limit")
+        .desc("zh-tw", "超過連線數限制的請求之回應狀態碼")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Status")
+            .display_name("zh-tw", "狀態碼")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "A status from 400 to 599, 503 by default")
+            .desc("zh-tw", "400 到 599 的狀態碼，預設 503")
+            .build()])
+        .build(handle_limit_conn_status)
+);
+
+pub const DEFAULT_STATUS: StatusCode = StatusCode::SERVICE_UNAVAILABLE;
+/// Rough memory a counted key takes, to turn a zone size into a key count.
+const BYTES_PER_KEY: u64 = 64;
+
+/// The open connections of each key.
+pub struct LimitConnZone {
+    pub name: String,
+    key: VarTemplate,
+    capacity: usize,
+    counts: Mutex<HashMap<String, u32>>,
+}
+
+impl std::fmt::Debug for LimitConnZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LimitConnZone")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl LimitConnZone {
+    pub fn new(name: &str, key: VarTemplate, capacity: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            key,
+            capacity: capacity.max(1),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one of the `max` slots of `key`, or `None` when all are taken
+    /// or the zone has no room for another key.
+    pub fn acquire(self: &Arc<Self>, key: &str, max: u32) -> Option<ConnSlot> {
+        let mut counts = self.counts.lock().ok()?;
+        if !counts.contains_key(key) && counts.len() >= self.capacity {
+            log_error!("limit_conn zone \"{}\" is full", self.name);
+            return None;
+        }
+        let count = counts.entry(key.to_string()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnSlot {
+            zone: Arc::clone(self),
+            key: key.to_string(),
+        })
+    }
+
+    pub fn count(&self, key: &str) -> u32 {
+        self.counts
+            .lock()
+            .ok()
+            .and_then(|counts| counts.get(key).copied())
+            .unwrap_or(0)
+    }
+}
+
+/// A connection counted against a key; the count drops with it.
+pub struct ConnSlot {
+    zone: Arc<LimitConnZone>,
+    key: String,
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.zone.counts.lock() {
+            if let Some(count) = counts.get_mut(&self.key) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&self.key);
+                }
+            }
+        }
+    }
+}
+
+/// The slots a client connection holds, released when it closes.
+#[derive(Default)]
+pub struct ConnectionSlots(Mutex<Vec<ConnSlot>>);
+
+impl ConnectionSlots {
+    fn holds(&self, zone: &Arc<LimitConnZone>, key: &str) -> bool {
+        self.0.lock().is_ok_and(|slots| {
+            slots
+                .iter()
+                .any(|slot| Arc::ptr_eq(&slot.zone, zone) && slot.key == key)
+        })
+    }
+
+    fn push(&self, slot: ConnSlot) {
+        if let Ok(mut slots) = self.0.lock() {
+            slots.push(slot);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LimitConnZones {
+    pub zones: Vec<Arc<LimitConnZone>>,
+}
+
+impl MergeConfig for LimitConnZones {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// A `limit_conn` directive; its zone is resolved once the http block is
+/// read, as zones may be defined after the limits using them.
+#[derive(Debug, Clone)]
+pub struct ConnLimit {
+    pub zone_name: String,
+    pub max: u32,
+    pub zone: Option<Arc<LimitConnZone>>,
+    pos: Option<ConfigPosition>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LimitConnConfig {
+    pub limits: Option<Vec<ConnLimit>>,
+    pub status: Option<StatusCode>,
+}
+
+impl MergeConfig for LimitConnConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.limits.is_none() {
+            self.limits = parent.limits.clone();
+        }
+        self.status = self.status.or(parent.status);
+    }
+}
+
+/// Rejects requests whose connection would go over the limit of a zone.
+/// A connection keeps its slot until it closes, however many requests it
+/// sends; subrequests and requests without a connection are not counted.
+pub struct LimitConnPhase {
+    limits: Vec<(Arc<LimitConnZone>, u32)>,
+    status: StatusCode,
+}
+
+impl RequestPhase for LimitConnPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        if req.is_subrequest() {
+            return PhaseResult::Continue;
+        }
+        let Some(slots) = req.extensions().get::<Arc<ConnectionSlots>>().cloned() else {
+            return PhaseResult::Continue;
+        };
+        let vars = RequestVariables::new(req);
+        for (zone, max) in &self.limits {
+            let key = zone.key.render(&vars);
+            if key.is_empty() || slots.holds(zone, &key) {
+                continue;
+            }
+            match zone.acquire(&key, *max) {
+                Some(slot) => slots.push(slot),
+                None => {
+                    log_warn!(
+                        "limiting connections by zone \"{}\", client: {}, request: \"{}\"",
+                        zone.name,
+                        req.remote_addr()
+                            .map(|addr| addr.ip().to_string())
+                            .unwrap_or_default(),
+                        req.path()
+                    );
+                    return PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+                        req.version(),
+                        self.status,
+                    )));
+                }
+            }
+        }
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the connection limiting phase in effect for a block chain, if any.
+pub fn limit_conn_phase(chain: &[&ConfigContext]) -> Option<LimitConnPhase> {
+    let config = merged_config::<LimitConnConfig>(chain);
+    let limits: Vec<(Arc<LimitConnZone>, u32)> = config
+        .limits?
+        .into_iter()
+        .filter_map(|limit| Some((limit.zone?, limit.max)))
+        .collect();
+    (!limits.is_empty()).then(|| LimitConnPhase {
+        limits,
+        status: config.status.unwrap_or(DEFAULT_STATUS),
+    })
+}
+
+/// Points the `limit_conn` directives of the http block `ctx` and the blocks
+/// in it at their zones, failing on a zone that is not defined.
+pub fn resolve_limit_conn_zones(ctx: &ConfigContext) -> Result<(), ConfigError> {
+    let zones = merged_config::<LimitConnZones>(&[ctx]).zones;
+    resolve_limits(ctx, &zones)
+}
+
+fn resolve_limits(ctx: &ConfigContext, zones: &[Arc<LimitConnZone>]) -> Result<(), ConfigError> {
+    if let Some(config) = ctx.store.get::<Mutex<LimitConnConfig>>() {
+        if let Ok(mut config) = config.lock() {
+            for limit in config.limits.iter_mut().flatten() {
+                let zone = zones.iter().find(|zone| zone.name == limit.zone_name);
+                limit.zone = Some(Arc::clone(zone.ok_or_else(|| {
+                    ConfigError::InvalidValue {
+                        name: "limit_conn".to_string(),
+                        value: limit.zone_name.clone(),
+                        reason: "zone is not defined".to_string(),
+                        pos: limit.pos.clone(),
+                    }
+                })?));
+            }
+        }
+    }
+    ctx.children
+        .iter()
+        .try_for_each(|child| resolve_limits(child, zones))
+}
+
+pub fn handle_limit_conn_zone(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let [key, zone] = args.as_slice() else {
+        return Ok(());
+    };
+    let (name, size) = zone
+        .strip_prefix("zone=")
+        .and_then(|zone| zone.split_once(':'))
+        .ok_or_else(|| ctx.invalid_value(zone, "expected zone=name:size"))?;
+    let size = parse_size(size).map_err(|reason| ctx.invalid_value(zone, reason))?;
+    let zone = LimitConnZone::new(
+        name,
+        VarTemplate::parse(key),
+        (size / BYTES_PER_KEY) as usize,
+    );
+    if let Ok(mut config) = ctx.block_config::<LimitConnZones>().lock() {
+        if config.zones.iter().any(|zone| zone.name == name) {
+            return Err(ctx.invalid_value(name, "zone is already defined"));
+        }
+        config.zones.push(Arc::new(zone));
+    }
+    Ok(())
+}
+
+pub fn handle_limit_conn(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let [zone, max] = args.as_slice() else {
+        return Ok(());
+    };
+    let max = max
+        .parse::<u32>()
+        .ok()
+        .filter(|max| *max > 0)
+        .ok_or_else(|| ctx.invalid_value(max, "expected a positive number"))?;
+    let limit = ConnLimit {
+        zone_name: zone.clone(),
+        max,
+        zone: None,
+        pos: ctx.current_cmd_pos.clone(),
+    };
+    if let Ok(mut config) = ctx.block_config::<LimitConnConfig>().lock() {
+        config.limits.get_or_insert_with(Vec::new).push(limit);
+    }
+    Ok(())
+}
+
+pub fn handle_limit_conn_status(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let status = ctx.number_arg(0)?;
+    let status = u16::try_from(status)
+        .ok()
+        .filter(|status| (400..=599).contains(status))
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| ctx.invalid_value(&status.to_string(), "must be between 400 and 599"))?;
+    if let Ok(mut config) = ctx.block_config::<LimitConnConfig>().lock() {
+        config.status = Some(status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_released_with_connection() {
+        let zone = Arc::new(LimitConnZone::new(
+            "addr",
+            VarTemplate::parse("$remote_addr"),
+            1,
+        ));
+        let phase = LimitConnPhase {
+            limits: vec![(Arc::clone(&zone), 1)],
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let request = |slots: &Arc<ConnectionSlots>| {
+            let mut req = HttpRequest::new();
+            req.parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+            req.set_connection(Some("10.0.0.1:4000".parse().unwrap()), None, false);
+            req.extensions_mut().insert(Arc::clone(slots));
+            req
+        };
+
+        let first = Arc::new(ConnectionSlots::default());
+        assert!(matches!(
+            phase.run(&mut request(&first)),
+            PhaseResult::Continue
+        ));
+        // Later requests on the same connection reuse its slot.
+        assert!(matches!(
+            phase.run(&mut request(&first)),
+            PhaseResult::Continue
+        ));
+        assert_eq!(zone.count("10.0.0.1"), 1);
+
+        let second = Arc::new(ConnectionSlots::default());
+        assert!(matches!(
+            phase.run(&mut request(&second)),
+            PhaseResult::Respond(_)
+        ));
+        // The zone holds one key, so another client is turned away too.
+        assert!(zone.acquire("10.0.0.2", 1).is_none());
+
+        drop(first);
+        assert_eq!(zone.count("10.0.0.1"), 0);
+        assert!(matches!(
+            phase.run(&mut request(&second)),
+            PhaseResult::Continue
+        ));
+    }
+
+    #[test]
+    fn test_zones_are_resolved_once_http_block_is_read() {
+        let mut location = ConfigContext::new_empty("location", vec!["/".to_string()]);
+        location.current_cmd_args = vec!["addr".to_string(), "2".to_string()];
+        handle_limit_conn(&mut location, &Value::Null).unwrap();
+        let mut server = ConfigContext::new_empty("server", vec![]);
+        server.children.push(location);
+        let mut http = ConfigContext::new_empty("http", vec![]);
+        http.children.push(server);
+
+        // Zones may be defined after the limits using them.
+        let err = resolve_limit_conn_zones(&http).unwrap_err();
+        assert!(err.to_string().contains("zone is not defined"));
+        http.current_cmd_args = vec!["$remote_addr".to_string(), "zone=addr:1m".to_string()];
+        handle_limit_conn_zone(&mut http, &Value::Null).unwrap();
+        resolve_limit_conn_zones(&http).unwrap();
+
+        let location = &http.children[0].children[0];
+        let phase = limit_conn_phase(&[&http, &http.children[0], location]).unwrap();
+        assert_eq!(phase.limits[0].0.name, "addr");
+        assert_eq!(phase.limits[0].1, 2);
+        assert_eq!(phase.status, DEFAULT_STATUS);
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    io::{self, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::ResponseFilter,
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(
+    CommandBuilder::new("limit_rate")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Response Rate Limit")
+        .display_name("zh-tw", "回應傳輸速率限制")
+        .desc(
+            "en",
+            "Limits the speed each response is sent to the client at"
+        )
+        .desc("zh-tw", "限制每個回應傳送給客戶端的速度")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Rate")
+            .display_name("zh-tw", "速率")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Bytes per second, e.g. 500k; 0 (the default) sends at full speed"
+            )
+            .desc("zh-tw", "每秒位元組數，例如 500k；預設 0 表示不限制")
+            .build()])
+        .build(handle_limit_rate),
+    CommandBuilder::new("limit_rate_after")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Response Rate Limit After")
+        .display_name("zh-tw", "開始限速的傳輸量")
+        .desc(
+            "en",
+            "Sends the first part of each response at full speed before limit_rate applies"
+        )
+        .desc(
+            "zh-tw",
+            "每個回應先以全速傳送前段內容，之後才套用 limit_rate"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc("en", "Bytes sent unlimited, e.g. 1m; 0 by default")
+            .desc("zh-tw", "不限速傳送的位元組數，例如 1m；預設 0")
+            .build()])
+        .build(handle_limit_rate_after)
+);
+
+#[derive(Debug, Default, Clone)]
+pub struct LimitRateConfig {
+    pub rate: Option<u64>,
+    pub after: Option<u64>,
+}
+
+impl MergeConfig for LimitRateConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.rate = self.rate.or(parent.rate);
+        self.after = self.after.or(parent.after);
+    }
+}
+
+/// How fast a response is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitRate {
+    /// Bytes per second.
+    pub rate: u64,
+    /// Bytes sent at full speed first.
+    pub after: u64,
+}
+
+/// Paces the bytes written through it to a [`LimitRate`].
+pub struct Throttled<'a, W> {
+    inner: &'a mut W,
+    limit: LimitRate,
+    sent: u64,
+    /// When the bytes past `after` started going out.
+    throttled_since: Option<Instant>,
+}
+
+impl<'a, W: Write> Throttled<'a, W> {
+    pub fn new(inner: &'a mut W, limit: LimitRate) -> Self {
+        Self {
+            inner,
+            limit,
+            sent: 0,
+            throttled_since: None,
+        }
+    }
+}
+
+impl<W: Write> Write for Throttled<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = if self.sent < self.limit.after {
+            buf.len().min((self.limit.after - self.sent) as usize)
+        } else {
+            let since = *self.throttled_since.get_or_insert_with(Instant::now);
+            let due = Duration::from_secs_f64(
+                (self.sent - self.limit.after) as f64 / self.limit.rate as f64,
+            );
+            if let Some(wait) = due.checked_sub(since.elapsed()) {
+                thread::sleep(wait);
+            }
+            // A tenth of a second's worth at a time keeps the pace smooth.
+            buf.len().min((self.limit.rate / 10).max(1) as usize)
+        };
+        let n = self.inner.write(&buf[..len])?;
+        self.sent += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sets the rate the responses of a block are sent at.
+pub struct LimitRateFilter {
+    limit: LimitRate,
+}
+
+impl ResponseFilter for LimitRateFilter {
+    fn filter(&self, _req: &HttpRequest, resp: &mut HttpResponse) {
+        resp.limit_rate = Some(self.limit);
+    }
+}
+
+/// Builds the rate limiting filter for a block chain with a `limit_rate`.
+pub fn limit_rate_filter(chain: &[&ConfigContext]) -> Option<LimitRateFilter> {
+    let config = merged_config::<LimitRateConfig>(chain);
+    let rate = config.rate.filter(|rate| *rate > 0)?;
+    Some(LimitRateFilter {
+        limit: LimitRate {
+            rate,
+            after: config.after.unwrap_or(0),
+        },
+    })
+}
+
+pub fn handle_limit_rate(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let rate = ctx.size_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<LimitRateConfig>().lock() {
+        config.rate = Some(rate);
+    }
+    Ok(())
+}
+
+pub fn handle_limit_rate_after(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let after = ctx.size_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<LimitRateConfig>().lock() {
+        config.after = Some(after);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttled_paces_after_allowance() {
+        let mut out = Vec::new();
+        let limit = LimitRate {
+            rate: 10_000,
+            after: 1_000,
+        };
+        let started = Instant::now();
+        Throttled::new(&mut out, limit)
+            .write_all(&[b'x'; 4_000])
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(out.len(), 4_000);
+        // The 3000 bytes past the allowance go out in 1000 byte steps, the
+        // last one due 0.2s after the first.
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_size,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase, ResponseFilter},
+    },
+    log_error, log_warn, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("limit_req_zone")
+        .is_repeatable()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Request Rate Limit Zone")
+        .display_name("zh-tw", "請求速率限制區域")
+        .desc(
+            "en",
+            "Defines a zone that tracks the request rate of each key, for limit_req"
+        )
+        .desc("zh-tw", "定義記錄每個鍵請求速率的區域，供 limit_req 使用")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Key")
+                .display_name("zh-tw", "鍵")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "Value with variables the rate is tracked by, such as $binary_remote_addr; requests with an empty key are not limited"
+                )
+                .desc(
+                    "zh-tw",
+                    "用來區分速率的值，可包含變數，例如 $binary_remote_addr；鍵為空的請求不受限制"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "zone=name:size, where each key takes about 128 bytes of the size"
+                )
+                .desc("zh-tw", "zone=名稱:大小，每個鍵約佔用 128 位元組")
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "Rate")
+                .display_name("zh-tw", "速率")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "rate=Nr/s or rate=Nr/m")
+                .desc("zh-tw", "rate=每秒 Nr/s 或每分鐘 Nr/m")
+                .build(),
+        ])
+        .arity(Arity::Exact(3))
+        .build(handle_limit_req_zone),
+    CommandBuilder::new("limit_req")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Request Rate Limit")
+        .display_name("zh-tw", "請求速率限制")
+        .desc(
+            "en",
+            "Limits the request rate per key of a limit_req_zone; requests over the rate wait their turn up to the burst and are rejected beyond it"
+        )
+        .desc(
+            "zh-tw",
+            "依 limit_req_zone 的鍵限制請求速率；超過速率的請求在 burst 範圍內排隊等待，超出則拒絕"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "zone=name of a limit_req_zone")
+                .desc("zh-tw", "zone=limit_req_zone 的名稱")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Options")
+                .display_name("zh-tw", "選項")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "burst=N requests allowed over the rate (0 by default), nodelay to serve them at once, or delay=N to serve the first N at once and slow down the rest"
+                )
+                .desc(
+                    "zh-tw",
+                    "burst=N 允許超過速率的請求數（預設 0）；nodelay 立即處理這些請求，或 delay=N 立即處理前 N 個、其餘放慢處理"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_limit_req),
+    CommandBuilder::new("limit_req_status")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Request Rate Limit Status")
+        .display_name("zh-tw", "請求速率限制狀態碼")
+        .desc("en", "Status code of rejected requests")
+        .desc("zh-tw", "被拒絕請求的回應狀態碼")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Status")
+            .display_name("zh-tw", "狀態碼")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "A status from 400 to 599, 503 by default")
+            .desc("zh-tw", "400 到 599 的狀態碼，預設 503")
+            .build()])
+        .build(handle_limit_req_status),
+    CommandBuilder::new("limit_req_headers")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Request Rate Limit Headers")
+        .display_name("zh-tw", "請求速率限制標頭")
+        .desc(
+            "en",
+            "Sets whether responses carry RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers"
+        )
+        .desc(
+            "zh-tw",
+            "設定回應是否附上 RateLimit-Limit、RateLimit-Remaining 與 RateLimit-Reset 標頭"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_limit_req_headers)
+);
+
+pub const DEFAULT_STATUS: u16 = 503;
+/// Rough memory a tracked key takes, to turn a zone size into a key count.
+const BYTES_PER_KEY: u64 = 128;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// Requests over the rate still waiting to drain, in thousandths.
+    excess: u64,
+    last: Instant,
+}
+
+/// The request rate of each key, measured as a leaky bucket.
+pub struct LimitReqZone {
+    pub name: String,
+    key: VarTemplate,
+    /// Thousandths of a request per second.
+    rate: u64,
+    capacity: usize,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl LimitReqZone {
+    pub fn new(name: &str, key: VarTemplate, rate: u64, capacity: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            key,
+            rate,
+            capacity: capacity.max(1),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn drained(&self, bucket: &Bucket, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(bucket.last).as_millis() as u64;
+        bucket
+            .excess
+            .saturating_sub(self.rate.saturating_mul(elapsed) / 1000)
+    }
+
+    /// The excess a request arriving `now` leaves; zero once the bucket has
+    /// room for it, the same as for a key never seen.
+    fn next_excess(&self, bucket: &Bucket, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(bucket.last).as_millis() as u64;
+        (bucket.excess + 1000).saturating_sub(self.rate.saturating_mul(elapsed) / 1000)
+    }
+
+    /// Counts a request for `key`, returning the excess it leaves, or the
+    /// excess it would have left when that is over `burst`.
+    pub fn account(&self, key: &str, burst: u64, now: Instant) -> Result<u64, u64> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(0);
+        };
+        let excess = buckets
+            .get(key)
+            .map_or(0, |bucket| self.next_excess(bucket, now));
+        if excess > burst * 1000 {
+            return Err(excess);
+        }
+        if buckets.len() >= self.capacity && !buckets.contains_key(key) {
+            self.evict(&mut buckets, now);
+        }
+        buckets.insert(key.to_string(), Bucket { excess, last: now });
+        Ok(excess)
+    }
+
+    /// Frees room for a key: drops every bucket a new request would find
+    /// empty, or the least recently used one when there is none.
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| self.next_excess(bucket, now) > 0);
+        if buckets.len() >= self.capacity {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.last)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                buckets.remove(&oldest);
+            }
+        }
+    }
+
+    /// The current excess of `key`, without counting a request.
+    pub fn excess(&self, key: &str, now: Instant) -> u64 {
+        self.buckets
+            .lock()
+            .ok()
+            .and_then(|buckets| buckets.get(key).map(|bucket| self.drained(bucket, now)))
+            .unwrap_or(0)
+    }
+
+    /// How long `excess` thousandths of a request take to drain.
+    fn drain_time(&self, excess: u64) -> Duration {
+        Duration::from_millis(excess.saturating_mul(1000) / self.rate.max(1))
+    }
+}
+
+/// Parses a rate such as `10r/s` or `30r/m` into thousandths of a request
+/// per second.
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let (count, per) = value
+        .split_once("r/")
+        .ok_or("expected a rate such as 10r/s or 30r/m")?;
+    let count: u64 = count
+        .parse()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or("rate must be a positive number")?;
+    match per {
+        "s" => Ok(count * 1000),
+        "m" => Ok((count * 1000 / 60).max(1)),
+        _ => Err(format!("unknown rate unit \"r/{}\"", per)),
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LimitReqZones {
+    pub zones: Vec<Arc<LimitReqZone>>,
+}
+
+impl MergeConfig for LimitReqZones {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitSpec {
+    pub zone: String,
+    pub burst: u64,
+    /// Requests over the rate served without waiting; `burst` with nodelay.
+    pub delay: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LimitReqConfig {
+    pub limits: Option<Vec<LimitSpec>>,
+    pub status: Option<u16>,
+    pub headers: Option<bool>,
+}
+
+impl MergeConfig for LimitReqConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.limits.is_none() {
+            self.limits = parent.limits.clone();
+        }
+        self.status = self.status.or(parent.status);
+        self.headers = self.headers.or(parent.headers);
+    }
+}
+
+impl std::fmt::Debug for LimitReqZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LimitReqZone")
+            .field("name", &self.name)
+            .field("rate", &self.rate)
+            .finish()
+    }
+}
+
+struct Limit {
+    zone: Arc<LimitReqZone>,
+    burst: u64,
+    delay: u64,
+}
+
+/// Delays or rejects requests over the rate of their zones.
+pub struct LimitReqPhase {
+    limits: Arc<Vec<Limit>>,
+    status: StatusCode,
+}
+
+impl RequestPhase for LimitReqPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let now = Instant::now();
+        let vars = RequestVariables::new(req);
+        let mut wait = Duration::ZERO;
+        for limit in self.limits.iter() {
+            let key = limit.zone.key.render(&vars);
+            if key.is_empty() {
+                continue;
+            }
+            match limit.zone.account(&key, limit.burst, now) {
+                Ok(excess) => {
+                    let delayed = excess.saturating_sub(limit.delay * 1000);
+                    wait = wait.max(limit.zone.drain_time(delayed));
+                }
+                Err(excess) => {
+                    log_warn!(
+                        "limiting requests, excess: {}.{:03} by zone \"{}\", client: {}, request: \"{}\"",
+                        excess / 1000,
+                        excess % 1000,
+                        limit.zone.name,
+                        req.remote_addr().map(|addr| addr.ip().to_string()).unwrap_or_default(),
+                        req.path()
+                    );
+                    let retry = limit.zone.drain_time(excess - limit.burst * 1000);
+                    let mut resp =
+                        HttpProcessor::create_status_response(req.version(), self.status);
+                    resp.set_header(
+                        "Retry-After",
+                        &retry.as_secs_f64().ceil().max(1.0).to_string(),
+                    );
+                    return PhaseResult::Respond(Box::new(resp));
+                }
+            }
+        }
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        PhaseResult::Continue
+    }
+}
+
+/// Adds the `RateLimit-*` headers of the limit closest to rejecting.
+pub struct LimitReqHeaders {
+    limits: Arc<Vec<Limit>>,
+}
+
+impl ResponseFilter for LimitReqHeaders {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        let now = Instant::now();
+        let vars = RequestVariables::new(req);
+        let tightest = self
+            .limits
+            .iter()
+            .filter_map(|limit| {
+                let key = limit.zone.key.render(&vars);
+                if key.is_empty() {
+                    return None;
+                }
+                let excess = limit.zone.excess(&key, now);
+                let remaining = (limit.burst * 1000).saturating_sub(excess) / 1000;
+                Some((remaining, limit, excess))
+            })
+            .min_by_key(|(remaining, _, _)| *remaining);
+        if let Some((remaining, limit, excess)) = tightest {
+            let reset = limit.zone.drain_time(excess).as_secs_f64().ceil();
+            resp.set_header("RateLimit-Limit", &(limit.burst + 1).to_string());
+            resp.set_header("RateLimit-Remaining", &remaining.to_string());
+            resp.set_header("RateLimit-Reset", &reset.to_string());
+        }
+    }
+}
+
+fn limits(chain: &[&ConfigContext]) -> Option<(Arc<Vec<Limit>>, LimitReqConfig)> {
+    let config = merged_config::<LimitReqConfig>(chain);
+    let specs = config.limits.as_ref().filter(|limits| !limits.is_empty())?;
+    let zones = merged_config::<LimitReqZones>(&chain[..1]).zones;
+    let limits: Vec<Limit> = specs
+        .iter()
+        .filter_map(|spec| {
+            let zone = zones.iter().find(|zone| zone.name == spec.zone);
+            if zone.is_none() {
+                log_error!("limit_req: zone \"{}\" is not defined", spec.zone);
+            }
+            Some(Limit {
+                zone: Arc::clone(zone?),
+                burst: spec.burst,
+                delay: spec.delay,
+            })
+        })
+        .collect();
+    (!limits.is_empty()).then(|| (Arc::new(limits), config))
+}
+
+/// Builds the rate limiting phase in effect for a block chain, if any.
+pub fn limit_req_phase(chain: &[&ConfigContext]) -> Option<LimitReqPhase> {
+    let (limits, config) = limits(chain)?;
+    Some(LimitReqPhase {
+        limits,
+        status: StatusCode::from_u16(config.status.unwrap_or(DEFAULT_STATUS))
+            .unwrap_or(StatusCode::SERVICE_UNAVAILABLE),
+    })
+}
+
+/// Builds the `RateLimit-*` header filter for a block chain that asks for it.
+pub fn limit_req_headers(chain: &[&ConfigContext]) -> Option<LimitReqHeaders> {
+    let (limits, config) = limits(chain)?;
+    config
+        .headers
+        .unwrap_or(false)
+        .then_some(LimitReqHeaders { limits })
+}
+
+pub fn handle_limit_req_zone(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let [key, zone, rate] = args.as_slice() else {
+        return Ok(());
+    };
+    let (name, size) = zone
+        .strip_prefix("zone=")
+        .and_then(|zone| zone.split_once(':'))
+        .ok_or_else(|| ctx.invalid_value(zone, "expected zone=name:size"))?;
+    let size = parse_size(size).map_err(|reason| ctx.invalid_value(zone, reason))?;
+    let rate = rate
+        .strip_prefix("rate=")
+        .ok_or_else(|| "expected rate=Nr/s".to_string())
+        .and_then(parse_rate)
+        .map_err(|reason| ctx.invalid_value(rate, reason))?;
+    let zone = LimitReqZone::new(
+        name,
+        VarTemplate::parse(key),
+        rate,
+        (size / BYTES_PER_KEY) as usize,
+    );
+    if let Ok(mut config) = ctx.block_config::<LimitReqZones>().lock() {
+        if config.zones.iter().any(|zone| zone.name == name) {
+            return Err(ctx.invalid_value(name, "zone is already defined"));
+        }
+        config.zones.push(Arc::new(zone));
+    }
+    Ok(())
+}
+
+pub fn handle_limit_req(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((zone, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let zone = zone
+        .strip_prefix("zone=")
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| ctx.invalid_value(zone, "expected zone=name"))?;
+    let mut spec = LimitSpec {
+        zone: zone.to_string(),
+        burst: 0,
+        delay: 0,
+    };
+    let mut nodelay = false;
+    for option in options {
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| ctx.invalid_value(option, "expected a number"))
+        };
+        match option.split_once('=') {
+            Some(("burst", value)) => spec.burst = number(value)?,
+            Some(("delay", value)) => spec.delay = number(value)?,
+            None if option == "nodelay" => nodelay = true,
+            _ => return Err(ctx.invalid_value(option, "unknown option")),
+        }
+    }
+    if nodelay {
+        spec.delay = spec.burst;
+    }
+    if let Ok(mut config) = ctx.block_config::<LimitReqConfig>().lock() {
+        config.limits.get_or_insert_with(Vec::new).push(spec);
+    }
+    Ok(())
+}
+
+pub fn handle_limit_req_status(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let status = ctx.number_arg(0)?;
+    if !(400..=599).contains(&status) {
+        return Err(ctx.invalid_value(&status.to_string(), "must be between 400 and 599"));
+    }
+    if let Ok(mut config) = ctx.block_config::<LimitReqConfig>().lock() {
+        config.status = Some(status as u16);
+    }
+    Ok(())
+}
+
+pub fn handle_limit_req_headers(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<LimitReqConfig>().lock() {
+        config.headers = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_burst_then_rejects() {
+        let zone = LimitReqZone::new(
+            "one",
+            VarTemplate::parse("$remote_addr"),
+            parse_rate("2r/s").unwrap(),
+            2,
+        );
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(zone.account("a", 2, at(0)), Ok(0));
+        assert_eq!(zone.account("a", 2, at(0)), Ok(1000));
+        assert_eq!(zone.account("a", 2, at(0)), Ok(2000));
+        assert_eq!(zone.account("a", 2, at(0)), Err(3000));
+        assert_eq!(zone.drain_time(2000), Duration::from_secs(1));
+        // Half a second drains one request.
+        assert_eq!(zone.account("a", 2, at(500)), Ok(2000));
+        assert_eq!(zone.excess("a", at(1500)), 0);
+
+        assert_eq!(zone.account("b", 0, at(1500)), Ok(0));
+        // The zone holds two keys, so a third evicts the least recently used.
+        assert_eq!(zone.account("c", 0, at(1500)), Ok(0));
+        assert_eq!(zone.excess("a", at(1500)), 0);
+        assert_eq!(zone.account("b", 0, at(1500)), Err(1000));
+
+        assert_eq!(parse_rate("30r/m"), Ok(500));
+        assert!(parse_rate("0r/s").is_err());
+        assert!(parse_rate("10r/h").is_err());
+    }
+}
@@ -3,18 +3,19 @@ use reqwest::blocking::Client;
 use serde_json::Value;
 use std::{
     collections::HashMap,
-    sync::{
-        atomic::{AtomicPtr, Ordering},
-        Arc, Mutex,
-    },
+    sync::{Arc, Mutex},
 };
 
 use crate::{
-    core::config::{
-        command::{CommandBuilder, ParameterBuilder},
-        config_manager::get_config_param,
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::ConfigContext,
+            config_loader::ConfigError,
+        },
+        processor::LocationPattern,
     },
-    register_commands,
+    log_debug, register_commands,
 };
 
 use super::{
@@ -33,18 +34,32 @@ register_commands!(
             "Creates a new configuration block for defining a specific URL path handling"
         )
         .desc("zh-tw", "建立處理特定 URL 路徑的配置區塊")
-        .params(vec![ParameterBuilder::new(0)
-            .display_name("en", "Path")
-            .display_name("zh-tw", "路徑")
-            .type_name("String")
-            .is_required(true)
-            .default("")
-            .desc(
-                "en",
-                "Specifies the URL path pattern to be matched for this location block"
-            )
-            .desc("zh-tw", "定義此位置區塊要匹配的 URL 路徑模式")
-            .build()])
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Path")
+                .display_name("zh-tw", "路徑")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "URL path pattern, optionally preceded by a modifier: = (exact), ^~ (prefix without regex checks), ~ (regex) or ~* (case-insensitive regex)"
+                )
+                .desc(
+                    "zh-tw",
+                    "URL 路徑模式，可在前面加上修飾符：=（完全比對）、^~（前綴比對且不檢查正規表示式）、~（正規表示式）或 ~*（不分大小寫的正規表示式）"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Pattern")
+                .display_name("zh-tw", "模式")
+                .type_name("String")
+                .is_required(false)
+                .default("")
+                .desc("en", "URL path pattern when a modifier is given")
+                .desc("zh-tw", "指定修飾符時的 URL 路徑模式")
+                .build(),
+        ])
         .build(handle_create_location),
     CommandBuilder::new("static_file")
         .allowed_parents(vec!["location".to_string()])
@@ -59,6 +74,7 @@ register_commands!(
             .display_name("en", "File Path")
             .display_name("zh-tw", "檔案路徑")
             .type_name("String")
+            .arg_type(ArgType::Path)
             .is_required(true)
             .default("")
             .desc(
@@ -92,101 +108,75 @@ register_commands!(
         .build(handle_port_forward)
 );
 
-fn clone_arc_from_atomic_ptr<T>(atomic_ptr: &AtomicPtr<u8>) -> Option<Arc<T>> {
-    let raw = atomic_ptr.load(Ordering::SeqCst) as *const T;
-    if raw.is_null() {
-        None
-    } else {
-        unsafe {
-            Arc::increment_strong_count(raw);
-            Some(Arc::from_raw(raw))
-        }
-    }
-}
-
-pub fn handle_create_location(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    _config: &Value,
-) {
-    let location_ctx = Arc::new(HttpLocationContext::new());
-    let raw_ptr = Arc::into_raw(location_ctx.clone()) as *mut u8;
-    ctx.current_ctx = Some(AtomicPtr::new(raw_ptr));
-    ctx.current_block_type_id = Some(std::any::TypeId::of::<HttpLocationContext>());
+pub fn handle_create_location(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let pattern = LocationPattern::parse(&args)
+        .map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    ctx.store
+        .insert(Arc::new(HttpLocationContext::with_pattern(pattern)));
+    Ok(())
 }
 
-pub fn handle_set_static_file(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let file_path = get_config_param(config, 0).expect("Missing static_file parameter");
-    if file_path.is_empty() {
-        return;
-    }
-    if let Some(ctx_ptr) = &ctx.current_ctx {
-        if let Some(location_ctx) = clone_arc_from_atomic_ptr::<HttpLocationContext>(ctx_ptr) {
-            let content = Arc::new(
-                std::fs::read_to_string(&file_path)
-                    .expect("Failed to read static file"),
-            );
-            let content_type = get_content_type(&file_path).to_string();
-            let handler = Box::new(move |_req: &HttpRequest| {
-                println!("Serving static file: {}", file_path);
-                let mut resp = HttpResponse::new();
-                resp.set_status_line(Version::HTTP_11, StatusCode::OK);
-                resp.set_header("Content-Type", &content_type);
-                resp.set_body(&content);
-                resp
-            });
-            location_ctx.set_handler(200, handler);
-        }
+pub fn handle_set_static_file(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let file_path = ctx.str_arg(0)?;
+    if let Some(location_ctx) = ctx.store.get::<HttpLocationContext>() {
+        let content = Arc::new(
+            std::fs::read_to_string(&file_path)
+                .map_err(|e| ctx.invalid_value(&file_path, e.to_string()))?,
+        );
+        let content_type = get_content_type(&file_path).to_string();
+        let handler = Box::new(move |_req: &HttpRequest| {
+            log_debug!("Serving static file: {}", file_path);
+            let mut resp = HttpResponse::new();
+            resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+            resp.set_header("Content-Type", &content_type);
+            resp.set_body(&content);
+            resp
+        });
+        location_ctx.set_handler(200, handler);
     }
+    Ok(())
 }
 
-pub fn handle_port_forward(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let forward_addr = get_config_param(config, 0).expect("Missing port_forward parameter");
-    if forward_addr.is_empty() {
-        return;
-    }
+pub fn handle_port_forward(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let forward_addr = ctx.str_arg(0)?;
 
-    if let Some(ctx_ptr) = &ctx.current_ctx {
-        if let Some(location_ctx) = clone_arc_from_atomic_ptr::<HttpLocationContext>(ctx_ptr) {
-            let forward_addr = forward_addr.to_string();
-            let handler = Box::new(move |req: &HttpRequest| {
-                let client = Client::new();
-                let url = format!("{}{}", forward_addr, req.path());
-                let result = client.get(&url).send();
-                match result {
-                    Ok(response) => {
-                        let status = StatusCode::from_u16(response.status().as_u16())
-                            .expect("Invalid status code");
-                        let body = response
-                            .text()
-                            .unwrap_or_else(|_| "Error reading forwarded response".into());
-                        let mut resp = HttpResponse::new();
-                        resp.set_status_line(Version::HTTP_11, status);
-                        resp.set_body(&body);
-                        resp
-                    }
-                    Err(_) => {
-                        let mut resp = HttpResponse::new();
-                        resp.set_status_line(Version::HTTP_11, StatusCode::BAD_GATEWAY);
-                        resp.set_body("Bad Gateway");
-                        resp
-                    }
+    if let Some(location_ctx) = ctx.store.get::<HttpLocationContext>() {
+        let forward_addr = forward_addr.to_string();
+        let handler = Box::new(move |req: &HttpRequest| {
+            let client = Client::new();
+            let url = format!("{}{}", forward_addr, req.path());
+            let result = client.get(&url).send();
+            match result {
+                Ok(response) => {
+                    let status = StatusCode::from_u16(response.status().as_u16())
+                        .expect("Invalid status code");
+                    let body = response
+                        .text()
+                        .unwrap_or_else(|_| "Error reading forwarded response".into());
+                    let mut resp = HttpResponse::new();
+                    resp.set_status_line(Version::HTTP_11, status);
+                    resp.set_body(&body);
+                    resp
                 }
-            });
-            location_ctx.set_handler(200, handler);
-        }
+                Err(_) => {
+                    let mut resp = HttpResponse::new();
+                    resp.set_status_line(Version::HTTP_11, StatusCode::BAD_GATEWAY);
+                    resp.set_body("Bad Gateway");
+                    resp
+                }
+            }
+        });
+        location_ctx.set_handler(200, handler);
     }
+    Ok(())
 }
 
 pub type HttpHandlerFunction = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static>;
 
 #[derive(Default, Clone)]
 pub struct HttpLocationContext {
+    pub pattern: LocationPattern,
     pub handlers: Arc<Mutex<HashMap<u16, HttpHandlerFunction>>>,
 }
 
@@ -195,6 +185,13 @@ impl HttpLocationContext {
         Self::default()
     }
 
+    pub fn with_pattern(pattern: LocationPattern) -> Self {
+        Self {
+            pattern,
+            ..Self::default()
+        }
+    }
+
     pub fn set_handler(&self, code: u16, handler: HttpHandlerFunction) {
         if let Ok(mut handlers) = self.handlers.lock() {
             handlers.insert(code, handler);
@@ -209,4 +206,3 @@ impl HttpLocationContext {
         map
     }
 }
-
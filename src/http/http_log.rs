@@ -0,0 +1,489 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        error_log::Level,
+        log_file::LogFileOptions,
+        log_target::{LogDestination, LogTarget},
+        processor::ResponseFilter,
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("log_format")
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Log Format")
+        .display_name("zh-tw", "日誌格式")
+        .desc("en", "Defines a named format for access_log lines")
+        .desc("zh-tw", "定義 access_log 每一行使用的具名格式")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Name")
+                .display_name("zh-tw", "名稱")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Name access_log refers to the format by")
+                .desc("zh-tw", "access_log 引用此格式時使用的名稱")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Format")
+                .display_name("zh-tw", "格式")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "Optional escape=default, json or none for how variable values are escaped, then text with variables such as $remote_addr, $status and $request_time; several strings are joined"
+                )
+                .desc(
+                    "zh-tw",
+                    "可先以 escape=default、json 或 none 指定變數值的跳脫方式，接著是包含 $remote_addr、$status、$request_time 等變數的文字；多個字串會串接在一起"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(2))
+        .is_repeatable()
+        .build(handle_log_format),
+    CommandBuilder::new("access_log")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Access Log")
+        .display_name("zh-tw", "存取日誌")
+        .desc("en", "Writes a line to a file for every request served")
+        .desc("zh-tw", "每處理一個請求便寫入一行至檔案")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Path")
+                .display_name("zh-tw", "路徑")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "File the lines are appended to, syslog:server=address[,facility=name,severity=level,tag=name,nohostname], journald, or off"
+                )
+                .desc(
+                    "zh-tw",
+                    "附加日誌的檔案、syslog:server=位址[,facility=名稱,severity=等級,tag=名稱,nohostname]、journald，或 off"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Format")
+                .display_name("zh-tw", "格式")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "Name of a format declared by log_format, combined by default"
+                )
+                .desc("zh-tw", "log_format 宣告的格式名稱，預設為 combined")
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "Options")
+                .display_name("zh-tw", "選項")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "buffer=size to collect lines in memory before writing them, flush=time to write buffered lines at least that often, rotate_size=size and rotate_time=time to rotate the file, rotate_keep=n (7 by default) rotated files to keep"
+                )
+                .desc(
+                    "zh-tw",
+                    "buffer=大小 先於記憶體累積日誌再寫入，flush=時間 設定緩衝日誌寫入的最長間隔，rotate_size=大小 與 rotate_time=時間 輪替檔案，rotate_keep=數量（預設 7）保留的舊檔數量"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .is_repeatable()
+        .build(handle_access_log),
+);
+
+/// The format used when `access_log` names none; it cannot be redefined.
+const COMBINED: &str = "combined";
+const COMBINED_FORMAT: &str = "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \"$http_referer\" \"$http_user_agent\"";
+
+/// How variable values are written into a log line.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LogEscape {
+    /// `\xXX` for quotes, backslashes and bytes outside printable ASCII,
+    /// and `-` for unset variables.
+    #[default]
+    Default,
+    /// JSON string escapes, and nothing for unset variables, so that the
+    /// value can sit inside a quoted JSON string.
+    Json,
+    /// Values as they are, and nothing for unset variables.
+    None,
+}
+
+impl LogEscape {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "default" => Ok(Self::Default),
+            "json" => Ok(Self::Json),
+            "none" => Ok(Self::None),
+            _ => Err("escape must be default, json or none".to_string()),
+        }
+    }
+
+    pub fn apply(self, value: Option<&str>) -> String {
+        match (self, value) {
+            (Self::Default, None) => "-".to_string(),
+            (_, None) => String::new(),
+            (Self::Default, Some(value)) => {
+                let mut out = String::with_capacity(value.len());
+                for &byte in value.as_bytes() {
+                    if byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte) {
+                        out.push_str(&format!("\\x{:02X}", byte));
+                    } else {
+                        out.push(byte as char);
+                    }
+                }
+                out
+            }
+            (Self::Json, Some(value)) => {
+                let mut out = String::with_capacity(value.len());
+                escape_json(value, &mut out);
+                out
+            }
+            (Self::None, Some(value)) => value.to_string(),
+        }
+    }
+}
+
+/// Appends `value` to `out` escaped for use inside a JSON string.
+pub fn escape_json(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFormat {
+    pub escape: LogEscape,
+    pub format: String,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LogFormats {
+    pub formats: HashMap<String, LogFormat>,
+}
+
+impl MergeConfig for LogFormats {
+    fn merge_from(&mut self, parent: &Self) {
+        for (name, format) in &parent.formats {
+            self.formats
+                .entry(name.clone())
+                .or_insert_with(|| format.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogSpec {
+    pub destination: LogDestination,
+    pub format: String,
+    pub file: LogFileOptions,
+}
+
+impl AccessLogSpec {
+    /// Parses the arguments of `access_log` other than `off`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (path, rest) = args.split_first().ok_or("missing log file path")?;
+        let mut format = None;
+        let mut file = LogFileOptions::default();
+        for (i, arg) in rest.iter().enumerate() {
+            let Some((name, value)) = arg.split_once('=') else {
+                if i > 0 {
+                    return Err(format!("unknown option \"{}\"", arg));
+                }
+                format = Some(arg.clone());
+                continue;
+            };
+            if !file.parse_option(name, value)? {
+                return Err(format!("unknown option \"{}\"", name));
+            }
+        }
+        Ok(Self {
+            destination: LogDestination::parse(path)?,
+            format: format.unwrap_or_else(|| COMBINED.to_string()),
+            file,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AccessLogConfig {
+    /// `access_log off` leaves it empty.
+    pub logs: Option<Vec<AccessLogSpec>>,
+}
+
+impl MergeConfig for AccessLogConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.logs = self.logs.take().or_else(|| parent.logs.clone());
+    }
+}
+
+/// What the server knows about a response once it has been written.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SentResponse {
+    /// From the first byte of the request to the last byte of the response.
+    pub request_time: Duration,
+    pub bytes: u64,
+    pub body_bytes: u64,
+}
+
+struct AccessLog {
+    target: LogTarget,
+    format: VarTemplate,
+    escape: LogEscape,
+}
+
+/// The access logs of a block, carried by each of its responses to the
+/// server, which writes them after the response is sent.
+pub struct AccessLogs {
+    logs: Vec<AccessLog>,
+}
+
+impl PartialEq for AccessLogs {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl AccessLogs {
+    pub fn write(&self, req: &HttpRequest, resp: &HttpResponse, sent: &SentResponse) {
+        let vars = RequestVariables::new(req)
+            .with_response(resp)
+            .with_value(
+                "request_time",
+                format!("{:.3}", sent.request_time.as_secs_f64()),
+            )
+            .with_value("bytes_sent", sent.bytes.to_string())
+            .with_value("body_bytes_sent", sent.body_bytes.to_string());
+        for log in &self.logs {
+            let line = log
+                .format
+                .render_escaped(&vars, |value| log.escape.apply(value));
+            log.target.write(Level::Info, &line);
+        }
+    }
+}
+
+/// Attaches the access logs of a block to its responses.
+pub struct AccessLogFilter {
+    logs: Arc<AccessLogs>,
+}
+
+impl ResponseFilter for AccessLogFilter {
+    fn filter(&self, _req: &HttpRequest, resp: &mut HttpResponse) {
+        resp.access_log = Some(Arc::clone(&self.logs));
+    }
+}
+
+/// Builds the access log filter for a block chain, opening its log files.
+/// Logs whose file cannot be opened or whose format is unknown are left out.
+pub fn access_log_filter(chain: &[&ConfigContext]) -> Option<AccessLogFilter> {
+    let specs = merged_config::<AccessLogConfig>(chain).logs?;
+    let formats = merged_config::<LogFormats>(chain);
+    let mut logs = Vec::new();
+    for spec in specs {
+        let format = match spec.format.as_str() {
+            COMBINED => LogFormat {
+                escape: LogEscape::Default,
+                format: COMBINED_FORMAT.to_string(),
+            },
+            name => match formats.formats.get(name) {
+                Some(format) => format.clone(),
+                None => {
+                    log_error!("unknown log format \"{}\"", name);
+                    continue;
+                }
+            },
+        };
+        match spec.destination.open(&spec.file) {
+            Ok(target) => logs.push(AccessLog {
+                target,
+                format: VarTemplate::parse(&format.format),
+                escape: format.escape,
+            }),
+            Err(e) => log_error!("cannot open access log \"{}\": {}", spec.destination, e),
+        }
+    }
+    (!logs.is_empty()).then(|| AccessLogFilter {
+        logs: Arc::new(AccessLogs { logs }),
+    })
+}
+
+pub fn handle_log_format(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((name, mut parts)) = args.split_first() else {
+        return Ok(());
+    };
+    let mut escape = LogEscape::Default;
+    if let Some(value) = parts.first().and_then(|part| part.strip_prefix("escape=")) {
+        escape = LogEscape::parse(value).map_err(|reason| ctx.invalid_value(value, reason))?;
+        parts = &parts[1..];
+    }
+    if parts.is_empty() {
+        return Err(ctx.invalid_value(name, "missing format"));
+    }
+    if name == COMBINED {
+        return Err(ctx.invalid_value(name, "the combined format is predefined"));
+    }
+    let formats = ctx.block_config::<LogFormats>();
+    let Ok(mut formats) = formats.lock() else {
+        return Ok(());
+    };
+    if formats.formats.contains_key(name) {
+        return Err(ctx.invalid_value(name, "duplicate log format name"));
+    }
+    let format = LogFormat {
+        escape,
+        format: parts.concat(),
+    };
+    formats.formats.insert(name.clone(), format);
+    Ok(())
+}
+
+pub fn handle_access_log(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let spec = if args == ["off"] {
+        None
+    } else {
+        Some(
+            AccessLogSpec::parse(&args)
+                .map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?,
+        )
+    };
+    if let Ok(mut config) = ctx.block_config::<AccessLogConfig>().lock() {
+        let logs = config.logs.get_or_insert_with(Vec::new);
+        match spec {
+            Some(spec) => logs.push(spec),
+            None => logs.clear(),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use http::{StatusCode, Version};
+
+    use super::*;
+
+    #[test]
+    fn test_access_log_lines() {
+        let path = std::env::temp_dir().join(format!("blur-access-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let args = [path.to_string_lossy().to_string(), "buffer=4k".to_string()];
+        let spec = AccessLogSpec::parse(&args).unwrap();
+        assert_eq!(spec.format, COMBINED);
+        let logs = AccessLogs {
+            logs: vec![
+                AccessLog {
+                    target: spec.destination.open(&spec.file).unwrap(),
+                    format: VarTemplate::parse(COMBINED_FORMAT),
+                    escape: LogEscape::Default,
+                },
+                AccessLog {
+                    target: spec.destination.open(&spec.file).unwrap(),
+                    format: VarTemplate::parse("$status $request_time $bytes_sent $arg_q"),
+                    escape: LogEscape::Default,
+                },
+            ],
+        };
+        let (LogTarget::File(first), LogTarget::File(second)) =
+            (&logs.logs[0].target, &logs.logs[1].target)
+        else {
+            panic!("access log is not a file");
+        };
+        assert!(Arc::ptr_eq(first, second));
+
+        let mut req = HttpRequest::new();
+        req.parse(
+            concat!(
+                "GET /search?q=a%20\"b\" HTTP/1.1\r\nHost: a\r\n",
+                "Authorization: Basic YWxpY2U6c2VjcmV0\r\nUser-Agent: t\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+        resp.set_body("hello");
+        let sent = SentResponse {
+            request_time: Duration::from_millis(1500),
+            bytes: 80,
+            body_bytes: 5,
+        };
+        logs.write(&req, &resp, &sent);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        drop(logs);
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("- - alice ["), "{}", lines[0]);
+        assert!(
+            lines[0].ends_with("] \"GET /search?q=a%20\\x22b\\x22 HTTP/1.1\" 200 5 \"-\" \"t\""),
+            "{}",
+            lines[0]
+        );
+        assert_eq!(lines[1], "200 1.500 80 a%20\\x22b\\x22");
+        let _ = fs::remove_file(&path);
+
+        let args = ["/tmp/a.log", "main", "gzip"].map(str::to_string);
+        assert!(AccessLogSpec::parse(&args).is_err());
+    }
+
+    #[test]
+    fn test_json_escape() {
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /a?q=say%20\"hi\" HTTP/1.1\r\nHost: a\r\nUser-Agent: x\\y\ttab\r\n\r\n")
+            .unwrap();
+        let vars = RequestVariables::new(&req).with_value("note", "line\nbreak\u{1}é".to_string());
+        let format = VarTemplate::parse(
+            r#"{"uri":"$request_uri","ua":"$http_user_agent","ref":"$http_referer","note":"$note"}"#,
+        );
+        let line = format.render_escaped(&vars, |value| LogEscape::Json.apply(value));
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["uri"], "/a?q=say%20\"hi\"");
+        assert_eq!(parsed["ua"], "x\\y\ttab");
+        assert_eq!(parsed["ref"], "");
+        assert_eq!(parsed["note"], "line\nbreak\u{1}é");
+        assert_eq!(LogEscape::None.apply(Some("a\"b")), "a\"b");
+        assert!(LogEscape::parse("xml").is_err());
+    }
+}
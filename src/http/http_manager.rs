@@ -1,14 +1,16 @@
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicPtr, Arc, Mutex},
+    sync::{Arc, Mutex},
     thread,
 };
 
 use serde_json::Value;
 
 use crate::{
-    core::config::{command::CommandBuilder, config_context::ConfigContext},
-    register_commands,
+    core::config::{
+        command::CommandBuilder, config_context::ConfigContext, config_loader::ConfigError,
+    },
+    log_error, log_info, log_notice, register_commands,
 };
 
 use super::http_server::{HttpServer, HttpServerContext};
@@ -23,14 +25,9 @@ register_commands!(CommandBuilder::new("http")
     .desc("zh-tw", "HTTP 協定配置。")
     .build(handle_create_http));
 
-pub fn handle_create_http(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    _config: &Value,
-) {
-    let http_ctx = Arc::new(HttpContext::new());
-    let http_raw = Arc::into_raw(http_ctx.clone()) as *mut u8;
-    ctx.current_ctx = Some(AtomicPtr::new(http_raw));
-    ctx.current_block_type_id = Some(std::any::TypeId::of::<HttpContext>());
+pub fn handle_create_http(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    ctx.store.insert(Arc::new(HttpContext::new()));
+    Ok(())
 }
 
 #[derive(Default)]
@@ -44,7 +41,7 @@ impl HttpContext {
     }
 
     pub fn set_server(&self, addr: &str, ctx: Arc<HttpServerContext>) {
-        println!("Setting server: {}", addr);
+        log_info!("Setting server: {}", addr);
         let addr_str = if addr.chars().all(|c| c.is_ascii_digit()) {
             format!("0.0.0.0:{}", addr)
         } else {
@@ -66,15 +63,9 @@ impl HttpManager {
     pub fn new(http_config: &ConfigContext) -> Self {
         let mut servers = Vec::new();
         for server_ctx in &http_config.children {
-            if server_ctx.block_name == "server" {
-                if let Some(ptr) = server_ctx.current_ctx.as_ref() {
-                    let srv_raw = ptr.load(std::sync::atomic::Ordering::SeqCst);
-                    let srv_arc: Arc<HttpServerContext> =
-                        unsafe { Arc::from_raw(srv_raw as *const HttpServerContext) };
-                    let server = HttpServer::new(server_ctx);
-                    servers.push(server);
-                    std::mem::forget(srv_arc);
-                }
+            if server_ctx.block_name == "server" && server_ctx.store.contains::<HttpServerContext>()
+            {
+                servers.push(HttpServer::new(http_config, server_ctx));
             }
         }
         Self {
@@ -84,7 +75,7 @@ impl HttpManager {
     }
 
     pub fn start(&mut self) {
-        println!("Starting HTTP servers...");
+        log_notice!("Starting HTTP servers...");
         for server in self.servers.drain(..) {
             let handle = server.start();
             self.server_handles.push(handle);
@@ -94,7 +85,7 @@ impl HttpManager {
     pub fn join(self) {
         for handle in self.server_handles {
             if let Err(e) = handle.join() {
-                eprintln!("Error joining server thread: {:?}", e);
+                log_error!("Error joining server thread: {:?}", e);
             }
         }
     }
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, LazyLock},
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{CommandBuilder, ParameterBuilder},
+        config_context::{merged_config, ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+register_commands!(
+    CommandBuilder::new("types")
+        .is_raw_block()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "MIME Types")
+        .display_name("zh-tw", "MIME 類型")
+        .desc(
+            "en",
+            "Maps file extensions to response MIME types, replacing the built-in mapping"
+        )
+        .desc("zh-tw", "將副檔名對應到回應的 MIME 類型，並取代內建的對應")
+        .build(handle_types),
+    CommandBuilder::new("default_type")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Default Type")
+        .display_name("zh-tw", "預設類型")
+        .desc(
+            "en",
+            "Sets the MIME type used for files with an unknown extension"
+        )
+        .desc("zh-tw", "設定副檔名不明的檔案所使用的 MIME 類型")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "MIME Type")
+            .display_name("zh-tw", "MIME 類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "MIME type, e.g. application/octet-stream")
+            .desc("zh-tw", "MIME 類型，例如 application/octet-stream")
+            .build()])
+        .build(handle_default_type),
+);
+
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// The mapping shipped in `config/mime.types`, used until a `types` block
+/// replaces it.
+pub static DEFAULT_TYPES: LazyLock<Arc<MimeTypes>> = LazyLock::new(|| {
+    Arc::new(
+        MimeTypes::parse(include_str!("../../config/mime.types"))
+            .expect("bundled mime.types is invalid"),
+    )
+});
+
+#[derive(Debug, Default, Clone)]
+pub struct MimeTypes {
+    by_extension: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a `types { mime ext ...; }` block as found in mime.types files.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let body: String = input
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let body = body
+            .trim()
+            .strip_prefix("types")
+            .and_then(|rest| rest.trim_start().strip_prefix('{'))
+            .and_then(|rest| rest.trim_end().strip_suffix('}'))
+            .ok_or_else(|| "expected a \"types { ... }\" block".to_string())?;
+
+        let mut types = Self::new();
+        for entry in body.split(';') {
+            let words: Vec<&str> = entry.split_whitespace().collect();
+            match words.as_slice() {
+                [] => {}
+                [mime, extensions @ ..] if !extensions.is_empty() => {
+                    types.insert(mime, extensions.iter().copied())
+                }
+                _ => return Err(format!("missing extensions in \"{}\"", entry.trim())),
+            }
+        }
+        Ok(types)
+    }
+
+    pub fn insert<'a>(&mut self, mime: &str, extensions: impl IntoIterator<Item = &'a str>) {
+        for extension in extensions {
+            self.by_extension
+                .insert(extension.to_ascii_lowercase(), mime.to_string());
+        }
+    }
+
+    pub fn get(&self, extension: &str) -> Option<&str> {
+        self.by_extension
+            .get(&extension.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    pub fn for_path(&self, path: &str) -> Option<&str> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        self.get(extension)
+    }
+}
+
+/// MIME settings in effect for a block.
+#[derive(Debug, Default, Clone)]
+pub struct MimeConfig {
+    pub types: Option<Arc<MimeTypes>>,
+    pub default_type: Option<String>,
+}
+
+impl MergeConfig for MimeConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.types = self.types.take().or_else(|| parent.types.clone());
+        self.default_type = self
+            .default_type
+            .take()
+            .or_else(|| parent.default_type.clone());
+    }
+}
+
+impl MimeConfig {
+    /// Resolves the settings for a block chain, where `types` blocks are
+    /// stored as children of the block they apply to.
+    pub fn for_chain(chain: &[&ConfigContext]) -> Self {
+        let mut config = merged_config::<MimeConfig>(chain);
+        config.types = chain
+            .iter()
+            .rev()
+            .find_map(|ctx| {
+                ctx.children
+                    .iter()
+                    .filter(|child| child.block_name.trim() == "types")
+                    .find_map(|child| child.store.get::<MimeTypes>())
+            })
+            .or(config.types);
+        config
+    }
+
+    /// The type configured for a file extension, without falling back to
+    /// `default_type`.
+    pub fn known_type(&self, extension: &str) -> Option<&str> {
+        self.types
+            .as_deref()
+            .unwrap_or(&DEFAULT_TYPES)
+            .get(extension)
+    }
+
+    pub fn content_type(&self, path: &str) -> &str {
+        let types = self.types.as_deref().unwrap_or(&DEFAULT_TYPES);
+        types
+            .for_path(path)
+            .or(self.default_type.as_deref())
+            .unwrap_or(DEFAULT_MIME_TYPE)
+    }
+}
+
+pub fn handle_types(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let mut types = MimeTypes::new();
+    for entry in &ctx.raw_entries {
+        match entry.args.as_slice() {
+            [mime, extensions @ ..] if !extensions.is_empty() => {
+                types.insert(mime, extensions.iter().map(String::as_str))
+            }
+            _ => {
+                return Err(ConfigError::InvalidArgCount {
+                    name: ctx.current_cmd_name.clone(),
+                    pos: entry.pos.clone(),
+                })
+            }
+        }
+    }
+    ctx.store.insert(Arc::new(types));
+    Ok(())
+}
+
+pub fn handle_default_type(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let default_type = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<MimeConfig>().lock() {
+        config.default_type = Some(default_type);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_types_lookup() {
+        assert_eq!(DEFAULT_TYPES.for_path("/a/b.HTML"), Some("text/html"));
+        assert_eq!(DEFAULT_TYPES.for_path("font.woff2"), Some("font/woff2"));
+        assert_eq!(DEFAULT_TYPES.for_path("noext"), None);
+
+        let types = MimeTypes::parse("types {\n  text/x-custom foo bar; # comment\n}").unwrap();
+        let config = MimeConfig {
+            types: Some(Arc::new(types)),
+            default_type: Some("text/plain".to_string()),
+        };
+        assert_eq!(config.content_type("a.bar"), "text/x-custom");
+        assert_eq!(config.content_type("a.html"), "text/plain");
+        assert_eq!(
+            MimeConfig::default().content_type("a.xyz"),
+            DEFAULT_MIME_TYPE
+        );
+        assert!(MimeTypes::parse("types { text/html; }").is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    thread,
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, ProcessorSlot, RequestPhase, Subrequest},
+    },
+    log_warn, register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("mirror")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Mirror")
+        .display_name("zh-tw", "鏡像請求")
+        .desc(
+            "en",
+            "Sends a copy of each request to another location in the background, discarding its response"
+        )
+        .desc("zh-tw", "在背景將每個請求複製一份送往另一個 location，並捨棄其回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "URI")
+            .display_name("zh-tw", "URI")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "URI of the location the copy is sent to, e.g. /shadow, or off"
+            )
+            .desc("zh-tw", "接收複本的 location URI，例如 /shadow，或 off")
+            .build()])
+        .is_repeatable()
+        .build(handle_mirror),
+    CommandBuilder::new("mirror_request_body")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Mirror Request Body")
+        .display_name("zh-tw", "鏡像請求內容")
+        .desc("en", "Sets whether mirrored requests carry the request body")
+        .desc("zh-tw", "設定鏡像請求是否包含請求內容")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on (the default) or off")
+            .desc("zh-tw", "on（預設）或 off")
+            .build()])
+        .build(handle_mirror_request_body),
+);
+
+/// Mirrored requests in flight across all servers; copies beyond this are
+/// dropped rather than piling up threads behind a slow mirror.
+const MAX_IN_FLIGHT: usize = 256;
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Default, Clone)]
+pub struct MirrorConfig {
+    /// `mirror off` leaves it empty.
+    pub uris: Option<Vec<String>>,
+    pub request_body: Option<bool>,
+}
+
+impl MergeConfig for MirrorConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.uris = self.uris.take().or_else(|| parent.uris.clone());
+        self.request_body = self.request_body.or(parent.request_body);
+    }
+}
+
+/// Copies each request of a location to the mirror URIs. The copies run on
+/// their own threads through the whole processor, like requests from a
+/// client, and their responses are read and thrown away.
+pub struct MirrorPhase {
+    uris: Vec<String>,
+    request_body: bool,
+    processor: ProcessorSlot,
+}
+
+impl MirrorPhase {
+    pub fn new(uris: Vec<String>, request_body: bool, processor: ProcessorSlot) -> Self {
+        Self {
+            uris,
+            request_body,
+            processor,
+        }
+    }
+
+    fn mirror(&self, processor: &Arc<HttpProcessor>, req: &HttpRequest, uri: &str) {
+        let claimed = IN_FLIGHT.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count < MAX_IN_FLIGHT).then_some(count + 1)
+        });
+        if claimed.is_err() {
+            log_warn!(
+                "mirror of \"{}\" to \"{}\" dropped: too many in flight",
+                req.path(),
+                uri
+            );
+            return;
+        }
+        let mut copy = Subrequest::new(req, uri);
+        if !self.request_body {
+            copy = copy.without_body();
+        }
+        let processor = Arc::clone(processor);
+        thread::spawn(move || {
+            processor.subrequest(copy).discard_body();
+            IN_FLIGHT.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+}
+
+impl RequestPhase for MirrorPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        // Copies are never mirrored again, even where `mirror` is inherited.
+        if req.is_subrequest() {
+            return PhaseResult::Continue;
+        }
+        if let Some(processor) = self.processor.get().and_then(Weak::upgrade) {
+            for uri in &self.uris {
+                self.mirror(&processor, req, uri);
+            }
+        }
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the mirror phase for a location chain that sets `mirror`.
+pub fn mirror_phase(chain: &[&ConfigContext], processor: &ProcessorSlot) -> Option<MirrorPhase> {
+    let config = merged_config::<MirrorConfig>(chain);
+    let uris = config.uris.filter(|uris| !uris.is_empty())?;
+    Some(MirrorPhase::new(
+        uris,
+        config.request_body.unwrap_or(true),
+        Arc::clone(processor),
+    ))
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut MirrorConfig)) {
+    if let Ok(mut config) = ctx.block_config::<MirrorConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_mirror(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let uri = ctx.str_arg(0)?;
+    if uri == "off" {
+        update(ctx, |config| config.uris = Some(Vec::new()));
+        return Ok(());
+    }
+    if !uri.starts_with('/') {
+        return Err(ctx.invalid_value(&uri, "must start with /"));
+    }
+    update(ctx, |config| {
+        config.uris.get_or_insert_with(Vec::new).push(uri)
+    });
+    Ok(())
+}
+
+pub fn handle_mirror_request_body(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.request_body = Some(enabled));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{core::processor::LocationPattern, http::http_response::HttpResponse};
+
+    use super::*;
+
+    #[test]
+    fn test_mirror_sends_copies() {
+        let slot = ProcessorSlot::default();
+        let (sent, received) = mpsc::channel();
+        let sent = Mutex::new(sent);
+        let mut processor = HttpProcessor::new();
+        processor.add_location_with_phases(
+            LocationPattern::parse(&["/".to_string()]).unwrap(),
+            Some(Box::new(|_: &HttpRequest| {
+                HttpProcessor::create_status_response(&http::Version::HTTP_11, http::StatusCode::OK)
+            })),
+            vec![Arc::new(MirrorPhase::new(
+                vec!["/shadow".to_string()],
+                false,
+                Arc::clone(&slot),
+            ))],
+            Arc::default(),
+            Vec::new(),
+        );
+        processor.add_location(
+            LocationPattern::parse(&["/shadow".to_string()]).unwrap(),
+            Box::new(move |req: &HttpRequest| {
+                let _ = sent.lock().unwrap().send((
+                    req.path().to_string(),
+                    req.request_uri().to_string(),
+                    req.body().to_vec(),
+                    req.header("Content-Length").map(str::to_string),
+                ));
+                HttpResponse::new()
+            }),
+        );
+        let processor = Arc::new(processor);
+        slot.set(Arc::downgrade(&processor)).unwrap();
+
+        let mut req = HttpRequest::new();
+        req.parse(b"POST /api/items?page=2 HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nbody")
+            .unwrap();
+        assert_eq!(processor.handle(&mut req).status(), Some(200));
+
+        let (path, request_uri, body, content_length) =
+            received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(path, "/shadow?page=2");
+        assert_eq!(request_uri, "/api/items?page=2");
+        assert!(body.is_empty());
+        assert_eq!(content_length, None);
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::{http_mime::MimeConfig, http_request::HttpRequest};
+
+register_commands!(CommandBuilder::new("negotiate")
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "Content Negotiation")
+    .display_name("zh-tw", "內容協商")
+    .desc(
+        "en",
+        "Serves a missing file from its language or type variants, such as page.html.en, chosen by Accept-Language and Accept"
+    )
+    .desc(
+        "zh-tw",
+        "檔案不存在時，依 Accept-Language 與 Accept 從其語言或類型版本（例如 page.html.en）中挑選回應"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Enable")
+        .display_name("zh-tw", "啟用")
+        .arg_type(ArgType::Bool)
+        .is_required(true)
+        .default("")
+        .desc("en", "on or off, defaults to off")
+        .desc("zh-tw", "on 或 off，預設為 off")
+        .build()])
+    .build(handle_negotiate));
+
+/// Suffixes left to `gzip_static` and `brotli_static`.
+const ENCODING_SUFFIXES: [&str; 2] = ["gz", "br"];
+
+/// Quality of a variant without a language when the client names the
+/// languages it wants: acceptable, but below any that match.
+const UNLABELED_QUALITY: f32 = 0.001;
+
+#[derive(Debug, Default, Clone)]
+pub struct NegotiateConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for NegotiateConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+    }
+}
+
+impl NegotiateConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+}
+
+/// A file standing in for a requested path in one language or type, named
+/// after it with extra extensions such as `page.html.de` or `page.en.json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub path: PathBuf,
+    /// Lowercase language tag from the file name.
+    pub language: Option<String>,
+    pub content_type: String,
+    /// Whether the content type comes from the variant's own extension
+    /// rather than the requested path.
+    pub typed: bool,
+}
+
+/// Lists the variants of `path` in its directory, ordered by file name.
+pub fn find_variants(path: &Path, mime: &MimeConfig) -> Vec<Variant> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let prefix = format!("{}.", name);
+    let mut variants: Vec<Variant> = entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_type()
+                .is_ok_and(|t| t.is_file() || t.is_symlink())
+        })
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            let extensions = file_name.strip_prefix(&prefix)?;
+            let mut variant = Variant {
+                path: entry.path(),
+                language: None,
+                content_type: mime.content_type(name).to_string(),
+                typed: false,
+            };
+            for extension in extensions.split('.') {
+                if ENCODING_SUFFIXES.contains(&extension) {
+                    return None;
+                }
+                if let Some(content_type) = mime.known_type(extension) {
+                    if variant.typed {
+                        return None;
+                    }
+                    variant.content_type = content_type.to_string();
+                    variant.typed = true;
+                } else if is_language_tag(extension) && variant.language.is_none() {
+                    variant.language = Some(extension.to_ascii_lowercase());
+                } else {
+                    return None;
+                }
+            }
+            Some(variant)
+        })
+        .filter(|variant| fs::metadata(&variant.path).is_ok_and(|m| m.is_file()))
+        .collect();
+    variants.sort_by(|a, b| a.path.cmp(&b.path));
+    variants
+}
+
+/// Picks the variant the client prefers, or none if it accepts none of
+/// them. Among equally good variants the language listed first wins, and
+/// without `Accept-Language` a variant without a language is preferred.
+pub fn choose<'a>(req: &HttpRequest, variants: &'a [Variant]) -> Option<&'a Variant> {
+    let languages = req.header("Accept-Language").map(preferences);
+    let types = req.header("Accept").map(preferences);
+    let mut best: Option<(&Variant, f32, usize)> = None;
+    for variant in variants {
+        let (language_quality, rank) = match (&languages, &variant.language) {
+            (None, None) => (1.0, 0),
+            (None, Some(_)) => (1.0, 1),
+            (Some(ranges), None) => (UNLABELED_QUALITY, ranges.len()),
+            (Some(ranges), Some(tag)) => language_quality(ranges, tag),
+        };
+        let type_quality = match &types {
+            Some(ranges) if variant.typed => type_quality(ranges, &variant.content_type),
+            _ => 1.0,
+        };
+        let quality = language_quality * type_quality;
+        if quality <= 0.0 {
+            continue;
+        }
+        let better = best.is_none_or(|(_, best_quality, best_rank)| {
+            quality > best_quality || (quality == best_quality && rank < best_rank)
+        });
+        if better {
+            best = Some((variant, quality, rank));
+        }
+    }
+    best.map(|(variant, _, _)| variant)
+}
+
+/// The request headers the choice among `variants` depends on.
+pub fn vary(variants: &[Variant]) -> Vec<&'static str> {
+    let mut headers = Vec::new();
+    if variants.iter().any(|variant| variant.typed) {
+        headers.push("Accept");
+    }
+    if variants.iter().any(|variant| variant.language.is_some()) {
+        headers.push("Accept-Language");
+    }
+    headers
+}
+
+/// Parses a header such as `de-CH, en;q=0.8` into lowercase ranges and
+/// their qualities, in the order listed.
+fn preferences(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            if range.is_empty() {
+                return None;
+            }
+            let quality = parts
+                .find_map(|param| {
+                    let (key, value) = param.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("q")
+                        .then(|| value.trim().parse::<f32>().ok())?
+                })
+                .unwrap_or(1.0);
+            Some((range, quality))
+        })
+        .collect()
+}
+
+/// The quality of the most specific range matching `tag`, and the position
+/// of that range in the header.
+fn language_quality(ranges: &[(String, f32)], tag: &str) -> (f32, usize) {
+    let mut matched: Option<(usize, f32, usize)> = None;
+    for (index, (range, quality)) in ranges.iter().enumerate() {
+        let specificity = if range == tag {
+            usize::MAX
+        } else if tag.starts_with(range.as_str()) && tag.as_bytes().get(range.len()) == Some(&b'-')
+        {
+            range.len()
+        } else if range == "*" {
+            0
+        } else {
+            continue;
+        };
+        if matched.is_none_or(|(best, _, _)| specificity > best) {
+            matched = Some((specificity, *quality, index));
+        }
+    }
+    matched.map_or((0.0, ranges.len()), |(_, quality, index)| (quality, index))
+}
+
+/// The quality of the most specific media range matching `content_type`.
+fn type_quality(ranges: &[(String, f32)], content_type: &str) -> f32 {
+    let content_type = content_type.to_ascii_lowercase();
+    let main = content_type.split('/').next().unwrap_or_default();
+    let mut matched: Option<(u8, f32)> = None;
+    for (range, quality) in ranges {
+        let specificity = if *range == content_type {
+            2
+        } else if range.strip_suffix("/*") == Some(main) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if matched.is_none_or(|(best, _)| specificity > best) {
+            matched = Some((specificity, *quality));
+        }
+    }
+    matched.map_or(0.0, |(_, quality)| quality)
+}
+
+/// A two-letter primary language subtag, optionally followed by subtags
+/// such as `en-us` or `zh-hant-tw`. Three-letter codes are left out since
+/// they cannot be told apart from suffixes like `.bak` or `.old`.
+fn is_language_tag(extension: &str) -> bool {
+    let mut subtags = extension.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    primary.len() == 2
+        && primary.bytes().all(|b| b.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+pub fn handle_negotiate(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<NegotiateConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(format!("GET /page.html HTTP/1.1\r\nHost: a\r\n{}\r\n", headers).as_bytes())
+            .unwrap();
+        req
+    }
+
+    #[test]
+    fn test_choose_variant() {
+        let dir = std::env::temp_dir().join(format!("blur-negotiate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "page.html.en",
+            "page.html.de",
+            "page.html.zh-tw",
+            "page.html.en.gz",
+            "page.html.bak",
+            "data.json",
+            "data.xml",
+        ] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        let mime = MimeConfig::default();
+        let pages = find_variants(&dir.join("page.html"), &mime);
+        assert_eq!(pages.len(), 3);
+        assert!(pages
+            .iter()
+            .all(|v| v.content_type == "text/html" && !v.typed));
+        assert_eq!(vary(&pages), vec!["Accept-Language"]);
+
+        let chosen = |headers: &str, variants: &[Variant]| {
+            choose(&request(headers), variants).and_then(|v| v.language.clone())
+        };
+        assert_eq!(
+            chosen("Accept-Language: de-CH, de;q=0.9, en;q=0.8\r\n", &pages),
+            Some("de".to_string())
+        );
+        assert_eq!(
+            chosen("Accept-Language: zh-TW, en\r\n", &pages),
+            Some("zh-tw".to_string())
+        );
+        assert_eq!(
+            chosen("Accept-Language: en, de\r\n", &pages),
+            Some("en".to_string())
+        );
+        assert_eq!(
+            chosen("Accept-Language: zh\r\n", &pages),
+            Some("zh-tw".to_string())
+        );
+        assert!(choose(&request("Accept-Language: fr\r\n"), &pages).is_none());
+        assert!(choose(&request("Accept-Language: fr, *;q=0.1\r\n"), &pages).is_some());
+
+        let data = find_variants(&dir.join("data"), &mime);
+        assert_eq!(vary(&data), vec!["Accept"]);
+        let accepted = |accept: &str| {
+            choose(&request(&format!("Accept: {}\r\n", accept)), &data)
+                .map(|v| v.content_type.clone())
+        };
+        assert_eq!(
+            accepted("text/xml, application/json;q=0.5").as_deref(),
+            Some("text/xml")
+        );
+        assert_eq!(
+            accepted("application/*").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(accepted("image/png"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,716 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::{Method, StatusCode};
+use openssl::{
+    rand::rand_bytes,
+    sha,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use reqwest::blocking::Client;
+use serde_json::{json, Map, Value};
+use url::{form_urlencoded, Url};
+
+use crate::{
+    core::{
+        config::{
+            command::{CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_error, log_info, log_notice, register_commands,
+};
+
+use super::{
+    http_auth_jwt::{
+        base64url_decode, base64url_encode, ClaimRules, KeySet, KeySource, VerifiedJwt,
+        DEFAULT_KEY_CACHE,
+    },
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+};
+
+register_commands!(
+    CommandBuilder::new("oidc_provider")
+        .is_raw_block()
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "OpenID Connect Provider")
+        .display_name("zh-tw", "OpenID Connect 提供者")
+        .desc(
+            "en",
+            "Defines an OpenID Connect identity provider that oidc sends users to for logging in"
+        )
+        .desc("zh-tw", "定義 oidc 引導使用者登入的 OpenID Connect 身分提供者")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Name")
+            .display_name("zh-tw", "名稱")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Provider name; entries are issuer url, client_id id, client_secret secret, redirect_uri uri, scope scopes, logout_uri uri, session_secret secret, session_timeout time, cookie_name name and header name claim"
+            )
+            .desc(
+                "zh-tw",
+                "提供者名稱；項目為 issuer 網址、client_id 識別碼、client_secret 密鑰、redirect_uri URI、scope 範圍、logout_uri URI、session_secret 密鑰、session_timeout 時間、cookie_name 名稱與 header 標頭 聲明"
+            )
+            .build()])
+        .build(handle_oidc_provider),
+    CommandBuilder::new("oidc")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "OpenID Connect Login")
+        .display_name("zh-tw", "OpenID Connect 登入")
+        .desc(
+            "en",
+            "Requires users to log in with an oidc_provider before requests are served; off turns off an inherited one"
+        )
+        .desc(
+            "zh-tw",
+            "要求使用者先透過 oidc_provider 登入才處理請求；off 關閉繼承來的設定"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Provider")
+            .display_name("zh-tw", "提供者")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Name of an oidc_provider, or off")
+            .desc("zh-tw", "oidc_provider 的名稱，或 off")
+            .build()])
+        .build(handle_oidc)
+);
+
+pub const DEFAULT_SCOPE: &str = "openid";
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(8 * 3600);
+pub const DEFAULT_COOKIE_NAME: &str = "blur_session";
+/// How long a login may take between leaving for the provider and coming
+/// back to the redirect URI.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Clock skew allowed when checking the ID token.
+const TOKEN_LEEWAY: Duration = Duration::from_secs(60);
+/// Claims kept in the session besides those named by `header` entries.
+const SESSION_CLAIMS: [&str; 5] = ["sub", "email", "name", "preferred_username", "groups"];
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand_bytes(&mut bytes).expect("the system random source is available");
+    base64url_encode(&bytes)
+}
+
+/// Encrypts and authenticates cookie values with AES-256-GCM; the cookie
+/// name is bound in so values cannot be swapped between cookies.
+pub struct CookieSealer {
+    key: [u8; 32],
+}
+
+impl CookieSealer {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: sha::sha256(secret),
+        }
+    }
+
+    pub fn seal(&self, name: &str, data: &[u8]) -> String {
+        let mut iv = [0; 12];
+        rand_bytes(&mut iv).expect("the system random source is available");
+        let mut tag = [0; 16];
+        let sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&iv),
+            name.as_bytes(),
+            data,
+            &mut tag,
+        )
+        .expect("AES-256-GCM is available");
+        base64url_encode(&[&iv[..], &sealed, &tag].concat())
+    }
+
+    pub fn open(&self, name: &str, value: &str) -> Option<Vec<u8>> {
+        let data = base64url_decode(value).ok()?;
+        if data.len() < 28 {
+            return None;
+        }
+        let (iv, rest) = data.split_at(12);
+        let (sealed, tag) = rest.split_at(rest.len() - 16);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(iv),
+            name.as_bytes(),
+            sealed,
+            tag,
+        )
+        .ok()
+    }
+}
+
+fn cookie<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.header("Cookie")?.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// The endpoints and keys read from the provider's discovery document.
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    end_session_endpoint: Option<String>,
+    keys: KeySet,
+}
+
+/// An identity provider and the relying party settings used with it.
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to, a path or an absolute URL.
+    pub redirect_uri: String,
+    pub scope: String,
+    pub logout_uri: Option<String>,
+    pub session_timeout: Duration,
+    pub cookie_name: String,
+    /// Request headers set from session claims, as (header, claim).
+    pub headers: Vec<(String, String)>,
+    sealer: CookieSealer,
+    discovery: Mutex<Option<Arc<Discovery>>>,
+}
+
+impl OidcProvider {
+    fn state_cookie(&self) -> String {
+        format!("{}_login", self.cookie_name)
+    }
+
+    fn redirect_path(&self) -> &str {
+        match self.redirect_uri.find("://") {
+            Some(scheme_end) => {
+                let rest = &self.redirect_uri[scheme_end + 3..];
+                rest.find('/').map(|path| &rest[path..]).unwrap_or("/")
+            }
+            None => &self.redirect_uri,
+        }
+    }
+
+    fn redirect_url(&self, req: &HttpRequest) -> String {
+        if self.redirect_uri.contains("://") {
+            return self.redirect_uri.clone();
+        }
+        let scheme = if req.is_secure() { "https" } else { "http" };
+        let host = req.header("Host").unwrap_or("localhost");
+        format!("{}://{}{}", scheme, host, self.redirect_uri)
+    }
+
+    fn discovery(&self) -> Result<Arc<Discovery>, String> {
+        let mut discovery = self
+            .discovery
+            .lock()
+            .map_err(|_| "discovery lock poisoned".to_string())?;
+        if let Some(discovery) = discovery.as_ref() {
+            return Ok(Arc::clone(discovery));
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        );
+        let document: Value = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .and_then(|client| client.get(&url).send())
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| format!("{}: {}", url, e))?;
+        let endpoint = |name: &str| {
+            document
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        let required = |name: &str| endpoint(name).ok_or(format!("{}: missing {}", url, name));
+        let loaded = Arc::new(Discovery {
+            authorization_endpoint: required("authorization_endpoint")?,
+            token_endpoint: required("token_endpoint")?,
+            end_session_endpoint: endpoint("end_session_endpoint"),
+            keys: KeySet::new(KeySource::Url(required("jwks_uri")?), DEFAULT_KEY_CACHE),
+        });
+        *discovery = Some(Arc::clone(&loaded));
+        Ok(loaded)
+    }
+
+    /// The claims of a valid, unexpired session cookie.
+    pub fn session(&self, req: &HttpRequest) -> Option<Value> {
+        let sealed = cookie(req, &self.cookie_name)?;
+        let session: Value =
+            serde_json::from_slice(&self.sealer.open(&self.cookie_name, sealed)?).ok()?;
+        if session.get("exp")?.as_u64()? <= unix_now() {
+            return None;
+        }
+        session.get("claims").cloned()
+    }
+
+    fn session_cookie(&self, claims: &Value, secure: bool) -> String {
+        let mut kept = Map::new();
+        let names = SESSION_CLAIMS
+            .iter()
+            .copied()
+            .chain(self.headers.iter().map(|(_, claim)| claim.as_str()));
+        for name in names {
+            if let Some(value) = claims.get(name) {
+                kept.insert(name.to_string(), value.clone());
+            }
+        }
+        let session = json!({
+            "claims": kept,
+            "exp": unix_now() + self.session_timeout.as_secs(),
+        });
+        let sealed = self
+            .sealer
+            .seal(&self.cookie_name, session.to_string().as_bytes());
+        set_cookie(&self.cookie_name, &sealed, None, secure)
+    }
+
+    /// Sends the user to the provider, remembering where they were going.
+    fn login(&self, req: &HttpRequest) -> Result<HttpResponse, String> {
+        let discovery = self.discovery()?;
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        let challenge = base64url_encode(&sha::sha256(verifier.as_bytes()));
+        let mut url = Url::parse(&discovery.authorization_endpoint).map_err(|e| e.to_string())?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url(req))
+            .append_pair("scope", &self.scope)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        let login = json!({
+            "state": state,
+            "nonce": nonce,
+            "verifier": verifier,
+            "return_to": req.request_uri(),
+        });
+        let name = self.state_cookie();
+        let sealed = self.sealer.seal(&name, login.to_string().as_bytes());
+        let mut resp = redirect(req, url.as_str());
+        resp.set_header(
+            "Set-Cookie",
+            &set_cookie(&name, &sealed, Some(LOGIN_TIMEOUT), req.is_secure()),
+        );
+        Ok(resp)
+    }
+
+    /// Finishes a login: checks the state, trades the code for an ID token
+    /// and starts a session.
+    fn callback(&self, req: &HttpRequest) -> Result<HttpResponse, (StatusCode, String)> {
+        let bad_request = |reason: &str| (StatusCode::BAD_REQUEST, reason.to_string());
+        let query = req
+            .path()
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or("");
+        let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        if let Some(error) = param("error") {
+            return Err(bad_request(&format!("provider returned {}", error)));
+        }
+        let name = self.state_cookie();
+        let login: Value = cookie(req, &name)
+            .and_then(|sealed| self.sealer.open(&name, sealed))
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .ok_or_else(|| bad_request("login expired or started elsewhere"))?;
+        let expected = |field: &str| login.get(field).and_then(Value::as_str).unwrap_or_default();
+        if param("state") != Some(expected("state")) {
+            return Err(bad_request("state mismatch"));
+        }
+        let code = param("code").ok_or_else(|| bad_request("missing code"))?;
+
+        let gateway = |e: String| (StatusCode::BAD_GATEWAY, e);
+        let discovery = self.discovery().map_err(gateway)?;
+        let redirect_url = self.redirect_url(req);
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &redirect_url)
+            .append_pair("client_id", &self.client_id)
+            .append_pair("code_verifier", expected("verifier"));
+        if let Some(secret) = &self.client_secret {
+            form.append_pair("client_secret", secret);
+        }
+        let tokens: Value = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .and_then(|client| {
+                client
+                    .post(&discovery.token_endpoint)
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .header("Accept", "application/json")
+                    .body(form.finish())
+                    .send()
+            })
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| gateway(format!("{}: {}", discovery.token_endpoint, e)))?;
+        let id_token = tokens
+            .get("id_token")
+            .and_then(Value::as_str)
+            .ok_or_else(|| gateway("token response has no id_token".to_string()))?;
+
+        let unauthorized = |reason: String| (StatusCode::UNAUTHORIZED, reason);
+        let jwt = discovery
+            .keys
+            .verify(id_token)
+            .map_err(gateway)?
+            .map_err(unauthorized)?;
+        let rules = ClaimRules {
+            issuer: Some(self.issuer.clone()),
+            audiences: vec![self.client_id.clone()],
+            leeway: TOKEN_LEEWAY,
+        };
+        rules.check(&jwt.claims, unix_now()).map_err(unauthorized)?;
+        if jwt.claims.get("nonce").and_then(Value::as_str) != Some(expected("nonce")) {
+            return Err(unauthorized("nonce mismatch".to_string()));
+        }
+
+        let return_to = expected("return_to");
+        // Only go back to paths on this server.
+        let return_to = if return_to.starts_with('/') && !return_to.starts_with("//") {
+            return_to
+        } else {
+            "/"
+        };
+        let mut resp = redirect(req, return_to);
+        resp.set_header(
+            "Set-Cookie",
+            &self.session_cookie(&jwt.claims, req.is_secure()),
+        );
+        resp.set_header(
+            "Set-Cookie",
+            &set_cookie(&name, "", Some(Duration::ZERO), req.is_secure()),
+        );
+        Ok(resp)
+    }
+
+    /// Ends the session and, when the provider supports it, the login there.
+    fn logout(&self, req: &HttpRequest) -> HttpResponse {
+        let target = self
+            .discovery()
+            .ok()
+            .and_then(|discovery| discovery.end_session_endpoint.clone())
+            .and_then(|endpoint| Url::parse(&endpoint).ok())
+            .map(|mut url| {
+                url.query_pairs_mut()
+                    .append_pair("client_id", &self.client_id);
+                url.to_string()
+            })
+            .unwrap_or_else(|| "/".to_string());
+        let mut resp = redirect(req, &target);
+        resp.set_header(
+            "Set-Cookie",
+            &set_cookie(&self.cookie_name, "", Some(Duration::ZERO), req.is_secure()),
+        );
+        resp
+    }
+}
+
+fn set_cookie(name: &str, value: &str, max_age: Option<Duration>, secure: bool) -> String {
+    let mut cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax", name, value);
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+    }
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+fn redirect(req: &HttpRequest, location: &str) -> HttpResponse {
+    let mut resp = HttpProcessor::create_status_response(req.version(), StatusCode::FOUND);
+    resp.set_header("Location", location);
+    resp.set_header("Cache-Control", "no-store");
+    resp
+}
+
+fn status_response(req: &HttpRequest, status: StatusCode) -> PhaseResult {
+    PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+        req.version(),
+        status,
+    )))
+}
+
+/// Lets requests with a session through, with identity headers set, and
+/// sends others to log in. Also serves the redirect and logout URIs.
+pub struct OidcPhase {
+    provider: Arc<OidcProvider>,
+}
+
+impl RequestPhase for OidcPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let provider = &self.provider;
+        // Only the server may set the identity headers.
+        for (header, _) in &provider.headers {
+            req.remove_header(header);
+        }
+        let path = req.path().split('?').next().unwrap_or_default();
+        if path == provider.redirect_path() {
+            return match provider.callback(req) {
+                Ok(resp) => PhaseResult::Respond(Box::new(resp)),
+                Err((status, reason)) => {
+                    log_info!("oidc login with \"{}\" failed: {}", provider.name, reason);
+                    status_response(req, status)
+                }
+            };
+        }
+        if provider.logout_uri.as_deref() == Some(path) {
+            return PhaseResult::Respond(Box::new(provider.logout(req)));
+        }
+
+        if let Some(claims) = provider.session(req) {
+            for (header, claim) in &provider.headers {
+                if let Some(value) = VerifiedJwt::value(&claims, claim) {
+                    req.set_header(header, &value);
+                }
+            }
+            req.extensions_mut().insert(Arc::new(VerifiedJwt {
+                header: json!({}),
+                claims,
+            }));
+            return PhaseResult::Continue;
+        }
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            return status_response(req, StatusCode::UNAUTHORIZED);
+        }
+        match provider.login(req) {
+            Ok(resp) => PhaseResult::Respond(Box::new(resp)),
+            Err(e) => {
+                log_error!("oidc provider \"{}\": {}", provider.name, e);
+                status_response(req, StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct OidcConfig {
+    /// The provider name, or `Some(None)` after `oidc off`.
+    pub provider: Option<Option<String>>,
+}
+
+impl MergeConfig for OidcConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.provider.is_none() {
+            self.provider = parent.provider.clone();
+        }
+    }
+}
+
+pub fn find_oidc_provider(http_config: &ConfigContext, name: &str) -> Option<Arc<OidcProvider>> {
+    http_config
+        .children
+        .iter()
+        .filter_map(|child| child.store.get::<OidcProvider>())
+        .find(|provider| provider.name == name)
+}
+
+/// Builds the login phase in effect for a block chain, if any.
+pub fn oidc_phase(chain: &[&ConfigContext]) -> Option<OidcPhase> {
+    let name = merged_config::<OidcConfig>(chain).provider.flatten()?;
+    let provider = chain
+        .first()
+        .and_then(|http_config| find_oidc_provider(http_config, &name));
+    match provider {
+        Some(provider) => Some(OidcPhase { provider }),
+        None => {
+            log_error!("oidc: provider \"{}\" is not defined", name);
+            None
+        }
+    }
+}
+
+pub fn handle_oidc_provider(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let name = ctx.str_arg(0)?;
+    let mut issuer = None;
+    let mut client_id = None;
+    let mut client_secret = None;
+    let mut redirect_uri = None;
+    let mut scope = DEFAULT_SCOPE.to_string();
+    let mut logout_uri = None;
+    let mut session_secret = None;
+    let mut session_timeout = DEFAULT_SESSION_TIMEOUT;
+    let mut cookie_name = DEFAULT_COOKIE_NAME.to_string();
+    let mut headers = Vec::new();
+    for entry in &ctx.raw_entries {
+        let invalid = |reason: String| ctx.invalid_entry(entry, &entry.args.join(" "), reason);
+        match entry.args.as_slice() {
+            [directive, url] if directive == "issuer" => issuer = Some(url.clone()),
+            [directive, id] if directive == "client_id" => client_id = Some(id.clone()),
+            [directive, secret] if directive == "client_secret" => {
+                client_secret = Some(secret.clone())
+            }
+            [directive, uri] if directive == "redirect_uri" || directive == "logout_uri" => {
+                if !uri.starts_with('/') && !uri.contains("://") {
+                    return Err(invalid(
+                        "must start with / or be an absolute URL".to_string(),
+                    ));
+                }
+                if directive == "redirect_uri" {
+                    redirect_uri = Some(uri.clone());
+                } else {
+                    logout_uri = Some(uri.clone());
+                }
+            }
+            [directive, scopes @ ..] if directive == "scope" && !scopes.is_empty() => {
+                scope = scopes.join(" ")
+            }
+            [directive, secret] if directive == "session_secret" => {
+                session_secret = Some(secret.clone())
+            }
+            [directive, timeout] if directive == "session_timeout" => {
+                session_timeout = parse_duration(timeout).map_err(invalid)?
+            }
+            [directive, name] if directive == "cookie_name" => cookie_name = name.clone(),
+            [directive, header, claim] if directive == "header" => {
+                headers.push((header.clone(), claim.clone()))
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "unknown entry \"{}\"",
+                    entry.args.first().map(String::as_str).unwrap_or_default()
+                )))
+            }
+        }
+    }
+    let missing = |what: &str| ctx.invalid_value(&name, format!("missing {}", what));
+    let issuer = issuer.ok_or_else(|| missing("issuer"))?;
+    let client_id = client_id.ok_or_else(|| missing("client_id"))?;
+    let redirect_uri = redirect_uri.ok_or_else(|| missing("redirect_uri"))?;
+    let sealer = match session_secret {
+        Some(secret) => CookieSealer::new(secret.as_bytes()),
+        None => {
+            log_notice!(
+                "oidc provider \"{}\" has no session_secret, sessions end when blur restarts",
+                name
+            );
+            CookieSealer::new(random_token().as_bytes())
+        }
+    };
+    ctx.store.insert(Arc::new(OidcProvider {
+        name,
+        issuer,
+        client_id,
+        client_secret,
+        redirect_uri,
+        scope,
+        logout_uri,
+        session_timeout,
+        cookie_name,
+        headers,
+        sealer,
+        discovery: Mutex::new(None),
+    }));
+    Ok(())
+}
+
+pub fn handle_oidc(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<OidcConfig>().lock() {
+        config.provider = Some((name != "off").then_some(name));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_cookie_is_sealed() {
+        let provider = OidcProvider {
+            name: "id".to_string(),
+            issuer: "https://id.example.com".to_string(),
+            client_id: "app".to_string(),
+            client_secret: None,
+            redirect_uri: "https://app.example.com/oidc/callback".to_string(),
+            scope: DEFAULT_SCOPE.to_string(),
+            logout_uri: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+            headers: vec![("X-Team".to_string(), "team".to_string())],
+            sealer: CookieSealer::new(b"secret"),
+            discovery: Mutex::new(None),
+        };
+        assert_eq!(provider.redirect_path(), "/oidc/callback");
+
+        let claims = json!({"sub": "ann", "team": "ops", "at_hash": "x"});
+        let set_cookie = provider.session_cookie(&claims, true);
+        assert!(set_cookie.ends_with("; Secure"));
+        let value = set_cookie.split(';').next().unwrap();
+        let request = |cookie: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(
+                format!(
+                    "GET / HTTP/1.1\r\nHost: a\r\nCookie: x=1; {}\r\n\r\n",
+                    cookie
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            req
+        };
+        assert_eq!(
+            provider.session(&request(value)),
+            Some(json!({"sub": "ann", "team": "ops"}))
+        );
+
+        let sealed = value.split_once('=').unwrap().1;
+        let middle = sealed.len() / 2;
+        let flipped = if &sealed[middle..=middle] == "A" {
+            "B"
+        } else {
+            "A"
+        };
+        let tampered = format!(
+            "{}={}{}{}",
+            DEFAULT_COOKIE_NAME,
+            &sealed[..middle],
+            flipped,
+            &sealed[middle + 1..]
+        );
+        assert_eq!(provider.session(&request(&tampered)), None);
+        assert_eq!(provider.sealer.open(&provider.state_cookie(), sealed), None);
+        let other = CookieSealer::new(b"other");
+        assert_eq!(other.open(DEFAULT_COOKIE_NAME, sealed), None);
+    }
+}
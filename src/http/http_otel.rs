@@ -0,0 +1,613 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex, Once},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    core::config::{
+        command::CommandBuilder, config_context::ConfigContext, config_loader::ConfigError,
+        units::parse_duration,
+    },
+    log_warn, register_commands,
+};
+
+use super::{http_proxy::ProxyTarget, http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(CommandBuilder::new("otel")
+    .is_raw_block()
+    .is_unique()
+    .allowed_parents(vec!["http".to_string()])
+    .display_name("en", "OpenTelemetry")
+    .display_name("zh-tw", "OpenTelemetry 追蹤")
+    .desc(
+        "en",
+        "Records a span for every request and exports them over OTLP/HTTP; entries are endpoint url, service_name name, sample_ratio 0-1, trace_context propagate|extract|inject|ignore, interval time and batch_size n"
+    )
+    .desc(
+        "zh-tw",
+        "為每個請求記錄 span 並以 OTLP/HTTP 匯出；項目為 endpoint 網址、service_name 名稱、sample_ratio 0-1、trace_context propagate|extract|inject|ignore、interval 時間與 batch_size 數量"
+    )
+    .build(handle_otel));
+
+const DEFAULT_SERVICE_NAME: &str = "blur";
+const DEFAULT_PATH: &str = "/v1/traces";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_BATCH_SIZE: usize = 512;
+/// Batches held while the collector is slow before new spans are dropped.
+const MAX_PENDING_BATCHES: usize = 4;
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `traceparent` version written and understood.
+const TRACEPARENT_VERSION: &str = "00";
+
+// Span kind and status code values from the OTLP protobuf definitions.
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// How W3C `traceparent` headers are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraceContext {
+    /// Continues the client's trace and passes the new span on upstream.
+    #[default]
+    Propagate,
+    /// Continues the client's trace, leaving the header as it came.
+    Extract,
+    /// Starts a new trace and passes it on upstream.
+    Inject,
+    /// Starts a new trace and leaves the header as it came.
+    Ignore,
+}
+
+impl TraceContext {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "propagate" => Ok(Self::Propagate),
+            "extract" => Ok(Self::Extract),
+            "inject" => Ok(Self::Inject),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err("trace_context must be propagate, extract, inject or ignore".to_string()),
+        }
+    }
+
+    fn extracts(self) -> bool {
+        matches!(self, Self::Propagate | Self::Extract)
+    }
+
+    fn injects(self) -> bool {
+        matches!(self, Self::Propagate | Self::Inject)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtelConfig {
+    /// The collector, reached over plain HTTP.
+    pub endpoint: ProxyTarget,
+    pub service_name: String,
+    /// Share of new traces recorded; traces started by a client follow its
+    /// sampled flag instead.
+    pub sample_ratio: f64,
+    pub trace_context: TraceContext,
+    /// Longest time a span waits before it is exported.
+    pub interval: Duration,
+    pub batch_size: usize,
+}
+
+impl OtelConfig {
+    fn new(endpoint: ProxyTarget) -> Self {
+        Self {
+            endpoint,
+            service_name: DEFAULT_SERVICE_NAME.to_string(),
+            sample_ratio: 1.0,
+            trace_context: TraceContext::default(),
+            interval: DEFAULT_INTERVAL,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
+
+/// The parent of a span, as carried by a `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    /// Parses `version-traceid-spanid-flags`, rejecting the all-zero IDs the
+    /// W3C spec marks invalid.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        if version.len() != 2 || version == "ff" {
+            return None;
+        }
+        let trace_id: [u8; 16] = decode_hex(parts.next()?)?;
+        let span_id: [u8; 8] = decode_hex(parts.next()?)?;
+        let [flags]: [u8; 1] = decode_hex(parts.next()?)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        // Later versions may append fields; version 00 has exactly four.
+        if version == TRACEPARENT_VERSION && parts.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+/// A request span being timed.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub sampled: bool,
+    start: SystemTime,
+    started: Instant,
+}
+
+impl Span {
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            TRACEPARENT_VERSION,
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            if self.sampled { "01" } else { "00" }
+        )
+    }
+}
+
+/// Records request spans and exports them in batches from a background
+/// thread, started with the first span.
+pub struct Tracer {
+    config: OtelConfig,
+    queue: Mutex<Vec<Value>>,
+    ready: Condvar,
+    exporter: Once,
+}
+
+impl Tracer {
+    pub fn new(config: OtelConfig) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(Vec::new()),
+            ready: Condvar::new(),
+            exporter: Once::new(),
+        }
+    }
+
+    /// Starts the span of a request that began at `started`, continuing the
+    /// client's trace and rewriting its `traceparent` for upstreams as
+    /// `trace_context` says.
+    pub fn start(&self, req: &mut HttpRequest, started: Instant) -> Span {
+        let mode = self.config.trace_context;
+        let parent = mode
+            .extracts()
+            .then(|| req.header("traceparent").and_then(TraceParent::parse))
+            .flatten();
+        let span = Span {
+            trace_id: parent.map_or_else(random_bytes, |parent| parent.trace_id),
+            span_id: random_bytes(),
+            parent_span_id: parent.map(|parent| parent.span_id),
+            sampled: parent.map_or_else(|| self.sample(), |parent| parent.sampled),
+            start: SystemTime::now() - started.elapsed(),
+            started,
+        };
+        if mode.injects() {
+            req.set_header("traceparent", &span.traceparent());
+        }
+        span
+    }
+
+    /// Ends the span once the response has been sent and queues it for
+    /// export; `route` is the location that served the request.
+    pub fn finish(
+        self: &Arc<Self>,
+        span: Span,
+        req: &HttpRequest,
+        resp: &HttpResponse,
+        route: Option<&str>,
+    ) {
+        if !span.sampled {
+            return;
+        }
+        let end = span.start + span.started.elapsed();
+        let entry = span_json(&span, end, req, resp, route);
+
+        self.exporter.call_once(|| self.spawn_exporter());
+        let Ok(mut queue) = self.queue.lock() else {
+            return;
+        };
+        if queue.len() >= self.config.batch_size * MAX_PENDING_BATCHES {
+            return;
+        }
+        queue.push(entry);
+        if queue.len() >= self.config.batch_size {
+            self.ready.notify_one();
+        }
+    }
+
+    fn sample(&self) -> bool {
+        let ratio = self.config.sample_ratio;
+        if ratio >= 1.0 {
+            return true;
+        }
+        let roll = u64::from_le_bytes(random_bytes()) as f64 / u64::MAX as f64;
+        roll < ratio
+    }
+
+    fn spawn_exporter(self: &Arc<Self>) {
+        let weak = Arc::downgrade(self);
+        thread::spawn(move || {
+            while let Some(tracer) = weak.upgrade() {
+                let batch = tracer.next_batch();
+                if batch.is_empty() {
+                    continue;
+                }
+                if let Err(e) = tracer.export(batch) {
+                    log_warn!(
+                        "cannot export spans to {}: {}",
+                        tracer.config.endpoint.authority(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    /// Waits for a full batch or the export interval, whichever comes first.
+    fn next_batch(&self) -> Vec<Value> {
+        let Ok(queue) = self.queue.lock() else {
+            thread::sleep(self.config.interval);
+            return Vec::new();
+        };
+        let batch_size = self.config.batch_size;
+        let Ok((mut queue, _)) =
+            self.ready
+                .wait_timeout_while(queue, self.config.interval, |queue| {
+                    queue.len() < batch_size
+                })
+        else {
+            return Vec::new();
+        };
+        let len = queue.len().min(batch_size);
+        queue.drain(..len).collect()
+    }
+
+    fn export(&self, spans: Vec<Value>) -> io::Result<()> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [string_attribute("service.name", &self.config.service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "blur", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        })
+        .to_string();
+
+        let endpoint = &self.config.endpoint;
+        let addr = (endpoint.host.as_str(), endpoint.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "collector has no addresses"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            endpoint.uri.as_deref().unwrap_or(DEFAULT_PATH),
+            endpoint.authority(),
+            body.len()
+        )?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some(status) => Err(io::Error::other(format!("collector answered {}", status))),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid collector response",
+            )),
+        }
+    }
+}
+
+fn span_json(
+    span: &Span,
+    end: SystemTime,
+    req: &HttpRequest,
+    resp: &HttpResponse,
+    route: Option<&str>,
+) -> Value {
+    let path = req.path().split('?').next().unwrap_or_default();
+    let mut attributes = vec![
+        string_attribute("http.request.method", req.method().as_str()),
+        string_attribute("url.path", path),
+    ];
+    if let Some(route) = route {
+        attributes.push(string_attribute("http.route", route));
+    }
+    if let Some(status) = resp.status() {
+        attributes.push(int_attribute("http.response.status_code", status.into()));
+    }
+    if let Some(addr) = req.remote_addr() {
+        attributes.push(string_attribute("client.address", &addr.ip().to_string()));
+    }
+    if let Some(host) = req.header("Host") {
+        attributes.push(string_attribute("server.address", host));
+    }
+    if let Some(agent) = req.header("User-Agent") {
+        attributes.push(string_attribute("user_agent.original", agent));
+    }
+    if let Some(upstream) = resp.upstream.last() {
+        attributes.push(string_attribute("upstream.address", &upstream.address));
+        attributes.push(int_attribute(
+            "upstream.attempts",
+            resp.upstream.len() as i64,
+        ));
+        if let Some(connect) = upstream.connect {
+            attributes.push(double_attribute(
+                "upstream.connect_time",
+                connect.as_secs_f64(),
+            ));
+        }
+        if let Some(header) = upstream.header {
+            attributes.push(double_attribute(
+                "upstream.header_time",
+                header.as_secs_f64(),
+            ));
+        }
+        if let Some(response) = upstream.response_time() {
+            attributes.push(double_attribute(
+                "upstream.response_time",
+                response.as_secs_f64(),
+            ));
+        }
+    }
+
+    let name = match route {
+        Some(route) => format!("{} {}", req.method(), route),
+        None => req.method().to_string(),
+    };
+    let mut entry = json!({
+        "traceId": encode_hex(&span.trace_id),
+        "spanId": encode_hex(&span.span_id),
+        "name": name,
+        "kind": SPAN_KIND_SERVER,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(end),
+        "attributes": attributes,
+    });
+    if let Some(parent) = span.parent_span_id {
+        entry["parentSpanId"] = Value::String(encode_hex(&parent));
+    }
+    if resp.status().is_some_and(|status| status >= 500) {
+        entry["status"] = json!({ "code": STATUS_CODE_ERROR });
+    }
+    entry
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// OTLP's JSON encoding writes 64-bit integers as strings.
+fn int_attribute(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn double_attribute(key: &str, value: f64) -> Value {
+    json!({ "key": key, "value": { "doubleValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        for chunk in bytes.chunks_mut(8) {
+            let random = RandomState::new().build_hasher().finish().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+    bytes
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2
+        || !value
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Finds the tracer set up by the `otel` block of the http block.
+pub fn find_tracer(http_config: &ConfigContext) -> Option<Arc<Tracer>> {
+    http_config
+        .children
+        .iter()
+        .find_map(|child| child.store.get::<Tracer>())
+}
+
+pub fn handle_otel(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let mut endpoint = None;
+    let mut settings = Vec::new();
+    for entry in &ctx.raw_entries {
+        let invalid = |reason: String| ctx.invalid_entry(entry, &entry.args.join(" "), reason);
+        match entry.args.as_slice() {
+            [name, url] if name == "endpoint" => {
+                let target = ProxyTarget::parse(url).map_err(invalid)?;
+                if target.ssl {
+                    return Err(invalid("only http:// collectors are supported".to_string()));
+                }
+                endpoint = Some(target);
+            }
+            [name, value] => settings.push((entry, name, value)),
+            _ => return Err(invalid("expected a name and a value".to_string())),
+        }
+    }
+    let Some(endpoint) = endpoint else {
+        return Err(ctx.invalid_value("otel", "endpoint is required"));
+    };
+
+    let mut config = OtelConfig::new(endpoint);
+    for (entry, name, value) in settings {
+        let invalid = |reason: String| ctx.invalid_entry(entry, value, reason);
+        match name.as_str() {
+            "service_name" => config.service_name = value.clone(),
+            "sample_ratio" => {
+                config.sample_ratio = value
+                    .parse()
+                    .ok()
+                    .filter(|ratio| (0.0..=1.0).contains(ratio))
+                    .ok_or_else(|| invalid("sample_ratio must be between 0 and 1".to_string()))?
+            }
+            "trace_context" => {
+                config.trace_context = TraceContext::parse(value).map_err(invalid)?
+            }
+            "interval" => {
+                config.interval = parse_duration(value)
+                    .ok()
+                    .filter(|interval| !interval.is_zero())
+                    .ok_or_else(|| invalid("invalid interval".to_string()))?
+            }
+            "batch_size" => {
+                config.batch_size = value
+                    .parse()
+                    .ok()
+                    .filter(|size| *size > 0)
+                    .ok_or_else(|| invalid("invalid batch size".to_string()))?
+            }
+            _ => {
+                return Err(ctx.invalid_entry(
+                    entry,
+                    name,
+                    format!("unknown otel setting \"{}\"", name),
+                ))
+            }
+        }
+    }
+    ctx.store.insert(Arc::new(Tracer::new(config)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener};
+
+    use http::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn test_spans_continue_client_trace_and_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let endpoint = ProxyTarget::parse(&format!("http://127.0.0.1:{}/v1/traces", port)).unwrap();
+        let mut config = OtelConfig::new(endpoint);
+        config.interval = Duration::from_millis(20);
+        let tracer = Arc::new(Tracer::new(config));
+
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut req = HttpRequest::new();
+        req.parse(
+            format!(
+                "GET /api/users?id=1 HTTP/1.1\r\nHost: example.com\r\ntraceparent: {}\r\n\r\n",
+                incoming
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let span = tracer.start(&mut req, Instant::now());
+        assert_eq!(
+            encode_hex(&span.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        let forwarded = TraceParent::parse(req.header("traceparent").unwrap()).unwrap();
+        assert_eq!(forwarded.trace_id, span.trace_id);
+        assert_eq!(forwarded.span_id, span.span_id);
+        assert!(forwarded.sampled);
+
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), StatusCode::BAD_GATEWAY);
+        tracer.finish(span, &req, &resp, Some("/api"));
+
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        let body = loop {
+            let n = conn.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&received).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if body.len() >= len {
+                    assert!(head.starts_with("POST /v1/traces HTTP/1.1"));
+                    break body.to_string();
+                }
+            }
+        };
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+
+        let export: Value = serde_json::from_str(&body).unwrap();
+        let span = &export["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["name"], "GET /api");
+        assert_eq!(span["status"]["code"], 2);
+        assert!(span["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&int_attribute("http.response.status_code", 502)));
+
+        assert!(
+            TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none()
+        );
+    }
+}
@@ -0,0 +1,1824 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, HttpProcessor, LocationModifier, LocationPattern},
+    },
+    log_error, log_warn, register_commands,
+};
+
+use super::{
+    http_proxy_cache::{proxy_cache, CacheOrigin, ProxyCache},
+    http_proxy_ssl::{ProxySslConfig, UpstreamTls},
+    http_request::{normalize_target, HttpRequest},
+    http_resolver::{lookup, resolver, Resolver},
+    http_response::{HttpResponse, StreamBody, UpgradedConnection},
+    http_slice::{slice, SliceFetch},
+    http_upstream::{
+        find_upstream, split_host_port, ActivePeer, Upstream, UpstreamStream, UpstreamTiming,
+    },
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("proxy_pass")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "Proxy Pass")
+        .display_name("zh-tw", "反向代理")
+        .desc(
+            "en",
+            "Forwards requests to an upstream HTTP server and relays its response"
+        )
+        .desc("zh-tw", "將請求轉送到上游 HTTP 伺服器並回傳其回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "URL")
+            .display_name("zh-tw", "網址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Upstream URL, e.g. http://127.0.0.1:9000; a path after the address replaces the matched location prefix"
+            )
+            .desc(
+                "zh-tw",
+                "上游網址，例如 http://127.0.0.1:9000；位址後的路徑會取代符合的 location 前綴"
+            )
+            .build()])
+        .build(handle_proxy_pass),
+    CommandBuilder::new("proxy_next_upstream")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Next Upstream")
+        .display_name("zh-tw", "改試下一台上游")
+        .desc(
+            "en",
+            "Sets the failures after which a request is retried on the next upstream server"
+        )
+        .desc("zh-tw", "設定發生哪些失敗時改將請求轉送到下一台上游伺服器")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Conditions")
+            .display_name("zh-tw", "條件")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Any of error, timeout, invalid_header, http_500, http_502, http_503, http_504, http_403, http_404, http_429 and non_idempotent, or off; defaults to error timeout"
+            )
+            .desc(
+                "zh-tw",
+                "error、timeout、invalid_header、http_500、http_502、http_503、http_504、http_403、http_404、http_429 與 non_idempotent 的任意組合，或 off；預設為 error timeout"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_proxy_next_upstream),
+    CommandBuilder::new("proxy_next_upstream_tries")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Next Upstream Tries")
+        .display_name("zh-tw", "上游嘗試次數")
+        .desc(
+            "en",
+            "Limits how many upstream servers a request may be sent to"
+        )
+        .desc("zh-tw", "限制一個請求最多可轉送到幾台上游伺服器")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Tries")
+            .display_name("zh-tw", "次數")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "Number of tries, 0 (the default) for no limit")
+            .desc("zh-tw", "嘗試次數，0（預設）表示不限制")
+            .build()])
+        .build(handle_proxy_next_upstream_tries),
+    CommandBuilder::new("proxy_next_upstream_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Next Upstream Timeout")
+        .display_name("zh-tw", "上游重試時限")
+        .desc(
+            "en",
+            "Limits how long a request may keep moving on to other upstream servers"
+        )
+        .desc("zh-tw", "限制一個請求可持續改試其他上游伺服器的時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Time limit, 0 (the default) for no limit")
+            .desc("zh-tw", "時間限制，0（預設）表示不限制")
+            .build()])
+        .build(handle_proxy_next_upstream_timeout),
+    CommandBuilder::new("proxy_connect_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Connect Timeout")
+        .display_name("zh-tw", "上游連線逾時")
+        .desc("en", "Sets how long connecting to an upstream server may take")
+        .desc("zh-tw", "設定連線到上游伺服器的逾時時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Timeout, defaults to 60s")
+            .desc("zh-tw", "逾時時間，預設為 60s")
+            .build()])
+        .build(handle_proxy_connect_timeout),
+    CommandBuilder::new("proxy_read_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Read Timeout")
+        .display_name("zh-tw", "上游讀取逾時")
+        .desc("en", "Sets how long to wait between two reads from an upstream server")
+        .desc("zh-tw", "設定兩次從上游伺服器讀取之間的逾時時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Timeout, defaults to 60s")
+            .desc("zh-tw", "逾時時間，預設為 60s")
+            .build()])
+        .build(handle_proxy_read_timeout),
+    CommandBuilder::new("proxy_send_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Send Timeout")
+        .display_name("zh-tw", "上游傳送逾時")
+        .desc("en", "Sets how long to wait between two writes to an upstream server")
+        .desc("zh-tw", "設定兩次寫入上游伺服器之間的逾時時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .arg_type(ArgType::Duration)
+            .is_required(true)
+            .default("")
+            .desc("en", "Timeout, defaults to 60s")
+            .desc("zh-tw", "逾時時間，預設為 60s")
+            .build()])
+        .build(handle_proxy_send_timeout),
+    CommandBuilder::new("proxy_buffering")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Buffering")
+        .display_name("zh-tw", "上游回應緩衝")
+        .desc(
+            "en",
+            "Reads upstream responses into memory before relaying them, freeing the upstream from slow clients"
+        )
+        .desc(
+            "zh-tw",
+            "先將上游回應讀入記憶體再轉送，使上游不必等待緩慢的客戶端"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_proxy_buffering),
+    CommandBuilder::new("proxy_buffers")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Buffers")
+        .display_name("zh-tw", "上游回應緩衝區")
+        .desc(
+            "en",
+            "Sets the number and size of the buffers an upstream response is read into"
+        )
+        .desc("zh-tw", "設定用來讀取上游回應的緩衝區數量與大小")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Number")
+                .display_name("zh-tw", "數量")
+                .arg_type(ArgType::Number)
+                .is_required(true)
+                .default("")
+                .desc("en", "Number of buffers, defaults to 8")
+                .desc("zh-tw", "緩衝區數量，預設為 8")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Size")
+                .display_name("zh-tw", "大小")
+                .arg_type(ArgType::Size)
+                .is_required(true)
+                .default("")
+                .desc("en", "Size of each buffer, defaults to 8k")
+                .desc("zh-tw", "每個緩衝區的大小，預設為 8k")
+                .build(),
+        ])
+        .build(handle_proxy_buffers),
+    CommandBuilder::new("proxy_set_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Set Header")
+        .display_name("zh-tw", "設定上游請求標頭")
+        .desc(
+            "en",
+            "Adds, replaces or removes a header of requests sent upstream"
+        )
+        .desc("zh-tw", "新增、取代或移除轉送到上游的請求標頭")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Header")
+                .display_name("zh-tw", "標頭")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Header name")
+                .desc("zh-tw", "標頭名稱")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Value")
+                .display_name("zh-tw", "值")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "Header value, may contain variables; an empty value removes the header"
+                )
+                .desc("zh-tw", "標頭值，可包含變數；空值會移除該標頭")
+                .build(),
+        ])
+        .build(handle_proxy_set_header),
+    CommandBuilder::new("proxy_hide_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Hide Header")
+        .display_name("zh-tw", "隱藏上游回應標頭")
+        .desc("en", "Keeps an upstream response header from being relayed to the client")
+        .desc("zh-tw", "不將指定的上游回應標頭轉送給客戶端")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Header")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Header name")
+            .desc("zh-tw", "標頭名稱")
+            .build()])
+        .build(handle_proxy_hide_header),
+    CommandBuilder::new("proxy_pass_header")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Pass Header")
+        .display_name("zh-tw", "轉送上游回應標頭")
+        .desc("en", "Relays an upstream response header that is hidden by default, such as X-Accel-*")
+        .desc("zh-tw", "轉送預設會被隱藏的上游回應標頭，例如 X-Accel-*")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Header")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Header name")
+            .desc("zh-tw", "標頭名稱")
+            .build()])
+        .build(handle_proxy_pass_header),
+);
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_BUFFERS: (u64, u64) = (8, 8 * 1024);
+
+/// Request headers that only apply to a single connection and are never
+/// forwarded, in either direction.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Characters re-encoded when a normalized path is sent upstream. `%` is kept
+/// as is so sequences that normalization left encoded stay intact.
+const PATH_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyTarget {
+    pub host: String,
+    pub port: u16,
+    /// Replaces the matched location prefix when set.
+    pub uri: Option<String>,
+    /// Whether the upstream is reached over TLS.
+    pub ssl: bool,
+}
+
+impl ProxyTarget {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (rest, ssl) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+            (Some(rest), _) => (rest, false),
+            (_, Some(rest)) => (rest, true),
+            _ => return Err("only http:// and https:// upstreams are supported".to_string()),
+        };
+        let (authority, uri) = match rest.find('/') {
+            Some(start) => (&rest[..start], Some(rest[start..].to_string())),
+            None => (rest, None),
+        };
+        let (host, port) = split_host_port(authority, if ssl { 443 } else { 80 })?;
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            uri,
+            ssl,
+        })
+    }
+
+    /// The value sent as the upstream `Host` header.
+    pub fn authority(&self) -> String {
+        if self.port == if self.ssl { 443 } else { 80 } {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// The outcomes of an upstream attempt after which `proxy_next_upstream`
+/// moves the request on to the next server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NextUpstream {
+    pub error: bool,
+    pub timeout: bool,
+    pub invalid_header: bool,
+    pub statuses: Vec<u16>,
+    /// Also retries methods such as POST once the request has been sent.
+    pub non_idempotent: bool,
+}
+
+impl Default for NextUpstream {
+    fn default() -> Self {
+        Self {
+            error: true,
+            timeout: true,
+            invalid_header: false,
+            statuses: Vec::new(),
+            non_idempotent: false,
+        }
+    }
+}
+
+impl NextUpstream {
+    pub fn off() -> Self {
+        Self {
+            error: false,
+            timeout: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        if args == ["off"] {
+            return Ok(Self::off());
+        }
+        let mut next = Self::off();
+        for arg in args {
+            match arg.as_str() {
+                "error" => next.error = true,
+                "timeout" => next.timeout = true,
+                "invalid_header" => next.invalid_header = true,
+                "non_idempotent" => next.non_idempotent = true,
+                "http_500" | "http_502" | "http_503" | "http_504" | "http_403" | "http_404"
+                | "http_429" => next.statuses.push(arg[5..].parse().unwrap_or_default()),
+                _ => return Err(format!("unknown condition \"{}\"", arg)),
+            }
+        }
+        Ok(next)
+    }
+
+    /// Whether `result` warrants trying another server. `sent` tells whether
+    /// the request reached the server, after which non-idempotent requests
+    /// are only retried when allowed.
+    pub fn retries(
+        &self,
+        result: &io::Result<HttpResponse>,
+        sent: bool,
+        req: &HttpRequest,
+    ) -> bool {
+        let idempotent = !matches!(req.method().as_str(), "POST" | "PATCH" | "LOCK");
+        if sent && !idempotent && !self.non_idempotent {
+            return false;
+        }
+        match result {
+            Ok(resp) => resp
+                .status()
+                .is_some_and(|status| self.statuses.contains(&status)),
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => self.timeout,
+                io::ErrorKind::InvalidData => self.invalid_header,
+                _ => self.error,
+            },
+        }
+    }
+
+    /// Whether `result` counts against the server's `max_fails`: any error,
+    /// and the listed statuses other than 403 and 404.
+    pub fn is_failure(&self, result: &io::Result<HttpResponse>) -> bool {
+        match result {
+            Ok(resp) => resp.status().is_some_and(|status| {
+                status != 403 && status != 404 && self.statuses.contains(&status)
+            }),
+            Err(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProxyConfig {
+    pub pass: Option<ProxyTarget>,
+    pub next_upstream: Option<NextUpstream>,
+    pub next_upstream_tries: Option<u32>,
+    pub next_upstream_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub send_timeout: Option<Duration>,
+    pub buffering: Option<bool>,
+    /// Number and size of the response buffers.
+    pub buffers: Option<(u64, u64)>,
+    /// Like the lists below, only inherited by blocks that set none.
+    pub set_headers: Option<Vec<(String, VarTemplate)>>,
+    pub hide_headers: Option<Vec<String>>,
+    pub pass_headers: Option<Vec<String>>,
+}
+
+impl MergeConfig for ProxyConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.next_upstream = self
+            .next_upstream
+            .take()
+            .or_else(|| parent.next_upstream.clone());
+        self.next_upstream_tries = self.next_upstream_tries.or(parent.next_upstream_tries);
+        self.next_upstream_timeout = self.next_upstream_timeout.or(parent.next_upstream_timeout);
+        self.connect_timeout = self.connect_timeout.or(parent.connect_timeout);
+        self.read_timeout = self.read_timeout.or(parent.read_timeout);
+        self.send_timeout = self.send_timeout.or(parent.send_timeout);
+        self.buffering = self.buffering.or(parent.buffering);
+        self.buffers = self.buffers.or(parent.buffers);
+        self.set_headers = self
+            .set_headers
+            .take()
+            .or_else(|| parent.set_headers.clone());
+        self.hide_headers = self
+            .hide_headers
+            .take()
+            .or_else(|| parent.hide_headers.clone());
+        self.pass_headers = self
+            .pass_headers
+            .take()
+            .or_else(|| parent.pass_headers.clone());
+    }
+}
+
+/// Forwards requests of one location to its upstream servers.
+pub struct Proxy {
+    target: ProxyTarget,
+    upstream: Arc<Upstream>,
+    /// Location prefix replaced by the target URI.
+    location_prefix: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    send_timeout: Duration,
+    /// Whether responses are read ahead of the client, unless an upstream
+    /// overrides it with `X-Accel-Buffering`.
+    buffering: bool,
+    /// Bytes of a response read ahead of the client when buffering.
+    buffer_size: u64,
+    set_headers: Vec<(String, VarTemplate)>,
+    hide_headers: Vec<String>,
+    pass_headers: Vec<String>,
+    next_upstream: NextUpstream,
+    next_upstream_tries: u32,
+    next_upstream_timeout: Duration,
+    /// Set for https:// upstreams.
+    tls: Option<UpstreamTls>,
+    /// Resolves upstream host names instead of the system resolver.
+    resolver: Option<Arc<Resolver>>,
+    cache: Option<ProxyCache>,
+}
+
+impl Proxy {
+    pub fn new(target: ProxyTarget, pattern: Option<&LocationPattern>) -> Self {
+        let location_prefix = pattern
+            .filter(|pattern| {
+                matches!(
+                    pattern.modifier,
+                    LocationModifier::Prefix
+                        | LocationModifier::PreferPrefix
+                        | LocationModifier::Exact
+                )
+            })
+            .map(|pattern| pattern.path.clone());
+        Self {
+            upstream: Arc::new(Upstream::single(&target.host, target.port)),
+            target,
+            location_prefix,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            buffering: false,
+            buffer_size: 0,
+            set_headers: Vec::new(),
+            hide_headers: Vec::new(),
+            pass_headers: Vec::new(),
+            next_upstream: NextUpstream::default(),
+            next_upstream_tries: 0,
+            next_upstream_timeout: Duration::ZERO,
+            tls: None,
+            resolver: None,
+            cache: None,
+        }
+    }
+
+    /// Balances requests across `upstream` instead of connecting to the
+    /// target address directly.
+    pub fn with_upstream(mut self, upstream: Arc<Upstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    pub fn with_timeouts(mut self, connect: Duration, read: Duration, send: Duration) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self.send_timeout = send;
+        self
+    }
+
+    /// Reads up to `buffer_size` bytes of each response before relaying it
+    /// when `enabled`, so a small response frees the upstream connection
+    /// right away.
+    pub fn with_buffering(mut self, enabled: bool, buffer_size: u64) -> Self {
+        self.buffering = enabled;
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets the headers added to upstream requests, and the upstream response
+    /// headers hidden from or passed to the client.
+    pub fn with_headers(
+        mut self,
+        set: Vec<(String, VarTemplate)>,
+        hide: Vec<String>,
+        pass: Vec<String>,
+    ) -> Self {
+        self.set_headers = set;
+        self.hide_headers = hide;
+        self.pass_headers = pass;
+        self
+    }
+
+    /// Sets when a request moves on to the next server, how many servers it
+    /// may try and for how long; zero disables either limit.
+    pub fn with_next_upstream(
+        mut self,
+        next_upstream: NextUpstream,
+        tries: u32,
+        timeout: Duration,
+    ) -> Self {
+        self.next_upstream = next_upstream;
+        self.next_upstream_tries = tries;
+        self.next_upstream_timeout = timeout;
+        self
+    }
+
+    /// Connects to the servers over TLS.
+    pub fn with_tls(mut self, tls: UpstreamTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Serves cacheable responses from `cache` instead of the upstream.
+    pub fn with_cache(mut self, cache: ProxyCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Forwards `req` to the upstream, bypassing the cache.
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        self.fetch(req).unwrap_or_else(|e| self.failure(req, e))
+    }
+
+    /// Answers `req` through the cache when one is set.
+    pub fn serve(self: &Arc<Self>, req: &HttpRequest) -> HttpResponse {
+        match &self.cache {
+            Some(cache) => {
+                let origin: Arc<dyn CacheOrigin> = Arc::clone(self) as _;
+                cache.handle(req, &origin)
+            }
+            None => self.handle(req),
+        }
+    }
+
+    /// Tries the servers of the upstream in turn until one answers with a
+    /// response that `proxy_next_upstream` accepts or the retry limits run out.
+    fn forward(&self, req: &HttpRequest) -> io::Result<HttpResponse> {
+        let mut timings = Vec::new();
+        let result = self.try_servers(req, &mut timings);
+        result.map(|mut resp| {
+            resp.upstream = timings;
+            resp
+        })
+    }
+
+    fn try_servers(
+        &self,
+        req: &HttpRequest,
+        timings: &mut Vec<UpstreamTiming>,
+    ) -> io::Result<HttpResponse> {
+        let started = Instant::now();
+        let mut tried = Vec::new();
+        let mut last = None;
+        loop {
+            let Some(peer) = self.upstream.select(req, &tried) else {
+                return last.unwrap_or_else(|| {
+                    Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        "no live upstreams",
+                    ))
+                });
+            };
+            let index = peer.index();
+            let address = peer.server().address();
+            tried.push(index);
+
+            let (result, sent, timing) = self.attempt(peer, req);
+            timings.push(timing);
+            self.upstream
+                .report(index, !self.next_upstream.is_failure(&result));
+
+            let tries_left =
+                self.next_upstream_tries == 0 || tried.len() < self.next_upstream_tries as usize;
+            let time_left = self.next_upstream_timeout.is_zero()
+                || started.elapsed() < self.next_upstream_timeout;
+            if !(tries_left && time_left && self.next_upstream.retries(&result, sent, req)) {
+                return result.map(|mut resp| {
+                    if let Some(cookie) = self.upstream.sticky_cookie(req, index) {
+                        resp.set_header("Set-Cookie", &cookie);
+                    }
+                    resp
+                });
+            }
+            match &result {
+                Ok(resp) => log_warn!(
+                    "upstream server {} returned {} for \"{}\", trying next",
+                    address,
+                    resp.status().unwrap_or_default(),
+                    req.path()
+                ),
+                Err(e) => log_warn!(
+                    "upstream server {} failed for \"{}\": {}, trying next",
+                    address,
+                    req.path(),
+                    e
+                ),
+            }
+            last = Some(result);
+        }
+    }
+
+    /// Sends the request to one server, over an idle keepalive connection
+    /// when there is one, and relays its response. Also tells whether the
+    /// request reached the server, and how long the steps took.
+    fn attempt(
+        &self,
+        peer: ActivePeer,
+        req: &HttpRequest,
+    ) -> (io::Result<HttpResponse>, bool, UpstreamTiming) {
+        let mut timing = UpstreamTiming::start(peer.server().address());
+        let mut idle = peer.idle_connection();
+        let (result, sent) = loop {
+            let (stream, requests, reused) = match idle.take() {
+                Some(conn) => (conn.stream, conn.requests, true),
+                None => match self.connect(&peer) {
+                    Ok(stream) => (stream, 0, false),
+                    Err(e) => break (Err(e), false),
+                },
+            };
+            timing.connect = Some(if reused {
+                Duration::ZERO
+            } else {
+                timing.elapsed()
+            });
+            match self.send(stream, req) {
+                Ok((reader, head)) => {
+                    timing.header = Some(timing.elapsed());
+                    break (
+                        self.relay(peer, reader, head, requests + 1, req, &timing),
+                        true,
+                    );
+                }
+                // The server may have closed the idle connection meanwhile.
+                Err(_) if reused => idle = peer.idle_connection(),
+                Err(e) => break (Err(e), true),
+            }
+        };
+        // A streamed body finishes the attempt once it has been relayed.
+        if !matches!(&result, Ok(resp) if resp.stream.is_some()) {
+            timing.finish();
+        }
+        (result, sent, timing)
+    }
+
+    fn send(
+        &self,
+        mut stream: UpstreamStream,
+        req: &HttpRequest,
+    ) -> io::Result<(BufReader<UpstreamStream>, ResponseHead)> {
+        stream.socket().set_read_timeout(Some(self.read_timeout))?;
+        stream.socket().set_write_timeout(Some(self.send_timeout))?;
+        stream.write_all(&self.build_request(req))?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let head = read_response_head(&mut reader)?;
+        Ok((reader, head))
+    }
+
+    /// Builds the response from the upstream head, streaming the body. The
+    /// server stays counted as active until the body has been relayed, after
+    /// which a persistent connection goes back to the keepalive pool.
+    fn relay(
+        &self,
+        peer: ActivePeer,
+        reader: BufReader<UpstreamStream>,
+        head: ResponseHead,
+        requests: u32,
+        req: &HttpRequest,
+        timing: &UpstreamTiming,
+    ) -> io::Result<HttpResponse> {
+        let status = StatusCode::from_u16(head.status)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid upstream status"))?;
+
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), status);
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut buffering = self.buffering;
+        for (name, value) in &head.headers {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<u64>().ok();
+            } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                chunked = value.to_ascii_lowercase().contains("chunked");
+            } else if name.eq_ignore_ascii_case("X-Accel-Redirect") && self.is_hidden(name) {
+                resp.accel_redirect = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("X-Accel-Buffering") && self.is_hidden(name) {
+                match value.trim() {
+                    v if v.eq_ignore_ascii_case("yes") => buffering = true,
+                    v if v.eq_ignore_ascii_case("no") => buffering = false,
+                    _ => {}
+                }
+            }
+            if !is_hop_by_hop(name)
+                && !name.eq_ignore_ascii_case("Content-Length")
+                && !self.is_hidden(name)
+            {
+                resp.set_header(name, value);
+            }
+        }
+
+        if status == StatusCode::SWITCHING_PROTOCOLS {
+            return self.upgrade(resp, peer, reader, &head, req);
+        }
+        // The body of a redirected response is never sent, so the
+        // connection is closed rather than drained.
+        if resp.accel_redirect.is_some() {
+            return Ok(resp);
+        }
+
+        let reuse = (peer.is_keepalive() && head.persistent).then_some(requests);
+        let bodiless = *req.method() == http::Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED;
+        if bodiless {
+            if let Some(len) = content_length {
+                resp.set_header("Content-Length", &len.to_string());
+            }
+            if let (Some(requests), Some(stream)) = (reuse, into_stream(reader)) {
+                peer.keep_connection(stream, requests);
+            }
+        } else if chunked {
+            let framing = BodyFraming::Chunked(ChunkedReader::new(reader));
+            let body = PeerBody::new(framing, peer, reuse, timing.clone());
+            self.set_body(&mut resp, body, None, buffering)?;
+        } else if let Some(len) = content_length {
+            let framing = BodyFraming::Length(reader.take(len));
+            let body = PeerBody::new(framing, peer, reuse, timing.clone());
+            self.set_body(&mut resp, body, Some(len), buffering)?;
+        } else {
+            let framing = BodyFraming::UntilClose(reader);
+            let body = PeerBody::new(framing, peer, None, timing.clone());
+            self.set_body(&mut resp, body, None, buffering)?;
+        }
+        Ok(resp)
+    }
+
+    /// Completes a `101 Switching Protocols` answer to an upgrade request by
+    /// handing the upstream connection over to the server, which tunnels it
+    /// to the client.
+    fn upgrade(
+        &self,
+        mut resp: HttpResponse,
+        peer: ActivePeer,
+        reader: BufReader<UpstreamStream>,
+        head: &ResponseHead,
+        req: &HttpRequest,
+    ) -> io::Result<HttpResponse> {
+        let protocol = head
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Upgrade"))
+            .map(|(_, value)| value.clone());
+        let (Some(protocol), Some(_)) = (protocol, upgrade_protocol(req)) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "upstream switched protocols without an upgrade request",
+            ));
+        };
+        resp.set_header("Connection", "upgrade");
+        resp.set_header("Upgrade", &protocol);
+
+        let buffered = reader.buffer().to_vec();
+        let stream = reader.into_inner();
+        stream.socket().set_read_timeout(None)?;
+        stream.socket().set_write_timeout(None)?;
+        resp.set_upgrade(UpgradedConnection::new(
+            stream,
+            buffered,
+            self.read_timeout,
+            peer,
+        ));
+        Ok(resp)
+    }
+
+    /// Streams `body` to the client. With buffering, a body that fits in the
+    /// buffers is read whole first; a larger one is relayed once they fill.
+    fn set_body(
+        &self,
+        resp: &mut HttpResponse,
+        mut body: PeerBody,
+        len: Option<u64>,
+        buffering: bool,
+    ) -> io::Result<()> {
+        if !buffering {
+            resp.set_body_stream(StreamBody::new(body, len));
+            return Ok(());
+        }
+        let buffer_size = self.buffer_size;
+        let mut buffered = Vec::new();
+        (&mut body)
+            .take(buffer_size + 1)
+            .read_to_end(&mut buffered)?;
+        if buffered.len() as u64 <= buffer_size {
+            resp.set_body_bytes(&buffered);
+        } else {
+            resp.set_body_stream(StreamBody::new(io::Cursor::new(buffered).chain(body), len));
+        }
+        Ok(())
+    }
+
+    /// Opens a connection to the server, completing the TLS handshake
+    /// within the connect timeout when the upstream is reached over TLS.
+    fn connect(&self, peer: &ActivePeer) -> io::Result<UpstreamStream> {
+        let server = peer.server();
+        let mut last_error = None;
+        for addr in lookup(self.resolver.as_ref(), &server.host, server.port)? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => {
+                    let Some(tls) = &self.tls else {
+                        return Ok(UpstreamStream::Plain(stream));
+                    };
+                    stream.set_read_timeout(Some(self.connect_timeout))?;
+                    stream.set_write_timeout(Some(self.connect_timeout))?;
+                    return tls.connect(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "upstream host has no addresses")
+        }))
+    }
+
+    fn build_request(&self, req: &HttpRequest) -> Vec<u8> {
+        let mut headers = vec![("Host".to_string(), self.target.authority())];
+        if let Some(protocol) = upgrade_protocol(req) {
+            headers.push(("Connection".to_string(), "upgrade".to_string()));
+            headers.push(("Upgrade".to_string(), protocol.to_string()));
+        } else if self.upstream.keepalive().is_none() {
+            headers.push(("Connection".to_string(), "close".to_string()));
+        }
+        for (name, value) in req.headers() {
+            let skipped = [
+                "Host",
+                "Content-Length",
+                "X-Forwarded-For",
+                "X-Forwarded-Proto",
+            ];
+            if is_hop_by_hop(name) || skipped.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            headers.push((name.clone(), value.clone()));
+        }
+        if let Some(forwarded_for) = forwarded_for(req) {
+            headers.push(("X-Forwarded-For".to_string(), forwarded_for));
+        }
+        let proto = if req.is_secure() { "https" } else { "http" };
+        headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+
+        // `proxy_set_header` replaces every header of the same name, and an
+        // empty value leaves the header out.
+        let vars = RequestVariables::new(req);
+        for (name, template) in &self.set_headers {
+            let value = template.render(&vars);
+            let position = headers
+                .iter()
+                .position(|(n, _)| n.eq_ignore_ascii_case(name))
+                .unwrap_or(headers.len());
+            headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+            if !value.is_empty() {
+                headers.insert(position.min(headers.len()), (name.clone(), value));
+            }
+        }
+
+        let mut head = format!("{} {} HTTP/1.1\r\n", req.method(), self.upstream_uri(req));
+        for (name, value) in &headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !req.body().is_empty() || req.content_length().is_some() {
+            head.push_str(&format!("Content-Length: {}\r\n", req.body().len()));
+        }
+        head.push_str("\r\n");
+
+        let mut request = head.into_bytes();
+        request.extend_from_slice(req.body());
+        request
+    }
+
+    /// Whether an upstream response header is kept from the client: those
+    /// listed by `proxy_hide_header` and `X-Accel-*` control headers, unless
+    /// `proxy_pass_header` lets them through.
+    fn is_hidden(&self, name: &str) -> bool {
+        let hidden = name
+            .get(..8)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("X-Accel-"))
+            || self
+                .hide_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name));
+        hidden
+            && !self
+                .pass_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+    }
+
+    /// The URI requested upstream: the client's original URI when neither a
+    /// rewrite nor a target URI changed it, or for mirrored copies without a
+    /// target URI, and the current URI otherwise.
+    fn upstream_uri(&self, req: &HttpRequest) -> String {
+        let (path, query) = match req.path().split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (req.path(), None),
+        };
+        let path = match (&self.target.uri, &self.location_prefix) {
+            (Some(uri), Some(prefix)) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => format!("{}{}", uri, rest),
+                None => path.to_string(),
+            },
+            _ => {
+                let unchanged = req.is_subrequest()
+                    || normalize_target(req.request_uri()).is_ok_and(|target| target == req.path());
+                if unchanged && req.request_uri().starts_with('/') {
+                    return req.request_uri().to_string();
+                }
+                path.to_string()
+            }
+        };
+        let mut uri = utf8_percent_encode(&path, PATH_ENCODE_SET).to_string();
+        if let Some(query) = query {
+            uri.push('?');
+            uri.push_str(query);
+        }
+        uri
+    }
+}
+
+/// The protocol a request asks to switch to with `Upgrade` and
+/// `Connection: upgrade`.
+fn upgrade_protocol(req: &HttpRequest) -> Option<&str> {
+    let connection = req.header("Connection")?;
+    connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        .then(|| req.header("Upgrade"))
+        .flatten()
+}
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Appends the client address to any `X-Forwarded-For` it sent.
+fn forwarded_for(req: &HttpRequest) -> Option<String> {
+    let client = req.remote_addr().map(|addr| addr.ip().to_string());
+    match (req.header("X-Forwarded-For"), client) {
+        (Some(previous), Some(client)) => Some(format!("{}, {}", previous, client)),
+        (Some(previous), None) => Some(previous.to_string()),
+        (None, client) => client,
+    }
+}
+
+/// The status line and headers of an upstream response.
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Whether the server keeps the connection open after the response.
+    persistent: bool,
+}
+
+/// Reads the status line and headers of an upstream response, skipping any
+/// interim 1xx responses.
+fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<ResponseHead> {
+    loop {
+        let status_line = read_line(reader)?;
+        let mut parts = status_line.split_whitespace();
+        let version = parts.next().unwrap_or_default().to_string();
+        let status = parts
+            .next()
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid upstream status line")
+            })?;
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        if !(100..200).contains(&status) || status == 101 {
+            let connection = headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+                .map(|(_, value)| value.to_ascii_lowercase())
+                .unwrap_or_default();
+            let persistent = if version == "HTTP/1.1" {
+                !connection.contains("close")
+            } else {
+                connection.contains("keep-alive")
+            };
+            return Ok(ResponseHead {
+                status,
+                headers,
+                persistent,
+            });
+        }
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "upstream closed the connection",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// How the end of an upstream response body is found.
+enum BodyFraming {
+    Length(io::Take<BufReader<UpstreamStream>>),
+    Chunked(ChunkedReader<BufReader<UpstreamStream>>),
+    UntilClose(BufReader<UpstreamStream>),
+}
+
+impl BodyFraming {
+    fn is_complete(&self) -> bool {
+        match self {
+            Self::Length(reader) => reader.limit() == 0,
+            Self::Chunked(reader) => reader.is_done(),
+            Self::UntilClose(_) => false,
+        }
+    }
+
+    fn into_reader(self) -> BufReader<UpstreamStream> {
+        match self {
+            Self::Length(reader) => reader.into_inner(),
+            Self::Chunked(reader) => reader.into_inner(),
+            Self::UntilClose(reader) => reader,
+        }
+    }
+}
+
+/// The connection under `reader`, unless the server sent more than asked for.
+fn into_stream(reader: BufReader<UpstreamStream>) -> Option<UpstreamStream> {
+    reader.buffer().is_empty().then(|| reader.into_inner())
+}
+
+/// An upstream response body that keeps its server counted as active and,
+/// once read to the end, hands a reusable connection back to the pool.
+struct PeerBody {
+    framing: Option<BodyFraming>,
+    peer: ActivePeer,
+    /// Requests carried by the connection when it may be reused.
+    reuse: Option<u32>,
+    timing: UpstreamTiming,
+}
+
+impl PeerBody {
+    fn new(
+        framing: BodyFraming,
+        peer: ActivePeer,
+        reuse: Option<u32>,
+        timing: UpstreamTiming,
+    ) -> Self {
+        Self {
+            framing: Some(framing),
+            peer,
+            reuse,
+            timing,
+        }
+    }
+}
+
+impl Read for PeerBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(framing) = self.framing.as_mut() else {
+            return Ok(0);
+        };
+        let n = match framing {
+            BodyFraming::Length(reader) => reader.read(buf)?,
+            BodyFraming::Chunked(reader) => reader.read(buf)?,
+            BodyFraming::UntilClose(reader) => reader.read(buf)?,
+        };
+        if n == 0 || framing.is_complete() {
+            self.timing.finish();
+        }
+        if let Some(requests) = self.reuse.filter(|_| framing.is_complete()) {
+            if let Some(stream) = self
+                .framing
+                .take()
+                .and_then(|f| into_stream(f.into_reader()))
+            {
+                self.peer.keep_connection(stream, requests);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Decodes a chunked message body.
+pub struct ChunkedReader<R> {
+    inner: R,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Whether the last chunk and trailers have been read.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = read_line(&mut self.inner)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+            if self.remaining == 0 {
+                // Skip trailers up to the terminating blank line.
+                while !read_line(&mut self.inner)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let want = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            read_line(&mut self.inner)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Builds the proxy handler for a location chain that sets `proxy_pass`,
+/// resolving the target against the `upstream` blocks of the http block.
+impl CacheOrigin for Proxy {
+    fn host(&self) -> String {
+        self.target.authority()
+    }
+
+    fn fetch(&self, req: &HttpRequest) -> io::Result<HttpResponse> {
+        if let Err(status) = self.upstream.admit() {
+            return Ok(HttpProcessor::create_status_response(req.version(), status));
+        }
+        self.forward(req)
+    }
+
+    fn failure(&self, req: &HttpRequest, error: io::Error) -> HttpResponse {
+        log_error!(
+            "upstream {} failed for \"{}\": {}",
+            self.target.authority(),
+            req.path(),
+            error
+        );
+        let status = match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        HttpProcessor::create_status_response(req.version(), status)
+    }
+}
+
+pub fn proxy_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let config = merged_config::<ProxyConfig>(chain);
+    let target = config.pass?;
+    let upstream = chain
+        .first()
+        .and_then(|http_config| find_upstream(http_config, &target.authority()));
+    let buffers = config.buffers.unwrap_or(DEFAULT_BUFFERS);
+    let mut proxy = Proxy::new(target, pattern)
+        .with_timeouts(
+            config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            config.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+            config.send_timeout.unwrap_or(DEFAULT_SEND_TIMEOUT),
+        )
+        .with_buffering(config.buffering.unwrap_or(true), buffers.0 * buffers.1)
+        .with_headers(
+            config.set_headers.unwrap_or_default(),
+            config.hide_headers.unwrap_or_default(),
+            config.pass_headers.unwrap_or_default(),
+        )
+        .with_next_upstream(
+            config.next_upstream.unwrap_or_default(),
+            config.next_upstream_tries.unwrap_or(0),
+            config.next_upstream_timeout.unwrap_or(Duration::ZERO),
+        );
+    if let Some(upstream) = upstream {
+        proxy = proxy.with_upstream(upstream);
+    }
+    if let Some(resolver) = resolver(chain) {
+        proxy = proxy.with_resolver(resolver);
+    }
+    if let Some(cache) = proxy_cache(chain) {
+        proxy = proxy.with_cache(cache);
+    }
+    if proxy.target.ssl {
+        let ssl_config = merged_config::<ProxySslConfig>(chain);
+        match UpstreamTls::new(&ssl_config, &proxy.target.host) {
+            Ok(tls) => proxy = proxy.with_tls(tls),
+            Err(e) => {
+                // Never fall back to plain text; the location answers 502
+                // until its settings are fixed.
+                log_error!("cannot proxy to {}: {}", proxy.target.authority(), e);
+                return Some(Box::new(|req: &HttpRequest| {
+                    HttpProcessor::create_status_response(req.version(), StatusCode::BAD_GATEWAY)
+                }));
+            }
+        }
+    }
+    let proxy = Arc::new(proxy);
+    let fetch: SliceFetch = Arc::new(move |req: &HttpRequest| proxy.serve(req));
+    if let Some(slice) = slice(chain, Arc::clone(&fetch)) {
+        return Some(Box::new(move |req: &HttpRequest| slice.handle(req)));
+    }
+    Some(Box::new(move |req: &HttpRequest| fetch(req)))
+}
+
+pub fn handle_proxy_pass(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let url = ctx.str_arg(0)?;
+    let target = ProxyTarget::parse(&url).map_err(|reason| ctx.invalid_value(&url, reason))?;
+    update(ctx, |config| config.pass = Some(target));
+    Ok(())
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut ProxyConfig)) {
+    if let Ok(mut config) = ctx.block_config::<ProxyConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_proxy_next_upstream(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let next =
+        NextUpstream::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    update(ctx, |config| config.next_upstream = Some(next));
+    Ok(())
+}
+
+pub fn handle_proxy_next_upstream_tries(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let tries = ctx.number_arg(0)?;
+    let tries = u32::try_from(tries)
+        .map_err(|_| ctx.invalid_value(&tries.to_string(), "must not be negative"))?;
+    update(ctx, |config| config.next_upstream_tries = Some(tries));
+    Ok(())
+}
+
+pub fn handle_proxy_next_upstream_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.next_upstream_timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_proxy_connect_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.connect_timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_proxy_read_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.read_timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_proxy_send_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.send_timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_proxy_buffering(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let buffering = ctx.bool_arg(0)?;
+    update(ctx, |config| config.buffering = Some(buffering));
+    Ok(())
+}
+
+pub fn handle_proxy_buffers(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let number = ctx.number_arg(0)?;
+    let size = ctx.size_arg(1)?;
+    if number < 1 {
+        return Err(ctx.invalid_value(&number.to_string(), "must be at least 1"));
+    }
+    if size == 0 {
+        return Err(ctx.invalid_value("0", "buffer size must not be zero"));
+    }
+    update(ctx, |config| config.buffers = Some((number as u64, size)));
+    Ok(())
+}
+
+pub fn handle_proxy_set_header(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let Some(name) = ctx
+        .current_cmd_args
+        .first()
+        .filter(|name| !name.is_empty())
+        .cloned()
+    else {
+        return Ok(());
+    };
+    let value = VarTemplate::parse(ctx.current_cmd_args.get(1).map_or("", String::as_str));
+    update(ctx, |config| {
+        config
+            .set_headers
+            .get_or_insert_with(Vec::new)
+            .push((name, value))
+    });
+    Ok(())
+}
+
+pub fn handle_proxy_hide_header(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    update(ctx, |config| {
+        config.hide_headers.get_or_insert_with(Vec::new).push(name)
+    });
+    Ok(())
+}
+
+pub fn handle_proxy_pass_header(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    update(ctx, |config| {
+        config.pass_headers.get_or_insert_with(Vec::new).push(name)
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::http::http_upstream::{Keepalive, UpstreamServer};
+
+    #[test]
+    fn test_proxy_forwards_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let upstream = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let line = read_line(&mut reader).unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                head.push(line);
+            }
+            let mut stream = stream;
+            stream
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Upstream: yes\r\n\r\n\
+                      5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+                )
+                .unwrap();
+            head
+        });
+
+        let target = ProxyTarget::parse(&format!("http://127.0.0.1:{}/v1/", port)).unwrap();
+        let proxy = Proxy::new(target, Some(&LocationPattern::prefix("/api/")));
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /api/users?id=1 HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n")
+            .unwrap();
+        req.set_connection(Some("192.0.2.7:5000".parse().unwrap()), None, false);
+
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.status(), Some(201));
+        assert_eq!(resp.header_value("X-Upstream"), Some("yes"));
+        assert!(resp.is_chunked());
+        let mut body = Vec::new();
+        resp.stream
+            .as_ref()
+            .unwrap()
+            .copy_to(&mut body, false)
+            .unwrap();
+        assert_eq!(body, b"hello world");
+
+        let head = upstream.join().unwrap();
+        assert_eq!(head[0], "GET /v1/users?id=1 HTTP/1.1");
+        assert!(head.contains(&format!("Host: 127.0.0.1:{}", port)));
+        assert!(head.contains(&"X-Forwarded-For: 10.0.0.1, 192.0.2.7".to_string()));
+        assert!(head.contains(&"X-Forwarded-Proto: http".to_string()));
+
+        let unreachable = Proxy::new(ProxyTarget::parse("http://127.0.0.1:1").unwrap(), None);
+        assert_eq!(unreachable.handle(&req).status(), Some(502));
+    }
+
+    /// Answers a single request with `response` and returns its head lines.
+    fn serve_once(response: &'static [u8]) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let line = read_line(&mut reader).unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                head.push(line);
+            }
+            stream.write_all(response).unwrap();
+            head
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_proxy_moves_on_to_next_upstream() {
+        let (unavailable, unavailable_head) =
+            serve_once(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        let (ok, ok_head) = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let servers = [
+            "127.0.0.1:1".to_string(),
+            format!("127.0.0.1:{}", unavailable),
+            format!("127.0.0.1:{}", ok),
+        ]
+        .iter()
+        .map(|address| UpstreamServer::parse(std::slice::from_ref(address)).unwrap())
+        .collect();
+        let upstream = Arc::new(Upstream::new("backend", servers));
+        let next = NextUpstream::parse(&["error".to_string(), "http_503".to_string()]).unwrap();
+        let proxy = Proxy::new(ProxyTarget::parse("http://backend").unwrap(), None)
+            .with_upstream(upstream.clone())
+            .with_next_upstream(next, 0, Duration::ZERO);
+
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /retry HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(unavailable_head.join().unwrap()[0], "GET /retry HTTP/1.1");
+        assert!(ok_head
+            .join()
+            .unwrap()
+            .contains(&"Host: backend".to_string()));
+
+        // Both failed servers sit out their fail_timeout, leaving no server
+        // to move on to once the last one is tried as well.
+        assert_eq!(upstream.select(&req, &[2]).map(|peer| peer.index()), None);
+
+        let mut post = HttpRequest::new();
+        post.parse(b"POST /retry HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let result = Ok(HttpProcessor::create_status_response(
+            post.version(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+        let next = NextUpstream::parse(&["http_503".to_string()]).unwrap();
+        assert!(!next.retries(&result, true, &post));
+        assert!(next.retries(&result, true, &req));
+        assert!(!NextUpstream::off().retries(
+            &Err(io::ErrorKind::ConnectionRefused.into()),
+            false,
+            &req
+        ));
+    }
+
+    #[test]
+    fn test_proxy_reuses_keepalive_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let upstream_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut requests = Vec::new();
+            for body in ["first", "second"] {
+                requests.push(read_line(&mut reader).unwrap());
+                while !read_line(&mut reader).unwrap().is_empty() {}
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let server = UpstreamServer::parse(&[format!("127.0.0.1:{}", port)]).unwrap();
+        let keepalive = Keepalive {
+            requests: 2,
+            ..Keepalive::new(4)
+        };
+        let upstream = Arc::new(Upstream::new("backend", vec![server]).with_keepalive(keepalive));
+        let proxy = Proxy::new(ProxyTarget::parse("http://backend").unwrap(), None)
+            .with_upstream(upstream.clone())
+            .with_buffering(true, 5);
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /keepalive HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        // The first body fits in the buffer, freeing the connection at once.
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.body, b"first");
+        assert!(resp.stream.is_none());
+        assert_eq!(upstream.idle_count(), 1);
+
+        // The second one does not and is relayed from where buffering stopped.
+        let resp = proxy.handle(&req);
+        let mut body = Vec::new();
+        resp.stream
+            .as_ref()
+            .unwrap()
+            .copy_to(&mut body, false)
+            .unwrap();
+        assert_eq!(body, b"second");
+        // The second request used up keepalive_requests, so the connection
+        // was closed rather than pooled again.
+        assert_eq!(upstream.idle_count(), 0);
+        assert_eq!(upstream_thread.join().unwrap().len(), 2);
+        assert!(!proxy
+            .build_request(&req)
+            .windows(17)
+            .any(|w| w == b"Connection: close"));
+    }
+
+    #[test]
+    fn test_proxy_header_manipulation() {
+        let (port, upstream) = serve_once(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nX-Internal: 1\r\nX-Accel-Redirect: /secret\r\nX-Accel-Expires: 60\r\n\r\n",
+        );
+        let set = [
+            ("Host", "$host"),
+            ("X-Forwarded-For", ""),
+            ("X-Real-IP", "$remote_addr"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), VarTemplate::parse(value)))
+        .collect();
+        let proxy = Proxy::new(
+            ProxyTarget::parse(&format!("http://127.0.0.1:{}", port)).unwrap(),
+            None,
+        )
+        .with_headers(
+            set,
+            vec!["x-internal".to_string()],
+            vec!["X-Accel-Expires".to_string()],
+        );
+        let mut req = HttpRequest::new();
+        req.parse(b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n")
+            .unwrap();
+        req.set_connection(Some("192.0.2.7:5000".parse().unwrap()), None, false);
+
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.header_value("X-Internal"), None);
+        assert_eq!(resp.header_value("X-Accel-Redirect"), None);
+        assert_eq!(resp.accel_redirect.as_deref(), Some("/secret"));
+        assert_eq!(resp.header_value("X-Accel-Expires"), Some("60"));
+
+        let head = upstream.join().unwrap();
+        assert_eq!(head[1], "Host: example.com");
+        assert!(head.contains(&"X-Real-IP: 192.0.2.7".to_string()));
+        assert!(!head.iter().any(|line| line.starts_with("X-Forwarded-For")));
+    }
+
+    #[test]
+    fn test_proxy_hands_over_upgraded_connection() {
+        let (port, upstream) = serve_once(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nhello",
+        );
+        let proxy = Proxy::new(
+            ProxyTarget::parse(&format!("http://127.0.0.1:{}", port)).unwrap(),
+            None,
+        );
+        let mut req = HttpRequest::new();
+        req.parse(
+            b"GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+        )
+        .unwrap();
+
+        let resp = proxy.handle(&req);
+        assert_eq!(resp.header_value("Connection"), Some("upgrade"));
+        assert_eq!(resp.header_value("Upgrade"), Some("websocket"));
+        let head = upstream.join().unwrap();
+        assert!(head.contains(&"Connection: upgrade".to_string()));
+        assert!(head.contains(&"Upgrade: websocket".to_string()));
+
+        let (stream, mut received) = resp.upgrade.unwrap().take().unwrap();
+        stream
+            .take(5 - received.len() as u64)
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received, b"hello");
+    }
+}
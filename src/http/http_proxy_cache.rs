@@ -0,0 +1,2224 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::{Method, StatusCode};
+use openssl::hash::{hash, MessageDigest};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::{parse_duration, parse_size},
+        },
+        processor::HttpProcessor,
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::{parse_http_date, FileBody, HttpResponse, StreamBody},
+    http_slice::SliceRange,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("proxy_cache_path")
+        .allowed_parents(vec!["http".to_string()])
+        .display_name("en", "Proxy Cache Path")
+        .display_name("zh-tw", "代理快取路徑")
+        .desc(
+            "en",
+            "Declares a cache zone storing upstream responses on disk under a directory"
+        )
+        .desc("zh-tw", "宣告一個將上游回應儲存於磁碟目錄中的快取區域")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Path")
+                .display_name("zh-tw", "路徑")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Directory the cached responses are stored in")
+                .desc("zh-tw", "儲存快取回應的目錄")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Zone")
+                .display_name("zh-tw", "區域")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "keys_zone=name:size, the name used by proxy_cache and the memory for keys, about 8000 keys per megabyte"
+                )
+                .desc(
+                    "zh-tw",
+                    "keys_zone=名稱:大小，proxy_cache 使用的名稱與存放鍵值的記憶體，每 MB 約可存放 8000 個鍵值"
+                )
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "Options")
+                .display_name("zh-tw", "選項")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "levels=1:2 for subdirectories, max_size=size to bound the disk usage, inactive=time (10m by default) to remove entries nobody requests, memory_size=size to keep recently used small entries in memory, memory_max_object=size (64k by default) and memory_ttl=time to limit them"
+                )
+                .desc(
+                    "zh-tw",
+                    "levels=1:2 設定子目錄層級，max_size=大小 限制磁碟用量，inactive=時間（預設 10m）移除無人請求的項目，memory_size=大小 將最近使用的小型項目保留於記憶體，memory_max_object=大小（預設 64k）與 memory_ttl=時間 限制保留的項目"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(2))
+        .is_repeatable()
+        .build(handle_proxy_cache_path),
+    CommandBuilder::new("proxy_cache")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache")
+        .display_name("zh-tw", "代理快取")
+        .desc("en", "Caches proxied responses in a cache zone")
+        .desc("zh-tw", "將代理的回應快取於快取區域中")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Zone")
+            .display_name("zh-tw", "區域")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Name of a zone declared by proxy_cache_path, or off"
+            )
+            .desc("zh-tw", "proxy_cache_path 宣告的區域名稱，或 off")
+            .build()])
+        .build(handle_proxy_cache),
+    CommandBuilder::new("proxy_cache_revalidate")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Revalidate")
+        .display_name("zh-tw", "代理快取重新驗證")
+        .desc(
+            "en",
+            "Revalidates expired entries with conditional requests using their ETag and Last-Modified"
+        )
+        .desc(
+            "zh-tw",
+            "以快取項目的 ETag 與 Last-Modified 發送條件式請求，重新驗證過期的項目"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_proxy_cache_revalidate),
+    CommandBuilder::new("proxy_cache_use_stale")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Use Stale")
+        .display_name("zh-tw", "使用過期快取")
+        .desc(
+            "en",
+            "Sets when an expired entry is served instead of the upstream response"
+        )
+        .desc("zh-tw", "設定何時以過期的快取項目取代上游的回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Conditions")
+            .display_name("zh-tw", "條件")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more of error, timeout, invalid_header, updating, http_500, http_502, http_503, http_504, http_403, http_404, http_429, or off"
+            )
+            .desc(
+                "zh-tw",
+                "error、timeout、invalid_header、updating、http_500、http_502、http_503、http_504、http_403、http_404、http_429 其中一個或多個，或 off"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_proxy_cache_use_stale),
+    CommandBuilder::new("proxy_cache_background_update")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Background Update")
+        .display_name("zh-tw", "代理快取背景更新")
+        .desc(
+            "en",
+            "Refreshes expired entries in the background while serving them, with proxy_cache_use_stale updating"
+        )
+        .desc(
+            "zh-tw",
+            "在回應過期項目的同時於背景更新，需搭配 proxy_cache_use_stale updating"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_proxy_cache_background_update),
+    CommandBuilder::new("proxy_cache_lock")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Lock")
+        .display_name("zh-tw", "代理快取鎖定")
+        .desc(
+            "en",
+            "Lets only one request at a time fetch a missing entry while the others wait for it to be cached"
+        )
+        .desc(
+            "zh-tw",
+            "同一時間只讓一個請求向上游取得尚未快取的項目，其他請求等待其存入快取"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on, or off (the default)")
+            .desc("zh-tw", "on，或 off（預設）")
+            .build()])
+        .build(handle_proxy_cache_lock),
+    CommandBuilder::new("proxy_cache_lock_timeout")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Lock Timeout")
+        .display_name("zh-tw", "代理快取鎖定逾時")
+        .desc(
+            "en",
+            "Sets how long a request waits for the cache lock before fetching from the upstream itself"
+        )
+        .desc("zh-tw", "設定請求等待快取鎖定的時間，逾時後自行向上游取得")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Timeout")
+            .display_name("zh-tw", "逾時")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Duration, e.g. 5s (the default)")
+            .desc("zh-tw", "時間長度，例如 5s（預設）")
+            .build()])
+        .build(handle_proxy_cache_lock_timeout),
+    CommandBuilder::new("proxy_cache_purge")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Purge")
+        .display_name("zh-tw", "清除代理快取")
+        .desc(
+            "en",
+            "Lets PURGE requests remove cache entries by key, or every key starting with a prefix ending in *"
+        )
+        .desc(
+            "zh-tw",
+            "允許 PURGE 請求依鍵值移除快取項目，以 * 結尾時移除所有符合該前綴的項目"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Condition")
+            .display_name("zh-tw", "條件")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Purging is allowed when this is neither empty nor 0, e.g. $purge_allowed; on, or off (the default)"
+            )
+            .desc(
+                "zh-tw",
+                "值不為空且不為 0 時允許清除，例如 $purge_allowed；on，或 off（預設）"
+            )
+            .build()])
+        .build(handle_proxy_cache_purge),
+    CommandBuilder::new("proxy_cache_key")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Key")
+        .display_name("zh-tw", "代理快取鍵值")
+        .desc("en", "Sets the key cached responses are stored and looked up under")
+        .desc("zh-tw", "設定儲存與查詢快取回應時使用的鍵值")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Key")
+            .display_name("zh-tw", "鍵值")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Key with variables, $scheme$proxy_host$request_uri$slice_range by default"
+            )
+            .desc(
+                "zh-tw",
+                "可包含變數的鍵值，預設為 $scheme$proxy_host$request_uri$slice_range"
+            )
+            .build()])
+        .build(handle_proxy_cache_key),
+    CommandBuilder::new("proxy_cache_bypass")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Bypass")
+        .display_name("zh-tw", "略過代理快取")
+        .desc(
+            "en",
+            "Fetches the response from the upstream instead of the cache when any value is neither empty nor 0"
+        )
+        .desc(
+            "zh-tw",
+            "任一個值不為空且不為 0 時，不從快取回應而是向上游取得"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Conditions")
+            .display_name("zh-tw", "條件")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "One or more values with variables, e.g. $cookie_nocache $arg_nocache")
+            .desc("zh-tw", "一個或多個可包含變數的值，例如 $cookie_nocache $arg_nocache")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_proxy_cache_bypass),
+    CommandBuilder::new("proxy_no_cache")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy No Cache")
+        .display_name("zh-tw", "不儲存代理快取")
+        .desc(
+            "en",
+            "Keeps the response out of the cache when any value is neither empty nor 0"
+        )
+        .desc("zh-tw", "任一個值不為空且不為 0 時，回應不會存入快取")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Conditions")
+            .display_name("zh-tw", "條件")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "One or more values with variables, e.g. $http_pragma")
+            .desc("zh-tw", "一個或多個可包含變數的值，例如 $http_pragma")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_proxy_no_cache),
+    CommandBuilder::new("proxy_cache_methods")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy Cache Methods")
+        .display_name("zh-tw", "代理快取方法")
+        .desc(
+            "en",
+            "Sets the request methods answered from and stored in the cache, in addition to GET and HEAD"
+        )
+        .desc("zh-tw", "設定除了 GET 與 HEAD 之外，使用並存入快取的請求方法")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Methods")
+            .display_name("zh-tw", "方法")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "One or more methods, e.g. GET HEAD POST")
+            .desc("zh-tw", "一個或多個方法，例如 GET HEAD POST")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_proxy_cache_methods),
+);
+
+/// `$slice_range` is empty unless the request fetches a `slice`.
+const DEFAULT_KEY: &str = "$scheme$proxy_host$request_uri$slice_range";
+const DEFAULT_INACTIVE: Duration = Duration::from_secs(600);
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest body kept in memory unless `memory_max_object` says otherwise.
+const DEFAULT_MEMORY_MAX_OBJECT: u64 = 64 * 1024;
+/// Keys held by each megabyte of `keys_zone`, as in nginx.
+const KEYS_PER_MEGABYTE: u64 = 8000;
+/// How often entries idle for longer than `inactive` are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Statuses cached when the response allows it; `206 Partial Content` is
+/// also cached for the ranges fetched by `slice`.
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 302, 404, 410];
+/// Response headers that describe a single transfer and are not stored.
+const UNSTORED_HEADERS: &[&str] = &[
+    "Age",
+    "Connection",
+    "Content-Length",
+    "Keep-Alive",
+    "Transfer-Encoding",
+    "X-Cache-Status",
+];
+
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default, Clone)]
+pub struct ProxyCachePaths {
+    pub zones: Vec<Arc<CacheZone>>,
+}
+
+impl MergeConfig for ProxyCachePaths {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProxyCacheConfig {
+    /// The zone name; `Some(None)` is `proxy_cache off`.
+    pub zone: Option<Option<String>>,
+    pub revalidate: Option<bool>,
+    pub use_stale: Option<UseStale>,
+    pub background_update: Option<bool>,
+    pub lock: Option<bool>,
+    pub lock_timeout: Option<Duration>,
+    /// The condition allowing PURGE; `Some(None)` is `proxy_cache_purge off`.
+    pub purge: Option<Option<String>>,
+    pub key: Option<String>,
+    pub bypass: Option<Vec<String>>,
+    pub no_cache: Option<Vec<String>>,
+    /// Methods cached besides GET and HEAD.
+    pub methods: Option<Vec<Method>>,
+}
+
+impl MergeConfig for ProxyCacheConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.zone = self.zone.take().or_else(|| parent.zone.clone());
+        self.revalidate = self.revalidate.or(parent.revalidate);
+        self.use_stale = self.use_stale.take().or_else(|| parent.use_stale.clone());
+        self.background_update = self.background_update.or(parent.background_update);
+        self.lock = self.lock.or(parent.lock);
+        self.lock_timeout = self.lock_timeout.or(parent.lock_timeout);
+        self.purge = self.purge.take().or_else(|| parent.purge.clone());
+        self.key = self.key.take().or_else(|| parent.key.clone());
+        self.bypass = self.bypass.take().or_else(|| parent.bypass.clone());
+        self.no_cache = self.no_cache.take().or_else(|| parent.no_cache.clone());
+        self.methods = self.methods.take().or_else(|| parent.methods.clone());
+    }
+}
+
+/// The conditions under which `proxy_cache_use_stale` answers with an
+/// expired entry instead of the upstream.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UseStale {
+    pub error: bool,
+    pub timeout: bool,
+    pub invalid_header: bool,
+    /// While another request is fetching a fresh copy.
+    pub updating: bool,
+    pub statuses: Vec<u16>,
+}
+
+impl UseStale {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut stale = Self::default();
+        if args == ["off"] {
+            return Ok(stale);
+        }
+        for arg in args {
+            match arg.as_str() {
+                "error" => stale.error = true,
+                "timeout" => stale.timeout = true,
+                "invalid_header" => stale.invalid_header = true,
+                "updating" => stale.updating = true,
+                "http_500" | "http_502" | "http_503" | "http_504" | "http_403" | "http_404"
+                | "http_429" => stale.statuses.push(arg[5..].parse().unwrap_or_default()),
+                _ => return Err(format!("unknown condition \"{}\"", arg)),
+            }
+        }
+        Ok(stale)
+    }
+
+    fn covers(&self, result: &io::Result<HttpResponse>) -> bool {
+        match result {
+            Ok(resp) => resp
+                .status()
+                .is_some_and(|status| self.statuses.contains(&status)),
+            Err(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => self.timeout,
+                io::ErrorKind::InvalidData => self.invalid_header,
+                _ => self.error,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    key: String,
+    size: u64,
+    accessed: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct ZoneIndex {
+    /// Entries by the MD5 of their key, which is also their file name.
+    entries: HashMap<String, IndexEntry>,
+    size: u64,
+    swept: Option<SystemTime>,
+}
+
+/// Copies of small, recently used entries kept in memory in front of the
+/// files, so the hottest responses are served without disk reads.
+#[derive(Debug)]
+struct MemoryTier {
+    /// Bytes of bodies held before the least recently used entries go.
+    max_size: u64,
+    max_object: u64,
+    /// How long an entry is served from memory before it is read again.
+    ttl: Option<Duration>,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Entries by the MD5 of their key, like the zone index.
+    entries: HashMap<String, MemoryEntry>,
+    size: u64,
+    /// Counts lookups, ordering the entries by last use.
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    entry: CachedEntry,
+    loaded: Instant,
+    used: u64,
+}
+
+impl MemoryTier {
+    fn get(&self, hash: &str) -> Option<CachedEntry> {
+        let mut state = self.state.lock().ok()?;
+        state.clock += 1;
+        let clock = state.clock;
+        let expired = {
+            let memory = state.entries.get_mut(hash)?;
+            memory.used = clock;
+            self.ttl.is_some_and(|ttl| memory.loaded.elapsed() >= ttl)
+        };
+        if expired {
+            if let Some(memory) = state.entries.remove(hash) {
+                state.size -= memory.entry.body_len;
+            }
+            return None;
+        }
+        state.entries.get(hash).map(|memory| memory.entry.clone())
+    }
+
+    /// Reads the body of an entry found on disk into memory if it is small
+    /// enough, evicting the least recently used entries to make room.
+    fn load(&self, hash: &str, entry: CachedEntry) -> CachedEntry {
+        if entry.body_len > self.max_object || entry.body_len > self.max_size {
+            return entry;
+        }
+        let mut body = Vec::with_capacity(entry.body_len as usize);
+        if entry.copy_body(&mut body).is_err() {
+            return entry;
+        }
+        let entry = CachedEntry {
+            body: EntryBody::Memory(Arc::new(body)),
+            ..entry
+        };
+        let Ok(mut state) = self.state.lock() else {
+            return entry;
+        };
+        if let Some(old) = state.entries.remove(hash) {
+            state.size -= old.entry.body_len;
+        }
+        while state.size + entry.body_len > self.max_size {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, memory)| memory.used)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            if let Some(old) = state.entries.remove(&oldest) {
+                state.size -= old.entry.body_len;
+            }
+        }
+        state.clock += 1;
+        state.size += entry.body_len;
+        let memory = MemoryEntry {
+            entry: entry.clone(),
+            loaded: Instant::now(),
+            used: state.clock,
+        };
+        state.entries.insert(hash.to_string(), memory);
+        entry
+    }
+
+    fn remove(&self, hash: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(memory) = state.entries.remove(hash) {
+                state.size -= memory.entry.body_len;
+            }
+        }
+    }
+}
+
+/// A directory of cached responses with an in-memory index of its entries.
+/// Each entry is a file holding the key, expiry, response head and body.
+#[derive(Debug)]
+pub struct CacheZone {
+    pub name: String,
+    root: PathBuf,
+    levels: Vec<usize>,
+    max_keys: usize,
+    /// Bytes on disk before the least recently used entries go; 0 means
+    /// unlimited.
+    max_size: u64,
+    inactive: Duration,
+    index: Mutex<ZoneIndex>,
+    /// Keys a fresh copy is being fetched for.
+    updating: Mutex<HashSet<String>>,
+    /// Signalled whenever a key leaves `updating`.
+    updated: Condvar,
+    memory: Option<MemoryTier>,
+}
+
+impl CacheZone {
+    /// Parses the arguments of `proxy_cache_path`, creating the directory
+    /// and indexing the entries already stored in it.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (root, options) = args.split_first().ok_or("missing cache directory")?;
+        let mut zone = None;
+        let mut levels = Vec::new();
+        let mut max_size = 0;
+        let mut inactive = DEFAULT_INACTIVE;
+        let mut memory_size = 0;
+        let mut memory_max_object = DEFAULT_MEMORY_MAX_OBJECT;
+        let mut memory_ttl = None;
+        for option in options {
+            let Some((name, value)) = option.split_once('=') else {
+                return Err(format!("unknown option \"{}\"", option));
+            };
+            match name {
+                "keys_zone" => {
+                    let (name, size) = value
+                        .split_once(':')
+                        .filter(|(name, _)| !name.is_empty())
+                        .ok_or("keys_zone must be name:size")?;
+                    let keys = parse_size(size)?.saturating_mul(KEYS_PER_MEGABYTE) >> 20;
+                    zone = Some((name.to_string(), keys.max(1) as usize));
+                }
+                "levels" => {
+                    levels = value
+                        .split(':')
+                        .map(|level| match level {
+                            "1" => Ok(1),
+                            "2" => Ok(2),
+                            _ => Err("levels must be 1 or 2 characters each, e.g. 1:2"),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    if levels.len() > 3 {
+                        return Err("at most three levels are allowed".to_string());
+                    }
+                }
+                "max_size" => max_size = parse_size(value)?,
+                "inactive" => inactive = parse_duration(value)?,
+                "memory_size" => memory_size = parse_size(value)?,
+                "memory_max_object" => memory_max_object = parse_size(value)?,
+                "memory_ttl" => memory_ttl = Some(parse_duration(value)?),
+                _ => return Err(format!("unknown option \"{}\"", name)),
+            }
+        }
+        let (name, max_keys) = zone.ok_or("keys_zone is required")?;
+        let zone = Self {
+            name,
+            root: PathBuf::from(root),
+            levels,
+            max_keys,
+            max_size,
+            inactive,
+            index: Mutex::new(ZoneIndex::default()),
+            updating: Mutex::default(),
+            updated: Condvar::new(),
+            memory: (memory_size > 0).then(|| MemoryTier {
+                max_size: memory_size,
+                max_object: memory_max_object,
+                ttl: memory_ttl,
+                state: Mutex::default(),
+            }),
+        };
+        zone.load()
+            .map_err(|e| format!("cannot open cache directory \"{}\": {}", root, e))?;
+        Ok(zone)
+    }
+
+    /// Indexes the entries left by a previous run and removes unfinished
+    /// temporary files.
+    fn load(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    dirs.push(path);
+                } else if path.extension().is_some_and(|ext| ext == "tmp") {
+                    let _ = fs::remove_file(&path);
+                } else if file_type.is_file() {
+                    let Ok(cached) = CachedEntry::open(&path) else {
+                        continue;
+                    };
+                    let size = entry.metadata()?.len();
+                    self.insert(key_hash(&cached.key), cached.key, size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn file_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        let mut end = hash.len();
+        for level in &self.levels {
+            path.push(&hash[end - level..end]);
+            end -= level;
+        }
+        path.push(hash);
+        path
+    }
+
+    /// Finds the entry stored for `key` that suits `req`, fresh or not.
+    pub fn lookup(&self, key: &str, req: &HttpRequest) -> Option<CachedEntry> {
+        let hash = key_hash(key);
+        {
+            let mut index = self.index.lock().ok()?;
+            let entry = index.entries.get_mut(&hash)?;
+            entry.accessed = SystemTime::now();
+        }
+        let memory = self.memory.as_ref();
+        if let Some(entry) = memory.and_then(|memory| memory.get(&hash)) {
+            return (entry.key == key && entry.matches(req)).then_some(entry);
+        }
+        match CachedEntry::open(&self.file_path(&hash)) {
+            Ok(entry) if entry.key == key => {
+                let entry = match memory {
+                    Some(memory) => memory.load(&hash, entry),
+                    None => entry,
+                };
+                entry.matches(req).then_some(entry)
+            }
+            Ok(_) => None,
+            Err(_) => {
+                self.remove(&hash);
+                None
+            }
+        }
+    }
+
+    fn remove(&self, hash: &str) -> bool {
+        self.forget(hash);
+        let mut removed = false;
+        if let Ok(mut index) = self.index.lock() {
+            if let Some(entry) = index.entries.remove(hash) {
+                index.size -= entry.size;
+                removed = true;
+            }
+        }
+        let _ = fs::remove_file(self.file_path(hash));
+        removed
+    }
+
+    /// Removes the entry stored for `key`, or with a trailing `*` every
+    /// entry whose key starts with the rest, returning how many went.
+    pub fn purge(&self, key: &str) -> usize {
+        let hashes: Vec<String> = match key.strip_suffix('*') {
+            Some(prefix) => match self.index.lock() {
+                Ok(index) => index
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.key.starts_with(prefix))
+                    .map(|(hash, _)| hash.clone())
+                    .collect(),
+                Err(_) => return 0,
+            },
+            None => vec![key_hash(key)],
+        };
+        hashes.iter().filter(|hash| self.remove(hash)).count()
+    }
+
+    /// Marks `key` as being fetched, unless another request already is.
+    fn begin_update(self: &Arc<Self>, key: &str) -> Option<UpdateGuard> {
+        let mut updating = self.updating.lock().ok()?;
+        updating.insert(key.to_string()).then(|| UpdateGuard {
+            zone: Arc::clone(self),
+            key: key.to_string(),
+        })
+    }
+
+    /// Waits until no request is fetching `key`, returning false if that
+    /// does not happen before `deadline`.
+    fn wait_update(&self, key: &str, deadline: Instant) -> bool {
+        let Ok(mut updating) = self.updating.lock() else {
+            return false;
+        };
+        while updating.contains(key) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            updating = match self.updated.wait_timeout(updating, remaining) {
+                Ok((updating, _)) => updating,
+                Err(_) => return false,
+            };
+        }
+        true
+    }
+
+    /// Starts saving `resp` under `key` if its headers allow caching it; a
+    /// streamed body is saved as the client reads it, and `update` is held
+    /// until then.
+    fn store(
+        self: &Arc<Self>,
+        key: &str,
+        req: &HttpRequest,
+        resp: &mut HttpResponse,
+        update: Option<UpdateGuard>,
+    ) {
+        if resp.file.is_some() || resp.upgrade.is_some() || resp.accel_redirect.is_some() {
+            return;
+        }
+        let Some(status) = resp.status().filter(|status| {
+            CACHEABLE_STATUSES.contains(status)
+                || (*status == 206 && req.extensions().get::<SliceRange>().is_some())
+        }) else {
+            return;
+        };
+        let head = {
+            let headers: Vec<(&str, &str)> = resp.headers().collect();
+            let (Some(lifetime), Some(vary)) = (lifetime(&headers), vary(&headers, req)) else {
+                return;
+            };
+            format_head(key, lifetime, status, &vary, &headers)
+        };
+
+        let mut writer = match self.create(key, &head, update) {
+            Ok(writer) => writer,
+            Err(e) => {
+                log_error!("cannot create cache file in zone \"{}\": {}", self.name, e);
+                return;
+            }
+        };
+        writer.append(&resp.body);
+        match resp.stream.take() {
+            Some(stream) => {
+                let expected = stream.len;
+                let tee = TeeBody {
+                    body: stream,
+                    writer,
+                    expected,
+                    read: 0,
+                };
+                resp.set_body_stream(StreamBody::new(tee, expected));
+            }
+            None => writer.finish(),
+        }
+    }
+
+    /// Renews an expired entry the upstream confirmed with `304 Not
+    /// Modified`, taking the headers it sent over the stored ones.
+    fn refresh(
+        self: &Arc<Self>,
+        entry: &CachedEntry,
+        not_modified: &HttpResponse,
+    ) -> io::Result<()> {
+        let updates: Vec<(&str, &str)> = not_modified
+            .headers()
+            .filter(|(name, _)| {
+                !UNSTORED_HEADERS
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(name))
+            })
+            .collect();
+        let mut headers: Vec<(&str, &str)> = entry
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .filter(|(name, _)| !updates.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .collect();
+        headers.extend(updates);
+        let Some(lifetime) = lifetime(&headers) else {
+            return Ok(());
+        };
+        let head = format_head(
+            &entry.key,
+            lifetime,
+            entry.status.as_u16(),
+            &entry.vary,
+            &headers,
+        );
+        let mut writer = self.create(&entry.key, &head, None)?;
+        entry.copy_body(&mut writer)?;
+        writer.finish();
+        Ok(())
+    }
+
+    /// Opens a temporary file for a new entry and writes its head.
+    fn create(
+        self: &Arc<Self>,
+        key: &str,
+        head: &str,
+        update: Option<UpdateGuard>,
+    ) -> io::Result<CacheWriter> {
+        let temp = self.root.join(format!(
+            "{}.{}.{}.tmp",
+            key_hash(key),
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = File::create(&temp)?;
+        if let Err(e) = file.write_all(head.as_bytes()) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        Ok(CacheWriter {
+            zone: Arc::clone(self),
+            key: key.to_string(),
+            temp,
+            file: Some(file),
+            written: head.len() as u64,
+            _update: update,
+        })
+    }
+
+    /// Moves a completed temporary file into place.
+    fn commit(&self, key: &str, temp: &Path, size: u64) -> io::Result<()> {
+        let hash = key_hash(key);
+        let path = self.file_path(&hash);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(temp, &path)?;
+        self.forget(&hash);
+        self.insert(hash, key.to_string(), size);
+        Ok(())
+    }
+
+    /// Records an entry, then removes those idle for longer than `inactive`
+    /// and the least recently used ones while the zone is over its limits.
+    fn insert(&self, hash: String, key: String, size: u64) {
+        let Ok(mut index) = self.index.lock() else {
+            return;
+        };
+        let now = SystemTime::now();
+        let entry = IndexEntry {
+            key,
+            size,
+            accessed: now,
+        };
+        if let Some(old) = index.entries.insert(hash, entry) {
+            index.size -= old.size;
+        }
+        index.size += size;
+
+        let mut removed = Vec::new();
+        let sweep = index
+            .swept
+            .is_none_or(|swept| now.duration_since(swept).unwrap_or_default() >= SWEEP_INTERVAL);
+        if sweep {
+            index.swept = Some(now);
+            let inactive = self.inactive;
+            index.entries.retain(|hash, entry| {
+                let idle = now.duration_since(entry.accessed).unwrap_or_default() > inactive;
+                if idle {
+                    removed.push((hash.clone(), entry.size));
+                }
+                !idle
+            });
+        }
+        while index.entries.len() > self.max_keys
+            || (self.max_size > 0 && index.size > self.max_size)
+        {
+            let Some(oldest) = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.accessed)
+                .map(|(hash, _)| hash.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = index.entries.remove(&oldest) {
+                removed.push((oldest, entry.size));
+            }
+        }
+        for (_, size) in &removed {
+            index.size -= size;
+        }
+        drop(index);
+        for (hash, _) in removed {
+            self.forget(&hash);
+            let _ = fs::remove_file(self.file_path(&hash));
+        }
+    }
+
+    /// Drops the copy of an entry held in memory, once its file changes.
+    fn forget(&self, hash: &str) {
+        if let Some(memory) = &self.memory {
+            memory.remove(hash);
+        }
+    }
+}
+
+fn key_hash(key: &str) -> String {
+    hash(MessageDigest::md5(), key.as_bytes())
+        .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect())
+        .unwrap_or_default()
+}
+
+/// Formats the head of a cache file; the body follows its blank line.
+fn format_head(
+    key: &str,
+    lifetime: Duration,
+    status: u16,
+    vary: &[(String, String)],
+    headers: &[(&str, &str)],
+) -> String {
+    let now = SystemTime::now();
+    let mut head = format!(
+        "KEY {}\nEXPIRES {}\nSTORED {}\nSTATUS {}\n",
+        key,
+        unix_secs(now + lifetime),
+        unix_secs(now),
+        status
+    );
+    for (name, value) in vary {
+        head.push_str(&format!("VARY {}: {}\n", name, value));
+    }
+    for (name, value) in headers {
+        if !UNSTORED_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            head.push_str(&format!("HEADER {}: {}\n", name, value));
+        }
+    }
+    head.push('\n');
+    head
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Where the body of a cached response is read from.
+#[derive(Debug, Clone)]
+enum EntryBody {
+    File { file: Arc<File>, offset: u64 },
+    Memory(Arc<Vec<u8>>),
+}
+
+/// A response read back from a cache file; the body stays on disk unless
+/// the zone keeps it in memory.
+#[derive(Debug, Clone)]
+pub struct CachedEntry {
+    key: String,
+    expires: SystemTime,
+    stored: SystemTime,
+    /// The request headers named by `Vary`, with the values they had.
+    vary: Vec<(String, String)>,
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: EntryBody,
+    body_len: u64,
+}
+
+impl CachedEntry {
+    fn open(path: &Path) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid cache file");
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut entry = Self {
+            key: String::new(),
+            expires: UNIX_EPOCH,
+            stored: UNIX_EPOCH,
+            vary: Vec::new(),
+            status: StatusCode::OK,
+            headers: Vec::new(),
+            body: EntryBody::File {
+                file: Arc::new(file.try_clone()?),
+                offset: 0,
+            },
+            body_len: 0,
+        };
+        let mut body_offset = 0;
+        loop {
+            let mut line = String::new();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                return Err(invalid());
+            }
+            body_offset += n as u64;
+            let line = line.trim_end_matches('\n');
+            if line.is_empty() {
+                break;
+            }
+            let (field, value) = line.split_once(' ').ok_or_else(invalid)?;
+            let secs = || {
+                value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| invalid())
+            };
+            let pair = || {
+                value
+                    .split_once(": ")
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .ok_or_else(invalid)
+            };
+            match field {
+                "KEY" => entry.key = value.to_string(),
+                "EXPIRES" => entry.expires = UNIX_EPOCH + secs()?,
+                "STORED" => entry.stored = UNIX_EPOCH + secs()?,
+                "VARY" => entry.vary.push(pair()?),
+                "STATUS" => {
+                    entry.status = value
+                        .parse::<u16>()
+                        .ok()
+                        .and_then(|code| StatusCode::from_u16(code).ok())
+                        .ok_or_else(invalid)?
+                }
+                "HEADER" => entry.headers.push(pair()?),
+                _ => {}
+            }
+        }
+        entry.body_len = len.checked_sub(body_offset).ok_or_else(invalid)?;
+        if let EntryBody::File { offset, .. } = &mut entry.body {
+            *offset = body_offset;
+        }
+        Ok(entry)
+    }
+
+    fn copy_body<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match &self.body {
+            EntryBody::File { file, offset } => {
+                file_region(file, *offset, self.body_len).copy_to(out)
+            }
+            EntryBody::Memory(body) => out.write_all(body),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn matches(&self, req: &HttpRequest) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.header(name).unwrap_or_default() == value)
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        SystemTime::now() < self.expires
+    }
+
+    /// Builds the response served from this entry.
+    pub fn response(&self, req: &HttpRequest, cache_status: &str) -> HttpResponse {
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), self.status);
+        for (name, value) in &self.headers {
+            resp.set_header(name, value);
+        }
+        let age = SystemTime::now()
+            .duration_since(self.stored)
+            .unwrap_or_default();
+        resp.set_header("Age", &age.as_secs().to_string());
+        resp.set_header("X-Cache-Status", cache_status);
+        if *req.method() == Method::HEAD {
+            resp.set_header("Content-Length", &self.body_len.to_string());
+            return resp;
+        }
+        match &self.body {
+            EntryBody::File { file, offset } => {
+                resp.set_body_file(file_region(file, *offset, self.body_len));
+            }
+            EntryBody::Memory(body) => {
+                resp.set_body_bytes(body);
+            }
+        }
+        resp
+    }
+}
+
+fn file_region(file: &Arc<File>, offset: u64, len: u64) -> FileBody {
+    let mut body = FileBody::new(Arc::clone(file), len);
+    body.offset = offset;
+    body
+}
+
+/// Copies a response body into a temporary cache file as it is read, and
+/// moves the file into place once the body has been read in full. A body
+/// that is cut short leaves nothing behind.
+struct CacheWriter {
+    zone: Arc<CacheZone>,
+    key: String,
+    temp: PathBuf,
+    file: Option<File>,
+    written: u64,
+    /// Released once the entry is stored or given up.
+    _update: Option<UpdateGuard>,
+}
+
+impl CacheWriter {
+    fn append(&mut self, data: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        match file.write_all(data) {
+            Ok(()) => self.written += data.len() as u64,
+            Err(e) => {
+                log_error!("cannot write cache file \"{}\": {}", self.temp.display(), e);
+                self.abandon();
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        let Some(file) = self.file.take() else {
+            return;
+        };
+        drop(file);
+        if let Err(e) = self.zone.commit(&self.key, &self.temp, self.written) {
+            log_error!("cannot store cache file \"{}\": {}", self.temp.display(), e);
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+
+    fn abandon(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.append(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
+
+/// Marks a key as being fetched from the upstream until dropped.
+pub struct UpdateGuard {
+    zone: Arc<CacheZone>,
+    key: String,
+}
+
+impl Drop for UpdateGuard {
+    fn drop(&mut self) {
+        if let Ok(mut updating) = self.zone.updating.lock() {
+            updating.remove(&self.key);
+        }
+        self.zone.updated.notify_all();
+    }
+}
+
+/// Reads a streamed body, saving it to the cache on the way.
+struct TeeBody<R> {
+    body: R,
+    writer: CacheWriter,
+    /// The length the upstream announced, if any; a shorter body is not
+    /// stored.
+    expected: Option<u64>,
+    read: u64,
+}
+
+impl<R: Read> Read for TeeBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.body.read(buf)?;
+        if n == 0 {
+            if self.expected.is_none_or(|len| len == self.read) {
+                self.writer.finish();
+            } else {
+                self.writer.abandon();
+            }
+        } else {
+            self.read += n as u64;
+            self.writer.append(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// Where a cache fetches the responses it stores.
+pub trait CacheOrigin: Send + Sync {
+    /// The value of `$proxy_host` in cache keys.
+    fn host(&self) -> String;
+
+    fn fetch(&self, req: &HttpRequest) -> io::Result<HttpResponse>;
+
+    /// The response sent to the client when fetching failed.
+    fn failure(&self, req: &HttpRequest, error: io::Error) -> HttpResponse;
+}
+
+/// Serves the responses of a proxied location from a cache zone, filling it
+/// with cacheable upstream responses.
+pub struct ProxyCache {
+    zone: Arc<CacheZone>,
+    key: VarTemplate,
+    revalidate: bool,
+    use_stale: UseStale,
+    background_update: bool,
+    /// How long requests wait for another request filling the same missing
+    /// entry; `None` lets every request fetch it.
+    lock: Option<Duration>,
+    /// Allows PURGE requests when it renders to neither "" nor "0".
+    purge: Option<VarTemplate>,
+    /// Skip looking up the cache when any is set.
+    bypass: Vec<VarTemplate>,
+    /// Skip storing the response when any is set.
+    no_cache: Vec<VarTemplate>,
+    methods: Vec<Method>,
+}
+
+impl ProxyCache {
+    pub fn new(zone: Arc<CacheZone>) -> Self {
+        Self {
+            zone,
+            key: VarTemplate::parse(DEFAULT_KEY),
+            revalidate: false,
+            use_stale: UseStale::default(),
+            background_update: false,
+            lock: None,
+            purge: None,
+            bypass: Vec::new(),
+            no_cache: Vec::new(),
+            methods: vec![Method::GET, Method::HEAD],
+        }
+    }
+
+    /// Revalidates expired entries that have an `ETag` or `Last-Modified`
+    /// with a conditional request instead of fetching them again.
+    pub fn with_revalidate(mut self, revalidate: bool) -> Self {
+        self.revalidate = revalidate;
+        self
+    }
+
+    /// Serves expired entries when the upstream fails as `use_stale` lists,
+    /// and, with `background_update` and `updating`, while a fresh copy is
+    /// fetched in the background.
+    pub fn with_use_stale(mut self, use_stale: UseStale, background_update: bool) -> Self {
+        self.use_stale = use_stale;
+        self.background_update = background_update;
+        self
+    }
+
+    /// Makes requests for a missing entry wait up to `timeout` while another
+    /// request fetches it, instead of all going to the upstream.
+    pub fn with_lock(mut self, timeout: Option<Duration>) -> Self {
+        self.lock = timeout;
+        self
+    }
+
+    /// Lets PURGE requests remove entries when `condition` renders to
+    /// neither an empty string nor "0".
+    pub fn with_purge(mut self, condition: Option<VarTemplate>) -> Self {
+        self.purge = condition;
+        self
+    }
+
+    /// Stores and looks up responses under `key` instead of the default.
+    pub fn with_key(mut self, key: VarTemplate) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Skips looking up the cache when any of `bypass` is set, and storing
+    /// the response when any of `no_cache` is, where a value is set when it
+    /// renders to neither an empty string nor "0".
+    pub fn with_conditions(mut self, bypass: Vec<VarTemplate>, no_cache: Vec<VarTemplate>) -> Self {
+        self.bypass = bypass;
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Caches the responses to `methods` as well as to GET and HEAD.
+    pub fn with_methods(mut self, methods: Vec<Method>) -> Self {
+        for method in methods {
+            if !self.methods.contains(&method) {
+                self.methods.push(method);
+            }
+        }
+        self
+    }
+
+    fn variables<'a>(
+        &self,
+        req: &'a HttpRequest,
+        origin: &Arc<dyn CacheOrigin>,
+    ) -> RequestVariables<'a> {
+        RequestVariables::new(req).with_value("proxy_host", origin.host())
+    }
+
+    /// Answers from the cache when a fresh entry exists, and otherwise with
+    /// the response of `origin`, storing it when it may be cached. The
+    /// outcome is reported in `X-Cache-Status`.
+    pub fn handle(&self, req: &HttpRequest, origin: &Arc<dyn CacheOrigin>) -> HttpResponse {
+        let vars = self.variables(req, origin);
+        if let Some(condition) = self.purge.as_ref().filter(|_| req.method() == "PURGE") {
+            let status = if !any_set(std::slice::from_ref(condition), &vars) {
+                StatusCode::FORBIDDEN
+            } else if self.zone.purge(&self.key.render(&vars)) > 0 {
+                StatusCode::OK
+            } else {
+                StatusCode::NOT_FOUND
+            };
+            return HttpProcessor::create_status_response(req.version(), status);
+        }
+        if !self.methods.contains(req.method()) {
+            return origin.fetch(req).unwrap_or_else(|e| origin.failure(req, e));
+        }
+        let key = self.key.render(&vars);
+        if any_set(&self.bypass, &vars) {
+            let result = origin.fetch(req);
+            return self.respond(Some(&key), req, origin, result, None, "BYPASS");
+        }
+        let Some(entry) = self.zone.lookup(&key, req) else {
+            return self.fill(&key, req, origin);
+        };
+        if entry.is_fresh() {
+            return entry.response(req, "HIT");
+        }
+
+        let update = self.zone.begin_update(&key);
+        if self.use_stale.updating {
+            match update {
+                None => return entry.response(req, "UPDATING"),
+                // Updating in the background is pointless if nothing is stored.
+                Some(update) if self.background_update && !any_set(&self.no_cache, &vars) => {
+                    self.update_in_background(key, req, origin, &entry, update);
+                    return entry.response(req, "STALE");
+                }
+                update => return self.refetch(&key, req, origin, entry, update),
+            }
+        }
+        self.refetch(&key, req, origin, entry, update)
+    }
+
+    /// Fetches a missing entry. With the cache lock, a request finding the
+    /// entry already being fetched waits for it and answers from the cache;
+    /// if the wait times out it fetches the response without storing it.
+    fn fill(&self, key: &str, req: &HttpRequest, origin: &Arc<dyn CacheOrigin>) -> HttpResponse {
+        let deadline = self.lock.map(|timeout| Instant::now() + timeout);
+        loop {
+            let update = self.zone.begin_update(key);
+            let Some(deadline) = deadline.filter(|_| update.is_none()) else {
+                let result = origin.fetch(req);
+                return self.respond(Some(key), req, origin, result, update, "MISS");
+            };
+            if !self.zone.wait_update(key, deadline) {
+                let result = origin.fetch(req);
+                return self.respond(None, req, origin, result, None, "MISS");
+            }
+            if let Some(entry) = self.zone.lookup(key, req).filter(CachedEntry::is_fresh) {
+                return entry.response(req, "HIT");
+            }
+        }
+    }
+
+    /// Fetches a fresh copy of an expired entry, revalidating it if allowed,
+    /// and falls back to the entry when the upstream fails as allowed.
+    fn refetch(
+        &self,
+        key: &str,
+        req: &HttpRequest,
+        origin: &Arc<dyn CacheOrigin>,
+        entry: CachedEntry,
+        update: Option<UpdateGuard>,
+    ) -> HttpResponse {
+        let conditional = self
+            .revalidate
+            .then(|| conditional_request(req, &entry))
+            .flatten();
+        let result = origin.fetch(conditional.as_ref().unwrap_or(req));
+        match &result {
+            Ok(resp) if conditional.is_some() && resp.status() == Some(304) => {
+                if let Err(e) = self.zone.refresh(&entry, resp) {
+                    log_error!("cannot refresh cache entry \"{}\": {}", key, e);
+                }
+                let entry = self.zone.lookup(key, req).unwrap_or(entry);
+                return entry.response(req, "REVALIDATED");
+            }
+            result if self.use_stale.covers(result) => return entry.response(req, "STALE"),
+            _ => {}
+        }
+        self.respond(Some(key), req, origin, result, update, "EXPIRED")
+    }
+
+    /// Sends the upstream's answer to the client, storing it under `key` on
+    /// the way.
+    fn respond(
+        &self,
+        key: Option<&str>,
+        req: &HttpRequest,
+        origin: &Arc<dyn CacheOrigin>,
+        result: io::Result<HttpResponse>,
+        update: Option<UpdateGuard>,
+        cache_status: &str,
+    ) -> HttpResponse {
+        let mut resp = match result {
+            Ok(mut resp) => {
+                let store = key.filter(|_| {
+                    *req.method() != Method::HEAD
+                        && !any_set(&self.no_cache, &self.variables(req, origin))
+                });
+                if let Some(key) = store {
+                    self.zone.store(key, req, &mut resp, update);
+                }
+                resp
+            }
+            Err(e) => origin.failure(req, e),
+        };
+        resp.set_header("X-Cache-Status", cache_status);
+        resp
+    }
+
+    /// Fetches a fresh copy of an expired entry on another thread, reading
+    /// the whole body so that it is stored.
+    fn update_in_background(
+        &self,
+        key: String,
+        req: &HttpRequest,
+        origin: &Arc<dyn CacheOrigin>,
+        entry: &CachedEntry,
+        update: UpdateGuard,
+    ) {
+        let mut req = req.clone();
+        if *req.method() == Method::HEAD {
+            req.set_method(Method::GET);
+        }
+        let conditional = self
+            .revalidate
+            .then(|| conditional_request(&req, entry))
+            .flatten();
+        let revalidating = conditional.is_some();
+        let req = conditional.unwrap_or(req);
+        let (zone, origin) = (Arc::clone(&self.zone), Arc::clone(origin));
+        thread::spawn(move || match origin.fetch(&req) {
+            Ok(resp) if revalidating && resp.status() == Some(304) => {
+                if let Some(entry) = zone.lookup(&key, &req) {
+                    if let Err(e) = zone.refresh(&entry, &resp) {
+                        log_error!("cannot refresh cache entry \"{}\": {}", key, e);
+                    }
+                }
+            }
+            Ok(mut resp) => {
+                zone.store(&key, &req, &mut resp, Some(update));
+                if let Some(stream) = resp.stream.take() {
+                    let _ = stream.copy_to(&mut io::sink(), false);
+                }
+            }
+            Err(e) => log_error!("background update of cache entry \"{}\" failed: {}", key, e),
+        });
+    }
+}
+
+/// Whether any of `conditions` renders to neither an empty string nor "0".
+fn any_set(conditions: &[VarTemplate], vars: &RequestVariables) -> bool {
+    conditions.iter().any(|condition| {
+        let value = condition.render(vars);
+        !value.is_empty() && value != "0"
+    })
+}
+
+/// A copy of `req` asking the upstream whether `entry` is still current, if
+/// the entry has a validator.
+fn conditional_request(req: &HttpRequest, entry: &CachedEntry) -> Option<HttpRequest> {
+    let etag = entry.header("ETag");
+    let modified = entry.header("Last-Modified");
+    if etag.is_none() && modified.is_none() {
+        return None;
+    }
+    let mut conditional = req.clone();
+    conditional.remove_header("If-None-Match");
+    conditional.remove_header("If-Modified-Since");
+    if let Some(etag) = etag {
+        conditional.set_header("If-None-Match", etag);
+    }
+    if let Some(modified) = modified {
+        conditional.set_header("If-Modified-Since", modified);
+    }
+    Some(conditional)
+}
+
+/// How long a response may be served from the cache, from `Cache-Control`
+/// or else `Expires`; `None` if it must not be stored.
+fn lifetime(headers: &[(&str, &str)]) -> Option<Duration> {
+    let values = |wanted: &'static str| {
+        headers
+            .iter()
+            .filter(move |(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| *value)
+    };
+    if values("Set-Cookie").next().is_some() {
+        return None;
+    }
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for value in values("Cache-Control") {
+        for directive in value.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            let (name, argument) = directive.split_once('=').unwrap_or((&directive, ""));
+            let seconds = || argument.trim_matches('"').parse::<u64>().ok();
+            match name.trim() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = seconds(),
+                "s-maxage" => shared_max_age = seconds(),
+                _ => {}
+            }
+        }
+    }
+    if let Some(seconds) = shared_max_age.or(max_age) {
+        return (seconds > 0).then(|| Duration::from_secs(seconds));
+    }
+    let expires = parse_http_date(values("Expires").next()?)?;
+    expires
+        .duration_since(SystemTime::now())
+        .ok()
+        .filter(|lifetime| !lifetime.is_zero())
+}
+
+/// The request headers a response varies on with their values, or `None`
+/// for `Vary: *`, which can never be matched.
+fn vary(headers: &[(&str, &str)], req: &HttpRequest) -> Option<Vec<(String, String)>> {
+    let mut vary = Vec::new();
+    for (_, value) in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+    {
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            if name == "*" {
+                return None;
+            }
+            vary.push((
+                name.to_string(),
+                req.header(name).unwrap_or_default().to_string(),
+            ));
+        }
+    }
+    Some(vary)
+}
+
+/// Builds the cache of a proxied location chain that sets `proxy_cache`.
+pub fn proxy_cache(chain: &[&ConfigContext]) -> Option<ProxyCache> {
+    let config = merged_config::<ProxyCacheConfig>(chain);
+    let name = config.zone.flatten()?;
+    let paths = merged_config::<ProxyCachePaths>(chain.get(..1)?);
+    match paths.zones.iter().find(|zone| zone.name == name) {
+        Some(zone) => Some(
+            ProxyCache::new(Arc::clone(zone))
+                .with_revalidate(config.revalidate.unwrap_or(false))
+                .with_use_stale(
+                    config.use_stale.unwrap_or_default(),
+                    config.background_update.unwrap_or(false),
+                )
+                .with_lock(
+                    config
+                        .lock
+                        .unwrap_or(false)
+                        .then(|| config.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT)),
+                )
+                .with_purge(config.purge.flatten().map(|c| VarTemplate::parse(&c)))
+                .with_key(VarTemplate::parse(
+                    config.key.as_deref().unwrap_or(DEFAULT_KEY),
+                ))
+                .with_conditions(templates(config.bypass), templates(config.no_cache))
+                .with_methods(config.methods.unwrap_or_default()),
+        ),
+        None => {
+            log_error!("unknown proxy_cache zone \"{}\", caching disabled", name);
+            None
+        }
+    }
+}
+
+fn templates(values: Option<Vec<String>>) -> Vec<VarTemplate> {
+    values
+        .unwrap_or_default()
+        .iter()
+        .map(|value| VarTemplate::parse(value))
+        .collect()
+}
+
+pub fn handle_proxy_cache_path(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let zone =
+        CacheZone::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    let paths = ctx.block_config::<ProxyCachePaths>();
+    let Ok(mut paths) = paths.lock() else {
+        return Ok(());
+    };
+    if paths.zones.iter().any(|other| other.name == zone.name) {
+        return Err(ctx.invalid_value(&zone.name, "duplicate cache zone name"));
+    }
+    paths.zones.push(Arc::new(zone));
+    Ok(())
+}
+
+pub fn handle_proxy_cache(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    let zone = (name != "off").then_some(name);
+    update(ctx, |config| config.zone = Some(zone));
+    Ok(())
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut ProxyCacheConfig)) {
+    if let Ok(mut config) = ctx.block_config::<ProxyCacheConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_proxy_cache_revalidate(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.revalidate = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_use_stale(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let stale =
+        UseStale::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    update(ctx, |config| config.use_stale = Some(stale));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_background_update(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.background_update = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_lock(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.lock = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_lock_timeout(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let timeout = ctx.duration_arg(0)?;
+    update(ctx, |config| config.lock_timeout = Some(timeout));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_purge(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let condition = ctx.str_arg(0)?;
+    let condition = (condition != "off").then_some(condition);
+    update(ctx, |config| config.purge = Some(condition));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_key(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let key = ctx.str_arg(0)?;
+    update(ctx, |config| config.key = Some(key));
+    Ok(())
+}
+
+pub fn handle_proxy_cache_bypass(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let conditions = ctx.args();
+    if !conditions.is_empty() {
+        update(ctx, |config| config.bypass = Some(conditions));
+    }
+    Ok(())
+}
+
+pub fn handle_proxy_no_cache(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let conditions = ctx.args();
+    if !conditions.is_empty() {
+        update(ctx, |config| config.no_cache = Some(conditions));
+    }
+    Ok(())
+}
+
+pub fn handle_proxy_cache_methods(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let mut methods = Vec::new();
+    for arg in ctx.args() {
+        match Method::from_bytes(arg.as_bytes()) {
+            Ok(method) if arg.bytes().all(|b| b.is_ascii_uppercase()) => methods.push(method),
+            _ => return Err(ctx.invalid_value(&arg, "not an HTTP method")),
+        }
+    }
+    if !methods.is_empty() {
+        update(ctx, |config| config.methods = Some(methods));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    type Respond = Box<dyn Fn(&HttpRequest) -> io::Result<HttpResponse> + Send>;
+
+    struct TestOrigin {
+        fetches: AtomicUsize,
+        respond: Mutex<Respond>,
+    }
+
+    impl TestOrigin {
+        fn new(respond: Respond) -> Arc<Self> {
+            Arc::new(Self {
+                fetches: AtomicUsize::new(0),
+                respond: Mutex::new(respond),
+            })
+        }
+
+        fn fetches(&self) -> usize {
+            self.fetches.load(Ordering::SeqCst)
+        }
+    }
+
+    impl CacheOrigin for TestOrigin {
+        fn host(&self) -> String {
+            "backend".to_string()
+        }
+
+        fn fetch(&self, req: &HttpRequest) -> io::Result<HttpResponse> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            (self.respond.lock().unwrap())(req)
+        }
+
+        fn failure(&self, _req: &HttpRequest, _error: io::Error) -> HttpResponse {
+            let mut resp = HttpResponse::new();
+            resp.set_status_line(http::Version::HTTP_11, StatusCode::BAD_GATEWAY);
+            resp
+        }
+    }
+
+    fn upstream_response(cache_control: &str, body: &'static [u8]) -> HttpResponse {
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(http::Version::HTTP_11, StatusCode::OK);
+        resp.set_header("Content-Type", "text/plain");
+        resp.set_header("Cache-Control", cache_control);
+        resp.set_body_stream(StreamBody::new(body, Some(body.len() as u64)));
+        resp
+    }
+
+    fn temp_zone(name: &str) -> (std::path::PathBuf, [String; 3]) {
+        let dir = std::env::temp_dir().join(format!("blur-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let args = [
+            dir.to_string_lossy().to_string(),
+            "keys_zone=main:1m".to_string(),
+            "levels=1:2".to_string(),
+        ];
+        (dir, args)
+    }
+
+    fn get(cache: &ProxyCache, origin: &Arc<TestOrigin>, path: &str) -> HttpResponse {
+        let mut req = HttpRequest::new();
+        req.parse(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path).as_bytes())
+            .unwrap();
+        let origin: Arc<dyn CacheOrigin> = origin.clone();
+        let mut resp = cache.handle(&req, &origin);
+        resp.load_body().unwrap();
+        resp
+    }
+
+    #[test]
+    fn test_proxy_cache_stores_and_serves() {
+        let (dir, args) = temp_zone("proxy-cache");
+        let cache = ProxyCache::new(Arc::new(CacheZone::parse(&args).unwrap()));
+        let origin = TestOrigin::new(Box::new(|req| {
+            let cache_control = match req.path() {
+                "/a" => "public, max-age=60",
+                _ => "no-store",
+            };
+            Ok(upstream_response(cache_control, b"hello"))
+        }));
+
+        let resp = get(&cache, &origin, "/a");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("MISS"));
+        assert_eq!(resp.body, b"hello");
+        let resp = get(&cache, &origin, "/a");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("HIT"));
+        assert_eq!(resp.header_value("Content-Type"), Some("text/plain"));
+        assert_eq!(resp.header_value("Age"), Some("0"));
+        assert_eq!(resp.body, b"hello");
+        assert_eq!(origin.fetches(), 1);
+
+        // Responses that forbid caching are fetched every time.
+        get(&cache, &origin, "/b");
+        assert_eq!(
+            get(&cache, &origin, "/b").header_value("X-Cache-Status"),
+            Some("MISS")
+        );
+        assert_eq!(origin.fetches(), 3);
+
+        // Entries survive a restart.
+        let zone = CacheZone::parse(&args).unwrap();
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        assert!(zone
+            .lookup("httpbackend/a", &req)
+            .is_some_and(|e| e.is_fresh()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_cache_serves_stale_and_revalidates() {
+        let (dir, args) = temp_zone("proxy-cache-stale");
+        let stale = UseStale::parse(&["error".to_string(), "updating".to_string()]).unwrap();
+        let zone = Arc::new(CacheZone::parse(&args).unwrap());
+        let cache = ProxyCache::new(Arc::clone(&zone))
+            .with_revalidate(true)
+            .with_use_stale(stale.clone(), false);
+        let origin = TestOrigin::new(Box::new(|_| {
+            let mut resp = upstream_response("max-age=1", b"v1");
+            resp.set_header("ETag", "\"v1\"");
+            Ok(resp)
+        }));
+        get(&cache, &origin, "/a");
+        get(&cache, &origin, "/b");
+        thread::sleep(Duration::from_millis(2100));
+
+        *origin.respond.lock().unwrap() =
+            Box::new(|_| Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down")));
+        let resp = get(&cache, &origin, "/a");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("STALE"));
+        assert_eq!(resp.body, b"v1");
+
+        *origin.respond.lock().unwrap() = Box::new(|req| {
+            assert_eq!(req.header("If-None-Match"), Some("\"v1\""));
+            let mut resp = HttpResponse::new();
+            resp.set_status_line(http::Version::HTTP_11, StatusCode::NOT_MODIFIED);
+            resp.set_header("Cache-Control", "max-age=60");
+            Ok(resp)
+        });
+        let resp = get(&cache, &origin, "/a");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("REVALIDATED"));
+        assert_eq!(resp.header_value("ETag"), Some("\"v1\""));
+        assert_eq!(resp.body, b"v1");
+        assert_eq!(
+            get(&cache, &origin, "/a").header_value("X-Cache-Status"),
+            Some("HIT")
+        );
+
+        // With background updates the stale copy is answered at once.
+        let cache = ProxyCache::new(zone).with_use_stale(stale, true);
+        *origin.respond.lock().unwrap() = Box::new(|_| Ok(upstream_response("max-age=60", b"v2")));
+        let resp = get(&cache, &origin, "/b");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("STALE"));
+        assert_eq!(resp.body, b"v1");
+        let mut resp = get(&cache, &origin, "/b");
+        for _ in 0..50 {
+            if resp.header_value("X-Cache-Status") == Some("HIT") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            resp = get(&cache, &origin, "/b");
+        }
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("HIT"));
+        assert_eq!(resp.body, b"v2");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_cache_lock_coalesces_misses() {
+        let (dir, args) = temp_zone("proxy-cache-lock");
+        let cache = ProxyCache::new(Arc::new(CacheZone::parse(&args).unwrap()))
+            .with_lock(Some(Duration::from_secs(5)));
+        let origin = TestOrigin::new(Box::new(|_| {
+            thread::sleep(Duration::from_millis(300));
+            Ok(upstream_response("max-age=60", b"hello"))
+        }));
+
+        let statuses: Vec<String> = thread::scope(|scope| {
+            let requests: Vec<_> = (0..5)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let resp = get(&cache, &origin, "/a");
+                        assert_eq!(resp.body, b"hello");
+                        resp.header_value("X-Cache-Status").unwrap().to_string()
+                    })
+                })
+                .collect();
+            requests.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        assert_eq!(origin.fetches(), 1);
+        assert_eq!(statuses.iter().filter(|s| *s == "MISS").count(), 1);
+        assert_eq!(statuses.iter().filter(|s| *s == "HIT").count(), 4);
+
+        // Waiting past the timeout goes to the upstream without storing.
+        let cache = cache.with_lock(Some(Duration::from_millis(50)));
+        let statuses: Vec<String> = thread::scope(|scope| {
+            let requests: Vec<_> = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let resp = get(&cache, &origin, "/b");
+                        resp.header_value("X-Cache-Status").unwrap().to_string()
+                    })
+                })
+                .collect();
+            requests.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        assert_eq!(statuses, ["MISS", "MISS"]);
+        assert_eq!(origin.fetches(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_cache_purge() {
+        let (dir, args) = temp_zone("proxy-cache-purge");
+        let cache = ProxyCache::new(Arc::new(CacheZone::parse(&args).unwrap()))
+            .with_purge(Some(VarTemplate::parse("$http_x_purge")));
+        let origin = TestOrigin::new(Box::new(|_| Ok(upstream_response("max-age=60", b"hello"))));
+        for path in ["/news/1", "/news/2", "/about"] {
+            get(&cache, &origin, path);
+        }
+        let purge = |target: &str, token: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(
+                format!(
+                    "PURGE {} HTTP/1.1\r\nHost: example.com\r\nX-Purge: {}\r\n\r\n",
+                    target, token
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            let origin: Arc<dyn CacheOrigin> = origin.clone();
+            cache.handle(&req, &origin).status()
+        };
+
+        assert_eq!(purge("/about", "0"), Some(403));
+        assert_eq!(purge("/about", "1"), Some(200));
+        assert_eq!(purge("/about", "1"), Some(404));
+        assert_eq!(purge("/news/*", "1"), Some(200));
+        assert_eq!(
+            get(&cache, &origin, "/news/2").header_value("X-Cache-Status"),
+            Some("MISS")
+        );
+        assert_eq!(origin.fetches(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_cache_key_and_conditions() {
+        let (dir, args) = temp_zone("proxy-cache-conditions");
+        let cache = ProxyCache::new(Arc::new(CacheZone::parse(&args).unwrap()))
+            .with_key(VarTemplate::parse("$host$uri"))
+            .with_conditions(
+                vec![VarTemplate::parse("$http_x_bypass")],
+                vec![VarTemplate::parse("$arg_nocache")],
+            )
+            .with_methods(vec![Method::POST]);
+        let origin = TestOrigin::new(Box::new(|_| Ok(upstream_response("max-age=60", b"hello"))));
+        let send = |raw: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(raw.as_bytes()).unwrap();
+            let origin: Arc<dyn CacheOrigin> = origin.clone();
+            let mut resp = cache.handle(&req, &origin);
+            resp.load_body().unwrap();
+            resp.header_value("X-Cache-Status").map(str::to_string)
+        };
+
+        // The query string is not part of the key.
+        assert_eq!(
+            send("GET /a?x=1 HTTP/1.1\r\nHost: example.com\r\n\r\n").as_deref(),
+            Some("MISS")
+        );
+        assert_eq!(
+            send("GET /a?x=2 HTTP/1.1\r\nHost: example.com\r\n\r\n").as_deref(),
+            Some("HIT")
+        );
+        assert_eq!(
+            send("GET /a HTTP/1.1\r\nHost: example.com\r\nX-Bypass: 1\r\n\r\n").as_deref(),
+            Some("BYPASS")
+        );
+        assert_eq!(origin.fetches(), 2);
+
+        send("GET /b?nocache=1 HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(
+            send("GET /b HTTP/1.1\r\nHost: example.com\r\n\r\n").as_deref(),
+            Some("MISS")
+        );
+
+        let post = "POST /c HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(send(post).as_deref(), Some("MISS"));
+        assert_eq!(send(post).as_deref(), Some("HIT"));
+        let put = "PUT /c HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(send(put), None);
+        assert_eq!(origin.fetches(), 6);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_cache_memory_tier() {
+        let (dir, [root, keys_zone, _]) = temp_zone("proxy-cache-memory");
+        let args = [
+            root,
+            keys_zone,
+            "memory_size=1m".to_string(),
+            "memory_max_object=10".to_string(),
+        ];
+        let zone = Arc::new(CacheZone::parse(&args).unwrap());
+        let cache = ProxyCache::new(Arc::clone(&zone));
+        let origin = TestOrigin::new(Box::new(|req| {
+            Ok(match req.path() {
+                "/small" => upstream_response("max-age=60", b"hello"),
+                _ => upstream_response("max-age=60", b"a body over ten bytes"),
+            })
+        }));
+        for path in ["/small", "/large"] {
+            get(&cache, &origin, path);
+            assert_eq!(
+                get(&cache, &origin, path).header_value("X-Cache-Status"),
+                Some("HIT")
+            );
+        }
+
+        // Only the small body is served without its file.
+        fs::remove_dir_all(&dir).unwrap();
+        let resp = get(&cache, &origin, "/small");
+        assert_eq!(resp.header_value("X-Cache-Status"), Some("HIT"));
+        assert_eq!(resp.body, b"hello");
+        assert_eq!(
+            get(&cache, &origin, "/large").header_value("X-Cache-Status"),
+            Some("MISS")
+        );
+
+        // Removing an entry drops its copy in memory too.
+        assert_eq!(zone.purge("httpbackend/small"), 1);
+        assert_eq!(
+            get(&cache, &origin, "/small").header_value("X-Cache-Status"),
+            Some("MISS")
+        );
+        assert_eq!(origin.fetches(), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,490 @@
+use std::{io, net::TcpStream, path::Path, sync::Arc};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    core::config::{
+        command::{ArgType, CommandBuilder, ParameterBuilder},
+        config_context::{ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_upstream::UpstreamStream;
+
+register_commands!(
+    CommandBuilder::new("proxy_ssl_verify")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy SSL Verify")
+        .display_name("zh-tw", "驗證上游憑證")
+        .desc(
+            "en",
+            "Verifies the certificate of https:// upstream servers"
+        )
+        .desc("zh-tw", "驗證 https:// 上游伺服器的憑證")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "on or off, defaults to off; certificates are checked against proxy_ssl_trusted_certificate"
+            )
+            .desc(
+                "zh-tw",
+                "on 或 off，預設為 off；以 proxy_ssl_trusted_certificate 中的憑證驗證"
+            )
+            .build()])
+        .build(handle_proxy_ssl_verify),
+    CommandBuilder::new("proxy_ssl_trusted_certificate")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy SSL Trusted Certificate")
+        .display_name("zh-tw", "上游信任憑證")
+        .desc(
+            "en",
+            "Sets the CA certificates used to verify upstream servers"
+        )
+        .desc("zh-tw", "設定用於驗證上游伺服器的 CA 憑證")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "PEM file with one or more certificates")
+            .desc("zh-tw", "包含一個或多個憑證的 PEM 檔案")
+            .build()])
+        .build(handle_proxy_ssl_trusted_certificate),
+    CommandBuilder::new("proxy_ssl_server_name")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy SSL Server Name")
+        .display_name("zh-tw", "上游 SNI")
+        .desc(
+            "en",
+            "Sends the upstream host name with SNI when connecting over TLS"
+        )
+        .desc("zh-tw", "以 TLS 連線時透過 SNI 傳送上游主機名稱")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_proxy_ssl_server_name),
+    CommandBuilder::new("proxy_ssl_certificate")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy SSL Certificate")
+        .display_name("zh-tw", "上游用戶端憑證")
+        .desc(
+            "en",
+            "Sets the client certificate presented to upstream servers"
+        )
+        .desc("zh-tw", "設定向上游伺服器出示的用戶端憑證")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "PEM file with the certificate chain, used with proxy_ssl_certificate_key"
+            )
+            .desc(
+                "zh-tw",
+                "包含憑證鏈的 PEM 檔案，需搭配 proxy_ssl_certificate_key"
+            )
+            .build()])
+        .build(handle_proxy_ssl_certificate),
+    CommandBuilder::new("proxy_ssl_certificate_key")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Proxy SSL Certificate Key")
+        .display_name("zh-tw", "上游用戶端私鑰")
+        .desc(
+            "en",
+            "Sets the private key of the client certificate"
+        )
+        .desc("zh-tw", "設定用戶端憑證的私鑰")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "File")
+            .display_name("zh-tw", "檔案")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "PEM file with the private key")
+            .desc("zh-tw", "包含私鑰的 PEM 檔案")
+            .build()])
+        .build(handle_proxy_ssl_certificate_key),
+);
+
+#[derive(Debug, Error)]
+pub enum ProxySslError {
+    #[error("invalid upstream server name \"{0}\"")]
+    ServerName(String),
+    #[error("proxy_ssl_certificate and proxy_ssl_certificate_key must be set together")]
+    IncompleteClientCertificate,
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ProxySslConfig {
+    pub verify: Option<bool>,
+    pub trusted_certificates: Option<Vec<CertificateDer<'static>>>,
+    pub server_name: Option<bool>,
+    pub certificate: Option<Vec<CertificateDer<'static>>>,
+    pub certificate_key: Option<Arc<PrivateKeyDer<'static>>>,
+}
+
+impl MergeConfig for ProxySslConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.verify = self.verify.or(parent.verify);
+        self.trusted_certificates = self
+            .trusted_certificates
+            .take()
+            .or_else(|| parent.trusted_certificates.clone());
+        self.server_name = self.server_name.or(parent.server_name);
+        self.certificate = self
+            .certificate
+            .take()
+            .or_else(|| parent.certificate.clone());
+        self.certificate_key = self
+            .certificate_key
+            .take()
+            .or_else(|| parent.certificate_key.clone());
+    }
+}
+
+/// The TLS client settings used to connect to an https:// upstream.
+pub struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl UpstreamTls {
+    /// Builds the client settings for connecting to `host`, the name the
+    /// server certificate is verified against and sent with SNI.
+    pub fn new(config: &ProxySslConfig, host: &str) -> Result<Self, ProxySslError> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| ProxySslError::ServerName(host.to_string()))?;
+        let provider = Arc::new(crypto::aws_lc_rs::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if config.verify.unwrap_or(false) {
+            let mut roots = RootCertStore::empty();
+            for cert in config.trusted_certificates.iter().flatten() {
+                roots.add(cert.clone())?;
+            }
+            builder.with_root_certificates(roots)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyServerCert(provider)))
+        };
+        let mut client_config = match (&config.certificate, &config.certificate_key) {
+            (Some(certs), Some(key)) => {
+                builder.with_client_auth_cert(certs.clone(), key.clone_key())?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(ProxySslError::IncompleteClientCertificate),
+        };
+        client_config.enable_sni = config.server_name.unwrap_or(false);
+        Ok(Self {
+            config: Arc::new(client_config),
+            server_name,
+        })
+    }
+
+    /// Completes the TLS handshake over a connected socket.
+    pub fn connect(&self, mut stream: TcpStream) -> io::Result<UpstreamStream> {
+        let mut conn = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(io::Error::other)?;
+        while conn.is_handshaking() {
+            // Handshake failures are connection errors, not malformed
+            // responses.
+            conn.complete_io(&mut stream).map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => io::Error::other(e),
+                _ => e,
+            })?;
+        }
+        Ok(UpstreamStream::Tls(Box::new(StreamOwned::new(
+            conn, stream,
+        ))))
+    }
+}
+
+/// Accepts any upstream certificate for `proxy_ssl_verify off`, while still
+/// checking the handshake signatures.
+#[derive(Debug)]
+struct AnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut ProxySslConfig)) {
+    if let Ok(mut config) = ctx.block_config::<ProxySslConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+fn load_certificates(
+    ctx: &ConfigContext,
+    path: &Path,
+) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let invalid = |e: rustls::pki_types::pem::Error| {
+        ctx.invalid_value(&path.display().to_string(), e.to_string())
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if certs.is_empty() {
+        return Err(ctx.invalid_value(&path.display().to_string(), "no certificates found"));
+    }
+    Ok(certs)
+}
+
+pub fn handle_proxy_ssl_verify(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let verify = ctx.bool_arg(0)?;
+    update(ctx, |config| config.verify = Some(verify));
+    Ok(())
+}
+
+pub fn handle_proxy_ssl_trusted_certificate(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let certs = load_certificates(ctx, &ctx.path_arg(0)?)?;
+    update(ctx, |config| config.trusted_certificates = Some(certs));
+    Ok(())
+}
+
+pub fn handle_proxy_ssl_server_name(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let server_name = ctx.bool_arg(0)?;
+    update(ctx, |config| config.server_name = Some(server_name));
+    Ok(())
+}
+
+pub fn handle_proxy_ssl_certificate(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let certs = load_certificates(ctx, &ctx.path_arg(0)?)?;
+    update(ctx, |config| config.certificate = Some(certs));
+    Ok(())
+}
+
+pub fn handle_proxy_ssl_certificate_key(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let path = ctx.path_arg(0)?;
+    let key = PrivateKeyDer::from_pem_file(&path)
+        .map_err(|e| ctx.invalid_value(&path.display().to_string(), e.to_string()))?;
+    update(ctx, |config| config.certificate_key = Some(Arc::new(key)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+    };
+    use rustls::{pki_types::PrivatePkcs8KeyDer, ServerConfig, ServerConnection};
+
+    use super::*;
+    use crate::http::{
+        http_proxy::{Proxy, ProxyTarget},
+        http_request::HttpRequest,
+    };
+
+    fn self_signed(name: &str) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(name)
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (
+            CertificateDer::from(cert.build().to_der().unwrap()),
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+                key.private_key_to_pkcs8().unwrap(),
+            )),
+        )
+    }
+
+    /// Answers TLS connections with "ok" until the listener is dropped.
+    fn serve_tls(cert: CertificateDer<'static>, key: PrivateKeyDer<'static>) -> u16 {
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut conn = ServerConnection::new(config.clone()).unwrap();
+                let mut stream = stream.unwrap();
+                let mut tls = rustls::Stream::new(&mut conn, &mut stream);
+                let mut reader = BufReader::new(&mut tls);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let _ = tls.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                );
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_proxy_ssl_verification() {
+        let (cert, key) = self_signed("localhost");
+        let (other, _) = self_signed("localhost");
+        let port = serve_tls(cert.clone(), key);
+        let target = ProxyTarget::parse(&format!("https://localhost:{}", port)).unwrap();
+        assert!(target.ssl);
+        let mut req = HttpRequest::new();
+        req.parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+
+        let status = |config: ProxySslConfig| {
+            let tls = UpstreamTls::new(&config, &target.host).unwrap();
+            let proxy = Proxy::new(target.clone(), None).with_tls(tls);
+            proxy.handle(&req).status()
+        };
+        assert_eq!(status(ProxySslConfig::default()), Some(200));
+        let verified = |trusted: &CertificateDer<'static>| ProxySslConfig {
+            verify: Some(true),
+            trusted_certificates: Some(vec![trusted.clone()]),
+            ..ProxySslConfig::default()
+        };
+        assert_eq!(status(verified(&cert)), Some(200));
+        assert_eq!(status(verified(&other)), Some(502));
+    }
+}
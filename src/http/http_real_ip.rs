@@ -0,0 +1,292 @@
+use std::net::{IpAddr, SocketAddr};
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        ip_trie::{canonical, parse_cidr, IpTrie},
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("set_real_ip_from")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Set Real IP From")
+        .display_name("zh-tw", "信任的代理位址")
+        .desc(
+            "en",
+            "Trusts a proxy or load balancer to report the client address in real_ip_header"
+        )
+        .desc(
+            "zh-tw",
+            "信任此代理或負載平衡器以 real_ip_header 提供的客戶端位址"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Address")
+            .display_name("zh-tw", "位址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "IPv4 or IPv6 address, or network in CIDR form such as 10.0.0.0/8"
+            )
+            .desc(
+                "zh-tw",
+                "IPv4 或 IPv6 位址，或 CIDR 格式的網段（例如 10.0.0.0/8）"
+            )
+            .build()])
+        .build(handle_set_real_ip_from),
+    CommandBuilder::new("real_ip_header")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Real IP Header")
+        .display_name("zh-tw", "真實 IP 標頭")
+        .desc(
+            "en",
+            "The request header trusted proxies put the client address in; the last address of a comma-separated list is used"
+        )
+        .desc(
+            "zh-tw",
+            "信任的代理放置客戶端位址的請求標頭；以逗號分隔的清單使用最後一個位址"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Header")
+            .display_name("zh-tw", "標頭")
+            .type_name("String")
+            .is_required(true)
+            .default("X-Real-IP")
+            .desc("en", "e.g. X-Forwarded-For; X-Real-IP by default")
+            .desc("zh-tw", "例如 X-Forwarded-For；預設為 X-Real-IP")
+            .build()])
+        .build(handle_real_ip_header),
+    CommandBuilder::new("real_ip_recursive")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Real IP Recursive")
+        .display_name("zh-tw", "遞迴尋找真實 IP")
+        .desc(
+            "en",
+            "Sets whether trusted addresses at the end of the list are skipped, taking the last untrusted one as the client"
+        )
+        .desc(
+            "zh-tw",
+            "設定是否略過清單尾端的信任位址，以最後一個不受信任的位址作為客戶端"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_real_ip_recursive)
+);
+
+/// The address the connection came from, before `real_ip` replaced it.
+pub const REALIP_REMOTE_ADDR: &str = "realip_remote_addr";
+
+const DEFAULT_HEADER: &str = "X-Real-IP";
+
+#[derive(Debug, Default, Clone)]
+pub struct RealIpConfig {
+    pub trusted: Option<Vec<(IpAddr, u8)>>,
+    pub header: Option<String>,
+    pub recursive: Option<bool>,
+}
+
+impl MergeConfig for RealIpConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.trusted.is_none() {
+            self.trusted = parent.trusted.clone();
+        }
+        if self.header.is_none() {
+            self.header = parent.header.clone();
+        }
+        if self.recursive.is_none() {
+            self.recursive = parent.recursive;
+        }
+    }
+}
+
+/// Replaces the client address with the one a trusted proxy reports, ahead
+/// of every phase that looks at it.
+pub struct RealIpPhase {
+    trusted: IpTrie<()>,
+    header: String,
+    recursive: bool,
+}
+
+impl RealIpPhase {
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted.lookup(addr).is_some()
+    }
+
+    /// Picks the client address out of the header sent by `peer`.
+    fn real_addr(&self, peer: IpAddr, value: &str) -> Option<IpAddr> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+        let mut addrs = value.split(',').map(str::trim).rev();
+        let mut real = parse_addr(addrs.next()?)?;
+        if self.recursive {
+            while self.is_trusted(real) {
+                match addrs.next().map(parse_addr) {
+                    Some(Some(addr)) => real = addr,
+                    Some(None) => return None,
+                    None => break,
+                }
+            }
+        }
+        Some(canonical(real))
+    }
+}
+
+/// Reads an address that may carry a port, as in `192.0.2.1:51234` or
+/// `[2001:db8::1]:443`.
+fn parse_addr(value: &str) -> Option<IpAddr> {
+    value
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+impl RequestPhase for RealIpPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        // The server phases have already replaced the address.
+        if req.var(REALIP_REMOTE_ADDR).is_some() {
+            return PhaseResult::Continue;
+        }
+        let Some(peer) = req.remote_addr() else {
+            return PhaseResult::Continue;
+        };
+        req.set_var(REALIP_REMOTE_ADDR, &peer.ip().to_string());
+        let real = req
+            .header(&self.header)
+            .and_then(|value| self.real_addr(peer.ip(), value));
+        if let Some(real) = real {
+            req.set_remote_addr(SocketAddr::new(real, peer.port()));
+        }
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the address replacement for a block chain trusting some proxies.
+pub fn real_ip_phase(chain: &[&ConfigContext]) -> Option<RealIpPhase> {
+    let config = merged_config::<RealIpConfig>(chain);
+    let mut trusted = IpTrie::new();
+    for (addr, prefix_len) in config.trusted? {
+        trusted.insert(addr, prefix_len, ());
+    }
+    Some(RealIpPhase {
+        trusted,
+        header: config.header.unwrap_or_else(|| DEFAULT_HEADER.to_string()),
+        recursive: config.recursive.unwrap_or(false),
+    })
+}
+
+pub fn handle_set_real_ip_from(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let value = ctx.str_arg(0)?;
+    let network = parse_cidr(&value).map_err(|reason| ctx.invalid_value(&value, reason))?;
+    if let Ok(mut config) = ctx.block_config::<RealIpConfig>().lock() {
+        config.trusted.get_or_insert_with(Vec::new).push(network);
+    }
+    Ok(())
+}
+
+pub fn handle_real_ip_header(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let header = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<RealIpConfig>().lock() {
+        config.header = Some(header);
+    }
+    Ok(())
+}
+
+pub fn handle_real_ip_recursive(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let recursive = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<RealIpConfig>().lock() {
+        config.recursive = Some(recursive);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_addr_from_trusted_proxies() {
+        let mut trusted = IpTrie::new();
+        for network in ["10.0.0.0/8", "192.168.1.1"] {
+            let (addr, prefix_len) = parse_cidr(network).unwrap();
+            trusted.insert(addr, prefix_len, ());
+        }
+        let mut phase = RealIpPhase {
+            trusted,
+            header: "X-Forwarded-For".to_string(),
+            recursive: false,
+        };
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+        let proxy = ip("10.1.2.3");
+        let list = "203.0.113.9, 198.51.100.2:4711, 192.168.1.1";
+
+        assert_eq!(phase.real_addr(ip("203.0.113.1"), list), None);
+        assert_eq!(phase.real_addr(proxy, list), Some(ip("192.168.1.1")));
+        assert_eq!(
+            phase.real_addr(proxy, "[2001:db8::1]:443"),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(phase.real_addr(proxy, "unknown"), None);
+
+        phase.recursive = true;
+        assert_eq!(phase.real_addr(proxy, list), Some(ip("198.51.100.2")));
+        assert_eq!(
+            phase.real_addr(ip("::ffff:10.0.0.1"), list),
+            Some(ip("198.51.100.2"))
+        );
+        assert_eq!(
+            phase.real_addr(proxy, "10.0.0.7, 192.168.1.1"),
+            Some(ip("10.0.0.7"))
+        );
+        assert_eq!(phase.real_addr(proxy, "junk, 10.0.0.7"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use std::net::SocketAddr;
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::ResponseFilter,
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(
+    CommandBuilder::new("absolute_redirect")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Absolute Redirect")
+        .display_name("zh-tw", "絕對重新導向")
+        .desc(
+            "en",
+            "Sets whether relative Location headers of redirects are sent as absolute URLs with scheme, host and port"
+        )
+        .desc(
+            "zh-tw",
+            "設定重新導向的相對 Location 標頭是否改為帶有協定、主機與連接埠的絕對網址"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_absolute_redirect),
+    CommandBuilder::new("server_name_in_redirect")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Server Name in Redirect")
+        .display_name("zh-tw", "重新導向使用伺服器名稱")
+        .desc(
+            "en",
+            "Sets whether absolute redirects use the first server_name instead of the Host header"
+        )
+        .desc(
+            "zh-tw",
+            "設定絕對重新導向是否使用第一個 server_name 而非 Host 標頭"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_server_name_in_redirect),
+    CommandBuilder::new("port_in_redirect")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Port in Redirect")
+        .display_name("zh-tw", "重新導向包含連接埠")
+        .desc(
+            "en",
+            "Sets whether absolute redirects include the port the request was received on"
+        )
+        .desc("zh-tw", "設定絕對重新導向是否包含接收請求的連接埠")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "on or off, defaults to on; 80 for http and 443 for https are never added"
+            )
+            .desc(
+                "zh-tw",
+                "on 或 off，預設為 on；http 的 80 與 https 的 443 一律省略"
+            )
+            .build()])
+        .build(handle_port_in_redirect)
+);
+
+#[derive(Debug, Default, Clone)]
+pub struct RedirectConfig {
+    pub absolute: Option<bool>,
+    pub server_name: Option<bool>,
+    pub port: Option<bool>,
+}
+
+impl MergeConfig for RedirectConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.absolute = self.absolute.or(parent.absolute);
+        self.server_name = self.server_name.or(parent.server_name);
+        self.port = self.port.or(parent.port);
+    }
+}
+
+/// Completes relative `Location` headers, such as those of `rewrite`,
+/// `return` and directory redirects, into absolute URLs.
+pub struct RedirectFilter {
+    /// The primary `server_name`, when `server_name_in_redirect` is on and
+    /// it is a plain host name.
+    server_name: Option<String>,
+    port: bool,
+}
+
+impl RedirectFilter {
+    fn authority(&self, req: &HttpRequest) -> String {
+        let local = req.local_addr();
+        let host = self
+            .server_name
+            .clone()
+            .or_else(|| req.header("Host").map(host_without_port))
+            .filter(|host| !host.is_empty())
+            .or_else(|| local.map(|addr| bracketed_ip(&addr)))
+            .unwrap_or_default();
+        let default_port = if req.is_secure() { 443 } else { 80 };
+        match local.map(|addr| addr.port()) {
+            Some(port) if self.port && port != default_port => format!("{}:{}", host, port),
+            _ => host,
+        }
+    }
+}
+
+impl ResponseFilter for RedirectFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        let Some(location) = resp
+            .header_value("Location")
+            .filter(|location| location.starts_with('/') && !location.starts_with("//"))
+            .map(str::to_string)
+        else {
+            return;
+        };
+        let scheme = if req.is_secure() { "https" } else { "http" };
+        let absolute = format!("{}://{}{}", scheme, self.authority(req), location);
+        resp.remove_header("Location");
+        resp.set_header("Location", &absolute);
+    }
+}
+
+/// The host of a `Host` header, keeping the brackets of an IPv6 address.
+fn host_without_port(host: &str) -> String {
+    let host = host.trim();
+    match host.strip_prefix('[') {
+        Some(v6) => format!("[{}]", v6.split(']').next().unwrap_or_default()),
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    }
+    .to_ascii_lowercase()
+}
+
+fn bracketed_ip(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V4(addr) => addr.ip().to_string(),
+        SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+    }
+}
+
+/// Builds the redirect filter for a block chain unless `absolute_redirect`
+/// is off.
+pub fn redirect_filter(
+    chain: &[&ConfigContext],
+    server_names: &[String],
+) -> Option<RedirectFilter> {
+    let config = merged_config::<RedirectConfig>(chain);
+    if !config.absolute.unwrap_or(true) {
+        return None;
+    }
+    let server_name = server_names
+        .first()
+        .filter(|_| config.server_name.unwrap_or(false))
+        .filter(|name| {
+            !name.is_empty()
+                && *name != "_"
+                && !name.starts_with('~')
+                && !name.contains('*')
+                && !name.starts_with('.')
+        })
+        .cloned();
+    Some(RedirectFilter {
+        server_name,
+        port: config.port.unwrap_or(true),
+    })
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut RedirectConfig)) {
+    if let Ok(mut config) = ctx.block_config::<RedirectConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_absolute_redirect(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.absolute = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_server_name_in_redirect(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.server_name = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_port_in_redirect(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.port = Some(enabled));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use crate::http::http_rewrite::redirect_response;
+
+    use super::*;
+
+    #[test]
+    fn test_relative_locations_are_completed() {
+        let request = |host: &str, port: u16| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET /docs HTTP/1.1\r\nHost: {}\r\n\r\n", host).as_bytes())
+                .unwrap();
+            req.set_connection(None, Some(SocketAddr::from(([127, 0, 0, 1], port))), false);
+            req
+        };
+        let location = |filter: &RedirectFilter, req: &HttpRequest, target: &str| {
+            let mut resp = redirect_response(req, StatusCode::MOVED_PERMANENTLY, target);
+            filter.filter(req, &mut resp);
+            resp.header_value("Location")
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let filter = RedirectFilter {
+            server_name: None,
+            port: true,
+        };
+        assert_eq!(
+            location(&filter, &request("Example.com:8080", 8080), "/docs/"),
+            "http://example.com:8080/docs/"
+        );
+        assert_eq!(
+            location(&filter, &request("example.com", 80), "/docs/"),
+            "http://example.com/docs/"
+        );
+        assert_eq!(
+            location(&filter, &request("[::1]:8080", 8080), "/a"),
+            "http://[::1]:8080/a"
+        );
+        assert_eq!(
+            location(&filter, &request("a", 8080), "https://b/"),
+            "https://b/"
+        );
+        assert_eq!(location(&filter, &request("a", 8080), "//b/"), "//b/");
+
+        let filter = RedirectFilter {
+            server_name: Some("www.example.com".to_string()),
+            port: false,
+        };
+        assert_eq!(
+            location(&filter, &request("internal:8080", 8080), "/docs/"),
+            "http://www.example.com/docs/"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_variables::parse_regex_arg};
+
+register_commands!(CommandBuilder::new("valid_referers")
+    .is_unique()
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "Valid Referers")
+    .display_name("zh-tw", "有效來源頁面")
+    .desc(
+        "en",
+        "Lists the Referer values allowed to link here; $invalid_referer is 1 for any other request, for use with if and return 403"
+    )
+    .desc(
+        "zh-tw",
+        "列出允許連結至此的 Referer；其他請求的 $invalid_referer 為 1，可搭配 if 與 return 403 使用"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Referers")
+        .display_name("zh-tw", "來源")
+        .type_name("String")
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "none for no Referer, blocked for one without http:// or https://, server_names, host names with a leading or trailing * and an optional URI prefix, or ~regex"
+        )
+        .desc(
+            "zh-tw",
+            "none 表示沒有 Referer，blocked 表示不以 http:// 或 https:// 開頭，server_names 表示伺服器名稱，主機名稱可在開頭或結尾使用 * 並可加上 URI 前綴，~ 開頭為正規表示式"
+        )
+        .build()])
+    .arity(Arity::AtLeast(1))
+    .build(handle_valid_referers));
+
+/// The variable the referer check sets.
+pub const INVALID_REFERER: &str = "invalid_referer";
+
+#[derive(Debug, Clone)]
+pub enum RefererPattern {
+    /// A host, with a leading `*.` or trailing `.*` wildcard, and an
+    /// optional URI prefix.
+    Host { host: String, uri: Option<String> },
+    /// Matched against the Referer without its scheme.
+    Regex(Regex),
+}
+
+impl RefererPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        if let Some(regex) = parse_regex_arg(pattern) {
+            return regex.map(Self::Regex);
+        }
+        let (host, uri) = match pattern.find('/') {
+            Some(slash) => (&pattern[..slash], Some(pattern[slash..].to_string())),
+            None => (pattern, None),
+        };
+        let wildcards = host.matches('*').count();
+        let valid = match wildcards {
+            0 => true,
+            1 => host.starts_with("*.") || host.ends_with(".*"),
+            _ => false,
+        };
+        if host.is_empty() || !valid {
+            return Err(format!("invalid referer pattern \"{}\"", pattern));
+        }
+        Ok(Self::Host {
+            host: host.to_ascii_lowercase(),
+            uri,
+        })
+    }
+
+    fn matches(&self, host: &str, uri: &str, referer: &str) -> bool {
+        match self {
+            Self::Regex(regex) => regex.is_match(referer),
+            Self::Host {
+                host: pattern,
+                uri: prefix,
+            } => {
+                let host_matches = if let Some(suffix) = pattern.strip_prefix('*') {
+                    host.ends_with(suffix)
+                } else if let Some(prefix) = pattern.strip_suffix('*') {
+                    host.starts_with(prefix)
+                } else {
+                    host == pattern
+                };
+                host_matches && prefix.as_ref().is_none_or(|prefix| uri.starts_with(prefix))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidReferers {
+    pub none: bool,
+    pub blocked: bool,
+    pub server_names: bool,
+    pub patterns: Vec<RefererPattern>,
+}
+
+impl ValidReferers {
+    /// Whether a request with this Referer header, sent to a server with
+    /// these names, may proceed.
+    pub fn is_valid(&self, referer: Option<&str>, server_names: &[String]) -> bool {
+        let Some(referer) = referer.map(str::trim).filter(|r| !r.is_empty()) else {
+            return self.none;
+        };
+        let lower = referer.to_ascii_lowercase();
+        let Some(rest) = ["http://", "https://"]
+            .iter()
+            .find_map(|scheme| lower.strip_prefix(scheme).map(|_| &referer[scheme.len()..]))
+        else {
+            return self.blocked;
+        };
+        let end = rest.find(['/', ':']).unwrap_or(rest.len());
+        let host = rest[..end].to_ascii_lowercase();
+        let uri = rest[end..].trim_start_matches(|c: char| c == ':' || c.is_ascii_digit());
+        if self.server_names
+            && server_names
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&host))
+        {
+            return true;
+        }
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches(&host, uri, rest))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RefererConfig {
+    pub valid: Option<ValidReferers>,
+}
+
+impl MergeConfig for RefererConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.valid.is_none() {
+            self.valid = parent.valid.clone();
+        }
+    }
+}
+
+/// Sets `$invalid_referer` ahead of the rewrite rules that test it.
+pub struct RefererPhase {
+    valid: ValidReferers,
+    server_names: Vec<String>,
+}
+
+impl RequestPhase for RefererPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let valid = self
+            .valid
+            .is_valid(req.header("Referer"), &self.server_names);
+        req.set_var(INVALID_REFERER, if valid { "" } else { "1" });
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the referer check for a block chain with `valid_referers`.
+pub fn referer_phase(chain: &[&ConfigContext], server_names: &[String]) -> Option<RefererPhase> {
+    Some(RefererPhase {
+        valid: merged_config::<RefererConfig>(chain).valid?,
+        server_names: server_names.to_vec(),
+    })
+}
+
+pub fn handle_valid_referers(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let mut valid = ValidReferers::default();
+    for arg in &args {
+        match arg.as_str() {
+            "none" => valid.none = true,
+            "blocked" => valid.blocked = true,
+            "server_names" => valid.server_names = true,
+            pattern => valid.patterns.push(
+                RefererPattern::parse(pattern).map_err(|reason| ctx.invalid_value(arg, reason))?,
+            ),
+        }
+    }
+    if let Ok(mut config) = ctx.block_config::<RefererConfig>().lock() {
+        config.valid = Some(valid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referer_patterns() {
+        let mut valid = ValidReferers {
+            none: true,
+            blocked: false,
+            server_names: true,
+            patterns: Vec::new(),
+        };
+        for pattern in [
+            "*.example.com",
+            "example.*",
+            "partner.org/shared/",
+            "~\\.test/ok",
+        ] {
+            valid.patterns.push(RefererPattern::parse(pattern).unwrap());
+        }
+        let names = vec!["media.site".to_string()];
+        let check = |referer| valid.is_valid(referer, &names);
+
+        assert!(check(None));
+        assert!(!check(Some("ref-stripped")));
+        assert!(check(Some("https://media.site/page")));
+        assert!(check(Some("https://www.Example.com:8443/a")));
+        assert!(!check(Some("https://notexample.com/")));
+        assert!(check(Some("http://example.net/")));
+        assert!(check(Some("http://partner.org/shared/1")));
+        assert!(!check(Some("http://partner.org/private")));
+        assert!(check(Some("http://a.test/ok")));
+        assert!(!check(Some("http://other.org/")));
+        assert!(RefererPattern::parse("a.*.com").is_err());
+    }
+}
@@ -1,38 +1,160 @@
 use std::{
-    collections::HashMap,
-    io::{self},
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{self, Read},
+    net::SocketAddr,
     str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use http::{Method, Version};
+use http::{Extensions, Method, StatusCode, Version};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
 use url::form_urlencoded;
 
-#[derive(PartialEq)]
+use super::{http_body::BodyStream, http_response::HttpResponse, http_variables::VariableRegistry};
+
+/// Largest body `HttpRequest::json` accepts.
+pub const DEFAULT_JSON_LIMIT: usize = 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum JsonError {
+    #[error("Expected a JSON body, got Content-Type \"{0}\"")]
+    ContentType(String),
+    #[error("JSON body larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("Failed to read JSON body: {0}")]
+    Read(#[from] io::Error),
+    #[error("Invalid JSON body: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+impl JsonError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::ContentType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Read(e) if e.kind() == io::ErrorKind::TimedOut => StatusCode::REQUEST_TIMEOUT,
+            Self::Read(_) | Self::Invalid(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// Reports the error to the client as `{"error": "..."}` with its status.
+    pub fn response(&self, req: &HttpRequest) -> HttpResponse {
+        let mut resp = HttpResponse::json(&serde_json::json!({ "error": self.to_string() }));
+        resp.set_status_line(*req.version(), self.status());
+        resp
+    }
+}
+
+/// How far the request line, each header line and the whole request
+/// header may grow before a request is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderLimits {
+    pub line: usize,
+    pub total: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            line: 8 * 1024,
+            total: 32 * 1024,
+        }
+    }
+}
+
+/// How strictly request framing and header syntax are checked, against
+/// requests that blur and the servers around it could read differently.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Also rejects header names that are not tokens, control characters in
+    /// header values, repeated `Content-Length` headers even when they agree,
+    /// and request lines not separated by single spaces.
+    Strict,
+    /// Rejects conflicting `Content-Length` headers, codings other than
+    /// `Transfer-Encoding: chunked`, folded header lines and bare CR or LF.
+    #[default]
+    Standard,
+    /// Accepts bare LF line ends, turns bare CR into spaces and unfolds
+    /// folded header lines; ambiguous bodies are still rejected.
+    Lenient,
+}
+
+impl FromStr for Strictness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "standard" => Ok(Self::Standard),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err("expected strict, standard or lenient".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq)]
 enum ParseState {
+    #[default]
     RequestLine,
     Headers,
     Body,
+    /// The body is chunked and left on the connection, for a location that
+    /// streams it to decode.
+    Chunked,
     Complete,
     Error(String),
 }
 
-impl Default for ParseState {
-    fn default() -> Self {
-        Self::RequestLine
-    }
-}
-
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct HttpRequest {
     method: Method,
     path: String,
+    /// The request target as received, before normalization.
+    request_uri: String,
     version: Version,
     headers: HashMap<String, String>,
     body: Vec<u8>,
     parse_state: ParseState,
     buffer: Vec<u8>,
     header_index: usize,
+    /// The bytes of the request line and the header lines parsed so far.
+    header_bytes: usize,
+    header_limits: HeaderLimits,
+    strictness: Strictness,
+    /// Set by `merge_slashes off` to keep repeated slashes in the path.
+    keep_slashes: bool,
+    /// The header the last header line set, which a folded line continues.
+    last_header: Option<String>,
+    /// Set when the request cannot be served safely, such as when its header
+    /// outgrew `header_limits`, to the status refusing it.
+    rejected: Option<StatusCode>,
     body_bytes_read: usize,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    secure: bool,
+    variables: Option<Arc<VariableRegistry>>,
+    /// How many subrequests deep the request is made, zero for requests
+    /// from clients.
+    subrequest_depth: usize,
+    /// Set once the request is redirected inside the server, by an error
+    /// page or an upstream's `X-Accel-Redirect`; only such requests may
+    /// reach `internal` locations.
+    internal: bool,
+    /// Values phases set for the request, read as variables such as
+    /// `$invalid_referer`.
+    vars: HashMap<String, String>,
+    /// When the first byte of the request arrived, for `$request_time`.
+    started: Option<Instant>,
+    /// The body still on the connection, for locations that stream it;
+    /// shared with copies of the request so any of them can take it.
+    body_stream: Arc<Mutex<Option<BodyStream>>>,
+    /// Typed values the server, phases and middleware attach for later
+    /// handlers, such as the `RequestId` or the verified JWT.
+    extensions: Extensions,
 }
 
 impl HttpRequest {
@@ -64,7 +186,7 @@ impl HttpRequest {
                         return Ok(true);
                     }
                 }
-                ParseState::Complete => {
+                ParseState::Chunked | ParseState::Complete => {
                     return Ok(false);
                 }
                 ParseState::Error(ref err) => {
@@ -75,28 +197,41 @@ impl HttpRequest {
     }
 
     fn parse_request_line(&mut self) -> io::Result<bool> {
-        if let Some(line_end) = find_line_end(&self.buffer) {
-            let line = &self.buffer[..line_end];
-            let line_str = std::str::from_utf8(line)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-            let parts: Vec<&str> = line_str.split_whitespace().collect();
-            if parts.len() != 3 {
-                self.parse_state = ParseState::Error("Invalid request line".into());
-                return Ok(false);
+        let line = self.read_line(0)?;
+        let len = line
+            .as_ref()
+            .map_or(self.buffer.len(), |(line, _)| line.len());
+        if len > self.header_limits.line {
+            return Err(self.refuse(StatusCode::URI_TOO_LONG, "Request line too long"));
+        }
+        if let Some((line_str, consumed)) = line {
+            let parts: Vec<&str> = if self.strictness == Strictness::Strict {
+                line_str.split(' ').collect()
+            } else {
+                line_str.split_whitespace().collect()
+            };
+            if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+                return Err(self.refuse(StatusCode::BAD_REQUEST, "Invalid request line"));
             }
 
             self.method = Method::from_str(parts[0])
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-            self.path = parts[1].to_string();
+            self.request_uri = parts[1].to_string();
+            self.path = match normalize_target_with(parts[1], !self.keep_slashes) {
+                Ok(path) => path,
+                Err(reason) => return Err(self.refuse(StatusCode::BAD_REQUEST, reason)),
+            };
 
-            self.version = parse_http_version(parts[2]).unwrap_or_else(|| {
-                self.parse_state = ParseState::Error("Invalid HTTP version".into());
-                Version::HTTP_09
-            });
+            self.version = match parse_http_version(parts[2]) {
+                Some(version) => version,
+                None => {
+                    return Err(self.refuse(StatusCode::BAD_REQUEST, "Invalid HTTP version"));
+                }
+            };
 
-            self.buffer.drain(..line_end + 2);
+            self.buffer.drain(..consumed);
+            self.header_bytes = consumed;
             self.parse_state = ParseState::Headers;
             Ok(true)
         } else {
@@ -105,14 +240,43 @@ impl HttpRequest {
     }
 
     fn parse_headers(&mut self) -> io::Result<bool> {
-        while let Some(line_end) = find_line_end(&self.buffer[self.header_index..]) {
-            let absolute_end = self.header_index + line_end;
+        while let Some((line, consumed)) = self.read_line(self.header_index)? {
+            if line.len() > self.header_limits.line
+                || self.header_bytes + self.header_index + consumed > self.header_limits.total
+            {
+                return Err(self.refuse(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "Headers too large",
+                ));
+            }
+            self.header_index += consumed;
 
-            if line_end == 0 {
-                self.buffer.drain(..self.header_index + 2);
+            if line.is_empty() {
+                self.buffer.drain(..self.header_index);
                 self.header_index = 0;
 
-                self.parse_state = if self.headers.contains_key("Content-Length") {
+                if let Some(coding) = self.header("Transfer-Encoding") {
+                    // A body framed any way but chunked would be read as the
+                    // next request.
+                    let chunked = coding.eq_ignore_ascii_case("chunked");
+                    return if self.header("Content-Length").is_some() {
+                        Err(self.refuse(
+                            StatusCode::BAD_REQUEST,
+                            "Transfer-Encoding with Content-Length",
+                        ))
+                    } else if self.version == Version::HTTP_10 {
+                        Err(self.refuse(StatusCode::BAD_REQUEST, "Transfer-Encoding in HTTP/1.0"))
+                    } else if !chunked {
+                        Err(self.refuse(
+                            StatusCode::NOT_IMPLEMENTED,
+                            "Transfer-Encoding is not supported",
+                        ))
+                    } else {
+                        self.parse_state = ParseState::Chunked;
+                        Ok(true)
+                    };
+                }
+                self.parse_state = if self.header("Content-Length").is_some() {
                     ParseState::Body
                 } else {
                     ParseState::Complete
@@ -121,29 +285,125 @@ impl HttpRequest {
                 return Ok(true);
             }
 
-            let line = &self.buffer[self.header_index..absolute_end];
-            let line_str = std::str::from_utf8(line)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            self.parse_header_line(&line)?;
+        }
+
+        if self.buffer.len() - self.header_index > self.header_limits.line
+            || self.header_bytes + self.buffer.len() > self.header_limits.total
+        {
+            return Err(self.refuse(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "Headers too large",
+            ));
+        }
 
-            if let Some((key, value)) = parse_header_line(line_str) {
-                self.headers.insert(key.to_string(), value.to_string());
+        Ok(false)
+    }
+
+    /// Reads the line at `start` of the buffer, returning it with the bytes
+    /// it takes up including its line end.
+    fn read_line(&mut self, start: usize) -> io::Result<Option<(String, usize)>> {
+        let rest = &self.buffer[start..];
+        let Some(lf) = rest.iter().position(|&b| b == b'\n') else {
+            return Ok(None);
+        };
+        let lenient = self.strictness == Strictness::Lenient;
+        let len = if lf > 0 && rest[lf - 1] == b'\r' {
+            lf - 1
+        } else if lenient {
+            lf
+        } else {
+            return Err(self.refuse(StatusCode::BAD_REQUEST, "Bare LF in request header"));
+        };
+        let mut line = rest[..len].to_vec();
+        if line.contains(&b'\r') {
+            if !lenient {
+                return Err(self.refuse(StatusCode::BAD_REQUEST, "Bare CR in request header"));
             }
+            line.iter_mut()
+                .filter(|b| **b == b'\r')
+                .for_each(|b| *b = b' ');
+        }
+        let line =
+            String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some((line, lf + 1)))
+    }
 
-            self.header_index = absolute_end + 2;
+    fn parse_header_line(&mut self, line: &str) -> io::Result<()> {
+        let lenient = self.strictness == Strictness::Lenient;
+        let strict = self.strictness == Strictness::Strict;
+        if line.starts_with([' ', '\t']) {
+            // An obsolete line folding continues the previous header.
+            let folded = self
+                .last_header
+                .clone()
+                .filter(|name| lenient && !is_framing_header(name));
+            let Some(name) = folded else {
+                return Err(self.refuse(StatusCode::BAD_REQUEST, "Folded request header"));
+            };
+            let value = format!("{} {}", self.header(&name).unwrap_or_default(), line.trim());
+            self.headers.insert(name, value.trim().to_string());
+            return Ok(());
         }
 
-        if self.buffer.len() - self.header_index > 8192 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Headers too large",
+        let Some((name, value)) = line.split_once(':') else {
+            return if lenient {
+                Ok(())
+            } else {
+                Err(self.refuse(StatusCode::BAD_REQUEST, "Malformed request header"))
+            };
+        };
+        if name.trim().is_empty() || (!lenient && name.ends_with([' ', '\t'])) {
+            return Err(self.refuse(StatusCode::BAD_REQUEST, "Malformed request header"));
+        }
+        let (name, value) = (name.trim(), value.trim());
+        if strict
+            && (!name.bytes().all(is_token_char)
+                || value.bytes().any(|b| b.is_ascii_control() && b != b'\t'))
+        {
+            return Err(self.refuse(
+                StatusCode::BAD_REQUEST,
+                "Invalid character in request header",
             ));
         }
 
-        Ok(false)
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let Some(length) = parse_content_length(value, self.strictness) else {
+                return Err(self.refuse(StatusCode::BAD_REQUEST, "Invalid Content-Length"));
+            };
+            match self.header("Content-Length") {
+                Some(existing) if existing != length || strict => {
+                    return Err(self.refuse(StatusCode::BAD_REQUEST, "Duplicate Content-Length"));
+                }
+                Some(_) => return Ok(()),
+                None => self.headers.insert(name.to_string(), length),
+            };
+        } else if name.eq_ignore_ascii_case("Host") && self.header("Host").is_some() {
+            return Err(self.refuse(StatusCode::BAD_REQUEST, "Duplicate Host header"));
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding")
+            && self.header("Transfer-Encoding").is_some()
+        {
+            return Err(self.refuse(
+                StatusCode::BAD_REQUEST,
+                "Duplicate Transfer-Encoding header",
+            ));
+        } else {
+            self.headers.insert(name.to_string(), value.to_string());
+        }
+        self.last_header = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Stops parsing a request that cannot be served safely, answering it
+    /// with `status`.
+    fn refuse(&mut self, status: StatusCode, reason: &str) -> io::Error {
+        self.rejected = Some(status);
+        self.parse_state = ParseState::Error(reason.into());
+        io::Error::new(io::ErrorKind::InvalidData, reason)
     }
 
     fn parse_body(&mut self) -> io::Result<bool> {
-        let content_length: usize = match self.headers.get("Content-Length") {
+        let content_length: usize = match self.header("Content-Length") {
             Some(len) => len.parse().map_err(|_| {
                 io::Error::new(io::ErrorKind::InvalidData, "Invalid Content-Length")
             })?,
@@ -153,6 +413,11 @@ impl HttpRequest {
             }
         };
 
+        if content_length == 0 {
+            self.parse_state = ParseState::Complete;
+            return Ok(true);
+        }
+
         let bytes_remaining = content_length.saturating_sub(self.body_bytes_read);
         let bytes_available = self.buffer.len();
 
@@ -174,10 +439,27 @@ impl HttpRequest {
         &self.method
     }
 
+    pub fn set_method(&mut self, method: Method) {
+        self.method = method;
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
 
+    pub fn set_path(&mut self, path: impl Into<String>) {
+        self.path = path.into();
+    }
+
+    /// The original request target, unaffected by normalization and rewrites.
+    pub fn request_uri(&self) -> &str {
+        if self.request_uri.is_empty() {
+            &self.path
+        } else {
+            &self.request_uri
+        }
+    }
+
     pub fn version(&self) -> &Version {
         &self.version
     }
@@ -190,10 +472,234 @@ impl HttpRequest {
         &self.body
     }
 
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    /// Hands the body to the handler as a stream instead of `body`.
+    pub fn set_body_stream(&mut self, stream: BodyStream) {
+        self.body_stream = Arc::new(Mutex::new(Some(stream)));
+    }
+
+    /// Takes the streamed body of a request to a location that streams
+    /// bodies; only the first call gets it, and `body` stays empty.
+    pub fn take_body_stream(&self) -> Option<BodyStream> {
+        self.body_stream.lock().ok()?.take()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces every header with the same name, ignoring case.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("Content-Length")
+            .and_then(|value| value.trim().parse().ok())
+    }
+
+    /// Whether `Accept-Encoding` allows `coding` with a non-zero quality,
+    /// either by name or through `*`.
+    pub fn accepts_encoding(&self, coding: &str) -> bool {
+        self.encoding_quality(coding)
+            .is_some_and(|quality| quality > 0.0)
+    }
+
+    /// The quality `Accept-Encoding` gives `coding`, by name or through `*`.
+    pub fn encoding_quality(&self, coding: &str) -> Option<f32> {
+        let accept = self.header("Accept-Encoding")?;
+        let mut wildcard = None;
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| {
+                    let (key, value) = param.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("q")
+                        .then(|| value.trim().parse::<f32>().ok())?
+                })
+                .unwrap_or(1.0);
+            if name.eq_ignore_ascii_case(coding) {
+                return Some(quality);
+            }
+            if name == "*" {
+                wildcard = Some(quality);
+            }
+        }
+        wildcard
+    }
+
+    pub fn is_headers_complete(&self) -> bool {
+        matches!(
+            self.parse_state,
+            ParseState::Body | ParseState::Chunked | ParseState::Complete
+        )
+    }
+
+    /// Whether the body is chunked. The parser leaves such a body on the
+    /// connection; only locations that stream bodies read it.
+    pub fn is_chunked(&self) -> bool {
+        matches!(self.parse_state, ParseState::Chunked)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").map(str::to_ascii_lowercase);
+        match connection.as_deref() {
+            Some("close") => false,
+            Some("keep-alive") => true,
+            _ => self.version >= Version::HTTP_11,
+        }
+    }
+
+    pub fn take_remaining(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    pub fn set_connection(
+        &mut self,
+        remote_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+        secure: bool,
+    ) {
+        self.remote_addr = remote_addr;
+        self.local_addr = local_addr;
+        self.secure = secure;
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Replaces the client address, for a trusted proxy reporting the real one.
+    pub fn set_remote_addr(&mut self, remote_addr: SocketAddr) {
+        self.remote_addr = Some(remote_addr);
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    pub fn set_variables(&mut self, variables: Arc<VariableRegistry>) {
+        self.variables = Some(variables);
+    }
+
+    pub fn variables(&self) -> Option<&Arc<VariableRegistry>> {
+        self.variables.as_ref()
+    }
+
     pub fn is_complete(&self) -> bool {
         matches!(self.parse_state, ParseState::Complete)
     }
 
+    /// A complete copy of the request addressed to `uri`, keeping the query
+    /// string, `$request_uri` and the connection details.
+    pub fn subrequest(&self, uri: &str) -> Self {
+        let path = match self.path.split_once('?') {
+            Some((_, query)) => format!("{}?{}", uri, query),
+            None => uri.to_string(),
+        };
+        Self {
+            method: self.method.clone(),
+            path,
+            request_uri: self.request_uri().to_string(),
+            version: self.version,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            parse_state: ParseState::Complete,
+            remote_addr: self.remote_addr,
+            local_addr: self.local_addr,
+            secure: self.secure,
+            variables: self.variables.clone(),
+            subrequest_depth: self.subrequest_depth + 1,
+            internal: true,
+            started: self.started,
+            extensions: self.extensions.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the server made the request on its own, such as a mirror or
+    /// an `auth_request` check.
+    pub fn is_subrequest(&self) -> bool {
+        self.subrequest_depth > 0
+    }
+
+    pub fn subrequest_depth(&self) -> usize {
+        self.subrequest_depth
+    }
+
+    pub fn set_internal(&mut self) {
+        self.internal = true;
+    }
+
+    pub fn is_internal(&self) -> bool {
+        self.internal
+    }
+
+    /// Values attached to the request by type, one per type. Middleware
+    /// inserts them, such as the identity it authenticated, and handlers
+    /// read them back without passing them through headers.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    pub fn set_header_limits(&mut self, limits: HeaderLimits) {
+        self.header_limits = limits;
+    }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    /// Whether repeated slashes in the request path are merged into one,
+    /// as they are by default.
+    pub fn set_merge_slashes(&mut self, merge: bool) {
+        self.keep_slashes = !merge;
+    }
+
+    /// The status to refuse the request with when it could not be parsed
+    /// safely, such as `431` for header fields over the limits.
+    pub fn rejected(&self) -> Option<StatusCode> {
+        self.rejected
+    }
+
+    pub fn set_started(&mut self, started: Instant) {
+        self.started = Some(started);
+    }
+
+    pub fn started(&self) -> Option<Instant> {
+        self.started
+    }
+
     pub fn query_params(&self) -> HashMap<String, Vec<String>> {
         let mut params: HashMap<String, Vec<String>> = HashMap::new();
         if let Some(query_start) = self.path.find('?') {
@@ -207,6 +713,67 @@ impl HttpRequest {
         }
         params
     }
+
+    /// Parses the body as JSON of up to `DEFAULT_JSON_LIMIT` bytes, sent
+    /// with `application/json` or another `+json` media type.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        self.json_limited(DEFAULT_JSON_LIMIT)
+    }
+
+    /// Like `json`, for bodies of up to `limit` bytes. A streamed body is
+    /// read here, and no further than the limit.
+    pub fn json_limited<T: DeserializeOwned>(&self, limit: usize) -> Result<T, JsonError> {
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        if !is_json_media_type(content_type) {
+            return Err(JsonError::ContentType(content_type.to_string()));
+        }
+        if self.content_length().is_some_and(|len| len > limit as u64) {
+            return Err(JsonError::TooLarge(limit));
+        }
+        let streamed = match self.take_body_stream() {
+            Some(stream) => {
+                let mut body = Vec::new();
+                stream.take(limit as u64 + 1).read_to_end(&mut body)?;
+                Some(body)
+            }
+            None => None,
+        };
+        let body = streamed.as_deref().unwrap_or(&self.body);
+        if body.len() > limit {
+            return Err(JsonError::TooLarge(limit));
+        }
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+/// Whether a `Content-Type` names JSON, such as `application/json` or
+/// `application/problem+json`, whatever its parameters.
+fn is_json_media_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json"
+        || media_type.starts_with("application/") && media_type.ends_with("+json")
+}
+
+/// Identifies a request across logs and upstreams, as `$request_id`; kept
+/// in the request's extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// A new request ID: 16 random bytes written as 32 hex digits.
+pub fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        for chunk in bytes.chunks_mut(8) {
+            let random = RandomState::new().build_hasher().finish().to_le_bytes();
+            chunk.copy_from_slice(&random);
+        }
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 pub fn http_version_to_string(version: &Version) -> &'static str {
@@ -233,13 +800,103 @@ fn parse_http_version(version_str: &str) -> Option<Version> {
     }
 }
 
-fn find_line_end(buf: &[u8]) -> Option<usize> {
-    buf.windows(2).position(|window| window == b"\r\n")
+/// Normalizes a request target: the path is percent-decoded, `.` and `..`
+/// segments and repeated slashes are resolved, and the query string is kept
+/// as received. A decoded `?` stays encoded so it cannot start a query.
+pub fn normalize_target(target: &str) -> Result<String, &'static str> {
+    normalize_target_with(target, true)
+}
+
+/// Normalizes a request target like `normalize_target`, keeping repeated
+/// slashes unless `merge_slashes` is set.
+pub fn normalize_target_with(target: &str, merge_slashes: bool) -> Result<String, &'static str> {
+    if target == "*" {
+        return Ok(target.to_string());
+    }
+    let target = match target.split_once("://") {
+        Some((scheme, rest)) if !scheme.contains('/') => {
+            rest.find('/').map_or("/", |start| &rest[start..])
+        }
+        _ => target,
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let mut normalized = normalize_path_with(path, merge_slashes)?;
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    Ok(normalized)
 }
 
-fn parse_header_line(line: &str) -> Option<(&str, &str)> {
-    let mut parts = line.splitn(2, ':');
-    Some((parts.next()?.trim(), parts.next()?.trim()))
+pub fn normalize_path(path: &str) -> Result<String, &'static str> {
+    normalize_path_with(path, true)
+}
+
+fn normalize_path_with(path: &str, merge_slashes: bool) -> Result<String, &'static str> {
+    if !path.starts_with('/') {
+        return Err("Invalid request target");
+    }
+    let decoded = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|_| "Invalid UTF-8 in request path")?;
+    if decoded.chars().any(|c| c.is_ascii_control()) {
+        return Err("Control character in request path");
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    let parts: Vec<&str> = decoded.split('/').skip(1).collect();
+    for (index, segment) in parts.iter().enumerate() {
+        trailing_slash = matches!(*segment, "" | "." | "..");
+        match *segment {
+            // An empty last segment is the trailing slash itself.
+            "" if merge_slashes || index + 1 == parts.len() => {}
+            "." => {}
+            ".." => {
+                segments
+                    .pop()
+                    .ok_or("Request path escapes the root directory")?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(&segment.replace('?', "%3F"));
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Reads a `Content-Length` value; a list of equal lengths, as left by
+/// merging repeated headers, counts as one unless parsing strictly.
+fn parse_content_length(value: &str, strictness: Strictness) -> Option<String> {
+    let mut lengths = value.split(',').map(str::trim);
+    let first = lengths.next()?;
+    if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let repeated = value.contains(',');
+    (lengths.all(|length| length == first) && !(repeated && strictness == Strictness::Strict))
+        .then(|| first.to_string())
+}
+
+/// Headers that decide where a request ends or which server it is for.
+fn is_framing_header(name: &str) -> bool {
+    ["Content-Length", "Transfer-Encoding", "Host"]
+        .iter()
+        .any(|framing| framing.eq_ignore_ascii_case(name))
+}
+
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
 }
 
 #[cfg(test)]
@@ -257,6 +914,50 @@ mod tests {
         assert_eq!(*request.version(), Version::HTTP_11);
     }
 
+    #[test]
+    fn test_normalize_target() {
+        assert_eq!(
+            normalize_target("/a//b/./c/../d?x=%2e%2e").unwrap(),
+            "/a/b/d?x=%2e%2e"
+        );
+        assert_eq!(normalize_target("/a%20b/%2e%2e/").unwrap(), "/");
+        assert_eq!(normalize_target("/dir/.").unwrap(), "/dir/");
+        assert_eq!(normalize_target("/what%3F").unwrap(), "/what%3F");
+        assert_eq!(normalize_target("http://example.com/x?y").unwrap(), "/x?y");
+        assert!(normalize_target("/../etc/passwd").is_err());
+        assert!(normalize_target("/a/%2e%2e/%2e%2e/b").is_err());
+        assert!(normalize_target("/a%00b").is_err());
+        assert!(normalize_target("/b%0d%0aSet-Cookie:%20evil=2").is_err());
+        assert!(normalize_target("/a%0Ab").is_err());
+        assert!(normalize_target("/a%09b").is_err());
+        assert!(normalize_target("/a%7fb").is_err());
+        assert_eq!(normalize_target("/a?x=%0d%0a").unwrap(), "/a?x=%0d%0a");
+        assert!(normalize_target("/%ff").is_err());
+        assert_eq!(
+            normalize_target_with("/a//b/.//c//?x=//", false).unwrap(),
+            "/a//b//c//?x=//"
+        );
+        assert_eq!(normalize_target_with("//a//../b", false).unwrap(), "//a/b");
+        assert_eq!(normalize_target_with("/", false).unwrap(), "/");
+
+        let mut request = HttpRequest::new();
+        request.parse(b"GET /a/../b%20c HTTP/1.1\r\n").unwrap();
+        assert_eq!(request.path(), "/b c");
+        assert_eq!(request.request_uri(), "/a/../b%20c");
+        let mut request = HttpRequest::new();
+        request.set_merge_slashes(false);
+        request.parse(b"GET /a//b HTTP/1.1\r\n").unwrap();
+        assert_eq!(request.path(), "/a//b");
+        assert!(HttpRequest::new()
+            .parse(b"GET /../x HTTP/1.1\r\n\r\n")
+            .is_err());
+        let mut request = HttpRequest::new();
+        assert!(request
+            .parse(b"GET /b%0d%0aSet-Cookie:%20evil=2 HTTP/1.1\r\n\r\n")
+            .is_err());
+        assert_eq!(request.rejected(), Some(StatusCode::BAD_REQUEST));
+    }
+
     #[test]
     fn test_parse_headers() {
         let mut request = HttpRequest::new();
@@ -283,4 +984,139 @@ mod tests {
 
         assert_eq!(request.body(), b"Hello");
     }
+
+    #[test]
+    fn test_ambiguous_requests_are_rejected() {
+        let parse = |strictness: Strictness, input: &str| {
+            let mut request = HttpRequest::new();
+            request.set_strictness(strictness);
+            let result = request.parse(input.as_bytes());
+            (result.is_ok(), request)
+        };
+        let rejected = |strictness, input| parse(strictness, input).1.rejected();
+        let bad = Some(StatusCode::BAD_REQUEST);
+        use Strictness::*;
+
+        let both = "POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(rejected(Lenient, both), bad);
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let (parsed, request) = parse(Strict, chunked);
+        assert!(parsed && request.is_headers_complete() && request.is_chunked());
+        assert!(!request.is_complete());
+        // The body is left for the stream to decode.
+        assert_eq!(request.clone().take_remaining(), b"0\r\n\r\n");
+        let gzip = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(rejected(Lenient, gzip), Some(StatusCode::NOT_IMPLEMENTED));
+        let repeated =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(rejected(Lenient, repeated), bad);
+        let old = "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(rejected(Lenient, old), bad);
+
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 4\r\ncontent-length: 5\r\n\r\n";
+        assert_eq!(rejected(Lenient, conflicting), bad);
+        let repeated = "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\nbody";
+        assert_eq!(rejected(Standard, repeated), None);
+        assert_eq!(rejected(Strict, repeated), bad);
+        assert_eq!(
+            rejected(Standard, "POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\n"),
+            bad
+        );
+        assert_eq!(
+            rejected(Standard, "GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"),
+            bad
+        );
+
+        let folded = "GET / HTTP/1.1\r\nX-A: one\r\n two\r\n\r\n";
+        assert_eq!(rejected(Standard, folded), bad);
+        let (ok, request) = parse(Lenient, folded);
+        assert!(ok && request.header("X-A") == Some("one two"));
+        let folded_length = "POST / HTTP/1.1\r\nContent-Length: 4\r\n 2\r\n\r\n";
+        assert_eq!(rejected(Lenient, folded_length), bad);
+
+        let bare_lf = "GET / HTTP/1.1\nX-A: 1\r\n\r\n";
+        assert_eq!(rejected(Standard, bare_lf), bad);
+        assert!(parse(Lenient, bare_lf).1.is_complete());
+        let bare_cr = "GET / HTTP/1.1\r\nX-A: 1\rX-B: 2\r\n\r\n";
+        assert_eq!(rejected(Standard, bare_cr), bad);
+        assert_eq!(parse(Lenient, bare_cr).1.header("X-A"), Some("1 X-B: 2"));
+
+        assert_eq!(rejected(Standard, "GET / HTTP/1.1\r\nX-A : 1\r\n\r\n"), bad);
+        assert_eq!(
+            rejected(Standard, "GET / HTTP/1.1\r\nX(A): 1\r\n\r\n"),
+            None
+        );
+        assert_eq!(rejected(Strict, "GET / HTTP/1.1\r\nX(A): 1\r\n\r\n"), bad);
+        assert_eq!(rejected(Strict, "GET  / HTTP/1.1\r\n\r\n"), bad);
+        assert!(parse(Standard, "GET  / HTTP/1.1\r\n\r\n").1.is_complete());
+    }
+
+    #[test]
+    fn test_json_body() {
+        let request = |content_type: &str, body: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(
+                format!(
+                    "POST /api HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                    content_type,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            req
+        };
+        let req = request("application/json; charset=utf-8", r#"{"a": 1, "b": 2}"#);
+        let value: HashMap<String, u32> = req.json().unwrap();
+        assert_eq!(value["b"], 2);
+        let req = request("application/merge-patch+json", "[1]");
+        assert_eq!(req.json::<Vec<u8>>().unwrap(), vec![1]);
+
+        let status = |req: &HttpRequest| req.json::<serde_json::Value>().unwrap_err().status();
+        let req = request("text/plain", "{}");
+        assert_eq!(status(&req), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            status(&request("application/json", "{")),
+            StatusCode::BAD_REQUEST
+        );
+        let req = request("application/json", "[1, 2, 3]");
+        let err = req.json_limited::<Vec<u8>>(4).unwrap_err();
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = err.response(&req);
+        assert_eq!(resp.status(), Some(413));
+        assert_eq!(resp.header_value("Content-Type"), Some("application/json"));
+        let body: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(body["error"], "JSON body larger than 4 bytes");
+    }
+
+    #[test]
+    fn test_extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct User(&'static str);
+
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /a?x=1 HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        assert!(req.extensions().get::<User>().is_none());
+        req.extensions_mut().insert(User("ann"));
+        req.extensions_mut().insert(RequestId("id1".to_string()));
+        assert_eq!(req.extensions().get::<User>(), Some(&User("ann")));
+        // One value per type: inserting again replaces it.
+        let old = req.extensions_mut().insert(User("bob"));
+        assert_eq!(old, Some(User("ann")));
+        assert_eq!(req.extensions().get::<User>(), Some(&User("bob")));
+        assert_eq!(
+            req.extensions().get::<RequestId>(),
+            Some(&RequestId("id1".to_string()))
+        );
+
+        // Subrequests and copies start with the values of their request.
+        let sub = req.subrequest("/b");
+        assert_eq!(sub.extensions().get::<User>(), Some(&User("bob")));
+        let mut copy = req.clone();
+        copy.extensions_mut().insert(User("cy"));
+        assert_eq!(req.extensions().get::<User>(), Some(&User("bob")));
+    }
 }
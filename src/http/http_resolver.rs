@@ -0,0 +1,534 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, Once, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{Arity, CommandBuilder, ParameterBuilder},
+        config_context::{merged_config, ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+        units::parse_duration,
+    },
+    log_error, log_warn, register_commands,
+};
+
+register_commands!(CommandBuilder::new("resolver")
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "Resolver")
+    .display_name("zh-tw", "DNS 解析伺服器")
+    .desc(
+        "en",
+        "Sets the DNS servers upstream host names are resolved with while running, re-resolving them as their records expire"
+    )
+    .desc(
+        "zh-tw",
+        "設定執行期間解析上游主機名稱所用的 DNS 伺服器，記錄過期後會重新解析"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Addresses")
+        .display_name("zh-tw", "位址")
+        .type_name("String")
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "One or more server IP addresses with an optional port (53 by default), then optionally valid=time to override record TTLs and ipv6=off to look up IPv4 addresses only"
+        )
+        .desc(
+            "zh-tw",
+            "一個或多個伺服器 IP 位址，可加上連接埠（預設 53），之後可選擇 valid=時間 取代記錄的 TTL，以及 ipv6=off 只查詢 IPv4 位址"
+        )
+        .build()])
+    .arity(Arity::AtLeast(1))
+    .build(handle_resolver));
+
+const DNS_PORT: u16 = 53;
+/// How long each server may take to answer before the next one is asked.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often records about to expire are looked up again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Lower bound for record lifetimes, so zero TTLs do not query on every
+/// connection.
+const MIN_TTL: Duration = Duration::from_secs(1);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Debug, Default, Clone)]
+pub struct ResolverConfig {
+    pub resolver: Option<Arc<Resolver>>,
+}
+
+impl MergeConfig for ResolverConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.resolver = self.resolver.take().or_else(|| parent.resolver.clone());
+    }
+}
+
+/// The resolver configured for a block chain, if any.
+pub fn resolver(chain: &[&ConfigContext]) -> Option<Arc<Resolver>> {
+    merged_config::<ResolverConfig>(chain).resolver
+}
+
+/// Addresses to connect to for `host`, looked up through `resolver` when one
+/// is configured and through the system otherwise.
+pub fn lookup(
+    resolver: Option<&Arc<Resolver>>,
+    host: &str,
+    port: u16,
+) -> io::Result<Vec<SocketAddr>> {
+    match resolver {
+        Some(resolver) => Ok(resolver
+            .resolve(host)?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect()),
+        None => Ok((host, port).to_socket_addrs()?.collect()),
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+    /// Whether a connection asked for the host since it was last resolved;
+    /// hosts nobody uses are dropped instead of refreshed.
+    used: bool,
+}
+
+/// Resolves host names with the configured DNS servers, caching the answers
+/// for their TTL. Hosts still in use are re-resolved in the background
+/// before their records expire, so connections rarely wait for a lookup.
+#[derive(Debug)]
+pub struct Resolver {
+    servers: Vec<SocketAddr>,
+    /// Overrides the TTL of the records when set.
+    valid: Option<Duration>,
+    ipv6: bool,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    refresher: Once,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>, valid: Option<Duration>, ipv6: bool) -> Arc<Self> {
+        Arc::new(Self {
+            servers,
+            valid,
+            ipv6,
+            cache: Mutex::new(HashMap::new()),
+            refresher: Once::new(),
+        })
+    }
+
+    pub fn resolve(self: &Arc<Self>, host: &str) -> io::Result<Vec<IpAddr>> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        self.start_refresher();
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Ok(mut cache) = self.cache.lock() {
+            if let Some(entry) = cache.get_mut(&host) {
+                if entry.expires > Instant::now() {
+                    entry.used = true;
+                    return Ok(entry.addrs.clone());
+                }
+            }
+        }
+        match self.query(&host) {
+            Ok((addrs, ttl)) => {
+                self.store(&host, addrs.clone(), ttl, true);
+                Ok(addrs)
+            }
+            Err(e) => {
+                // Better an address that was right a moment ago than none.
+                let stale = self
+                    .cache
+                    .lock()
+                    .ok()
+                    .and_then(|cache| cache.get(&host).map(|entry| entry.addrs.clone()));
+                match stale {
+                    Some(addrs) => {
+                        log_warn!("cannot resolve {}, using expired addresses: {}", host, e);
+                        Ok(addrs)
+                    }
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    fn store(&self, host: &str, addrs: Vec<IpAddr>, ttl: Duration, used: bool) {
+        let ttl = self.valid.unwrap_or(ttl).max(MIN_TTL);
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.insert(
+                host.to_string(),
+                CacheEntry {
+                    addrs,
+                    expires: Instant::now() + ttl,
+                    used,
+                },
+            );
+        }
+    }
+
+    fn start_refresher(self: &Arc<Self>) {
+        self.refresher.call_once(|| {
+            let resolver = Arc::downgrade(self);
+            thread::spawn(move || refresh_loop(resolver));
+        });
+    }
+
+    /// Looks up the used hosts whose records expire before the next round,
+    /// and forgets the unused ones.
+    fn refresh(&self) {
+        let soon = Instant::now() + REFRESH_INTERVAL;
+        let due: Vec<String> = match self.cache.lock() {
+            Ok(mut cache) => {
+                cache.retain(|_, entry| entry.used || entry.expires > soon);
+                cache
+                    .iter()
+                    .filter(|(_, entry)| entry.expires <= soon)
+                    .map(|(host, _)| host.clone())
+                    .collect()
+            }
+            Err(_) => return,
+        };
+        for host in due {
+            match self.query(&host) {
+                Ok((addrs, ttl)) => self.store(&host, addrs, ttl, false),
+                Err(e) => {
+                    log_error!("cannot re-resolve {}: {}", host, e);
+                    // Tried again only if a connection still wants it.
+                    if let Some(entry) = self
+                        .cache
+                        .lock()
+                        .ok()
+                        .as_mut()
+                        .and_then(|cache| cache.get_mut(&host))
+                    {
+                        entry.used = false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Asks each server in turn, returning the addresses with the lowest TTL
+    /// among their records.
+    fn query(&self, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let mut last_error = None;
+        for server in &self.servers {
+            match self.query_server(*server, host) {
+                Ok(found) => return Ok(found),
+                // The name does not exist; other servers will agree.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no DNS servers")))
+    }
+
+    fn query_server(&self, server: SocketAddr, host: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let local: SocketAddr = if server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+
+        let id = random_id();
+        let types: &[u16] = if self.ipv6 {
+            &[TYPE_A, TYPE_AAAA]
+        } else {
+            &[TYPE_A]
+        };
+        let mut pending: Vec<u16> = (0..types.len() as u16)
+            .map(|i| id.wrapping_add(i))
+            .collect();
+        for (id, qtype) in pending.iter().zip(types) {
+            socket.send(&encode_query(*id, host, *qtype)?)?;
+        }
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut addrs = Vec::new();
+        let mut ttl = u32::MAX;
+        let mut buf = [0; 4096];
+        while !pending.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("DNS server {} did not answer", server),
+                ));
+            }
+            socket.set_read_timeout(Some(left))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            };
+            // Anything that is not an answer to one of the queries is ignored.
+            let Some(response) = parse_response(&buf[..len]) else {
+                continue;
+            };
+            let Some(index) = pending.iter().position(|id| *id == response.id) else {
+                continue;
+            };
+            pending.remove(index);
+            match response.rcode {
+                0 => {}
+                RCODE_NXDOMAIN => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("host {} not found", host),
+                    ))
+                }
+                rcode => {
+                    return Err(io::Error::other(format!(
+                        "DNS server {} failed with code {}",
+                        server, rcode
+                    )))
+                }
+            }
+            for (ip, record_ttl) in response.records {
+                addrs.push(ip);
+                ttl = ttl.min(record_ttl);
+            }
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("host {} has no addresses", host),
+            ));
+        }
+        Ok((addrs, Duration::from_secs(ttl.into())))
+    }
+}
+
+fn refresh_loop(resolver: Weak<Resolver>) {
+    loop {
+        thread::sleep(REFRESH_INTERVAL);
+        // Ends once the configuration holding the resolver is gone.
+        let Some(resolver) = resolver.upgrade() else {
+            return;
+        };
+        resolver.refresh();
+    }
+}
+
+fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+fn encode_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid host name \"{}\"", host),
+        )
+    };
+    if host.is_empty() || host.len() > 253 {
+        return Err(invalid());
+    }
+    let mut query = Vec::with_capacity(host.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+struct Response {
+    id: u16,
+    rcode: u16,
+    records: Vec<(IpAddr, u32)>,
+}
+
+/// Parses the address records of a DNS response; CNAME records are skipped
+/// as recursive servers send the records of their target along.
+fn parse_response(buf: &[u8]) -> Option<Response> {
+    let u16_at = |pos: usize| Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?));
+    let id = u16_at(0)?;
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(buf, pos)?;
+        let rtype = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let ttl = u32::from_be_bytes(buf.get(pos + 4..pos + 8)?.try_into().ok()?);
+        let len = u16_at(pos + 8)? as usize;
+        let data = buf.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        if class != CLASS_IN {
+            continue;
+        }
+        let ip = match (rtype, len) {
+            (TYPE_A, 4) => IpAddr::from(<[u8; 4]>::try_from(data).ok()?),
+            (TYPE_AAAA, 16) => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+            _ => continue,
+        };
+        records.push((ip, ttl));
+    }
+    Some(Response {
+        id,
+        rcode: flags & 0x000f,
+        records,
+    })
+}
+
+/// Returns the position after the name starting at `pos`.
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer to a name elsewhere ends this one.
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn parse_server(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .map_err(|_| "must be an IP address with an optional port".to_string())
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut ResolverConfig)) {
+    if let Ok(mut config) = ctx.block_config::<ResolverConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_resolver(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let mut servers = Vec::new();
+    let mut valid = None;
+    let mut ipv6 = true;
+    for arg in &args {
+        if let Some(value) = arg.strip_prefix("valid=") {
+            valid = Some(parse_duration(value).map_err(|reason| ctx.invalid_value(arg, reason))?);
+        } else if let Some(value) = arg.strip_prefix("ipv6=") {
+            ipv6 = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(ctx.invalid_value(arg, "must be ipv6=on or ipv6=off")),
+            };
+        } else {
+            servers.push(parse_server(arg).map_err(|reason| ctx.invalid_value(arg, reason))?);
+        }
+    }
+    if servers.is_empty() {
+        return Err(ctx.invalid_value(&args.join(" "), "no DNS server address"));
+    }
+    let resolver = Resolver::new(servers, valid, ipv6);
+    update(ctx, |config| config.resolver = Some(resolver));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+
+    /// Answers every A query with 10.0.0.N, N counting the queries, and
+    /// records of the given TTL.
+    fn dns_server(ttl: u32) -> (SocketAddr, Arc<AtomicU8>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicU8::new(0));
+        let counter = Arc::clone(&queries);
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let mut answer = buf[..len].to_vec();
+                answer[2..4].copy_from_slice(&[0x81, 0x80]);
+                answer[6..8].copy_from_slice(&[0, 1]);
+                answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+                answer.extend_from_slice(&ttl.to_be_bytes());
+                answer.extend_from_slice(&[0, 4, 10, 0, 0, n]);
+                socket.send_to(&answer, peer).unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn test_resolver_caches_and_re_resolves() {
+        let (server, queries) = dns_server(1);
+        let resolver = Resolver::new(vec![server], None, false);
+        let first = vec![IpAddr::from([10, 0, 0, 1])];
+
+        assert_eq!(resolver.resolve("App.Example.").unwrap(), first);
+        assert_eq!(resolver.resolve("app.example").unwrap(), first);
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            resolver.resolve("192.0.2.7").unwrap(),
+            vec![IpAddr::from([192, 0, 2, 7])]
+        );
+
+        // Refreshed in the background once the TTL runs out.
+        thread::sleep(Duration::from_millis(2500));
+        assert!(queries.load(Ordering::SeqCst) >= 2);
+        assert_ne!(resolver.resolve("app.example").unwrap(), first);
+
+        let (server, queries) = dns_server(1);
+        let resolver = Resolver::new(vec![server], Some(Duration::from_secs(60)), false);
+        let addrs = lookup(Some(&resolver), "other.example", 8080).unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].port(), 8080);
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(
+            lookup(Some(&resolver), "other.example", 8080).unwrap(),
+            addrs
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,12 +1,220 @@
+use std::{
+    any::Any,
+    fs::File,
+    io::{self, Cursor, Read, Write},
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
 use http::{StatusCode, Version};
+use serde::Serialize;
+
+use crate::log_error;
+
+use super::{
+    http_limit_rate::LimitRate,
+    http_log::AccessLogs,
+    http_mime::{DEFAULT_MIME_TYPE, DEFAULT_TYPES},
+    http_request::http_version_to_string,
+    http_upstream::{UpstreamStream, UpstreamTiming},
+};
+
+/// A region of an open file sent after the in-memory body, so large files
+/// can be written with `sendfile(2)` instead of being read into memory.
+#[derive(Debug, Clone)]
+pub struct FileBody {
+    pub file: Arc<File>,
+    pub offset: u64,
+    pub len: u64,
+    /// Upper bound on bytes per `sendfile` call; 0 means unlimited.
+    pub max_chunk: u64,
+}
+
+impl PartialEq for FileBody {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.file, &other.file) && self.offset == other.offset && self.len == other.len
+    }
+}
+
+impl FileBody {
+    pub fn new(file: impl Into<Arc<File>>, len: u64) -> Self {
+        Self {
+            file: file.into(),
+            offset: 0,
+            len,
+            max_chunk: 0,
+        }
+    }
+
+    /// Copies the region through a userspace buffer, for streams that cannot
+    /// use `sendfile`.
+    pub fn copy_to<W: Write + ?Sized>(&self, out: &mut W) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut offset = self.offset;
+        let end = self.offset + self.len;
+        while offset < end {
+            let want = buf.len().min((end - offset) as usize);
+            let n = self.file.read_at(&mut buf[..want], offset)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            out.write_all(&buf[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the region from the start, consuming it.
+impl Read for FileBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min(self.len as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read_at(&mut buf[..want], self.offset)?;
+        self.offset += n as u64;
+        self.len -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A body read from a source such as an upstream connection and written to
+/// the client as it arrives; without a known length it is sent chunked.
+#[derive(Clone)]
+pub struct StreamBody {
+    reader: Arc<Mutex<Box<dyn Read + Send>>>,
+    pub len: Option<u64>,
+}
+
+impl PartialEq for StreamBody {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.reader, &other.reader)
+    }
+}
+
+impl StreamBody {
+    pub fn new(reader: impl Read + Send + 'static, len: Option<u64>) -> Self {
+        Self {
+            reader: Arc::new(Mutex::new(Box::new(reader))),
+            len,
+        }
+    }
+
+    /// Copies the remaining body to `out`, in chunked framing if `chunked`.
+    pub fn copy_to<W: Write + ?Sized>(&self, out: &mut W, chunked: bool) -> io::Result<()> {
+        self.copy_framed(out, chunked.then_some(""))
+    }
+
+    /// Copies the remaining body to `out`, in chunked framing ending with
+    /// the `Name: value\r\n` lines of `trailer` when one is given.
+    pub fn copy_framed<W: Write + ?Sized>(
+        &self,
+        out: &mut W,
+        trailer: Option<&str>,
+    ) -> io::Result<()> {
+        let chunked = trailer.is_some();
+        let mut reader = self
+            .reader
+            .lock()
+            .map_err(|_| io::Error::other("response body stream poisoned"))?;
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if chunked {
+                out.write_all(format!("{:x}\r\n", n).as_bytes())?;
+                out.write_all(&buf[..n])?;
+                out.write_all(b"\r\n")?;
+            } else {
+                out.write_all(&buf[..n])?;
+            }
+            out.flush()?;
+        }
+        if let Some(trailer) = trailer {
+            out.write_all(b"0\r\n")?;
+            out.write_all(trailer.as_bytes())?;
+            out.write_all(b"\r\n")?;
+        }
+        Ok(())
+    }
+}
 
-use super::http_request::http_version_to_string;
+impl Read for StreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader
+            .lock()
+            .map_err(|_| io::Error::other("response body stream poisoned"))?
+            .read(buf)
+    }
+}
+
+/// An upstream connection together with the bytes already read from it.
+type UpstreamConnection = (UpstreamStream, Vec<u8>);
+
+/// The upstream side of a connection switched to another protocol with a
+/// `101 Switching Protocols` response; once the response is sent, bytes are
+/// relayed both ways until either side closes or `idle_timeout` passes.
+#[derive(Clone)]
+pub struct UpgradedConnection {
+    upstream: Arc<Mutex<Option<UpstreamConnection>>>,
+    pub idle_timeout: Duration,
+    /// Kept alive for as long as the tunnel is open.
+    _guard: Arc<dyn Any + Send + Sync>,
+}
+
+impl PartialEq for UpgradedConnection {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.upstream, &other.upstream)
+    }
+}
+
+impl UpgradedConnection {
+    /// `buffered` holds bytes the upstream sent right after its response head.
+    pub fn new(
+        upstream: UpstreamStream,
+        buffered: Vec<u8>,
+        idle_timeout: Duration,
+        guard: impl Any + Send + Sync,
+    ) -> Self {
+        Self {
+            upstream: Arc::new(Mutex::new(Some((upstream, buffered)))),
+            idle_timeout,
+            _guard: Arc::new(guard),
+        }
+    }
+
+    /// Takes the upstream connection and the bytes already read from it.
+    pub fn take(&self) -> Option<UpstreamConnection> {
+        self.upstream.lock().ok()?.take()
+    }
+}
 
 #[derive(Default, PartialEq)]
 pub struct HttpResponse {
     pub status_line: String,
     pub header: String,
-    pub body: String,
+    pub body: Vec<u8>,
+    pub file: Option<FileBody>,
+    pub stream: Option<StreamBody>,
+    pub upgrade: Option<UpgradedConnection>,
+    /// URI from an upstream's `X-Accel-Redirect`; the processor serves it
+    /// in place of this response.
+    pub accel_redirect: Option<String>,
+    /// Trailer fields sent after a chunked body, as `Name: value\r\n` lines.
+    pub trailer: String,
+    /// Access logs the response is written to once it has been sent.
+    pub access_log: Option<Arc<AccessLogs>>,
+    /// How fast the response is sent, from `limit_rate`.
+    pub limit_rate: Option<LimitRate>,
+    /// The upstream servers tried for the response, in order.
+    pub upstream: Vec<UpstreamTiming>,
 }
 
 impl HttpResponse {
@@ -14,59 +222,283 @@ impl HttpResponse {
         Self::default()
     }
 
+    /// A 200 response with `value` serialized as a JSON body; use
+    /// `set_status_line` to send another status. A value that cannot be
+    /// serialized, such as a map with non-string keys, gives 500.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Self {
+        let mut resp = Self::new();
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+                resp.set_header("Content-Type", "application/json");
+                resp.set_body_bytes(&body);
+            }
+            Err(e) => {
+                log_error!("Failed to serialize JSON response: {}", e);
+                resp.set_status_line(Version::HTTP_11, StatusCode::INTERNAL_SERVER_ERROR);
+                resp.set_header("Content-Type", "text/plain");
+                resp.set_body("Internal Server Error");
+            }
+        }
+        resp
+    }
+
     pub fn set_status_line(&mut self, version: Version, status_code: StatusCode) -> &mut Self {
-        let message = status_code.canonical_reason().unwrap();
+        let message = status_code.canonical_reason().unwrap_or("");
         self.status_line = format!(
             "{} {} {}\r\n",
             http_version_to_string(&version),
-            status_code,
+            status_code.as_u16(),
             message
         );
 
         self
     }
 
+    /// Adds a header. CR and LF are dropped from the name and value, so a
+    /// value built from the request cannot start a header of its own.
     pub fn set_header(&mut self, key: &str, value: &str) -> &mut Self {
-        self.header.push_str(key);
-        self.header.push_str(": ");
-        self.header.push_str(value);
-        self.header.push_str("\r\n");
+        push_field(&mut self.header, key, value);
+
+        self
+    }
+
+    /// Adds a trailer field, sent only when the body is chunked.
+    pub fn set_trailer(&mut self, key: &str, value: &str) -> &mut Self {
+        push_field(&mut self.trailer, key, value);
+
+        self
+    }
+
+    pub fn remove_header(&mut self, key: &str) -> &mut Self {
+        self.header = self
+            .header
+            .split_inclusive("\r\n")
+            .filter(|line| {
+                line.split_once(':')
+                    .is_none_or(|(name, _)| !name.trim().eq_ignore_ascii_case(key))
+            })
+            .collect();
 
         self
     }
 
+    /// Sets the entity tag the processor compares against `If-None-Match`,
+    /// adding the quotes when missing. A `W/` prefix marks it weak.
+    pub fn set_etag(&mut self, etag: &str) -> &mut Self {
+        let etag = if etag.ends_with('"') {
+            etag.to_string()
+        } else {
+            format!("\"{}\"", etag)
+        };
+        self.remove_header("ETag").set_header("ETag", &etag)
+    }
+
+    /// Sets the modification time the processor compares against
+    /// `If-Modified-Since`.
+    pub fn set_last_modified(&mut self, modified: SystemTime) -> &mut Self {
+        self.remove_header("Last-Modified")
+            .set_header("Last-Modified", &http_date(modified))
+    }
+
+    /// Whether the response carries an `ETag` or `Last-Modified` validator.
+    pub fn has_validators(&self) -> bool {
+        self.has_header("ETag") || self.has_header("Last-Modified")
+    }
+
     pub fn set_body(&mut self, body: &str) -> &mut Self {
-        self.body.push_str("\r\n");
-        self.body.push_str(body);
+        self.set_body_bytes(body.as_bytes())
+    }
+
+    pub fn set_body_bytes(&mut self, body: &[u8]) -> &mut Self {
+        self.body.extend_from_slice(body);
+
+        self
+    }
+
+    pub fn set_body_file(&mut self, file: FileBody) -> &mut Self {
+        self.file = Some(file);
+
+        self
+    }
+
+    pub fn set_body_stream(&mut self, stream: StreamBody) -> &mut Self {
+        self.stream = Some(stream);
+
+        self
+    }
+
+    pub fn set_upgrade(&mut self, upgrade: UpgradedConnection) -> &mut Self {
+        self.upgrade = Some(upgrade);
 
         self
     }
 
+    /// Reads a file region or stream into the in-memory body, for filters
+    /// that need to transform it.
+    pub fn load_body(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            let mut content = Vec::with_capacity(file.len as usize);
+            file.copy_to(&mut content)?;
+            self.body.extend_from_slice(&content);
+            self.file = None;
+        }
+        if let Some(stream) = &self.stream {
+            let mut content = Vec::new();
+            stream.copy_to(&mut content, false)?;
+            self.body.extend_from_slice(&content);
+            self.stream = None;
+        }
+        Ok(())
+    }
+
+    /// Moves the in-memory body, file region and stream out as one reader,
+    /// for filters that transform the body as it is sent. The length is
+    /// `None` when the stream's is unknown.
+    pub fn take_body_reader(&mut self) -> (Box<dyn Read + Send>, Option<u64>) {
+        let mut len = Some(self.body.len() as u64);
+        let mut reader: Box<dyn Read + Send> =
+            Box::new(Cursor::new(std::mem::take(&mut self.body)));
+        if let Some(file) = self.file.take() {
+            len = len.map(|len| len + file.len);
+            reader = Box::new(reader.chain(file));
+        }
+        if let Some(stream) = self.stream.take() {
+            len = len.zip(stream.len).map(|(len, stream)| len + stream);
+            reader = Box::new(reader.chain(stream));
+        }
+        (reader, len)
+    }
+
+    /// Reads a stream to the end and drops it, for responses only wanted for
+    /// their status and headers.
+    pub fn discard_body(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.copy_to(&mut io::sink(), false);
+        }
+        self.file = None;
+        self.body.clear();
+    }
+
+    /// Length of the body, including a file region and a stream of known
+    /// length.
+    pub fn body_len(&self) -> u64 {
+        self.body.len() as u64
+            + self.file.as_ref().map_or(0, |file| file.len)
+            + self
+                .stream
+                .as_ref()
+                .and_then(|stream| stream.len)
+                .unwrap_or(0)
+    }
+
+    /// Whether the body ends only when the connection is closed, because its
+    /// length is unknown and the client cannot take chunked framing.
+    pub fn is_close_delimited(&self) -> bool {
+        self.has_unknown_length() && self.status_line.starts_with("HTTP/1.0")
+    }
+
+    /// Whether the body is written in chunked framing.
+    pub fn is_chunked(&self) -> bool {
+        self.has_unknown_length() && !self.status_line.starts_with("HTTP/1.0")
+    }
+
+    fn has_unknown_length(&self) -> bool {
+        self.allows_body()
+            && self
+                .stream
+                .as_ref()
+                .is_some_and(|stream| stream.len.is_none())
+            && !self.has_header("Content-Length")
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.status_line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    pub fn has_header(&self, key: &str) -> bool {
+        self.header_value(key).is_some()
+    }
+
+    pub fn header_value(&self, key: &str) -> Option<&str> {
+        self.headers()
+            .find_map(|(name, value)| name.eq_ignore_ascii_case(key).then_some(value))
+    }
+
+    /// The headers as name and value pairs, in the order they were set.
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.header.split("\r\n").filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim(), value.trim()))
+        })
+    }
+
+    /// 1xx, 204 and 304 responses never carry a body or a Content-Length.
+    fn allows_body(&self) -> bool {
+        self.status()
+            .is_none_or(|status| status >= 200 && status != 204 && status != 304)
+    }
+
+    /// The status line and headers, terminated by the blank line.
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut head = Vec::with_capacity(self.status_line.len() + self.header.len() + 32);
+        head.extend_from_slice(self.status_line.as_bytes());
+        head.extend_from_slice(self.header.as_bytes());
+        if self.is_chunked() {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        } else if self.allows_body()
+            && !self.has_unknown_length()
+            && !self.has_header("Content-Length")
+            && !self.has_header("Transfer-Encoding")
+        {
+            head.extend_from_slice(format!("Content-Length: {}\r\n", self.body_len()).as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut response = Vec::new();
-        response.extend_from_slice(self.status_line.as_bytes());
-        response.extend_from_slice(self.header.as_bytes());
-        response.extend_from_slice(self.body.as_bytes());
+        let mut response = self.head_bytes();
+        response.extend_from_slice(&self.body);
+        if let Some(file) = &self.file {
+            if let Err(e) = file.copy_to(&mut response) {
+                log_error!("Failed to read response body file: {}", e);
+            }
+        }
+        if let Some(stream) = &self.stream {
+            let trailer = self.is_chunked().then_some(self.trailer.as_str());
+            if let Err(e) = stream.copy_framed(&mut response, trailer) {
+                log_error!("Failed to read response body stream: {}", e);
+            }
+        }
         response
     }
 }
 
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(SystemTime::from)
+}
+
+fn push_field(fields: &mut String, key: &str, value: &str) {
+    let line_break = |c: char| c == '\r' || c == '\n';
+    fields.extend(key.split(line_break));
+    fields.push_str(": ");
+    fields.extend(value.split(line_break));
+    fields.push_str("\r\n");
+}
+
+/// Looks up the MIME type of a path in the built-in mapping.
 pub fn get_content_type(path: &str) -> &'static str {
-    path.split('.')
-        .last()
-        .map(|ext| match ext.to_lowercase().as_str() {
-            "html" => "text/html",
-            "css" => "text/css",
-            "js" => "application/javascript",
-            "jpg" | "jpeg" => "image/jpeg",
-            "png" => "image/png",
-            "gif" => "image/gif",
-            "svg" => "image/svg+xml",
-            "json" => "application/json",
-            "txt" => "text/plain",
-            _ => "application/octet-stream",
-        })
-        .unwrap_or("application/octet-stream")
+    DEFAULT_TYPES.for_path(path).unwrap_or(DEFAULT_MIME_TYPE)
 }
 
 #[cfg(test)]
@@ -80,4 +512,20 @@ mod tests {
 
         assert_eq!(response.status_line, "HTTP/1.1 200 OK".to_string());
     }
+
+    #[test]
+    fn test_set_header_drops_line_breaks() {
+        let mut response = HttpResponse::new();
+        response.set_header("Location", "/b\r\nSet-Cookie: evil=2");
+        response.set_header("X-A\n", "1\r");
+        response.set_trailer("X-T", "a\r\nb");
+
+        assert_eq!(
+            response.header_value("Location"),
+            Some("/bSet-Cookie: evil=2")
+        );
+        assert!(!response.has_header("Set-Cookie"));
+        assert_eq!(response.header_value("X-A"), Some("1"));
+        assert_eq!(response.trailer, "X-T: ab\r\n");
+    }
 }
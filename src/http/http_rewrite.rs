@@ -0,0 +1,736 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    sync::{Arc, Mutex},
+};
+
+use http::StatusCode;
+use percent_encoding::{percent_encode, CONTROLS};
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{ConfigContext, ConfigPosition},
+            config_loader::ConfigError,
+        },
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(CommandBuilder::new("rewrite")
+    .is_repeatable()
+    .allowed_parents(vec![
+        "server".to_string(),
+        "location".to_string(),
+        "if".to_string(),
+    ])
+    .display_name("en", "Rewrite")
+    .display_name("zh-tw", "重寫")
+    .desc(
+        "en",
+        "Rewrites the request URI when it matches a regular expression"
+    )
+    .desc("zh-tw", "當請求 URI 符合正規表示式時改寫 URI")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Pattern")
+            .display_name("zh-tw", "模式")
+            .arg_type(ArgType::Regex)
+            .is_required(true)
+            .default("")
+            .desc("en", "Regular expression matched against the URI")
+            .desc("zh-tw", "用於比對 URI 的正規表示式")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Replacement")
+            .display_name("zh-tw", "替換")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "New URI; $1..$9 refer to capture groups and variables are expanded"
+            )
+            .desc("zh-tw", "新的 URI；可用 $1..$9 引用擷取群組，並會展開變數")
+            .build(),
+        ParameterBuilder::new(2)
+            .display_name("en", "Flag")
+            .display_name("zh-tw", "旗標")
+            .arg_type(ArgType::Enum(vec![
+                "last".to_string(),
+                "break".to_string(),
+                "redirect".to_string(),
+                "permanent".to_string(),
+            ]))
+            .is_required(false)
+            .default("")
+            .desc(
+                "en",
+                "last: match locations again; break: stop rewriting; redirect: 302; permanent: 301"
+            )
+            .desc(
+                "zh-tw",
+                "last：重新匹配 location；break：停止重寫；redirect：302 轉址；permanent：301 轉址"
+            )
+            .build(),
+    ])
+    .build(handle_rewrite), CommandBuilder::new("return")
+    .allowed_parents(vec![
+        "server".to_string(),
+        "location".to_string(),
+        "if".to_string(),
+    ])
+    .display_name("en", "Return")
+    .display_name("zh-tw", "回傳")
+    .desc(
+        "en",
+        "Stops processing and returns the given status code, text or redirect"
+    )
+    .desc("zh-tw", "停止處理並回傳指定的狀態碼、文字或轉址")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Code or URL")
+            .display_name("zh-tw", "狀態碼或網址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Status code, or a URL to redirect to with 302"
+            )
+            .desc("zh-tw", "狀態碼，或以 302 轉址的網址")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Text or URL")
+            .display_name("zh-tw", "文字或網址")
+            .type_name("String")
+            .is_required(false)
+            .default("")
+            .desc(
+                "en",
+                "Redirect URL for 301, 302, 303, 307 and 308, otherwise the response body; variables are expanded"
+            )
+            .desc(
+                "zh-tw",
+                "301、302、303、307、308 時為轉址網址，其他狀態碼則為回應內容；會展開變數"
+            )
+            .build(),
+    ])
+    .build(handle_return), CommandBuilder::new("if")
+    .is_block()
+    .allowed_parents(vec!["server".to_string(), "location".to_string()])
+    .display_name("en", "If")
+    .display_name("zh-tw", "條件")
+    .desc(
+        "en",
+        "Runs the rewrite and return directives inside the block when the condition is true"
+    )
+    .desc("zh-tw", "條件成立時執行區塊內的 rewrite 與 return 指令")
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Condition")
+        .display_name("zh-tw", "條件")
+        .type_name("String")
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "Condition in parentheses, e.g. ($http_user_agent ~* bot), ($arg_debug = 1) or (!-f $uri)"
+        )
+        .desc(
+            "zh-tw",
+            "以括號包住的條件，例如 ($http_user_agent ~* bot)、($arg_debug = 1) 或 (!-f $uri)"
+        )
+        .build()])
+    .arity(Arity::AtLeast(1))
+    .build(handle_if));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteFlag {
+    None,
+    Last,
+    Break,
+    Redirect,
+    Permanent,
+}
+
+pub struct RewriteRule {
+    regex: Regex,
+    replacement: VarTemplate,
+    flag: RewriteFlag,
+}
+
+pub struct ReturnAction {
+    status: StatusCode,
+    text: Option<VarTemplate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileTest {
+    File,
+    Dir,
+    Exists,
+    Executable,
+}
+
+pub enum IfCondition {
+    /// True when the value is neither empty nor "0".
+    Value(VarTemplate),
+    Equals {
+        value: VarTemplate,
+        expected: VarTemplate,
+        negate: bool,
+    },
+    Matches {
+        value: VarTemplate,
+        regex: Regex,
+        negate: bool,
+    },
+    File {
+        path: VarTemplate,
+        test: FileTest,
+        negate: bool,
+    },
+}
+
+pub struct IfBlock {
+    condition: Arc<IfCondition>,
+    script: RewriteScript,
+}
+
+pub enum ScriptOp {
+    Rewrite(RewriteRule),
+    Return(ReturnAction),
+    If(IfBlock),
+}
+
+enum OpResult {
+    Next,
+    Stop(PhaseResult),
+}
+
+/// The ordered rewrite-module instructions of one `server` or `location`
+/// block, executed before the content handler.
+#[derive(Default)]
+pub struct RewriteScript {
+    ops: Vec<(Option<ConfigPosition>, ScriptOp)>,
+}
+
+impl RewriteScript {
+    pub fn push(&mut self, pos: Option<ConfigPosition>, op: ScriptOp) {
+        self.ops.push((pos, op));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Takes the script of a `server` or `location` block, together with the
+    /// scripts of its `if` blocks, in configuration order.
+    pub fn from_block(block: &ConfigContext) -> Self {
+        let mut script = block
+            .store
+            .get::<Mutex<RewriteScript>>()
+            .and_then(|script| {
+                script
+                    .lock()
+                    .ok()
+                    .map(|mut script| std::mem::take(&mut *script))
+            })
+            .unwrap_or_default();
+        for child in &block.children {
+            if child.block_name.trim() != "if" {
+                continue;
+            }
+            if let Some(condition) = child.store.get::<IfCondition>() {
+                script.push(
+                    child.position.clone(),
+                    ScriptOp::If(IfBlock {
+                        condition,
+                        script: Self::from_block(child),
+                    }),
+                );
+            }
+        }
+        script.into_ordered()
+    }
+
+    /// Directives of different names reach their handlers grouped by name, so
+    /// restore the order in which they were written when positions are known.
+    pub fn into_ordered(mut self) -> Self {
+        let same_file = self
+            .ops
+            .windows(2)
+            .all(|pair| match (&pair[0].0, &pair[1].0) {
+                (Some(a), Some(b)) => a.file == b.file,
+                _ => false,
+            });
+        if same_file {
+            self.ops.sort_by_key(|(pos, _)| {
+                pos.as_ref()
+                    .map(|pos| (pos.line, pos.column))
+                    .unwrap_or_default()
+            });
+        }
+        self
+    }
+}
+
+impl RewriteScript {
+    fn execute(&self, req: &mut HttpRequest) -> OpResult {
+        for (_, op) in &self.ops {
+            let result = match op {
+                ScriptOp::Rewrite(rule) => rule.apply(req),
+                ScriptOp::Return(action) => action.apply(req),
+                ScriptOp::If(block) if block.condition.evaluate(req) => block.script.execute(req),
+                ScriptOp::If(_) => OpResult::Next,
+            };
+            if let OpResult::Stop(result) = result {
+                return OpResult::Stop(result);
+            }
+        }
+        OpResult::Next
+    }
+}
+
+impl RequestPhase for RewriteScript {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        match self.execute(req) {
+            OpResult::Stop(result) => result,
+            OpResult::Next => PhaseResult::Continue,
+        }
+    }
+}
+
+impl IfCondition {
+    /// Parses the condition of an `if` block from its arguments, with the
+    /// surrounding parentheses still attached.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut args = args.to_vec();
+        if let Some(first) = args.first_mut() {
+            match first.strip_prefix('(') {
+                Some(rest) => *first = rest.to_string(),
+                None => return Err("condition must be enclosed in parentheses".to_string()),
+            }
+        }
+        match args
+            .last_mut()
+            .map(|last| last.strip_suffix(')').map(str::to_string))
+        {
+            Some(Some(rest)) => *args.last_mut().unwrap() = rest,
+            _ => return Err("condition must be enclosed in parentheses".to_string()),
+        }
+        if args.first().is_some_and(|arg| arg.is_empty()) {
+            args.remove(0);
+        }
+        if args.len() > 1 && args.last().is_some_and(|arg| arg.is_empty()) {
+            args.pop();
+        }
+
+        let condition = match args.as_slice() {
+            [value] if value.starts_with('$') => IfCondition::Value(VarTemplate::parse(value)),
+            [test, path] if test.trim_start_matches('!').starts_with('-') => {
+                let (negate, test) = match test.strip_prefix('!') {
+                    Some(test) => (true, test),
+                    None => (false, test.as_str()),
+                };
+                let test = match test {
+                    "-f" => FileTest::File,
+                    "-d" => FileTest::Dir,
+                    "-e" => FileTest::Exists,
+                    "-x" => FileTest::Executable,
+                    other => return Err(format!("invalid file test \"{}\"", other)),
+                };
+                IfCondition::File {
+                    path: VarTemplate::parse(path),
+                    test,
+                    negate,
+                }
+            }
+            [value, op, operand] if value.starts_with('$') => match op.as_str() {
+                "=" | "!=" => IfCondition::Equals {
+                    value: VarTemplate::parse(value),
+                    expected: VarTemplate::parse(operand),
+                    negate: op == "!=",
+                },
+                "~" | "~*" | "!~" | "!~*" => IfCondition::Matches {
+                    value: VarTemplate::parse(value),
+                    regex: RegexBuilder::new(operand)
+                        .case_insensitive(op.ends_with('*'))
+                        .build()
+                        .map_err(|e| e.to_string())?,
+                    negate: op.starts_with('!'),
+                },
+                other => return Err(format!("unexpected operator \"{}\"", other)),
+            },
+            _ => return Err("invalid condition".to_string()),
+        };
+        Ok(condition)
+    }
+
+    pub fn evaluate(&self, req: &HttpRequest) -> bool {
+        let vars = RequestVariables::new(req);
+        match self {
+            IfCondition::Value(value) => {
+                let value = value.render(&vars);
+                !value.is_empty() && value != "0"
+            }
+            IfCondition::Equals {
+                value,
+                expected,
+                negate,
+            } => (value.render(&vars) == expected.render(&vars)) != *negate,
+            IfCondition::Matches {
+                value,
+                regex,
+                negate,
+            } => regex.is_match(&value.render(&vars)) != *negate,
+            IfCondition::File { path, test, negate } => {
+                let metadata = fs::metadata(path.render(&vars));
+                let result = match test {
+                    FileTest::File => metadata.is_ok_and(|m| m.is_file()),
+                    FileTest::Dir => metadata.is_ok_and(|m| m.is_dir()),
+                    FileTest::Exists => metadata.is_ok(),
+                    FileTest::Executable => {
+                        metadata.is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                    }
+                };
+                result != *negate
+            }
+        }
+    }
+}
+
+impl RewriteRule {
+    pub fn new(regex: Regex, replacement: &str, flag: RewriteFlag) -> Self {
+        Self {
+            regex,
+            replacement: VarTemplate::parse(replacement),
+            flag,
+        }
+    }
+
+    fn apply(&self, req: &mut HttpRequest) -> OpResult {
+        let uri = req.path().to_string();
+        let (path, query) = match uri.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (uri.as_str(), ""),
+        };
+        let Some(captures) = self.regex.captures(path) else {
+            return OpResult::Next;
+        };
+        let mut target = self
+            .replacement
+            .render_with_captures(&RequestVariables::new(req), Some(&captures));
+
+        // A trailing "?" drops the original arguments, as in nginx.
+        if target.ends_with('?') {
+            target.pop();
+        } else if !query.is_empty() {
+            target.push(if target.contains('?') { '&' } else { '?' });
+            target.push_str(query);
+        }
+
+        let external = target.starts_with("http://") || target.starts_with("https://");
+        match self.flag {
+            RewriteFlag::Permanent => {
+                return OpResult::Stop(PhaseResult::Respond(Box::new(redirect_response(
+                    req,
+                    StatusCode::MOVED_PERMANENTLY,
+                    &target,
+                ))))
+            }
+            RewriteFlag::Redirect => {
+                return OpResult::Stop(PhaseResult::Respond(Box::new(redirect_response(
+                    req,
+                    StatusCode::FOUND,
+                    &target,
+                ))))
+            }
+            _ if external => {
+                return OpResult::Stop(PhaseResult::Respond(Box::new(redirect_response(
+                    req,
+                    StatusCode::FOUND,
+                    &target,
+                ))))
+            }
+            _ => {}
+        }
+
+        req.set_path(target);
+        match self.flag {
+            RewriteFlag::Last => OpResult::Stop(PhaseResult::Restart),
+            RewriteFlag::Break => OpResult::Stop(PhaseResult::Continue),
+            _ => OpResult::Next,
+        }
+    }
+}
+
+impl ReturnAction {
+    pub fn new(status: StatusCode, text: Option<&str>) -> Self {
+        Self {
+            status,
+            text: text.map(VarTemplate::parse),
+        }
+    }
+
+    fn apply(&self, req: &mut HttpRequest) -> OpResult {
+        let text = self
+            .text
+            .as_ref()
+            .map(|text| text.render(&RequestVariables::new(req)));
+        let resp = match text {
+            Some(location) if is_redirect_status(self.status) => {
+                redirect_response(req, self.status, &location)
+            }
+            Some(text) => {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), self.status);
+                resp.set_header("Content-Type", "text/plain");
+                resp.set_body(&text);
+                resp
+            }
+            None => status_response(req, self.status),
+        };
+        OpResult::Stop(PhaseResult::Respond(Box::new(resp)))
+    }
+}
+
+fn is_redirect_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308)
+}
+
+/// Builds the default response for a status code: an HTML page for errors and
+/// redirects, and an empty body otherwise.
+pub fn status_response(req: &HttpRequest, status: StatusCode) -> HttpResponse {
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*req.version(), status);
+    if status.is_client_error() || status.is_server_error() || status.is_redirection() {
+        resp.set_header("Content-Type", "text/html");
+        resp.set_body(&status_page(status));
+    }
+    resp
+}
+
+fn status_page(status: StatusCode) -> String {
+    let reason = status.canonical_reason().unwrap_or_default();
+    format!(
+        "<html><head><title>{code} {reason}</title></head><body><h1>{code} {reason}</h1></body></html>\n",
+        code = status.as_u16(),
+    )
+}
+
+/// Builds a redirect. Control characters in the location, such as a decoded
+/// CR or LF taken from `$uri`, are percent-encoded again.
+pub fn redirect_response(req: &HttpRequest, status: StatusCode, location: &str) -> HttpResponse {
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*req.version(), status);
+    let location = percent_encode(location.as_bytes(), CONTROLS).to_string();
+    resp.set_header("Location", &location);
+    resp.set_header("Content-Type", "text/html");
+    resp.set_body(&status_page(status));
+    resp
+}
+
+pub fn rewrite_script(ctx: &mut ConfigContext) -> Arc<Mutex<RewriteScript>> {
+    ctx.store
+        .get_or_insert_with(|| Mutex::new(RewriteScript::default()))
+}
+
+pub fn handle_rewrite(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let pattern = ctx.str_arg(0)?;
+    let replacement = ctx.str_arg(1)?;
+    let flag = match ctx.opt_arg(2).and_then(|v| v.as_str()) {
+        Some("last") => RewriteFlag::Last,
+        Some("break") => RewriteFlag::Break,
+        Some("redirect") => RewriteFlag::Redirect,
+        Some("permanent") => RewriteFlag::Permanent,
+        _ => RewriteFlag::None,
+    };
+    let regex = Regex::new(&pattern).map_err(|e| ctx.invalid_value(&pattern, e.to_string()))?;
+    let pos = ctx.current_cmd_pos.clone();
+    if let Ok(mut script) = rewrite_script(ctx).lock() {
+        script.push(
+            pos,
+            ScriptOp::Rewrite(RewriteRule::new(regex, &replacement, flag)),
+        );
+    }
+    Ok(())
+}
+
+pub fn handle_if(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.current_cmd_args.clone();
+    let condition =
+        IfCondition::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    ctx.store.insert(Arc::new(condition));
+    Ok(())
+}
+
+pub fn handle_return(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let first = ctx.str_arg(0)?;
+    let text = ctx
+        .opt_arg(1)
+        .and_then(|v| v.as_str())
+        .filter(|text| !text.is_empty())
+        .map(str::to_string);
+
+    let action = match first.parse::<u16>() {
+        Ok(code) => {
+            let status = StatusCode::from_u16(code)
+                .ok()
+                .filter(|_| code < 1000)
+                .ok_or_else(|| ctx.invalid_value(&first, "invalid status code"))?;
+            ReturnAction::new(status, text.as_deref())
+        }
+        Err(_) if text.is_none() && is_url(&first) => {
+            ReturnAction::new(StatusCode::FOUND, Some(&first))
+        }
+        Err(_) => {
+            return Err(ctx.invalid_value(&first, "expected a status code or a URL"));
+        }
+    };
+
+    let pos = ctx.current_cmd_pos.clone();
+    if let Ok(mut script) = rewrite_script(ctx).lock() {
+        script.push(pos, ScriptOp::Return(action));
+    }
+    Ok(())
+}
+
+fn is_url(value: &str) -> bool {
+    ["http://", "https://", "$scheme"]
+        .iter()
+        .any(|prefix| value.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+            .unwrap();
+        req
+    }
+
+    fn rule(pattern: &str, replacement: &str, flag: RewriteFlag) -> RewriteScript {
+        let mut script = RewriteScript::default();
+        script.push(
+            None,
+            ScriptOp::Rewrite(RewriteRule::new(
+                Regex::new(pattern).unwrap(),
+                replacement,
+                flag,
+            )),
+        );
+        script
+    }
+
+    #[test]
+    fn test_rewrite_captures_and_args() {
+        let mut req = request("/old/a/b?x=1");
+        let script = rule("^/old/(.*)$", "/new/$1", RewriteFlag::Last);
+        assert!(matches!(script.run(&mut req), PhaseResult::Restart));
+        assert_eq!(req.path(), "/new/a/b?x=1");
+
+        let mut req = request("/p?x=1");
+        let script = rule("^/p$", "/q?y=2", RewriteFlag::Break);
+        assert!(matches!(script.run(&mut req), PhaseResult::Continue));
+        assert_eq!(req.path(), "/q?y=2&x=1");
+
+        let mut req = request("/p?x=1");
+        rule("^/p$", "/q?", RewriteFlag::None).run(&mut req);
+        assert_eq!(req.path(), "/q");
+    }
+
+    #[test]
+    fn test_rewrite_redirects() {
+        let mut req = request("/old/page");
+        let script = rule("^/old/(.*)$", "/new/$1", RewriteFlag::Permanent);
+        match script.run(&mut req) {
+            PhaseResult::Respond(resp) => {
+                assert_eq!(resp.status(), Some(301));
+                assert_eq!(resp.header_value("Location"), Some("/new/page"));
+            }
+            _ => panic!("expected redirect"),
+        }
+        let script = rule("^", "https://example.com/", RewriteFlag::None);
+        match script.run(&mut request("/")) {
+            PhaseResult::Respond(resp) => assert_eq!(resp.status(), Some(302)),
+            _ => panic!("expected redirect"),
+        }
+    }
+
+    #[test]
+    fn test_return_responses() {
+        let mut script = RewriteScript::default();
+        script.push(
+            None,
+            ScriptOp::Return(ReturnAction::new(
+                StatusCode::MOVED_PERMANENTLY,
+                Some("https://example.com$request_uri"),
+            )),
+        );
+        match script.run(&mut request("/a?b=1")) {
+            PhaseResult::Respond(resp) => {
+                assert_eq!(resp.status(), Some(301));
+                assert_eq!(
+                    resp.header_value("Location"),
+                    Some("https://example.com/a?b=1")
+                );
+            }
+            _ => panic!("expected redirect"),
+        }
+
+        let action = ReturnAction::new(StatusCode::NO_CONTENT, None);
+        match action.apply(&mut request("/")) {
+            OpResult::Stop(PhaseResult::Respond(resp)) => {
+                assert_eq!(resp.status(), Some(204));
+                assert!(!resp.has_header("Location"));
+            }
+            _ => panic!("expected response"),
+        }
+
+        let resp = redirect_response(
+            &request("/"),
+            StatusCode::FOUND,
+            "/x/b\r\nSet-Cookie: evil=2",
+        );
+        assert_eq!(
+            resp.header_value("Location"),
+            Some("/x/b%0D%0ASet-Cookie: evil=2")
+        );
+        assert!(!resp.has_header("Set-Cookie"));
+    }
+
+    #[test]
+    fn test_if_conditions() {
+        let parse = |line: &str| {
+            let args: Vec<String> = line.split(' ').map(str::to_string).collect();
+            IfCondition::parse(&args)
+        };
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /a?debug=1 HTTP/1.1\r\nUser-Agent: Googlebot/2.1\r\n\r\n")
+            .unwrap();
+
+        assert!(parse("($http_user_agent ~* bot )").unwrap().evaluate(&req));
+        assert!(!parse("($http_user_agent !~* bot)").unwrap().evaluate(&req));
+        assert!(parse("($arg_debug = 1)").unwrap().evaluate(&req));
+        assert!(parse("($arg_debug)").unwrap().evaluate(&req));
+        assert!(!parse("($arg_missing)").unwrap().evaluate(&req));
+        assert!(parse("(-d /)").unwrap().evaluate(&req));
+        assert!(parse("(!-f $uri)").unwrap().evaluate(&req));
+        assert!(parse("$arg_debug").is_err());
+        assert!(parse("($arg_debug > 1)").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::io;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::ConfigContext,
+        },
+        processor::{HttpHandler, LocationPattern},
+    },
+    register_commands,
+};
+
+use super::http_gateway::{
+    handle_protocol_param, handle_protocol_pass, protocol_handler, GatewayProtocol,
+};
+
+register_commands!(
+    CommandBuilder::new("scgi_pass")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "SCGI Pass")
+        .display_name("zh-tw", "SCGI 轉送")
+        .desc("en", "Passes requests to an SCGI server")
+        .desc("zh-tw", "將請求交給 SCGI 伺服器處理")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Address")
+            .display_name("zh-tw", "位址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "host:port, an upstream name, or unix: followed by a socket path"
+            )
+            .desc(
+                "zh-tw",
+                "host:port、upstream 名稱，或 unix: 加上 socket 路徑"
+            )
+            .build()])
+        .build(handle_protocol_pass::<Scgi>),
+    CommandBuilder::new("scgi_param")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "SCGI Param")
+        .display_name("zh-tw", "SCGI 參數")
+        .desc(
+            "en",
+            "Sets a parameter passed to the SCGI server, replacing any default of the same name"
+        )
+        .desc("zh-tw", "設定傳給 SCGI 伺服器的參數，會取代同名的預設參數")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Name")
+                .display_name("zh-tw", "名稱")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Parameter name, e.g. SCRIPT_NAME")
+                .desc("zh-tw", "參數名稱，例如 SCRIPT_NAME")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Value")
+                .display_name("zh-tw", "值")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Value, may contain variables")
+                .desc("zh-tw", "值，可包含變數")
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "If Not Empty")
+                .display_name("zh-tw", "非空才傳送")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "if_not_empty to leave the parameter out when its value is empty"
+                )
+                .desc("zh-tw", "if_not_empty 表示值為空時不傳送此參數")
+                .build(),
+        ])
+        .arity(Arity::AtLeast(2))
+        .is_repeatable()
+        .build(handle_protocol_param::<Scgi>),
+);
+
+/// The SCGI protocol.
+#[derive(Debug, Default, Clone)]
+pub struct Scgi;
+
+impl GatewayProtocol for Scgi {
+    const NAME: &'static str = "SCGI";
+    const PARAMS: &'static [(&'static str, &'static str, bool)] =
+        &[("PATH_INFO", "$document_uri", false)];
+
+    fn encode(params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut request = encode_headers(params, body.len());
+        request.extend_from_slice(body);
+        Ok(request)
+    }
+}
+
+/// Encodes the parameters as the SCGI header netstring, which must start
+/// with `CONTENT_LENGTH` and carry `SCGI: 1`.
+fn encode_headers(params: &[(String, String)], content_length: usize) -> Vec<u8> {
+    let mut headers = format!("CONTENT_LENGTH\0{}\0SCGI\01\0", content_length).into_bytes();
+    for (name, value) in params {
+        if name == "CONTENT_LENGTH" || name == "SCGI" {
+            continue;
+        }
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut netstring = format!("{}:", headers.len()).into_bytes();
+    netstring.extend(headers);
+    netstring.push(b',');
+    netstring
+}
+
+/// Builds the SCGI handler for a location chain that sets `scgi_pass`.
+pub fn scgi_handler(
+    chain: &[&ConfigContext],
+    _pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    protocol_handler::<Scgi>(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let params = [
+            ("CONTENT_LENGTH".to_string(), "99".to_string()),
+            ("PATH_INFO".to_string(), "/form".to_string()),
+        ];
+        let request = Scgi::encode(&params, b"body").unwrap();
+        let expected = b"CONTENT_LENGTH\x004\x00SCGI\x001\x00PATH_INFO\x00/form\x00";
+        let mut netstring = format!("{}:", expected.len()).into_bytes();
+        netstring.extend_from_slice(expected);
+        netstring.extend_from_slice(b",body");
+        assert_eq!(request, netstring);
+    }
+}
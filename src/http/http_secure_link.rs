@@ -0,0 +1,355 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::{
+    hash::{hash, MessageDigest},
+    memcmp,
+    pkey::PKey,
+    sign::Signer,
+};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{PhaseResult, RequestPhase},
+    },
+    register_commands,
+};
+
+use super::{
+    http_auth_jwt::base64url_decode,
+    http_request::HttpRequest,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("secure_link")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Secure Link")
+        .display_name("zh-tw", "安全連結")
+        .desc(
+            "en",
+            "Where the signature and expiry of a signed link are found; $secure_link is 1 for a valid link, 0 for an expired one and empty otherwise"
+        )
+        .desc(
+            "zh-tw",
+            "簽章連結的簽章與到期時間來源；$secure_link 在連結有效時為 1，過期時為 0，其餘為空"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Signature and Expiry")
+            .display_name("zh-tw", "簽章與到期時間")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "The signature, then optionally a comma and the expiry as a Unix timestamp, e.g. $arg_md5,$arg_expires"
+            )
+            .desc(
+                "zh-tw",
+                "簽章，可再接逗號與 Unix 時間戳記格式的到期時間，例如 $arg_md5,$arg_expires"
+            )
+            .build()])
+        .build(handle_secure_link),
+    CommandBuilder::new("secure_link_md5")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Secure Link MD5")
+        .display_name("zh-tw", "安全連結 MD5")
+        .desc(
+            "en",
+            "Checks the signature as the base64url encoded MD5 of this text, which should include a secret"
+        )
+        .desc(
+            "zh-tw",
+            "以此文字 MD5 的 base64url 編碼驗證簽章，文字中應包含密鑰"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Text")
+            .display_name("zh-tw", "文字")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Variables and a secret, e.g. \"$secure_link_expires$uri$remote_addr secret\""
+            )
+            .desc(
+                "zh-tw",
+                "變數與密鑰，例如 \"$secure_link_expires$uri$remote_addr secret\""
+            )
+            .build()])
+        .build(handle_secure_link_md5),
+    CommandBuilder::new("secure_link_hmac")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Secure Link HMAC")
+        .display_name("zh-tw", "安全連結 HMAC")
+        .desc(
+            "en",
+            "Checks the signature as the HMAC-SHA256 of a message under a secret key, in hex or base64url"
+        )
+        .desc(
+            "zh-tw",
+            "以密鑰計算訊息的 HMAC-SHA256 驗證簽章，簽章可為十六進位或 base64url"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Message")
+                .display_name("zh-tw", "訊息")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Variables signed, e.g. \"$uri|$secure_link_expires\"")
+                .desc("zh-tw", "簽署的變數，例如 \"$uri|$secure_link_expires\"")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Secret")
+                .display_name("zh-tw", "密鑰")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "The key shared with the application issuing links")
+                .desc("zh-tw", "與產生連結的應用程式共用的密鑰")
+                .build(),
+        ])
+        .arity(Arity::Exact(2))
+        .build(handle_secure_link_hmac)
+);
+
+/// Set to `1` for a valid link, `0` for an expired one and empty otherwise.
+pub const SECURE_LINK: &str = "secure_link";
+/// The expiry taken from the link, for the signed text to include.
+pub const SECURE_LINK_EXPIRES: &str = "secure_link_expires";
+
+/// How the signature of a link is computed.
+#[derive(Debug, Clone)]
+pub enum SecureLinkCheck {
+    Md5(VarTemplate),
+    Hmac {
+        message: VarTemplate,
+        secret: Vec<u8>,
+    },
+}
+
+impl SecureLinkCheck {
+    fn verify(&self, signature: &str, vars: &RequestVariables) -> bool {
+        match self {
+            Self::Md5(text) => {
+                let Ok(signature) = base64url_decode(signature) else {
+                    return false;
+                };
+                hash(MessageDigest::md5(), text.render(vars).as_bytes())
+                    .map(|digest| {
+                        digest.len() == signature.len() && memcmp::eq(&digest, &signature)
+                    })
+                    .unwrap_or(false)
+            }
+            Self::Hmac { message, secret } => {
+                let signature = match decode_hex(signature) {
+                    Some(signature) => signature,
+                    None => match base64url_decode(signature) {
+                        Ok(signature) => signature,
+                        Err(_) => return false,
+                    },
+                };
+                PKey::hmac(secret)
+                    .and_then(|key| {
+                        Signer::new(MessageDigest::sha256(), &key)?
+                            .sign_oneshot_to_vec(message.render(vars).as_bytes())
+                    })
+                    .map(|mac| mac.len() == signature.len() && memcmp::eq(&mac, &signature))
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() != 64 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SecureLinkConfig {
+    pub link: Option<VarTemplate>,
+    pub check: Option<SecureLinkCheck>,
+}
+
+impl MergeConfig for SecureLinkConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.link.is_none() {
+            self.link = parent.link.clone();
+        }
+        if self.check.is_none() {
+            self.check = parent.check.clone();
+        }
+    }
+}
+
+/// Sets `$secure_link` ahead of the rewrite rules that test it.
+pub struct SecureLinkPhase {
+    link: VarTemplate,
+    check: SecureLinkCheck,
+}
+
+impl SecureLinkPhase {
+    fn status(&self, req: &mut HttpRequest, now: u64) -> &'static str {
+        let link = self.link.render(&RequestVariables::new(req));
+        let (signature, expires) = match link.split_once(',') {
+            Some((signature, expires)) => (signature, Some(expires)),
+            None => (link.as_str(), None),
+        };
+        req.set_var(SECURE_LINK_EXPIRES, expires.unwrap_or_default());
+        let expires = match expires.map(str::parse::<u64>) {
+            Some(Ok(expires)) => Some(expires),
+            Some(Err(_)) => return "",
+            None => None,
+        };
+        if signature.is_empty() || !self.check.verify(signature, &RequestVariables::new(req)) {
+            return "";
+        }
+        match expires {
+            Some(expires) if expires < now => "0",
+            _ => "1",
+        }
+    }
+}
+
+impl RequestPhase for SecureLinkPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let status = self.status(req, now);
+        req.set_var(SECURE_LINK, status);
+        PhaseResult::Continue
+    }
+}
+
+/// Builds the link check for a block chain with `secure_link` and a way to
+/// verify it.
+pub fn secure_link_phase(chain: &[&ConfigContext]) -> Option<SecureLinkPhase> {
+    let config = merged_config::<SecureLinkConfig>(chain);
+    Some(SecureLinkPhase {
+        link: config.link?,
+        check: config.check?,
+    })
+}
+
+pub fn handle_secure_link(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let link = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SecureLinkConfig>().lock() {
+        config.link = Some(VarTemplate::parse(&link));
+    }
+    Ok(())
+}
+
+pub fn handle_secure_link_md5(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let text = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SecureLinkConfig>().lock() {
+        config.check = Some(SecureLinkCheck::Md5(VarTemplate::parse(&text)));
+    }
+    Ok(())
+}
+
+pub fn handle_secure_link_hmac(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let message = ctx.str_arg(0)?;
+    let secret = ctx.str_arg(1)?;
+    if secret.is_empty() {
+        return Err(ctx.invalid_value(&secret, "the secret must not be empty"));
+    }
+    if let Ok(mut config) = ctx.block_config::<SecureLinkConfig>().lock() {
+        config.check = Some(SecureLinkCheck::Hmac {
+            message: VarTemplate::parse(&message),
+            secret: secret.into_bytes(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::http_auth_jwt::base64url_encode;
+
+    fn request(query: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        let raw = format!("GET /file.zip?{} HTTP/1.1\r\nHost: a\r\n\r\n", query);
+        req.parse(raw.as_bytes()).unwrap();
+        req
+    }
+
+    #[test]
+    fn test_secure_link_status() {
+        let md5 = SecureLinkPhase {
+            link: VarTemplate::parse("$arg_md5,$arg_expires"),
+            check: SecureLinkCheck::Md5(VarTemplate::parse("$secure_link_expires$uri secret")),
+        };
+        let digest = hash(MessageDigest::md5(), b"2000/file.zip secret").unwrap();
+        let signature = base64url_encode(&digest);
+        let query = format!("md5={}&expires=2000", signature);
+
+        assert_eq!(md5.status(&mut request(&query), 1000), "1");
+        assert_eq!(md5.status(&mut request(&query), 3000), "0");
+        let tampered = format!("md5={}&expires=2001", signature);
+        assert_eq!(md5.status(&mut request(&tampered), 1000), "");
+        assert_eq!(md5.status(&mut request("expires=2000"), 1000), "");
+
+        let hmac = SecureLinkPhase {
+            link: VarTemplate::parse("$arg_sig"),
+            check: SecureLinkCheck::Hmac {
+                message: VarTemplate::parse("$uri"),
+                secret: b"key".to_vec(),
+            },
+        };
+        let key = PKey::hmac(b"key").unwrap();
+        let mac = Signer::new(MessageDigest::sha256(), &key)
+            .unwrap()
+            .sign_oneshot_to_vec(b"/file.zip")
+            .unwrap();
+        let hex: String = mac.iter().map(|b| format!("{:02X}", b)).collect();
+        assert_eq!(hmac.status(&mut request(&format!("sig={}", hex)), 0), "1");
+        let encoded = base64url_encode(&mac);
+        assert_eq!(
+            hmac.status(&mut request(&format!("sig={}", encoded)), 0),
+            "1"
+        );
+        assert_eq!(hmac.status(&mut request("sig=abc"), 0), "");
+    }
+}
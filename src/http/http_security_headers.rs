@@ -0,0 +1,466 @@
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::ResponseFilter,
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(
+    CommandBuilder::new("security_headers")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Security Headers")
+        .display_name("zh-tw", "安全性標頭")
+        .desc(
+            "en",
+            "Adds X-Content-Type-Options: nosniff, X-Frame-Options: SAMEORIGIN and Referrer-Policy: strict-origin-when-cross-origin to responses; off removes every header of this module"
+        )
+        .desc(
+            "zh-tw",
+            "在回應加上 X-Content-Type-Options: nosniff、X-Frame-Options: SAMEORIGIN 與 Referrer-Policy: strict-origin-when-cross-origin；off 則停用本模組所有標頭"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off")
+            .desc("zh-tw", "on 或 off")
+            .build()])
+        .build(handle_security_headers),
+    CommandBuilder::new("hsts")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "HTTP Strict Transport Security")
+        .display_name("zh-tw", "HTTP 嚴格傳輸安全")
+        .desc(
+            "en",
+            "Sends Strict-Transport-Security on HTTPS responses so browsers only use HTTPS for the site"
+        )
+        .desc(
+            "zh-tw",
+            "在 HTTPS 回應送出 Strict-Transport-Security，讓瀏覽器之後只以 HTTPS 連線此網站"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Max Age")
+                .display_name("zh-tw", "有效時間")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "How long browsers remember it, e.g. 365d, or off")
+                .desc("zh-tw", "瀏覽器記住的時間，例如 365d，或 off")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Options")
+                .display_name("zh-tw", "選項")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "includeSubDomains, and preload for the browser preload lists, which needs includeSubDomains and at least 365d"
+                )
+                .desc(
+                    "zh-tw",
+                    "includeSubDomains，以及申請瀏覽器預載清單用的 preload；preload 需搭配 includeSubDomains 且至少 365d"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_hsts),
+    CommandBuilder::new("frame_options")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Frame Options")
+        .display_name("zh-tw", "框架嵌入限制")
+        .desc(
+            "en",
+            "Sets who may embed pages in a frame, as X-Frame-Options and the frame-ancestors directive of Content-Security-Policy"
+        )
+        .desc(
+            "zh-tw",
+            "設定誰能以框架嵌入頁面，同時以 X-Frame-Options 與 Content-Security-Policy 的 frame-ancestors 送出"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Policy")
+            .display_name("zh-tw", "政策")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "deny, sameorigin or off")
+            .desc("zh-tw", "deny、sameorigin 或 off")
+            .build()])
+        .build(handle_frame_options),
+    CommandBuilder::new("referrer_policy")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Referrer Policy")
+        .display_name("zh-tw", "Referrer 政策")
+        .desc("en", "Sets the Referrer-Policy header")
+        .desc("zh-tw", "設定 Referrer-Policy 標頭")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Policy")
+            .display_name("zh-tw", "政策")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "A policy such as no-referrer or same-origin, or off")
+            .desc("zh-tw", "政策，例如 no-referrer 或 same-origin，或 off")
+            .build()])
+        .build(handle_referrer_policy),
+    CommandBuilder::new("content_security_policy")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Content Security Policy")
+        .display_name("zh-tw", "內容安全政策")
+        .desc("en", "Sets the Content-Security-Policy header")
+        .desc("zh-tw", "設定 Content-Security-Policy 標頭")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Policy")
+                .display_name("zh-tw", "政策")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "The policy, quoted, such as \"default-src 'self'\", or off"
+                )
+                .desc(
+                    "zh-tw",
+                    "加上引號的政策，例如 \"default-src 'self'\"，或 off"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Report Only")
+                .display_name("zh-tw", "僅回報")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "report_only to send it as Content-Security-Policy-Report-Only"
+                )
+                .desc(
+                    "zh-tw",
+                    "report_only 時改以 Content-Security-Policy-Report-Only 送出"
+                )
+                .build(),
+        ])
+        .arity(Arity::AtLeast(1))
+        .build(handle_content_security_policy)
+);
+
+/// HSTS preload lists require at least a year.
+const PRELOAD_MIN_AGE: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Csp {
+    pub policy: String,
+    pub report_only: bool,
+}
+
+/// Each header is unset, `Some(None)` after `off`, or its value.
+#[derive(Debug, Default, Clone)]
+pub struct SecurityHeadersConfig {
+    pub enabled: Option<bool>,
+    pub hsts: Option<Option<String>>,
+    pub frame_options: Option<Option<String>>,
+    pub referrer_policy: Option<Option<String>>,
+    pub csp: Option<Option<Csp>>,
+}
+
+impl MergeConfig for SecurityHeadersConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        if self.hsts.is_none() {
+            self.hsts = parent.hsts.clone();
+        }
+        if self.frame_options.is_none() {
+            self.frame_options = parent.frame_options.clone();
+        }
+        if self.referrer_policy.is_none() {
+            self.referrer_policy = parent.referrer_policy.clone();
+        }
+        if self.csp.is_none() {
+            self.csp = parent.csp.clone();
+        }
+    }
+}
+
+/// Adds the security headers of a block to responses that do not set
+/// them already.
+#[derive(Debug, Default)]
+pub struct SecurityHeadersFilter {
+    nosniff: bool,
+    hsts: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    csp: Option<Csp>,
+}
+
+impl SecurityHeadersFilter {
+    fn add(resp: &mut HttpResponse, name: &str, value: &str) {
+        if !resp.has_header(name) {
+            resp.set_header(name, value);
+        }
+    }
+
+    /// The enforced policy, with `frame-ancestors` matching the frame
+    /// options unless the policy sets its own.
+    fn enforced_policy(&self) -> Option<String> {
+        let ancestors = self.frame_options.as_deref().map(|option| match option {
+            "DENY" => "frame-ancestors 'none'",
+            _ => "frame-ancestors 'self'",
+        });
+        let policy = self.csp.as_ref().filter(|csp| !csp.report_only);
+        match (policy, ancestors) {
+            (Some(csp), Some(ancestors)) if !csp.policy.contains("frame-ancestors") => Some(
+                format!("{}; {}", csp.policy.trim_end_matches([';', ' ']), ancestors),
+            ),
+            (Some(csp), _) => Some(csp.policy.clone()),
+            (None, ancestors) => ancestors.map(str::to_string),
+        }
+    }
+}
+
+impl ResponseFilter for SecurityHeadersFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if let Some(hsts) = self.hsts.as_deref().filter(|_| req.is_secure()) {
+            Self::add(resp, "Strict-Transport-Security", hsts);
+        }
+        if self.nosniff {
+            Self::add(resp, "X-Content-Type-Options", "nosniff");
+        }
+        if let Some(frame_options) = &self.frame_options {
+            Self::add(resp, "X-Frame-Options", frame_options);
+        }
+        if let Some(referrer_policy) = &self.referrer_policy {
+            Self::add(resp, "Referrer-Policy", referrer_policy);
+        }
+        if let Some(policy) = self.enforced_policy() {
+            Self::add(resp, "Content-Security-Policy", &policy);
+        }
+        if let Some(csp) = self.csp.as_ref().filter(|csp| csp.report_only) {
+            Self::add(resp, "Content-Security-Policy-Report-Only", &csp.policy);
+        }
+    }
+}
+
+/// Builds the security headers filter for a block chain, if it adds any.
+pub fn security_headers_filter(chain: &[&ConfigContext]) -> Option<SecurityHeadersFilter> {
+    let config = merged_config::<SecurityHeadersConfig>(chain);
+    let defaults = match config.enabled {
+        Some(false) => return None,
+        Some(true) => true,
+        None => false,
+    };
+    let or_default = |value: Option<Option<String>>, default: &str| match value {
+        Some(value) => value,
+        None => defaults.then(|| default.to_string()),
+    };
+    let filter = SecurityHeadersFilter {
+        nosniff: defaults,
+        hsts: config.hsts.flatten(),
+        frame_options: or_default(config.frame_options, "SAMEORIGIN"),
+        referrer_policy: or_default(config.referrer_policy, "strict-origin-when-cross-origin"),
+        csp: config.csp.flatten(),
+    };
+    let adds_any = filter.nosniff
+        || filter.hsts.is_some()
+        || filter.frame_options.is_some()
+        || filter.referrer_policy.is_some()
+        || filter.csp.is_some();
+    adds_any.then_some(filter)
+}
+
+pub fn handle_security_headers(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SecurityHeadersConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_hsts(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let Some((max_age, options)) = args.split_first() else {
+        return Ok(());
+    };
+    let value = if max_age == "off" {
+        None
+    } else {
+        let seconds = parse_duration(max_age)
+            .map_err(|reason| ctx.invalid_value(max_age, reason))?
+            .as_secs();
+        let mut value = format!("max-age={}", seconds);
+        let mut subdomains = false;
+        let mut preload = false;
+        for option in options {
+            if option.eq_ignore_ascii_case("includeSubDomains") {
+                subdomains = true;
+            } else if option.eq_ignore_ascii_case("preload") {
+                preload = true;
+            } else {
+                return Err(ctx.invalid_value(option, "expected includeSubDomains or preload"));
+            }
+        }
+        if preload && (!subdomains || seconds < PRELOAD_MIN_AGE) {
+            return Err(ctx.invalid_value(
+                "preload",
+                "needs includeSubDomains and a max age of at least 365d",
+            ));
+        }
+        if subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if preload {
+            value.push_str("; preload");
+        }
+        Some(value)
+    };
+    if let Ok(mut config) = ctx.block_config::<SecurityHeadersConfig>().lock() {
+        config.hsts = Some(value);
+    }
+    Ok(())
+}
+
+pub fn handle_frame_options(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(option) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    let value = match option.to_ascii_lowercase().as_str() {
+        "deny" => Some("DENY".to_string()),
+        "sameorigin" => Some("SAMEORIGIN".to_string()),
+        "off" => None,
+        _ => return Err(ctx.invalid_value(&option, "expected deny, sameorigin or off")),
+    };
+    if let Ok(mut config) = ctx.block_config::<SecurityHeadersConfig>().lock() {
+        config.frame_options = Some(value);
+    }
+    Ok(())
+}
+
+pub fn handle_referrer_policy(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(policy) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    const POLICIES: [&str; 8] = [
+        "no-referrer",
+        "no-referrer-when-downgrade",
+        "origin",
+        "origin-when-cross-origin",
+        "same-origin",
+        "strict-origin",
+        "strict-origin-when-cross-origin",
+        "unsafe-url",
+    ];
+    if policy != "off" && !POLICIES.contains(&policy.as_str()) {
+        return Err(ctx.invalid_value(&policy, "unknown referrer policy"));
+    }
+    if let Ok(mut config) = ctx.block_config::<SecurityHeadersConfig>().lock() {
+        config.referrer_policy = Some((policy != "off").then_some(policy));
+    }
+    Ok(())
+}
+
+pub fn handle_content_security_policy(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let csp = match args.as_slice() {
+        [] => return Ok(()),
+        [off] if off == "off" => None,
+        [policy] => Some(Csp {
+            policy: policy.clone(),
+            report_only: false,
+        }),
+        [policy, flag] if flag == "report_only" => Some(Csp {
+            policy: policy.clone(),
+            report_only: true,
+        }),
+        [_, flag, ..] => return Err(ctx.invalid_value(flag, "expected report_only")),
+    };
+    if let Ok(mut config) = ctx.block_config::<SecurityHeadersConfig>().lock() {
+        config.csp = Some(csp);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_adds_missing_headers() {
+        let filter = SecurityHeadersFilter {
+            nosniff: true,
+            hsts: Some("max-age=31536000; includeSubDomains".to_string()),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("no-referrer".to_string()),
+            csp: Some(Csp {
+                policy: "default-src 'self';".to_string(),
+                report_only: false,
+            }),
+        };
+        let mut req = HttpRequest::new();
+        req.parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        let mut resp = HttpResponse::new();
+        resp.set_header("Referrer-Policy", "origin");
+        filter.filter(&req, &mut resp);
+
+        assert_eq!(resp.header_value("Strict-Transport-Security"), None);
+        assert_eq!(resp.header_value("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(resp.header_value("X-Frame-Options"), Some("DENY"));
+        assert_eq!(resp.header_value("Referrer-Policy"), Some("origin"));
+        assert_eq!(
+            resp.header_value("Content-Security-Policy"),
+            Some("default-src 'self'; frame-ancestors 'none'")
+        );
+
+        req.set_connection(None, None, true);
+        let mut resp = HttpResponse::new();
+        filter.filter(&req, &mut resp);
+        assert_eq!(
+            resp.header_value("Strict-Transport-Security"),
+            Some("max-age=31536000; includeSubDomains")
+        );
+    }
+}
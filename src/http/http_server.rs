@@ -1,17 +1,18 @@
-use http::{Method, StatusCode, Version};
+use http::{StatusCode, Version};
 use rustls::pki_types::pem::PemObject;
 use serde_json::Value;
 use std::{
     env,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{Cursor, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
     path::PathBuf,
     sync::{
-        atomic::{AtomicBool, AtomicPtr, Ordering},
+        atomic::{AtomicBool, Ordering},
         Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use rustls::{
@@ -22,15 +23,70 @@ use rustls::{
 use crate::{
     core::{
         config::{
-            command::{CommandBuilder, ParameterBuilder},
-            config_context::ConfigContext,
-            config_manager::{bool_str_to_bool, get_config_param},
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext},
+            config_loader::ConfigError,
+        },
+        processor::{
+            HttpHandler, HttpProcessor, PhaseResult, ProcessorSlot, RequestPhase, ResponseFilter,
         },
-        processor::{HttpProcessor, Processor},
     },
     events::thread_pool::THREAD_POOL,
-    http::{http_ssl::HttpSSL, web_config},
-    register_commands,
+    http::{
+        http_access::access_phase,
+        http_addition::addition_filter,
+        http_auth_basic::auth_basic_phase,
+        http_auth_jwt::auth_jwt_phase,
+        http_auth_request::auth_request_phase,
+        http_ban::{ban_api_handler, ban_filter, ban_phase},
+        http_body::{body_channel, chunked_body_channel},
+        http_bot_filter::bot_filter_phase,
+        http_cgi::cgi_handler,
+        http_compression::compression_filter,
+        http_core::HttpCoreConfig,
+        http_cors::{cors_filter, cors_phase},
+        http_dav::dav_handler,
+        http_error_page::ErrorPages,
+        http_fastcgi::fastcgi_handler,
+        http_gunzip::gunzip_phase,
+        http_headers::headers_filter,
+        http_health::health_handler,
+        http_internal::internal_phase,
+        http_keyval::keyval_api_handler,
+        http_limit_conn::{limit_conn_phase, ConnectionSlots},
+        http_limit_rate::{limit_rate_filter, Throttled},
+        http_limit_req::{limit_req_headers, limit_req_phase},
+        http_log::{access_log_filter, SentResponse},
+        http_mirror::mirror_phase,
+        http_oidc::oidc_phase,
+        http_otel::{find_tracer, Tracer},
+        http_proxy::proxy_handler,
+        http_real_ip::real_ip_phase,
+        http_redirect::redirect_filter,
+        http_referer::{referer_phase, RefererConfig},
+        http_request::{generate_request_id, HttpRequest, RequestId},
+        http_response::{FileBody, HttpResponse, UpgradedConnection},
+        http_rewrite::RewriteScript,
+        http_scgi::scgi_handler,
+        http_secure_link::{secure_link_phase, SecureLinkConfig},
+        http_security_headers::security_headers_filter,
+        http_slow_log::{slow_log, PhaseTimes, SlowLog},
+        http_ssi::ssi_filter,
+        http_ssl::{HttpSSL, HttpSSLContext},
+        http_static::static_handler,
+        http_statsd::{statsd, Statsd},
+        http_status::{
+            count_request, status_handler, ActiveConnection, Activity, ConnectionActivity,
+        },
+        http_sub_filter::sub_filter,
+        http_upstream_api::upstream_api_handler,
+        http_userid::{userid_filter, userid_phase},
+        http_uwsgi::uwsgi_handler,
+        http_variables::VariableRegistry,
+        http_waf::waf_phase,
+        web_config,
+    },
+    log_crit, log_debug, log_error, log_info, log_notice, register_commands,
 };
 
 use super::{http_location::HttpLocationContext, web_config::WebConfig};
@@ -60,6 +116,7 @@ register_commands!(
             .display_name("en", "Address")
             .display_name("zh-tw", "監聽位址")
             .type_name("String")
+            .arg_type(ArgType::Address)
             .is_required(true)
             .default("")
             .desc(
@@ -106,59 +163,45 @@ register_commands!(
         .build(handle_web_config)
 );
 
-fn clone_arc_from_atomic_ptr<T>(atomic_ptr: &AtomicPtr<u8>) -> Option<Arc<T>> {
-    let raw = atomic_ptr.load(Ordering::SeqCst) as *const T;
-    if raw.is_null() {
-        None
-    } else {
-        unsafe {
-            Arc::increment_strong_count(raw);
-            Some(Arc::from_raw(raw))
-        }
-    }
-}
-
-pub fn handle_create_server(ctx: &mut ConfigContext, _config: &Value) {
-    let server_ctx = Arc::new(HttpServerContext::new());
-    let raw_ptr = Arc::into_raw(server_ctx.clone()) as *mut u8;
-    ctx.current_ctx = Some(AtomicPtr::new(raw_ptr));
-    ctx.current_block_type_id = Some(std::any::TypeId::of::<HttpServerContext>());
+pub fn handle_create_server(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    ctx.store.insert(Arc::new(HttpServerContext::new()));
+    Ok(())
 }
 
-pub fn handle_set_listen(ctx: &mut ConfigContext, config: &Value) {
-    let listen = get_config_param(config, 0).expect("Missing listen parameter");
-    if let Some(ctx_ptr) = &ctx.current_ctx {
-        if let Some(server_ctx) = clone_arc_from_atomic_ptr::<HttpServerContext>(ctx_ptr) {
-            server_ctx.set_listen(&listen);
-        }
+pub fn handle_set_listen(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let listen = ctx.str_arg(0)?;
+    if let Some(server_ctx) = ctx.store.get::<HttpServerContext>() {
+        server_ctx.set_listen(&listen);
     }
+    Ok(())
 }
 
-pub fn handle_set_server_name(ctx: &mut ConfigContext, config: &Value) {
-    let server_name = get_config_param(config, 0).expect("Missing server_name parameter");
-    if let Some(ctx_ptr) = &ctx.current_ctx {
-        if let Some(server_ctx) = clone_arc_from_atomic_ptr::<HttpServerContext>(ctx_ptr) {
-            server_ctx.add_server_name(&server_name);
-        }
+pub fn handle_set_server_name(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let server_name = ctx.str_arg(0)?;
+    if let Some(server_ctx) = ctx.store.get::<HttpServerContext>() {
+        server_ctx.add_server_name(&server_name);
     }
+    Ok(())
 }
 
-pub fn handle_web_config(ctx: &mut ConfigContext, config: &Value) {
-    let flag = get_config_param(config, 0).expect("Missing web_config parameter");
-    if !bool_str_to_bool(&flag).expect("Invalid web_config value") {
-        return;
+pub fn handle_web_config(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if !ctx.bool_arg(0)? {
+        return Ok(());
     }
-    if let Some(ctx_ptr) = &ctx.current_ctx {
-        if let Some(server_ctx) = clone_arc_from_atomic_ptr::<HttpServerContext>(ctx_ptr) {
-            let storage_path = get_default_storage_path();
-            let web_config = WebConfig::new(&storage_path).expect("Failed to create web config");
-            if let Ok(mut web_config_lock) = server_ctx.web_config.lock() {
-                *web_config_lock = Some(Arc::new(web_config));
-            }
+    if let Some(server_ctx) = ctx.store.get::<HttpServerContext>() {
+        let storage_path = get_default_storage_path();
+        let web_config =
+            WebConfig::new(&storage_path).map_err(|e| ctx.invalid_value("on", e.to_string()))?;
+        if let Ok(mut web_config_lock) = server_ctx.web_config.lock() {
+            *web_config_lock = Some(Arc::new(web_config));
         }
     }
+    Ok(())
 }
 
+/// The header `request_id on` carries `$request_id` in.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
 #[derive(Default)]
 pub struct HttpServerContext {
     listen: Mutex<String>,
@@ -195,6 +238,10 @@ impl HttpServerContext {
         }
     }
 
+    pub fn server_names(&self) -> Vec<String> {
+        self.server_names.lock().unwrap().clone()
+    }
+
     pub fn get_http_version(&self) -> Version {
         *self.http_version.lock().unwrap()
     }
@@ -202,86 +249,123 @@ impl HttpServerContext {
 
 pub struct HttpServer {
     listener: TcpListener,
-    http_version: Arc<Version>,
     processor: Arc<HttpProcessor>,
     ssl: Option<Arc<ServerConfig>>,
+    conn_config: Arc<ConnectionConfig>,
     running: Arc<AtomicBool>,
 }
 
 impl HttpServer {
-    pub fn new(server_config: &ConfigContext) -> Self {
-        let server_ctx_arc: Arc<HttpServerContext> = if let Some(ptr) = &server_config.current_ctx {
-            let raw = ptr.load(Ordering::SeqCst);
-            unsafe {
-                Arc::increment_strong_count(raw as *const HttpServerContext);
-                Arc::from_raw(raw as *const HttpServerContext)
-            }
-        } else {
-            panic!("Server block missing HttpServerContext");
-        };
-
-        let server_ctx = server_ctx_arc.clone();
-        std::mem::forget(server_ctx_arc);
+    pub fn new(http_config: &ConfigContext, server_config: &ConfigContext) -> Self {
+        let server_ctx = server_config
+            .store
+            .get::<HttpServerContext>()
+            .expect("Server block missing HttpServerContext");
 
         let listen = server_ctx.listen();
-        println!("Listening on: {}", listen);
+        let server_names = server_ctx.server_names();
+        log_notice!("Listening on: {}", listen);
 
         let mut ssl_config: Option<Arc<ServerConfig>> = None;
+        let processor_slot = ProcessorSlot::default();
 
         for child in &server_config.children {
             match child.block_name.trim() {
                 "location" => {
-                    let path = child
-                        .block_args
-                        .first()
-                        .expect("location block must have a path")
-                        .clone();
-                    if let Some(ptr) = &child.current_ctx {
-                        if let Some(loc_ctx) = clone_arc_from_atomic_ptr::<HttpLocationContext>(ptr)
-                        {
-                            let handlers = loc_ctx.take_handlers();
-                            for (code, handler) in handlers {
-                                if let Ok(mut proc_lock) = server_ctx.processor.lock() {
-                                    proc_lock.add_handler(
-                                        path.clone(),
-                                        StatusCode::from_u16(code).unwrap(),
-                                        &Method::OPTIONS,
-                                        handler,
-                                    );
-                                }
-                            }
+                    if let Some(loc_ctx) = child.store.get::<HttpLocationContext>() {
+                        let mut handlers = loc_ctx.take_handlers();
+                        let chain = [http_config, server_config, child];
+                        let handler = handlers
+                            .remove(&StatusCode::OK.as_u16())
+                            .or_else(|| proxy_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| fastcgi_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| uwsgi_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| scgi_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| cgi_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| health_handler(&chain))
+                            .or_else(|| status_handler(child))
+                            .or_else(|| upstream_api_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| keyval_api_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| ban_api_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| dav_handler(&chain, Some(&loc_ctx.pattern)))
+                            .or_else(|| static_handler(&chain, Some(&loc_ctx.pattern)));
+                        let mut phases = block_phases(&chain, &server_names);
+                        if let Some(internal) = internal_phase(child) {
+                            phases.insert(0, Arc::new(internal));
                         }
-                    }
-                }
-                "ssl" => {
-                    if child.current_ctx.is_some() {
-                        if let Ok(http_ssl) = HttpSSL::from_config(child) {
-                            let pem_key = http_ssl
-                                .cert_key
-                                .pri_key
-                                .private_key_to_pem_pkcs8()
-                                .unwrap();
-                            let pri_key = PrivateKeyDer::Pkcs8(
-                                PrivatePkcs8KeyDer::from_pem_slice(&pem_key).expect("Invalid key"),
+                        phases.extend(access_phases(&chain, &processor_slot));
+                        if let Some(mirror) = mirror_phase(&chain, &processor_slot) {
+                            phases.push(Arc::new(mirror));
+                        }
+                        let error_pages =
+                            merged_config::<ErrorPages>(&[http_config, server_config, child]);
+                        let filters = block_filters(
+                            &[http_config, server_config, child],
+                            &server_names,
+                            &processor_slot,
+                        );
+                        if let Ok(mut proc_lock) = server_ctx.processor.lock() {
+                            proc_lock.add_location_with_phases(
+                                loc_ctx.pattern.clone(),
+                                handler,
+                                phases,
+                                Arc::new(error_pages),
+                                filters,
                             );
-                            let pem_cert = http_ssl.cert.cert.to_pem().unwrap();
-                            let cert = CertificateDer::from_pem_slice(&pem_cert).unwrap();
-
-                            ssl_config = Some(Arc::new(
-                                ServerConfig::builder()
-                                    .with_no_client_auth()
-                                    .with_single_cert(vec![cert], pri_key)
-                                    .unwrap(),
-                            ));
-                        } else {
-                            eprintln!("Failed to create SSL config");
                         }
                     }
                 }
+                "ssl" if child.store.contains::<Mutex<HttpSSLContext>>() => {
+                    if let Ok(http_ssl) = HttpSSL::from_config(child) {
+                        let pem_key = http_ssl
+                            .cert_key
+                            .pri_key
+                            .private_key_to_pem_pkcs8()
+                            .unwrap();
+                        let pri_key = PrivateKeyDer::Pkcs8(
+                            PrivatePkcs8KeyDer::from_pem_slice(&pem_key).expect("Invalid key"),
+                        );
+                        let pem_cert = http_ssl.cert.cert.to_pem().unwrap();
+                        let cert = CertificateDer::from_pem_slice(&pem_cert).unwrap();
+
+                        ssl_config = Some(Arc::new(
+                            ServerConfig::builder()
+                                .with_no_client_auth()
+                                .with_single_cert(vec![cert], pri_key)
+                                .unwrap(),
+                        ));
+                    } else {
+                        log_error!("Failed to create SSL config");
+                    }
+                }
                 _ => {}
             }
         }
 
+        if let Ok(mut proc_lock) = server_ctx.processor.lock() {
+            if let Some(phase) = gunzip_phase(&[http_config, server_config]) {
+                proc_lock.add_server_phase(Arc::new(phase));
+            }
+            for phase in block_phases(&[http_config, server_config], &server_names) {
+                proc_lock.add_server_phase(phase);
+            }
+            if let Some(handler) = dav_handler(&[http_config, server_config], None)
+                .or_else(|| static_handler(&[http_config, server_config], None))
+            {
+                let access = access_phases(&[http_config, server_config], &processor_slot);
+                proc_lock.set_default_handler(guard_handler(access, handler));
+            }
+            proc_lock.set_error_pages(Arc::new(merged_config::<ErrorPages>(&[
+                http_config,
+                server_config,
+            ])));
+            proc_lock.set_filters(block_filters(
+                &[http_config, server_config],
+                &server_names,
+                &processor_slot,
+            ));
+        }
+
         if let Some(web_config) = server_ctx.web_config.lock().unwrap().as_ref() {
             let web_config = Arc::clone(web_config);
             if let Ok(proc_lock) = server_ctx.processor.lock() {
@@ -291,28 +375,68 @@ impl HttpServer {
 
         let processor = {
             let mut proc_lock = server_ctx.processor.lock().unwrap();
-            std::mem::replace(&mut *proc_lock, HttpProcessor::new())
+            Arc::new(std::mem::replace(&mut *proc_lock, HttpProcessor::new()))
         };
+        let _ = processor_slot.set(Arc::downgrade(&processor));
 
         let listener = TcpListener::bind(&listen).unwrap();
-        let http_version = Arc::new(server_ctx.get_http_version());
+        let conn_config = ConnectionConfig {
+            http_version: server_ctx.get_http_version(),
+            core: merged_config::<HttpCoreConfig>(&[http_config, server_config]),
+            variables: Arc::new(VariableRegistry::from_config(http_config)),
+            tracer: find_tracer(http_config),
+            slow_log: slow_log(&[http_config, server_config]),
+            statsd: statsd(http_config),
+        };
 
         Self {
             listener,
-            http_version,
-            processor: Arc::new(processor),
+            processor,
             ssl: ssl_config,
+            conn_config: Arc::new(conn_config),
             running: Arc::new(AtomicBool::new(true)),
         }
     }
 
+    /// Serves `processor` on `listen` without a configuration file, with
+    /// the `core` settings and, if given, TLS.
+    pub fn from_processor(
+        listen: &str,
+        processor: HttpProcessor,
+        ssl: Option<Arc<ServerConfig>>,
+        core: HttpCoreConfig,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(listen)?;
+        let conn_config = ConnectionConfig {
+            http_version: Version::default(),
+            core,
+            variables: Arc::new(VariableRegistry::new()),
+            tracer: None,
+            slow_log: None,
+            statsd: None,
+        };
+        Ok(Self {
+            listener,
+            processor: Arc::new(processor),
+            ssl,
+            conn_config: Arc::new(conn_config),
+            running: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    /// The address the server listens on, with the port the system picked
+    /// when it was asked to listen on port 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
     pub fn start(self) -> thread::JoinHandle<()> {
-        println!("Server started");
+        log_notice!("Server started");
         let running_flag = self.running.clone();
         let listener = self.listener;
-        let http_version = self.http_version.clone();
         let processor = self.processor.clone();
         let ssl_config = self.ssl.clone();
+        let conn_config = self.conn_config.clone();
 
         thread::spawn(move || {
             listener
@@ -320,19 +444,19 @@ impl HttpServer {
                 .expect("Failed to set non-blocking");
 
             if processor.is_empty() {
-                eprintln!("No routes configured for server");
+                log_error!("No routes configured for server");
                 return;
             }
 
             while running_flag.load(Ordering::SeqCst) {
                 match listener.incoming().next() {
                     Some(Ok(stream)) => {
-                        println!("Connection from: {}", stream.peer_addr().unwrap());
+                        log_debug!("Connection from: {}", stream.peer_addr().unwrap());
                         process_connection(
                             stream,
                             processor.clone(),
-                            http_version.clone(),
                             ssl_config.clone(),
+                            conn_config.clone(),
                         );
                     }
                     Some(Err(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
@@ -340,87 +464,736 @@ impl HttpServer {
                         continue;
                     }
                     Some(Err(e)) => {
-                        eprintln!("Connection failed: {}", e);
+                        log_error!("Connection failed: {}", e);
                     }
                     None => break,
                 }
             }
-            println!("Server stopped accepting connections.");
+            log_notice!("Server stopped accepting connections.");
         })
     }
 
     pub fn stop(&self) {
         self.running.store(false, Ordering::SeqCst);
-        println!("Server stop requested");
+        log_notice!("Server stop requested");
+    }
+}
+
+/// Collects the request phases of a `server` block, or those a `location`
+/// adds to the server's. The server's run before the location is selected,
+/// so a location only gets the ones its server lacks or that it configures
+/// itself, and none runs twice with the same settings.
+fn block_phases(chain: &[&ConfigContext], server_names: &[String]) -> Vec<Arc<dyn RequestPhase>> {
+    let server = &chain[..chain.len().min(2)];
+    let location = chain.get(2);
+    let mut phases: Vec<Arc<dyn RequestPhase>> = Vec::new();
+    // The address is replaced and looked up once, by the first block that
+    // does it.
+    if location.is_none() || real_ip_phase(server).is_none() {
+        if let Some(real_ip) = real_ip_phase(chain) {
+            phases.push(Arc::new(real_ip));
+        }
+    }
+    #[cfg(feature = "geoip")]
+    if location.is_none() || super::http_geoip::geoip_phase(server).is_none() {
+        if let Some(geoip) = super::http_geoip::geoip_phase(chain) {
+            phases.push(Arc::new(geoip));
+        }
+    }
+    if location.is_none_or(|block| block.store.contains::<Mutex<RefererConfig>>()) {
+        if let Some(referer) = referer_phase(chain, server_names) {
+            phases.push(Arc::new(referer));
+        }
+    }
+    if location.is_none_or(|block| block.store.contains::<Mutex<SecureLinkConfig>>()) {
+        if let Some(secure_link) = secure_link_phase(chain) {
+            phases.push(Arc::new(secure_link));
+        }
+    }
+    let script = RewriteScript::from_block(chain[chain.len() - 1]);
+    if !script.is_empty() {
+        phases.push(Arc::new(script));
+    }
+    phases
+}
+
+/// Collects the phases that decide whether a client may access a block chain,
+/// checked after its rewrite rules.
+fn access_phases(
+    chain: &[&ConfigContext],
+    processor: &ProcessorSlot,
+) -> Vec<Arc<dyn RequestPhase>> {
+    let mut phases: Vec<Arc<dyn RequestPhase>> = Vec::new();
+    if let Some(userid) = userid_phase(chain) {
+        phases.push(Arc::new(userid));
+    }
+    if let Some(ban) = ban_phase(chain) {
+        phases.push(Arc::new(ban));
+    }
+    if let Some(bot_filter) = bot_filter_phase(chain) {
+        phases.push(Arc::new(bot_filter));
+    }
+    if let Some(limit_req) = limit_req_phase(chain) {
+        phases.push(Arc::new(limit_req));
+    }
+    if let Some(limit_conn) = limit_conn_phase(chain) {
+        phases.push(Arc::new(limit_conn));
+    }
+    if let Some(waf) = waf_phase(chain) {
+        phases.push(Arc::new(waf));
+    }
+    if let Some(cors) = cors_phase(chain) {
+        phases.push(Arc::new(cors));
+    }
+    if let Some(access) = access_phase(chain) {
+        phases.push(Arc::new(access));
+    }
+    if let Some(auth_basic) = auth_basic_phase(chain) {
+        phases.push(Arc::new(auth_basic));
+    }
+    if let Some(auth_jwt) = auth_jwt_phase(chain) {
+        phases.push(Arc::new(auth_jwt));
+    }
+    if let Some(auth_request) = auth_request_phase(chain, processor) {
+        phases.push(Arc::new(auth_request));
+    }
+    if let Some(oidc) = oidc_phase(chain) {
+        phases.push(Arc::new(oidc));
+    }
+    phases
+}
+
+/// Wraps a handler that serves requests matching no location so the server's
+/// access phases apply to them too.
+fn guard_handler(phases: Vec<Arc<dyn RequestPhase>>, handler: HttpHandler) -> HttpHandler {
+    if phases.is_empty() {
+        return handler;
+    }
+    Box::new(move |req: &HttpRequest| {
+        let mut checked = req.clone();
+        for phase in &phases {
+            if let PhaseResult::Respond(resp) = phase.run(&mut checked) {
+                return *resp;
+            }
+        }
+        handler(&checked)
+    })
+}
+
+/// Collects the response filters in effect for a block chain.
+fn block_filters(
+    chain: &[&ConfigContext],
+    server_names: &[String],
+    processor: &ProcessorSlot,
+) -> Vec<Arc<dyn ResponseFilter>> {
+    let mut filters: Vec<Arc<dyn ResponseFilter>> = Vec::new();
+    if let Some(addition) = addition_filter(chain, processor) {
+        filters.push(Arc::new(addition));
+    }
+    if let Some(sub_filter) = sub_filter(chain) {
+        filters.push(Arc::new(sub_filter));
+    }
+    if let Some(ssi) = ssi_filter(chain, processor) {
+        filters.push(Arc::new(ssi));
+    }
+    if let Some(compression) = compression_filter(chain) {
+        filters.push(Arc::new(compression));
+    }
+    if let Some(limit_req) = limit_req_headers(chain) {
+        filters.push(Arc::new(limit_req));
+    }
+    if let Some(limit_rate) = limit_rate_filter(chain) {
+        filters.push(Arc::new(limit_rate));
+    }
+    if let Some(redirect) = redirect_filter(chain, server_names) {
+        filters.push(Arc::new(redirect));
+    }
+    if let Some(security_headers) = security_headers_filter(chain) {
+        filters.push(Arc::new(security_headers));
+    }
+    if let Some(userid) = userid_filter(chain) {
+        filters.push(Arc::new(userid));
+    }
+    if let Some(headers) = headers_filter(chain) {
+        filters.push(Arc::new(headers));
+    }
+    if let Some(cors) = cors_filter(chain) {
+        filters.push(Arc::new(cors));
+    }
+    if let Some(ban) = ban_filter(chain) {
+        filters.push(Arc::new(ban));
+    }
+    if let Some(access_log) = access_log_filter(chain) {
+        filters.push(Arc::new(access_log));
+    }
+    filters
+}
+
+struct ConnectionConfig {
+    http_version: Version,
+    core: HttpCoreConfig,
+    variables: Arc<VariableRegistry>,
+    tracer: Option<Arc<Tracer>>,
+    slow_log: Option<Arc<SlowLog>>,
+    statsd: Option<Arc<Statsd>>,
+}
+
+struct ConnectionInfo {
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    secure: bool,
+}
+
+impl ConnectionInfo {
+    fn from_stream(stream: &TcpStream, secure: bool) -> Self {
+        Self {
+            remote_addr: stream.peer_addr().ok(),
+            local_addr: stream.local_addr().ok(),
+            secure,
+        }
     }
 }
 
 fn process_connection(
     stream: TcpStream,
     processor: Arc<HttpProcessor>,
-    http_version: Arc<Version>,
     ssl_config: Option<Arc<ServerConfig>>,
+    conn_config: Arc<ConnectionConfig>,
 ) {
+    let connection = ActiveConnection::accept();
     if let Ok(pool) = THREAD_POOL.lock() {
         let _ = pool.spawn(move || {
+            connection.handle();
             let result = if let Some(ssl_cfg) = ssl_config {
-                process_tls_connection(stream, ssl_cfg, &processor, &http_version)
+                process_tls_connection(stream, ssl_cfg, &processor, &conn_config)
             } else {
-                process_plain_connection(stream, &processor, &http_version)
+                process_plain_connection(stream, &processor, &conn_config)
             };
             if let Err(e) = result {
-                eprintln!("Error handling connection: {}", e);
+                log_info!("Error handling connection: {}", e);
             }
         });
     } else {
-        eprintln!("Thread pool error");
+        log_crit!("Thread pool error");
     }
 }
 
 fn process_plain_connection(
     mut stream: TcpStream,
     processor: &HttpProcessor,
-    http_version: &Version,
+    conn_config: &ConnectionConfig,
 ) -> std::io::Result<()> {
-    handle_connection(&mut stream, processor, http_version)
+    prepare_stream(&stream, &conn_config.core)?;
+    let info = ConnectionInfo::from_stream(&stream, false);
+    handle_connection(&mut stream, processor, conn_config, &info)
 }
 
 fn process_tls_connection(
     mut stream: TcpStream,
     ssl_cfg: Arc<ServerConfig>,
     processor: &HttpProcessor,
-    http_version: &Version,
+    conn_config: &ConnectionConfig,
 ) -> std::io::Result<()> {
-    let mut conn = ServerConnection::new(ssl_cfg)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    prepare_stream(&stream, &conn_config.core)?;
+    let info = ConnectionInfo::from_stream(&stream, true);
+    let mut conn = ServerConnection::new(ssl_cfg).map_err(std::io::Error::other)?;
     let mut tls_stream = rustls::Stream::new(&mut conn, &mut stream);
 
     tls_stream.flush()?;
-    handle_connection(&mut tls_stream, processor, http_version)
+    handle_connection(&mut tls_stream, processor, conn_config, &info)
 }
 
-fn handle_connection<S: Read + Write>(
-    stream: &mut S,
-    processor: &HttpProcessor,
-    http_version: &Version,
+fn prepare_stream(stream: &TcpStream, core: &HttpCoreConfig) -> std::io::Result<()> {
+    // The listener is non-blocking; accepted sockets must block so reads honour the timeout.
+    stream.set_nonblocking(false)?;
+    let timeout = core.keepalive_timeout();
+    stream.set_read_timeout((!timeout.is_zero()).then_some(timeout))
+}
+
+/// A stream that can write a file region of a response body.
+trait SendFile: Write {
+    fn send_file(&mut self, body: &FileBody) -> std::io::Result<()> {
+        body.copy_to(self)
+    }
+}
+
+/// TLS records are encrypted in userspace, so the file is copied as usual.
+impl SendFile for rustls::Stream<'_, ServerConnection, TcpStream> {}
+
+#[cfg(not(target_os = "linux"))]
+impl SendFile for TcpStream {}
+
+#[cfg(target_os = "linux")]
+impl SendFile for TcpStream {
+    fn send_file(&mut self, body: &FileBody) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let mut offset = body.offset as libc::off_t;
+        let end = body.offset + body.len;
+        while (offset as u64) < end {
+            let mut count = (end - offset as u64) as usize;
+            if body.max_chunk > 0 {
+                count = count.min(body.max_chunk as usize);
+            }
+            // SAFETY: both descriptors stay open for the duration of the call
+            // and `offset` is a valid pointer that sendfile updates in place.
+            let sent = unsafe {
+                libc::sendfile(self.as_raw_fd(), body.file.as_raw_fd(), &mut offset, count)
+            };
+            match sent {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n if n < 0 => {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the bytes written through it, for the access log.
+struct Counted<'a, S> {
+    inner: &'a mut S,
+    bytes: u64,
+}
+
+impl<S: Write> Write for Counted<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: SendFile> SendFile for Counted<'_, S> {
+    fn send_file(&mut self, body: &FileBody) -> std::io::Result<()> {
+        self.inner.send_file(body)?;
+        self.bytes += body.len;
+        Ok(())
+    }
+}
+
+/// Paced file regions go through `write` so every byte is counted against
+/// the rate.
+impl<S: SendFile> SendFile for Throttled<'_, S> {}
+
+/// A client stream whose socket can be waited on directly, so that an
+/// upgraded connection can be tunneled.
+trait ClientSocket {
+    fn socket(&self) -> Option<&TcpStream>;
+}
+
+impl ClientSocket for TcpStream {
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl ClientSocket for rustls::Stream<'_, ServerConnection, TcpStream> {
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(&*self.sock)
+    }
+}
+
+/// Relays bytes between the client and an upgraded upstream connection
+/// until either side closes or neither sends anything for the idle timeout.
+/// `pending` holds client bytes read past the request.
+fn tunnel<S: Read + Write + ClientSocket>(
+    client: &mut S,
+    upgrade: &UpgradedConnection,
+    pending: &[u8],
 ) -> std::io::Result<()> {
-    let mut buffer = [0; 1024];
-    let n = stream.read(&mut buffer)?;
-    if n == 0 {
+    let Some((mut upstream, buffered)) = upgrade.take() else {
         return Ok(());
+    };
+    let Some(socket) = client.socket().map(TcpStream::try_clone).transpose()? else {
+        return Ok(());
+    };
+    upstream.write_all(pending)?;
+    upstream.flush()?;
+    client.write_all(&buffered)?;
+    client.flush()?;
+
+    socket.set_nonblocking(true)?;
+    upstream.socket().set_nonblocking(true)?;
+    let mut buf = [0; 16 * 1024];
+    let mut last_active = Instant::now();
+    loop {
+        let mut active = false;
+        if let Some(n) = read_available(client, &mut buf)? {
+            upstream.socket().set_nonblocking(false)?;
+            upstream.write_all(&buf[..n])?;
+            upstream.flush()?;
+            upstream.socket().set_nonblocking(true)?;
+            active = true;
+        }
+        if let Some(n) = read_available(&mut upstream, &mut buf)? {
+            socket.set_nonblocking(false)?;
+            client.write_all(&buf[..n])?;
+            client.flush()?;
+            socket.set_nonblocking(true)?;
+            active = true;
+        }
+        if active {
+            last_active = Instant::now();
+            continue;
+        }
+        match upgrade.idle_timeout.checked_sub(last_active.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => {
+                wait_readable(&[&socket, upstream.socket()], remaining)?
+            }
+            _ => return Ok(()),
+        }
     }
-    let request_bytes = buffer[..n].to_vec();
+}
 
-    let response_bytes = match processor.process(request_bytes) {
-        Ok(resp) => resp,
-        Err(_) => HttpProcessor::create_404_response(http_version).as_bytes(),
-    };
+/// Reads what a non-blocking stream has available: `None` when nothing is,
+/// and an `UnexpectedEof` error once the peer has closed.
+fn read_available<R: Read>(stream: &mut R, buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+    match stream.read(buf) {
+        Ok(0) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        Ok(n) => Ok(Some(n)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Blocks until one of the sockets is readable or `timeout` passes.
+fn wait_readable(sockets: &[&TcpStream], timeout: Duration) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
 
-    stream.write_all(&response_bytes)?;
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|socket| libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+    // SAFETY: `fds` points to `fds.len()` initialized pollfd entries that
+    // outlive the call.
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    if ready < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn write_response<S: SendFile>(stream: &mut S, resp: &HttpResponse) -> std::io::Result<()> {
+    stream.write_all(&resp.head_bytes())?;
+    stream.write_all(&resp.body)?;
     stream.flush()?;
+    if let Some(file) = &resp.file {
+        stream.send_file(file)?;
+    }
+    if let Some(body) = &resp.stream {
+        body.copy_framed(stream, resp.is_chunked().then_some(resp.trailer.as_str()))?;
+    }
     Ok(())
 }
 
+/// Writes the response and then its access log lines, even when the client
+/// went away part way through.
+fn send_response<S: SendFile>(
+    stream: &mut S,
+    req: &HttpRequest,
+    resp: &HttpResponse,
+    started: Instant,
+) -> std::io::Result<()> {
+    let mut counted = Counted {
+        inner: stream,
+        bytes: 0,
+    };
+    let result = match resp.limit_rate {
+        Some(limit) => write_response(&mut Throttled::new(&mut counted, limit), resp),
+        None => write_response(&mut counted, resp),
+    };
+    if let Some(logs) = &resp.access_log {
+        let head = resp.head_bytes().len() as u64;
+        let sent = SentResponse {
+            request_time: started.elapsed(),
+            bytes: counted.bytes,
+            body_bytes: counted.bytes.saturating_sub(head),
+        };
+        logs.write(req, resp, &sent);
+    }
+    result
+}
+
+fn handle_connection<S: Read + SendFile + ClientSocket + Send>(
+    stream: &mut S,
+    processor: &HttpProcessor,
+    conn_config: &ConnectionConfig,
+    info: &ConnectionInfo,
+) -> std::io::Result<()> {
+    let http_version = &conn_config.http_version;
+    let max_body_size = conn_config.core.client_max_body_size();
+    let keepalive_enabled = !conn_config.core.keepalive_timeout().is_zero();
+    let mut pending = Vec::new();
+    let mut buffer = [0; 8192];
+    let mut activity = ConnectionActivity::new();
+    let slots = Arc::new(ConnectionSlots::default());
+    let header_limits = conn_config.core.header_limits();
+
+    loop {
+        activity.set(Activity::Waiting);
+        let mut req = HttpRequest::new();
+        req.set_connection(info.remote_addr, info.local_addr, info.secure);
+        req.set_variables(conn_config.variables.clone());
+        req.extensions_mut().insert(Arc::clone(&slots));
+        req.set_header_limits(header_limits);
+        req.set_strictness(conn_config.core.request_strictness());
+        req.set_merge_slashes(conn_config.core.merge_slashes());
+        let mut input = std::mem::take(&mut pending);
+        let mut started = (!input.is_empty()).then(Instant::now);
+        if started.is_some() {
+            activity.set(Activity::Reading);
+        }
+        let mut too_large = false;
+        let mut streamed = None;
+
+        loop {
+            let more = match req.parse(&input) {
+                Ok(more) => more,
+                Err(_) => {
+                    let status = req.rejected().unwrap_or(StatusCode::BAD_REQUEST);
+                    let resp = error_response(http_version, status, false);
+                    stream.write_all(&resp.as_bytes())?;
+                    return stream.flush();
+                }
+            };
+
+            if req.is_headers_complete()
+                && max_body_size > 0
+                && req.content_length().is_some_and(|len| len > max_body_size)
+            {
+                too_large = true;
+                break;
+            }
+            if req.is_headers_complete()
+                && *streamed.get_or_insert_with(|| processor.streams_body(&req))
+            {
+                break;
+            }
+            if req.is_chunked() {
+                // Only locations that stream bodies decode chunked ones.
+                let resp = error_response(http_version, StatusCode::LENGTH_REQUIRED, false);
+                stream.write_all(&resp.as_bytes())?;
+                return stream.flush();
+            }
+            if !more || req.is_complete() {
+                break;
+            }
+
+            let n = match stream.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if is_idle_close(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            started.get_or_insert_with(Instant::now);
+            activity.set(Activity::Reading);
+            input = buffer[..n].to_vec();
+        }
+
+        count_request();
+        activity.set(Activity::Writing);
+
+        let parsed = Instant::now();
+        let started = started.unwrap_or(parsed);
+        req.set_started(started);
+        let request_id = generate_request_id();
+        if conn_config.core.request_id() {
+            req.set_header(REQUEST_ID_HEADER, &request_id);
+        }
+        req.extensions_mut().insert(RequestId(request_id));
+
+        if too_large {
+            let mut resp = processor.error_response(&mut req, StatusCode::PAYLOAD_TOO_LARGE);
+            set_request_id_header(&mut resp, &req, &conn_config.core);
+            resp.set_header("Connection", "close");
+            return send_response(stream, &req, &resp, started);
+        }
+
+        let tracer = conn_config.tracer.as_ref();
+        let span = tracer.map(|tracer| tracer.start(&mut req, started));
+        let (resp, rest) = if streamed == Some(true) {
+            handle_streamed(stream, processor, &mut req, &conn_config.core)
+        } else {
+            (guarded_handle(processor, &mut req), Some(Vec::new()))
+        };
+        let (mut resp, rest) = match resp {
+            Some(resp) => (resp, rest),
+            // What the handler left behind is unknown, so the connection closes.
+            None => (
+                error_response(http_version, StatusCode::INTERNAL_SERVER_ERROR, false),
+                None,
+            ),
+        };
+        let handled = Instant::now();
+        set_request_id_header(&mut resp, &req, &conn_config.core);
+        // Body bytes the handler left unread are still on the connection.
+        let keep_alive =
+            keepalive_enabled && rest.is_some() && req.keep_alive() && !resp.is_close_delimited();
+        if !resp.has_header("Connection") {
+            resp.set_header(
+                "Connection",
+                if keep_alive { "keep-alive" } else { "close" },
+            );
+        }
+        let sent = send_response(stream, &req, &resp, started);
+        if let Some(slow_log) = &conn_config.slow_log {
+            let times = PhaseTimes {
+                parse: parsed - started,
+                handler: handled - parsed,
+                write: handled.elapsed(),
+            };
+            slow_log.write(&req, &resp, &times);
+        }
+        if let Some(statsd) = &conn_config.statsd {
+            statsd.record(&resp, started.elapsed());
+        }
+        if let (Some(tracer), Some(span)) = (tracer, span) {
+            let path = req.path().split('?').next().unwrap_or_default();
+            let route = processor.find_location(path).map(|loc| loc.path.as_str());
+            tracer.finish(span, &req, &resp, route);
+        }
+        sent?;
+
+        let mut rest = rest.unwrap_or_default();
+        rest.extend(req.take_remaining());
+        if let Some(upgrade) = &resp.upgrade {
+            return match tunnel(stream, upgrade, &rest) {
+                Err(e) if !is_idle_close(&e) => Err(e),
+                _ => Ok(()),
+            };
+        }
+        if !keep_alive {
+            return Ok(());
+        }
+        pending = rest;
+    }
+}
+
+/// Runs the phases, handler and filters for a request, or returns `None`
+/// when one of them panics.
+fn guarded_handle(processor: &HttpProcessor, req: &mut HttpRequest) -> Option<HttpResponse> {
+    match panic::catch_unwind(AssertUnwindSafe(|| processor.handle(req))) {
+        Ok(resp) => Some(resp),
+        Err(_) => {
+            log_error!(
+                "Request handling panicked, request: \"{} {}\"",
+                req.method(),
+                req.request_uri()
+            );
+            None
+        }
+    }
+}
+
+/// Handles a request whose location streams bodies, reading the body from
+/// the client on another thread as the handler asks for it. Returns the
+/// response, if handling did not panic, and, once the whole body was read,
+/// the bytes received past it.
+fn handle_streamed<S: Read + ClientSocket + Send>(
+    stream: &mut S,
+    processor: &HttpProcessor,
+    req: &mut HttpRequest,
+    core: &HttpCoreConfig,
+) -> (Option<HttpResponse>, Option<Vec<u8>>) {
+    // What arrived of a chunked body is still encoded, and may run into
+    // the next request.
+    let (encoded, (body, feed)) = if req.is_chunked() {
+        (
+            req.take_remaining(),
+            chunked_body_channel(core.client_max_body_size()),
+        )
+    } else {
+        let received = req.body().to_vec();
+        req.set_body(Vec::new());
+        let remaining = req
+            .content_length()
+            .unwrap_or(0)
+            .saturating_sub(received.len() as u64);
+        (Vec::new(), body_channel(received, remaining))
+    };
+    req.set_body_stream(body);
+
+    let set_timeout = |stream: &S, timeout: Duration| {
+        if let Some(socket) = stream.socket() {
+            let _ = socket.set_read_timeout((!timeout.is_zero()).then_some(timeout));
+        }
+    };
+    set_timeout(stream, core.client_body_timeout());
+    let mut source = Cursor::new(encoded).chain(&mut *stream);
+    let source_ref = &mut source;
+    let (resp, body_read) = thread::scope(|scope| {
+        let reader = scope.spawn(move || feed.feed(source_ref));
+        let resp = guarded_handle(processor, req);
+        // Dropping the stream lets the reader stop if the handler left it.
+        drop(req.take_body_stream());
+        (resp, reader.join().unwrap_or(false))
+    });
+    let (encoded, _) = source.into_inner();
+    set_timeout(stream, core.keepalive_timeout());
+    let rest = encoded.get_ref()[encoded.position() as usize..].to_vec();
+    (resp, body_read.then_some(rest))
+}
+
+/// Echoes the request ID to the client when `request_id` is on, unless
+/// the response already names one.
+fn set_request_id_header(resp: &mut HttpResponse, req: &HttpRequest, core: &HttpCoreConfig) {
+    if !core.request_id() || resp.has_header(REQUEST_ID_HEADER) {
+        return;
+    }
+    if let Some(RequestId(id)) = req.extensions().get() {
+        resp.set_header(REQUEST_ID_HEADER, id);
+    }
+}
+
+fn error_response(version: &Version, status: StatusCode, keep_alive: bool) -> HttpResponse {
+    let mut resp = HttpResponse::new();
+    resp.set_status_line(*version, status);
+    resp.set_header("Content-Type", "text/plain");
+    resp.set_header(
+        "Connection",
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+    resp.set_body(status.canonical_reason().unwrap_or_default());
+    resp
+}
+
+fn is_idle_close(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
 pub fn get_default_storage_path() -> PathBuf {
     let app_name = env!("CARGO_PKG_NAME");
 
@@ -433,3 +1206,333 @@ pub fn get_default_storage_path() -> PathBuf {
         base_dir.join(".local/share").join(app_name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use http::Method;
+
+    use crate::core::processor::LocationPattern;
+
+    use super::*;
+
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SendFile for MockStream {}
+
+    impl ClientSocket for MockStream {
+        fn socket(&self) -> Option<&TcpStream> {
+            None
+        }
+    }
+
+    fn run(input: &str, core: HttpCoreConfig) -> String {
+        serve(ok_processor(), input, core)
+    }
+
+    fn ok_processor() -> HttpProcessor {
+        let mut processor = HttpProcessor::new();
+        processor.add_handler(
+            "/".to_string(),
+            StatusCode::OK,
+            &Method::GET,
+            Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body("ok");
+                resp
+            }),
+        );
+        processor
+    }
+
+    fn serve(processor: HttpProcessor, input: &str, core: HttpCoreConfig) -> String {
+        let mut stream = MockStream {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        let conn_config = ConnectionConfig {
+            http_version: Version::HTTP_11,
+            core,
+            variables: Arc::new(VariableRegistry::new()),
+            tracer: None,
+            slow_log: None,
+            statsd: None,
+        };
+        let info = ConnectionInfo {
+            remote_addr: None,
+            local_addr: None,
+            secure: false,
+        };
+        handle_connection(&mut stream, &processor, &conn_config, &info).unwrap();
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    #[test]
+    fn test_keepalive_serves_pipelined_requests() {
+        let output = run(
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+            HttpCoreConfig::default(),
+        );
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(output.contains("Connection: keep-alive"));
+        assert!(output.ends_with("Connection: close\r\nContent-Length: 2\r\n\r\nok"));
+    }
+
+    #[test]
+    fn test_panicking_handler_answers_500() {
+        let mut processor = ok_processor();
+        processor.add_handler(
+            "/boom".to_string(),
+            StatusCode::OK,
+            &Method::GET,
+            Box::new(|req: &HttpRequest| {
+                assert_ne!(req.path(), "/boom");
+                HttpResponse::new()
+            }),
+        );
+        let output = serve(
+            processor,
+            "GET /boom HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+            HttpCoreConfig::default(),
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("Connection: close"));
+        assert!(!output.contains("200 OK"));
+    }
+
+    #[test]
+    fn test_request_ids_are_sent_to_clients() {
+        let core = HttpCoreConfig {
+            request_id: Some(true),
+            ..Default::default()
+        };
+        let output = run(
+            "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+            core,
+        );
+        let ids: Vec<&str> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("X-Request-Id: "))
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids
+            .iter()
+            .all(|id| id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())));
+    }
+
+    #[test]
+    fn test_streamed_bodies_are_read_on_demand() {
+        let mut processor = ok_processor();
+        processor.add_location(
+            LocationPattern::prefix("/upload"),
+            Box::new(|req: &HttpRequest| {
+                let mut body = req.take_body_stream().unwrap();
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                let mut received = Vec::new();
+                body.read_to_end(&mut received).unwrap();
+                assert!(req.body().is_empty());
+                let all_x = received.iter().all(|b| *b == b'x');
+                resp.set_body(&format!("{} {}", received.len(), all_x));
+                resp
+            }),
+        );
+        processor.set_stream_body();
+        let body = "x".repeat(20000);
+
+        let upload = |path: &str| {
+            format!(
+                "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET / HTTP/1.1\r\n\r\n",
+                path,
+                body.len(),
+                body
+            )
+        };
+        let output = serve(processor, &upload("/upload"), HttpCoreConfig::default());
+        assert!(output.contains("\r\n\r\n20000 true"));
+        // The request after the body is served on the same connection.
+        assert!(output.ends_with("\r\n\r\nok"));
+
+        let mut processor = ok_processor();
+        processor.add_location(
+            LocationPattern::prefix("/upload"),
+            Box::new(|req: &HttpRequest| {
+                let body = req.take_body_stream().unwrap();
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!("skipped {}", body.len().unwrap()));
+                resp
+            }),
+        );
+        processor.set_stream_body();
+        let output = serve(
+            processor,
+            &upload("/upload/skip"),
+            HttpCoreConfig::default(),
+        );
+        // A body left unread closes the connection.
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.ends_with("skipped 20000"));
+        assert_eq!(output.matches("HTTP/1.1 200").count(), 1);
+    }
+
+    #[test]
+    fn test_chunked_bodies_are_streamed() {
+        let mut processor = ok_processor();
+        processor.add_location(
+            LocationPattern::prefix("/upload"),
+            Box::new(|req: &HttpRequest| {
+                let mut body = String::new();
+                let read = req.take_body_stream().unwrap().read_to_string(&mut body);
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_body(&format!("{} {}", read.is_ok(), body));
+                resp
+            }),
+        );
+        processor.set_stream_body();
+        let upload = |path: &str, chunks: &str| {
+            format!(
+                "POST {} HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}GET / HTTP/1.1\r\n\r\n",
+                path, chunks
+            )
+        };
+        let output = serve(
+            processor,
+            &upload("/upload", "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"),
+            HttpCoreConfig::default(),
+        );
+        assert!(output.contains("\r\n\r\ntrue hello world"));
+        // The request after the body is served on the same connection.
+        assert!(output.ends_with("\r\n\r\nok"));
+
+        // A malformed body fails the handler's read and closes the connection.
+        let mut processor = ok_processor();
+        processor.add_location(
+            LocationPattern::prefix("/upload"),
+            Box::new(|req: &HttpRequest| {
+                let read = req.take_body_stream().unwrap().read_to_end(&mut Vec::new());
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::BAD_REQUEST);
+                resp.set_body(&read.unwrap_err().kind().to_string());
+                resp
+            }),
+        );
+        processor.set_stream_body();
+        let output = serve(
+            processor,
+            &upload("/upload", "5\r\nhello world\r\n0\r\n\r\n"),
+            HttpCoreConfig::default(),
+        );
+        assert!(output.contains("Connection: close\r\n"));
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+
+        // Locations that buffer bodies refuse chunked ones.
+        let output = serve(
+            ok_processor(),
+            &upload("/", "2\r\nhi\r\n0\r\n\r\n"),
+            HttpCoreConfig::default(),
+        );
+        assert!(output.starts_with("HTTP/1.1 411 Length Required\r\n"));
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[test]
+    fn test_body_over_limit_is_rejected() {
+        let core = HttpCoreConfig {
+            client_max_body_size: Some(4),
+            ..Default::default()
+        };
+        let output = run(
+            "GET / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789",
+            core,
+        );
+        assert!(output.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_oversized_headers_are_rejected() {
+        let core = HttpCoreConfig {
+            client_header_buffer_size: Some(64),
+            large_client_header_buffers: Some((2, 128)),
+            ..Default::default()
+        };
+        let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(200));
+        assert!(run(&long_uri, core.clone()).starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+
+        let long_header = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "a".repeat(200));
+        assert!(run(&long_header, core.clone())
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: 0123456789abcdef\r\n".repeat(12)
+        );
+        assert!(run(&many_headers, core.clone())
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let fits = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "a".repeat(100));
+        assert!(run(&fits, core).starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_block_phases_run_once_per_request() {
+        use crate::{
+            core::config::command::ArgValue,
+            http::{http_real_ip::handle_set_real_ip_from, http_referer::handle_valid_referers},
+        };
+
+        fn set(
+            ctx: &mut ConfigContext,
+            handler: fn(&mut ConfigContext, &Value) -> Result<(), ConfigError>,
+            arg: &str,
+        ) {
+            ctx.current_cmd_args = vec![arg.to_string()];
+            ctx.current_cmd_values = vec![ArgValue::String(arg.to_string())];
+            handler(ctx, &Value::Null).unwrap();
+        }
+
+        let http = ConfigContext::new_empty("http", vec![]);
+        let mut server = ConfigContext::new_empty("server", vec![]);
+        set(&mut server, handle_set_real_ip_from, "10.0.0.0/8");
+        set(&mut server, handle_valid_referers, "none");
+        let mut plain = ConfigContext::new_empty("location", vec!["/".to_string()]);
+        set(&mut plain, handle_set_real_ip_from, "192.168.0.0/16");
+        let mut own = ConfigContext::new_empty("location", vec!["/own".to_string()]);
+        set(&mut own, handle_valid_referers, "blocked");
+
+        assert_eq!(block_phases(&[&http, &server], &[]).len(), 2);
+        // The server's real_ip and referer check have already run.
+        assert!(block_phases(&[&http, &server, &plain], &[]).is_empty());
+        assert_eq!(block_phases(&[&http, &server, &own], &[]).len(), 1);
+        // Without the server's, the location replaces the address itself.
+        let bare = ConfigContext::new_empty("server", vec![]);
+        assert_eq!(block_phases(&[&http, &bare, &plain], &[]).len(), 1);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+
+use http::{Method, StatusCode};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::HttpProcessor,
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::{HttpResponse, StreamBody},
+};
+
+register_commands!(CommandBuilder::new("slice")
+    .allowed_parents(vec![
+        "http".to_string(),
+        "server".to_string(),
+        "location".to_string(),
+    ])
+    .display_name("en", "Slice")
+    .display_name("zh-tw", "切片")
+    .desc(
+        "en",
+        "Fetches proxied responses as byte ranges of a fixed size, each cached on its own"
+    )
+    .desc(
+        "zh-tw",
+        "以固定大小的位元組範圍分段取得代理回應，每一段各自快取"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Size")
+        .display_name("zh-tw", "大小")
+        .arg_type(ArgType::Size)
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "Size of each range, e.g. 1m, or 0 (the default) to disable"
+        )
+        .desc("zh-tw", "每段範圍的大小，例如 1m，或 0（預設）表示停用")
+        .build()])
+    .build(handle_slice));
+
+/// Fetches one slice of a response, usually through the proxy cache.
+pub type SliceFetch = Arc<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+#[derive(Debug, Default, Clone)]
+pub struct SliceConfig {
+    pub size: Option<u64>,
+}
+
+impl MergeConfig for SliceConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.size = self.size.or(parent.size);
+    }
+}
+
+/// A client's byte range, before the length of the response is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteRange {
+    /// From the first position to the last, or to the end.
+    From(u64, Option<u64>),
+    /// The final bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a single `bytes=` range; anything else is answered in full.
+    fn parse(req: &HttpRequest) -> Option<Self> {
+        let spec = req.header("Range")?.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        if first.is_empty() {
+            return last.parse().ok().map(Self::Suffix);
+        }
+        let first = first.parse().ok()?;
+        match last {
+            "" => Some(Self::From(first, None)),
+            last => last
+                .parse()
+                .ok()
+                .filter(|last| *last >= first)
+                .map(|last| Self::From(first, Some(last))),
+        }
+    }
+
+    /// The position the first slice fetched must cover.
+    fn first(&self) -> u64 {
+        match self {
+            Self::From(first, _) => *first,
+            Self::Suffix(_) => 0,
+        }
+    }
+
+    /// The requested bytes as `start..end` of a body of `len` bytes, or
+    /// `None` when none of them exist.
+    fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            Self::From(first, _) if first >= len => None,
+            Self::From(first, last) => Some((first, last.map_or(len, |last| (last + 1).min(len)))),
+            Self::Suffix(0) => None,
+            Self::Suffix(_) if len == 0 => None,
+            Self::Suffix(suffix) => Some((len.saturating_sub(suffix), len)),
+        }
+    }
+}
+
+/// The byte range a slice request fetches, as `$slice_range`; kept in the
+/// request's extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SliceRange(pub String);
+
+/// Splits GET requests into ranges of `size` bytes aligned to multiples of
+/// it, fetching each as a request of its own so that caches store every
+/// slice separately, and joins the ones a client asks for into its response.
+pub struct Slice {
+    size: u64,
+    fetch: SliceFetch,
+}
+
+impl Slice {
+    pub fn new(size: u64, fetch: SliceFetch) -> Self {
+        Self { size, fetch }
+    }
+
+    pub fn handle(&self, req: &HttpRequest) -> HttpResponse {
+        if *req.method() != Method::GET {
+            return (self.fetch)(req);
+        }
+        let range = ByteRange::parse(req);
+        let mut start = range.map_or(0, |range| range.first()) / self.size * self.size;
+        let mut first = self.fetch_slice(req, start);
+        let Some(len) = self.slice_length(&first, start) else {
+            // Answered in full or with an error; send it as it is.
+            return first;
+        };
+        let Some((from, to)) = range.map_or(Some((0, len)), |range| range.resolve(len)) else {
+            let mut resp = HttpProcessor::create_status_response(
+                req.version(),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            );
+            resp.set_header("Content-Range", &format!("bytes */{}", len));
+            return resp;
+        };
+        if from / self.size * self.size != start {
+            // Only a suffix range learns where it starts from the first slice.
+            start = from / self.size * self.size;
+            first = self.fetch_slice(req, start);
+            if self.slice_length(&first, start) != Some(len) {
+                return bad_gateway(req);
+            }
+        }
+        if let Err(e) = first.load_body() {
+            log_error!("cannot read slice of \"{}\": {}", req.path(), e);
+            return bad_gateway(req);
+        }
+
+        let mut resp = HttpResponse::new();
+        match range {
+            Some(_) => {
+                resp.set_status_line(*req.version(), StatusCode::PARTIAL_CONTENT);
+                resp.set_header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", from, to - 1, len),
+                );
+            }
+            None => {
+                resp.set_status_line(*req.version(), StatusCode::OK);
+            }
+        }
+        for (name, value) in first.headers() {
+            if !name.eq_ignore_ascii_case("Content-Range")
+                && !name.eq_ignore_ascii_case("Content-Length")
+            {
+                resp.set_header(name, value);
+            }
+        }
+        let skip = ((from - start) as usize).min(first.body.len());
+        let take = ((to - start) as usize).min(first.body.len());
+        let reader = SliceReader {
+            slice: Slice::new(self.size, Arc::clone(&self.fetch)),
+            req: req.clone(),
+            etag: first.header_value("ETag").map(str::to_string),
+            len,
+            buf: first.body[skip..take].to_vec(),
+            pos: 0,
+            next: start + self.size,
+            end: to,
+        };
+        resp.set_body_stream(StreamBody::new(reader, Some(to - from)));
+        resp
+    }
+
+    fn fetch_slice(&self, req: &HttpRequest, start: u64) -> HttpResponse {
+        let range = format!("bytes={}-{}", start, start + self.size - 1);
+        let mut sub = req.clone();
+        sub.remove_header("If-Range");
+        sub.set_header("Range", &range);
+        sub.extensions_mut().insert(SliceRange(range));
+        (self.fetch)(&sub)
+    }
+
+    /// The full length of the response a slice fetched at `start` is part
+    /// of, if it is one.
+    fn slice_length(&self, resp: &HttpResponse, start: u64) -> Option<u64> {
+        if resp.status() != Some(206) {
+            return None;
+        }
+        let (range, len) = resp
+            .header_value("Content-Range")?
+            .trim()
+            .strip_prefix("bytes ")?
+            .split_once('/')?;
+        let (first, _) = range.split_once('-')?;
+        (first.parse::<u64>().ok()? == start).then_some(())?;
+        len.parse().ok()
+    }
+}
+
+fn bad_gateway(req: &HttpRequest) -> HttpResponse {
+    HttpProcessor::create_status_response(req.version(), StatusCode::BAD_GATEWAY)
+}
+
+/// Reads the bytes a client asked for, fetching the slices after the first
+/// one as they are needed.
+struct SliceReader {
+    slice: Slice,
+    req: HttpRequest,
+    /// Every slice must come from the same version of the response.
+    etag: Option<String>,
+    len: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Start of the next slice to fetch.
+    next: u64,
+    /// End of the requested bytes.
+    end: u64,
+}
+
+impl SliceReader {
+    fn fetch_next(&mut self) -> io::Result<()> {
+        let mut resp = self.slice.fetch_slice(&self.req, self.next);
+        if self.slice.slice_length(&resp, self.next) != Some(self.len)
+            || resp.header_value("ETag") != self.etag.as_deref()
+        {
+            return Err(io::Error::other(format!(
+                "slice at {} of \"{}\" does not match the first",
+                self.next,
+                self.req.path()
+            )));
+        }
+        resp.load_body()?;
+        let take = ((self.end - self.next) as usize).min(resp.body.len());
+        resp.body.truncate(take);
+        if resp.body.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf = resp.body;
+        self.pos = 0;
+        self.next += self.slice.size;
+        Ok(())
+    }
+}
+
+impl Read for SliceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            if self.next >= self.end {
+                return Ok(0);
+            }
+            self.fetch_next()?;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Builds the slicer for a location chain that sets a non-zero `slice`.
+pub fn slice(chain: &[&ConfigContext], fetch: SliceFetch) -> Option<Slice> {
+    let size = merged_config::<SliceConfig>(chain)
+        .size
+        .filter(|size| *size > 0)?;
+    Some(Slice::new(size, fetch))
+}
+
+pub fn handle_slice(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let size = ctx.size_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SliceConfig>().lock() {
+        config.size = Some(size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_slices_join_into_client_ranges() {
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let upstream = content.clone();
+        let log = Arc::clone(&fetched);
+        let slice = Slice::new(
+            1000,
+            Arc::new(move |req: &HttpRequest| {
+                let range = req.extensions().get::<SliceRange>().unwrap().0.clone();
+                assert_eq!(req.header("Range"), Some(range.as_str()));
+                log.lock().unwrap().push(range.clone());
+                let (first, last) = range["bytes=".len()..].split_once('-').unwrap();
+                let first: usize = first.parse().unwrap();
+                let last = last.parse::<usize>().unwrap().min(upstream.len() - 1);
+                let mut resp = HttpResponse::new();
+                if first >= upstream.len() {
+                    resp.set_status_line(http::Version::HTTP_11, StatusCode::RANGE_NOT_SATISFIABLE);
+                    resp.set_header("Content-Range", &format!("bytes */{}", upstream.len()));
+                    return resp;
+                }
+                resp.set_status_line(http::Version::HTTP_11, StatusCode::PARTIAL_CONTENT);
+                resp.set_header("ETag", "\"v1\"");
+                resp.set_header(
+                    "Content-Range",
+                    &format!("bytes {}-{}/{}", first, last, upstream.len()),
+                );
+                resp.set_body_stream(StreamBody::new(
+                    io::Cursor::new(upstream[first..=last].to_vec()),
+                    Some((last - first + 1) as u64),
+                ));
+                resp
+            }),
+        );
+        let get = |range: Option<&str>| {
+            fetched.lock().unwrap().clear();
+            let mut raw = "GET /video.mp4 HTTP/1.1\r\nHost: a\r\n".to_string();
+            if let Some(range) = range {
+                raw.push_str(&format!("Range: {}\r\n", range));
+            }
+            raw.push_str("\r\n");
+            let mut req = HttpRequest::new();
+            req.parse(raw.as_bytes()).unwrap();
+            let mut resp = slice.handle(&req);
+            resp.load_body().unwrap();
+            resp
+        };
+
+        let resp = get(None);
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.body, content);
+        assert_eq!(resp.header_value("Content-Range"), None);
+        assert_eq!(fetched.lock().unwrap().len(), 3);
+
+        let resp = get(Some("bytes=1500-2100"));
+        assert_eq!(resp.status(), Some(206));
+        assert_eq!(
+            resp.header_value("Content-Range"),
+            Some("bytes 1500-2100/2500")
+        );
+        assert_eq!(resp.body, &content[1500..=2100]);
+        assert_eq!(
+            *fetched.lock().unwrap(),
+            ["bytes=1000-1999", "bytes=2000-2999"]
+        );
+
+        let resp = get(Some("bytes=-100"));
+        assert_eq!(resp.body, &content[2400..]);
+        assert_eq!(
+            resp.header_value("Content-Range"),
+            Some("bytes 2400-2499/2500")
+        );
+
+        let resp = get(Some("bytes=3000-"));
+        assert_eq!(resp.status(), Some(416));
+        assert_eq!(resp.header_value("Content-Range"), Some("bytes */2500"));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Local;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        error_log::Level,
+        log_file::LogFileOptions,
+        log_target::{LogDestination, LogTarget},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::{http_version_to_string, HttpRequest, RequestId},
+    http_response::HttpResponse,
+};
+
+register_commands!(CommandBuilder::new("slow_log")
+    .allowed_parents(vec!["http".to_string(), "server".to_string()])
+    .display_name("en", "Slow Request Log")
+    .display_name("zh-tw", "慢速請求日誌")
+    .desc(
+        "en",
+        "Writes requests that take longer than a threshold to a separate log, with the time spent in each phase"
+    )
+    .desc("zh-tw", "將處理時間超過門檻的請求連同各階段耗時寫入獨立的日誌")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "File the lines are appended to, syslog:server=address[,...], journald, or off"
+            )
+            .desc(
+                "zh-tw",
+                "附加日誌的檔案、syslog:server=位址[,...]、journald，或 off"
+            )
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Threshold")
+            .display_name("zh-tw", "門檻")
+            .type_name("String")
+            .default("")
+            .desc("en", "Requests taking at least this long are logged, 1s by default")
+            .desc("zh-tw", "處理時間達到此值的請求會被記錄，預設為 1s")
+            .build(),
+        ParameterBuilder::new(2)
+            .display_name("en", "Options")
+            .display_name("zh-tw", "選項")
+            .type_name("String")
+            .default("")
+            .desc(
+                "en",
+                "buffer=size, flush=time, rotate_size=size, rotate_time=time and rotate_keep=n, as for access_log"
+            )
+            .desc(
+                "zh-tw",
+                "buffer=大小、flush=時間、rotate_size=大小、rotate_time=時間 與 rotate_keep=數量，與 access_log 相同"
+            )
+            .build(),
+    ])
+    .arity(Arity::AtLeast(1))
+    .build(handle_slow_log));
+
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowLogSpec {
+    pub destination: LogDestination,
+    pub threshold: Duration,
+    pub file: LogFileOptions,
+}
+
+impl SlowLogSpec {
+    /// Parses the arguments of `slow_log`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (path, rest) = args.split_first().ok_or("missing log file path")?;
+        let mut spec = Self {
+            destination: LogDestination::parse(path)?,
+            threshold: DEFAULT_THRESHOLD,
+            file: LogFileOptions::default(),
+        };
+        for (i, arg) in rest.iter().enumerate() {
+            match arg.split_once('=') {
+                Some((name, value)) => {
+                    if !spec.file.parse_option(name, value)? {
+                        return Err(format!("unknown option \"{}\"", name));
+                    }
+                }
+                None if i == 0 => spec.threshold = parse_duration(arg)?,
+                None => return Err(format!("unknown option \"{}\"", arg)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SlowLogConfig {
+    /// `Some(None)` is `slow_log off`, which stops an inherited log.
+    pub log: Option<Option<SlowLogSpec>>,
+}
+
+impl MergeConfig for SlowLogConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.log = self.log.take().or_else(|| parent.log.clone());
+    }
+}
+
+/// How long each phase of a request took: reading it, producing the
+/// response, and sending it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PhaseTimes {
+    pub parse: Duration,
+    pub handler: Duration,
+    pub write: Duration,
+}
+
+impl PhaseTimes {
+    pub fn total(&self) -> Duration {
+        self.parse + self.handler + self.write
+    }
+}
+
+pub struct SlowLog {
+    threshold: Duration,
+    target: LogTarget,
+}
+
+impl SlowLog {
+    /// Writes a line for the request when it took at least the threshold.
+    pub fn write(&self, req: &HttpRequest, resp: &HttpResponse, times: &PhaseTimes) {
+        if times.total() < self.threshold {
+            return;
+        }
+        self.target.write(Level::Warn, &slow_line(req, resp, times));
+    }
+}
+
+fn slow_line(req: &HttpRequest, resp: &HttpResponse, times: &PhaseTimes) -> String {
+    let mut line = format!(
+        "{} {:.3}s \"{} {} {}\" {}",
+        Local::now().format("%Y/%m/%d %H:%M:%S"),
+        times.total().as_secs_f64(),
+        req.method(),
+        req.request_uri(),
+        http_version_to_string(req.version()),
+        resp.status().unwrap_or_default()
+    );
+    if let Some(addr) = req.remote_addr() {
+        line.push_str(&format!(" client={}", addr.ip()));
+    }
+    if let Some(RequestId(id)) = req.extensions().get() {
+        line.push_str(&format!(" id={}", id));
+    }
+    line.push_str(&format!(
+        " parse={:.3} handler={:.3}",
+        times.parse.as_secs_f64(),
+        times.handler.as_secs_f64()
+    ));
+    for upstream in &resp.upstream {
+        let seconds = |time: Option<Duration>| match time {
+            Some(time) => format!("{:.3}", time.as_secs_f64()),
+            None => "-".to_string(),
+        };
+        line.push_str(&format!(
+            " upstream={} connect={} header={} response={}",
+            upstream.address,
+            seconds(upstream.connect),
+            seconds(upstream.header),
+            seconds(upstream.response_time())
+        ));
+    }
+    line.push_str(&format!(" write={:.3}", times.write.as_secs_f64()));
+    line
+}
+
+/// Opens the slow log a block chain sets, if any.
+pub fn slow_log(chain: &[&ConfigContext]) -> Option<Arc<SlowLog>> {
+    let spec = merged_config::<SlowLogConfig>(chain).log.flatten()?;
+    match spec.destination.open(&spec.file) {
+        Ok(target) => Some(Arc::new(SlowLog {
+            threshold: spec.threshold,
+            target,
+        })),
+        Err(e) => {
+            log_error!("cannot open slow log \"{}\": {}", spec.destination, e);
+            None
+        }
+    }
+}
+
+pub fn handle_slow_log(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let spec = if args == ["off"] {
+        None
+    } else {
+        Some(
+            SlowLogSpec::parse(&args)
+                .map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?,
+        )
+    };
+    if let Ok(mut config) = ctx.block_config::<SlowLogConfig>().lock() {
+        config.log = Some(spec);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use http::StatusCode;
+
+    use crate::http::http_upstream::UpstreamTiming;
+
+    use super::*;
+
+    #[test]
+    fn test_only_slow_requests_are_logged() {
+        let path = std::env::temp_dir().join(format!("blur-slow-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let args = [path.to_string_lossy().to_string(), "500ms".to_string()];
+        let spec = SlowLogSpec::parse(&args).unwrap();
+        assert_eq!(spec.threshold, Duration::from_millis(500));
+        let log = SlowLog {
+            threshold: spec.threshold,
+            target: spec.destination.open(&spec.file).unwrap(),
+        };
+
+        let mut req = HttpRequest::new();
+        req.parse(b"GET /report?year=2024 HTTP/1.1\r\n\r\n")
+            .unwrap();
+        req.extensions_mut().insert(RequestId("abc123".to_string()));
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(*req.version(), StatusCode::OK);
+        let upstream = UpstreamTiming {
+            address: "10.0.0.2:8080".to_string(),
+            connect: Some(Duration::from_millis(1)),
+            header: Some(Duration::from_millis(650)),
+            ..Default::default()
+        };
+        upstream.response.set(Duration::from_millis(700)).unwrap();
+        resp.upstream.push(upstream);
+
+        let fast = PhaseTimes {
+            parse: Duration::from_millis(1),
+            handler: Duration::from_millis(100),
+            write: Duration::from_millis(2),
+        };
+        log.write(&req, &resp, &fast);
+        let slow = PhaseTimes {
+            handler: Duration::from_millis(702),
+            ..fast
+        };
+        log.write(&req, &resp, &slow);
+
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].ends_with(
+                "0.705s \"GET /report?year=2024 HTTP/1.1\" 200 id=abc123 parse=0.001 handler=0.702 upstream=10.0.0.2:8080 connect=0.001 header=0.650 response=0.700 write=0.002"
+            ),
+            "{}",
+            lines[0]
+        );
+        drop(log);
+        let _ = fs::remove_file(&path);
+
+        assert!(SlowLogSpec::parse(&["stderr".to_string(), "soon".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{CommandBuilder, ParameterBuilder},
+        config_context::ConfigContext,
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_variables::{
+    parse_variable_name, RequestVariables, VarTemplate, VariableDefinition, VariableSource,
+};
+
+register_commands!(CommandBuilder::new("split_clients")
+    .is_raw_block()
+    .allowed_parents(vec!["http".to_string()])
+    .display_name("en", "Split Clients")
+    .display_name("zh-tw", "流量分組")
+    .desc(
+        "en",
+        "Creates a variable that deterministically splits clients into buckets for A/B testing"
+    )
+    .desc(
+        "zh-tw",
+        "建立一個將客戶端穩定分配到不同組別的變數，用於 A/B 測試"
+    )
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Key")
+            .display_name("zh-tw", "分組依據")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Value to hash, may combine variables, e.g. \"$remote_addr$request_uri\""
+            )
+            .desc(
+                "zh-tw",
+                "用於雜湊的值，可組合多個變數，例如 \"$remote_addr$request_uri\""
+            )
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Variable")
+            .display_name("zh-tw", "變數")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Name of the resulting variable, e.g. $variant")
+            .desc("zh-tw", "結果變數名稱，例如 $variant")
+            .build(),
+    ])
+    .build(handle_split_clients));
+
+/// Percentages are kept with two decimals, so 100% is 10000.
+const FULL_PERCENT: u64 = 10000;
+
+pub struct SplitClients {
+    key: VarTemplate,
+    // Upper hash bound of each bucket; `None` marks the `*` catch-all.
+    buckets: Vec<(Option<u32>, String)>,
+    total: u64,
+}
+
+impl SplitClients {
+    pub fn new(key: VarTemplate) -> Self {
+        Self {
+            key,
+            buckets: Vec::new(),
+            total: 0,
+        }
+    }
+
+    pub fn add_bucket(&mut self, percent: &str, value: &str) -> Result<(), String> {
+        if self.buckets.iter().any(|(bound, _)| bound.is_none()) {
+            return Err("bucket after \"*\" is never used".to_string());
+        }
+        if percent == "*" {
+            self.buckets.push((None, value.to_string()));
+            return Ok(());
+        }
+        let percent = parse_percent(percent)?;
+        self.total += percent;
+        if self.total > FULL_PERCENT {
+            return Err("percent total is greater than 100%".to_string());
+        }
+        let bound = (self.total * u32::MAX as u64 / FULL_PERCENT) as u32;
+        self.buckets.push((Some(bound), value.to_string()));
+        Ok(())
+    }
+
+    pub fn bucket_for(&self, key: &str) -> Option<&str> {
+        let hash = murmur_hash2(key.as_bytes());
+        self.buckets
+            .iter()
+            .find(|(bound, _)| bound.is_none_or(|bound| hash < bound))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl VariableSource for SplitClients {
+    fn evaluate(&self, vars: &RequestVariables) -> Option<String> {
+        let key = self.key.render(vars);
+        Some(self.bucket_for(&key).unwrap_or_default().to_string())
+    }
+}
+
+fn parse_percent(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid percent value \"{}\"", value);
+    let number = value.strip_suffix('%').ok_or_else(invalid)?;
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty()
+        || fraction.len() > 2
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+    let whole: u64 = whole.parse().map_err(|_| invalid())?;
+    let fraction: u64 = format!("{:0<2}", fraction).parse().map_err(|_| invalid())?;
+    let percent = whole
+        .checked_mul(100)
+        .and_then(|v| v.checked_add(fraction))
+        .ok_or_else(invalid)?;
+    if percent == 0 {
+        return Err(invalid());
+    }
+    Ok(percent)
+}
+
+/// MurmurHash2 with a zero seed, matching the bucket assignment of nginx.
+pub fn murmur_hash2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = data.len() as u32;
+
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+pub fn handle_split_clients(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let key = ctx.str_arg(0)?;
+    let target = ctx.str_arg(1)?;
+    let name = parse_variable_name(&target)
+        .ok_or_else(|| ctx.invalid_value(&target, "variable name must start with \"$\""))?
+        .to_string();
+
+    let mut split = SplitClients::new(VarTemplate::parse(&key));
+    for entry in &ctx.raw_entries {
+        match entry.args.as_slice() {
+            [percent, value] => split
+                .add_bucket(percent, value)
+                .map_err(|reason| ctx.invalid_entry(entry, percent, reason))?,
+            [percent] => split
+                .add_bucket(percent, "")
+                .map_err(|reason| ctx.invalid_entry(entry, percent, reason))?,
+            _ => {
+                return Err(ConfigError::InvalidArgCount {
+                    name: ctx.current_cmd_name.clone(),
+                    pos: entry.pos.clone(),
+                })
+            }
+        }
+    }
+
+    ctx.store.insert(Arc::new(VariableDefinition {
+        name,
+        source: Arc::new(split),
+    }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur_hash2_matches_reference() {
+        assert_eq!(murmur_hash2(b""), 0);
+        assert_eq!(murmur_hash2(b"a"), 0x92685f5e);
+        assert_eq!(murmur_hash2(b"hello world"), 0x44a81419);
+    }
+
+    #[test]
+    fn test_split_is_deterministic_and_proportional() {
+        let mut split = SplitClients::new(VarTemplate::parse("$remote_addr"));
+        split.add_bucket("20%", "a").unwrap();
+        split.add_bucket("30.5%", "b").unwrap();
+        split.add_bucket("*", "c").unwrap();
+
+        let mut counts = [0usize; 3];
+        for i in 0..10000 {
+            let key = format!("10.0.{}.{}", i / 256, i % 256);
+            let bucket = split.bucket_for(&key).unwrap();
+            assert_eq!(split.bucket_for(&key), Some(bucket));
+            counts[(bucket.as_bytes()[0] - b'a') as usize] += 1;
+        }
+        assert!((1700..2300).contains(&counts[0]), "{:?}", counts);
+        assert!((2750..3350).contains(&counts[1]), "{:?}", counts);
+
+        assert!(split.add_bucket("10%", "d").is_err());
+        assert!(parse_percent("101%").is_ok());
+        assert!(parse_percent("1.234%").is_err());
+        assert!(parse_percent("50").is_err());
+    }
+}
@@ -0,0 +1,664 @@
+use std::{fmt::Write as _, sync::Weak};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local, Utc,
+};
+use http::Method;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{ProcessorSlot, ResponseFilter, Subrequest},
+    },
+    log_error, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("ssi")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Server-Side Includes")
+        .display_name("zh-tw", "伺服器端引入")
+        .desc(
+            "en",
+            "Sets whether SSI commands in responses of the ssi_types are processed"
+        )
+        .desc("zh-tw", "設定是否處理 ssi_types 類型回應中的 SSI 指令")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_ssi),
+    CommandBuilder::new("ssi_types")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "SSI Types")
+        .display_name("zh-tw", "SSI 類型")
+        .desc(
+            "en",
+            "Sets the MIME types SSI commands are processed in, in addition to text/html"
+        )
+        .desc("zh-tw", "設定除了 text/html 之外處理 SSI 指令的 MIME 類型")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "MIME Types")
+            .display_name("zh-tw", "MIME 類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more MIME types, e.g. text/plain, or * for any type"
+            )
+            .desc(
+                "zh-tw",
+                "一個或多個 MIME 類型，例如 text/plain，或以 * 表示所有類型"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_ssi_types),
+    CommandBuilder::new("ssi_silent_errors")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "SSI Silent Errors")
+        .display_name("zh-tw", "SSI 隱藏錯誤")
+        .desc(
+            "en",
+            "Sets whether a failed SSI command is left out instead of replaced by the error message"
+        )
+        .desc("zh-tw", "設定 SSI 指令失敗時是否不輸出錯誤訊息")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_ssi_silent_errors)
+);
+
+/// How deep included documents may include others.
+const MAX_INCLUDE_DEPTH: usize = 10;
+const DEFAULT_ERRMSG: &str = "[an error occurred while processing the directive]";
+const DEFAULT_TIMEFMT: &str = "%A, %d-%b-%Y %H:%M:%S %Z";
+
+#[derive(Debug, Default, Clone)]
+pub struct SsiConfig {
+    pub enabled: Option<bool>,
+    pub types: Option<Vec<String>>,
+    pub silent_errors: Option<bool>,
+}
+
+impl MergeConfig for SsiConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.enabled.is_none() {
+            self.enabled = parent.enabled;
+        }
+        if self.types.is_none() {
+            self.types = parent.types.clone();
+        }
+        if self.silent_errors.is_none() {
+            self.silent_errors = parent.silent_errors;
+        }
+    }
+}
+
+/// One `if` block; `active` is whether the branch being read is output.
+struct Condition {
+    parent_active: bool,
+    matched: bool,
+    active: bool,
+}
+
+/// What a document has set up so far while its commands are run.
+struct SsiState {
+    scope: HttpRequest,
+    errmsg: String,
+    timefmt: String,
+    conditions: Vec<Condition>,
+}
+
+impl SsiState {
+    fn active(&self) -> bool {
+        self.conditions.last().is_none_or(|c| c.active)
+    }
+
+    fn date(&self, utc: bool) -> String {
+        let mut out = String::new();
+        let written = if utc {
+            write!(out, "{}", Utc::now().format(&self.timefmt))
+        } else {
+            write!(out, "{}", Local::now().format(&self.timefmt))
+        };
+        if written.is_err() {
+            out.clear();
+        }
+        out
+    }
+
+    fn variables(&self) -> RequestVariables<'_> {
+        let uri = self.scope.path().split('?').next().unwrap_or_default();
+        RequestVariables::new(&self.scope)
+            .with_value("DATE_LOCAL", self.date(false))
+            .with_value("DATE_GMT", self.date(true))
+            .with_value("DOCUMENT_URI", uri.to_string())
+    }
+
+    fn render(&self, text: &str) -> String {
+        VarTemplate::parse(text).render(&self.variables())
+    }
+}
+
+/// Runs Server-Side Includes commands embedded in response bodies as
+/// `<!--#command param="value" -->`.
+pub struct SsiFilter {
+    types: Vec<String>,
+    silent_errors: bool,
+    processor: ProcessorSlot,
+}
+
+impl SsiFilter {
+    fn applies_to(&self, req: &HttpRequest, resp: &HttpResponse) -> bool {
+        if *req.method() == Method::HEAD
+            || resp.has_header("Content-Encoding")
+            || resp.status().is_some_and(|status| {
+                status < 200 || status == 204 || status == 206 || status == 304
+            })
+        {
+            return false;
+        }
+        let content_type = resp
+            .header_value("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        content_type == "text/html"
+            || self
+                .types
+                .iter()
+                .any(|t| t == "*" || t.eq_ignore_ascii_case(&content_type))
+    }
+
+    fn process(&self, req: &HttpRequest, body: &[u8]) -> Vec<u8> {
+        let mut state = SsiState {
+            scope: req.clone(),
+            errmsg: DEFAULT_ERRMSG.to_string(),
+            timefmt: DEFAULT_TIMEFMT.to_string(),
+            conditions: Vec::new(),
+        };
+        let mut out = Vec::with_capacity(body.len());
+        let mut rest = body;
+        while let Some(start) = find(rest, b"<!--#") {
+            if state.active() {
+                out.extend_from_slice(&rest[..start]);
+            }
+            let command = &rest[start + 5..];
+            let Some(end) = find(command, b"-->") else {
+                rest = &rest[start..];
+                break;
+            };
+            rest = &command[end + 3..];
+            let command = String::from_utf8_lossy(&command[..end]);
+            if let Err(e) = self.run(&mut state, &command, &mut out) {
+                log_error!(
+                    "SSI command \"{}\" failed in \"{}\": {}",
+                    command.trim(),
+                    req.path(),
+                    e
+                );
+                if !self.silent_errors && state.active() {
+                    out.extend_from_slice(state.errmsg.as_bytes());
+                }
+            }
+        }
+        if state.active() {
+            out.extend_from_slice(rest);
+        }
+        if !state.conditions.is_empty() {
+            log_error!("SSI \"if\" without \"endif\" in \"{}\"", req.path());
+        }
+        out
+    }
+
+    fn run(&self, state: &mut SsiState, command: &str, out: &mut Vec<u8>) -> Result<(), String> {
+        let command = Command::parse(command)?;
+        let param = |key: &str| command.param(key);
+        match command.name {
+            "if" => {
+                let parent_active = state.active();
+                let hit = parent_active && evaluate(state, param("expr").ok_or("missing expr")?)?;
+                state.conditions.push(Condition {
+                    parent_active,
+                    matched: hit,
+                    active: hit,
+                });
+            }
+            "elif" => {
+                let expr = param("expr").ok_or("missing expr")?;
+                let condition = state.conditions.last().ok_or("elif without if")?;
+                let hit = !condition.matched && condition.parent_active && evaluate(state, expr)?;
+                let condition = state.conditions.last_mut().ok_or("elif without if")?;
+                condition.matched |= hit;
+                condition.active = hit;
+            }
+            "else" => {
+                let condition = state.conditions.last_mut().ok_or("else without if")?;
+                condition.active = condition.parent_active && !condition.matched;
+                condition.matched = true;
+            }
+            "endif" => {
+                state.conditions.pop().ok_or("endif without if")?;
+            }
+            _ if !state.active() => {}
+            "echo" => {
+                let var = param("var").ok_or("missing var")?;
+                let value = match state.variables().get(var) {
+                    Some(value) => match param("encoding").unwrap_or("entity") {
+                        "entity" => escape_html(&value),
+                        "url" => escape_url(&value),
+                        "none" => value,
+                        other => return Err(format!("unknown encoding \"{}\"", other)),
+                    },
+                    None => param("default").unwrap_or("(none)").to_string(),
+                };
+                out.extend_from_slice(value.as_bytes());
+            }
+            "set" => {
+                let var = param("var").ok_or("missing var")?;
+                let value = state.render(param("value").ok_or("missing value")?);
+                state.scope.set_var(var, &value);
+            }
+            "include" => {
+                let uri = match (param("virtual"), param("file")) {
+                    (Some(uri), None) | (None, Some(uri)) => state.render(uri),
+                    _ => return Err("expected one of virtual or file".to_string()),
+                };
+                let body = self.include(state, &uri)?;
+                match param("set") {
+                    Some(var) => state.scope.set_var(var, &String::from_utf8_lossy(&body)),
+                    None => out.extend_from_slice(&body),
+                }
+            }
+            "config" => {
+                if let Some(errmsg) = param("errmsg") {
+                    state.errmsg = errmsg.to_string();
+                }
+                if let Some(timefmt) = param("timefmt") {
+                    if StrftimeItems::new(timefmt).any(|item| matches!(item, Item::Error)) {
+                        return Err(format!("invalid timefmt \"{}\"", timefmt));
+                    }
+                    state.timefmt = timefmt.to_string();
+                }
+            }
+            other => return Err(format!("unknown command \"{}\"", other)),
+        }
+        Ok(())
+    }
+
+    /// The body of a `GET` subrequest to `uri`, resolved against the
+    /// document's own directory when relative.
+    fn include(&self, state: &SsiState, uri: &str) -> Result<Vec<u8>, String> {
+        if state.scope.subrequest_depth() >= MAX_INCLUDE_DEPTH {
+            return Err(format!("includes nested deeper than {}", MAX_INCLUDE_DEPTH));
+        }
+        let uri = if uri.starts_with('/') {
+            uri.to_string()
+        } else {
+            let document = state.scope.path().split('?').next().unwrap_or_default();
+            let dir = &document[..document.rfind('/').map_or(0, |i| i + 1)];
+            format!("{}{}", dir, uri)
+        };
+        let processor = self
+            .processor
+            .get()
+            .and_then(Weak::upgrade)
+            .ok_or("no server to send the subrequest to")?;
+        let sub = Subrequest::new(&state.scope, &uri)
+            .without_args()
+            .method(Method::GET)
+            .without_body()
+            .remove_header("Range");
+        let mut resp = processor.subrequest(sub);
+        let status = resp.status().unwrap_or(0);
+        if !(200..300).contains(&status) {
+            resp.discard_body();
+            return Err(format!("\"{}\" answered status {}", uri, status));
+        }
+        resp.load_body()
+            .map_err(|e| format!("reading \"{}\": {}", uri, e))?;
+        Ok(resp.body)
+    }
+}
+
+impl ResponseFilter for SsiFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if !self.applies_to(req, resp) {
+            return;
+        }
+        if let Err(e) = resp.load_body() {
+            log_error!("Failed to read \"{}\" for SSI: {}", req.path(), e);
+            return;
+        }
+        if find(&resp.body, b"<!--#").is_some() {
+            let body = self.process(req, &resp.body);
+            resp.body = body;
+        }
+        resp.remove_header("Content-Length");
+        resp.remove_header("Accept-Ranges");
+        resp.remove_header("Last-Modified");
+        resp.remove_header("ETag");
+    }
+}
+
+/// Builds the SSI filter for a block chain with `ssi on`.
+pub fn ssi_filter(chain: &[&ConfigContext], processor: &ProcessorSlot) -> Option<SsiFilter> {
+    let config = merged_config::<SsiConfig>(chain);
+    if !config.enabled.unwrap_or(false) {
+        return None;
+    }
+    Some(SsiFilter {
+        types: config.types.unwrap_or_default(),
+        silent_errors: config.silent_errors.unwrap_or(false),
+        processor: ProcessorSlot::clone(processor),
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A command such as `include virtual="/a" set="b"`.
+struct Command<'a> {
+    name: &'a str,
+    params: Vec<(String, String)>,
+}
+
+impl<'a> Command<'a> {
+    fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Splits the text between `<!--#` and `-->` into the command name and
+    /// its quoted parameters.
+    fn parse(text: &'a str) -> Result<Self, String> {
+        let text = text.trim();
+        let (name, mut rest) = text
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .unwrap_or((text, ""));
+        if name.is_empty() {
+            return Err("missing command".to_string());
+        }
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                return Ok(Self { name, params });
+            }
+            let (key, value) = rest
+                .split_once('=')
+                .ok_or_else(|| format!("expected param=\"value\" at \"{}\"", rest))?;
+            let key = key.trim();
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid parameter name \"{}\"", key));
+            }
+            let value = value.trim_start();
+            let quote = value
+                .chars()
+                .next()
+                .filter(|c| *c == '"' || *c == '\'')
+                .ok_or_else(|| format!("value of \"{}\" must be quoted", key))?;
+            let mut parsed = String::new();
+            let mut chars = value[1..].char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some((_, next)) if next == quote => parsed.push(next),
+                        Some((_, next)) => {
+                            parsed.push('\\');
+                            parsed.push(next);
+                        }
+                        None => parsed.push('\\'),
+                    },
+                    c if c == quote => {
+                        end = Some(i + 2);
+                        break;
+                    }
+                    c => parsed.push(c),
+                }
+            }
+            let end = end.ok_or_else(|| format!("unterminated value of \"{}\"", key))?;
+            params.push((key.to_string(), parsed));
+            rest = &value[end..];
+        }
+    }
+}
+
+/// Evaluates `$var`, `$var = text`, `$var != text`, `$var = /regex/` or
+/// any of these preceded by `!`.
+fn evaluate(state: &SsiState, expr: &str) -> Result<bool, String> {
+    let expr = expr.trim();
+    if let Some(inner) = expr.strip_prefix('!') {
+        return evaluate(state, inner).map(|result| !result);
+    }
+    let name = expr
+        .strip_prefix('$')
+        .ok_or_else(|| format!("expected a variable in \"{}\"", expr))?;
+    let (name, rest) = match name.strip_prefix('{') {
+        Some(braced) => braced
+            .split_once('}')
+            .ok_or_else(|| format!("unterminated variable in \"{}\"", expr))?,
+        None => name.split_at(
+            name.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(name.len()),
+        ),
+    };
+    let value = state.variables().get(name).unwrap_or_default();
+    let rest = rest.trim_start();
+    let (negate, operand) = if let Some(operand) = rest.strip_prefix("!=") {
+        (true, operand)
+    } else if let Some(operand) = rest.strip_prefix('=') {
+        (false, operand)
+    } else if rest.is_empty() {
+        return Ok(!value.is_empty());
+    } else {
+        return Err(format!("expected = or != in \"{}\"", expr));
+    };
+    let operand = operand.trim();
+    let matched = match operand
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'))
+    {
+        Some(pattern) => Regex::new(pattern)
+            .map_err(|e| format!("invalid regex \"{}\": {}", pattern, e))?
+            .is_match(&value),
+        None => {
+            let text = operand
+                .strip_prefix('\'')
+                .and_then(|text| text.strip_suffix('\''))
+                .unwrap_or(operand);
+            value == state.render(text)
+        }
+    };
+    Ok(matched != negate)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn escape_url(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+pub fn handle_ssi(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SsiConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_ssi_types(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let types = ctx.args();
+    if types.is_empty() {
+        return Ok(());
+    }
+    if let Ok(mut config) = ctx.block_config::<SsiConfig>().lock() {
+        config.types = Some(types);
+    }
+    Ok(())
+}
+
+pub fn handle_ssi_silent_errors(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let silent = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SsiConfig>().lock() {
+        config.silent_errors = Some(silent);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use http::StatusCode;
+
+    use crate::core::processor::{HttpProcessor, LocationPattern};
+
+    use super::*;
+
+    #[test]
+    fn test_ssi_commands() {
+        let slot = ProcessorSlot::default();
+        let filter = SsiFilter {
+            types: Vec::new(),
+            silent_errors: false,
+            processor: Arc::clone(&slot),
+        };
+        let mut processor = HttpProcessor::new();
+        processor.add_location_with_phases(
+            LocationPattern::parse(&["/".to_string()]).unwrap(),
+            Some(Box::new(|req: &HttpRequest| {
+                let mut resp = HttpResponse::new();
+                resp.set_status_line(*req.version(), StatusCode::OK);
+                resp.set_header("Content-Type", "text/html");
+                resp.set_header("Content-Length", "1");
+                let body = match req.path().split('?').next().unwrap_or_default() {
+                    "/inc/frag" => {
+                        "<b><!--#echo var=\"who\" default=\"nobody\" --></b>".to_string()
+                    }
+                    "/loop" => "<!--#include virtual=\"/loop\" -->".to_string(),
+                    "/missing" => {
+                        resp.set_status_line(*req.version(), StatusCode::NOT_FOUND);
+                        String::new()
+                    }
+                    _ => req.header("X-Page").unwrap_or_default().to_string(),
+                };
+                resp.set_body(&body);
+                resp
+            })),
+            Vec::new(),
+            Arc::default(),
+            vec![Arc::new(filter)],
+        );
+        let processor = Arc::new(processor);
+        slot.set(Arc::downgrade(&processor)).unwrap();
+
+        let send = |page: &str| {
+            let mut req = HttpRequest::new();
+            let raw = format!(
+                "GET /inc/page?q=a%26b HTTP/1.1\r\nHost: a\r\nX-Page: {}\r\n\r\n",
+                page
+            );
+            req.parse(raw.as_bytes()).unwrap();
+            let resp = processor.handle(&mut req);
+            assert!(!resp.has_header("Content-Length"));
+            String::from_utf8(resp.body).unwrap()
+        };
+
+        assert_eq!(
+            send("[<!--#include virtual=\"frag\" -->]"),
+            "[<b>nobody</b>]"
+        );
+        assert_eq!(
+            send("<!--#set var=\"who\" value=\"$arg_q\" --><!--#echo var=\"who\" -->|<!--#echo var=\"who\" encoding=\"url\" -->|<!--#include virtual=\"/inc/frag\" -->"),
+            "a%26b|a%2526b|<b>nobody</b>"
+        );
+        assert_eq!(
+            send("<!--#include virtual=\"/inc/frag\" set=\"f\" --><!--#echo var=\"f\" encoding=\"none\" -->"),
+            "<b>nobody</b>"
+        );
+        assert_eq!(
+            send("<!--#if expr=\"$arg_q = /^a/\" -->A<!--#if expr=\"$nothing\" -->X<!--#else -->B<!--#endif --><!--#elif expr=\"$host = a\" -->C<!--#else -->D<!--#endif -->"),
+            "AB"
+        );
+        assert_eq!(
+            send("<!--#if expr=\"$host != 'a'\" -->A<!--#elif expr=\"!$nothing\" -->B<!--#else -->C<!--#endif -->"),
+            "B"
+        );
+        assert_eq!(
+            send("<!--#config errmsg=\"[oops]\" --><!--#include virtual=\"/missing\" --><!--#bogus --><!--#include virtual=\"/loop\" -->"),
+            format!("[oops][oops]{}", DEFAULT_ERRMSG)
+        );
+        assert_eq!(send("plain <!--# unterminated"), "plain <!--# unterminated");
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     str::FromStr,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -17,9 +17,9 @@ use crate::{
     core::config::{
         command::{CommandBuilder, ParameterBuilder},
         config_context::ConfigContext,
-        config_manager::{bool_str_to_bool, get_config_param},
+        config_loader::ConfigError,
     },
-    register_commands,
+    log_notice, register_commands,
 };
 
 #[derive(Debug, Error)]
@@ -190,103 +190,98 @@ fn update_ssl_context<F>(ctx: &mut ConfigContext, update_fn: F)
 where
     F: FnOnce(&mut HttpSSLContext),
 {
-    if let Some(ssl_ctx) = get_mut_ssl_ctx(ctx) {
-        update_fn(ssl_ctx);
-    }
-}
-
-fn get_mut_ssl_ctx(ctx: &ConfigContext) -> Option<&mut HttpSSLContext> {
-    if let Some(ssl_ctx_ptr) = &ctx.current_ctx {
-        let ssl_ptr = ssl_ctx_ptr.load(Ordering::SeqCst);
-        return Some(unsafe { &mut *(ssl_ptr as *mut HttpSSLContext) });
+    if let Some(ssl_ctx) = ctx.store.get::<Mutex<HttpSSLContext>>() {
+        if let Ok(mut ssl_ctx) = ssl_ctx.lock() {
+            update_fn(&mut ssl_ctx);
+        }
     }
-    None
 }
 
 pub fn handle_create_ssl(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let enable = get_config_param(config, 0).expect("Missing SSL enable parameter");
-    let enable = bool_str_to_bool(&enable).unwrap();
-    if !enable {
-        return;
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    if !ctx.bool_arg(0)? {
+        return Ok(());
     }
-    let mut ssl_ctx = Box::new(HttpSSLContext::new());
+    let mut ssl_ctx = HttpSSLContext::new();
     ssl_ctx.ssl = true;
-    let ssl_raw = Box::into_raw(ssl_ctx) as *mut u8;
-    ctx.current_ctx = Some(AtomicPtr::new(ssl_raw));
-    ctx.current_block_type_id = Some(std::any::TypeId::of::<HttpSSLContext>());
+    ctx.store.insert(Arc::new(Mutex::new(ssl_ctx)));
+    Ok(())
 }
 
 pub fn handle_set_ssl_email(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let email = get_config_param(config, 0).expect("Missing ssl_email parameter");
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    let email = ctx.str_arg(0)?;
     update_ssl_context(ctx, |ssl_ctx| {
         ssl_ctx.email = email.to_string();
     });
+    Ok(())
 }
 
 pub fn handle_set_ssl_domain(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let domain = get_config_param(config, 0).expect("Missing ssl_domain parameter");
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    let domain = ctx.str_arg(0)?;
     update_ssl_context(ctx, |ssl_ctx| {
         ssl_ctx.domain = domain.to_string();
     });
+    Ok(())
 }
 
 pub fn handle_set_ssl_auto_renew(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let enable_str = get_config_param(config, 0).expect("Missing ssl_auto_renew parameter");
-    let enable = bool_str_to_bool(&enable_str).unwrap();
-    if !enable {
-        return;
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    if !ctx.bool_arg(0)? {
+        return Ok(());
     }
     update_ssl_context(ctx, |ssl_ctx| {
         ssl_ctx.auto_renew = true;
     });
+    Ok(())
 }
 
 pub fn handle_set_ssl_renew_day(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let days_str = get_config_param(config, 0).expect("Missing ssl_renew_day parameter");
-    let days = days_str
-        .parse::<u32>()
-        .expect("Invalid number for ssl_renew_day");
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    let days = ctx.number_arg(0)?;
+    let days =
+        u32::try_from(days).map_err(|e| ctx.invalid_value(&days.to_string(), e.to_string()))?;
     update_ssl_context(ctx, |ssl_ctx| {
         ssl_ctx.renew_days = days;
     });
+    Ok(())
 }
 
 pub fn handle_set_ssl_dns_provider(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let provider = get_config_param(config, 0).expect("Missing ssl_dns_provider parameter");
-    let api_token =
-        get_config_param(config, 1).expect("Missing ssl_dns_provider API token parameter");
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    let provider = ctx.str_arg(0)?;
+    let api_token = ctx.str_arg(1)?;
+    let dns_provider = DnsProvider::from_str(&provider)
+        .map_err(|_| ctx.invalid_value(&provider, "unsupported DNS provider"))?;
     update_ssl_context(ctx, |ssl_ctx| {
-        ssl_ctx.dns_provider = DnsProvider::from_str(&provider).unwrap();
+        ssl_ctx.dns_provider = dns_provider;
         ssl_ctx.dns_provider_api_token = api_token.to_string();
     });
+    Ok(())
 }
 
 pub fn handle_set_ssl_dns_instructions_lang(
-    ctx: &mut crate::core::config::config_context::ConfigContext,
-    config: &Value,
-) {
-    let lang = get_config_param(config, 0).expect("Missing ssl_dns_instructions_lang parameter");
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> std::result::Result<(), ConfigError> {
+    let lang = ctx.str_arg(0)?;
     update_ssl_context(ctx, |ssl_ctx| {
         ssl_ctx.dns_instructions_lang = lang.to_string();
     });
+    Ok(())
 }
 
 pub struct HttpSSLContext {
@@ -350,7 +345,7 @@ impl HttpSSL {
             .expect("Failed to get certificate");
 
         if ctx.auto_renew && cert.should_renew(ctx.renew_days)? {
-            println!("Renewing SSL certificate for domain: {}", ctx.domain);
+            log_notice!("Renewing SSL certificate for domain: {}", ctx.domain);
             Self::init(&mut account, ctx, true)?;
             cert = account.get_certificate(&ctx.domain).unwrap();
         }
@@ -359,12 +354,12 @@ impl HttpSSL {
     }
 
     pub fn from_config(ctx: &ConfigContext) -> Result<Self> {
-        if let Some(ssl_ctx_ptr) = &ctx.current_ctx {
-            let ssl_raw = ssl_ctx_ptr.load(Ordering::SeqCst);
-            let ssl_ctx: &HttpSSLContext = unsafe { &*(ssl_raw as *const HttpSSLContext) };
-            Self::new(ssl_ctx)
-        } else {
-            Err(HttpSSLError::SSLNotEnabled)
+        match ctx.store.get::<Mutex<HttpSSLContext>>() {
+            Some(ssl_ctx) => match ssl_ctx.lock() {
+                Ok(ssl_ctx) => Self::new(&ssl_ctx),
+                Err(_) => Err(HttpSSLError::SSLNotEnabled),
+            },
+            None => Err(HttpSSLError::SSLNotEnabled),
         }
     }
 
@@ -0,0 +1,1166 @@
+use std::{
+    fs::{self, File, Metadata},
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use http::{Method, StatusCode};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpHandler, HttpProcessor, LocationModifier, LocationPattern},
+    },
+    register_commands,
+};
+
+use super::{
+    http_autoindex::{listing_response, AutoindexConfig},
+    http_file_cache::{OpenFileCache, OpenFileCacheConfig},
+    http_mime::MimeConfig,
+    http_negotiate::{choose, find_variants, vary, NegotiateConfig, Variant},
+    http_request::HttpRequest,
+    http_response::{http_date, parse_http_date, FileBody, HttpResponse},
+    http_rewrite::redirect_response,
+};
+
+register_commands!(
+    CommandBuilder::new("root")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Root")
+        .display_name("zh-tw", "根目錄")
+        .desc(
+            "en",
+            "Sets the directory that request URIs are appended to when serving files"
+        )
+        .desc("zh-tw", "設定提供檔案時，請求 URI 所接在其後的目錄")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "Document root directory")
+            .desc("zh-tw", "文件根目錄")
+            .build()])
+        .build(handle_root),
+    CommandBuilder::new("alias")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "Alias")
+        .display_name("zh-tw", "別名")
+        .desc(
+            "en",
+            "Replaces the matched location prefix with the given path when serving files"
+        )
+        .desc("zh-tw", "提供檔案時以指定路徑取代符合的 location 前綴")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .arg_type(ArgType::Path)
+            .is_required(true)
+            .default("")
+            .desc("en", "Directory or file that replaces the location prefix")
+            .desc("zh-tw", "取代 location 前綴的目錄或檔案")
+            .build()])
+        .build(handle_alias),
+    CommandBuilder::new("index")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Index")
+        .display_name("zh-tw", "索引檔案")
+        .desc(
+            "en",
+            "Sets the files tried, in order, when a directory is requested"
+        )
+        .desc("zh-tw", "設定請求目錄時依序嘗試的檔案")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Files")
+            .display_name("zh-tw", "檔案")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "One or more file names, e.g. index.html index.htm")
+            .desc("zh-tw", "一個或多個檔案名稱，例如 index.html index.htm")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_index),
+    CommandBuilder::new("etag")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "ETag")
+        .display_name("zh-tw", "ETag")
+        .desc("en", "Enables or disables the ETag header for static files")
+        .desc("zh-tw", "啟用或停用靜態檔案的 ETag 標頭")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_etag),
+    CommandBuilder::new("gzip_static")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Gzip Static")
+        .display_name("zh-tw", "預先壓縮 Gzip")
+        .desc(
+            "en",
+            "Serves file.gz instead of file to clients that accept gzip"
+        )
+        .desc("zh-tw", "對接受 gzip 的客戶端改為提供 file.gz")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_gzip_static),
+    CommandBuilder::new("brotli_static")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Brotli Static")
+        .display_name("zh-tw", "預先壓縮 Brotli")
+        .desc(
+            "en",
+            "Serves file.br instead of file to clients that accept br"
+        )
+        .desc("zh-tw", "對接受 br 的客戶端改為提供 file.br")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_brotli_static),
+    CommandBuilder::new("disable_symlinks")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Disable Symlinks")
+        .display_name("zh-tw", "停用符號連結")
+        .desc(
+            "en",
+            "Refuses to serve files reached through symbolic links below the root"
+        )
+        .desc("zh-tw", "拒絕提供經由根目錄下符號連結存取的檔案")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Policy")
+            .display_name("zh-tw", "策略")
+            .arg_type(ArgType::Enum(vec![
+                "off".to_string(),
+                "on".to_string(),
+                "if_not_owner".to_string(),
+            ]))
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "off, on, or if_not_owner to refuse only links whose owner differs from the target's; defaults to off"
+            )
+            .desc(
+                "zh-tw",
+                "off、on，或 if_not_owner 僅拒絕擁有者與目標不同的連結；預設為 off"
+            )
+            .build()])
+        .build(handle_disable_symlinks),
+    CommandBuilder::new("sendfile")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Sendfile")
+        .display_name("zh-tw", "Sendfile")
+        .desc(
+            "en",
+            "Sends static files with sendfile(2) on plaintext connections instead of reading them into memory"
+        )
+        .desc(
+            "zh-tw",
+            "在未加密的連線上以 sendfile(2) 傳送靜態檔案，而不先讀入記憶體"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_sendfile),
+    CommandBuilder::new("sendfile_max_chunk")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Sendfile Max Chunk")
+        .display_name("zh-tw", "Sendfile 單次上限")
+        .desc(
+            "en",
+            "Limits the amount of data sent by a single sendfile(2) call"
+        )
+        .desc("zh-tw", "限制單次 sendfile(2) 呼叫傳送的資料量")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Size")
+            .display_name("zh-tw", "大小")
+            .arg_type(ArgType::Size)
+            .is_required(true)
+            .default("")
+            .desc("en", "Chunk size, e.g. 2m; 0 means unlimited, defaults to 2m")
+            .desc("zh-tw", "單次大小，例如 2m；0 表示不限制，預設為 2m")
+            .build()])
+        .build(handle_sendfile_max_chunk),
+);
+
+pub const DEFAULT_INDEX: &str = "index.html";
+pub const DEFAULT_SENDFILE_MAX_CHUNK: u64 = 2 * 1024 * 1024;
+
+/// Whether symbolic links below the root may be followed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    #[default]
+    Off,
+    /// Refuse every symbolic link.
+    On,
+    /// Refuse links owned by a different user than their target.
+    IfNotOwner,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StaticConfig {
+    pub root: Option<PathBuf>,
+    /// Unlike `root`, replaces the prefix of the location declaring it.
+    pub alias: Option<PathBuf>,
+    pub index: Option<Vec<String>>,
+    pub etag: Option<bool>,
+    pub sendfile: Option<bool>,
+    pub sendfile_max_chunk: Option<u64>,
+    pub gzip_static: Option<bool>,
+    pub brotli_static: Option<bool>,
+    pub disable_symlinks: Option<SymlinkPolicy>,
+}
+
+impl MergeConfig for StaticConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.alias.is_none() {
+            self.root = self.root.take().or_else(|| parent.root.clone());
+        }
+        self.index = self.index.take().or_else(|| parent.index.clone());
+        self.etag = self.etag.or(parent.etag);
+        self.sendfile = self.sendfile.or(parent.sendfile);
+        self.sendfile_max_chunk = self.sendfile_max_chunk.or(parent.sendfile_max_chunk);
+        self.gzip_static = self.gzip_static.or(parent.gzip_static);
+        self.brotli_static = self.brotli_static.or(parent.brotli_static);
+        self.disable_symlinks = self.disable_symlinks.or(parent.disable_symlinks);
+    }
+}
+
+impl StaticConfig {
+    pub fn index(&self) -> Vec<String> {
+        self.index
+            .clone()
+            .unwrap_or_else(|| vec![DEFAULT_INDEX.to_string()])
+    }
+}
+
+/// Maps request URIs onto files below a `root` or `alias` directory.
+pub struct StaticFiles {
+    base: PathBuf,
+    /// URI prefix replaced by `base` when serving an `alias`.
+    strip_prefix: Option<String>,
+    index: Vec<String>,
+    mime: MimeConfig,
+    etag: bool,
+    /// Chunk limit for `sendfile`, or `None` to read files into memory.
+    sendfile: Option<u64>,
+    autoindex: AutoindexConfig,
+    /// Whether missing files are looked for among their variants.
+    negotiate: bool,
+    open_file_cache: Option<(Arc<OpenFileCache>, Duration)>,
+    /// Content codings whose precompressed variants are looked up, in order
+    /// of preference, with the file suffix of each.
+    precompressed: Vec<(&'static str, &'static str)>,
+    symlinks: SymlinkPolicy,
+}
+
+impl StaticFiles {
+    pub fn new(
+        config: &StaticConfig,
+        mime: MimeConfig,
+        pattern: Option<&LocationPattern>,
+    ) -> Option<Self> {
+        let (base, strip_prefix) = match (&config.alias, pattern) {
+            (Some(alias), Some(pattern)) => {
+                let strip = match pattern.modifier {
+                    LocationModifier::Prefix
+                    | LocationModifier::PreferPrefix
+                    | LocationModifier::Exact => pattern.path.clone(),
+                    _ => String::new(),
+                };
+                (alias.clone(), Some(strip))
+            }
+            _ => (config.root.clone()?, None),
+        };
+        Some(Self {
+            base,
+            strip_prefix,
+            index: config.index(),
+            mime,
+            etag: config.etag.unwrap_or(true),
+            sendfile: config.sendfile.unwrap_or(false).then(|| {
+                config
+                    .sendfile_max_chunk
+                    .unwrap_or(DEFAULT_SENDFILE_MAX_CHUNK)
+            }),
+            autoindex: AutoindexConfig::default(),
+            negotiate: false,
+            open_file_cache: None,
+            precompressed: [
+                (config.brotli_static, ("br", "br")),
+                (config.gzip_static, ("gzip", "gz")),
+            ]
+            .into_iter()
+            .filter(|(enabled, _)| enabled.unwrap_or(false))
+            .map(|(_, coding)| coding)
+            .collect(),
+            symlinks: config.disable_symlinks.unwrap_or_default(),
+        })
+    }
+
+    pub fn with_autoindex(mut self, autoindex: AutoindexConfig) -> Self {
+        self.autoindex = autoindex;
+        self
+    }
+
+    pub fn with_negotiation(mut self, config: &NegotiateConfig) -> Self {
+        self.negotiate = config.enabled();
+        self
+    }
+
+    pub fn with_open_file_cache(mut self, config: &OpenFileCacheConfig) -> Self {
+        self.open_file_cache = config.cache().map(|cache| (cache, config.valid()));
+        self
+    }
+
+    /// The directory `root` or `alias` names.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Resolves the file path for a request URI, without the query string.
+    pub fn map_uri(&self, uri: &str) -> Option<PathBuf> {
+        let relative = match &self.strip_prefix {
+            Some(prefix) => uri.strip_prefix(prefix.as_str())?,
+            None => uri,
+        };
+        if relative.contains('\0') || relative.split('/').any(|segment| segment == "..") {
+            return None;
+        }
+        let relative = relative.trim_start_matches('/');
+        if relative.is_empty() {
+            return Some(self.base.clone());
+        }
+        Some(self.base.join(relative))
+    }
+
+    pub fn serve(&self, req: &HttpRequest) -> HttpResponse {
+        let version = *req.version();
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            return HttpProcessor::create_status_response(&version, StatusCode::METHOD_NOT_ALLOWED);
+        }
+
+        let uri = req.path().split('?').next().unwrap_or_default();
+        let Some(path) = self.map_uri(uri) else {
+            return HttpProcessor::create_status_response(&version, StatusCode::BAD_REQUEST);
+        };
+
+        let mut negotiated = match self.negotiate(req, &path) {
+            Ok(negotiated) => negotiated,
+            Err(vary) => return not_acceptable(req, &vary),
+        };
+        let path = match &negotiated {
+            Some((variant, _)) => variant.path.clone(),
+            None => path,
+        };
+        let metadata = match self.metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => return error_for(req, &e),
+        };
+        let path = if metadata.is_dir() {
+            if !uri.ends_with('/') {
+                let query = req.path().split_once('?').map(|(_, query)| query);
+                let location = match query {
+                    Some(query) => format!("{}/?{}", uri, query),
+                    None => format!("{}/", uri),
+                };
+                return redirect_response(req, StatusCode::MOVED_PERMANENTLY, &location);
+            }
+            match self
+                .index
+                .iter()
+                .map(|index| path.join(index))
+                .find(|candidate| {
+                    self.metadata(candidate)
+                        .is_ok_and(|metadata| metadata.is_file())
+                }) {
+                Some(index) => index,
+                None => match self.negotiate_index(req, &path) {
+                    Ok(Some((variant, vary))) => {
+                        let index = variant.path.clone();
+                        negotiated = Some((variant, vary));
+                        index
+                    }
+                    Ok(None) if self.autoindex.enabled() && self.is_allowed(&path) => {
+                        return listing_response(req, &path, uri, &self.autoindex)
+                            .unwrap_or_else(|e| error_for(req, &e));
+                    }
+                    Ok(None) => {
+                        return HttpProcessor::create_status_response(
+                            &version,
+                            StatusCode::FORBIDDEN,
+                        )
+                    }
+                    Err(vary) => return not_acceptable(req, &vary),
+                },
+            }
+        } else {
+            path
+        };
+
+        if !self.is_allowed(&path) {
+            return HttpProcessor::create_status_response(&version, StatusCode::FORBIDDEN);
+        }
+
+        let (encoding, vary) = self.precompressed_variant(req, &path);
+        let content_path = match encoding {
+            Some((_, suffix)) => with_suffix(&path, suffix),
+            None => path.clone(),
+        };
+        let (metadata, file) = match &self.open_file_cache {
+            Some((cache, valid)) => match cache.open(&content_path, *valid) {
+                Ok(open) => (open.metadata, Some(open.file)),
+                Err(e) => return error_for(req, &e),
+            },
+            None => match fs::metadata(&content_path) {
+                Ok(metadata) => (metadata, None),
+                Err(e) => return error_for(req, &e),
+            },
+        };
+        let modified = metadata.modified().ok();
+        let etag = match modified {
+            Some(modified) if self.etag => Some(file_etag(modified, metadata.len())),
+            _ => None,
+        };
+
+        let mut resp = HttpResponse::new();
+        let mut varies = negotiated
+            .as_ref()
+            .map(|(_, vary)| vary.clone())
+            .unwrap_or_default();
+        if vary {
+            varies.push("Accept-Encoding");
+        }
+        if !varies.is_empty() {
+            resp.set_header("Vary", &varies.join(", "));
+        }
+        if let Some(language) = negotiated
+            .as_ref()
+            .and_then(|(variant, _)| variant.language.as_deref())
+        {
+            resp.set_header("Content-Language", language);
+        }
+        if is_not_modified(req, etag.as_deref(), modified) {
+            resp.set_status_line(version, StatusCode::NOT_MODIFIED);
+            set_validators(&mut resp, etag.as_deref(), modified);
+            return resp;
+        }
+
+        resp.set_status_line(version, StatusCode::OK);
+        match &negotiated {
+            Some((variant, _)) => resp.set_header("Content-Type", &variant.content_type),
+            None => resp.set_header(
+                "Content-Type",
+                self.mime.content_type(&path.to_string_lossy()),
+            ),
+        };
+        if let Some((coding, _)) = encoding {
+            resp.set_header("Content-Encoding", coding);
+        }
+        set_validators(&mut resp, etag.as_deref(), modified);
+        resp.set_header("Accept-Ranges", "bytes");
+        let len = metadata.len();
+        let (offset, body_len) = match byte_range(req, len, etag.as_deref(), modified) {
+            Ok(None) => (0, len),
+            Ok(Some((start, end))) => {
+                resp.set_status_line(version, StatusCode::PARTIAL_CONTENT);
+                resp.set_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+                (start, end - start + 1)
+            }
+            Err(()) => {
+                let mut resp = HttpProcessor::create_status_response(
+                    &version,
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                );
+                resp.set_header("Content-Range", &format!("bytes */{}", len));
+                return resp;
+            }
+        };
+        if *req.method() == Method::HEAD {
+            resp.set_header("Content-Length", &body_len.to_string());
+            return resp;
+        }
+
+        let file = match file {
+            Some(file) => file,
+            None => match File::open(&content_path) {
+                Ok(file) => Arc::new(file),
+                Err(e) => return error_for(req, &e),
+            },
+        };
+        let mut body = FileBody::new(file, body_len);
+        body.offset = offset;
+        match self.sendfile {
+            Some(max_chunk) => {
+                body.max_chunk = max_chunk;
+                resp.set_body_file(body);
+            }
+            None => {
+                let mut content = Vec::with_capacity(body_len as usize);
+                if let Err(e) = body.copy_to(&mut content) {
+                    return error_for(req, &e);
+                }
+                resp.set_body_bytes(&content);
+            }
+        }
+        resp
+    }
+
+    /// Looks for the language and type variants of a missing `path` when
+    /// `negotiate` is on. `Err` carries the `Vary` headers when the client
+    /// accepts none of them.
+    fn negotiate(
+        &self,
+        req: &HttpRequest,
+        path: &Path,
+    ) -> Result<Option<(Variant, Vec<&'static str>)>, Vec<&'static str>> {
+        if !self.negotiate || self.metadata(path).is_ok() {
+            return Ok(None);
+        }
+        let variants = find_variants(path, &self.mime);
+        if variants.is_empty() {
+            return Ok(None);
+        }
+        let vary = vary(&variants);
+        match choose(req, &variants) {
+            Some(variant) => Ok(Some((variant.clone(), vary))),
+            None => Err(vary),
+        }
+    }
+
+    /// Negotiates the first index file of `dir` that has variants.
+    fn negotiate_index(
+        &self,
+        req: &HttpRequest,
+        dir: &Path,
+    ) -> Result<Option<(Variant, Vec<&'static str>)>, Vec<&'static str>> {
+        for index in &self.index {
+            if let Some(negotiated) = self.negotiate(req, &dir.join(index))? {
+                return Ok(Some(negotiated));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Picks the preferred precompressed variant of `path` the client
+    /// accepts, and reports whether any variant exists, in which case the
+    /// response depends on `Accept-Encoding`.
+    fn precompressed_variant(
+        &self,
+        req: &HttpRequest,
+        path: &Path,
+    ) -> (Option<(&'static str, &'static str)>, bool) {
+        let mut chosen = None;
+        let mut vary = false;
+        for &(coding, suffix) in &self.precompressed {
+            let variant = with_suffix(path, suffix);
+            if !self
+                .metadata(&variant)
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                continue;
+            }
+            vary = true;
+            if chosen.is_none() && req.accepts_encoding(coding) && self.is_allowed(&variant) {
+                chosen = Some((coding, suffix));
+            }
+        }
+        (chosen, vary)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        match &self.open_file_cache {
+            Some((cache, valid)) => cache.open(path, *valid).map(|open| open.metadata),
+            None => fs::metadata(path),
+        }
+    }
+
+    fn is_allowed(&self, path: &Path) -> bool {
+        self.contains(path) && self.follows_symlink_policy(path)
+    }
+
+    /// Whether WebDAV may create, change or remove `path`: the path, or its
+    /// nearest existing ancestor when it does not exist yet, has to pass the
+    /// same checks as a file being served.
+    pub fn allows_change(&self, path: &Path) -> bool {
+        path.ancestors()
+            .find(|path| fs::symlink_metadata(path).is_ok())
+            .is_some_and(|existing| self.is_allowed(existing))
+    }
+
+    /// Checks the path components below the base directory against
+    /// `disable_symlinks`; components that do not exist are left to the
+    /// file lookup to report.
+    fn follows_symlink_policy(&self, path: &Path) -> bool {
+        if self.symlinks == SymlinkPolicy::Off {
+            return true;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let mut current = self.base.clone();
+        for component in relative.components() {
+            current.push(component);
+            let Ok(link) = fs::symlink_metadata(&current) else {
+                return true;
+            };
+            if !link.file_type().is_symlink() {
+                continue;
+            }
+            match self.symlinks {
+                SymlinkPolicy::IfNotOwner => match fs::metadata(&current) {
+                    Ok(target) if target.uid() == link.uid() => {}
+                    Ok(_) => return false,
+                    Err(_) => return true,
+                },
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Checks that the resolved file stays below the base directory.
+    fn contains(&self, path: &Path) -> bool {
+        let (Ok(base), Ok(path)) = (fs::canonicalize(&self.base), fs::canonicalize(path)) else {
+            return false;
+        };
+        path.starts_with(base)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Builds a strong entity tag from the modification time and size, in the
+/// same format nginx uses.
+pub fn file_etag(modified: SystemTime, len: u64) -> String {
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", secs, len)
+}
+
+fn set_validators(resp: &mut HttpResponse, etag: Option<&str>, modified: Option<SystemTime>) {
+    if let Some(modified) = modified {
+        resp.set_last_modified(modified);
+    }
+    if let Some(etag) = etag {
+        resp.set_etag(etag);
+    }
+}
+
+/// The first and last byte of the single range a `Range: bytes=` header asks
+/// for in a body of `len` bytes. `Ok(None)` serves the whole body: without a
+/// usable range, for several ranges, or when `If-Range` no longer matches.
+/// `Err` means the range lies past the end of the body.
+fn byte_range(
+    req: &HttpRequest,
+    len: u64,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = req
+        .header("Range")
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    if let Some(if_range) = req.header("If-Range").map(str::trim) {
+        let matches = if if_range.starts_with('"') {
+            etag == Some(if_range)
+        } else {
+            modified.is_some_and(|modified| http_date(modified) == if_range)
+        };
+        if !matches {
+            return Ok(None);
+        }
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    if first.is_empty() {
+        let Ok(suffix) = last.parse::<u64>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || len == 0 {
+            return Err(());
+        }
+        return Ok(Some((len.saturating_sub(suffix), len - 1)));
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return Ok(None);
+    };
+    let end = match last {
+        "" => u64::MAX,
+        last => match last.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return Ok(None),
+        },
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end.min(len - 1))))
+}
+
+/// Evaluates `If-None-Match` and, when it is absent, `If-Modified-Since`.
+pub fn is_not_modified(
+    req: &HttpRequest,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> bool {
+    if let Some(if_none_match) = req.header("If-None-Match") {
+        let Some(etag) = etag else {
+            return false;
+        };
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+    }
+    match (
+        req.header("If-Modified-Since").and_then(parse_http_date),
+        modified,
+    ) {
+        (Some(since), Some(modified)) => {
+            let secs = |time: SystemTime| {
+                time.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            };
+            secs(modified) <= secs(since)
+        }
+        _ => false,
+    }
+}
+
+fn not_acceptable(req: &HttpRequest, vary: &[&str]) -> HttpResponse {
+    let mut resp = HttpProcessor::create_status_response(req.version(), StatusCode::NOT_ACCEPTABLE);
+    resp.set_header("Vary", &vary.join(", "));
+    resp
+}
+
+fn error_for(req: &HttpRequest, e: &io::Error) -> HttpResponse {
+    let status = match e.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    HttpProcessor::create_status_response(req.version(), status)
+}
+
+/// The files a block chain ending in a `location` or `server` serves, if a
+/// `root` or `alias` applies to it.
+pub fn static_files(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<StaticFiles> {
+    let config = merged_config::<StaticConfig>(chain);
+    Some(
+        StaticFiles::new(&config, MimeConfig::for_chain(chain), pattern)?
+            .with_autoindex(merged_config::<AutoindexConfig>(chain))
+            .with_negotiation(&merged_config::<NegotiateConfig>(chain))
+            .with_open_file_cache(&merged_config::<OpenFileCacheConfig>(chain)),
+    )
+}
+
+/// Builds the file-serving handler for a block chain ending in a `location`
+/// or `server`, if a `root` or `alias` applies to it.
+pub fn static_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let files = Arc::new(static_files(chain, pattern)?);
+    Some(Box::new(move |req: &HttpRequest| files.serve(req)))
+}
+
+pub fn handle_root(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let root = ctx.path_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        if config.alias.is_some() {
+            return Err(ctx.invalid_value(&root.to_string_lossy(), "\"alias\" is already set"));
+        }
+        config.root = Some(root);
+    }
+    Ok(())
+}
+
+pub fn handle_alias(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let alias = ctx.path_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        if config.root.is_some() {
+            return Err(ctx.invalid_value(&alias.to_string_lossy(), "\"root\" is already set"));
+        }
+        config.alias = Some(alias);
+    }
+    Ok(())
+}
+
+pub fn handle_etag(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.etag = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_gzip_static(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.gzip_static = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_brotli_static(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.brotli_static = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_disable_symlinks(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let policy = match ctx.str_arg(0)?.as_str() {
+        "on" => SymlinkPolicy::On,
+        "if_not_owner" => SymlinkPolicy::IfNotOwner,
+        _ => SymlinkPolicy::Off,
+    };
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.disable_symlinks = Some(policy);
+    }
+    Ok(())
+}
+
+pub fn handle_sendfile(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.sendfile = Some(enabled);
+    }
+    Ok(())
+}
+
+pub fn handle_sendfile_max_chunk(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let max_chunk = ctx.size_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.sendfile_max_chunk = Some(max_chunk);
+    }
+    Ok(())
+}
+
+pub fn handle_index(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let files = ctx.args();
+    if files.is_empty() {
+        return Ok(());
+    }
+    if let Ok(mut config) = ctx.block_config::<StaticConfig>().lock() {
+        config.index = Some(files);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(format!("{} HTTP/1.1\r\n\r\n", line).as_bytes())
+            .unwrap();
+        req
+    }
+
+    #[test]
+    fn test_serve_root_and_alias() {
+        let dir = std::env::temp_dir().join(format!("blur-static-{}", std::process::id()));
+        fs::create_dir_all(dir.join("docs/empty")).unwrap();
+        fs::write(dir.join("docs/index.html"), "<h1>index</h1>").unwrap();
+        fs::write(dir.join("docs/a.txt"), "hello").unwrap();
+
+        let root = StaticFiles::new(
+            &StaticConfig {
+                root: Some(dir.clone()),
+                ..Default::default()
+            },
+            MimeConfig::default(),
+            None,
+        )
+        .unwrap();
+        let resp = root.serve(&request("GET /docs/a.txt"));
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.header_value("Content-Type"), Some("text/plain"));
+        assert_eq!(resp.body, b"hello");
+        assert_eq!(root.serve(&request("GET /docs/")).body, b"<h1>index</h1>");
+        assert_eq!(root.serve(&request("GET /docs")).status(), Some(301));
+        assert_eq!(root.serve(&request("GET /docs/empty/")).status(), Some(403));
+        assert_eq!(root.serve(&request("GET /missing")).status(), Some(404));
+        let mut escaping = request("GET /");
+        escaping.set_path("/../etc/passwd");
+        assert_eq!(root.serve(&escaping).status(), Some(400));
+        assert_eq!(root.serve(&request("POST /docs/a.txt")).status(), Some(405));
+
+        let head = root.serve(&request("HEAD /docs/a.txt"));
+        assert_eq!(head.header_value("Content-Length"), Some("5"));
+        assert!(head.body.is_empty());
+
+        let alias = StaticFiles::new(
+            &StaticConfig {
+                alias: Some(dir.join("docs")),
+                ..Default::default()
+            },
+            MimeConfig::default(),
+            Some(&LocationPattern::prefix("/files/")),
+        )
+        .unwrap();
+        assert_eq!(alias.serve(&request("GET /files/a.txt")).body, b"hello");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_precompressed_variants() {
+        let dir = std::env::temp_dir().join(format!("blur-precompressed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("app.js"), "plain").unwrap();
+        fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        fs::write(dir.join("app.js.br"), "brotli").unwrap();
+
+        let files = StaticFiles::new(
+            &StaticConfig {
+                root: Some(dir.clone()),
+                gzip_static: Some(true),
+                brotli_static: Some(true),
+                ..Default::default()
+            },
+            MimeConfig::default(),
+            None,
+        )
+        .unwrap();
+        let serve = |accept: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(
+                format!(
+                    "GET /app.js HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                    accept
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            files.serve(&req)
+        };
+
+        let resp = serve("gzip, deflate, br");
+        assert_eq!(resp.body, b"brotli");
+        assert_eq!(resp.header_value("Content-Encoding"), Some("br"));
+        assert_eq!(
+            resp.header_value("Content-Type"),
+            Some("application/javascript")
+        );
+        assert_eq!(resp.header_value("Vary"), Some("Accept-Encoding"));
+        assert_eq!(serve("gzip, br;q=0").body, b"gzipped");
+
+        let resp = serve("identity");
+        assert_eq!(resp.body, b"plain");
+        assert!(!resp.has_header("Content-Encoding"));
+        assert_eq!(resp.header_value("Vary"), Some("Accept-Encoding"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disable_symlinks() {
+        let dir = std::env::temp_dir().join(format!("blur-symlinks-{}", std::process::id()));
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/a.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+
+        let serve = |policy| {
+            let config = StaticConfig {
+                root: Some(dir.clone()),
+                disable_symlinks: Some(policy),
+                ..Default::default()
+            };
+            let files = StaticFiles::new(&config, MimeConfig::default(), None).unwrap();
+            files.serve(&request("GET /link/a.txt")).status()
+        };
+        assert_eq!(serve(SymlinkPolicy::Off), Some(200));
+        assert_eq!(serve(SymlinkPolicy::On), Some(403));
+        assert_eq!(serve(SymlinkPolicy::IfNotOwner), Some(200));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let etag = file_etag(modified, 5);
+        assert_eq!(etag, "\"6553f100-5\"");
+
+        let conditional = |header: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET / HTTP/1.1\r\n{}\r\n\r\n", header).as_bytes())
+                .unwrap();
+            is_not_modified(&req, Some(&etag), Some(modified))
+        };
+        assert!(conditional("If-None-Match: \"x\", W/\"6553f100-5\""));
+        assert!(conditional("If-None-Match: *"));
+        assert!(!conditional("If-None-Match: \"x\""));
+        assert!(conditional(&format!(
+            "If-Modified-Since: {}",
+            http_date(modified)
+        )));
+        assert!(!conditional(
+            "If-Modified-Since: Tue, 14 Nov 2023 22:13:19 GMT"
+        ));
+        assert!(!conditional("If-Modified-Since: garbage"));
+    }
+
+    #[test]
+    fn test_byte_ranges() {
+        let dir = std::env::temp_dir().join(format!("blur-ranges-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let config = StaticConfig {
+            root: Some(dir.clone()),
+            ..Default::default()
+        };
+        let files = StaticFiles::new(&config, MimeConfig::default(), None).unwrap();
+        let serve = |headers: &str| {
+            let mut req = HttpRequest::new();
+            req.parse(format!("GET /a.txt HTTP/1.1\r\n{}\r\n", headers).as_bytes())
+                .unwrap();
+            let mut resp = files.serve(&req);
+            resp.load_body().unwrap();
+            resp
+        };
+
+        let resp = serve("Range: bytes=2-4\r\n");
+        assert_eq!(resp.status(), Some(206));
+        assert_eq!(resp.header_value("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(resp.body, b"234");
+        assert_eq!(serve("Range: bytes=7-\r\n").body, b"789");
+        assert_eq!(serve("Range: bytes=-3\r\n").body, b"789");
+        assert_eq!(serve("Range: bytes=8-20\r\n").body, b"89");
+
+        let resp = serve("Range: bytes=10-\r\n");
+        assert_eq!(resp.status(), Some(416));
+        assert_eq!(resp.header_value("Content-Range"), Some("bytes */10"));
+
+        // Several ranges and a stale If-Range get the whole file.
+        let resp = serve("Range: bytes=0-1,4-5\r\n");
+        assert_eq!(resp.status(), Some(200));
+        assert_eq!(resp.header_value("Accept-Ranges"), Some("bytes"));
+        assert_eq!(resp.body, b"0123456789");
+        let resp = serve("Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n");
+        assert_eq!(resp.status(), Some(200));
+        let etag = resp.header_value("ETag").unwrap().to_string();
+        let resp = serve(&format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", etag));
+        assert_eq!(resp.body, b"01");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    io,
+    net::UdpSocket,
+    sync::{Arc, Mutex, Once, Weak},
+    thread,
+    time::Duration,
+};
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{Arity, CommandBuilder, ParameterBuilder},
+        config_context::ConfigContext,
+        config_loader::ConfigError,
+        units::parse_duration,
+    },
+    log_warn, register_commands,
+};
+
+use super::{http_response::HttpResponse, http_status::StatusSnapshot};
+
+register_commands!(CommandBuilder::new("statsd")
+    .allowed_parents(vec!["http".to_string()])
+    .is_unique()
+    .display_name("en", "StatsD")
+    .display_name("zh-tw", "StatsD 指標")
+    .desc(
+        "en",
+        "Sends request counters, latency timers and connection gauges to a StatsD or DogStatsD server over UDP"
+    )
+    .desc(
+        "zh-tw",
+        "以 UDP 將請求計數、延遲計時與連線數量送往 StatsD 或 DogStatsD 伺服器"
+    )
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Address")
+            .display_name("zh-tw", "位址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "host:port of the server; the port defaults to 8125")
+            .desc("zh-tw", "伺服器的 主機:連接埠，連接埠預設為 8125")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Options")
+            .display_name("zh-tw", "選項")
+            .type_name("String")
+            .default("")
+            .desc(
+                "en",
+                "prefix=name put before every metric (blur by default), tags=key:value,... added DogStatsD-style to every metric, interval=time between gauge reports and flushes (10s by default)"
+            )
+            .desc(
+                "zh-tw",
+                "prefix=名稱 為每個指標加上的前綴（預設 blur），tags=鍵:值,... 以 DogStatsD 格式附加於每個指標，interval=時間 為回報連線數量與送出的間隔（預設 10s）"
+            )
+            .build(),
+    ])
+    .arity(Arity::AtLeast(1))
+    .build(handle_statsd));
+
+const DEFAULT_PORT: u16 = 8125;
+const DEFAULT_PREFIX: &str = "blur";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Largest datagram sent, small enough not to be fragmented on most links.
+const MAX_PACKET: usize = 1432;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdSpec {
+    pub server: String,
+    pub prefix: String,
+    /// `key:value` pairs, or bare keys, sent with every metric.
+    pub tags: Vec<String>,
+    pub interval: Duration,
+}
+
+impl StatsdSpec {
+    /// Parses the arguments of `statsd`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (server, options) = args.split_first().ok_or("missing server address")?;
+        let server = match server.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => server.clone(),
+            _ => format!("{}:{}", server, DEFAULT_PORT),
+        };
+        let mut spec = Self {
+            server,
+            prefix: DEFAULT_PREFIX.to_string(),
+            tags: Vec::new(),
+            interval: DEFAULT_INTERVAL,
+        };
+        for option in options {
+            let Some((name, value)) = option.split_once('=') else {
+                return Err(format!("unknown option \"{}\"", option));
+            };
+            match name {
+                "prefix" => spec.prefix = value.trim_end_matches('.').to_string(),
+                "tags" => {
+                    spec.tags = value
+                        .split(',')
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                "interval" => {
+                    spec.interval = Some(parse_duration(value)?)
+                        .filter(|interval| !interval.is_zero())
+                        .ok_or("interval must be greater than zero")?
+                }
+                _ => return Err(format!("unknown option \"{}\"", name)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// Sends metrics to a StatsD server. Lines are collected into datagrams
+/// that go out when full and on every interval, along with the connection
+/// gauges.
+pub struct Statsd {
+    spec: StatsdSpec,
+    /// Appended to every line: the configured tags in DogStatsD form.
+    suffix: String,
+    socket: UdpSocket,
+    buffer: Mutex<String>,
+    reporter: Once,
+}
+
+impl Statsd {
+    pub fn new(spec: StatsdSpec) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(&spec.server)?;
+        let suffix = if spec.tags.is_empty() {
+            String::new()
+        } else {
+            format!("|#{}", spec.tags.join(","))
+        };
+        Ok(Self {
+            spec,
+            suffix,
+            socket,
+            buffer: Mutex::new(String::new()),
+            reporter: Once::new(),
+        })
+    }
+
+    /// Starts reporting gauges and flushing every interval.
+    pub fn start(self: &Arc<Self>) {
+        self.reporter.call_once(|| {
+            let weak: Weak<Self> = Arc::downgrade(self);
+            let interval = self.spec.interval;
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                let Some(statsd) = weak.upgrade() else {
+                    break;
+                };
+                statsd.report_connections();
+                statsd.flush();
+            });
+        });
+    }
+
+    /// Records a response sent `elapsed` after its request began.
+    pub fn record(&self, resp: &HttpResponse, elapsed: Duration) {
+        self.metric("requests", "1", "c");
+        if let Some(status) = resp.status() {
+            self.metric(&format!("responses.{}xx", status / 100), "1", "c");
+        }
+        self.metric("request_time", &millis(elapsed), "ms");
+        if let Some(response) = resp.upstream.last().and_then(|u| u.response_time()) {
+            self.metric("upstream_response_time", &millis(response), "ms");
+        }
+    }
+
+    fn report_connections(&self) {
+        let snapshot = StatusSnapshot::now();
+        for (name, value) in [
+            ("connections.active", snapshot.active),
+            ("connections.reading", snapshot.reading),
+            ("connections.writing", snapshot.writing),
+            ("connections.waiting", snapshot.waiting),
+        ] {
+            self.metric(name, &value.to_string(), "g");
+        }
+    }
+
+    fn metric(&self, name: &str, value: &str, kind: &str) {
+        let line = format!(
+            "{}.{}:{}|{}{}",
+            self.spec.prefix, name, value, kind, self.suffix
+        );
+        let Ok(mut buffer) = self.buffer.lock() else {
+            return;
+        };
+        if !buffer.is_empty() && buffer.len() + 1 + line.len() > MAX_PACKET {
+            self.send(&buffer);
+            buffer.clear();
+        }
+        if !buffer.is_empty() {
+            buffer.push('\n');
+        }
+        buffer.push_str(&line);
+    }
+
+    pub fn flush(&self) {
+        let Ok(mut buffer) = self.buffer.lock() else {
+            return;
+        };
+        if !buffer.is_empty() {
+            self.send(&buffer);
+            buffer.clear();
+        }
+    }
+
+    fn send(&self, packet: &str) {
+        // Nothing listening yet is normal for StatsD, so only real errors
+        // are reported.
+        match self.socket.send(packet.as_bytes()) {
+            Err(e) if e.kind() != io::ErrorKind::ConnectionRefused => {
+                log_warn!("cannot send metrics to {}: {}", self.spec.server, e)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+/// Finds the StatsD client set up by the http block, starting its reports.
+pub fn statsd(http_config: &ConfigContext) -> Option<Arc<Statsd>> {
+    let statsd = http_config.store.get::<Statsd>()?;
+    statsd.start();
+    Some(statsd)
+}
+
+pub fn handle_statsd(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    if args.is_empty() {
+        return Ok(());
+    }
+    let spec =
+        StatsdSpec::parse(&args).map_err(|reason| ctx.invalid_value(&args.join(" "), reason))?;
+    let statsd = Statsd::new(spec).map_err(|e| ctx.invalid_value(&args[0], e.to_string()))?;
+    ctx.store.insert(Arc::new(statsd));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{StatusCode, Version};
+
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_batched_with_tags() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let args = [
+            server.local_addr().unwrap().to_string(),
+            "prefix=edge.".to_string(),
+            "tags=env:prod,canary".to_string(),
+        ];
+        let statsd = Statsd::new(StatsdSpec::parse(&args).unwrap()).unwrap();
+
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(Version::HTTP_11, StatusCode::NOT_FOUND);
+        statsd.record(&resp, Duration::from_micros(12_500));
+        statsd.flush();
+
+        let mut buf = [0; MAX_PACKET];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..n]).unwrap(),
+            "edge.requests:1|c|#env:prod,canary\nedge.responses.4xx:1|c|#env:prod,canary\nedge.request_time:12.500|ms|#env:prod,canary"
+        );
+
+        assert_eq!(
+            StatsdSpec::parse(&["localhost".to_string()])
+                .unwrap()
+                .server,
+            "localhost:8125"
+        );
+        assert!(StatsdSpec::parse(&["localhost".to_string(), "sample=1".to_string()]).is_err());
+    }
+}
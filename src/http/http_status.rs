@@ -0,0 +1,259 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use http::{Method, StatusCode};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::HttpHandler,
+    },
+    register_commands,
+};
+
+use super::{http_request::HttpRequest, http_response::HttpResponse};
+
+register_commands!(CommandBuilder::new("stub_status")
+    .allowed_parents(vec!["location".to_string()])
+    .display_name("en", "Stub Status")
+    .display_name("zh-tw", "基本狀態")
+    .desc(
+        "en",
+        "Sets whether the location answers with the server's connection and request counters"
+    )
+    .desc("zh-tw", "設定 location 是否回應伺服器的連線與請求計數")
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Enabled")
+        .display_name("zh-tw", "啟用")
+        .arg_type(ArgType::Bool)
+        .is_required(true)
+        .default("")
+        .desc("en", "on, or off (the default)")
+        .desc("zh-tw", "on，或 off（預設）")
+        .build()])
+    .build(handle_stub_status));
+
+static ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static HANDLED: AtomicU64 = AtomicU64::new(0);
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+static READING: AtomicU64 = AtomicU64::new(0);
+static WRITING: AtomicU64 = AtomicU64::new(0);
+
+/// The server's counters at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusSnapshot {
+    pub active: u64,
+    pub accepted: u64,
+    pub handled: u64,
+    pub requests: u64,
+    pub reading: u64,
+    pub writing: u64,
+    /// Open connections idle between requests.
+    pub waiting: u64,
+}
+
+impl StatusSnapshot {
+    pub fn now() -> Self {
+        let active = ACTIVE.load(Ordering::Relaxed);
+        let reading = READING.load(Ordering::Relaxed);
+        let writing = WRITING.load(Ordering::Relaxed);
+        Self {
+            active,
+            accepted: ACCEPTED.load(Ordering::Relaxed),
+            handled: HANDLED.load(Ordering::Relaxed),
+            requests: REQUESTS.load(Ordering::Relaxed),
+            reading,
+            writing,
+            waiting: active.saturating_sub(reading + writing),
+        }
+    }
+
+    /// The page in the format nginx's stub_status writes, which exporters
+    /// and monitoring scripts already parse.
+    pub fn render(&self) -> String {
+        format!(
+            "Active connections: {} \nserver accepts handled requests\n {} {} {} \nReading: {} Writing: {} Waiting: {} \n",
+            self.active,
+            self.accepted,
+            self.handled,
+            self.requests,
+            self.reading,
+            self.writing,
+            self.waiting
+        )
+    }
+}
+
+/// A connection counted as active from when it is accepted until dropped.
+pub struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub fn accept() -> Self {
+        ACCEPTED.fetch_add(1, Ordering::Relaxed);
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+
+    /// Counts the connection as handled, once a worker has picked it up.
+    pub fn handle(&self) {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Waiting,
+    Reading,
+    Writing,
+}
+
+/// What a connection is doing right now; it goes back to waiting when
+/// dropped.
+pub struct ConnectionActivity {
+    current: Activity,
+}
+
+impl ConnectionActivity {
+    pub fn new() -> Self {
+        Self {
+            current: Activity::Waiting,
+        }
+    }
+
+    pub fn set(&mut self, activity: Activity) {
+        if activity == self.current {
+            return;
+        }
+        if let Some(counter) = counter(self.current) {
+            counter.fetch_sub(1, Ordering::Relaxed);
+        }
+        if let Some(counter) = counter(activity) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        self.current = activity;
+    }
+}
+
+impl Default for ConnectionActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConnectionActivity {
+    fn drop(&mut self) {
+        self.set(Activity::Waiting);
+    }
+}
+
+fn counter(activity: Activity) -> Option<&'static AtomicU64> {
+    match activity {
+        Activity::Waiting => None,
+        Activity::Reading => Some(&READING),
+        Activity::Writing => Some(&WRITING),
+    }
+}
+
+/// Counts a request read from a client.
+pub fn count_request() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StubStatusConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for StubStatusConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Builds the handler for a location block that sets `stub_status on`.
+pub fn status_handler(block: &ConfigContext) -> Option<HttpHandler> {
+    if !merged_config::<StubStatusConfig>(&[block])
+        .enabled
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    Some(Box::new(|req: &HttpRequest| {
+        let mut resp = HttpResponse::new();
+        if *req.method() != Method::GET && *req.method() != Method::HEAD {
+            resp.set_status_line(*req.version(), StatusCode::METHOD_NOT_ALLOWED);
+            resp.set_header("Allow", "GET, HEAD");
+            resp.set_header("Content-Length", "0");
+            return resp;
+        }
+        let body = StatusSnapshot::now().render();
+        resp.set_status_line(*req.version(), StatusCode::OK);
+        resp.set_header("Content-Type", "text/plain");
+        resp.set_header("Cache-Control", "no-cache");
+        if *req.method() == Method::HEAD {
+            resp.set_header("Content-Length", &body.len().to_string());
+        } else {
+            resp.set_body(&body);
+        }
+        resp
+    }))
+}
+
+pub fn handle_stub_status(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<StubStatusConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_counters() {
+        let before = StatusSnapshot::now();
+        let conn = ActiveConnection::accept();
+        conn.handle();
+        let mut activity = ConnectionActivity::new();
+        activity.set(Activity::Reading);
+        count_request();
+        activity.set(Activity::Writing);
+
+        let during = StatusSnapshot::now();
+        assert!(during.accepted > before.accepted);
+        assert!(during.handled > before.handled);
+        assert!(during.requests > before.requests);
+        assert!(during.active >= 1 && during.writing >= 1);
+
+        drop(activity);
+        drop(conn);
+        let page = StatusSnapshot {
+            active: 2,
+            accepted: 10,
+            handled: 10,
+            requests: 31,
+            reading: 0,
+            writing: 1,
+            waiting: 1,
+        }
+        .render();
+        assert_eq!(
+            page,
+            "Active connections: 2 \nserver accepts handled requests\n 10 10 31 \nReading: 0 Writing: 1 Waiting: 1 \n"
+        );
+    }
+}
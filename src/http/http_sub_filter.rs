@@ -0,0 +1,575 @@
+use std::io::{self, Read};
+
+use http::Method;
+use regex::bytes::{Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::ResponseFilter,
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_response::{HttpResponse, StreamBody},
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(
+    CommandBuilder::new("sub_filter")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Substitute in Body")
+        .display_name("zh-tw", "替換回應內容")
+        .desc(
+            "en",
+            "Replaces a string or regular expression in response bodies of the sub_filter_types"
+        )
+        .desc(
+            "zh-tw",
+            "替換 sub_filter_types 類型回應內容中的字串或正規表示式"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Pattern")
+                .display_name("zh-tw", "樣式")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "A string matched case-insensitively, or ~regex / ~*regex matched within a line"
+                )
+                .desc(
+                    "zh-tw",
+                    "不分大小寫比對的字串，或在單行內比對的 ~regex / ~*regex"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Replacement")
+                .display_name("zh-tw", "替換內容")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "The text put in its place, which may contain variables")
+                .desc("zh-tw", "取代的文字，可包含變數")
+                .build(),
+        ])
+        .arity(Arity::Exact(2))
+        .build(handle_sub_filter),
+    CommandBuilder::new("sub_filter_types")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Substitution Types")
+        .display_name("zh-tw", "替換類型")
+        .desc(
+            "en",
+            "Sets the MIME types sub_filter applies to in addition to text/html"
+        )
+        .desc(
+            "zh-tw",
+            "設定除了 text/html 之外 sub_filter 適用的 MIME 類型"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "MIME Types")
+            .display_name("zh-tw", "MIME 類型")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "One or more MIME types, e.g. application/json, or * for any type"
+            )
+            .desc(
+                "zh-tw",
+                "一個或多個 MIME 類型，例如 application/json，或以 * 表示所有類型"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_sub_filter_types),
+    CommandBuilder::new("sub_filter_once")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Substitute Once")
+        .display_name("zh-tw", "只替換一次")
+        .desc(
+            "en",
+            "Sets whether each sub_filter pattern is replaced only where it first occurs"
+        )
+        .desc(
+            "zh-tw",
+            "設定每個 sub_filter 樣式是否只替換第一次出現的位置"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to on")
+            .desc("zh-tw", "on 或 off，預設為 on")
+            .build()])
+        .build(handle_sub_filter_once),
+    CommandBuilder::new("sub_filter_last_modified")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Keep Last-Modified")
+        .display_name("zh-tw", "保留 Last-Modified")
+        .desc(
+            "en",
+            "Sets whether substituted responses keep their Last-Modified header"
+        )
+        .desc("zh-tw", "設定替換後的回應是否保留 Last-Modified 標頭")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enabled")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_sub_filter_last_modified)
+);
+
+/// How much of a line without an end regular expressions wait for before
+/// it is passed on unmatched.
+const MAX_LINE: usize = 64 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SubRule {
+    /// The pattern as a regular expression source.
+    pattern: String,
+    /// Whether the pattern is a regular expression, which is matched within
+    /// a line so bodies can be streamed.
+    is_regex: bool,
+    /// The length of a plain string pattern.
+    len: usize,
+    replacement: VarTemplate,
+}
+
+impl SubRule {
+    pub fn parse(pattern: &str, replacement: &str) -> Result<Self, String> {
+        let regex = match pattern.strip_prefix("~*") {
+            Some(regex) => Some(format!("(?i:{})", regex)),
+            None => pattern
+                .strip_prefix('~')
+                .map(|regex| format!("(?:{})", regex)),
+        };
+        let rule = match regex {
+            Some(regex) => Self {
+                pattern: regex,
+                is_regex: true,
+                len: 0,
+                replacement: VarTemplate::parse(replacement),
+            },
+            None if pattern.is_empty() => return Err("the pattern must not be empty".into()),
+            None => Self {
+                pattern: format!("(?i:{})", regex::escape(pattern)),
+                is_regex: false,
+                len: pattern.len(),
+                replacement: VarTemplate::parse(replacement),
+            },
+        };
+        Regex::new(&rule.pattern).map_err(|e| e.to_string())?;
+        Ok(rule)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SubFilterConfig {
+    pub rules: Option<Vec<SubRule>>,
+    pub types: Option<Vec<String>>,
+    pub once: Option<bool>,
+    pub last_modified: Option<bool>,
+}
+
+impl MergeConfig for SubFilterConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.rules.is_none() {
+            self.rules = parent.rules.clone();
+        }
+        if self.types.is_none() {
+            self.types = parent.types.clone();
+        }
+        if self.once.is_none() {
+            self.once = parent.once;
+        }
+        if self.last_modified.is_none() {
+            self.last_modified = parent.last_modified;
+        }
+    }
+}
+
+/// Replaces the patterns in a body passed through in pieces.
+struct Substituter {
+    regex: Regex,
+    /// The capture group of each pattern.
+    groups: Vec<usize>,
+    replacements: Vec<Vec<u8>>,
+    /// Which patterns have been replaced, for `sub_filter_once`.
+    done: Vec<bool>,
+    once: bool,
+    /// Whether a regular expression is among the patterns.
+    by_line: bool,
+    /// The longest plain string pattern.
+    max_len: usize,
+}
+
+impl Substituter {
+    /// Writes the substituted start of `input` to `out` and returns how much
+    /// of it was consumed; the rest may hold the start of a match and is
+    /// passed again with more input, or with `last` at the end of the body.
+    fn process(&mut self, input: &[u8], last: bool, out: &mut Vec<u8>) -> usize {
+        let mut boundary = input.len().saturating_sub(self.max_len.saturating_sub(1));
+        if self.by_line {
+            boundary = match input.iter().rposition(|b| *b == b'\n') {
+                Some(end) => boundary.min(end + 1),
+                None if input.len() >= MAX_LINE => boundary,
+                None => 0,
+            };
+        }
+        if last {
+            boundary = input.len();
+        }
+        let mut pos = 0;
+        for caps in self.regex.captures_iter(input) {
+            let Some(found) = caps.get(0) else {
+                continue;
+            };
+            if found.start() >= boundary {
+                break;
+            }
+            if found.is_empty() {
+                continue;
+            }
+            let rule = self
+                .groups
+                .iter()
+                .position(|group| caps.get(*group).is_some())
+                .unwrap_or_default();
+            out.extend_from_slice(&input[pos..found.start()]);
+            if self.once && self.done[rule] {
+                out.extend_from_slice(found.as_bytes());
+            } else {
+                out.extend_from_slice(&self.replacements[rule]);
+                self.done[rule] = true;
+            }
+            pos = found.end();
+        }
+        let consumed = pos.max(boundary);
+        out.extend_from_slice(&input[pos..consumed]);
+        consumed
+    }
+}
+
+/// Reads the substituted form of another reader.
+struct SubstitutingReader {
+    source: Box<dyn Read + Send>,
+    substituter: Substituter,
+    pending: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    eof: bool,
+}
+
+impl Read for SubstitutingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.written < self.output.len() {
+                let n = buf.len().min(self.output.len() - self.written);
+                buf[..n].copy_from_slice(&self.output[self.written..self.written + n]);
+                self.written += n;
+                return Ok(n);
+            }
+            if self.eof {
+                return Ok(0);
+            }
+            self.output.clear();
+            self.written = 0;
+            let start = self.pending.len();
+            self.pending.resize(start + READ_CHUNK, 0);
+            let n = match self.source.read(&mut self.pending[start..]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => {
+                    self.pending.truncate(start);
+                    return Err(e);
+                }
+            };
+            self.pending.truncate(start + n);
+            self.eof = n == 0;
+            let consumed = self
+                .substituter
+                .process(&self.pending, self.eof, &mut self.output);
+            self.pending.drain(..consumed);
+        }
+    }
+}
+
+/// Rewrites strings in response bodies. Bodies read from files or upstream
+/// streams are substituted as they are sent, in chunked framing.
+pub struct SubFilter {
+    rules: Vec<SubRule>,
+    regex: Regex,
+    groups: Vec<usize>,
+    types: Vec<String>,
+    once: bool,
+    last_modified: bool,
+}
+
+impl SubFilter {
+    fn applies_to(&self, req: &HttpRequest, resp: &HttpResponse) -> bool {
+        if *req.method() == Method::HEAD
+            || resp.has_header("Content-Encoding")
+            || resp.status().is_some_and(|status| {
+                status < 200 || status == 204 || status == 206 || status == 304
+            })
+        {
+            return false;
+        }
+        let content_type = resp
+            .header_value("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        content_type == "text/html"
+            || self
+                .types
+                .iter()
+                .any(|t| t == "*" || t.eq_ignore_ascii_case(&content_type))
+    }
+
+    fn substituter(&self, req: &HttpRequest) -> Substituter {
+        let vars = RequestVariables::new(req);
+        Substituter {
+            regex: self.regex.clone(),
+            groups: self.groups.clone(),
+            replacements: self
+                .rules
+                .iter()
+                .map(|rule| rule.replacement.render(&vars).into_bytes())
+                .collect(),
+            done: vec![false; self.rules.len()],
+            once: self.once,
+            by_line: self.rules.iter().any(|rule| rule.is_regex),
+            max_len: self.rules.iter().map(|rule| rule.len).max().unwrap_or(0),
+        }
+    }
+}
+
+impl ResponseFilter for SubFilter {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if !self.applies_to(req, resp) {
+            return;
+        }
+        let mut substituter = self.substituter(req);
+        if resp.file.is_none() && resp.stream.is_none() {
+            let body = std::mem::take(&mut resp.body);
+            substituter.process(&body, true, &mut resp.body);
+        } else {
+            let (source, _) = resp.take_body_reader();
+            let reader = SubstitutingReader {
+                source,
+                substituter,
+                pending: Vec::new(),
+                output: Vec::new(),
+                written: 0,
+                eof: false,
+            };
+            resp.set_body_stream(StreamBody::new(reader, None));
+        }
+        resp.remove_header("Content-Length");
+        resp.remove_header("Accept-Ranges");
+        if !self.last_modified {
+            resp.remove_header("Last-Modified");
+        }
+        if let Some(etag) = resp.header_value("ETag").map(str::to_string) {
+            if !etag.starts_with("W/") {
+                resp.remove_header("ETag");
+                resp.set_header("ETag", &format!("W/{}", etag));
+            }
+        }
+    }
+}
+
+/// Builds the substitution filter for a block chain with `sub_filter` rules.
+pub fn sub_filter(chain: &[&ConfigContext]) -> Option<SubFilter> {
+    let config = merged_config::<SubFilterConfig>(chain);
+    let rules = config.rules.filter(|rules| !rules.is_empty())?;
+    let pattern = rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| format!("(?P<s{}>{})", i, rule.pattern))
+        .collect::<Vec<_>>()
+        .join("|");
+    let regex = RegexBuilder::new(&pattern)
+        .size_limit(64 << 20)
+        .build()
+        .ok()?;
+    let groups = (0..rules.len())
+        .map(|i| {
+            let name = format!("s{}", i);
+            regex
+                .capture_names()
+                .position(|group| group == Some(name.as_str()))
+                .unwrap_or_default()
+        })
+        .collect();
+    Some(SubFilter {
+        rules,
+        regex,
+        groups,
+        types: config.types.unwrap_or_default(),
+        once: config.once.unwrap_or(true),
+        last_modified: config.last_modified.unwrap_or(false),
+    })
+}
+
+pub fn handle_sub_filter(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let pattern = ctx.current_cmd_args.first().cloned().unwrap_or_default();
+    let replacement = ctx.current_cmd_args.get(1).cloned().unwrap_or_default();
+    let rule = SubRule::parse(&pattern, &replacement)
+        .map_err(|reason| ctx.invalid_value(&pattern, reason))?;
+    if let Ok(mut config) = ctx.block_config::<SubFilterConfig>().lock() {
+        config.rules.get_or_insert_with(Vec::new).push(rule);
+    }
+    Ok(())
+}
+
+pub fn handle_sub_filter_types(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let types = ctx.args();
+    if types.is_empty() {
+        return Ok(());
+    }
+    if let Ok(mut config) = ctx.block_config::<SubFilterConfig>().lock() {
+        config.types = Some(types);
+    }
+    Ok(())
+}
+
+pub fn handle_sub_filter_once(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let once = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SubFilterConfig>().lock() {
+        config.once = Some(once);
+    }
+    Ok(())
+}
+
+pub fn handle_sub_filter_last_modified(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let last_modified = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<SubFilterConfig>().lock() {
+        config.last_modified = Some(last_modified);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{StatusCode, Version};
+
+    use super::*;
+
+    fn filter(rules: &[(&str, &str)], once: bool) -> SubFilter {
+        let mut ctx = ConfigContext::new_empty("location", Vec::new());
+        if let Ok(mut config) = ctx.block_config::<SubFilterConfig>().lock() {
+            config.rules = Some(
+                rules
+                    .iter()
+                    .map(|(pattern, replacement)| SubRule::parse(pattern, replacement).unwrap())
+                    .collect(),
+            );
+            config.once = Some(once);
+        }
+        sub_filter(&[&ctx]).unwrap()
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        let mut resp = HttpResponse::new();
+        resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+        resp.set_header("Content-Type", "text/html; charset=utf-8");
+        resp.set_header("Content-Length", &body.len().to_string());
+        resp.set_body(body);
+        resp
+    }
+
+    /// Hands out its content a few bytes at a time, to split matches.
+    struct Trickle(Vec<u8>, usize);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len() - self.1);
+            buf[..n].copy_from_slice(&self.0[self.1..self.1 + n]);
+            self.1 += n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_sub_filter_rewrites_bodies() {
+        let mut req = HttpRequest::new();
+        req.parse(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let body = "<a href=\"HTTP://internal/a\">http://internal/b</a>\n<p>id=42</p>\n";
+
+        let mut resp = response(body);
+        filter(&[("http://internal", "https://$host")], true).filter(&req, &mut resp);
+        let expected = "<a href=\"https://example.com/a\">http://internal/b</a>\n<p>id=42</p>\n";
+        assert_eq!(String::from_utf8_lossy(&resp.body), expected);
+        assert!(!resp.has_header("Content-Length"));
+
+        let all = filter(&[("http://internal", "/"), ("~id=\\d+", "id=*")], false);
+        let mut resp = response("");
+        resp.set_body_stream(StreamBody::new(Trickle(body.as_bytes().to_vec(), 0), None));
+        all.filter(&req, &mut resp);
+        assert!(resp.is_chunked());
+        let mut out = Vec::new();
+        resp.stream.unwrap().copy_to(&mut out, false).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "<a href=\"//a\">//b</a>\n<p>id=*</p>\n"
+        );
+
+        let mut resp = response(body);
+        resp.remove_header("Content-Type");
+        resp.set_header("Content-Type", "application/json");
+        all.filter(&req, &mut resp);
+        assert_eq!(String::from_utf8_lossy(&resp.body), body);
+    }
+}
@@ -0,0 +1,1486 @@
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::StatusCode;
+use rustls::{ClientConnection, StreamOwned};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    core::config::{
+        command::{CommandBuilder, ParameterBuilder},
+        config_context::ConfigContext,
+        config_loader::ConfigError,
+        units::{format_duration, parse_duration},
+    },
+    log_notice, log_warn, register_commands,
+};
+
+use super::{
+    http_request::HttpRequest,
+    http_split_clients::murmur_hash2,
+    http_variables::{RequestVariables, VarTemplate},
+};
+
+register_commands!(CommandBuilder::new("upstream")
+    .is_raw_block()
+    .allowed_parents(vec!["http".to_string()])
+    .display_name("en", "Upstream")
+    .display_name("zh-tw", "上游伺服器群組")
+    .desc(
+        "en",
+        "Defines a named group of servers that proxy_pass balances requests across"
+    )
+    .desc("zh-tw", "定義一組具名的伺服器，供 proxy_pass 分配請求")
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Name")
+        .display_name("zh-tw", "名稱")
+        .type_name("String")
+        .is_required(true)
+        .default("")
+        .desc(
+            "en",
+            "Group name, referenced as proxy_pass http://name; entries are server address [weight=N] [max_fails=N] [fail_timeout=time] [down], optionally one of least_conn, ip_hash, hash key [consistent] or random [two [least_conn]], sticky cookie name [expires=time] [domain=domain] [path=path] [httponly] [secure], keepalive N with keepalive_timeout and keepalive_requests, circuit_breaker [failures=N] [cooldown=time] [probes=N] [status=code], and state file, which keeps servers changed at runtime across restarts"
+        )
+        .desc(
+            "zh-tw",
+            "群組名稱，以 proxy_pass http://名稱 引用；項目格式為 server 位址 [weight=N] [max_fails=N] [fail_timeout=時間] [down]，可選擇 least_conn、ip_hash、hash 鍵值 [consistent] 或 random [two [least_conn]] 其中之一、sticky cookie 名稱 [expires=時間] [domain=網域] [path=路徑] [httponly] [secure]、keepalive 數量搭配 keepalive_timeout 與 keepalive_requests，circuit_breaker [failures=N] [cooldown=時間] [probes=N] [status=狀態碼]，以及 state 檔案，在重新啟動後保留執行期間變更的伺服器"
+        )
+        .build()])
+    .build(handle_upstream));
+
+pub const DEFAULT_MAX_FAILS: u32 = 1;
+pub const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamServer {
+    pub host: String,
+    pub port: u16,
+    pub weight: u32,
+    /// Failures within `fail_timeout` that take the server out of rotation;
+    /// zero disables the accounting.
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+    /// Kept in the group but never picked.
+    pub down: bool,
+}
+
+impl UpstreamServer {
+    pub fn new(host: &str, port: u16) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            weight: 1,
+            max_fails: DEFAULT_MAX_FAILS,
+            fail_timeout: DEFAULT_FAIL_TIMEOUT,
+            down: false,
+        }
+    }
+
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (address, options) = args
+            .split_first()
+            .ok_or_else(|| "missing server address".to_string())?;
+        let (host, port) = split_host_port(address, 80)?;
+        let mut server = Self::new(host, port);
+        for option in options {
+            if option == "down" {
+                server.down = true;
+                continue;
+            }
+            match option.split_once('=') {
+                Some(("weight", value)) => {
+                    server.weight = value
+                        .parse()
+                        .ok()
+                        .filter(|weight| *weight > 0)
+                        .ok_or_else(|| format!("invalid weight \"{}\"", value))?;
+                }
+                Some(("max_fails", value)) => {
+                    server.max_fails = value
+                        .parse()
+                        .map_err(|_| format!("invalid max_fails \"{}\"", value))?;
+                }
+                Some(("fail_timeout", value)) => {
+                    server.fail_timeout = parse_duration(value)?;
+                }
+                _ => return Err(format!("unknown server option \"{}\"", option)),
+            }
+        }
+        Ok(server)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Writes the server the way `UpstreamServer::parse` reads it.
+impl fmt::Display for UpstreamServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} weight={} max_fails={} fail_timeout={}",
+            self.address(),
+            self.weight,
+            self.max_fails,
+            format_duration(self.fail_timeout)
+        )?;
+        if self.down {
+            write!(f, " down")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `host[:port]`, defaulting the port to 80.
+pub fn split_host_port(authority: &str, default_port: u16) -> Result<(&str, u16), String> {
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port \"{}\"", port))?,
+        ),
+        _ => (authority, default_port),
+    };
+    if host.is_empty() {
+        return Err("missing upstream host".to_string());
+    }
+    Ok((host, port))
+}
+
+/// How an upstream picks the server for each request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Balancer {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    /// Keeps a client on one server, keyed by the first three octets of an
+    /// IPv4 address or the whole IPv6 address.
+    IpHash,
+    Hash {
+        key: VarTemplate,
+        consistent: bool,
+    },
+    /// Picks a random server, or the less busy of two random servers.
+    Random {
+        two: bool,
+    },
+}
+
+impl Balancer {
+    pub fn parse(directive: &str, args: &[String]) -> Result<Self, String> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match (directive, args.as_slice()) {
+            ("least_conn", []) => Ok(Self::LeastConn),
+            ("ip_hash", []) => Ok(Self::IpHash),
+            ("hash", [key]) => Ok(Self::Hash {
+                key: VarTemplate::parse(key),
+                consistent: false,
+            }),
+            ("hash", [key, "consistent"]) => Ok(Self::Hash {
+                key: VarTemplate::parse(key),
+                consistent: true,
+            }),
+            ("random", []) => Ok(Self::Random { two: false }),
+            ("random", ["two"] | ["two", "least_conn"]) => Ok(Self::Random { two: true }),
+            ("least_conn" | "ip_hash" | "hash" | "random", _) => {
+                Err(format!("invalid arguments for \"{}\"", directive))
+            }
+            _ => Err("expected server, least_conn, ip_hash, hash or random".to_string()),
+        }
+    }
+}
+
+/// Session affinity set with `sticky cookie`: a cookie names the server a
+/// client was sent to, and later requests carrying it go back there while
+/// the server is available.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StickyCookie {
+    pub name: String,
+    /// How long the cookie lasts; a session cookie without one.
+    pub expires: Option<Duration>,
+    pub domain: Option<String>,
+    pub path: String,
+    pub httponly: bool,
+    pub secure: bool,
+}
+
+impl StickyCookie {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let (name, options) = match args {
+            [kind, name, options @ ..] if kind == "cookie" => (name, options),
+            _ => return Err("expected sticky cookie name".to_string()),
+        };
+        let mut sticky = Self {
+            name: name.clone(),
+            expires: None,
+            domain: None,
+            path: "/".to_string(),
+            httponly: false,
+            secure: false,
+        };
+        for option in options {
+            match (option.as_str(), option.split_once('=')) {
+                ("httponly", _) => sticky.httponly = true,
+                ("secure", _) => sticky.secure = true,
+                (_, Some(("expires", value))) => sticky.expires = Some(parse_duration(value)?),
+                (_, Some(("domain", value))) => sticky.domain = Some(value.to_string()),
+                (_, Some(("path", value))) => sticky.path = value.to_string(),
+                _ => return Err(format!("unknown sticky option \"{}\"", option)),
+            }
+        }
+        Ok(sticky)
+    }
+
+    /// The route the cookie sent with `req` names, if any.
+    fn route<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        req.header("Cookie")?.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == self.name).then_some(value)
+        })
+    }
+
+    fn set_cookie(&self, route: &str) -> String {
+        let mut cookie = format!("{}={}; Path={}", self.name, route, self.path);
+        if let Some(expires) = self.expires {
+            cookie.push_str(&format!("; Max-Age={}", expires.as_secs()));
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        if self.httponly {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+/// The value a sticky cookie holds for `server`, stable across restarts and
+/// changes to the rest of the group.
+fn sticky_route(server: &UpstreamServer) -> String {
+    format!("{:08x}", murmur_hash2(server.address().as_bytes()))
+}
+
+pub const DEFAULT_CIRCUIT_FAILURES: u32 = 5;
+pub const DEFAULT_CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Circuit breaking set with `circuit_breaker`: after `failures` failed
+/// attempts in a row across the whole group, requests are answered with
+/// `status` for `cooldown` without trying any server, then up to `probes`
+/// requests are let through to decide whether the group has recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    pub failures: u32,
+    pub cooldown: Duration,
+    pub probes: u32,
+    pub status: StatusCode,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failures: DEFAULT_CIRCUIT_FAILURES,
+            cooldown: DEFAULT_CIRCUIT_COOLDOWN,
+            probes: 1,
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut breaker = Self::default();
+        let count = |name: &str, value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| format!("invalid {} \"{}\"", name, value))
+        };
+        for option in args {
+            match option.split_once('=') {
+                Some(("failures", value)) => breaker.failures = count("failures", value)?,
+                Some(("cooldown", value)) => breaker.cooldown = parse_duration(value)?,
+                Some(("probes", value)) => breaker.probes = count("probes", value)?,
+                Some(("status", value)) => {
+                    breaker.status = value
+                        .parse::<u16>()
+                        .ok()
+                        .and_then(|code| StatusCode::from_u16(code).ok())
+                        .filter(StatusCode::is_server_error)
+                        .ok_or_else(|| format!("invalid status \"{}\"", value))?;
+                }
+                _ => return Err(format!("unknown circuit_breaker option \"{}\"", option)),
+            }
+        }
+        Ok(breaker)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    #[default]
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// Probing since `since`, with `admitted` requests let through.
+    HalfOpen {
+        since: Instant,
+        admitted: u32,
+    },
+}
+
+/// Hash points each server gets on the consistent hashing ring per unit of
+/// weight.
+const RING_POINTS: u32 = 160;
+/// Rehashes tried before a hash balancer falls back to round-robin.
+const HASH_TRIES: u8 = 20;
+
+#[derive(Debug, Default)]
+struct PeerState {
+    current_weight: i64,
+    active: u32,
+    fails: u32,
+    /// When the last failure was counted.
+    checked: Option<Instant>,
+    /// Taken out of the group at runtime. The slot is kept so server ids and
+    /// the indices held by peers in flight stay valid.
+    removed: bool,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    servers: Vec<UpstreamServer>,
+    peers: Vec<PeerState>,
+    /// Sorted points of the consistent hashing ring and the server owning each.
+    ring: Vec<(u32, usize)>,
+    rng: u64,
+    circuit: CircuitState,
+    /// Failed attempts in a row across all servers.
+    failures: u32,
+}
+
+impl PoolState {
+    /// xorshift64, plenty for spreading load.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+
+    fn build_ring(&mut self) {
+        self.ring.clear();
+        for (index, server) in self.servers.iter().enumerate() {
+            if self.peers[index].removed {
+                continue;
+            }
+            for point in 0..RING_POINTS * server.weight {
+                let key = format!("{}-{}", server.address(), point);
+                self.ring.push((murmur_hash2(key.as_bytes()), index));
+            }
+        }
+        self.ring.sort_unstable();
+    }
+
+    /// The weight of the server at `index`, zero once it has been removed.
+    fn weight(&self, index: usize) -> u64 {
+        if self.peers[index].removed {
+            0
+        } else {
+            self.servers[index].weight as u64
+        }
+    }
+
+    /// Smooth weighted round-robin over the `candidates`.
+    fn round_robin(&mut self, candidates: &[bool]) -> Option<usize> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (index, server) in self.servers.iter().enumerate() {
+            if !candidates[index] {
+                continue;
+            }
+            let peers = &mut self.peers;
+            peers[index].current_weight += server.weight as i64;
+            total += server.weight as i64;
+            if best.is_none_or(|best| peers[index].current_weight > peers[best].current_weight) {
+                best = Some(index);
+            }
+        }
+        let best = best?;
+        self.peers[best].current_weight -= total;
+        Some(best)
+    }
+
+    /// The server with the fewest active connections relative to its weight,
+    /// with ties broken by round-robin.
+    fn least_conn(&mut self, available: &[bool]) -> Option<usize> {
+        let best = (0..self.servers.len())
+            .filter(|index| available[*index])
+            .min_by(|a, b| self.compare_load(*a, *b))?;
+        let tied: Vec<bool> = (0..self.servers.len())
+            .map(|index| available[index] && self.compare_load(index, best).is_eq())
+            .collect();
+        self.round_robin(&tied)
+    }
+
+    fn compare_load(&self, a: usize, b: usize) -> Ordering {
+        let load_a = self.peers[a].active as u64 * self.servers[b].weight as u64;
+        let load_b = self.peers[b].active as u64 * self.servers[a].weight as u64;
+        load_a.cmp(&load_b)
+    }
+
+    /// Maps `key` onto the servers by weight, rehashing when it lands on an
+    /// unavailable server and falling back to round-robin after `HASH_TRIES`.
+    fn hashed(&mut self, available: &[bool], key: &[u8]) -> Option<usize> {
+        let mut data = key.to_vec();
+        for attempt in 0..HASH_TRIES {
+            let index = self.weighted_index(murmur_hash2(&data) as u64);
+            if available[index] {
+                return Some(index);
+            }
+            data.push(attempt);
+        }
+        self.round_robin(available)
+    }
+
+    /// The first available server clockwise from `key` on the hash ring, so
+    /// adding or removing a server only moves the keys next to it.
+    fn consistent(&self, available: &[bool], key: &str) -> Option<usize> {
+        let hash = murmur_hash2(key.as_bytes());
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+            .find(|index| available[*index])
+    }
+
+    fn random(&mut self, available: &[bool], two: bool) -> Option<usize> {
+        let mut pick = |exclude: Option<usize>| {
+            (0..HASH_TRIES)
+                .map(|_| {
+                    let point = self.next_random();
+                    self.weighted_index(point)
+                })
+                .find(|index| available[*index] && Some(*index) != exclude)
+        };
+        let first = pick(None).or_else(|| available.iter().position(|a| *a))?;
+        if !two {
+            return Some(first);
+        }
+        let Some(second) = pick(Some(first)) else {
+            return Some(first);
+        };
+        if self.compare_load(second, first).is_lt() {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+
+    /// The server owning `point` when every server gets `weight` slots.
+    fn weighted_index(&self, point: u64) -> usize {
+        let total: u64 = (0..self.servers.len())
+            .map(|index| self.weight(index))
+            .sum();
+        let mut point = point % total.max(1);
+        for index in 0..self.servers.len() {
+            let weight = self.weight(index);
+            if point < weight {
+                return index;
+            }
+            point -= weight;
+        }
+        self.servers.len() - 1
+    }
+}
+
+/// A server of an upstream as reported by `Upstream::members`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamMember {
+    /// Stays the same for as long as the server is in the group.
+    pub id: usize,
+    pub server: UpstreamServer,
+    /// Requests being sent to the server right now.
+    pub active: u32,
+    /// Failures counted towards `max_fails`.
+    pub fails: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("no server with id {0}")]
+    UnknownServer(usize),
+    #[error("server {0} is already in the group")]
+    DuplicateServer(String),
+    #[error("cannot save state file \"{0}\": {1}")]
+    State(String, io::Error),
+}
+
+/// A named pool of servers, balanced with smooth weighted round-robin unless
+/// another `Balancer` is set. Servers can be added, changed and removed while
+/// running.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    balancer: Balancer,
+    state: Mutex<PoolState>,
+    keepalive: Option<Keepalive>,
+    /// Idle connections, oldest first.
+    idle: Mutex<VecDeque<IdleConnection>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    sticky: Option<StickyCookie>,
+    /// Where the servers are saved after every change, set with `state`.
+    state_file: Option<PathBuf>,
+}
+
+impl Upstream {
+    pub fn new(name: &str, servers: Vec<UpstreamServer>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_nanos() as u64)
+            .unwrap_or_default();
+        let state = PoolState {
+            peers: servers.iter().map(|_| PeerState::default()).collect(),
+            servers,
+            ring: Vec::new(),
+            rng: seed | 1,
+            circuit: CircuitState::Closed,
+            failures: 0,
+        };
+        Self {
+            name: name.to_string(),
+            balancer: Balancer::RoundRobin,
+            state: Mutex::new(state),
+            keepalive: None,
+            idle: Mutex::new(VecDeque::new()),
+            circuit_breaker: None,
+            sticky: None,
+            state_file: None,
+        }
+    }
+
+    /// A pool holding a single server, as used by a plain `proxy_pass` address.
+    pub fn single(host: &str, port: u16) -> Self {
+        Self::new(
+            &format!("{}:{}", host, port),
+            vec![UpstreamServer::new(host, port)],
+        )
+    }
+
+    pub fn with_balancer(mut self, balancer: Balancer) -> Self {
+        self.balancer = balancer;
+        self.rebuild_ring();
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    pub fn with_sticky(mut self, sticky: StickyCookie) -> Self {
+        self.sticky = Some(sticky);
+        self
+    }
+
+    /// Saves the servers to `path` whenever they change.
+    pub fn with_state_file(mut self, path: PathBuf) -> Self {
+        self.state_file = Some(path);
+        self
+    }
+
+    fn rebuild_ring(&mut self) {
+        let consistent = self.is_consistent();
+        if let Ok(state) = self.state.get_mut() {
+            state.ring.clear();
+            if consistent {
+                state.build_ring();
+            }
+        }
+    }
+
+    fn is_consistent(&self) -> bool {
+        matches!(
+            self.balancer,
+            Balancer::Hash {
+                consistent: true,
+                ..
+            }
+        )
+    }
+
+    /// The servers currently in the group.
+    pub fn servers(&self) -> Vec<UpstreamServer> {
+        self.members()
+            .into_iter()
+            .map(|member| member.server)
+            .collect()
+    }
+
+    /// The servers currently in the group with their ids and counters.
+    pub fn members(&self) -> Vec<UpstreamMember> {
+        let Ok(state) = self.state.lock() else {
+            return Vec::new();
+        };
+        state
+            .servers
+            .iter()
+            .zip(&state.peers)
+            .enumerate()
+            .filter(|(_, (_, peer))| !peer.removed)
+            .map(|(id, (server, peer))| UpstreamMember {
+                id,
+                server: server.clone(),
+                active: peer.active,
+                fails: peer.fails,
+            })
+            .collect()
+    }
+
+    /// Adds a server to the group, returning its id.
+    pub fn add_server(&self, server: UpstreamServer) -> Result<usize, UpstreamError> {
+        let id = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let address = server.address();
+            if state
+                .servers
+                .iter()
+                .zip(&state.peers)
+                .any(|(other, peer)| !peer.removed && other.address() == address)
+            {
+                return Err(UpstreamError::DuplicateServer(address));
+            }
+            state.servers.push(server);
+            state.peers.push(PeerState::default());
+            if self.is_consistent() {
+                state.build_ring();
+            }
+            state.servers.len() - 1
+        };
+        log_notice!("upstream {} added server {}", self.name, id);
+        self.save()?;
+        Ok(id)
+    }
+
+    /// Changes the settings of the server with `id`, returning the result.
+    /// Its address cannot change; add a new server instead.
+    pub fn update_server(
+        &self,
+        id: usize,
+        update: impl FnOnce(&mut UpstreamServer),
+    ) -> Result<UpstreamServer, UpstreamError> {
+        let server = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.peers.get(id).is_none_or(|peer| peer.removed) {
+                return Err(UpstreamError::UnknownServer(id));
+            }
+            let server = &mut state.servers[id];
+            let (host, port) = (server.host.clone(), server.port);
+            update(server);
+            server.host = host;
+            server.port = port;
+            let server = server.clone();
+            if self.is_consistent() {
+                state.build_ring();
+            }
+            server
+        };
+        log_notice!("upstream {} changed server {}", self.name, id);
+        self.save()?;
+        Ok(server)
+    }
+
+    /// Takes the server with `id` out of the group. Requests already sent to
+    /// it finish normally.
+    pub fn remove_server(&self, id: usize) -> Result<(), UpstreamError> {
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            match state.peers.get_mut(id) {
+                Some(peer) if !peer.removed => peer.removed = true,
+                _ => return Err(UpstreamError::UnknownServer(id)),
+            }
+            if self.is_consistent() {
+                state.build_ring();
+            }
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.retain(|conn| conn.index != id);
+        }
+        log_notice!("upstream {} removed server {}", self.name, id);
+        self.save()
+    }
+
+    /// Writes the servers to the state file, as `server` entries that
+    /// `handle_upstream` reads back on the next start.
+    fn save(&self) -> Result<(), UpstreamError> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let mut content = String::new();
+        for member in self.members() {
+            content.push_str(&format!("server {};\n", member.server));
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, content)
+            .and_then(|_| fs::rename(&temp, path))
+            .map_err(|e| UpstreamError::State(path.display().to_string(), e))
+    }
+
+    pub fn keepalive(&self) -> Option<&Keepalive> {
+        self.keepalive.as_ref()
+    }
+
+    /// Takes the most recently used idle connection to the server at
+    /// `index`, closing any that have been idle for too long.
+    pub fn take_idle(&self, index: usize) -> Option<IdleConnection> {
+        let keepalive = self.keepalive.as_ref()?;
+        let mut idle = self.idle.lock().ok()?;
+        idle.retain(|conn| conn.since.elapsed() < keepalive.timeout);
+        let position = idle.iter().rposition(|conn| conn.index == index)?;
+        idle.remove(position)
+    }
+
+    /// Keeps a connection to the server at `index` that has carried
+    /// `requests` requests for reuse, closing the oldest idle connection when
+    /// the pool is full.
+    pub fn put_idle(&self, index: usize, stream: UpstreamStream, requests: u32) {
+        let Some(keepalive) = &self.keepalive else {
+            return;
+        };
+        if requests >= keepalive.requests {
+            return;
+        }
+        let removed = self
+            .state
+            .lock()
+            .map(|state| state.peers[index].removed)
+            .unwrap_or(true);
+        if removed {
+            return;
+        }
+        let Ok(mut idle) = self.idle.lock() else {
+            return;
+        };
+        idle.push_back(IdleConnection {
+            stream,
+            requests,
+            index,
+            since: Instant::now(),
+        });
+        while idle.len() > keepalive.connections {
+            idle.pop_front();
+        }
+    }
+
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().map(|idle| idle.len()).unwrap_or(0)
+    }
+
+    /// Picks a server for `req` other than the `tried` ones, skipping those
+    /// that are down or failed `max_fails` times within the last
+    /// `fail_timeout`. A lone server is returned regardless of failures. The
+    /// server counts as active until the returned peer is dropped.
+    pub fn select(self: &Arc<Self>, req: &HttpRequest, tried: &[usize]) -> Option<ActivePeer> {
+        let now = Instant::now();
+        let mut state = self.state.lock().ok()?;
+        let live: Vec<usize> = (0..state.servers.len())
+            .filter(|index| !state.peers[*index].removed && !state.servers[*index].down)
+            .collect();
+        let index = if let [only] = live[..] {
+            if tried.contains(&only) {
+                return None;
+            }
+            only
+        } else {
+            let available: Vec<bool> = state
+                .servers
+                .iter()
+                .zip(&state.peers)
+                .enumerate()
+                .map(|(index, (server, peer))| {
+                    !tried.contains(&index) && is_available(server, peer, now)
+                })
+                .collect();
+            let sticky = self
+                .sticky
+                .as_ref()
+                .and_then(|sticky| sticky.route(req))
+                .and_then(|route| {
+                    (0..state.servers.len()).find(|index| {
+                        available[*index] && sticky_route(&state.servers[*index]) == route
+                    })
+                });
+            sticky.or_else(|| match &self.balancer {
+                Balancer::RoundRobin => state.round_robin(&available),
+                Balancer::LeastConn => state.least_conn(&available),
+                Balancer::IpHash => match req.remote_addr() {
+                    Some(addr) => state.hashed(&available, &ip_hash_key(addr.ip())),
+                    None => state.round_robin(&available),
+                },
+                Balancer::Hash { key, consistent } => {
+                    let key = key.render(&RequestVariables::new(req));
+                    if *consistent {
+                        state.consistent(&available, &key)
+                    } else {
+                        state.hashed(&available, key.as_bytes())
+                    }
+                }
+                Balancer::Random { two } => state.random(&available, *two),
+            })?
+        };
+        state.peers[index].active += 1;
+        Some(ActivePeer {
+            upstream: self.clone(),
+            server: state.servers[index].clone(),
+            index,
+        })
+    }
+
+    /// The `Set-Cookie` value that sends the client of `req` back to the
+    /// server at `index`, unless its sticky cookie already does.
+    pub fn sticky_cookie(&self, req: &HttpRequest, index: usize) -> Option<String> {
+        let sticky = self.sticky.as_ref()?;
+        let route = {
+            let state = self.state.lock().ok()?;
+            sticky_route(state.servers.get(index)?)
+        };
+        (sticky.route(req) != Some(route.as_str())).then(|| sticky.set_cookie(&route))
+    }
+
+    /// Tells whether a request may be sent to the group, or the status to
+    /// answer it with while the circuit is open.
+    pub fn admit(&self) -> Result<(), StatusCode> {
+        let Some(breaker) = &self.circuit_breaker else {
+            return Ok(());
+        };
+        let Ok(mut state) = self.state.lock() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        match state.circuit {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open { until } if now < until => Err(breaker.status),
+            CircuitState::Open { .. } => {
+                log_notice!("upstream {} circuit half-open, probing", self.name);
+                state.circuit = CircuitState::HalfOpen {
+                    since: now,
+                    admitted: 1,
+                };
+                Ok(())
+            }
+            CircuitState::HalfOpen { since, admitted } => {
+                if admitted < breaker.probes {
+                    state.circuit = CircuitState::HalfOpen {
+                        since,
+                        admitted: admitted + 1,
+                    };
+                    Ok(())
+                } else if now.duration_since(since) > breaker.cooldown {
+                    // The probes never reported back; send new ones.
+                    state.circuit = CircuitState::HalfOpen {
+                        since: now,
+                        admitted: 1,
+                    };
+                    Ok(())
+                } else {
+                    Err(breaker.status)
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of talking to the server at `index`.
+    pub fn report(&self, index: usize, ok: bool) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let Some(fail_timeout) = state.servers.get(index).map(|server| server.fail_timeout) else {
+            return;
+        };
+        self.report_circuit(&mut state, ok);
+        let peer = &mut state.peers[index];
+        if ok {
+            peer.fails = 0;
+            peer.checked = None;
+            return;
+        }
+        let now = Instant::now();
+        if peer
+            .checked
+            .is_some_and(|checked| now.duration_since(checked) > fail_timeout)
+        {
+            peer.fails = 0;
+        }
+        peer.fails += 1;
+        peer.checked = Some(now);
+    }
+
+    fn report_circuit(&self, state: &mut PoolState, ok: bool) {
+        let Some(breaker) = &self.circuit_breaker else {
+            return;
+        };
+        if ok {
+            state.failures = 0;
+            if state.circuit != CircuitState::Closed {
+                log_notice!("upstream {} circuit closed", self.name);
+                state.circuit = CircuitState::Closed;
+            }
+            return;
+        }
+        state.failures += 1;
+        let trips = match state.circuit {
+            CircuitState::Closed => state.failures >= breaker.failures,
+            CircuitState::HalfOpen { .. } => true,
+            CircuitState::Open { .. } => false,
+        };
+        if trips {
+            log_warn!(
+                "upstream {} circuit open for {:?} after {} failures",
+                self.name,
+                breaker.cooldown,
+                state.failures
+            );
+            state.circuit = CircuitState::Open {
+                until: Instant::now() + breaker.cooldown,
+            };
+        }
+    }
+}
+
+fn is_available(server: &UpstreamServer, peer: &PeerState, now: Instant) -> bool {
+    if peer.removed || server.down {
+        return false;
+    }
+    server.max_fails == 0
+        || peer.fails < server.max_fails
+        || peer
+            .checked
+            .is_none_or(|checked| now.duration_since(checked) > server.fail_timeout)
+}
+
+fn ip_hash_key(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[..3].to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_KEEPALIVE_REQUESTS: u32 = 1000;
+
+/// Limits of the idle connection pool set with `keepalive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle connections kept across all servers of the upstream.
+    pub connections: usize,
+    pub timeout: Duration,
+    /// Requests a connection carries before it is closed.
+    pub requests: u32,
+}
+
+impl Keepalive {
+    pub fn new(connections: usize) -> Self {
+        Self {
+            connections,
+            timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            requests: DEFAULT_KEEPALIVE_REQUESTS,
+        }
+    }
+}
+
+/// A connection to an upstream server, encrypted when the proxy talks TLS
+/// to it.
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl UpstreamStream {
+    /// The underlying socket, for timeouts and readiness.
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Self::Plain(stream) => stream,
+            Self::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            // Many servers close without sending a TLS close_notify first,
+            // which is read as the end of the stream like on plain TCP.
+            Self::Tls(stream) => match stream.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            },
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// An open connection to an upstream server waiting to be reused.
+#[derive(Debug)]
+pub struct IdleConnection {
+    pub stream: UpstreamStream,
+    /// Requests already sent over the connection.
+    pub requests: u32,
+    index: usize,
+    since: Instant,
+}
+
+/// A server chosen for one request, counted as active until dropped.
+#[derive(Debug)]
+pub struct ActivePeer {
+    upstream: Arc<Upstream>,
+    /// The server as it was when picked, in case it changes meanwhile.
+    server: UpstreamServer,
+    index: usize,
+}
+
+impl ActivePeer {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn server(&self) -> &UpstreamServer {
+        &self.server
+    }
+
+    pub fn is_keepalive(&self) -> bool {
+        self.upstream.keepalive.is_some()
+    }
+
+    pub fn idle_connection(&self) -> Option<IdleConnection> {
+        self.upstream.take_idle(self.index)
+    }
+
+    /// Returns a connection that is ready for another request to the pool.
+    pub fn keep_connection(&self, stream: UpstreamStream, requests: u32) {
+        self.upstream.put_idle(self.index, stream, requests);
+    }
+}
+
+impl Drop for ActivePeer {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.upstream.state.lock() {
+            let peer = &mut state.peers[self.index];
+            peer.active = peer.active.saturating_sub(1);
+        }
+    }
+}
+
+/// How one attempt at an upstream server went, for logs and traces. Times
+/// are counted from the start of the attempt.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpstreamTiming {
+    pub address: String,
+    pub started: Option<Instant>,
+    /// Time taken to open the connection; zero when an idle one was reused.
+    pub connect: Option<Duration>,
+    /// Time until the response head arrived.
+    pub header: Option<Duration>,
+    /// Time until the whole response was read. A streamed body is read while
+    /// it is sent to the client, so this is only known once it has been.
+    pub response: Arc<OnceLock<Duration>>,
+}
+
+impl UpstreamTiming {
+    pub fn start(address: String) -> Self {
+        Self {
+            address,
+            started: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started
+            .map(|started| started.elapsed())
+            .unwrap_or_default()
+    }
+
+    /// Records the attempt as over; only the first call counts.
+    pub fn finish(&self) {
+        let _ = self.response.set(self.elapsed());
+    }
+
+    pub fn response_time(&self) -> Option<Duration> {
+        self.response.get().copied()
+    }
+}
+
+/// Finds the `upstream` block named `name` declared in the http block.
+pub fn find_upstream(http_config: &ConfigContext, name: &str) -> Option<Arc<Upstream>> {
+    upstreams(http_config)
+        .into_iter()
+        .find(|upstream| upstream.name == name)
+}
+
+/// Every `upstream` block declared in the http block.
+pub fn upstreams(http_config: &ConfigContext) -> Vec<Arc<Upstream>> {
+    http_config
+        .children
+        .iter()
+        .filter_map(|child| child.store.get::<Upstream>())
+        .collect()
+}
+
+pub fn handle_upstream(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let name = ctx.str_arg(0)?;
+    let mut servers = Vec::new();
+    let mut balancer = None;
+    let mut keepalive = None;
+    let mut keepalive_timeout = DEFAULT_KEEPALIVE_TIMEOUT;
+    let mut keepalive_requests = DEFAULT_KEEPALIVE_REQUESTS;
+    let mut circuit_breaker = None;
+    let mut sticky = None;
+    let mut state_file = None;
+    for entry in &ctx.raw_entries {
+        let Some((directive, args)) = entry.args.split_first() else {
+            continue;
+        };
+        let invalid = |reason: String| ctx.invalid_entry(entry, &entry.args.join(" "), reason);
+        match (directive.as_str(), args) {
+            ("server", _) => {
+                servers.push(UpstreamServer::parse(args).map_err(invalid)?);
+                continue;
+            }
+            ("keepalive", [connections]) => {
+                keepalive = Some(
+                    connections
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|connections| *connections > 0)
+                        .ok_or_else(|| invalid("invalid number of connections".to_string()))?,
+                );
+                continue;
+            }
+            ("keepalive_timeout", [timeout]) => {
+                keepalive_timeout = parse_duration(timeout).map_err(invalid)?;
+                continue;
+            }
+            ("keepalive_requests", [requests]) => {
+                keepalive_requests = requests
+                    .parse()
+                    .ok()
+                    .filter(|requests| *requests > 0)
+                    .ok_or_else(|| invalid("invalid number of requests".to_string()))?;
+                continue;
+            }
+            ("circuit_breaker", _) => {
+                circuit_breaker = Some(CircuitBreaker::parse(args).map_err(invalid)?);
+                continue;
+            }
+            ("sticky", _) => {
+                sticky = Some(StickyCookie::parse(args).map_err(invalid)?);
+                continue;
+            }
+            ("state", [path]) => {
+                state_file = Some(PathBuf::from(path));
+                continue;
+            }
+            ("keepalive" | "keepalive_timeout" | "keepalive_requests" | "state", _) => {
+                return Err(invalid(format!("invalid arguments for \"{}\"", directive)));
+            }
+            _ => {}
+        }
+        if balancer.is_some() {
+            return Err(ctx.invalid_entry(
+                entry,
+                directive,
+                "load balancing method is already set",
+            ));
+        }
+        balancer = Some(
+            Balancer::parse(directive, args)
+                .map_err(|reason| ctx.invalid_entry(entry, &entry.args.join(" "), reason))?,
+        );
+    }
+    if let Some(path) = state_file.as_ref().filter(|path| path.exists()) {
+        servers = load_state(path)
+            .map_err(|reason| ctx.invalid_value(&path.display().to_string(), reason))?;
+    } else if servers.is_empty() {
+        return Err(ctx.invalid_value(&name, "upstream has no servers"));
+    }
+
+    let mut upstream = Upstream::new(&name, servers).with_balancer(balancer.unwrap_or_default());
+    if let Some(connections) = keepalive {
+        upstream = upstream.with_keepalive(Keepalive {
+            connections,
+            timeout: keepalive_timeout,
+            requests: keepalive_requests,
+        });
+    }
+    if let Some(breaker) = circuit_breaker {
+        upstream = upstream.with_circuit_breaker(breaker);
+    }
+    if let Some(sticky) = sticky {
+        upstream = upstream.with_sticky(sticky);
+    }
+    if let Some(path) = state_file {
+        upstream = upstream.with_state_file(path);
+    }
+    ctx.store.insert(Arc::new(upstream));
+    Ok(())
+}
+
+/// Reads the `server` entries an upstream saved to its state file.
+fn load_state(path: &Path) -> Result<Vec<UpstreamServer>, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    content
+        .lines()
+        .map(|line| line.trim().trim_end_matches(';'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let args: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+            match args.split_first() {
+                Some((directive, args)) if directive == "server" => UpstreamServer::parse(args),
+                _ => Err(format!("unexpected line \"{}\"", line)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn request(client: &str, uri: &str) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", uri).as_bytes())
+            .unwrap();
+        req.set_connection(Some(client.parse().unwrap()), None, false);
+        req
+    }
+
+    fn pool(lines: &[&str], balancer: Balancer) -> Arc<Upstream> {
+        let servers = lines
+            .iter()
+            .map(|line| UpstreamServer::parse(&args(line)).unwrap())
+            .collect();
+        Arc::new(Upstream::new("backend", servers).with_balancer(balancer))
+    }
+
+    #[test]
+    fn test_weighted_round_robin_and_failures() {
+        let upstream = pool(
+            &[
+                "10.0.0.1:8080 weight=3",
+                "10.0.0.2:8080 max_fails=2 fail_timeout=30s",
+            ],
+            Balancer::RoundRobin,
+        );
+        let req = request("192.0.2.1:5000", "/");
+        let pick = || upstream.select(&req, &[]).map(|peer| peer.index());
+        let picks: Vec<usize> = (0..8).map(|_| pick().unwrap()).collect();
+        assert_eq!(picks, [0, 0, 1, 0, 0, 0, 1, 0]);
+
+        upstream.report(1, false);
+        assert!((0..4).any(|_| pick() == Some(1)));
+        upstream.report(1, false);
+        assert!((0..8).all(|_| pick() == Some(0)));
+        upstream.report(1, true);
+        assert!((0..4).any(|_| pick() == Some(1)));
+
+        upstream.report(0, false);
+        upstream.report(1, false);
+        upstream.report(1, false);
+        assert_eq!(pick(), None);
+
+        assert_eq!(
+            UpstreamServer::parse(&args("backend.local"))
+                .unwrap()
+                .address(),
+            "backend.local:80"
+        );
+        assert!(UpstreamServer::parse(&args("10.0.0.1 weight=0")).is_err());
+        assert!(UpstreamServer::parse(&args("10.0.0.1 backup")).is_err());
+    }
+
+    #[test]
+    fn test_balancing_methods() {
+        let servers = ["10.0.0.1", "10.0.0.2", "10.0.0.3"];
+
+        let least_conn = pool(&servers, Balancer::LeastConn);
+        let req = request("192.0.2.1:5000", "/");
+        let first = least_conn.select(&req, &[]).unwrap();
+        let second = least_conn.select(&req, &[]).unwrap();
+        let third = least_conn.select(&req, &[]).unwrap();
+        assert_eq!([first.index(), second.index(), third.index()], [0, 1, 2]);
+        drop(second);
+        assert_eq!(least_conn.select(&req, &[]).unwrap().index(), 1);
+
+        let ip_hash = pool(&servers, Balancer::IpHash);
+        let client = ip_hash
+            .select(&request("192.0.2.1:5000", "/"), &[])
+            .unwrap()
+            .index();
+        for addr in ["192.0.2.1:6000", "192.0.2.200:5000"] {
+            assert_eq!(
+                ip_hash.select(&request(addr, "/"), &[]).unwrap().index(),
+                client
+            );
+        }
+        ip_hash.report(client, false);
+        assert_ne!(
+            ip_hash
+                .select(&request("192.0.2.1:5000", "/"), &[])
+                .unwrap()
+                .index(),
+            client
+        );
+
+        let balancer = Balancer::parse("hash", &args("$request_uri consistent")).unwrap();
+        let hash = pool(&servers, balancer);
+        let owner = |uri: &str| {
+            hash.select(&request("192.0.2.1:5000", uri), &[])
+                .unwrap()
+                .index()
+        };
+        let before: Vec<usize> = (0..50).map(|i| owner(&format!("/item/{}", i))).collect();
+        assert_eq!(
+            before,
+            (0..50)
+                .map(|i| owner(&format!("/item/{}", i)))
+                .collect::<Vec<_>>()
+        );
+        hash.report(1, false);
+        for (i, previous) in before.iter().enumerate() {
+            let now = owner(&format!("/item/{}", i));
+            assert_ne!(now, 1);
+            if *previous != 1 {
+                assert_eq!(now, *previous);
+            }
+        }
+
+        let random = pool(
+            &servers,
+            Balancer::parse("random", &args("two least_conn")).unwrap(),
+        );
+        let busy = random.select(&req, &[]).unwrap();
+        assert!((0..20).all(|_| random.select(&req, &[]).unwrap().index() != busy.index()));
+
+        assert!(Balancer::parse("hash", &[]).is_err());
+        assert!(Balancer::parse("round_robin", &[]).is_err());
+    }
+
+    #[test]
+    fn test_sticky_cookie() {
+        let sticky = StickyCookie::parse(&args("cookie srv expires=1h httponly")).unwrap();
+        let servers = ["10.0.0.1", "10.0.0.2", "10.0.0.3"];
+        let upstream = Arc::new(
+            Upstream::new(
+                "backend",
+                servers
+                    .iter()
+                    .map(|line| UpstreamServer::parse(&args(line)).unwrap())
+                    .collect(),
+            )
+            .with_sticky(sticky),
+        );
+
+        let fresh = request("192.0.2.1:5000", "/");
+        let first = upstream.select(&fresh, &[]).unwrap().index();
+        let cookie = upstream.sticky_cookie(&fresh, first).unwrap();
+        assert!(cookie.ends_with("; Path=/; Max-Age=3600; HttpOnly"));
+
+        let mut returning = request("192.0.2.1:5000", "/");
+        let route = cookie.split(';').next().unwrap();
+        returning.set_header("Cookie", &format!("a=b; {}", route));
+        assert!((0..6).all(|_| upstream.select(&returning, &[]).unwrap().index() == first));
+        assert_eq!(upstream.sticky_cookie(&returning, first), None);
+
+        upstream.report(first, false);
+        let moved = upstream.select(&returning, &[]).unwrap().index();
+        assert_ne!(moved, first);
+        assert!(upstream.sticky_cookie(&returning, moved).is_some());
+
+        assert!(StickyCookie::parse(&args("route srv")).is_err());
+        assert!(StickyCookie::parse(&args("cookie srv max=1")).is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker =
+            CircuitBreaker::parse(&args("failures=2 cooldown=100ms probes=1 status=502")).unwrap();
+        let upstream = Arc::new(
+            Upstream::new(
+                "backend",
+                vec![
+                    UpstreamServer::new("10.0.0.1", 80),
+                    UpstreamServer::new("10.0.0.2", 80),
+                ],
+            )
+            .with_circuit_breaker(breaker),
+        );
+
+        upstream.report(0, false);
+        upstream.report(1, true);
+        upstream.report(0, false);
+        assert_eq!(upstream.admit(), Ok(()));
+        upstream.report(1, false);
+        assert_eq!(upstream.admit(), Err(StatusCode::BAD_GATEWAY));
+
+        // One probe after the cooldown; its failure opens the circuit again.
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(upstream.admit(), Ok(()));
+        assert_eq!(upstream.admit(), Err(StatusCode::BAD_GATEWAY));
+        upstream.report(0, false);
+        assert_eq!(upstream.admit(), Err(StatusCode::BAD_GATEWAY));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(upstream.admit(), Ok(()));
+        upstream.report(1, true);
+        assert_eq!(upstream.admit(), Ok(()));
+        assert_eq!(upstream.admit(), Ok(()));
+
+        assert_eq!(
+            CircuitBreaker::parse(&[]).unwrap(),
+            CircuitBreaker::default()
+        );
+        assert!(CircuitBreaker::parse(&args("failures=0")).is_err());
+        assert!(CircuitBreaker::parse(&args("status=404")).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use std::sync::Arc;
+
+use http::{Method, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    core::{
+        config::{
+            command::{ArgType, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::{format_duration, parse_duration},
+        },
+        processor::{HttpHandler, LocationPattern},
+    },
+    register_commands,
+};
+
+use super::{
+    http_api::{api_handler, body_object, ApiError, ApiResult},
+    http_request::HttpRequest,
+    http_upstream::{upstreams, Upstream, UpstreamError, UpstreamMember, UpstreamServer},
+};
+
+register_commands!(CommandBuilder::new("upstream_api")
+    .allowed_parents(vec!["location".to_string()])
+    .display_name("en", "Upstream API")
+    .display_name("zh-tw", "上游管理 API")
+    .desc(
+        "en",
+        "Sets whether the location serves a JSON API to list, add, change and remove upstream servers without a reload"
+    )
+    .desc(
+        "zh-tw",
+        "設定 location 是否提供 JSON API，可在不重新載入的情況下列出、新增、修改與移除上游伺服器"
+    )
+    .params(vec![ParameterBuilder::new(0)
+        .display_name("en", "Enabled")
+        .display_name("zh-tw", "啟用")
+        .arg_type(ArgType::Bool)
+        .is_required(true)
+        .default("")
+        .desc("en", "on, or off (the default)")
+        .desc("zh-tw", "on，或 off（預設）")
+        .build()])
+    .build(handle_upstream_api));
+
+#[derive(Debug, Default, Clone)]
+pub struct UpstreamApiConfig {
+    pub enabled: Option<bool>,
+}
+
+impl MergeConfig for UpstreamApiConfig {
+    fn merge_from(&mut self, _parent: &Self) {}
+}
+
+/// Answers `/`, `/{upstream}` and `/{upstream}/{id}` below the location.
+struct UpstreamApi {
+    upstreams: Vec<Arc<Upstream>>,
+}
+
+impl UpstreamApi {
+    fn handle(&self, req: &HttpRequest, segments: &[&str]) -> ApiResult {
+        let method = req.method();
+        match *segments {
+            [] if method == Method::GET => {
+                let all: Map<String, Value> = self
+                    .upstreams
+                    .iter()
+                    .map(|upstream| (upstream.name.clone(), members_json(upstream)))
+                    .collect();
+                Ok((StatusCode::OK, Some(Value::Object(all))))
+            }
+            [] => Err(ApiError::method("GET")),
+            [name] => {
+                let upstream = self.upstream(name)?;
+                match *method {
+                    Method::GET => Ok((StatusCode::OK, Some(members_json(upstream)))),
+                    Method::POST => {
+                        let body = body_object(req)?;
+                        let address = body
+                            .get("server")
+                            .and_then(Value::as_str)
+                            .ok_or_else(|| ApiError::invalid("missing \"server\""))?;
+                        let mut server = UpstreamServer::parse(&[address.to_string()])
+                            .map_err(ApiError::invalid)?;
+                        apply(&mut server, &body, &["server"])?;
+                        let id = upstream.add_server(server)?;
+                        Ok((StatusCode::CREATED, Some(self.member(upstream, id)?)))
+                    }
+                    _ => Err(ApiError::method("GET, POST")),
+                }
+            }
+            [name, id] => {
+                let upstream = self.upstream(name)?;
+                let id = id
+                    .parse()
+                    .map_err(|_| ApiError::not_found(format!("no server with id {}", id)))?;
+                match *method {
+                    Method::GET => Ok((StatusCode::OK, Some(self.member(upstream, id)?))),
+                    Method::PATCH => {
+                        let body = body_object(req)?;
+                        let mut changed = upstream
+                            .members()
+                            .into_iter()
+                            .find(|member| member.id == id)
+                            .ok_or(UpstreamError::UnknownServer(id))?
+                            .server;
+                        apply(&mut changed, &body, &[])?;
+                        upstream.update_server(id, |server| *server = changed)?;
+                        Ok((StatusCode::OK, Some(self.member(upstream, id)?)))
+                    }
+                    Method::DELETE => {
+                        upstream.remove_server(id)?;
+                        Ok((StatusCode::NO_CONTENT, None))
+                    }
+                    _ => Err(ApiError::method("GET, PATCH, DELETE")),
+                }
+            }
+            _ => Err(ApiError::not_found("no such resource")),
+        }
+    }
+
+    fn upstream(&self, name: &str) -> Result<&Arc<Upstream>, ApiError> {
+        self.upstreams
+            .iter()
+            .find(|upstream| upstream.name == name)
+            .ok_or_else(|| ApiError::not_found(format!("no upstream named \"{}\"", name)))
+    }
+
+    fn member(&self, upstream: &Upstream, id: usize) -> Result<Value, ApiError> {
+        upstream
+            .members()
+            .iter()
+            .find(|member| member.id == id)
+            .map(member_json)
+            .ok_or_else(|| UpstreamError::UnknownServer(id).into())
+    }
+}
+
+impl From<UpstreamError> for ApiError {
+    fn from(e: UpstreamError) -> Self {
+        let status = match e {
+            UpstreamError::UnknownServer(_) => StatusCode::NOT_FOUND,
+            UpstreamError::DuplicateServer(_) => StatusCode::CONFLICT,
+            UpstreamError::State(..) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, e.to_string())
+    }
+}
+
+/// Sets the server fields present in `body`; `skip` lists keys handled
+/// elsewhere.
+fn apply(
+    server: &mut UpstreamServer,
+    body: &Map<String, Value>,
+    skip: &[&str],
+) -> Result<(), ApiError> {
+    let invalid = |key: &str| ApiError::invalid(format!("invalid \"{}\"", key));
+    for (key, value) in body {
+        match key.as_str() {
+            "weight" => {
+                server.weight = value
+                    .as_u64()
+                    .filter(|weight| *weight > 0)
+                    .and_then(|weight| u32::try_from(weight).ok())
+                    .ok_or_else(|| invalid(key))?
+            }
+            "max_fails" => {
+                server.max_fails = value
+                    .as_u64()
+                    .and_then(|fails| u32::try_from(fails).ok())
+                    .ok_or_else(|| invalid(key))?
+            }
+            "fail_timeout" => {
+                server.fail_timeout = value
+                    .as_str()
+                    .and_then(|value| parse_duration(value).ok())
+                    .ok_or_else(|| invalid(key))?
+            }
+            "down" => server.down = value.as_bool().ok_or_else(|| invalid(key))?,
+            _ if skip.contains(&key.as_str()) => {}
+            _ => return Err(ApiError::invalid(format!("unknown field \"{}\"", key))),
+        }
+    }
+    Ok(())
+}
+
+fn member_json(member: &UpstreamMember) -> Value {
+    json!({
+        "id": member.id,
+        "server": member.server.address(),
+        "weight": member.server.weight,
+        "max_fails": member.server.max_fails,
+        "fail_timeout": format_duration(member.server.fail_timeout),
+        "down": member.server.down,
+        "active": member.active,
+        "fails": member.fails,
+    })
+}
+
+fn members_json(upstream: &Upstream) -> Value {
+    Value::Array(upstream.members().iter().map(member_json).collect())
+}
+
+/// Builds the handler for a location block that sets `upstream_api on`.
+pub fn upstream_api_handler(
+    chain: &[&ConfigContext],
+    pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    let block = chain.last()?;
+    if !merged_config::<UpstreamApiConfig>(&[block])
+        .enabled
+        .unwrap_or(false)
+    {
+        return None;
+    }
+    let api = UpstreamApi {
+        upstreams: chain
+            .first()
+            .map(|http| upstreams(http))
+            .unwrap_or_default(),
+    };
+    Some(api_handler(pattern, move |req, segments| {
+        api.handle(req, segments)
+    }))
+}
+
+pub fn handle_upstream_api(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<UpstreamApiConfig>().lock() {
+        config.enabled = Some(enabled);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::http::http_api::route_segments;
+
+    fn call(api: &UpstreamApi, method: &str, path: &str, body: &str) -> (StatusCode, Value) {
+        let mut req = HttpRequest::new();
+        req.parse(
+            format!(
+                "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                method,
+                path,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        match api.handle(&req, &route_segments(&req, "/api/upstreams")) {
+            Ok((status, body)) => (status, body.unwrap_or_default()),
+            Err(e) => (e.status, json!(e.message)),
+        }
+    }
+
+    #[test]
+    fn test_servers_change_at_runtime_and_persist() {
+        let path = std::env::temp_dir().join(format!("blur-upstream-{}.state", std::process::id()));
+        let upstream = Upstream::new("backend", vec![UpstreamServer::new("10.0.0.1", 8080)])
+            .with_state_file(path.clone());
+        let api = UpstreamApi {
+            upstreams: vec![Arc::new(upstream)],
+        };
+
+        let (status, body) = call(
+            &api,
+            "POST",
+            "/api/upstreams/backend",
+            r#"{"server":"10.0.0.2:8080","weight":3}"#,
+        );
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["id"], 1);
+        let (status, _) = call(
+            &api,
+            "POST",
+            "/api/upstreams/backend",
+            r#"{"server":"10.0.0.2:8080"}"#,
+        );
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, body) = call(
+            &api,
+            "PATCH",
+            "/api/upstreams/backend/1",
+            r#"{"down":true,"fail_timeout":"30s"}"#,
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["down"], true);
+        assert_eq!(body["weight"], 3);
+        let (status, _) = call(&api, "PATCH", "/api/upstreams/backend/1", r#"{"weight":0}"#);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(&api, "DELETE", "/api/upstreams/backend/0", "");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&api, "GET", "/api/upstreams/backend/0", "");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = call(&api, "GET", "/api/upstreams/", "");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["backend"][0]["server"], "10.0.0.2:8080");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server 10.0.0.2:8080 weight=3 max_fails=1 fail_timeout=30s down;\n"
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,475 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+            units::parse_duration,
+        },
+        processor::{PhaseResult, RequestPhase, ResponseFilter},
+    },
+    register_commands,
+};
+
+use super::{
+    http_request::{generate_request_id, HttpRequest},
+    http_response::HttpResponse,
+};
+
+register_commands!(
+    CommandBuilder::new("userid")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID")
+        .display_name("zh-tw", "使用者識別碼")
+        .desc(
+            "en",
+            "Identifies clients with a cookie, exposed as $uid_got when received and $uid_set when issued"
+        )
+        .desc(
+            "zh-tw",
+            "以 cookie 識別客戶端，收到時存於 $uid_got，發出時存於 $uid_set"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Mode")
+            .display_name("zh-tw", "模式")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "on issues a cookie to clients without one, log only reads it, off disables it"
+            )
+            .desc(
+                "zh-tw",
+                "on 為沒有 cookie 的客戶端發出 cookie，log 只讀取，off 停用"
+            )
+            .build()])
+        .build(handle_userid),
+    CommandBuilder::new("userid_name")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID Cookie Name")
+        .display_name("zh-tw", "使用者識別 Cookie 名稱")
+        .desc("en", "Sets the name of the userid cookie")
+        .desc("zh-tw", "設定使用者識別 cookie 的名稱")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Name")
+            .display_name("zh-tw", "名稱")
+            .type_name("String")
+            .is_required(true)
+            .default("uid")
+            .desc("en", "Cookie name, uid by default")
+            .desc("zh-tw", "Cookie 名稱，預設為 uid")
+            .build()])
+        .build(handle_userid_name),
+    CommandBuilder::new("userid_domain")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID Cookie Domain")
+        .display_name("zh-tw", "使用者識別 Cookie 網域")
+        .desc("en", "Sets the domain the userid cookie is sent to")
+        .desc("zh-tw", "設定使用者識別 cookie 適用的網域")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Domain")
+            .display_name("zh-tw", "網域")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "e.g. .example.com, or off to leave the attribute out (the default)"
+            )
+            .desc("zh-tw", "例如 .example.com，或 off 不設定此屬性（預設）")
+            .build()])
+        .build(handle_userid_domain),
+    CommandBuilder::new("userid_path")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID Cookie Path")
+        .display_name("zh-tw", "使用者識別 Cookie 路徑")
+        .desc("en", "Sets the path the userid cookie is sent to")
+        .desc("zh-tw", "設定使用者識別 cookie 適用的路徑")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .type_name("String")
+            .is_required(true)
+            .default("/")
+            .desc("en", "URI path, / by default")
+            .desc("zh-tw", "URI 路徑，預設為 /")
+            .build()])
+        .build(handle_userid_path),
+    CommandBuilder::new("userid_expires")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID Cookie Expires")
+        .display_name("zh-tw", "使用者識別 Cookie 有效期限")
+        .desc("en", "Sets how long browsers keep the userid cookie")
+        .desc("zh-tw", "設定瀏覽器保留使用者識別 cookie 的時間")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Time")
+            .display_name("zh-tw", "時間")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "A duration such as 365d, max for ten years, or off for a session cookie (the default)"
+            )
+            .desc(
+                "zh-tw",
+                "時間長度，例如 365d；max 為十年；off 為工作階段 cookie（預設）"
+            )
+            .build()])
+        .build(handle_userid_expires),
+    CommandBuilder::new("userid_flags")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "User ID Cookie Flags")
+        .display_name("zh-tw", "使用者識別 Cookie 旗標")
+        .desc("en", "Adds attributes to the userid cookie")
+        .desc("zh-tw", "為使用者識別 cookie 加上屬性")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Flags")
+            .display_name("zh-tw", "旗標")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "Any of httponly, secure, samesite=strict, samesite=lax and samesite=none, or off"
+            )
+            .desc(
+                "zh-tw",
+                "httponly、secure、samesite=strict、samesite=lax、samesite=none 的任意組合，或 off"
+            )
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_userid_flags)
+);
+
+/// `name=value` of the userid cookie the client sent.
+pub const UID_GOT: &str = "uid_got";
+/// `name=value` of the userid cookie issued with the response.
+pub const UID_SET: &str = "uid_set";
+
+const DEFAULT_NAME: &str = "uid";
+/// What `userid_expires max` stands for.
+const MAX_EXPIRES: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UseridMode {
+    On,
+    Log,
+    Off,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UseridConfig {
+    pub mode: Option<UseridMode>,
+    pub name: Option<String>,
+    /// `Some(None)` records an explicit `off`.
+    pub domain: Option<Option<String>>,
+    pub path: Option<String>,
+    pub expires: Option<Option<Duration>>,
+    pub flags: Option<Vec<String>>,
+}
+
+impl MergeConfig for UseridConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        if self.mode.is_none() {
+            self.mode = parent.mode;
+        }
+        if self.name.is_none() {
+            self.name = parent.name.clone();
+        }
+        if self.domain.is_none() {
+            self.domain = parent.domain.clone();
+        }
+        if self.path.is_none() {
+            self.path = parent.path.clone();
+        }
+        if self.expires.is_none() {
+            self.expires = parent.expires;
+        }
+        if self.flags.is_none() {
+            self.flags = parent.flags.clone();
+        }
+    }
+}
+
+/// Reads the userid cookie of each client and, with `userid on`, issues one
+/// to clients that did not send a valid one.
+pub struct Userid {
+    issue: bool,
+    name: String,
+    domain: Option<String>,
+    path: String,
+    expires: Option<Duration>,
+    flags: Vec<String>,
+}
+
+impl Userid {
+    /// The identifier the client sent, if it is one this module issued.
+    fn received<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        req.header("Cookie")?
+            .split(';')
+            .find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                (key == self.name).then_some(value)
+            })
+            .filter(|value| value.len() == 32 && value.bytes().all(|b| b.is_ascii_hexdigit()))
+    }
+
+    fn set_cookie(&self, value: &str) -> String {
+        let mut cookie = format!("{}={}; Path={}", self.name, value, self.path);
+        if let Some(expires) = self.expires {
+            cookie.push_str(&format!("; Max-Age={}", expires.as_secs()));
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        for flag in &self.flags {
+            cookie.push_str(match flag.as_str() {
+                "httponly" => "; HttpOnly",
+                "secure" => "; Secure",
+                "samesite=strict" => "; SameSite=Strict",
+                "samesite=lax" => "; SameSite=Lax",
+                _ => "; SameSite=None",
+            });
+        }
+        cookie
+    }
+}
+
+impl RequestPhase for Userid {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        if let Some(value) = self.received(req) {
+            let got = format!("{}={}", self.name, value);
+            req.set_var(UID_GOT, &got);
+        } else if self.issue && !req.is_subrequest() && req.var(UID_SET).is_none() {
+            let set = format!("{}={}", self.name, generate_request_id());
+            req.set_var(UID_SET, &set);
+        }
+        PhaseResult::Continue
+    }
+}
+
+impl ResponseFilter for Userid {
+    fn filter(&self, req: &HttpRequest, resp: &mut HttpResponse) {
+        if !self.issue || req.is_subrequest() {
+            return;
+        }
+        let prefix = format!("{}=", self.name);
+        if let Some(value) = req.var(UID_SET).and_then(|set| set.strip_prefix(&prefix)) {
+            resp.set_header("Set-Cookie", &self.set_cookie(value));
+        }
+    }
+}
+
+fn userid(chain: &[&ConfigContext]) -> Option<Userid> {
+    let config = merged_config::<UseridConfig>(chain);
+    let issue = match config.mode? {
+        UseridMode::On => true,
+        UseridMode::Log => false,
+        UseridMode::Off => return None,
+    };
+    Some(Userid {
+        issue,
+        name: config.name.unwrap_or_else(|| DEFAULT_NAME.to_string()),
+        domain: config.domain.flatten(),
+        path: config.path.unwrap_or_else(|| "/".to_string()),
+        expires: config.expires.flatten(),
+        flags: config.flags.unwrap_or_default(),
+    })
+}
+
+/// Builds the phase setting `$uid_got` and `$uid_set` for a block chain
+/// with `userid` enabled.
+pub fn userid_phase(chain: &[&ConfigContext]) -> Option<Userid> {
+    userid(chain)
+}
+
+/// Builds the filter sending the cookies `userid on` issues.
+pub fn userid_filter(chain: &[&ConfigContext]) -> Option<Userid> {
+    userid(chain).filter(|userid| userid.issue)
+}
+
+pub fn handle_userid(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let value = ctx.str_arg(0)?;
+    let mode = match value.as_str() {
+        "on" => UseridMode::On,
+        "log" => UseridMode::Log,
+        "off" => UseridMode::Off,
+        _ => return Err(ctx.invalid_value(&value, "expected on, log or off")),
+    };
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.mode = Some(mode);
+    }
+    Ok(())
+}
+
+pub fn handle_userid_name(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let name = ctx.str_arg(0)?;
+    if name.contains(|c: char| c == '=' || c == ';' || c.is_ascii_whitespace()) {
+        return Err(ctx.invalid_value(&name, "not a valid cookie name"));
+    }
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.name = Some(name);
+    }
+    Ok(())
+}
+
+pub fn handle_userid_domain(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let domain = ctx.str_arg(0)?;
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.domain = Some((domain != "off").then_some(domain));
+    }
+    Ok(())
+}
+
+pub fn handle_userid_path(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let path = ctx.str_arg(0)?;
+    if !path.starts_with('/') {
+        return Err(ctx.invalid_value(&path, "expected a path starting with /"));
+    }
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.path = Some(path);
+    }
+    Ok(())
+}
+
+pub fn handle_userid_expires(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let value = ctx.str_arg(0)?;
+    let expires = match value.as_str() {
+        "off" => None,
+        "max" => Some(MAX_EXPIRES),
+        _ => Some(parse_duration(&value).map_err(|reason| ctx.invalid_value(&value, reason))?),
+    };
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.expires = Some(expires);
+    }
+    Ok(())
+}
+
+pub fn handle_userid_flags(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let flags: Vec<String> = ctx
+        .args()
+        .iter()
+        .map(|arg| arg.to_ascii_lowercase())
+        .collect();
+    if flags.is_empty() {
+        return Ok(());
+    }
+    let flags = if flags == ["off"] { Vec::new() } else { flags };
+    for flag in &flags {
+        if !matches!(
+            flag.as_str(),
+            "httponly" | "secure" | "samesite=strict" | "samesite=lax" | "samesite=none"
+        ) {
+            return Err(ctx.invalid_value(
+                flag,
+                "expected httponly, secure, samesite=strict, samesite=lax, samesite=none or off",
+            ));
+        }
+    }
+    if let Ok(mut config) = ctx.block_config::<UseridConfig>().lock() {
+        config.flags = Some(flags);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{StatusCode, Version};
+
+    use super::*;
+
+    #[test]
+    fn test_userid_cookie() {
+        let userid = Userid {
+            issue: true,
+            name: "uid".to_string(),
+            domain: Some(".example.com".to_string()),
+            path: "/".to_string(),
+            expires: Some(MAX_EXPIRES),
+            flags: vec!["httponly".to_string(), "samesite=lax".to_string()],
+        };
+        let request = |cookie: &str| {
+            let mut req = HttpRequest::new();
+            let raw = format!("GET / HTTP/1.1\r\nHost: a\r\nCookie: {}\r\n\r\n", cookie);
+            req.parse(raw.as_bytes()).unwrap();
+            req
+        };
+        let respond = |req: &HttpRequest| {
+            let mut resp = HttpResponse::new();
+            resp.set_status_line(Version::HTTP_11, StatusCode::OK);
+            userid.filter(req, &mut resp);
+            resp.header_value("Set-Cookie").map(str::to_string)
+        };
+
+        let mut new = request("theme=dark; uid=not-ours");
+        userid.run(&mut new);
+        assert_eq!(new.var(UID_GOT), None);
+        let set = new.var(UID_SET).unwrap().to_string();
+        let value = set.strip_prefix("uid=").unwrap();
+        assert_eq!(value.len(), 32);
+        assert_eq!(
+            respond(&new).unwrap(),
+            format!(
+                "uid={}; Path=/; Max-Age=315360000; Domain=.example.com; HttpOnly; SameSite=Lax",
+                value
+            )
+        );
+
+        let mut returning = request(&format!("theme=dark; {}", set));
+        userid.run(&mut returning);
+        assert_eq!(returning.var(UID_GOT), Some(set.as_str()));
+        assert_eq!(returning.var(UID_SET), None);
+        assert_eq!(respond(&returning), None);
+    }
+}
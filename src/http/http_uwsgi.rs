@@ -0,0 +1,150 @@
+use std::io;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::ConfigContext,
+        },
+        processor::{HttpHandler, LocationPattern},
+    },
+    register_commands,
+};
+
+use super::http_gateway::{
+    handle_protocol_param, handle_protocol_pass, protocol_handler, GatewayProtocol,
+};
+
+register_commands!(
+    CommandBuilder::new("uwsgi_pass")
+        .allowed_parents(vec!["location".to_string()])
+        .display_name("en", "uwsgi Pass")
+        .display_name("zh-tw", "uwsgi 轉送")
+        .desc("en", "Passes requests to a uwsgi server such as uWSGI")
+        .desc("zh-tw", "將請求交給 uwsgi 伺服器（例如 uWSGI）處理")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Address")
+            .display_name("zh-tw", "位址")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc(
+                "en",
+                "host:port, an upstream name, or unix: followed by a socket path"
+            )
+            .desc(
+                "zh-tw",
+                "host:port、upstream 名稱，或 unix: 加上 socket 路徑"
+            )
+            .build()])
+        .build(handle_protocol_pass::<Uwsgi>),
+    CommandBuilder::new("uwsgi_param")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "uwsgi Param")
+        .display_name("zh-tw", "uwsgi 參數")
+        .desc(
+            "en",
+            "Sets a parameter passed to the uwsgi server, replacing any default of the same name"
+        )
+        .desc("zh-tw", "設定傳給 uwsgi 伺服器的參數，會取代同名的預設參數")
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Name")
+                .display_name("zh-tw", "名稱")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Parameter name, e.g. UWSGI_SCRIPT")
+                .desc("zh-tw", "參數名稱，例如 UWSGI_SCRIPT")
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Value")
+                .display_name("zh-tw", "值")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc("en", "Value, may contain variables")
+                .desc("zh-tw", "值，可包含變數")
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "If Not Empty")
+                .display_name("zh-tw", "非空才傳送")
+                .type_name("String")
+                .default("")
+                .desc(
+                    "en",
+                    "if_not_empty to leave the parameter out when its value is empty"
+                )
+                .desc("zh-tw", "if_not_empty 表示值為空時不傳送此參數")
+                .build(),
+        ])
+        .arity(Arity::AtLeast(2))
+        .is_repeatable()
+        .build(handle_protocol_param::<Uwsgi>),
+);
+
+/// The uwsgi protocol.
+#[derive(Debug, Default, Clone)]
+pub struct Uwsgi;
+
+impl GatewayProtocol for Uwsgi {
+    const NAME: &'static str = "uwsgi";
+    const PARAMS: &'static [(&'static str, &'static str, bool)] =
+        &[("PATH_INFO", "$document_uri", false)];
+
+    fn encode(params: &[(String, String)], body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut request = encode_packet(params)?;
+        request.extend_from_slice(body);
+        Ok(request)
+    }
+}
+
+/// Encodes the parameters as a uwsgi packet with both modifiers zero, the
+/// WSGI request type.
+fn encode_packet(params: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "uwsgi parameters exceed 64k");
+    let mut vars = Vec::new();
+    for (name, value) in params {
+        for field in [name, value] {
+            let len = u16::try_from(field.len()).map_err(|_| too_long())?;
+            vars.extend_from_slice(&len.to_le_bytes());
+            vars.extend_from_slice(field.as_bytes());
+        }
+    }
+    let size = u16::try_from(vars.len()).map_err(|_| too_long())?;
+    let mut packet = vec![0];
+    packet.extend_from_slice(&size.to_le_bytes());
+    packet.push(0);
+    packet.extend(vars);
+    Ok(packet)
+}
+
+/// Builds the uwsgi handler for a location chain that sets `uwsgi_pass`.
+pub fn uwsgi_handler(
+    chain: &[&ConfigContext],
+    _pattern: Option<&LocationPattern>,
+) -> Option<HttpHandler> {
+    protocol_handler::<Uwsgi>(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let params = [("PATH_INFO".to_string(), "/items/1".to_string())];
+        let request = Uwsgi::encode(&params, b"body").unwrap();
+        assert_eq!(
+            request,
+            b"\x00\x15\x00\x00\x09\x00PATH_INFO\x08\x00/items/1body".to_vec()
+        );
+
+        let huge = [("X".to_string(), "y".repeat(70_000))];
+        assert!(Uwsgi::encode(&huge, b"").is_err());
+    }
+}
@@ -0,0 +1,734 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::Local;
+use regex::{Captures, Regex, RegexBuilder};
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{CommandBuilder, ParameterBuilder},
+        config_context::ConfigContext,
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::{
+    http_auth_basic::basic_credentials,
+    http_auth_jwt::VerifiedJwt,
+    http_request::{HttpRequest, RequestId},
+    http_response::HttpResponse,
+    http_slice::SliceRange,
+    http_status::StatusSnapshot,
+    http_upstream::UpstreamTiming,
+};
+
+register_commands!(CommandBuilder::new("map")
+    .is_raw_block()
+    .allowed_parents(vec!["http".to_string()])
+    .display_name("en", "Map")
+    .display_name("zh-tw", "變數映射")
+    .desc(
+        "en",
+        "Creates a variable whose value depends on the value of another variable"
+    )
+    .desc("zh-tw", "建立一個依據其他變數值決定其值的變數")
+    .params(vec![
+        ParameterBuilder::new(0)
+            .display_name("en", "Source")
+            .display_name("zh-tw", "來源")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Value to match, may combine variables, e.g. $host")
+            .desc("zh-tw", "要匹配的值，可組合多個變數，例如 $host")
+            .build(),
+        ParameterBuilder::new(1)
+            .display_name("en", "Variable")
+            .display_name("zh-tw", "變數")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "Name of the resulting variable, e.g. $backend")
+            .desc("zh-tw", "結果變數名稱，例如 $backend")
+            .build(),
+    ])
+    .build(handle_map));
+
+pub trait VariableSource: Send + Sync {
+    fn evaluate(&self, vars: &RequestVariables) -> Option<String>;
+}
+
+/// A user-defined variable as declared by a block such as `map`; stored in
+/// the declaring block's context and collected by `VariableRegistry`.
+pub struct VariableDefinition {
+    pub name: String,
+    pub source: Arc<dyn VariableSource>,
+}
+
+#[derive(Default)]
+pub struct VariableRegistry {
+    sources: HashMap<String, Arc<dyn VariableSource>>,
+}
+
+impl VariableRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_config(http_config: &ConfigContext) -> Self {
+        let mut registry = Self::new();
+        for child in &http_config.children {
+            if let Some(def) = child.store.get::<VariableDefinition>() {
+                registry.define(&def.name, def.source.clone());
+            }
+            for def in child
+                .store
+                .get::<Vec<VariableDefinition>>()
+                .iter()
+                .flat_map(|defs| defs.iter())
+            {
+                registry.define(&def.name, def.source.clone());
+            }
+        }
+        registry
+    }
+
+    pub fn define(&mut self, name: &str, source: Arc<dyn VariableSource>) {
+        self.sources.insert(name.to_string(), source);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn VariableSource>> {
+        self.sources.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sources.contains_key(name)
+    }
+}
+
+pub struct RequestVariables<'a> {
+    req: &'a HttpRequest,
+    resp: Option<&'a HttpResponse>,
+    /// Values set by the handler serving the request, such as
+    /// `$document_root`.
+    values: Vec<(&'static str, String)>,
+    evaluating: RefCell<Vec<String>>,
+}
+
+impl<'a> RequestVariables<'a> {
+    pub fn new(req: &'a HttpRequest) -> Self {
+        Self {
+            req,
+            resp: None,
+            values: Vec::new(),
+            evaluating: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn with_response(mut self, resp: &'a HttpResponse) -> Self {
+        self.resp = Some(resp);
+        self
+    }
+
+    pub fn with_value(mut self, name: &'static str, value: String) -> Self {
+        self.values.push((name, value));
+        self
+    }
+
+    pub fn request(&self) -> &HttpRequest {
+        self.req
+    }
+
+    pub fn response(&self) -> Option<&HttpResponse> {
+        self.resp
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        if let Some((_, value)) = self.values.iter().find(|(n, _)| *n == name) {
+            return Some(value.clone());
+        }
+        if let Some(value) = self.req.var(name) {
+            return Some(value.to_string());
+        }
+        if let Some(source) = self.req.variables().and_then(|vars| vars.get(name)) {
+            // A variable that refers back to itself evaluates to nothing on re-entry.
+            if self.evaluating.borrow().iter().any(|n| n == name) {
+                return None;
+            }
+            self.evaluating.borrow_mut().push(name.to_string());
+            let value = source.evaluate(self);
+            self.evaluating.borrow_mut().pop();
+            return value;
+        }
+        self.builtin(name)
+    }
+
+    fn builtin(&self, name: &str) -> Option<String> {
+        let req = self.req;
+        let (path, query) = match req.path().split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (req.path(), None),
+        };
+        let value = match name {
+            "request_method" => req.method().as_str().to_string(),
+            "request_id" => req.extensions().get::<RequestId>()?.0.clone(),
+            "request_uri" => req.request_uri().to_string(),
+            "request" => format!(
+                "{} {} {}",
+                req.method(),
+                req.request_uri(),
+                super::http_request::http_version_to_string(req.version())
+            ),
+            "uri" | "document_uri" => path.to_string(),
+            "args" | "query_string" => query.unwrap_or_default().to_string(),
+            "is_args" => if query.is_some() { "?" } else { "" }.to_string(),
+            "host" => self.host(),
+            "remote_addr" | "binary_remote_addr" => req.remote_addr()?.ip().to_string(),
+            "remote_port" => req.remote_addr()?.port().to_string(),
+            "remote_user" => self.remote_user()?,
+            "server_addr" => req.local_addr()?.ip().to_string(),
+            "server_port" => req.local_addr()?.port().to_string(),
+            "scheme" => if req.is_secure() { "https" } else { "http" }.to_string(),
+            "https" => if req.is_secure() { "on" } else { "" }.to_string(),
+            "server_protocol" => {
+                super::http_request::http_version_to_string(req.version()).to_string()
+            }
+            "proxy_add_x_forwarded_for" => {
+                let client = req.remote_addr()?.ip().to_string();
+                match req.header("X-Forwarded-For") {
+                    Some(previous) => format!("{}, {}", previous, client),
+                    None => client,
+                }
+            }
+            "content_length" => req.header("Content-Length")?.to_string(),
+            "content_type" => req.header("Content-Type")?.to_string(),
+            "request_body" => String::from_utf8_lossy(req.body()).to_string(),
+            "slice_range" => req
+                .extensions()
+                .get::<SliceRange>()
+                .map(|range| range.0.clone())
+                .unwrap_or_default(),
+            "status" => self.resp?.status()?.to_string(),
+            "body_bytes_sent" => self.resp?.body_len().to_string(),
+            "time_local" => Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+            "time_iso8601" => Local::now().format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+            "msec" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+                format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+            }
+            "request_time" => seconds(req.started()?.elapsed()),
+            "upstream_addr" => self.upstream(|timing| Some(timing.address.clone()))?,
+            "upstream_connect_time" => self.upstream(|timing| timing.connect.map(seconds))?,
+            "upstream_header_time" => self.upstream(|timing| timing.header.map(seconds))?,
+            "upstream_response_time" => {
+                self.upstream(|timing| timing.response_time().map(seconds))?
+            }
+            "connections_active" => StatusSnapshot::now().active.to_string(),
+            "connections_reading" => StatusSnapshot::now().reading.to_string(),
+            "connections_writing" => StatusSnapshot::now().writing.to_string(),
+            "connections_waiting" => StatusSnapshot::now().waiting.to_string(),
+            _ => return self.prefixed(name, query),
+        };
+        Some(value)
+    }
+
+    fn prefixed(&self, name: &str, query: Option<&str>) -> Option<String> {
+        if let Some(header) = name.strip_prefix("http_") {
+            let headers = self.req.headers().iter();
+            return find_header(headers.map(|(k, v)| (k.as_str(), v.as_str())), header);
+        }
+        if let Some(header) = name.strip_prefix("sent_http_") {
+            let headers = self.resp?.header.split("\r\n");
+            let headers = headers.filter_map(|line| line.split_once(':'));
+            return find_header(headers.map(|(k, v)| (k.trim(), v.trim())), header);
+        }
+        if let Some(arg) = name.strip_prefix("arg_") {
+            return query?.split('&').find_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (key == arg).then(|| value.to_string())
+            });
+        }
+        if let Some(claim) = name.strip_prefix("jwt_claim_") {
+            let jwt = self.req.extensions().get::<Arc<VerifiedJwt>>()?;
+            return VerifiedJwt::value(&jwt.claims, claim);
+        }
+        if let Some(field) = name.strip_prefix("jwt_header_") {
+            let jwt = self.req.extensions().get::<Arc<VerifiedJwt>>()?;
+            return VerifiedJwt::value(&jwt.header, field);
+        }
+        if let Some(cookie) = name.strip_prefix("cookie_") {
+            return self.req.header("Cookie")?.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                (key == cookie).then(|| value.to_string())
+            });
+        }
+        None
+    }
+
+    /// The user name sent with Basic authentication.
+    fn remote_user(&self) -> Option<String> {
+        basic_credentials(self.req).map(|(user, _)| user)
+    }
+
+    fn host(&self) -> String {
+        match self.req.header("Host") {
+            Some(host) => {
+                let host = match host.strip_prefix('[') {
+                    Some(v6) => v6.split(']').next().unwrap_or_default(),
+                    None => host.split(':').next().unwrap_or_default(),
+                };
+                host.to_ascii_lowercase()
+            }
+            None => self
+                .req
+                .local_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// One value per upstream attempt, joined the way nginx does, with "-"
+    /// for what an attempt never got to.
+    fn upstream(&self, value: impl Fn(&UpstreamTiming) -> Option<String>) -> Option<String> {
+        let attempts = &self.resp?.upstream;
+        if attempts.is_empty() {
+            return None;
+        }
+        let values: Vec<String> = attempts
+            .iter()
+            .map(|timing| value(timing).unwrap_or_else(|| "-".to_string()))
+            .collect();
+        Some(values.join(", "))
+    }
+}
+
+/// Seconds with millisecond resolution, as timing variables are written.
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+fn find_header<'h>(
+    mut headers: impl Iterator<Item = (&'h str, &'h str)>,
+    name: &str,
+) -> Option<String> {
+    headers.find_map(|(key, value)| {
+        let key = key.to_ascii_lowercase().replace('-', "_");
+        (key == name).then(|| value.to_string())
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Variable(String),
+    Capture(usize),
+}
+
+/// A string with embedded `$name` references, compiled once at config time
+/// and rendered per request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarTemplate {
+    source: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl VarTemplate {
+    pub fn parse(source: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((i, c)) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+            let rest = &source[i + 1..];
+            let (name, consumed) = if let Some(braced) = rest.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(end) => (&braced[..end], end + 2),
+                    None => ("", 0),
+                }
+            } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+                (&rest[..1], 1)
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            };
+            if name.is_empty() {
+                literal.push(c);
+                continue;
+            }
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(match name.parse::<usize>() {
+                Ok(index) => TemplatePart::Capture(index),
+                Err(_) => TemplatePart::Variable(name.to_string()),
+            });
+            for _ in 0..consumed {
+                chars.next();
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Self {
+            source: source.to_string(),
+            parts,
+        }
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, TemplatePart::Literal(_)))
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            TemplatePart::Variable(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn render(&self, vars: &RequestVariables) -> String {
+        self.render_with_captures(vars, None)
+    }
+
+    pub fn render_with_captures(
+        &self,
+        vars: &RequestVariables,
+        captures: Option<&Captures>,
+    ) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => out.push_str(text),
+                TemplatePart::Variable(name) => {
+                    if let Some(value) = vars.get(name) {
+                        out.push_str(&value);
+                    }
+                }
+                TemplatePart::Capture(index) => {
+                    if let Some(group) = captures.and_then(|caps| caps.get(*index)) {
+                        out.push_str(group.as_str());
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Renders like `render`, but passes each variable's value, or `None`
+    /// when it is not set, through `escape`; literal text is kept as written.
+    pub fn render_escaped(
+        &self,
+        vars: &RequestVariables,
+        escape: impl Fn(Option<&str>) -> String,
+    ) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(text) => out.push_str(text),
+                TemplatePart::Variable(name) => out.push_str(&escape(vars.get(name).as_deref())),
+                TemplatePart::Capture(_) => out.push_str(&escape(None)),
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for VarTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Extracts the name from a `$name` argument that declares a new variable.
+pub fn parse_variable_name(arg: &str) -> Option<&str> {
+    let name = arg.strip_prefix('$')?;
+    (!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .then_some(name)
+}
+
+/// Compiles the `~pattern` / `~*pattern` regex syntax shared by `map`,
+/// locations and other directives. Returns `None` for plain strings.
+pub fn parse_regex_arg(arg: &str) -> Option<Result<Regex, String>> {
+    let (pattern, case_insensitive) = if let Some(pattern) = arg.strip_prefix("~*") {
+        (pattern, true)
+    } else {
+        (arg.strip_prefix('~')?, false)
+    };
+    Some(
+        RegexBuilder::new(pattern)
+            .case_insensitive(case_insensitive)
+            .build()
+            .map_err(|e| e.to_string()),
+    )
+}
+
+pub struct VarMap {
+    source: VarTemplate,
+    hostnames: bool,
+    exact: HashMap<String, VarTemplate>,
+    leading_wildcards: Vec<(String, VarTemplate)>,
+    trailing_wildcards: Vec<(String, VarTemplate)>,
+    regexes: Vec<(Regex, VarTemplate)>,
+    default: Option<VarTemplate>,
+}
+
+impl VarMap {
+    pub fn new(source: VarTemplate) -> Self {
+        Self {
+            source,
+            hostnames: false,
+            exact: HashMap::new(),
+            leading_wildcards: Vec::new(),
+            trailing_wildcards: Vec::new(),
+            regexes: Vec::new(),
+            default: None,
+        }
+    }
+
+    pub fn set_hostnames(&mut self, hostnames: bool) {
+        self.hostnames = hostnames;
+    }
+
+    pub fn set_default(&mut self, value: &str) {
+        self.default = Some(VarTemplate::parse(value));
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = VarTemplate::parse(value);
+        if let Some(regex) = parse_regex_arg(key) {
+            self.regexes.push((regex?, value));
+            return Ok(());
+        }
+        let key = key.strip_prefix('\\').unwrap_or(key).to_ascii_lowercase();
+        if self.hostnames {
+            if let Some(suffix) = key.strip_prefix("*.").map(|s| format!(".{}", s)) {
+                self.leading_wildcards.push((suffix, value));
+                self.leading_wildcards
+                    .sort_by_key(|(key, _)| Reverse(key.len()));
+                return Ok(());
+            }
+            if let Some(domain) = key.strip_prefix('.') {
+                self.exact.insert(domain.to_string(), value.clone());
+                self.leading_wildcards.push((key.clone(), value));
+                self.leading_wildcards
+                    .sort_by_key(|(key, _)| Reverse(key.len()));
+                return Ok(());
+            }
+            if let Some(prefix) = key.strip_suffix(".*").map(|s| format!("{}.", s)) {
+                self.trailing_wildcards.push((prefix, value));
+                self.trailing_wildcards
+                    .sort_by_key(|(key, _)| Reverse(key.len()));
+                return Ok(());
+            }
+        }
+        if self.exact.contains_key(&key) {
+            return Err(format!("duplicate key \"{}\"", key));
+        }
+        self.exact.insert(key, value);
+        Ok(())
+    }
+
+    pub fn lookup(&self, vars: &RequestVariables, input: &str) -> Option<String> {
+        let lowered = input.to_ascii_lowercase();
+        let lowered = if self.hostnames {
+            lowered.trim_end_matches('.')
+        } else {
+            lowered.as_str()
+        };
+        if let Some(value) = self.exact.get(lowered) {
+            return Some(value.render(vars));
+        }
+        if self.hostnames {
+            let wildcard = self
+                .leading_wildcards
+                .iter()
+                .find(|(suffix, _)| lowered.ends_with(suffix.as_str()))
+                .or_else(|| {
+                    self.trailing_wildcards
+                        .iter()
+                        .find(|(prefix, _)| lowered.starts_with(prefix.as_str()))
+                });
+            if let Some((_, value)) = wildcard {
+                return Some(value.render(vars));
+            }
+        }
+        for (regex, value) in &self.regexes {
+            if let Some(captures) = regex.captures(input) {
+                return Some(value.render_with_captures(vars, Some(&captures)));
+            }
+        }
+        self.default.as_ref().map(|value| value.render(vars))
+    }
+}
+
+impl VariableSource for VarMap {
+    fn evaluate(&self, vars: &RequestVariables) -> Option<String> {
+        let input = self.source.render(vars);
+        Some(self.lookup(vars, &input).unwrap_or_default())
+    }
+}
+
+pub fn handle_map(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let source = ctx.str_arg(0)?;
+    let target = ctx.str_arg(1)?;
+    let name = parse_variable_name(&target)
+        .ok_or_else(|| ctx.invalid_value(&target, "variable name must start with \"$\""))?
+        .to_string();
+
+    let mut map = VarMap::new(VarTemplate::parse(&source));
+    map.set_hostnames(
+        ctx.raw_entries
+            .iter()
+            .any(|entry| entry.args.len() == 1 && entry.args[0] == "hostnames"),
+    );
+    for entry in &ctx.raw_entries {
+        match entry.args.as_slice() {
+            [flag] if flag == "hostnames" || flag == "volatile" => {}
+            [key, value] if key == "default" => map.set_default(value),
+            [key, value] => map
+                .insert(key, value)
+                .map_err(|reason| ctx.invalid_entry(entry, key, reason))?,
+            _ => {
+                return Err(ConfigError::InvalidArgCount {
+                    name: ctx.current_cmd_name.clone(),
+                    pos: entry.pos.clone(),
+                })
+            }
+        }
+    }
+
+    ctx.store.insert(Arc::new(VariableDefinition {
+        name,
+        source: Arc::new(map),
+    }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str, registry: VariableRegistry) -> HttpRequest {
+        let mut req = HttpRequest::new();
+        req.parse(raw.as_bytes()).unwrap();
+        req.set_connection(
+            Some("10.1.2.3:54321".parse().unwrap()),
+            Some("127.0.0.1:8080".parse().unwrap()),
+            false,
+        );
+        req.set_variables(Arc::new(registry));
+        req
+    }
+
+    #[test]
+    fn test_builtin_variables() {
+        let req = request(
+            "GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: Example.com:8080\r\nX-Real-Thing: yes\r\nCookie: sid=abc; theme=dark\r\n\r\n",
+            VariableRegistry::new(),
+        );
+        let vars = RequestVariables::new(&req);
+        let template =
+            VarTemplate::parse("$scheme://$host$uri$is_args$args from $remote_addr:$remote_port");
+        assert_eq!(
+            template.render(&vars),
+            "http://example.com/a/b?x=1&y=2 from 10.1.2.3:54321"
+        );
+        assert_eq!(vars.get("arg_y").as_deref(), Some("2"));
+        assert_eq!(vars.get("http_x_real_thing").as_deref(), Some("yes"));
+        assert_eq!(vars.get("cookie_theme").as_deref(), Some("dark"));
+        assert_eq!(
+            VarTemplate::parse("${uri}x $1 100$").render(&vars),
+            "/a/bx  100$"
+        );
+    }
+
+    #[test]
+    fn test_upstream_timing_variables() {
+        let mut req = request("GET / HTTP/1.1\r\n\r\n", VariableRegistry::new());
+        req.set_started(std::time::Instant::now() - Duration::from_secs(2));
+        let failed = UpstreamTiming {
+            address: "10.0.0.1:80".to_string(),
+            connect: Some(Duration::from_millis(3000)),
+            ..Default::default()
+        };
+        failed.response.set(Duration::from_millis(3000)).unwrap();
+        let answered = UpstreamTiming {
+            address: "10.0.0.2:80".to_string(),
+            connect: Some(Duration::from_millis(2)),
+            header: Some(Duration::from_millis(40)),
+            ..Default::default()
+        };
+        let mut resp = HttpResponse::new();
+        resp.upstream = vec![failed, answered];
+
+        let vars = RequestVariables::new(&req).with_response(&resp);
+        let template = VarTemplate::parse(
+            "$upstream_addr|$upstream_connect_time|$upstream_header_time|$upstream_response_time",
+        );
+        assert_eq!(
+            template.render(&vars),
+            "10.0.0.1:80, 10.0.0.2:80|3.000, 0.002|-, 0.040|3.000, -"
+        );
+        assert!(vars.get("request_time").unwrap().starts_with("2.0"));
+        assert_eq!(RequestVariables::new(&req).get("upstream_addr"), None);
+    }
+
+    #[test]
+    fn test_map_matches_exact_wildcard_regex_and_default() {
+        let mut map = VarMap::new(VarTemplate::parse("$host"));
+        map.set_hostnames(true);
+        map.insert("example.com", "main").unwrap();
+        map.insert("*.example.com", "sub").unwrap();
+        map.insert("~^(?P<name>\\w+)\\.test$", "test-$1").unwrap();
+        map.set_default("other");
+        let mut registry = VariableRegistry::new();
+        registry.define("site", Arc::new(map));
+
+        let cases = [
+            ("EXAMPLE.com", "main"),
+            ("api.example.com", "sub"),
+            ("foo.test", "test-foo"),
+            ("unknown.org", "other"),
+        ];
+        for (host, expected) in cases {
+            let mut registry_copy = VariableRegistry::new();
+            registry_copy.sources = registry.sources.clone();
+            let req = request(
+                &format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host),
+                registry_copy,
+            );
+            let vars = RequestVariables::new(&req);
+            assert_eq!(vars.get("site").as_deref(), Some(expected), "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_self_referencing_map_terminates() {
+        let mut map = VarMap::new(VarTemplate::parse("$loop"));
+        map.set_default("x$loop");
+        let mut registry = VariableRegistry::new();
+        registry.define("loop", Arc::new(map));
+        let req = request("GET / HTTP/1.1\r\n\r\n", registry);
+        assert_eq!(
+            RequestVariables::new(&req).get("loop").as_deref(),
+            Some("x")
+        );
+    }
+}
@@ -0,0 +1,1111 @@
+use std::{fs, sync::Arc};
+
+use http::StatusCode;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde_json::Value;
+use url::form_urlencoded;
+
+use crate::{
+    core::{
+        config::{
+            command::{Arity, CommandBuilder, ParameterBuilder},
+            config_context::{merged_config, ConfigContext, MergeConfig},
+            config_loader::ConfigError,
+        },
+        processor::{HttpProcessor, PhaseResult, RequestPhase},
+    },
+    log_warn, register_commands,
+};
+
+use super::http_request::HttpRequest;
+
+register_commands!(
+    CommandBuilder::new("waf")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Web Application Firewall")
+        .display_name("zh-tw", "網站應用程式防火牆")
+        .desc(
+            "en",
+            "Checks requests against the waf rules; detect only logs the requests that would be blocked"
+        )
+        .desc(
+            "zh-tw",
+            "以 waf 規則檢查請求；detect 只記錄會被阻擋的請求而不阻擋"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Mode")
+            .display_name("zh-tw", "模式")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "on, detect or off (the default)")
+            .desc("zh-tw", "on、detect 或 off（預設）")
+            .build()])
+        .build(handle_waf),
+    CommandBuilder::new("waf_rule")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WAF Rule")
+        .display_name("zh-tw", "WAF 規則")
+        .desc(
+            "en",
+            "Adds a rule in ModSecurity SecRule syntax; rules of outer blocks apply too"
+        )
+        .desc(
+            "zh-tw",
+            "以 ModSecurity SecRule 語法新增規則；外層區塊的規則同樣適用"
+        )
+        .params(vec![
+            ParameterBuilder::new(0)
+                .display_name("en", "Variables")
+                .display_name("zh-tw", "檢查對象")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "What to inspect, joined by |, e.g. ARGS|REQUEST_HEADERS:User-Agent|!ARGS:password"
+                )
+                .desc(
+                    "zh-tw",
+                    "要檢查的內容，以 | 連接，例如 ARGS|REQUEST_HEADERS:User-Agent|!ARGS:password"
+                )
+                .build(),
+            ParameterBuilder::new(1)
+                .display_name("en", "Operator")
+                .display_name("zh-tw", "比對方式")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "A regex, or @rx, @contains, @streq, @beginsWith, @endsWith, @within or @pm with its argument; ! negates it"
+                )
+                .desc(
+                    "zh-tw",
+                    "正規表示式，或 @rx、@contains、@streq、@beginsWith、@endsWith、@within、@pm 加上參數；! 表示反向"
+                )
+                .build(),
+            ParameterBuilder::new(2)
+                .display_name("en", "Actions")
+                .display_name("zh-tw", "動作")
+                .type_name("String")
+                .is_required(true)
+                .default("")
+                .desc(
+                    "en",
+                    "Comma separated, e.g. id:1001,deny,status:403,t:lowercase,msg:'SQL injection'"
+                )
+                .desc(
+                    "zh-tw",
+                    "以逗號分隔，例如 id:1001,deny,status:403,t:lowercase,msg:'SQL injection'"
+                )
+                .build(),
+        ])
+        .arity(Arity::Exact(3))
+        .build(handle_waf_rule),
+    CommandBuilder::new("waf_rules_file")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WAF Rules File")
+        .display_name("zh-tw", "WAF 規則檔")
+        .desc(
+            "en",
+            "Loads SecRule and SecRuleRemoveById lines from a ModSecurity style file"
+        )
+        .desc(
+            "zh-tw",
+            "從 ModSecurity 格式的檔案載入 SecRule 與 SecRuleRemoveById"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Path")
+            .display_name("zh-tw", "路徑")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "The rules file")
+            .desc("zh-tw", "規則檔")
+            .build()])
+        .build(handle_waf_rules_file),
+    CommandBuilder::new("waf_remove_rule")
+        .is_repeatable()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Remove WAF Rules")
+        .display_name("zh-tw", "移除 WAF 規則")
+        .desc(
+            "en",
+            "Turns off inherited rules in this block, for false positives"
+        )
+        .desc("zh-tw", "在此區塊停用繼承來的規則，用於排除誤判")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Rule IDs")
+            .display_name("zh-tw", "規則編號")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "IDs or ranges such as 942100-942999")
+            .desc("zh-tw", "編號或範圍，例如 942100-942999")
+            .build()])
+        .arity(Arity::AtLeast(1))
+        .build(handle_waf_remove_rule),
+    CommandBuilder::new("waf_score_threshold")
+        .is_unique()
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "WAF Score Threshold")
+        .display_name("zh-tw", "WAF 分數門檻")
+        .desc(
+            "en",
+            "Blocks a request with 403 once the scores of the rules it matches add up to this"
+        )
+        .desc(
+            "zh-tw",
+            "請求符合的規則分數總和達到此值時以 403 阻擋"
+        )
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Score")
+            .display_name("zh-tw", "分數")
+            .type_name("String")
+            .is_required(true)
+            .default("")
+            .desc("en", "5 by default; 0 never blocks on the score")
+            .desc("zh-tw", "預設 5；設為 0 則不依分數阻擋")
+            .build()])
+        .build(handle_waf_score_threshold)
+);
+
+/// CRS blocks at the score of one critical match by default.
+pub const DEFAULT_SCORE_THRESHOLD: u32 = 5;
+/// The total score of the rules a request matched.
+pub const WAF_SCORE: &str = "waf_score";
+/// The IDs of the rules a request matched, separated by commas.
+pub const WAF_RULES: &str = "waf_rules";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WafMode {
+    Off,
+    On,
+    /// Logs what would be blocked.
+    Detect,
+}
+
+/// A part of the request a rule inspects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Collection {
+    Uri,
+    Filename,
+    QueryString,
+    Method,
+    RemoteAddr,
+    Body,
+    Args,
+    ArgsNames,
+    Headers,
+    HeadersNames,
+    Cookies,
+    CookiesNames,
+}
+
+impl Collection {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "REQUEST_URI" => Self::Uri,
+            "REQUEST_FILENAME" => Self::Filename,
+            "QUERY_STRING" => Self::QueryString,
+            "REQUEST_METHOD" => Self::Method,
+            "REMOTE_ADDR" => Self::RemoteAddr,
+            "REQUEST_BODY" => Self::Body,
+            "ARGS" => Self::Args,
+            "ARGS_NAMES" => Self::ArgsNames,
+            "REQUEST_HEADERS" => Self::Headers,
+            "REQUEST_HEADERS_NAMES" => Self::HeadersNames,
+            "REQUEST_COOKIES" => Self::Cookies,
+            "REQUEST_COOKIES_NAMES" => Self::CookiesNames,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Uri => "REQUEST_URI",
+            Self::Filename => "REQUEST_FILENAME",
+            Self::QueryString => "QUERY_STRING",
+            Self::Method => "REQUEST_METHOD",
+            Self::RemoteAddr => "REMOTE_ADDR",
+            Self::Body => "REQUEST_BODY",
+            Self::Args => "ARGS",
+            Self::ArgsNames => "ARGS_NAMES",
+            Self::Headers => "REQUEST_HEADERS",
+            Self::HeadersNames => "REQUEST_HEADERS_NAMES",
+            Self::Cookies => "REQUEST_COOKIES",
+            Self::CookiesNames => "REQUEST_COOKIES_NAMES",
+        }
+    }
+}
+
+/// A collection, or one member of it, such as `ARGS:q`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Target {
+    collection: Collection,
+    key: Option<String>,
+}
+
+impl Target {
+    fn parse(target: &str) -> Result<Self, String> {
+        let (name, key) = match target.split_once(':') {
+            Some((name, key)) => (name, Some(key.to_string())),
+            None => (target, None),
+        };
+        let collection =
+            Collection::parse(name).ok_or_else(|| format!("unknown variable \"{}\"", name))?;
+        let keyed = matches!(
+            collection,
+            Collection::Args | Collection::Headers | Collection::Cookies
+        );
+        if key.is_some() && !keyed {
+            return Err(format!("\"{}\" has no members", name));
+        }
+        Ok(Self { collection, key })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Operator {
+    Rx(Regex),
+    Contains(String),
+    StrEq(String),
+    BeginsWith(String),
+    EndsWith(String),
+    Within(String),
+    /// Any of the phrases, ignoring case.
+    Pm(Vec<String>),
+}
+
+impl Operator {
+    fn parse(operator: &str) -> Result<(Self, bool), String> {
+        let (negated, operator) = match operator.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, operator),
+        };
+        let (name, argument) = match operator.strip_prefix('@') {
+            Some(rest) => rest.split_once(' ').unwrap_or((rest, "")),
+            None => ("rx", operator),
+        };
+        let argument = argument.to_string();
+        let operator = match name {
+            "rx" => Self::Rx(Regex::new(&argument).map_err(|e| e.to_string())?),
+            "contains" => Self::Contains(argument),
+            "streq" => Self::StrEq(argument),
+            "beginsWith" => Self::BeginsWith(argument),
+            "endsWith" => Self::EndsWith(argument),
+            "within" => Self::Within(argument),
+            "pm" => Self::Pm(argument.split_whitespace().map(str::to_lowercase).collect()),
+            _ => return Err(format!("unsupported operator \"@{}\"", name)),
+        };
+        Ok((operator, negated))
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Rx(regex) => regex.is_match(value),
+            Self::Contains(needle) => value.contains(needle.as_str()),
+            Self::StrEq(expected) => value == expected,
+            Self::BeginsWith(prefix) => value.starts_with(prefix.as_str()),
+            Self::EndsWith(suffix) => value.ends_with(suffix.as_str()),
+            Self::Within(haystack) => haystack.contains(value),
+            Self::Pm(phrases) => {
+                let value = value.to_lowercase();
+                phrases.iter().any(|phrase| value.contains(phrase.as_str()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transform {
+    Lowercase,
+    UrlDecode,
+    CompressWhitespace,
+    RemoveWhitespace,
+    RemoveNulls,
+    Trim,
+}
+
+impl Transform {
+    fn apply(self, value: String) -> String {
+        match self {
+            Self::Lowercase => value.to_lowercase(),
+            Self::UrlDecode => percent_decode_str(&value.replace('+', " "))
+                .decode_utf8_lossy()
+                .into_owned(),
+            Self::CompressWhitespace => value.split_whitespace().collect::<Vec<_>>().join(" "),
+            Self::RemoveWhitespace => value.chars().filter(|c| !c.is_whitespace()).collect(),
+            Self::RemoveNulls => value.replace('\0', ""),
+            Self::Trim => value.trim().to_string(),
+        }
+    }
+}
+
+/// A rule, in the subset of the ModSecurity `SecRule` language blur reads.
+#[derive(Debug, Clone)]
+pub struct WafRule {
+    pub id: u32,
+    targets: Vec<Target>,
+    excluded: Vec<Target>,
+    operator: Operator,
+    negated: bool,
+    transforms: Vec<Transform>,
+    /// Blocks as soon as it matches, rather than only adding its score.
+    deny: bool,
+    status: StatusCode,
+    log: bool,
+    score: u32,
+    msg: String,
+}
+
+impl WafRule {
+    pub fn parse(variables: &str, operator: &str, actions: &str) -> Result<Self, String> {
+        let mut targets = Vec::new();
+        let mut excluded = Vec::new();
+        for variable in variables.split('|').filter(|v| !v.is_empty()) {
+            match variable.strip_prefix('!') {
+                Some(variable) => excluded.push(Target::parse(variable)?),
+                None => targets.push(Target::parse(variable)?),
+            }
+        }
+        if targets.is_empty() {
+            return Err("a rule needs a variable to inspect".to_string());
+        }
+        let (operator, negated) = Operator::parse(operator)?;
+        let mut rule = Self {
+            id: 0,
+            targets,
+            excluded,
+            operator,
+            negated,
+            transforms: Vec::new(),
+            deny: false,
+            status: StatusCode::FORBIDDEN,
+            log: true,
+            score: 0,
+            msg: String::new(),
+        };
+        for action in split_actions(actions) {
+            let (name, value) = match action.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim().trim_matches('\'')),
+                None => (action.trim(), ""),
+            };
+            match name {
+                "id" => rule.id = value.parse().map_err(|_| "invalid id".to_string())?,
+                "deny" | "block" => rule.deny = true,
+                "pass" => rule.deny = false,
+                "log" => rule.log = true,
+                "nolog" => rule.log = false,
+                "msg" => rule.msg = value.to_string(),
+                "status" => {
+                    rule.status = value
+                        .parse::<u16>()
+                        .ok()
+                        .and_then(|code| StatusCode::from_u16(code).ok())
+                        .ok_or_else(|| format!("invalid status \"{}\"", value))?;
+                }
+                "score" => rule.score = value.parse().map_err(|_| "invalid score".to_string())?,
+                "severity" => {
+                    // The anomaly scores CRS gives each severity.
+                    let score = match value.to_ascii_uppercase().as_str() {
+                        "CRITICAL" | "2" => 5,
+                        "ERROR" | "3" => 4,
+                        "WARNING" | "4" => 3,
+                        "NOTICE" | "5" => 2,
+                        _ => 0,
+                    };
+                    if rule.score == 0 {
+                        rule.score = score;
+                    }
+                }
+                "t" => match value {
+                    "none" => rule.transforms.clear(),
+                    "lowercase" => rule.transforms.push(Transform::Lowercase),
+                    "urlDecode" | "urlDecodeUni" => rule.transforms.push(Transform::UrlDecode),
+                    "compressWhitespace" => rule.transforms.push(Transform::CompressWhitespace),
+                    "removeWhitespace" => rule.transforms.push(Transform::RemoveWhitespace),
+                    "removeNulls" => rule.transforms.push(Transform::RemoveNulls),
+                    "trim" => rule.transforms.push(Transform::Trim),
+                    _ => return Err(format!("unsupported transformation \"{}\"", value)),
+                },
+                // Metadata that does not change how the rule runs.
+                "phase" | "rev" | "ver" | "tag" | "logdata" | "capture" | "accuracy"
+                | "maturity" | "auditlog" | "noauditlog" => {}
+                _ => return Err(format!("unsupported action \"{}\"", name)),
+            }
+        }
+        if rule.id == 0 {
+            return Err("a rule needs an id".to_string());
+        }
+        Ok(rule)
+    }
+
+    /// The first inspected value the rule matches, named like `ARGS:q`.
+    fn find_match(&self, request: &Inspected) -> Option<String> {
+        for target in &self.targets {
+            for (name, value) in request.values(target) {
+                let excluded = self.excluded.iter().any(|excluded| {
+                    excluded.collection == target.collection
+                        && excluded
+                            .key
+                            .as_ref()
+                            .is_none_or(|key| key.eq_ignore_ascii_case(&name))
+                });
+                if excluded {
+                    continue;
+                }
+                let value = self
+                    .transforms
+                    .iter()
+                    .fold(value.to_string(), |value, transform| transform.apply(value));
+                if self.operator.matches(&value) != self.negated {
+                    return Some(if name.is_empty() {
+                        target.collection.name().to_string()
+                    } else {
+                        format!("{}:{}", target.collection.name(), name)
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Splits actions at the commas outside single quotes.
+fn split_actions(actions: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in actions.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' if !quoted => {
+                parts.push(&actions[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&actions[start..]);
+    parts
+        .into_iter()
+        .filter(|part| !part.trim().is_empty())
+        .collect()
+}
+
+/// The parts of a request the rules look at, decoded once.
+struct Inspected {
+    uri: String,
+    filename: String,
+    query: String,
+    method: String,
+    remote_addr: String,
+    body: String,
+    args: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    cookies: Vec<(String, String)>,
+}
+
+impl Inspected {
+    fn new(req: &HttpRequest) -> Self {
+        let uri = req.request_uri().to_string();
+        let (filename, query) = match uri.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (uri.clone(), String::new()),
+        };
+        let mut args: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let form = req.header("Content-Type").is_some_and(|content_type| {
+            content_type
+                .to_ascii_lowercase()
+                .starts_with("application/x-www-form-urlencoded")
+        });
+        if form {
+            args.extend(form_urlencoded::parse(req.body()).into_owned());
+        }
+        let cookies = req
+            .header("Cookie")
+            .unwrap_or_default()
+            .split(';')
+            .filter_map(|cookie| cookie.split_once('='))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Self {
+            filename: percent_decode_str(&filename)
+                .decode_utf8_lossy()
+                .into_owned(),
+            uri,
+            query,
+            method: req.method().to_string(),
+            remote_addr: req
+                .remote_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            body: String::from_utf8_lossy(req.body()).into_owned(),
+            args,
+            headers: req
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            cookies,
+        }
+    }
+
+    /// The values of a target, each with its member name.
+    fn values(&self, target: &Target) -> Vec<(String, &str)> {
+        let single = match target.collection {
+            Collection::Uri => &self.uri,
+            Collection::Filename => &self.filename,
+            Collection::QueryString => &self.query,
+            Collection::Method => &self.method,
+            Collection::RemoteAddr => &self.remote_addr,
+            Collection::Body => &self.body,
+            _ => {
+                let (pairs, names_only) = match target.collection {
+                    Collection::Args => (&self.args, false),
+                    Collection::ArgsNames => (&self.args, true),
+                    Collection::Headers => (&self.headers, false),
+                    Collection::HeadersNames => (&self.headers, true),
+                    Collection::Cookies => (&self.cookies, false),
+                    _ => (&self.cookies, true),
+                };
+                return pairs
+                    .iter()
+                    .filter(|(name, _)| {
+                        target
+                            .key
+                            .as_ref()
+                            .is_none_or(|key| key.eq_ignore_ascii_case(name))
+                    })
+                    .map(|(name, value)| {
+                        (name.clone(), if names_only { name } else { value }.as_str())
+                    })
+                    .collect();
+            }
+        };
+        vec![(String::new(), single.as_str())]
+    }
+}
+
+/// Rule IDs turned off in a block, as inclusive ranges.
+type RemovedRules = Vec<(u32, u32)>;
+
+#[derive(Debug, Default, Clone)]
+pub struct WafConfig {
+    pub mode: Option<WafMode>,
+    pub rules: Vec<Arc<WafRule>>,
+    pub removed: RemovedRules,
+    pub score_threshold: Option<u32>,
+}
+
+impl MergeConfig for WafConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.mode = self.mode.or(parent.mode);
+        self.rules = parent.rules.iter().chain(&self.rules).cloned().collect();
+        self.removed = parent
+            .removed
+            .iter()
+            .chain(&self.removed)
+            .copied()
+            .collect();
+        self.score_threshold = self.score_threshold.or(parent.score_threshold);
+    }
+}
+
+/// Checks requests against the rules of a block.
+pub struct WafPhase {
+    rules: Vec<Arc<WafRule>>,
+    detect_only: bool,
+    score_threshold: u32,
+}
+
+impl RequestPhase for WafPhase {
+    fn run(&self, req: &mut HttpRequest) -> PhaseResult {
+        let request = Inspected::new(req);
+        let mut score = 0;
+        let mut matched = Vec::new();
+        let mut blocked = None;
+        for rule in &self.rules {
+            let Some(target) = rule.find_match(&request) else {
+                continue;
+            };
+            score += rule.score;
+            matched.push(rule.id.to_string());
+            let blocks = rule.deny
+                || (self.score_threshold > 0 && score >= self.score_threshold && rule.score > 0);
+            if rule.log || blocks {
+                log_warn!(
+                    "{}rule {} matched {}{}, score: {}, client: {}, request: \"{} {}\"",
+                    if blocks && self.detect_only {
+                        "would block, "
+                    } else {
+                        ""
+                    },
+                    rule.id,
+                    target,
+                    if rule.msg.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" \"{}\"", rule.msg)
+                    },
+                    score,
+                    request.remote_addr,
+                    request.method,
+                    request.uri
+                );
+            }
+            if blocks && !self.detect_only {
+                blocked = Some(if rule.deny {
+                    rule.status
+                } else {
+                    StatusCode::FORBIDDEN
+                });
+                break;
+            }
+        }
+        req.set_var(WAF_SCORE, &score.to_string());
+        req.set_var(WAF_RULES, &matched.join(","));
+        match blocked {
+            Some(status) => PhaseResult::Respond(Box::new(HttpProcessor::create_status_response(
+                req.version(),
+                status,
+            ))),
+            None => PhaseResult::Continue,
+        }
+    }
+}
+
+/// Builds the rule check for a block chain where `waf` is on.
+pub fn waf_phase(chain: &[&ConfigContext]) -> Option<WafPhase> {
+    let config = merged_config::<WafConfig>(chain);
+    let detect_only = match config.mode? {
+        WafMode::Off => return None,
+        WafMode::On => false,
+        WafMode::Detect => true,
+    };
+    let rules: Vec<Arc<WafRule>> = config
+        .rules
+        .into_iter()
+        .filter(|rule| {
+            !config
+                .removed
+                .iter()
+                .any(|(first, last)| (*first..=*last).contains(&rule.id))
+        })
+        .collect();
+    (!rules.is_empty()).then(|| WafPhase {
+        rules,
+        detect_only,
+        score_threshold: config.score_threshold.unwrap_or(DEFAULT_SCORE_THRESHOLD),
+    })
+}
+
+/// Reads a rules file: `SecRule` and `SecRuleRemoveById` lines, with `#`
+/// comments and lines continued by a trailing backslash.
+pub fn parse_rules_file(source: &str) -> Result<(Vec<WafRule>, RemovedRules), String> {
+    let mut rules = Vec::new();
+    let mut removed = Vec::new();
+    let mut statement = String::new();
+    let mut first_line = 0;
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if statement.is_empty() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            first_line = number + 1;
+        }
+        if let Some(continued) = line.strip_suffix('\\') {
+            statement.push_str(continued);
+            statement.push(' ');
+            continue;
+        }
+        statement.push_str(line);
+        let words = split_words(&std::mem::take(&mut statement));
+        let result = match words.split_first() {
+            Some((directive, args)) if directive == "SecRule" => match args {
+                [variables, operator, actions] => {
+                    WafRule::parse(variables, operator, actions).map(|rule| rules.push(rule))
+                }
+                [variables, operator] => {
+                    WafRule::parse(variables, operator, "").map(|rule| rules.push(rule))
+                }
+                _ => Err("expected variables, an operator and actions".to_string()),
+            },
+            Some((directive, ids)) if directive == "SecRuleRemoveById" => ids
+                .iter()
+                .try_for_each(|id| parse_id_range(id).map(|range| removed.push(range))),
+            Some((directive, _)) => Err(format!("unsupported directive \"{}\"", directive)),
+            None => Ok(()),
+        };
+        result.map_err(|reason| format!("line {}: {}", first_line, reason))?;
+    }
+    Ok((rules, removed))
+}
+
+/// Splits a rules file statement at spaces outside double quotes, keeping
+/// backslashes other than the one escaping a quote.
+fn split_words(statement: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'"') => word.push(chars.next().unwrap_or('"')),
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_id_range(range: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("invalid rule id \"{}\"", range);
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first = first.parse().map_err(|_| invalid())?;
+    let last = last.parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+pub fn handle_waf(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(mode) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    let mode = match mode.as_str() {
+        "on" => WafMode::On,
+        "detect" => WafMode::Detect,
+        "off" => WafMode::Off,
+        _ => return Err(ctx.invalid_value(&mode, "expected on, detect or off")),
+    };
+    if let Ok(mut config) = ctx.block_config::<WafConfig>().lock() {
+        config.mode = Some(mode);
+    }
+    Ok(())
+}
+
+pub fn handle_waf_rule(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let args = ctx.args();
+    let [variables, operator, actions] = args.as_slice() else {
+        return Ok(());
+    };
+    let rule = WafRule::parse(variables, operator, actions)
+        .map_err(|reason| ctx.invalid_value(operator, reason))?;
+    if let Ok(mut config) = ctx.block_config::<WafConfig>().lock() {
+        config.rules.push(Arc::new(rule));
+    }
+    Ok(())
+}
+
+pub fn handle_waf_rules_file(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let Some(path) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    let source = fs::read_to_string(&path).map_err(|e| ctx.invalid_value(&path, e.to_string()))?;
+    let (rules, removed) =
+        parse_rules_file(&source).map_err(|reason| ctx.invalid_value(&path, reason))?;
+    if let Ok(mut config) = ctx.block_config::<WafConfig>().lock() {
+        config.rules.extend(rules.into_iter().map(Arc::new));
+        config.removed.extend(removed);
+    }
+    Ok(())
+}
+
+pub fn handle_waf_remove_rule(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    let mut removed = Vec::new();
+    for id in ctx.args() {
+        removed.push(parse_id_range(&id).map_err(|reason| ctx.invalid_value(&id, reason))?);
+    }
+    if let Ok(mut config) = ctx.block_config::<WafConfig>().lock() {
+        config.removed.extend(removed);
+    }
+    Ok(())
+}
+
+pub fn handle_waf_score_threshold(
+    ctx: &mut ConfigContext,
+    _config: &Value,
+) -> Result<(), ConfigError> {
+    let Some(score) = ctx.args().into_iter().next() else {
+        return Ok(());
+    };
+    let score = score
+        .parse()
+        .map_err(|_| ctx.invalid_value(&score, "expected a whole number"))?;
+    if let Ok(mut config) = ctx.block_config::<WafConfig>().lock() {
+        config.score_threshold = Some(score);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phase(mode: WafMode, rules: &[&str], threshold: u32) -> WafPhase {
+        let (rules, removed) = parse_rules_file(&rules.join("\n")).unwrap();
+        let mut ctx = ConfigContext::new_empty("location", Vec::new());
+        *ctx.block_config::<WafConfig>().lock().unwrap() = WafConfig {
+            mode: Some(mode),
+            rules: rules.into_iter().map(Arc::new).collect(),
+            removed,
+            score_threshold: Some(threshold),
+        };
+        waf_phase(&[&ctx]).unwrap()
+    }
+
+    /// The status the phase blocked with, the rules that matched and the score.
+    fn check(phase: &WafPhase, raw: &str) -> (Option<u16>, String, String) {
+        let mut req = HttpRequest::new();
+        req.parse(raw.as_bytes()).unwrap();
+        let status = match phase.run(&mut req) {
+            PhaseResult::Respond(resp) => resp.status(),
+            _ => None,
+        };
+        (
+            status,
+            req.var(WAF_RULES).unwrap_or_default().to_string(),
+            req.var(WAF_SCORE).unwrap_or_default().to_string(),
+        )
+    }
+
+    fn matches(operator: &str, value: &str) -> bool {
+        let (operator, negated) = Operator::parse(operator).unwrap();
+        operator.matches(value) != negated
+    }
+
+    #[test]
+    fn test_operators() {
+        assert!(matches(r"(?i)union\s+select", "1 UNION  select x"));
+        assert!(matches(r"@rx ^\d+$", "42"));
+        assert!(!matches(r"@rx ^\d+$", "4a"));
+        assert!(matches("@contains /admin", "/site/admin/users"));
+        assert!(matches("@streq debug", "debug"));
+        assert!(!matches("@streq debug", "debugger"));
+        assert!(matches("@beginsWith /api", "/api/v1"));
+        assert!(matches("@endsWith .php", "/index.php"));
+        assert!(matches("@within GET HEAD", "HEAD"));
+        assert!(!matches("@within GET HEAD", "POST"));
+        assert!(matches("@pm sqlmap nikto", "Mozilla NIKTO/2.5"));
+        assert!(matches("!@within GET HEAD", "DELETE"));
+        assert!(!matches("!@contains x", "xyz"));
+    }
+
+    #[test]
+    fn test_variables_and_exclusions() {
+        let phase = phase(
+            WafMode::On,
+            &[
+                r#"SecRule ARGS|!ARGS:comment "@contains <script" "id:1,deny""#,
+                r#"SecRule REQUEST_HEADERS:User-Agent "@pm sqlmap" "id:2,deny,status:406""#,
+                r#"SecRule REQUEST_COOKIES_NAMES "@streq debug" "id:3,deny""#,
+                r#"SecRule REQUEST_FILENAME "@endsWith .env" "id:4,deny""#,
+                r#"SecRule REQUEST_METHOD "@streq TRACE" "id:5,deny""#,
+            ],
+            0,
+        );
+        let query = "GET /?q=%3Cscript%3E HTTP/1.1\r\n\r\n";
+        assert_eq!(check(&phase, query).0, Some(403));
+        let form = "POST /post HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 18\r\n\r\nname=%3Cscript%3E";
+        assert_eq!(check(&phase, form).1, "1");
+        let comment = "POST /post HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 21\r\n\r\ncomment=%3Cscript%3E";
+        assert_eq!(
+            check(&phase, comment),
+            (None, String::new(), "0".to_string())
+        );
+        let scanner = "GET / HTTP/1.1\r\nUser-Agent: sqlmap/1.7\r\n\r\n";
+        assert_eq!(check(&phase, scanner).0, Some(406));
+        let cookie = "GET / HTTP/1.1\r\nCookie: theme=dark; debug=1\r\n\r\n";
+        assert_eq!(check(&phase, cookie).1, "3");
+        assert_eq!(check(&phase, "GET /app/%2Eenv HTTP/1.1\r\n\r\n").1, "4");
+        assert_eq!(check(&phase, "TRACE / HTTP/1.1\r\n\r\n").1, "5");
+        assert_eq!(check(&phase, "GET /?debug=1 HTTP/1.1\r\n\r\n").0, None);
+    }
+
+    #[test]
+    fn test_transforms() {
+        let phase = phase(
+            WafMode::On,
+            &[
+                r#"SecRule QUERY_STRING "@contains union select" "id:1,deny,t:urlDecode,t:lowercase,t:compressWhitespace""#,
+                r#"SecRule REQUEST_HEADERS:X-Cmd "@streq rm-rf" "id:2,deny,t:removeWhitespace,t:none,t:trim""#,
+            ],
+            0,
+        );
+        let sqli = "GET /?q=1+UNION%20%09+SELECT HTTP/1.1\r\n\r\n";
+        assert_eq!(check(&phase, sqli).1, "1");
+        assert_eq!(check(&phase, "GET /?q=union HTTP/1.1\r\n\r\n").1, "");
+        assert_eq!(
+            check(&phase, "GET / HTTP/1.1\r\nX-Cmd: rm-rf\r\n\r\n").1,
+            "2"
+        );
+        // t:none drops the transformations before it.
+        let spaced = "GET / HTTP/1.1\r\nX-Cmd: rm - rf\r\n\r\n";
+        assert_eq!(check(&phase, spaced).1, "");
+    }
+
+    #[test]
+    fn test_anomaly_score_threshold() {
+        let rules = [
+            r#"SecRule ARGS:a "@streq 1" "id:1,severity:WARNING""#,
+            r#"SecRule ARGS:b "@streq 1" "id:2,severity:NOTICE""#,
+            r#"SecRule ARGS:c "@streq 1" "id:3,score:1,severity:CRITICAL""#,
+            r#"SecRule ARGS:d "@streq 1" "id:4,nolog""#,
+        ];
+        let scoring = phase(WafMode::On, &rules, 5);
+        assert_eq!(
+            check(&scoring, "GET /?a=1 HTTP/1.1\r\n\r\n"),
+            (None, "1".to_string(), "3".to_string())
+        );
+        assert_eq!(
+            check(&scoring, "GET /?a=1&b=1 HTTP/1.1\r\n\r\n"),
+            (Some(403), "1,2".to_string(), "5".to_string())
+        );
+        // An explicit score wins over the one the severity implies.
+        assert_eq!(
+            check(&scoring, "GET /?b=1&c=1 HTTP/1.1\r\n\r\n"),
+            (None, "2,3".to_string(), "3".to_string())
+        );
+        assert_eq!(
+            check(&scoring, "GET /?d=1 HTTP/1.1\r\n\r\n"),
+            (None, "4".to_string(), "0".to_string())
+        );
+
+        let unlimited = phase(WafMode::On, &rules, 0);
+        assert_eq!(
+            check(&unlimited, "GET /?a=1&b=1&c=1 HTTP/1.1\r\n\r\n"),
+            (None, "1,2,3".to_string(), "6".to_string())
+        );
+    }
+
+    #[test]
+    fn test_detect_mode() {
+        let rules = [
+            r#"SecRule REQUEST_URI "@contains /admin" "id:1,deny,status:406""#,
+            r#"SecRule ARGS "@streq x" "id:2,severity:CRITICAL""#,
+            r#"SecRule ARGS "@streq x" "id:3,severity:CRITICAL""#,
+        ];
+        let raw = "GET /admin?q=x HTTP/1.1\r\n\r\n";
+        let blocking = phase(WafMode::On, &rules, 5);
+        assert_eq!(
+            check(&blocking, raw),
+            (Some(406), "1".to_string(), "0".to_string())
+        );
+        assert_eq!(
+            check(&blocking, "GET /?q=x HTTP/1.1\r\n\r\n"),
+            (Some(403), "2".to_string(), "5".to_string())
+        );
+
+        // Every rule still runs and is recorded, but nothing is blocked.
+        let detecting = phase(WafMode::Detect, &rules, 5);
+        assert_eq!(
+            check(&detecting, raw),
+            (None, "1,2,3".to_string(), "10".to_string())
+        );
+
+        let mut ctx = ConfigContext::new_empty("location", Vec::new());
+        *ctx.block_config::<WafConfig>().lock().unwrap() = WafConfig {
+            mode: Some(WafMode::Off),
+            rules: vec![Arc::new(
+                WafRule::parse("REQUEST_URI", "@contains /", "id:1,deny").unwrap(),
+            )],
+            ..Default::default()
+        };
+        assert!(waf_phase(&[&ctx]).is_none());
+    }
+
+    #[test]
+    fn test_rules_file_and_removal() {
+        let (rules, removed) = parse_rules_file(
+            r#"
+# SQL injection
+SecRule ARGS "@rx (?i)union\s+select" \
+    "id:942100,phase:2,deny,msg:'SQL injection, union'"
+SecRule REQUEST_HEADERS:User-Agent "@contains \"bad bot\"" "id:913100,deny"
+SecRuleRemoveById 942000-942999 1
+"#,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].msg, "SQL injection, union");
+        assert!(
+            matches!(&rules[1].operator, Operator::Contains(needle) if needle == "\"bad bot\"")
+        );
+        assert_eq!(removed, vec![(942000, 942999), (1, 1)]);
+
+        let phase = phase(
+            WafMode::On,
+            &[
+                r#"SecRule ARGS "@contains x" "id:942100,deny""#,
+                r#"SecRule ARGS "@contains x" "id:913100,deny,status:406""#,
+                "SecRuleRemoveById 942000-942999",
+            ],
+            5,
+        );
+        assert_eq!(check(&phase, "GET /?q=x HTTP/1.1\r\n\r\n").1, "913100");
+    }
+
+    #[test]
+    fn test_parser_rejects_bad_rules() {
+        assert!(WafRule::parse("ARGS", "@detectSQLi", "id:1").is_err());
+        assert!(WafRule::parse("ARGS", "@rx (", "id:1").is_err());
+        assert!(WafRule::parse("ARGS", "x", "deny").is_err());
+        assert!(WafRule::parse("ARGS", "x", "id:one").is_err());
+        assert!(WafRule::parse("", "x", "id:1").is_err());
+        assert!(WafRule::parse("!ARGS:q", "x", "id:1").is_err());
+        assert!(WafRule::parse("TX", "x", "id:1").is_err());
+        assert!(WafRule::parse("REQUEST_URI:path", "x", "id:1").is_err());
+        assert!(WafRule::parse("ARGS", "x", "id:1,status:99").is_err());
+        assert!(WafRule::parse("ARGS", "x", "id:1,t:base64Decode").is_err());
+        assert!(WafRule::parse("ARGS", "x", "id:1,exec:/bin/sh").is_err());
+        assert!(WafRule::parse("ARGS", "x", "id:1,msg:'a, b',tag:'x'").is_ok());
+
+        assert!(parse_rules_file("SecRuleEngine On").is_err());
+        assert!(parse_rules_file("SecRule ARGS").is_err());
+        assert!(parse_rules_file("SecRuleRemoveById 20-10").is_err());
+        assert_eq!(
+            parse_rules_file("\n# ok\nSecRule ARGS x \"id:1\"\nSecRule ARGS @unknown \"id:2\"")
+                .unwrap_err(),
+            "line 4: unsupported operator \"@unknown\""
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+
+use serde_json::Value;
+
+use crate::{
+    core::config::{
+        command::{ArgType, CommandBuilder, ParameterBuilder},
+        config_context::{merged_config, ConfigContext, MergeConfig},
+        config_loader::ConfigError,
+    },
+    register_commands,
+};
+
+use super::http_compression::Encoder;
+
+register_commands!(
+    CommandBuilder::new("zstd")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Zstd")
+        .display_name("zh-tw", "Zstd 壓縮")
+        .desc(
+            "en",
+            "Compresses responses with Zstd for clients that accept zstd"
+        )
+        .desc("zh-tw", "對接受 zstd 的客戶端以 Zstd 壓縮回應")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Enable")
+            .display_name("zh-tw", "啟用")
+            .arg_type(ArgType::Bool)
+            .is_required(true)
+            .default("")
+            .desc("en", "on or off, defaults to off")
+            .desc("zh-tw", "on 或 off，預設為 off")
+            .build()])
+        .build(handle_zstd),
+    CommandBuilder::new("zstd_comp_level")
+        .allowed_parents(vec![
+            "http".to_string(),
+            "server".to_string(),
+            "location".to_string(),
+        ])
+        .display_name("en", "Zstd Compression Level")
+        .display_name("zh-tw", "Zstd 壓縮等級")
+        .desc("en", "Sets the Zstd compression level")
+        .desc("zh-tw", "設定 Zstd 壓縮等級")
+        .params(vec![ParameterBuilder::new(0)
+            .display_name("en", "Level")
+            .display_name("zh-tw", "等級")
+            .arg_type(ArgType::Number)
+            .is_required(true)
+            .default("")
+            .desc("en", "1 (fastest) to 19 (smallest), defaults to 3")
+            .desc("zh-tw", "1（最快）到 19（最小），預設為 3")
+            .build()])
+        .build(handle_zstd_comp_level),
+);
+
+pub const DEFAULT_COMP_LEVEL: i32 = 3;
+
+#[derive(Debug, Default, Clone)]
+pub struct ZstdConfig {
+    pub enabled: Option<bool>,
+    pub comp_level: Option<i32>,
+}
+
+impl MergeConfig for ZstdConfig {
+    fn merge_from(&mut self, parent: &Self) {
+        self.enabled = self.enabled.or(parent.enabled);
+        self.comp_level = self.comp_level.or(parent.comp_level);
+    }
+}
+
+pub struct ZstdEncoder {
+    level: i32,
+}
+
+impl ZstdEncoder {
+    pub fn from_chain(chain: &[&ConfigContext]) -> Option<Self> {
+        let config = merged_config::<ZstdConfig>(chain);
+        config.enabled.unwrap_or(false).then(|| Self {
+            level: config.comp_level.unwrap_or(DEFAULT_COMP_LEVEL),
+        })
+    }
+}
+
+impl Encoder for ZstdEncoder {
+    fn coding(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::stream::encode_all(body, self.level)
+    }
+}
+
+fn update(ctx: &mut ConfigContext, apply: impl FnOnce(&mut ZstdConfig)) {
+    if let Ok(mut config) = ctx.block_config::<ZstdConfig>().lock() {
+        apply(&mut config);
+    }
+}
+
+pub fn handle_zstd(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let enabled = ctx.bool_arg(0)?;
+    update(ctx, |config| config.enabled = Some(enabled));
+    Ok(())
+}
+
+pub fn handle_zstd_comp_level(ctx: &mut ConfigContext, _config: &Value) -> Result<(), ConfigError> {
+    if ctx.current_cmd_values.is_empty() {
+        return Ok(());
+    }
+    let level = ctx.number_arg(0)?;
+    if !(1..=19).contains(&level) {
+        return Err(ctx.invalid_value(&level.to_string(), "must be between 1 and 19"));
+    }
+    update(ctx, |config| config.comp_level = Some(level as i32));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let body = "zstd zstd zstd zstd zstd zstd".repeat(64);
+        for level in [1, DEFAULT_COMP_LEVEL, 19] {
+            let encoded = ZstdEncoder { level }.encode(body.as_bytes()).unwrap();
+            assert!(encoded.len() < body.len());
+            let decoded = zstd::decode_all(encoded.as_slice()).unwrap();
+            assert_eq!(decoded, body.as_bytes());
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::core::config::config_manager::ConfigManager;
 use crate::http::http_request::HttpRequest;
 use crate::http::http_response::HttpResponse;
+use crate::log_info;
 use http::{Method, StatusCode};
 use serde_json::Value;
 use std::env;
@@ -706,16 +707,16 @@ fn ensure_static_up_to_date() -> Result<PathBuf, WebConfigError> {
 
 fn needs_update(static_dir: &Path) -> Result<bool, WebConfigError> {
     if !static_dir.exists() || !static_dir.join(".git").exists() {
-        println!("Static files or .git directory not found, need update");
+        log_info!("Static files or .git directory not found, need update");
         return Ok(true);
     }
 
     if !static_dir.join("dist/index.html").exists() {
-        println!("dist/index.html not found, need update (likely initial clone)");
+        log_info!("dist/index.html not found, need update (likely initial clone)");
         return Ok(true);
     }
 
-    println!("Static files found, checking git status");
+    log_info!("Static files found, checking git status");
     let get_git_hash = |args: &[&str]| {
         Command::new("git")
             .args(args)
@@ -734,7 +735,7 @@ fn needs_update(static_dir: &Path) -> Result<bool, WebConfigError> {
     let upstream = get_git_hash(&["rev-parse", "@{u}"]);
 
     if head.is_empty() || upstream.is_empty() {
-        println!("Git commands failed (possibly no upstream), forcing update.");
+        log_info!("Git commands failed (possibly no upstream), forcing update.");
         return Ok(true);
     }
 
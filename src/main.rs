@@ -3,9 +3,13 @@ use std::thread;
 use std::time::Duration;
 
 use blur::http::http_server::get_default_storage_path;
-use blur::{core::config::config_loader, http::http_manager::HttpManager};
+use blur::{
+    core::{config::config_loader, error_log, log_file},
+    http::http_manager::HttpManager,
+};
 use clap::Parser;
 use std::env;
+use std::process;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,6 +24,12 @@ struct Args {
 
     #[arg(short, long, value_name = "USE DEFAULT CONFIG")]
     use_default_config: bool,
+
+    #[arg(short = 't', long, value_name = "TEST CONFIG")]
+    test_config: bool,
+
+    #[arg(short = 'T', long, value_name = "DUMP CONFIG")]
+    dump_config: bool,
 }
 
 fn main() {
@@ -35,6 +45,28 @@ fn main() {
         args.config_path
     };
 
+    if args.test_config || args.dump_config {
+        let config_name = config_path.as_deref().unwrap_or("stored configuration");
+        match config_loader::resolve_config(
+            storage_path.to_str().unwrap(),
+            config_path.as_deref(),
+            vec!["http".to_string()],
+        ) {
+            Ok(config) => {
+                if args.dump_config {
+                    print!("{}", config_loader::render_config(&config));
+                }
+                eprintln!("blur: configuration {} test is successful", config_name);
+                return;
+            }
+            Err(e) => {
+                eprintln!("blur: {}", e);
+                eprintln!("blur: configuration {} test failed", config_name);
+                process::exit(1);
+            }
+        }
+    }
+
     let root_ctx = match config_loader::load_config(
         storage_path.to_str().unwrap(),
         config_path.as_deref(), 
@@ -53,6 +85,12 @@ fn main() {
         .find(|child| child.block_name.trim() == "http")
         .expect("http block not found");
 
+    if let Err(e) = error_log::configure(&[&root_ctx, http_block]) {
+        eprintln!("Error opening error log: {}", e);
+        return;
+    }
+    log_file::reopen_on_signal();
+
     let mut http_manager = HttpManager::new(http_block);
     http_manager.start();
     http_manager.join();